@@ -0,0 +1,119 @@
+//! Latency of a handler needing an entity and two related counts, with the three
+//! queries run:
+//!
+//! - `sequential`: one after the other on one connection (three round trips)
+//! - `connections`: at the same time on three connections (`join_queries!` of
+//!   repository calls)
+//! - `pipelined`: at the same time on one connection (`join_queries!` of queries on
+//!   the same `Client`)
+//!
+//! Runs against the PostgreSQL of the integration tests (`TEST_DB_HOST`, `TEST_DB_PORT`,
+//! `TEST_DB_USER`, `TEST_DB_PASSWORD`), on the system catalogs so no schema is needed:
+//!
+//! ```shell
+//! cargo bench --bench queries
+//! ```
+
+use std::env;
+use std::time::Duration;
+
+use bb8_postgres::tokio_postgres::{self, Client, NoTls, Statement};
+use criterion::{Criterion, criterion_group, criterion_main};
+use tokio::runtime::Runtime;
+
+const ENTITY: &str = "SELECT oid, relname FROM pg_class WHERE oid = $1";
+const COLUMNS: &str = "SELECT count(*) FROM pg_attribute WHERE attrelid = $1";
+const INDEXES: &str = "SELECT count(*) FROM pg_index WHERE indrelid = $1";
+
+/// `pg_class` itself, always there
+const TABLE_OID: u32 = 1259;
+
+async fn connect() -> Result<Client, tokio_postgres::Error> {
+    let setting = |name: &str, default: &str| env::var(name).unwrap_or(default.to_string());
+    let (client, connection) = tokio_postgres::Config::new()
+        .host(setting("TEST_DB_HOST", "localhost"))
+        .port(
+            setting("TEST_DB_PORT", "5432")
+                .parse()
+                .expect("TEST_DB_PORT"),
+        )
+        .user(setting("TEST_DB_USER", "postgres"))
+        .password(setting("TEST_DB_PASSWORD", "123456"))
+        .dbname("postgres")
+        .connect_timeout(Duration::from_secs(5))
+        .connect(NoTls)
+        .await?;
+    tokio::spawn(connection);
+    Ok(client)
+}
+
+/// A connection with the three statements prepared, as the pool keeps them.
+struct Prepared {
+    client: Client,
+    statements: [Statement; 3],
+}
+
+async fn prepare() -> Result<Prepared, tokio_postgres::Error> {
+    let client = connect().await?;
+    let statements = [
+        client.prepare(ENTITY).await?,
+        client.prepare(COLUMNS).await?,
+        client.prepare(INDEXES).await?,
+    ];
+    Ok(Prepared { client, statements })
+}
+
+fn queries(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("Benchmark runtime");
+    let connections = match runtime.block_on(async {
+        Ok::<_, tokio_postgres::Error>([prepare().await?, prepare().await?, prepare().await?])
+    }) {
+        Ok(connections) => connections,
+        Err(e) => {
+            eprintln!(
+                "PostgreSQL unavailable, benchmark skipped (set TEST_DB_*): {}",
+                e
+            );
+            return;
+        }
+    };
+    let [first, second, third] = &connections;
+    let [entity, columns, indexes] = &first.statements;
+
+    let mut group = c.benchmark_group("entity_and_counts");
+    group.bench_function("sequential", |b| {
+        b.to_async(&runtime).iter(async || {
+            let client = &first.client;
+            client.query_one(entity, &[&TABLE_OID]).await.unwrap();
+            client.query_one(columns, &[&TABLE_OID]).await.unwrap();
+            client.query_one(indexes, &[&TABLE_OID]).await.unwrap();
+        })
+    });
+    group.bench_function("connections", |b| {
+        b.to_async(&runtime).iter(async || {
+            tokio::try_join!(
+                first.client.query_one(entity, &[&TABLE_OID]),
+                second
+                    .client
+                    .query_one(&second.statements[1], &[&TABLE_OID]),
+                third.client.query_one(&third.statements[2], &[&TABLE_OID]),
+            )
+            .unwrap();
+        })
+    });
+    group.bench_function("pipelined", |b| {
+        b.to_async(&runtime).iter(async || {
+            let client = &first.client;
+            tokio::try_join!(
+                client.query_one(entity, &[&TABLE_OID]),
+                client.query_one(columns, &[&TABLE_OID]),
+                client.query_one(indexes, &[&TABLE_OID]),
+            )
+            .unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, queries);
+criterion_main!(benches);
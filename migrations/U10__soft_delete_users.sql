@@ -0,0 +1,3 @@
+-- Undoes V10__soft_delete_users. The soft-deleted users are back in the lists
+DROP INDEX users_not_deleted_idx;
+ALTER TABLE users DROP COLUMN deleted_at;
@@ -0,0 +1,2 @@
+-- Undoes V11__add_user_roles
+ALTER TABLE users DROP COLUMN role;
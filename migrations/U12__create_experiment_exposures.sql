@@ -0,0 +1,2 @@
+-- Undoes V12__create_experiment_exposures
+DROP TABLE experiment_exposures;
@@ -0,0 +1,2 @@
+-- Undoes V13__create_saved_views
+DROP TABLE saved_views;
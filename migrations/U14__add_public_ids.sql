@@ -0,0 +1,19 @@
+-- Undoes V14__add_public_ids
+CREATE OR REPLACE FUNCTION track_user_change() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        INSERT INTO user_tombstones (id) VALUES (OLD.id)
+        ON CONFLICT (id) DO UPDATE
+            SET deleted_at = now(), change_version = nextval('user_change_version_seq');
+        RETURN OLD;
+    END IF;
+
+    NEW.updated_at := now();
+    NEW.change_version := nextval('user_change_version_seq');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE user_tombstones DROP COLUMN public_id;
+ALTER TABLE products DROP COLUMN public_id;
+ALTER TABLE users DROP COLUMN public_id;
@@ -0,0 +1,3 @@
+-- Undoes V15__add_row_versions
+ALTER TABLE products DROP COLUMN version;
+ALTER TABLE users DROP COLUMN version;
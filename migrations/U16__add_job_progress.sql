@@ -0,0 +1,2 @@
+-- Undoes V16__add_job_progress
+ALTER TABLE jobs DROP COLUMN progress;
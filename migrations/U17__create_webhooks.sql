@@ -0,0 +1,3 @@
+-- Undoes V17__create_webhooks
+DROP TABLE webhook_deliveries;
+DROP TABLE webhooks;
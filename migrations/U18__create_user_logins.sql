@@ -0,0 +1,5 @@
+-- Undoes V18__create_user_logins
+DROP INDEX experiment_exposures_first_seen_idx;
+DROP INDEX webhook_deliveries_created_at_idx;
+DROP INDEX audit_log_created_at_idx;
+DROP TABLE user_logins;
@@ -0,0 +1,52 @@
+-- Undoes V19__partition_by_month. The partitions detached into the archive schema are
+-- not restored: this fails until they are dropped (or attached again).
+ALTER TABLE audit_log RENAME TO audit_log_partitioned;
+ALTER TABLE audit_log_partitioned RENAME CONSTRAINT audit_log_pkey TO audit_log_partitioned_pkey;
+ALTER SEQUENCE audit_log_id_seq OWNED BY NONE;
+ALTER TABLE webhook_deliveries RENAME TO webhook_deliveries_partitioned;
+ALTER TABLE webhook_deliveries_partitioned
+    RENAME CONSTRAINT webhook_deliveries_pkey TO webhook_deliveries_partitioned_pkey;
+ALTER SEQUENCE webhook_deliveries_id_seq OWNED BY NONE;
+ALTER INDEX audit_log_entity_idx RENAME TO audit_log_partitioned_entity_idx;
+ALTER INDEX audit_log_created_at_idx RENAME TO audit_log_partitioned_created_at_idx;
+ALTER INDEX webhook_deliveries_webhook_id_idx RENAME TO webhook_deliveries_partitioned_webhook_id_idx;
+ALTER INDEX webhook_deliveries_created_at_idx RENAME TO webhook_deliveries_partitioned_created_at_idx;
+
+CREATE TABLE audit_log (
+    id BIGINT PRIMARY KEY DEFAULT nextval('audit_log_id_seq'),
+    actor_id INT,
+    action TEXT NOT NULL,
+    entity TEXT NOT NULL,
+    entity_id INT NOT NULL,
+    changes JSONB NOT NULL,
+    request_id TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE webhook_deliveries (
+    id BIGINT PRIMARY KEY DEFAULT nextval('webhook_deliveries_id_seq'),
+    webhook_id UUID NOT NULL
+        CONSTRAINT webhook_deliveries_webhook_id_fkey REFERENCES webhooks (id) ON DELETE CASCADE,
+    job_id BIGINT NOT NULL,
+    attempt INT NOT NULL,
+    event_type TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    status_code INT,
+    error TEXT,
+    duration_ms INT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+INSERT INTO audit_log SELECT * FROM audit_log_partitioned;
+INSERT INTO webhook_deliveries SELECT * FROM webhook_deliveries_partitioned;
+DROP TABLE audit_log_partitioned;
+DROP TABLE webhook_deliveries_partitioned;
+ALTER SEQUENCE audit_log_id_seq OWNED BY audit_log.id;
+ALTER SEQUENCE webhook_deliveries_id_seq OWNED BY webhook_deliveries.id;
+
+CREATE INDEX audit_log_entity_idx ON audit_log (entity, entity_id, id);
+CREATE INDEX audit_log_created_at_idx ON audit_log (created_at);
+CREATE INDEX webhook_deliveries_webhook_id_idx ON webhook_deliveries (webhook_id, id);
+CREATE INDEX webhook_deliveries_created_at_idx ON webhook_deliveries (created_at);
+
+DROP SCHEMA archive;
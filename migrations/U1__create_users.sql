@@ -0,0 +1,2 @@
+-- Undoes V1__create_users, deleting every user
+DROP TABLE users;
@@ -0,0 +1,2 @@
+-- Undoes V20__create_archives. The exported files stay in the file storage.
+DROP TABLE archives;
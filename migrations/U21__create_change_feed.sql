@@ -0,0 +1,4 @@
+-- Undoes V21__create_change_feed
+DROP TABLE cdc_checkpoints;
+DROP INDEX audit_log_feed_idx;
+ALTER TABLE audit_log DROP COLUMN xid;
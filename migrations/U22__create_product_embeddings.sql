@@ -0,0 +1,3 @@
+-- Undoes V22__create_product_embeddings, the products are embedded again when it's
+-- applied anew
+DROP TABLE product_embeddings;
@@ -0,0 +1,3 @@
+-- Undoes V23__create_user_recommendations
+DROP INDEX orders_product_id_idx;
+DROP TABLE user_recommendations;
@@ -0,0 +1,12 @@
+-- Undoes V24__create_search_terms
+DROP TRIGGER products_search_terms ON products;
+DROP TRIGGER products_search_terms_rename ON products;
+DROP TRIGGER users_search_terms ON users;
+DROP TRIGGER users_search_terms_rename ON users;
+DROP FUNCTION track_user_search_terms();
+DROP FUNCTION track_search_terms();
+DROP FUNCTION count_search_terms(TEXT, INTEGER);
+DROP FUNCTION search_trigrams(TEXT);
+DROP FUNCTION search_words(TEXT);
+DROP TABLE search_term_trigrams;
+DROP TABLE search_terms;
@@ -0,0 +1,3 @@
+-- Undoes V25__create_oidc
+DROP TABLE oidc_authorization_codes;
+DROP TABLE oidc_signing_key;
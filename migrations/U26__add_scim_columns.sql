@@ -0,0 +1,6 @@
+-- Undoes V26__add_scim_columns: the deactivated accounts can log in again
+DROP INDEX users_lower_external_id_idx;
+DROP INDEX users_lower_email_idx;
+ALTER TABLE users
+    DROP COLUMN active,
+    DROP COLUMN external_id;
@@ -0,0 +1,4 @@
+-- Undoes V27__create_passkeys
+ALTER TABLE users DROP COLUMN passkey_second_factor;
+DROP TABLE passkey_challenges;
+DROP TABLE passkeys;
@@ -0,0 +1,6 @@
+-- Undoes V28__create_ledger
+DROP TABLE ledger_postings;
+DROP TABLE ledger_entries;
+DROP TABLE ledger_accounts;
+DROP FUNCTION refuse_ledger_change();
+DROP FUNCTION check_ledger_entry();
@@ -0,0 +1,5 @@
+-- Undoes V29__create_coupons
+ALTER TABLE orders DROP COLUMN coupon_id;
+ALTER TABLE orders DROP COLUMN discount;
+DROP TABLE coupon_redemptions;
+DROP TABLE coupons;
@@ -0,0 +1,2 @@
+-- Undoes V2__create_products, deleting every product
+DROP TABLE products;
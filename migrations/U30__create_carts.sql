@@ -0,0 +1,4 @@
+-- Undoes V30__create_carts
+DROP TABLE wishlist_items;
+DROP TABLE cart_items;
+DROP TABLE carts;
@@ -0,0 +1,4 @@
+-- Undoes V31__add_job_context
+ALTER TABLE jobs
+    DROP COLUMN actor_id,
+    DROP COLUMN request_id;
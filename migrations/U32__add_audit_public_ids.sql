@@ -0,0 +1,5 @@
+-- Undoes V32__add_audit_public_ids
+DROP INDEX audit_log_entity_public_id_idx;
+ALTER TABLE audit_log
+    DROP COLUMN entity_public_id,
+    DROP COLUMN actor_public_id;
@@ -0,0 +1,4 @@
+-- Undoes V3__add_user_credentials: the accounts can no longer log in
+ALTER TABLE users
+    DROP COLUMN email,
+    DROP COLUMN password_hash;
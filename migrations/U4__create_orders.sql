@@ -0,0 +1,2 @@
+-- Undoes V4__create_orders, deleting every order
+DROP TABLE orders;
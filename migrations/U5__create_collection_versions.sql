@@ -0,0 +1,5 @@
+-- Undoes V5__create_collection_versions
+DROP TRIGGER users_bump_version ON users;
+DROP TRIGGER products_bump_version ON products;
+DROP FUNCTION bump_collection_version();
+DROP TABLE collection_versions;
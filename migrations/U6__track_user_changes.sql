@@ -0,0 +1,9 @@
+-- Undoes V6__track_user_changes, forgetting the deleted users
+DROP TRIGGER users_track_update ON users;
+DROP TRIGGER users_track_delete ON users;
+DROP FUNCTION track_user_change();
+DROP TABLE user_tombstones;
+ALTER TABLE users
+    DROP COLUMN updated_at,
+    DROP COLUMN change_version;
+DROP SEQUENCE user_change_version_seq;
@@ -0,0 +1,2 @@
+-- Undoes V7__add_user_avatar. The files stay in the storage
+ALTER TABLE users DROP COLUMN avatar_path;
@@ -0,0 +1,2 @@
+-- Undoes V8__create_jobs, dropping the queued jobs
+DROP TABLE jobs;
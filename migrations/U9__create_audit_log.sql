@@ -0,0 +1,2 @@
+-- Undoes V9__create_audit_log, deleting the history
+DROP TABLE audit_log;
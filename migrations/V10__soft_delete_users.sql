@@ -0,0 +1,7 @@
+-- Soft delete of the users (DELETE /users/{id} without ?hard=true): the row stays, with
+-- the moment it was deleted, until it is restored (POST /users/{id}/restore) or deleted
+-- for good. NULL for the users that aren't deleted
+ALTER TABLE users ADD COLUMN deleted_at TIMESTAMPTZ;
+
+-- Lists read the users that aren't deleted
+CREATE INDEX users_not_deleted_idx ON users (id) WHERE deleted_at IS NULL;
@@ -0,0 +1,5 @@
+-- Role of each user (see `roles`): what the routes registered with a required role let
+-- them do. Existing and new users are viewers until given another role
+ALTER TABLE users
+    ADD COLUMN role TEXT NOT NULL DEFAULT 'viewer'
+        CHECK (role IN ('viewer', 'editor', 'admin'));
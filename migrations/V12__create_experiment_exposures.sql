@@ -0,0 +1,13 @@
+-- First exposure of every subject to an A/B experiment (see the `experiments` module).
+-- A new run of an experiment takes a new name, so its subjects are recorded again
+CREATE TABLE experiment_exposures (
+    experiment TEXT NOT NULL,
+    -- user:<id> for authenticated requests, ip:<address> otherwise
+    subject TEXT NOT NULL,
+    variant TEXT NOT NULL,
+    first_seen TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (experiment, subject)
+);
+
+-- Subjects of each variant, for the analysis
+CREATE INDEX experiment_exposures_variant_idx ON experiment_exposures (experiment, variant);
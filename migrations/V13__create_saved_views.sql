@@ -0,0 +1,14 @@
+-- Named filter and sort combinations of the lists, saved by each user (see
+-- `routes::views`) and applied with ?view=<name>
+CREATE TABLE saved_views (
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    -- List the view applies to: users or products
+    collection TEXT NOT NULL,
+    name TEXT NOT NULL,
+    -- Filter expression, as in ?filter=, empty for none
+    filter TEXT NOT NULL DEFAULT '',
+    sort TEXT,
+    sort_order TEXT CHECK (sort_order IN ('asc', 'desc')),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, collection, name)
+);
@@ -0,0 +1,26 @@
+-- UUIDs identifying users and products in the API (see `repository::ids`). The
+-- application generates version 7 UUIDs for new rows; the existing ones get random
+-- UUIDs here, and rows inserted by hand get one from the default
+ALTER TABLE users ADD COLUMN public_id UUID NOT NULL DEFAULT gen_random_uuid();
+ALTER TABLE users ADD CONSTRAINT users_public_id_key UNIQUE (public_id);
+
+ALTER TABLE products ADD COLUMN public_id UUID NOT NULL DEFAULT gen_random_uuid();
+ALTER TABLE products ADD CONSTRAINT products_public_id_key UNIQUE (public_id);
+
+-- Deleted users are reported by UUID to polling clients; older tombstones have none
+ALTER TABLE user_tombstones ADD COLUMN public_id UUID;
+
+CREATE OR REPLACE FUNCTION track_user_change() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        INSERT INTO user_tombstones (id, public_id) VALUES (OLD.id, OLD.public_id)
+        ON CONFLICT (id) DO UPDATE
+            SET deleted_at = now(), change_version = nextval('user_change_version_seq');
+        RETURN OLD;
+    END IF;
+
+    NEW.updated_at := now();
+    NEW.change_version := nextval('user_change_version_seq');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,4 @@
+-- Version of each user and product, bumped by every write and sent as the ETag of the
+-- entity; `If-Match` makes a write apply only to the version the client read
+ALTER TABLE users ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE products ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
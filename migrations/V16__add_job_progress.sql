@@ -0,0 +1,3 @@
+-- Progress of a long job, reported by the operations API while it runs
+-- ({"total": 1200, "deleted": 500} for a bulk delete)
+ALTER TABLE jobs ADD COLUMN progress JSONB;
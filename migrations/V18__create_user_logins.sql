@@ -0,0 +1,10 @@
+-- Latest login of each account, for the retention rules finding the inactive users.
+-- Kept out of the users table: a login isn't a change of the user (see V6)
+CREATE TABLE user_logins (
+    user_id INT PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    last_login_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX audit_log_created_at_idx ON audit_log (created_at);
+CREATE INDEX webhook_deliveries_created_at_idx ON webhook_deliveries (created_at);
+CREATE INDEX experiment_exposures_first_seen_idx ON experiment_exposures (first_seen);
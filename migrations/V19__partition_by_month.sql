@@ -0,0 +1,81 @@
+-- The audit log and the webhook deliveries, which grow with every write, are split in
+-- one partition per month of created_at (see `partitions`): the indexes written to
+-- stay the size of a month, and the old months are detached instead of deleted row by
+-- row. The partitions are named <table>_pYYYY_MM, in UTC.
+
+-- Old partitions, detached from their table but kept for export
+CREATE SCHEMA archive;
+
+-- The sequences of the IDs go on, owned by the new tables, and the constraints keep
+-- their names (the ones of the partitions are named after them)
+ALTER TABLE audit_log RENAME TO audit_log_unpartitioned;
+ALTER TABLE audit_log_unpartitioned RENAME CONSTRAINT audit_log_pkey TO audit_log_unpartitioned_pkey;
+ALTER SEQUENCE audit_log_id_seq OWNED BY NONE;
+ALTER TABLE webhook_deliveries RENAME TO webhook_deliveries_unpartitioned;
+ALTER TABLE webhook_deliveries_unpartitioned
+    RENAME CONSTRAINT webhook_deliveries_pkey TO webhook_deliveries_unpartitioned_pkey;
+ALTER SEQUENCE webhook_deliveries_id_seq OWNED BY NONE;
+
+-- The primary keys of partitioned tables include the partition key
+CREATE TABLE audit_log (
+    id BIGINT NOT NULL DEFAULT nextval('audit_log_id_seq'),
+    actor_id INT,
+    action TEXT NOT NULL,
+    entity TEXT NOT NULL,
+    entity_id INT NOT NULL,
+    changes JSONB NOT NULL,
+    request_id TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (id, created_at)
+) PARTITION BY RANGE (created_at);
+
+CREATE TABLE webhook_deliveries (
+    id BIGINT NOT NULL DEFAULT nextval('webhook_deliveries_id_seq'),
+    webhook_id UUID NOT NULL
+        CONSTRAINT webhook_deliveries_webhook_id_fkey REFERENCES webhooks (id) ON DELETE CASCADE,
+    job_id BIGINT NOT NULL,
+    attempt INT NOT NULL,
+    event_type TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    status_code INT,
+    error TEXT,
+    duration_ms INT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (id, created_at)
+) PARTITION BY RANGE (created_at);
+
+-- From the month of the oldest row to two months ahead, the application creates the
+-- next ones
+DO $$
+DECLARE
+    parent TEXT;
+    month TIMESTAMP;
+    last TIMESTAMP := date_trunc('month', now() AT TIME ZONE 'UTC') + interval '2 months';
+BEGIN
+    FOREACH parent IN ARRAY ARRAY['audit_log', 'webhook_deliveries'] LOOP
+        EXECUTE format('SELECT date_trunc(''month'', min(created_at) AT TIME ZONE ''UTC'') FROM %I',
+                       parent || '_unpartitioned')
+            INTO month;
+        month := LEAST(COALESCE(month, last), date_trunc('month', now() AT TIME ZONE 'UTC'));
+        WHILE month <= last LOOP
+            EXECUTE format('CREATE TABLE %I PARTITION OF %I FOR VALUES FROM (%L) TO (%L)',
+                           parent || '_p' || to_char(month, 'YYYY_MM'), parent,
+                           month AT TIME ZONE 'UTC',
+                           (month + interval '1 month') AT TIME ZONE 'UTC');
+            month := month + interval '1 month';
+        END LOOP;
+    END LOOP;
+END $$;
+
+INSERT INTO audit_log SELECT * FROM audit_log_unpartitioned;
+INSERT INTO webhook_deliveries SELECT * FROM webhook_deliveries_unpartitioned;
+DROP TABLE audit_log_unpartitioned;
+DROP TABLE webhook_deliveries_unpartitioned;
+ALTER SEQUENCE audit_log_id_seq OWNED BY audit_log.id;
+ALTER SEQUENCE webhook_deliveries_id_seq OWNED BY webhook_deliveries.id;
+
+-- The indexes of V9, V15 and V16, created on every partition
+CREATE INDEX audit_log_entity_idx ON audit_log (entity, entity_id, id);
+CREATE INDEX audit_log_created_at_idx ON audit_log (created_at);
+CREATE INDEX webhook_deliveries_webhook_id_idx ON webhook_deliveries (webhook_id, id);
+CREATE INDEX webhook_deliveries_created_at_idx ON webhook_deliveries (created_at);
@@ -0,0 +1,6 @@
+-- IF NOT EXISTS: databases set up by hand before migrations existed already have the table
+CREATE TABLE IF NOT EXISTS users (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    age INTEGER NOT NULL
+);
@@ -0,0 +1,18 @@
+-- Manifests of the partitions exported from the archive schema to the file storage
+-- (see `archives`), one gzipped CSV file each, listed and restored by the admins.
+CREATE TABLE archives (
+    id UUID PRIMARY KEY,
+    -- Partitioned table the rows come from (audit_log, webhook_deliveries)
+    table_name TEXT NOT NULL,
+    -- <table>_pYYYY_MM, dropped from the archive schema once exported
+    partition_name TEXT NOT NULL UNIQUE,
+    -- Location of the file in the file storage
+    location TEXT NOT NULL,
+    row_count BIGINT NOT NULL,
+    byte_size BIGINT NOT NULL,
+    -- Hex SHA-256 of the file, checked by the restores
+    sha256 TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- Latest restore of the partition into the archive schema
+    restored_at TIMESTAMPTZ
+);
@@ -0,0 +1,16 @@
+-- Change feed of the audit log (see `repository::cdc`): the transaction of each record,
+-- so a record is only read once every transaction started before it is finished.
+-- The records already there share the ID of this transaction, their order is their id
+ALTER TABLE audit_log ADD COLUMN xid xid8 NOT NULL DEFAULT pg_current_xact_id();
+
+-- Feed of a kind of entity, in the order of the changes
+CREATE INDEX audit_log_feed_idx ON audit_log (entity, xid, id);
+
+-- Position of each consumer of the feed of a table, saved by the consumer
+CREATE TABLE cdc_checkpoints (
+    consumer TEXT NOT NULL,
+    table_name TEXT NOT NULL,
+    position TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (consumer, table_name)
+);
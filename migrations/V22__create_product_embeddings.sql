@@ -0,0 +1,11 @@
+-- Embeddings of the products for the semantic search (see `embeddings`). Stored as
+-- arrays so the table works without pgvector; when the extension is installed, the
+-- search casts them to `vector`. `content` is the embedded text, so a product is
+-- embedded again when it changes, as when the model changes
+CREATE TABLE product_embeddings (
+    product_id INTEGER PRIMARY KEY REFERENCES products (id) ON DELETE CASCADE,
+    model TEXT NOT NULL,
+    content TEXT NOT NULL,
+    embedding REAL[] NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
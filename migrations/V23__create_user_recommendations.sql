@@ -0,0 +1,11 @@
+-- Product recommendations of each user (see `recommendations`), computed on the first
+-- request and refreshed by the scheduler:
+-- [{"product_id": <key>, "reason": "co_purchase" | "popular", "score": <n>}...], the best first
+CREATE TABLE user_recommendations (
+    user_id INTEGER PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    items JSONB NOT NULL,
+    computed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Buyers of a product, for the co-purchases
+CREATE INDEX orders_product_id_idx ON orders (product_id, user_id);
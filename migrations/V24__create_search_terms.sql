@@ -0,0 +1,103 @@
+-- Words of the product and user names, for the suggestions of GET /search/suggest.
+-- `frequency` is the number of names with the word, kept up to date by triggers; the
+-- trigrams of each word find the ones close to a misspelled word, as pg_trgm would,
+-- without needing the extension
+CREATE TABLE search_terms (
+    term TEXT PRIMARY KEY,
+    frequency INTEGER NOT NULL,
+    trigrams INTEGER NOT NULL
+);
+
+CREATE INDEX search_terms_prefix_idx ON search_terms (term text_pattern_ops);
+
+CREATE TABLE search_term_trigrams (
+    trigram TEXT NOT NULL,
+    term TEXT NOT NULL REFERENCES search_terms (term) ON DELETE CASCADE,
+    PRIMARY KEY (trigram, term)
+);
+
+CREATE INDEX search_term_trigrams_term_idx ON search_term_trigrams (term);
+
+-- The distinct lowercase words of a name, of 2 characters or more
+CREATE FUNCTION search_words(name TEXT) RETURNS SETOF TEXT AS $$
+    SELECT DISTINCT word FROM regexp_split_to_table(lower(name), '[^[:alnum:]]+') AS word
+    WHERE length(word) >= 2
+$$ LANGUAGE sql IMMUTABLE;
+
+-- The trigrams of a word, padded like pg_trgm's: "pen" has "  p", " pe", "pen", "en "
+CREATE FUNCTION search_trigrams(word TEXT) RETURNS SETOF TEXT AS $$
+    SELECT DISTINCT substr('  ' || word || ' ', i, 3) FROM generate_series(1, length(word) + 1) AS i
+$$ LANGUAGE sql IMMUTABLE;
+
+-- Counts the words of a name once more (delta 1) or once less (delta -1)
+CREATE FUNCTION count_search_terms(name TEXT, delta INTEGER) RETURNS void AS $$
+BEGIN
+    IF delta > 0 THEN
+        INSERT INTO search_terms (term, frequency, trigrams)
+        SELECT word, delta, (SELECT count(*) FROM search_trigrams(word))
+        FROM search_words(name) AS word
+        ON CONFLICT (term) DO UPDATE SET frequency = search_terms.frequency + EXCLUDED.frequency;
+        INSERT INTO search_term_trigrams (trigram, term)
+        SELECT trigram, word FROM search_words(name) AS word, search_trigrams(word) AS trigram
+        ON CONFLICT DO NOTHING;
+    ELSE
+        UPDATE search_terms SET frequency = frequency + delta
+        WHERE term IN (SELECT search_words(name));
+        DELETE FROM search_terms WHERE frequency <= 0 AND term IN (SELECT search_words(name));
+    END IF;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION track_search_terms() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') THEN
+        PERFORM count_search_terms(OLD.name, -1);
+    END IF;
+    IF TG_OP IN ('INSERT', 'UPDATE') THEN
+        PERFORM count_search_terms(NEW.name, 1);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- The same for the users, whose names only count while they are not soft-deleted
+CREATE FUNCTION track_user_search_terms() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') AND OLD.deleted_at IS NULL THEN
+        PERFORM count_search_terms(OLD.name, -1);
+    END IF;
+    IF TG_OP IN ('INSERT', 'UPDATE') AND NEW.deleted_at IS NULL THEN
+        PERFORM count_search_terms(NEW.name, 1);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER products_search_terms
+    AFTER INSERT OR DELETE ON products
+    FOR EACH ROW EXECUTE FUNCTION track_search_terms();
+
+CREATE TRIGGER products_search_terms_rename
+    AFTER UPDATE OF name ON products
+    FOR EACH ROW WHEN (OLD.name IS DISTINCT FROM NEW.name)
+    EXECUTE FUNCTION track_search_terms();
+
+CREATE TRIGGER users_search_terms
+    AFTER INSERT OR DELETE ON users
+    FOR EACH ROW EXECUTE FUNCTION track_user_search_terms();
+
+CREATE TRIGGER users_search_terms_rename
+    AFTER UPDATE OF name, deleted_at ON users
+    FOR EACH ROW WHEN (OLD.name IS DISTINCT FROM NEW.name
+        OR (OLD.deleted_at IS NULL) <> (NEW.deleted_at IS NULL))
+    EXECUTE FUNCTION track_user_search_terms();
+
+-- The names already there
+INSERT INTO search_terms (term, frequency, trigrams)
+SELECT word, count(*), (SELECT count(*) FROM search_trigrams(word))
+FROM (SELECT name FROM products UNION ALL SELECT name FROM users WHERE deleted_at IS NULL) AS names,
+    search_words(names.name) AS word
+GROUP BY word;
+
+INSERT INTO search_term_trigrams (trigram, term)
+SELECT trigram, term FROM search_terms, search_trigrams(term) AS trigram;
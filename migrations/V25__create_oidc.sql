@@ -0,0 +1,26 @@
+-- OpenID Connect provider (see `oidc`): the P-256 key signing the ID tokens, created by
+-- the first instance and shared by the others, and the authorization codes waiting to
+-- be exchanged for tokens, single use and short-lived
+CREATE TABLE oidc_signing_key (
+    singleton BOOLEAN PRIMARY KEY DEFAULT true CHECK (singleton),
+    kid TEXT NOT NULL,
+    -- PKCS#8 document
+    private_key BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- The SHA-256 of the codes is stored, not the codes
+CREATE TABLE oidc_authorization_codes (
+    code_hash TEXT PRIMARY KEY,
+    client_id TEXT NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    redirect_uri TEXT NOT NULL,
+    -- PKCE, S256
+    code_challenge TEXT NOT NULL,
+    scope TEXT NOT NULL,
+    nonce TEXT,
+    auth_time TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX oidc_authorization_codes_expires_at_idx ON oidc_authorization_codes (expires_at);
@@ -0,0 +1,9 @@
+-- SCIM provisioning (see `scim`): the ID of the user in the identity system, and
+-- whether the account is active, cleared when the identity system deactivates it
+ALTER TABLE users
+    ADD COLUMN external_id TEXT,
+    ADD COLUMN active BOOLEAN NOT NULL DEFAULT true;
+
+-- SCIM filters compare strings case insensitively
+CREATE INDEX users_lower_email_idx ON users (lower(email));
+CREATE INDEX users_lower_external_id_idx ON users (lower(external_id));
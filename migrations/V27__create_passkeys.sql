@@ -0,0 +1,32 @@
+-- Passkeys (see `passkeys`): the public keys of the authenticators of the users, and
+-- the challenges of the registrations and logins waiting for the answer of the browser,
+-- single use and short-lived
+CREATE TABLE passkeys (
+    id SERIAL PRIMARY KEY,
+    public_id UUID NOT NULL UNIQUE,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    credential_id BYTEA NOT NULL UNIQUE,
+    -- COSE_Key of the authenticator
+    public_key BYTEA NOT NULL,
+    sign_count BIGINT NOT NULL DEFAULT 0,
+    name TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMPTZ
+);
+
+CREATE INDEX passkeys_user_id_idx ON passkeys (user_id);
+
+CREATE TABLE passkey_challenges (
+    id UUID PRIMARY KEY,
+    -- registration, authentication (passwordless) or second_factor
+    purpose TEXT NOT NULL,
+    -- NULL for the passwordless logins, the user being the one of the passkey
+    user_id INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    challenge BYTEA NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX passkey_challenges_expires_at_idx ON passkey_challenges (expires_at);
+
+-- Whether the password logins of the user need one of their passkeys too
+ALTER TABLE users ADD COLUMN passkey_second_factor BOOLEAN NOT NULL DEFAULT false;
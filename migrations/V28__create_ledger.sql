@@ -0,0 +1,77 @@
+-- Double-entry ledger (see `ledger`): every movement of money is an entry whose
+-- postings sum to zero, debits positive and credits negative, in cents. The entries and
+-- their postings are never updated nor deleted; a mistake is undone by another entry
+CREATE TABLE ledger_accounts (
+    id SERIAL PRIMARY KEY,
+    -- promotions, refunds, or credit:<user key> for the credit of a user
+    name TEXT NOT NULL UNIQUE,
+    kind TEXT NOT NULL CHECK (kind IN ('asset', 'liability', 'equity', 'revenue', 'expense')),
+    -- The owner of a credit account; the account and its postings outlive the user
+    user_id INTEGER UNIQUE REFERENCES users (id) ON DELETE SET NULL,
+    -- Sum of the postings, updated in the transaction of each entry
+    balance BIGINT NOT NULL DEFAULT 0,
+    -- Whether the balance may go below zero on the side the account grows on (debits
+    -- for the assets and expenses, credits for the others)
+    may_overdraw BOOLEAN NOT NULL DEFAULT true,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    CONSTRAINT ledger_accounts_overdraw_check CHECK (
+        may_overdraw
+        OR CASE WHEN kind IN ('asset', 'expense') THEN balance ELSE -balance END >= 0
+    )
+);
+
+CREATE TABLE ledger_entries (
+    id BIGSERIAL PRIMARY KEY,
+    public_id UUID NOT NULL UNIQUE,
+    -- credit (granted or taken back) or refund
+    kind TEXT NOT NULL,
+    description TEXT NOT NULL,
+    -- The order refunded; no foreign key, the orders go with their user and the entry
+    -- stays
+    order_id INTEGER,
+    actor_id INTEGER,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- An order is refunded once
+CREATE UNIQUE INDEX ledger_entries_refund_idx ON ledger_entries (order_id) WHERE kind = 'refund';
+
+CREATE TABLE ledger_postings (
+    id BIGSERIAL PRIMARY KEY,
+    entry_id BIGINT NOT NULL REFERENCES ledger_entries (id),
+    account_id INTEGER NOT NULL REFERENCES ledger_accounts (id),
+    amount BIGINT NOT NULL CHECK (amount <> 0)
+);
+
+CREATE INDEX ledger_postings_entry_id_idx ON ledger_postings (entry_id);
+CREATE INDEX ledger_postings_account_id_idx ON ledger_postings (account_id, entry_id);
+
+-- Checked at commit, once every posting of the entry is inserted
+CREATE FUNCTION check_ledger_entry() RETURNS trigger AS $$
+BEGIN
+    IF (SELECT sum(amount) FROM ledger_postings WHERE entry_id = NEW.entry_id) <> 0 THEN
+        RAISE EXCEPTION 'Ledger entry % is unbalanced', NEW.entry_id
+            USING ERRCODE = 'check_violation';
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE CONSTRAINT TRIGGER ledger_postings_balanced
+    AFTER INSERT ON ledger_postings
+    DEFERRABLE INITIALLY DEFERRED
+    FOR EACH ROW EXECUTE FUNCTION check_ledger_entry();
+
+CREATE FUNCTION refuse_ledger_change() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'The ledger is append-only, post a correcting entry instead'
+        USING ERRCODE = 'insufficient_privilege';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER ledger_entries_append_only
+    BEFORE UPDATE OR DELETE ON ledger_entries
+    FOR EACH ROW EXECUTE FUNCTION refuse_ledger_change();
+CREATE TRIGGER ledger_postings_append_only
+    BEFORE UPDATE OR DELETE ON ledger_postings
+    FOR EACH ROW EXECUTE FUNCTION refuse_ledger_change();
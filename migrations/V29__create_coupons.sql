@@ -0,0 +1,41 @@
+-- Coupons (see `repository::coupons`): codes taking a percentage or a fixed amount
+-- off an order, until they expire or run out, and the record of every redemption
+CREATE TABLE coupons (
+    id SERIAL PRIMARY KEY,
+    public_id UUID NOT NULL UNIQUE,
+    -- Upper case, the codes are typed in any case
+    code TEXT NOT NULL UNIQUE,
+    percent_off INTEGER CHECK (percent_off BETWEEN 1 AND 100),
+    -- In cents
+    amount_off BIGINT CHECK (amount_off > 0),
+    -- Subtotal an order needs for the coupon to apply, in cents
+    min_order_amount BIGINT NOT NULL DEFAULT 0 CHECK (min_order_amount >= 0),
+    expires_at TIMESTAMPTZ,
+    -- Redemptions allowed in all, and by each user; NULL for no limit
+    max_redemptions INTEGER CHECK (max_redemptions > 0),
+    max_per_user INTEGER CHECK (max_per_user > 0),
+    -- Counted in the transaction of each order, never past the limit
+    redemptions INTEGER NOT NULL DEFAULT 0
+        CHECK (max_redemptions IS NULL OR redemptions <= max_redemptions),
+    active BOOLEAN NOT NULL DEFAULT true,
+    created_by INTEGER,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    CONSTRAINT coupons_discount_check CHECK ((percent_off IS NULL) <> (amount_off IS NULL))
+);
+
+-- No foreign keys: the record stays when the order goes with its user
+CREATE TABLE coupon_redemptions (
+    id BIGSERIAL PRIMARY KEY,
+    coupon_id INTEGER NOT NULL REFERENCES coupons (id),
+    user_id INTEGER NOT NULL,
+    order_id INTEGER NOT NULL,
+    -- In cents
+    discount BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX coupon_redemptions_coupon_id_idx ON coupon_redemptions (coupon_id, user_id);
+
+-- The discount of an order, in cents, taken off quantity * unit_price
+ALTER TABLE orders ADD COLUMN discount BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN coupon_id INTEGER REFERENCES coupons (id);
@@ -0,0 +1,30 @@
+-- Carts and wishlists (see `carts`). A cart belongs to a user, or to the holder of the
+-- `cart` cookie until they log in, its items then merged into the cart of the user
+CREATE TABLE carts (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER UNIQUE REFERENCES users (id) ON DELETE CASCADE,
+    -- SHA-256 of the cookie of an anonymous cart, never the cookie itself
+    token_digest BYTEA UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- Changed by every write to the items, the carts left since are removed by the
+    -- `carts` retention rules
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    CONSTRAINT carts_owner_check CHECK ((user_id IS NULL) <> (token_digest IS NULL))
+);
+
+CREATE INDEX carts_updated_at_idx ON carts (updated_at);
+
+CREATE TABLE cart_items (
+    cart_id INTEGER NOT NULL REFERENCES carts (id) ON DELETE CASCADE,
+    product_id INTEGER NOT NULL REFERENCES products (id) ON DELETE CASCADE,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    added_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (cart_id, product_id)
+);
+
+CREATE TABLE wishlist_items (
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    product_id INTEGER NOT NULL REFERENCES products (id) ON DELETE CASCADE,
+    added_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, product_id)
+);
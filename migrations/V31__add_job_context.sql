@@ -0,0 +1,5 @@
+-- Request a job was enqueued for, and its caller (see `context`): the job runs with
+-- them, so its logs and its audit records name them too
+ALTER TABLE jobs
+    ADD COLUMN request_id TEXT,
+    ADD COLUMN actor_id INTEGER;
@@ -0,0 +1,27 @@
+-- UUIDs of the caller and of the changed user or product, sent in place of their keys
+-- by the audit log and the change feeds (see `repository::ids`). Empty for the orders,
+-- and for the callers and entities deleted before their UUID could be found
+ALTER TABLE audit_log
+    ADD COLUMN actor_public_id UUID,
+    ADD COLUMN entity_public_id UUID;
+
+UPDATE audit_log a SET actor_public_id = COALESCE(
+    (SELECT public_id FROM users WHERE id = a.actor_id),
+    (SELECT public_id FROM user_tombstones WHERE id = a.actor_id)
+)
+WHERE a.actor_id IS NOT NULL;
+
+UPDATE audit_log a SET entity_public_id = u.public_id
+FROM users u WHERE a.entity = 'user' AND u.id = a.entity_id;
+
+UPDATE audit_log a SET entity_public_id = p.public_id
+FROM products p WHERE a.entity = 'product' AND p.id = a.entity_id;
+
+-- The deleted ones, from the record of their delete
+UPDATE audit_log a SET entity_public_id = (d.changes->'id'->>'old')::uuid
+FROM audit_log d
+WHERE a.entity_public_id IS NULL AND a.entity IN ('user', 'product')
+    AND d.entity = a.entity AND d.entity_id = a.entity_id AND d.action = 'delete'
+    AND d.changes->'id'->>'old' ~ '^[0-9a-f-]{36}$';
+
+CREATE INDEX audit_log_entity_public_id_idx ON audit_log (entity, entity_public_id, id);
@@ -0,0 +1,4 @@
+-- Columns used by the authentication routes
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS email TEXT UNIQUE,
+    ADD COLUMN IF NOT EXISTS password_hash TEXT;
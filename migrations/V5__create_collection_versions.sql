@@ -0,0 +1,24 @@
+-- Version counter of each collection, bumped by every statement writing to it.
+-- Lets list endpoints build an ETag without reading the rows.
+CREATE TABLE collection_versions (
+    name TEXT PRIMARY KEY,
+    version BIGINT NOT NULL DEFAULT 0
+);
+
+INSERT INTO collection_versions (name) VALUES ('users'), ('products');
+
+CREATE FUNCTION bump_collection_version() RETURNS trigger AS $$
+BEGIN
+    UPDATE collection_versions SET version = version + 1 WHERE name = TG_TABLE_NAME;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Statement-level: a multi-row write bumps the version once
+CREATE TRIGGER users_bump_version
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON users
+    FOR EACH STATEMENT EXECUTE FUNCTION bump_collection_version();
+
+CREATE TRIGGER products_bump_version
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON products
+    FOR EACH STATEMENT EXECUTE FUNCTION bump_collection_version();
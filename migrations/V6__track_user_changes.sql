@@ -0,0 +1,46 @@
+-- Change tracking for delta responses (GET /users?modified_since=).
+-- Every insert, update and delete of a user takes the next value of a shared sequence,
+-- so a client can ask for everything that changed after the last version it saw.
+CREATE SEQUENCE user_change_version_seq;
+
+-- Existing rows get distinct versions when the columns are added
+ALTER TABLE users
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN change_version BIGINT NOT NULL DEFAULT nextval('user_change_version_seq');
+
+CREATE INDEX users_change_version_idx ON users (change_version);
+CREATE INDEX users_updated_at_idx ON users (updated_at);
+
+-- Deleted users, so polling clients can remove them too
+CREATE TABLE user_tombstones (
+    id INT PRIMARY KEY,
+    deleted_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    change_version BIGINT NOT NULL DEFAULT nextval('user_change_version_seq')
+);
+
+CREATE INDEX user_tombstones_change_version_idx ON user_tombstones (change_version);
+CREATE INDEX user_tombstones_deleted_at_idx ON user_tombstones (deleted_at);
+
+CREATE FUNCTION track_user_change() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        INSERT INTO user_tombstones (id) VALUES (OLD.id)
+        ON CONFLICT (id) DO UPDATE
+            SET deleted_at = now(), change_version = nextval('user_change_version_seq');
+        RETURN OLD;
+    END IF;
+
+    NEW.updated_at := now();
+    NEW.change_version := nextval('user_change_version_seq');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Inserts get both columns from their defaults
+CREATE TRIGGER users_track_update
+    BEFORE UPDATE ON users
+    FOR EACH ROW EXECUTE FUNCTION track_user_change();
+
+CREATE TRIGGER users_track_delete
+    AFTER DELETE ON users
+    FOR EACH ROW EXECUTE FUNCTION track_user_change();
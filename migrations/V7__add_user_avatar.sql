@@ -0,0 +1,2 @@
+-- Location of the avatar in the file storage (POST /users/{id}/avatar), NULL without one
+ALTER TABLE users ADD COLUMN avatar_path TEXT;
@@ -0,0 +1,20 @@
+-- Background jobs (see the `jobs` module): one row per enqueued job, updated with
+-- its outcome so failures can be retried and inspected.
+CREATE TABLE jobs (
+    id BIGSERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    -- queued, running, succeeded or failed
+    status TEXT NOT NULL DEFAULT 'queued',
+    attempts INT NOT NULL DEFAULT 0,
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- Earliest time a queued job runs, later than created_at when waiting for a retry
+    run_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    finished_at TIMESTAMPTZ
+);
+
+-- Queued jobs picked up at startup
+CREATE INDEX jobs_queued_idx ON jobs (run_at) WHERE status = 'queued';
+-- Cleanup of the finished jobs
+CREATE INDEX jobs_finished_at_idx ON jobs (finished_at);
@@ -0,0 +1,20 @@
+-- Audit log (see `repository::audit`): one row per insert, update or delete of a user,
+-- product or order, written in the transaction of the change.
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    -- User of the access token; NULL for registrations and background jobs.
+    -- No foreign key: the records outlive the accounts
+    actor_id INT,
+    -- insert, update or delete
+    action TEXT NOT NULL,
+    -- user, product or order
+    entity TEXT NOT NULL,
+    entity_id INT NOT NULL,
+    -- Changed fields with their old and new values: {"age": {"old": 30, "new": 31}}
+    changes JSONB NOT NULL,
+    request_id TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- History of an entity, the latest first
+CREATE INDEX audit_log_entity_idx ON audit_log (entity, entity_id, id);
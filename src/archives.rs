@@ -0,0 +1,266 @@
+//! Export of the archived partitions to the file storage.
+//!
+//! With `PARTITION_EXPORT`, each partition moved to the `archive` schema by the
+//! maintenance of the `partitions` is exported by an `export_archive` job: its rows are
+//! streamed with `COPY` as CSV with a header line, gzipped, and stored at
+//! `archives/<table>/<partition>.csv.gz` (see `storage`). The manifest of the file
+//! (rows, size, SHA-256) is then recorded in the `archives` table and the partition
+//! dropped, in one transaction: a failed export leaves the partition where it was, for
+//! the next attempt.
+//!
+//! `POST /admin/archives/:id/restore` starts a `restore_archive` job, creating the
+//! partition in the `archive` schema again from its file, after checking it against
+//! its SHA-256. The rows can then be queried there, or attached back to their table
+//! (`ALTER TABLE audit_log ATTACH PARTITION archive.audit_log_p2024_01 ...`); a
+//! restored partition is never exported again, its file is still there.
+
+use std::io::Write;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use flate2::Compression;
+use flate2::write::{GzDecoder, GzEncoder};
+use futures_util::{Stream, StreamExt, TryStreamExt, stream};
+use hyper::body::Bytes;
+use ring::digest::{Context, SHA256};
+use serde_json::json;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::db::DistributedLock;
+use crate::error::AppError;
+use crate::repository::archives::{ArchiveRepository, CsvChunks, NewArchive, PgArchiveRepo};
+use crate::repository::jobs::{JobRepository, PgJobRepo};
+use crate::storage::{ObjectStore, store};
+
+/// SHA-256 and size of the bytes going through a stream.
+struct Tally {
+    digest: Context,
+    size: u64,
+}
+
+impl Tally {
+    fn new() -> Arc<Mutex<Tally>> {
+        Arc::new(Mutex::new(Tally {
+            digest: Context::new(&SHA256),
+            size: 0,
+        }))
+    }
+
+    fn add(tally: &Mutex<Tally>, chunk: &[u8]) {
+        let mut tally = tally.lock().unwrap_or_else(|e| e.into_inner());
+        tally.digest.update(chunk);
+        tally.size += chunk.len() as u64;
+    }
+
+    /// The hex SHA-256 and the size of the bytes so far.
+    fn finish(tally: &Mutex<Tally>) -> (String, u64) {
+        let tally = tally.lock().unwrap_or_else(|e| e.into_inner());
+        let digest = tally.digest.clone().finish();
+        let hex = digest
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        (hex, tally.size)
+    }
+}
+
+/// Exports the partition `partition` of `table` from the `archive` schema as the job
+/// `id`, then drops it; nothing is done if it was exported already.
+pub async fn export(id: i64, table: &str, partition: &str) -> Result<(), AppError> {
+    let _lock = DistributedLock::acquire(&format!("archive:{}", partition)).await?;
+    let Some(rows) = PgArchiveRepo::default().count(partition).await? else {
+        info!("Partition {} already exported", partition);
+        return Ok(());
+    };
+
+    let tally = Tally::new();
+    let counted = Arc::clone(&tally);
+    let gzipped = gzip(PgArchiveRepo::default().copy_out(partition).await?)
+        .inspect_ok(move |chunk| Tally::add(&counted, chunk));
+    let key = format!("archives/{}/{}.csv.gz", table, partition);
+    let location = store().put(&key, gzipped).await?;
+    let (sha256, size) = Tally::finish(&tally);
+
+    let archive = PgArchiveRepo::default()
+        .record(&NewArchive {
+            table_name: table,
+            partition_name: partition,
+            location: &location,
+            row_count: rows,
+            byte_size: size as i64,
+            sha256: &sha256,
+        })
+        .await;
+    let archive = match archive {
+        Ok(archive) => archive,
+        Err(e) => {
+            // The partition is still there, the next attempt stores it again
+            if let Err(e) = store().delete(&location).await {
+                warn!("Unable to delete archive {}: {}", location, e);
+            }
+            return Err(e);
+        }
+    };
+    info!(
+        "Partition {} exported to {} ({} rows, {} bytes)",
+        partition, location, rows, size
+    );
+
+    PgJobRepo::default()
+        .set_progress(
+            id,
+            &json!({"archive_id": archive.id, "rows": rows, "bytes": size}),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Restores the partition of the archive `archive_id` into the `archive` schema as the
+/// job `id`; nothing is done if it's there already.
+pub async fn restore(id: i64, archive_id: Uuid) -> Result<(), AppError> {
+    let Some(archive) = PgArchiveRepo::default().find(archive_id).await? else {
+        warn!("Archive {} deleted before its restore", archive_id);
+        return Ok(());
+    };
+    let _lock = DistributedLock::acquire(&format!("archive:{}", archive.partition_name)).await?;
+    let file = store().get(&archive.location).await?.ok_or_else(|| {
+        AppError::Internal(format!("Archive file {} is missing", archive.location))
+    })?;
+
+    let csv = gunzip(file.chunks, archive.sha256.clone());
+    let (columns, csv) = header(Box::pin(csv)).await?;
+    let restored = PgArchiveRepo::default()
+        .restore(&archive, &columns, csv)
+        .await?;
+    if restored {
+        info!(
+            "Partition {} restored from {}",
+            archive.partition_name, archive.location
+        );
+    }
+
+    PgJobRepo::default()
+        .set_progress(
+            id,
+            &json!({"partition": format!("archive.{}", archive.partition_name), "restored": restored}),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Reads the header line of a CSV stream.
+///
+/// # Returns
+///
+/// * `Result<(Vec<String>, CsvChunks), AppError>` - The column names, and the whole
+///   stream again, header included
+async fn header(mut csv: CsvChunks) -> Result<(Vec<String>, CsvChunks), AppError> {
+    let mut read = Vec::new();
+    let line = loop {
+        if let Some(end) = read.iter().position(|&byte| byte == b'\n') {
+            break String::from_utf8_lossy(&read[..end]).into_owned();
+        }
+        match csv.next().await {
+            Some(chunk) => read.extend_from_slice(&chunk?),
+            None => break String::from_utf8_lossy(&read).into_owned(),
+        }
+    };
+
+    // Plain names, as `COPY` writes the columns of the tables
+    let columns = line.trim_end().split(',').map(str::to_string);
+    let columns = columns.collect::<Vec<_>>();
+    let valid = columns.iter().all(|column| {
+        !column.is_empty()
+            && column
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    });
+    if !valid {
+        return Err(AppError::Internal(format!(
+            "Invalid header in the archive file: {}",
+            line
+        )));
+    }
+    let csv = stream::once(async { Ok(Bytes::from(read)) }).chain(csv);
+    Ok((columns, Box::pin(csv)))
+}
+
+/// Compresses a stream with gzip.
+fn gzip(chunks: CsvChunks) -> impl Stream<Item = Result<Bytes, AppError>> + Send {
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    stream::unfold(Some((chunks, encoder)), async |state| {
+        let (mut chunks, mut encoder) = state?;
+        loop {
+            match chunks.next().await {
+                Some(Ok(chunk)) => {
+                    if let Err(e) = encoder.write_all(&chunk) {
+                        return Some((Err(compression_error(e)), None));
+                    }
+                    // The encoder buffers its output, emitted once there is some
+                    if !encoder.get_ref().is_empty() {
+                        let out = Bytes::from(mem::take(encoder.get_mut()));
+                        return Some((Ok(out), Some((chunks, encoder))));
+                    }
+                }
+                Some(Err(e)) => return Some((Err(e), None)),
+                None => {
+                    let out = encoder.finish().map(Bytes::from);
+                    return Some((out.map_err(compression_error), None));
+                }
+            }
+        }
+    })
+}
+
+/// Decompresses a gzipped stream, failing at its end unless its SHA-256 is `sha256`.
+fn gunzip<S>(
+    chunks: S,
+    sha256: String,
+) -> impl Stream<Item = Result<Bytes, AppError>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, AppError>> + Send + Unpin + 'static,
+{
+    let tally = Tally::new();
+    let decoder = GzDecoder::new(Vec::new());
+    stream::unfold(Some((chunks, decoder)), move |state| {
+        let tally = Arc::clone(&tally);
+        let sha256 = sha256.clone();
+        async move {
+            let (mut chunks, mut decoder) = state?;
+            loop {
+                match chunks.next().await {
+                    Some(Ok(chunk)) => {
+                        Tally::add(&tally, &chunk);
+                        if let Err(e) = decoder.write_all(&chunk) {
+                            return Some((Err(compression_error(e)), None));
+                        }
+                        if !decoder.get_ref().is_empty() {
+                            let out = Bytes::from(mem::take(decoder.get_mut()));
+                            return Some((Ok(out), Some((chunks, decoder))));
+                        }
+                    }
+                    Some(Err(e)) => return Some((Err(e), None)),
+                    None => {
+                        let out = match decoder.finish() {
+                            Ok(out) => out,
+                            Err(e) => return Some((Err(compression_error(e)), None)),
+                        };
+                        if Tally::finish(&tally).0 != sha256 {
+                            let e = AppError::Internal(
+                                "The archive file doesn't match its SHA-256".to_string(),
+                            );
+                            return Some((Err(e), None));
+                        }
+                        return Some((Ok(Bytes::from(out)), None));
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn compression_error(e: std::io::Error) -> AppError {
+    AppError::Internal(format!("Compression failed: {}", e))
+}
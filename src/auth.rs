@@ -0,0 +1,289 @@
+//! Authentication.
+//!
+//! Passwords are hashed with Argon2 and clients authenticate with JWT access tokens
+//! (HS256, signed with `JWT_SECRET`) sent in the `Authorization: Bearer <token>` header.
+//!
+//! Routes registered with `Router::require_auth` are checked by the router before
+//! their handler runs; the authenticated user is then available to the handler as the
+//! caller of its `RequestContext`.
+//!
+//! The apps logging their users in with the OpenID Connect provider (see `oidc`) get
+//! client tokens instead: their `aud` is the app and their `scope` the one granted to
+//! it. They are only accepted by `/oauth/userinfo` (`authenticate_client`), never by
+//! the routes of the API.
+//!
+//! ## Configuration
+//! - `JWT_SECRET`: Secret used to sign the tokens (required, at least 32 characters)
+//! - `JWT_EXPIRATION`: Token lifetime in seconds (3600 by default)
+//!
+//! Both are loaded and validated by the `config` module.
+
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use argon2::Argon2;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use hyper::Request;
+use hyper::header::AUTHORIZATION;
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use serde::{Deserialize, Serialize};
+
+use crate::config::AuthConfig;
+use crate::error::AppError;
+
+// Static global variable to store the signing keys
+// Initialized once at startup from the configuration
+static JWT_KEYS: OnceLock<JwtKeys> = OnceLock::new();
+
+struct JwtKeys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    lifetime_secs: u64,
+}
+
+/// Claims stored in the access tokens.
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    /// ID of the user the token was issued to
+    sub: String,
+    /// Issued at (seconds since the Unix epoch)
+    iat: u64,
+    /// Expiration (seconds since the Unix epoch)
+    exp: u64,
+    /// App a client token was issued to, none for the tokens of the API
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aud: Option<String>,
+    /// Scopes granted to the app of a client token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+}
+
+/// The authenticated caller of a protected route.
+///
+/// Set in the `RequestContext` of the request by the router, read by handlers with
+/// `RequestContext::of(&req).caller()`.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthUser {
+    pub id: i32,
+}
+
+tokio::task_local! {
+    // Caller of the protected route handled by the current task, see `AuthUser::scope`
+    static CURRENT_USER: AuthUser;
+}
+
+impl AuthUser {
+    /// Runs `future` with this user as the caller, so code without access to the
+    /// request (the audit log) can still read it with [`AuthUser::current`].
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT_USER.scope(self, future).await
+    }
+
+    /// Caller of the protected route handled by the current task, if any.
+    pub fn current() -> Option<AuthUser> {
+        CURRENT_USER.try_with(|user| *user).ok()
+    }
+}
+
+/// The user of a token accepted by `authenticate_client`.
+#[derive(Debug, Clone)]
+pub struct ClientGrant {
+    pub user: AuthUser,
+    /// Scopes granted to the app, or `None` for a token of the API, which grants all
+    pub scope: Option<String>,
+}
+
+impl ClientGrant {
+    /// Whether the token grants the scope `scope`.
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scope
+            .as_deref()
+            .is_none_or(|granted| granted.split(' ').any(|granted| granted == scope))
+    }
+}
+
+/// Access token returned by the login endpoint.
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: u64,
+}
+
+/// Loads the JWT signing keys.
+/// This function should be called at application startup.
+///
+/// # Arguments
+///
+/// * `config` - The validated authentication settings
+///
+/// # Returns
+///
+/// * `Result<(), String>` - Success or an error message if it was already initialized
+pub fn init_auth(config: &AuthConfig) -> Result<(), String> {
+    let secret = config.jwt_secret.as_bytes();
+    let keys = JwtKeys {
+        encoding: EncodingKey::from_secret(secret),
+        decoding: DecodingKey::from_secret(secret),
+        lifetime_secs: config.jwt_expiration,
+    };
+    if JWT_KEYS.set(keys).is_err() {
+        return Err("Authentication is already initialized".to_string());
+    }
+
+    Ok(())
+}
+
+fn keys() -> Result<&'static JwtKeys, AppError> {
+    JWT_KEYS
+        .get()
+        .ok_or_else(|| AppError::Internal("Authentication is not initialized".to_string()))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Issues a signed access token for a user.
+///
+/// # Arguments
+///
+/// * `user_id` - ID of the authenticated user
+pub fn issue_token(user_id: i32) -> Result<TokenResponse, AppError> {
+    sign(user_id, None, None)
+}
+
+/// Issues a client token for an app of the OpenID Connect provider, only accepted by
+/// `authenticate_client`.
+///
+/// # Arguments
+///
+/// * `user_id` - ID of the user who logged in to the app
+/// * `client_id` - The app, set as the audience of the token
+/// * `scope` - Scopes granted to the app, separated by spaces
+pub fn issue_client_token(
+    user_id: i32,
+    client_id: &str,
+    scope: &str,
+) -> Result<TokenResponse, AppError> {
+    sign(
+        user_id,
+        Some(client_id.to_string()),
+        Some(scope.to_string()),
+    )
+}
+
+fn sign(
+    user_id: i32,
+    aud: Option<String>,
+    scope: Option<String>,
+) -> Result<TokenResponse, AppError> {
+    let keys = keys()?;
+    let iat = now_secs();
+    let claims = Claims {
+        sub: user_id.to_string(),
+        iat,
+        exp: iat + keys.lifetime_secs,
+        aud,
+        scope,
+    };
+
+    let token = encode(&Header::default(), &claims, &keys.encoding)
+        .map_err(|e| AppError::Internal(format!("Unable to sign token: {}", e)))?;
+
+    Ok(TokenResponse {
+        access_token: token,
+        token_type: "Bearer",
+        expires_in: keys.lifetime_secs,
+    })
+}
+
+/// Validates the `Authorization: Bearer <token>` header of a request.
+///
+/// # Returns
+///
+/// * `Result<AuthUser, AppError>` - The authenticated user, or `AppError::Unauthorized`
+///   if the header is missing, the token is invalid or expired, or is a client token
+pub fn authenticate<B>(req: &Request<B>) -> Result<AuthUser, AppError> {
+    let grant = authenticate_client(req)?;
+    if grant.scope.is_some() {
+        return Err(AppError::Unauthorized(
+            "Client tokens are only accepted by /oauth/userinfo".to_string(),
+        ));
+    }
+
+    Ok(grant.user)
+}
+
+/// Validates the `Authorization: Bearer <token>` header of a request, accepting the
+/// client tokens of the apps as well as the tokens of the API.
+///
+/// # Returns
+///
+/// * `Result<ClientGrant, AppError>` - The authenticated user and the scopes of the
+///   token, or `AppError::Unauthorized` if the header is missing or the token is
+///   invalid or expired
+pub fn authenticate_client<B>(req: &Request<B>) -> Result<ClientGrant, AppError> {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| AppError::Unauthorized("Missing bearer token".to_string()))?;
+
+    let keys = keys()?;
+    // Any app: the scopes tell what the token grants
+    let mut validation = Validation::default();
+    validation.validate_aud = false;
+    let data = decode::<Claims>(token.trim(), &keys.decoding, &validation)
+        .map_err(|_| AppError::Unauthorized("Invalid or expired token".to_string()))?;
+
+    let id = data
+        .claims
+        .sub
+        .parse::<i32>()
+        .map_err(|_| AppError::Unauthorized("Invalid or expired token".to_string()))?;
+    // A client token always has a scope, even empty
+    let scope = match (data.claims.aud, data.claims.scope) {
+        (Some(_), scope) => Some(scope.unwrap_or_default()),
+        (None, scope) => scope,
+    };
+
+    Ok(ClientGrant {
+        user: AuthUser { id },
+        scope,
+    })
+}
+
+/// Hashes a password with Argon2 and a random salt.
+///
+/// Hashing is deliberately slow, so it runs on the blocking thread pool
+/// instead of stalling the async workers.
+pub async fn hash_password(password: String) -> Result<String, AppError> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| AppError::Internal(format!("Unable to hash password: {}", e)))
+    })
+    .await
+    .map_err(|e| AppError::Internal(format!("Password hashing task failed: {}", e)))?
+}
+
+/// Checks a password against a hash produced by `hash_password`.
+pub async fn verify_password(password: String, hash: String) -> Result<bool, AppError> {
+    tokio::task::spawn_blocking(move || {
+        let parsed = PasswordHash::new(&hash)
+            .map_err(|e| AppError::Internal(format!("Invalid password hash: {}", e)))?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok())
+    })
+    .await
+    .map_err(|e| AppError::Internal(format!("Password verification task failed: {}", e)))?
+}
@@ -0,0 +1,172 @@
+//! In-process cache of entity responses (`GET /users/{id}`, `GET /products/{id}`).
+//!
+//! Enabled with `RESPONSE_CACHE_TTL`: the JSON body of an entity is kept for that
+//! many seconds, up to `RESPONSE_CACHE_CAPACITY` entities (the least recently used
+//! are evicted first), so repeated reads don't reach PostgreSQL.
+//!
+//! An entity is removed from the cache as soon as a change to it is published (see
+//! `events::publish`, called by every write handler). The cache only lives in the
+//! memory of this instance: a change made through another instance removes the entity
+//! once its event arrives through the database, or when the entry expires if the
+//! event was lost, so the TTL bounds how stale a response can be.
+//!
+//! With a read replica, an entity loaded from a replica that hasn't replayed a change
+//! yet is cached as it was before. Requests carrying a consistency token skip the
+//! cached entry and load it again (see `db::consistency`).
+//!
+//! Whether the cache is enabled or not, the body comes with its ETag, the version of
+//! the row, so clients sending `If-None-Match` get 304 Not Modified and writes can be
+//! conditioned on it (see `router::conditional`).
+
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use hyper::{
+    Response, StatusCode,
+    header::{CONTENT_TYPE, HeaderValue},
+};
+use lru::LruCache;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::config::CacheConfig;
+use crate::db::Reads;
+use crate::error::AppError;
+use crate::events::Collection;
+use crate::router::Body;
+use crate::router::conditional::{Versioned, version_etag, with_etag};
+
+// Set once at startup, unset when the cache is disabled
+static CACHE: OnceLock<ResponseCache> = OnceLock::new();
+
+// Bumped by every invalidation: an entity loaded while a change was published may be
+// the old version, it isn't cached
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+struct ResponseCache {
+    entries: Mutex<LruCache<(Collection, i32), Entry>>,
+    ttl: Duration,
+}
+
+struct Entry {
+    json: CachedJson,
+    expires: Instant,
+}
+
+/// JSON body of an entity, with its ETag.
+#[derive(Debug, Clone)]
+pub struct CachedJson {
+    pub body: String,
+    pub etag: HeaderValue,
+}
+
+impl CachedJson {
+    /// 200 OK response with the body and its `ETag`.
+    pub fn into_response(self) -> Response<Body> {
+        let res = Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(self.body))
+            .unwrap();
+        with_etag(res, &self.etag)
+    }
+}
+
+impl ResponseCache {
+    fn entries(&self) -> std::sync::MutexGuard<'_, LruCache<(Collection, i32), Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Returns the JSON of an entity from the cache, or loads it and caches it.
+///
+/// # Arguments
+///
+/// * `collection` - Collection of the entity
+/// * `id` - ID of the entity
+/// * `load` - Reads the entity from the database, only awaited on a cache miss
+///
+/// # Returns
+///
+/// * `Result<Option<CachedJson>, AppError>` - The JSON body, `None` if the entity
+///   doesn't exist (which isn't cached), or the error of `load`
+pub async fn get_or_load<T, F>(
+    collection: Collection,
+    id: i32,
+    load: F,
+) -> Result<Option<CachedJson>, AppError>
+where
+    T: Serialize + Versioned,
+    F: Future<Output = Result<Option<T>, AppError>>,
+{
+    let cache = CACHE.get();
+    // The entry may predate the write the request must see
+    let fresh = matches!(Reads::current(), Reads::ReplicaAfter(_));
+    if let Some(cache) = cache
+        && !fresh
+    {
+        let mut entries = cache.entries();
+        match entries.get(&(collection, id)) {
+            Some(entry) if entry.expires > Instant::now() => return Ok(Some(entry.json.clone())),
+            Some(_) => {
+                entries.pop(&(collection, id));
+            }
+            None => {}
+        }
+    }
+
+    let generation = GENERATION.load(Ordering::Acquire);
+    let Some(entity) = load.await? else {
+        return Ok(None);
+    };
+    let body = serde_json::to_string(&entity)
+        .map_err(|e| AppError::Internal(format!("Serialization failed: {}", e)))?;
+    let json = CachedJson {
+        etag: version_etag(entity.version()),
+        body,
+    };
+
+    if let Some(cache) = cache {
+        let mut entries = cache.entries();
+        if GENERATION.load(Ordering::Acquire) == generation {
+            let entry = Entry {
+                json: json.clone(),
+                expires: Instant::now() + cache.ttl,
+            };
+            entries.put((collection, id), entry);
+        }
+    }
+    Ok(Some(json))
+}
+
+/// Removes an entity from the cache, after it changed.
+pub fn invalidate(collection: Collection, id: i32) {
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+    if let Some(cache) = CACHE.get() {
+        cache.entries().pop(&(collection, id));
+    }
+}
+
+/// Enables the cache when `RESPONSE_CACHE_TTL` is set.
+/// This function should be called at application startup.
+pub fn init_cache(config: &CacheConfig) {
+    let (Some(ttl), Some(capacity)) = (config.ttl, NonZeroUsize::new(config.capacity)) else {
+        return;
+    };
+
+    let cache = ResponseCache {
+        entries: Mutex::new(LruCache::new(capacity)),
+        ttl,
+    };
+    if CACHE.set(cache).is_err() {
+        warn!("Attempt to reset the response cache ignored");
+        return;
+    }
+    info!(
+        "Response cache enabled ({} entries, {}s)",
+        capacity,
+        ttl.as_secs()
+    );
+}
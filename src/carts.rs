@@ -0,0 +1,204 @@
+//! Carts and wishlists.
+//!
+//! ## Owners
+//! A request with an access token uses the cart of its user. Without one, it uses the
+//! cart of its `cart` cookie, set with the first item added: a random token, of which
+//! only the SHA-256 is stored, so the table can't be used to take over a cart. The
+//! cookie carries the HMAC-SHA256 of the token too, with a key derived from
+//! `JWT_SECRET`: a token the server didn't issue, one a third party planted in the
+//! browser to see the cart later for instance, is ignored, and the next item added
+//! starts a new cart with a new token. The logins (`POST /auth/login`, `POST /auth/passkeys/login`) merge the cart of the cookie
+//! into the cart of the user, adding up the quantities, and clear the cookie.
+//!
+//! ## Checkout
+//! `POST /cart/checkout` places an order of each item at the current price, in one
+//! transaction, and empties the cart: a product without enough stock fails the whole
+//! checkout. The wishlists only keep products for later, they are never ordered.
+//!
+//! Carts nobody changed for a while are removed by the retention rules of the `carts`
+//! target (see `retention`), anonymous or not.
+
+use std::sync::OnceLock;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hyper::Request;
+use hyper::header::{AUTHORIZATION, COOKIE, HeaderMap, HeaderValue};
+use ring::digest::{SHA256, digest};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use tracing::{info, warn};
+
+use crate::auth::authenticate;
+use crate::config::AuthConfig;
+use crate::db::Db;
+use crate::error::AppError;
+use crate::forwarded::{ClientInfo, Scheme};
+use crate::repository::carts::{CartRepository, Owner, PgCartRepo};
+
+/// Name of the cookie of the anonymous carts
+const COOKIE_NAME: &str = "cart";
+
+/// Random bytes of the token of an anonymous cart
+const TOKEN_LEN: usize = 32;
+
+/// Lifetime of the cookie, renewed by every change of the cart
+const COOKIE_MAX_AGE_SECS: u64 = 30 * 24 * 3600;
+
+// Set once at startup
+static KEY: OnceLock<hmac::Key> = OnceLock::new();
+
+/// Derives the key of the cart tokens from `JWT_SECRET`.
+/// This function should be called once at application startup.
+pub fn init_carts(config: &AuthConfig) -> Result<(), String> {
+    // A key of its own, as the signed URLs
+    let secret = hmac::Key::new(hmac::HMAC_SHA256, config.jwt_secret.as_bytes());
+    let key = hmac::sign(&secret, b"cart-tokens");
+    KEY.set(hmac::Key::new(hmac::HMAC_SHA256, key.as_ref()))
+        .map_err(|_| "Carts are already initialized".to_string())
+}
+
+fn key() -> Result<&'static hmac::Key, AppError> {
+    KEY.get()
+        .ok_or_else(|| AppError::Internal("Carts are not initialized".to_string()))
+}
+
+/// The owner of the cart of a request: the user of its access token, or the holder of
+/// its `cart` cookie; `None` without either, or with a cookie the server didn't issue.
+///
+/// Fails with `AppError::Unauthorized` if the access token is invalid, rather than
+/// falling back to the cookie.
+pub fn owner<B>(req: &Request<B>) -> Result<Option<Owner>, AppError> {
+    if req.headers().contains_key(AUTHORIZATION) {
+        return Ok(Some(Owner::User(authenticate(req)?.id)));
+    }
+    let key = key()?;
+    Ok(cookie_token(req.headers())
+        .and_then(|cookie| verified(key, cookie))
+        .map(|token| Owner::Anonymous(token_digest(token))))
+}
+
+/// A new anonymous cart: its owner, and the `Set-Cookie` giving its token to the
+/// client.
+pub fn new_anonymous<B>(req: &Request<B>) -> Result<(Owner, HeaderValue), AppError> {
+    let mut bytes = [0; TOKEN_LEN];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| AppError::Internal("No random bytes for the cart token".to_string()))?;
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    let owner = Owner::Anonymous(token_digest(&token));
+    Ok((owner, set_cookie(req, &signed(key()?, &token))))
+}
+
+/// The `Set-Cookie` renewing the cookie of the cart of a request, if it has a valid
+/// one.
+pub fn renew_cookie<B>(req: &Request<B>) -> Option<HeaderValue> {
+    if req.headers().contains_key(AUTHORIZATION) {
+        return None;
+    }
+    let key = KEY.get()?;
+    cookie_token(req.headers())
+        .filter(|cookie| verified(key, cookie).is_some())
+        .map(|cookie| set_cookie(req, cookie))
+}
+
+/// Merges the anonymous cart of a login request into the cart of the user.
+///
+/// The login is valid even if the merge fails: the anonymous cart is then kept, only
+/// logged.
+///
+/// # Returns
+///
+/// * `Option<HeaderValue>` - The `Set-Cookie` clearing the cookie, `None` without one
+pub async fn merge_on_login(db: Db, headers: &HeaderMap, user_id: i32) -> Option<HeaderValue> {
+    let token = verified(KEY.get()?, cookie_token(headers)?)?;
+    match PgCartRepo(db).merge(&token_digest(token), user_id).await {
+        Ok(0) => {}
+        Ok(merged) => info!(
+            "{} products of an anonymous cart merged for user {}",
+            merged, user_id
+        ),
+        Err(e) => {
+            warn!("Anonymous cart not merged for user {}: {}", user_id, e);
+            return None;
+        }
+    }
+    Some(HeaderValue::from_static(
+        "cart=; Path=/api/v1; Max-Age=0; HttpOnly; SameSite=Lax",
+    ))
+}
+
+/// The token of the `cart` cookie among the `Cookie` headers.
+fn cookie_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, value)| *name == COOKIE_NAME && !value.is_empty())
+        .map(|(_, value)| value)
+}
+
+/// The cookie of a token: the token and its signature, separated by a dot.
+fn signed(key: &hmac::Key, token: &str) -> String {
+    let signature = URL_SAFE_NO_PAD.encode(hmac::sign(key, token.as_bytes()));
+    format!("{}.{}", token, signature)
+}
+
+/// The token of a cookie, if its signature is valid.
+fn verified<'a>(key: &hmac::Key, cookie: &'a str) -> Option<&'a str> {
+    let (token, signature) = cookie.split_once('.')?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    hmac::verify(key, token.as_bytes(), &signature).ok()?;
+    Some(token)
+}
+
+fn token_digest(token: &str) -> Vec<u8> {
+    digest(&SHA256, token.as_bytes()).as_ref().to_vec()
+}
+
+/// The `Set-Cookie` of a token, `Secure` when the client uses HTTPS.
+fn set_cookie<B>(req: &Request<B>, token: &str) -> HeaderValue {
+    let secure = ClientInfo::of(req).is_some_and(|client| client.scheme == Scheme::Https);
+    let cookie = format!(
+        "{}={}; Path=/api/v1; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        COOKIE_NAME,
+        token,
+        COOKIE_MAX_AGE_SECS,
+        if secure { "; Secure" } else { "" }
+    );
+    // base64url, or read from a header value: always a valid header value
+    HeaderValue::from_str(&cookie).expect("cart cookie")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_cart_cookie_among_others() {
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, HeaderValue::from_static("theme=dark; cart=abc_-1"));
+        headers.append(COOKIE, HeaderValue::from_static("lang=fr"));
+        assert_eq!(cookie_token(&headers), Some("abc_-1"));
+
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, HeaderValue::from_static("carts=x; cart="));
+        assert_eq!(cookie_token(&headers), None);
+    }
+
+    #[test]
+    fn only_the_signed_tokens_are_accepted() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"test key");
+        let cookie = signed(&key, "abc_-1");
+        assert_eq!(verified(&key, &cookie), Some("abc_-1"));
+
+        // Planted by a third party, signed with another key, or altered
+        assert_eq!(verified(&key, "abc_-1"), None);
+        let other = hmac::Key::new(hmac::HMAC_SHA256, b"other key");
+        assert_eq!(verified(&key, &signed(&other, "abc_-1")), None);
+        let (_, signature) = cookie.split_once('.').unwrap();
+        assert_eq!(verified(&key, &format!("abc_-2.{}", signature)), None);
+    }
+}
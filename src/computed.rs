@@ -0,0 +1,328 @@
+//! Computed fields of the responses (`COMPUTED_FIELDS_PATH`), for integrators who need
+//! a value the API doesn't send and can't change their clients.
+//!
+//! ## Configuration
+//! The fields are read from the JSON file pointed to by `COMPUTED_FIELDS_PATH` at
+//! startup, by resource, each an expression of the fields of the resource (see
+//! `expr`):
+//!
+//! ```json
+//! {
+//!   "users": {"full_label": "name + \" (\" + age + \")\""},
+//!   "products": {
+//!     "in_stock": "stock > 0",
+//!     "price_with_vat": "round(price * 1.2, 2)"
+//!   },
+//!   "orders": {"total": "round(quantity * unit_price, 2)"}
+//! }
+//! ```
+//!
+//! A field can read the ones defined before it in its resource. The expressions, the
+//! fields they read and the names of the new fields, which can't replace a field of
+//! the resource, are all checked at startup.
+//!
+//! ## Responses
+//! The fields are added to the users, products and orders of the responses: reads,
+//! lists (paged and streamed) and the responses of the writes. The deltas of
+//! `modified_since`, the events, the webhooks and the exports carry the stored fields
+//! only. A field whose expression fails on a resource, dividing by a zero stock for
+//! instance, is `null` in its response.
+
+mod expr;
+
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use serde::ser::Error as _;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{Map, Value};
+use tracing::{debug, warn};
+
+use crate::router::conditional::Versioned;
+
+use expr::Expr;
+
+// Set once at startup, empty without COMPUTED_FIELDS_PATH
+static FIELDS: OnceLock<ComputedFields> = OnceLock::new();
+
+/// Resources with computed fields.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resource {
+    Users,
+    Products,
+    Orders,
+}
+
+impl Resource {
+    /// Fields of the resource in the responses, which the expressions read.
+    fn fields(self) -> &'static [&'static str] {
+        match self {
+            Resource::Users => &["id", "name", "age"],
+            Resource::Products => &["id", "name", "price", "stock"],
+            Resource::Orders => &[
+                "id",
+                "user_id",
+                "product_id",
+                "quantity",
+                "unit_price",
+                "discount",
+            ],
+        }
+    }
+}
+
+/// The configuration file: expressions by name, in order, for each resource.
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FieldsFile {
+    #[serde(default)]
+    users: Map<String, Value>,
+    #[serde(default)]
+    products: Map<String, Value>,
+    #[serde(default)]
+    orders: Map<String, Value>,
+}
+
+/// A computed field, with its parsed expression.
+#[derive(Debug)]
+struct ComputedField {
+    name: String,
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Default)]
+struct ComputedFields {
+    users: Vec<ComputedField>,
+    products: Vec<ComputedField>,
+    orders: Vec<ComputedField>,
+}
+
+impl ComputedFields {
+    fn of(&self, resource: Resource) -> &[ComputedField] {
+        match resource {
+            Resource::Users => &self.users,
+            Resource::Products => &self.products,
+            Resource::Orders => &self.orders,
+        }
+    }
+}
+
+/// Parses the fields of `resource`, named `label` in the errors.
+fn parse_fields(
+    resource: Resource,
+    label: &str,
+    definitions: Map<String, Value>,
+) -> Result<Vec<ComputedField>, String> {
+    let mut fields: Vec<ComputedField> = Vec::with_capacity(definitions.len());
+    for (name, source) in definitions {
+        let valid = name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid {
+            return Err(format!(
+                "{}.{}: names are lowercase letters, digits and _",
+                label, name
+            ));
+        }
+        if resource.fields().contains(&name.as_str()) {
+            return Err(format!(
+                "{}.{}: a field of the resource can't be replaced",
+                label, name
+            ));
+        }
+        let Value::String(source) = source else {
+            return Err(format!(
+                "{}.{}: the expression must be a string",
+                label, name
+            ));
+        };
+        let expr = Expr::parse(&source).map_err(|e| format!("{}.{}: {}", label, name, e))?;
+        for field in expr.fields() {
+            let known = resource.fields().contains(&field)
+                || fields.iter().any(|computed| computed.name == field);
+            if !known {
+                return Err(format!(
+                    "{}.{}: unknown field '{}', the expressions read {} and the fields \
+                     computed before",
+                    label,
+                    name,
+                    field,
+                    resource.fields().join(", ")
+                ));
+            }
+        }
+        fields.push(ComputedField { name, source, expr });
+    }
+    Ok(fields)
+}
+
+/// Loads the computed fields from `COMPUTED_FIELDS_PATH`.
+/// This function should be called once at application startup.
+///
+/// # Arguments
+///
+/// * `path` - Path of the JSON file of the fields, `None` when no field is computed
+///
+/// # Returns
+///
+/// * `Result<usize, String>` - Number of fields, or an error message
+pub fn init_computed_fields(path: Option<&Path>) -> Result<usize, String> {
+    let file: FieldsFile = match path {
+        Some(path) => {
+            let content = fs::read_to_string(path).map_err(|e| {
+                format!("Unable to read computed fields '{}': {}", path.display(), e)
+            })?;
+            serde_json::from_str(&content)
+                .map_err(|e| format!("Invalid computed fields '{}': {}", path.display(), e))?
+        }
+        None => FieldsFile::default(),
+    };
+    let fields = ComputedFields {
+        users: parse_fields(Resource::Users, "users", file.users)?,
+        products: parse_fields(Resource::Products, "products", file.products)?,
+        orders: parse_fields(Resource::Orders, "orders", file.orders)?,
+    };
+
+    let count = fields.users.len() + fields.products.len() + fields.orders.len();
+    if FIELDS.set(fields).is_err() {
+        warn!("Attempt to reload the computed fields ignored");
+    }
+    Ok(count)
+}
+
+/// Names and expressions of the computed fields of `resource`, in order.
+pub fn fields(resource: Resource) -> Vec<(&'static str, &'static str)> {
+    FIELDS.get().map_or_else(Vec::new, |fields| {
+        fields
+            .of(resource)
+            .iter()
+            .map(|field| (field.name.as_str(), field.source.as_str()))
+            .collect()
+    })
+}
+
+/// Adds the computed fields of `resource` to `object`, in order.
+fn extend(resource: Resource, fields: &[ComputedField], object: &mut Map<String, Value>) {
+    for field in fields {
+        let value = field.expr.eval(object).unwrap_or_else(|e| {
+            debug!("Computed field {:?}.{} failed: {}", resource, field.name, e);
+            Value::Null
+        });
+        object.insert(field.name.clone(), value);
+    }
+}
+
+/// A resource serialized with its computed fields.
+#[derive(Debug, Clone)]
+pub struct WithComputed<T> {
+    resource: Resource,
+    entity: T,
+}
+
+impl<T: Serialize> Serialize for WithComputed<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let fields = FIELDS
+            .get()
+            .map_or(&[][..], |fields| fields.of(self.resource));
+        if fields.is_empty() {
+            return self.entity.serialize(serializer);
+        }
+        let mut value = serde_json::to_value(&self.entity).map_err(S::Error::custom)?;
+        if let Value::Object(object) = &mut value {
+            extend(self.resource, fields, object);
+        }
+        value.serialize(serializer)
+    }
+}
+
+// Cached responses keep the ETag of the entity
+impl<T: Versioned> Versioned for WithComputed<T> {
+    fn version(&self) -> i32 {
+        self.entity.version()
+    }
+}
+
+/// `entity`, a `resource`, serialized with its computed fields.
+pub fn with_fields<T>(resource: Resource, entity: T) -> WithComputed<T> {
+    WithComputed { resource, entity }
+}
+
+/// `entities`, of `resource`, serialized with their computed fields.
+pub fn all_with_fields<T>(resource: Resource, entities: Vec<T>) -> Vec<WithComputed<T>> {
+    entities
+        .into_iter()
+        .map(|entity| with_fields(resource, entity))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(resource: Resource, definitions: Value) -> Result<Vec<ComputedField>, String> {
+        let Value::Object(definitions) = definitions else {
+            unreachable!()
+        };
+        parse_fields(resource, "products", definitions)
+    }
+
+    #[test]
+    fn fields_read_the_resource_and_the_fields_before_them() {
+        let fields = parse(
+            Resource::Products,
+            json!({
+                "value": "price * stock",
+                "label": "name + \": \" + value",
+                "unit": "round(value / stock, 2)",
+            }),
+        )
+        .unwrap();
+        let mut product = json!({"id": "x", "name": "Pen", "price": 1.5, "stock": 0});
+        extend(
+            Resource::Products,
+            &fields,
+            product.as_object_mut().unwrap(),
+        );
+        // Dividing by the zero stock fails, the field is null
+        assert_eq!(
+            product,
+            json!({"id": "x", "name": "Pen", "price": 1.5, "stock": 0,
+                   "value": 0.0, "label": "Pen: 0.0", "unit": null})
+        );
+    }
+
+    #[test]
+    fn invalid_fields_are_rejected() {
+        let error = |definitions| parse(Resource::Products, definitions).unwrap_err();
+        assert_eq!(
+            error(json!({"total": "price * quantity"})),
+            "products.total: unknown field 'quantity', the expressions read id, name, \
+             price, stock and the fields computed before"
+        );
+        assert_eq!(
+            error(json!({"a": "b", "b": "1"})),
+            "products.a: unknown field 'b', the expressions read id, name, price, stock \
+             and the fields computed before"
+        );
+        assert_eq!(
+            error(json!({"stock": "0"})),
+            "products.stock: a field of the resource can't be replaced"
+        );
+        assert_eq!(
+            error(json!({"Label": "name"})),
+            "products.Label: names are lowercase letters, digits and _"
+        );
+        assert_eq!(
+            error(json!({"label": 1})),
+            "products.label: the expression must be a string"
+        );
+        assert_eq!(
+            error(json!({"label": "name +"})),
+            "products.label: unexpected end of the expression"
+        );
+    }
+}
@@ -0,0 +1,726 @@
+//! Expressions of the computed fields: parsed once at startup, evaluated against the
+//! fields of each response.
+//!
+//! The language has literals (`"text"`, `12`, `1.5`, `true`, `false`, `null`), the
+//! fields of the resource by name, the operators `+ - * / %`, `== != < <= > >=`,
+//! `&& || !` and parentheses, and the functions of [`Function`]. There are no loops,
+//! assignments or lookups outside the resource: evaluating an expression takes time in
+//! proportion to its length, which `MAX_LENGTH` bounds.
+//!
+//! `+` concatenates when one of its operands is a string (`null` as an empty string),
+//! and adds numbers otherwise. Integers stay integers, except through `/` and `round`
+//! with digits. `!`, `&&`, `||` and `if` take booleans, `null` counting as `false`.
+//! Anything else, a string minus a number for instance, is an evaluation error.
+
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use serde_json::{Map, Number, Value};
+
+/// Longest expression, in characters
+pub const MAX_LENGTH: usize = 1000;
+
+/// Deepest nesting of parentheses, calls and unary operators
+const MAX_DEPTH: usize = 32;
+
+/// Most digits `round` keeps
+const MAX_DIGITS: i64 = 10;
+
+/// A parsed expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    Field(String),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    And,
+    Or,
+}
+
+/// Functions of the expressions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    /// `upper(s)`
+    Upper,
+    /// `lower(s)`
+    Lower,
+    /// `trim(s)`, without the surrounding whitespace
+    Trim,
+    /// `len(s)`, in characters
+    Len,
+    /// `round(x)` to an integer, `round(x, digits)` to that many decimals
+    Round,
+    /// `coalesce(a, b...)`, the first argument that isn't `null`
+    Coalesce,
+    /// `if(condition, then, else)`
+    If,
+}
+
+impl Function {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "upper" => Function::Upper,
+            "lower" => Function::Lower,
+            "trim" => Function::Trim,
+            "len" => Function::Len,
+            "round" => Function::Round,
+            "coalesce" => Function::Coalesce,
+            "if" => Function::If,
+            _ => return None,
+        })
+    }
+
+    /// Fewest and most arguments of the function.
+    fn arity(self) -> (usize, usize) {
+        match self {
+            Function::Upper | Function::Lower | Function::Trim | Function::Len => (1, 1),
+            Function::Round => (1, 2),
+            Function::Coalesce => (1, usize::MAX),
+            Function::If => (3, 3),
+        }
+    }
+}
+
+impl Expr {
+    /// Parses `source`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Expr, String>` - The expression, or what is wrong with it and where
+    pub fn parse(source: &str) -> Result<Expr, String> {
+        if source.chars().count() > MAX_LENGTH {
+            return Err(format!("longer than {} characters", MAX_LENGTH));
+        }
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            depth: 0,
+        };
+        let expr = parser.expression()?;
+        match parser.tokens.get(parser.position) {
+            None => Ok(expr),
+            Some((at, token)) => Err(format!("unexpected {} at character {}", token, at + 1)),
+        }
+    }
+
+    /// Names of the fields the expression reads.
+    pub fn fields(&self) -> Vec<&str> {
+        let mut fields = Vec::new();
+        self.collect_fields(&mut fields);
+        fields
+    }
+
+    fn collect_fields<'a>(&'a self, fields: &mut Vec<&'a str>) {
+        match self {
+            Expr::Literal(_) => {}
+            Expr::Field(name) => fields.push(name),
+            Expr::Not(expr) | Expr::Negate(expr) => expr.collect_fields(fields),
+            Expr::Binary(_, left, right) => {
+                left.collect_fields(fields);
+                right.collect_fields(fields);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|arg| arg.collect_fields(fields)),
+        }
+    }
+
+    /// Evaluates the expression with the values of `fields`, a missing one being `null`.
+    pub fn eval(&self, fields: &Map<String, Value>) -> Result<Value, String> {
+        match self {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Field(name) => Ok(fields.get(name).cloned().unwrap_or(Value::Null)),
+            Expr::Not(expr) => Ok(Value::Bool(!truth(&expr.eval(fields)?)?)),
+            Expr::Negate(expr) => match expr.eval(fields)? {
+                Value::Number(n) => match n.as_i64() {
+                    Some(i) => i.checked_neg().map(Value::from).ok_or_else(overflow),
+                    None => float(-n.as_f64().unwrap_or_default()),
+                },
+                value => Err(format!("can't negate {}", kind(&value))),
+            },
+            // Both short-circuit
+            Expr::Binary(BinaryOp::And, left, right) => Ok(Value::Bool(
+                truth(&left.eval(fields)?)? && truth(&right.eval(fields)?)?,
+            )),
+            Expr::Binary(BinaryOp::Or, left, right) => Ok(Value::Bool(
+                truth(&left.eval(fields)?)? || truth(&right.eval(fields)?)?,
+            )),
+            Expr::Binary(op, left, right) => binary(*op, left.eval(fields)?, right.eval(fields)?),
+            Expr::Call(Function::If, args) => {
+                let branch = if truth(&args[0].eval(fields)?)? { 1 } else { 2 };
+                args[branch].eval(fields)
+            }
+            Expr::Call(Function::Coalesce, args) => {
+                for arg in args {
+                    let value = arg.eval(fields)?;
+                    if !value.is_null() {
+                        return Ok(value);
+                    }
+                }
+                Ok(Value::Null)
+            }
+            Expr::Call(function, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval(fields))
+                    .collect::<Result<Vec<_>, _>>()?;
+                call(*function, &args)
+            }
+        }
+    }
+}
+
+// ==================== PARSING ====================
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(Number),
+    Str(String),
+    Ident(String),
+    Symbol(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "number {}", n),
+            Token::Str(s) => write!(f, "string {:?}", s),
+            Token::Ident(name) => write!(f, "'{}'", name),
+            Token::Symbol(symbol) => write!(f, "'{}'", symbol),
+        }
+    }
+}
+
+/// Operators and punctuation, the two-character ones first
+const SYMBOLS: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "!", "(", ")", ",",
+];
+
+/// The tokens of `source`, with the index of their first character.
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    let mut index = 0;
+    while let Some(&(offset, c)) = chars.peek() {
+        let at = index;
+        if c.is_whitespace() {
+            chars.next();
+            index += 1;
+        } else if c == '"' {
+            chars.next();
+            let (text, length) = string(&mut chars)
+                .ok_or_else(|| format!("unterminated string at character {}", at + 1))?;
+            tokens.push((at, Token::Str(text)));
+            index += length + 1;
+        } else if c.is_ascii_digit() {
+            let mut end = offset;
+            while let Some(&(i, c)) = chars.peek()
+                && (c.is_ascii_digit() || c == '.')
+            {
+                end = i + 1;
+                chars.next();
+            }
+            let text = &source[offset..end];
+            let number = match text.parse::<i64>() {
+                Ok(i) => Number::from(i),
+                Err(_) => text
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(Number::from_f64)
+                    .ok_or_else(|| format!("invalid number '{}' at character {}", text, at + 1))?,
+            };
+            tokens.push((at, Token::Number(number)));
+            index += text.len();
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut end = offset;
+            while let Some(&(i, c)) = chars.peek()
+                && (c.is_ascii_alphanumeric() || c == '_')
+            {
+                end = i + 1;
+                chars.next();
+            }
+            let name = &source[offset..end];
+            tokens.push((at, Token::Ident(name.to_string())));
+            index += name.len();
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| source[offset..].starts_with(**symbol))
+                .ok_or_else(|| format!("unexpected '{}' at character {}", c, at + 1))?;
+            for _ in 0..symbol.len() {
+                chars.next();
+            }
+            tokens.push((at, Token::Symbol(symbol)));
+            index += symbol.len();
+        }
+    }
+    Ok(tokens)
+}
+
+/// The rest of a string literal after its opening quote, and its length in characters
+/// with the closing quote; `None` if it isn't closed.
+fn string(chars: &mut Peekable<CharIndices<'_>>) -> Option<(String, usize)> {
+    let mut text = String::new();
+    let mut length = 0;
+    loop {
+        let (_, c) = chars.next()?;
+        length += 1;
+        match c {
+            '"' => return Some((text, length)),
+            '\\' => {
+                let (_, escaped) = chars.next()?;
+                length += 1;
+                text.push(match escaped {
+                    'n' => '\n',
+                    't' => '\t',
+                    other => other,
+                });
+            }
+            c => text.push(c),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    /// Consumes the next token if it is `symbol`.
+    fn eat(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        if self.eat(symbol) {
+            return Ok(());
+        }
+        Err(match self.tokens.get(self.position) {
+            Some((at, token)) => format!(
+                "expected '{}' at character {}, found {}",
+                symbol,
+                at + 1,
+                token
+            ),
+            None => format!("expected '{}' at the end", symbol),
+        })
+    }
+
+    fn expression(&mut self) -> Result<Expr, String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(format!("nested deeper than {} levels", MAX_DEPTH));
+        }
+        let expr = self.or();
+        self.depth -= 1;
+        expr
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut left = self.and()?;
+        while self.eat("||") {
+            left = Expr::Binary(BinaryOp::Or, Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut left = self.comparison()?;
+        while self.eat("&&") {
+            left = Expr::Binary(BinaryOp::And, Box::new(left), Box::new(self.comparison()?));
+        }
+        Ok(left)
+    }
+
+    /// Comparisons don't chain: `a < b < c` is an error.
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.sum()?;
+        let ops = [
+            ("==", BinaryOp::Equal),
+            ("!=", BinaryOp::NotEqual),
+            ("<=", BinaryOp::LessOrEqual),
+            (">=", BinaryOp::GreaterOrEqual),
+            ("<", BinaryOp::Less),
+            (">", BinaryOp::Greater),
+        ];
+        for (symbol, op) in ops {
+            if self.eat(symbol) {
+                return Ok(Expr::Binary(op, Box::new(left), Box::new(self.sum()?)));
+            }
+        }
+        Ok(left)
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut left = self.product()?;
+        loop {
+            let op = if self.eat("+") {
+                BinaryOp::Add
+            } else if self.eat("-") {
+                BinaryOp::Subtract
+            } else {
+                return Ok(left);
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        loop {
+            let op = if self.eat("*") {
+                BinaryOp::Multiply
+            } else if self.eat("/") {
+                BinaryOp::Divide
+            } else if self.eat("%") {
+                BinaryOp::Remainder
+            } else {
+                return Ok(left);
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.nested(Self::unary)?)));
+        }
+        if self.eat("-") {
+            return Ok(Expr::Negate(Box::new(self.nested(Self::unary)?)));
+        }
+        self.primary()
+    }
+
+    /// Parses with `parse` one level deeper.
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Expr, String>) -> Result<Expr, String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(format!("nested deeper than {} levels", MAX_DEPTH));
+        }
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let Some((at, token)) = self.tokens.get(self.position).cloned() else {
+            return Err("unexpected end of the expression".to_string());
+        };
+        self.position += 1;
+        match token {
+            Token::Number(n) => Ok(Expr::Literal(Value::Number(n))),
+            Token::Str(s) => Ok(Expr::Literal(Value::String(s))),
+            Token::Symbol("(") => {
+                let expr = self.expression()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Token::Ident(name) => match name.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                _ if self.eat("(") => self.call(at, &name),
+                _ => Ok(Expr::Field(name)),
+            },
+            token => Err(format!("unexpected {} at character {}", token, at + 1)),
+        }
+    }
+
+    /// The arguments of the function `name`, after its opening parenthesis.
+    fn call(&mut self, at: usize, name: &str) -> Result<Expr, String> {
+        let function = Function::parse(name)
+            .ok_or_else(|| format!("unknown function '{}' at character {}", name, at + 1))?;
+        let mut args = Vec::new();
+        if !self.eat(")") {
+            loop {
+                args.push(self.expression()?);
+                if self.eat(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        let (min, max) = function.arity();
+        if !(min..=max).contains(&args.len()) {
+            return Err(format!(
+                "'{}' at character {} takes {} arguments, not {}",
+                name,
+                at + 1,
+                if min == max {
+                    min.to_string()
+                } else if max == usize::MAX {
+                    format!("at least {}", min)
+                } else {
+                    format!("{} to {}", min, max)
+                },
+                args.len()
+            ));
+        }
+        Ok(Expr::Call(function, args))
+    }
+}
+
+// ==================== EVALUATION ====================
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+fn overflow() -> String {
+    "integer overflow".to_string()
+}
+
+/// A number of `value`, which must be finite.
+fn float(value: f64) -> Result<Value, String> {
+    Number::from_f64(value)
+        .map(Value::Number)
+        .ok_or_else(|| "the result isn't a finite number".to_string())
+}
+
+/// Whether a condition holds, `null` being false.
+fn truth(value: &Value) -> Result<bool, String> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        Value::Null => Ok(false),
+        value => Err(format!("expected a boolean, found {}", kind(value))),
+    }
+}
+
+/// The text of a value concatenated to a string.
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn binary(op: BinaryOp, left: Value, right: Value) -> Result<Value, String> {
+    match op {
+        BinaryOp::Add if left.is_string() || right.is_string() => {
+            return Ok(Value::String(text(&left) + &text(&right)));
+        }
+        BinaryOp::Equal => return Ok(Value::Bool(equal(&left, &right))),
+        BinaryOp::NotEqual => return Ok(Value::Bool(!equal(&left, &right))),
+        BinaryOp::Less | BinaryOp::LessOrEqual | BinaryOp::Greater | BinaryOp::GreaterOrEqual => {
+            let ordering = compare(&left, &right)?;
+            return Ok(Value::Bool(match op {
+                BinaryOp::Less => ordering == Ordering::Less,
+                BinaryOp::LessOrEqual => ordering != Ordering::Greater,
+                BinaryOp::Greater => ordering == Ordering::Greater,
+                _ => ordering != Ordering::Less,
+            }));
+        }
+        _ => {}
+    }
+
+    let (Value::Number(a), Value::Number(b)) = (&left, &right) else {
+        return Err(format!(
+            "can't apply {:?} to {} and {}",
+            op,
+            kind(&left),
+            kind(&right)
+        ));
+    };
+    if op != BinaryOp::Divide
+        && let (Some(a), Some(b)) = (a.as_i64(), b.as_i64())
+    {
+        let result = match op {
+            BinaryOp::Add => a.checked_add(b),
+            BinaryOp::Subtract => a.checked_sub(b),
+            BinaryOp::Multiply => a.checked_mul(b),
+            _ if b == 0 => return Err("division by zero".to_string()),
+            _ => a.checked_rem(b),
+        };
+        return result.map(Value::from).ok_or_else(overflow);
+    }
+
+    let (a, b) = (
+        a.as_f64().unwrap_or_default(),
+        b.as_f64().unwrap_or_default(),
+    );
+    if matches!(op, BinaryOp::Divide | BinaryOp::Remainder) && b == 0.0 {
+        return Err("division by zero".to_string());
+    }
+    float(match op {
+        BinaryOp::Add => a + b,
+        BinaryOp::Subtract => a - b,
+        BinaryOp::Multiply => a * b,
+        BinaryOp::Divide => a / b,
+        _ => a % b,
+    })
+}
+
+/// Equality of JSON values, numbers by value (`1 == 1.0`).
+fn equal(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        _ => left == right,
+    }
+}
+
+/// Order of two numbers or two strings.
+fn compare(left: &Value, right: &Value) -> Result<Ordering, String> {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .ok_or_else(|| "can't compare these numbers".to_string()),
+        (Value::String(a), Value::String(b)) => Ok(a.cmp(b)),
+        _ => Err(format!("can't compare {} and {}", kind(left), kind(right))),
+    }
+}
+
+/// Calls a function other than `if` and `coalesce`, which choose what they evaluate.
+fn call(function: Function, args: &[Value]) -> Result<Value, String> {
+    let text_arg = |value: &Value| match value {
+        Value::String(s) => Ok(Some(s.clone())),
+        Value::Null => Ok(None),
+        value => Err(format!("expected a string, found {}", kind(value))),
+    };
+    match function {
+        Function::Upper => Ok(text_arg(&args[0])?.map(|s| s.to_uppercase()).into()),
+        Function::Lower => Ok(text_arg(&args[0])?.map(|s| s.to_lowercase()).into()),
+        Function::Trim => Ok(text_arg(&args[0])?.map(|s| s.trim().to_string()).into()),
+        Function::Len => Ok(text_arg(&args[0])?.map(|s| s.chars().count()).into()),
+        Function::Round => {
+            let x = match &args[0] {
+                Value::Number(n) => n.as_f64().unwrap_or_default(),
+                Value::Null => return Ok(Value::Null),
+                value => return Err(format!("expected a number, found {}", kind(value))),
+            };
+            let digits = match args.get(1) {
+                None => 0,
+                Some(Value::Number(n))
+                    if n.as_i64().is_some_and(|d| (0..=MAX_DIGITS).contains(&d)) =>
+                {
+                    n.as_i64().unwrap_or_default()
+                }
+                Some(_) => {
+                    return Err(format!(
+                        "digits must be an integer from 0 to {}",
+                        MAX_DIGITS
+                    ));
+                }
+            };
+            if digits == 0 {
+                let rounded = x.round();
+                // Within the integers exactly represented
+                if rounded.abs() < 9_007_199_254_740_992.0 {
+                    return Ok(Value::from(rounded as i64));
+                }
+                return float(rounded);
+            }
+            let scale = 10f64.powi(digits as i32);
+            float((x * scale).round() / scale)
+        }
+        Function::If | Function::Coalesce => unreachable!("Evaluated by Expr::eval"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn eval(source: &str) -> Result<Value, String> {
+        let fields = json!({"name": "Ann", "age": 30, "price": 9.99, "stock": 0, "nickname": null});
+        Expr::parse(source)?.eval(fields.as_object().unwrap())
+    }
+
+    #[test]
+    fn strings_concatenate_and_numbers_add() {
+        assert_eq!(eval(r#"name + " (" + age + ")""#), Ok(json!("Ann (30)")));
+        assert_eq!(eval(r#"nickname + "!""#), Ok(json!("!")));
+        assert_eq!(eval("age + 1 * 2"), Ok(json!(32)));
+        assert_eq!(eval("(age + 1) * 2"), Ok(json!(62)));
+        assert_eq!(eval("age / 4"), Ok(json!(7.5)));
+        assert_eq!(eval("round(price * 3, 1)"), Ok(json!(30.0)));
+        assert_eq!(eval("round(price)"), Ok(json!(10)));
+        assert_eq!(eval("-age % 7"), Ok(json!(-2)));
+    }
+
+    #[test]
+    fn conditions_and_functions() {
+        assert_eq!(eval("stock > 0"), Ok(json!(false)));
+        assert_eq!(
+            eval(r#"if(stock > 0, "in stock", "sold out")"#),
+            Ok(json!("sold out"))
+        );
+        assert_eq!(eval("age >= 18 && !(name == \"Bob\")"), Ok(json!(true)));
+        assert_eq!(eval("nickname || age == 30.0"), Ok(json!(true)));
+        assert_eq!(eval("upper(coalesce(nickname, name))"), Ok(json!("ANN")));
+        assert_eq!(eval(r#"len(trim("  a b  "))"#), Ok(json!(3)));
+    }
+
+    #[test]
+    fn evaluation_errors_are_reported() {
+        assert_eq!(eval("age / stock"), Err("division by zero".to_string()));
+        assert!(eval("name - 1").is_err());
+        assert!(eval("name && true").is_err());
+        assert!(eval("9223372036854775807 + age").is_err());
+        assert!(eval("round(price, 11)").is_err());
+    }
+
+    #[test]
+    fn invalid_expressions_are_rejected() {
+        assert_eq!(
+            Expr::parse("age +").unwrap_err(),
+            "unexpected end of the expression"
+        );
+        assert_eq!(
+            Expr::parse("age $ 1").unwrap_err(),
+            "unexpected '$' at character 5"
+        );
+        assert_eq!(
+            Expr::parse("exec(name)").unwrap_err(),
+            "unknown function 'exec' at character 1"
+        );
+        assert_eq!(
+            Expr::parse("if(age, 1)").unwrap_err(),
+            "'if' at character 1 takes 3 arguments, not 2"
+        );
+        assert!(Expr::parse("1 < 2 < 3").is_err());
+        assert!(Expr::parse(r#""open"#).is_err());
+        assert!(Expr::parse(&"(".repeat(40)).is_err());
+        assert!(Expr::parse(&format!("{}1", "1 + ".repeat(300))).is_err());
+
+        let expr = Expr::parse("if(stock > 0, name, \"\")").unwrap();
+        assert_eq!(expr.fields(), vec!["stock", "name"]);
+    }
+}
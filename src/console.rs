@@ -0,0 +1,312 @@
+//! Admin console (`CONSOLE_QUERIES_PATH`).
+//!
+//! ## Configuration
+//! The console runs the queries of the JSON file pointed to by `CONSOLE_QUERIES_PATH`
+//! at startup, and no other:
+//!
+//! ```json
+//! [
+//!   {"name": "orders-of-user", "description": "Orders of a user, by email",
+//!    "sql": "SELECT o.id, p.name AS product, o.quantity FROM orders o JOIN users u ON u.id = o.user_id JOIN products p ON p.id = o.product_id WHERE u.email = $1 ORDER BY o.id DESC",
+//!    "params": [{"name": "email", "type": "text"}], "max_rows": 500}
+//! ]
+//! ```
+//!
+//! The `params` are the `$1`, `$2`... of the SQL, in order, sent with their declared
+//! type (see [`ParamType`]): callers give values, never SQL.
+//!
+//! ## Runs
+//! A query is run as a subquery (`SELECT row_to_json(q) FROM (<sql>) q`) in a read-only
+//! transaction, on a read replica when there are some: only statements returning rows
+//! are accepted, and nothing they call can write. It returns at most `max_rows` rows
+//! (default `DEFAULT_MAX_ROWS`), and is cancelled past the timeout of its route
+//! (`ROUTE_TIMEOUTS`). Every run is logged with its caller.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use bb8_postgres::tokio_postgres::error::SqlState;
+use bb8_postgres::tokio_postgres::types::{ToSql, Type};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::auth::AuthUser;
+use crate::db::Db;
+use crate::error::{AppError, ErrorCode};
+use crate::repository::console::{ConsoleRepository, PgConsoleRepo};
+use crate::validation::ValidationErrors;
+
+/// Rows returned by the queries without `max_rows`
+const DEFAULT_MAX_ROWS: i64 = 1000;
+
+/// Highest `max_rows` of a query, the response is built in memory
+const MAX_ROWS: i64 = 10_000;
+
+// Set once at startup, empty without CONSOLE_QUERIES_PATH
+static QUERIES: OnceLock<Vec<Query>> = OnceLock::new();
+
+/// A query of the configuration file.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Query {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub sql: String,
+    #[serde(default)]
+    pub params: Vec<Param>,
+    #[serde(default = "default_max_rows")]
+    pub max_rows: i64,
+}
+
+/// A parameter of a query, `$1` for the first one.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Param {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub param_type: ParamType,
+}
+
+/// Type of a parameter: the JSON value it's given as, and the SQL type it's sent as.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParamType {
+    /// A string, as `TEXT` (cast it in the SQL for other types: `$1::timestamptz`)
+    Text,
+    /// An integer, as `BIGINT`
+    Integer,
+    /// A number, as `DOUBLE PRECISION`
+    Float,
+    /// `true` or `false`, as `BOOLEAN`
+    Boolean,
+    /// A UUID string, as `UUID`
+    Uuid,
+}
+
+impl ParamType {
+    fn sql_type(self) -> Type {
+        match self {
+            ParamType::Text => Type::TEXT,
+            ParamType::Integer => Type::INT8,
+            ParamType::Float => Type::FLOAT8,
+            ParamType::Boolean => Type::BOOL,
+            ParamType::Uuid => Type::UUID,
+        }
+    }
+
+    /// The SQL value of `value`, `None` if it isn't of this type.
+    fn bind(self, value: &Value) -> Option<Box<dyn ToSql + Send + Sync>> {
+        Some(match self {
+            ParamType::Text => Box::new(value.as_str()?.to_string()),
+            ParamType::Integer => Box::new(value.as_i64()?),
+            ParamType::Float => Box::new(value.as_f64()?),
+            ParamType::Boolean => Box::new(value.as_bool()?),
+            ParamType::Uuid => Box::new(value.as_str()?.parse::<Uuid>().ok()?),
+        })
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            ParamType::Text => "a string",
+            ParamType::Integer => "an integer",
+            ParamType::Float => "a number",
+            ParamType::Boolean => "a boolean",
+            ParamType::Uuid => "a UUID",
+        }
+    }
+}
+
+fn default_max_rows() -> i64 {
+    DEFAULT_MAX_ROWS
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+impl Query {
+    fn validate(&self) -> Result<(), String> {
+        if !valid_name(&self.name) {
+            return Err(format!(
+                "'{}': names are letters, digits, _, - and .",
+                self.name
+            ));
+        }
+        if self.sql.trim().is_empty() {
+            return Err(format!("'{}': sql is empty", self.name));
+        }
+        if !(1..=MAX_ROWS).contains(&self.max_rows) {
+            return Err(format!(
+                "'{}': max_rows must be between 1 and {}",
+                self.name, MAX_ROWS
+            ));
+        }
+        let mut names = HashSet::new();
+        for param in &self.params {
+            if !valid_name(&param.name) {
+                return Err(format!(
+                    "'{}': parameter '{}': names are letters, digits, _, - and .",
+                    self.name, param.name
+                ));
+            }
+            if !names.insert(&param.name) {
+                return Err(format!(
+                    "'{}': parameter '{}' is defined twice",
+                    self.name, param.name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rows of a run, in the envelope of the lists so they can be sent as CSV too.
+#[derive(Serialize, Debug)]
+pub struct QueryResult {
+    /// The rows, objects of the columns of the query
+    data: Vec<Value>,
+    pagination: ResultMeta,
+}
+
+#[derive(Serialize, Debug)]
+struct ResultMeta {
+    /// Rows in `data`
+    rows: usize,
+    max_rows: i64,
+    /// Whether the query had more rows than `max_rows`
+    truncated: bool,
+}
+
+/// Loads the queries of the console from `CONSOLE_QUERIES_PATH`.
+/// This function should be called once at application startup.
+///
+/// # Arguments
+///
+/// * `path` - Path of the JSON queries file, `None` when the console has no query
+///
+/// # Returns
+///
+/// * `Result<usize, String>` - Number of queries, or an error message
+pub fn init_console(path: Option<&Path>) -> Result<usize, String> {
+    let mut queries: Vec<Query> = match path {
+        Some(path) => {
+            let content = fs::read_to_string(path).map_err(|e| {
+                format!("Unable to read console queries '{}': {}", path.display(), e)
+            })?;
+            serde_json::from_str(&content)
+                .map_err(|e| format!("Invalid console queries '{}': {}", path.display(), e))?
+        }
+        None => Vec::new(),
+    };
+    let mut names = HashSet::new();
+    for query in &mut queries {
+        query.validate()?;
+        if !names.insert(query.name.clone()) {
+            return Err(format!("Console query '{}' is defined twice", query.name));
+        }
+        // The SQL becomes a subquery, where a final `;` is an error
+        query.sql = query.sql.trim().trim_end_matches(';').to_string();
+    }
+
+    let count = queries.len();
+    if QUERIES.set(queries).is_err() {
+        warn!("Attempt to reload the console queries ignored");
+    }
+    Ok(count)
+}
+
+/// The queries of the console, in the order of the configuration file.
+pub fn queries() -> &'static [Query] {
+    QUERIES.get().map_or(&[], Vec::as_slice)
+}
+
+/// Runs the query `name` for `caller` with the parameters `values`, by name.
+///
+/// # Returns
+///
+/// * `Result<QueryResult, AppError>` - The rows, `AppError::NotFound` for an unknown
+///   query, or `AppError::Unprocessable` listing the missing, unknown and invalid
+///   parameters
+pub async fn run(
+    db: Db,
+    name: &str,
+    values: &Map<String, Value>,
+    caller: AuthUser,
+) -> Result<QueryResult, AppError> {
+    let query = queries()
+        .iter()
+        .find(|query| query.name == name)
+        .ok_or_else(|| {
+            AppError::NotFound(
+                ErrorCode::QueryNotFound,
+                format!("No console query named '{}'", name),
+            )
+        })?;
+
+    let mut errors = ValidationErrors::default();
+    let mut types = Vec::with_capacity(query.params.len());
+    let mut params = Vec::with_capacity(query.params.len());
+    for param in &query.params {
+        types.push(param.param_type.sql_type());
+        match values.get(&param.name) {
+            Some(value) => match param.param_type.bind(value) {
+                Some(bound) => params.push(bound),
+                None => errors.check(
+                    param.name.as_str(),
+                    false,
+                    format!("must be {}", param.param_type.describe()),
+                ),
+            },
+            None => errors.check(param.name.as_str(), false, "is required"),
+        }
+    }
+    for name in values.keys() {
+        errors.check(
+            "params",
+            query.params.iter().any(|param| &param.name == name),
+            format!("'{}' is not a parameter of the query", name),
+        );
+    }
+    errors.into_result()?;
+
+    // One more row than returned tells whether there were more
+    let result = PgConsoleRepo(db)
+        .run(&query.sql, &types, &params, query.max_rows + 1)
+        .await;
+    let mut rows = match result {
+        Ok(rows) => rows,
+        Err(AppError::Db(e)) if e.code() == Some(&SqlState::READ_ONLY_SQL_TRANSACTION) => {
+            return Err(AppError::Internal(format!(
+                "Console query '{}' attempted a write: {}",
+                query.name, e
+            )));
+        }
+        Err(e) => return Err(e),
+    };
+    let truncated = rows.len() as i64 > query.max_rows;
+    rows.truncate(query.max_rows as usize);
+
+    info!(
+        "Console query '{}' run by user {}: {} rows{}",
+        query.name,
+        caller.id,
+        rows.len(),
+        if truncated { " (truncated)" } else { "" }
+    );
+    Ok(QueryResult {
+        pagination: ResultMeta {
+            rows: rows.len(),
+            max_rows: query.max_rows,
+            truncated,
+        },
+        data: rows,
+    })
+}
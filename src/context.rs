@@ -0,0 +1,256 @@
+//! Context of the request a task works for.
+//!
+//! The router runs every request in the scope of its ID (`RequestId::scope`), its
+//! locale and its caller once authenticated (`AuthUser::scope`): task-locals read by
+//! the logs and the audit log.
+//! Handlers read the same [`RequestContext`] from their request, where the router
+//! attaches it with the deadline and the database: `RequestContext::of(&req).caller()?`
+//! is the caller of a protected route, `.db.connection()` a connection for its queries.
+//!
+//! Work a handler hands over to another task would lose the task-locals, so:
+//!
+//! - [`spawn`] runs a future in a new task with the context of the current one, its
+//!   logs in a span naming the request and the caller
+//! - a job enqueued while handling a request keeps the request ID and the caller, and
+//!   runs in their scope (see `jobs`), as the webhook deliveries of the events the
+//!   request published (see `webhooks`)
+//!
+//! The deadline isn't carried over: the work handed over is meant to outlive the
+//! response, and its queries would be cancelled with it. Within the request, the
+//! queries are held to it by the db layer (`statement_timeout`), and the calls to other
+//! services by [`within_deadline`]: a call never waits longer than the client will.
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use hyper::Request;
+use hyper::header::ACCEPT_LANGUAGE;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, Span, info_span};
+
+use crate::auth::AuthUser;
+use crate::db::Db;
+use crate::error::AppError;
+use crate::logging::RequestId;
+
+/// Longest language tag kept from `Accept-Language`
+const MAX_LOCALE_LEN: usize = 35;
+
+tokio::task_local! {
+    // Locale of the request handled by the current task, see `Locale::scope`
+    static CURRENT_LOCALE: Locale;
+}
+
+/// Preferred language of the client, the tag of `Accept-Language` with the highest
+/// weight (`fr-CH`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Locale(pub String);
+
+impl Locale {
+    /// The locale of a request, `None` without `Accept-Language` or with only `*`.
+    pub fn from_request<B>(req: &Request<B>) -> Option<Self> {
+        let header = req.headers().get(ACCEPT_LANGUAGE)?.to_str().ok()?;
+        let mut best: Option<(&str, f32)> = None;
+        for range in header.split(',') {
+            let mut parts = range.split(';');
+            let tag = parts.next().unwrap_or_default().trim();
+            let weight = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if tag.is_empty() || tag == "*" || tag.len() > MAX_LOCALE_LEN || weight <= 0.0 {
+                continue;
+            }
+            // The first of equal weights wins
+            if best.is_none_or(|(_, best)| weight > best) {
+                best = Some((tag, weight));
+            }
+        }
+        best.map(|(tag, _)| Locale(tag.to_string()))
+    }
+
+    /// Runs `future` with this locale as the one of the current request.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT_LOCALE.scope(self, future).await
+    }
+
+    /// Locale of the request handled by the current task, if any.
+    pub fn current() -> Option<Locale> {
+        CURRENT_LOCALE.try_with(Locale::clone).ok()
+    }
+}
+
+/// Context of a request without one, built by hand
+static EMPTY: RequestContext = RequestContext {
+    request_id: None,
+    user: None,
+    locale: None,
+    deadline: None,
+    db: Db::UNBOUNDED,
+};
+
+/// What is known about a request: attached to it by the router, for its handler, and
+/// captured to be restored in another task.
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    pub request_id: Option<RequestId>,
+    /// Caller of a protected route, or the user of a job a request enqueued
+    pub user: Option<AuthUser>,
+    pub locale: Option<Locale>,
+    /// When the router stops waiting for the handler and answers 504, never carried
+    /// over to another task
+    pub deadline: Option<Instant>,
+    pub db: Db,
+}
+
+impl RequestContext {
+    /// The context the router attached to a request, completed with the caller once
+    /// authenticated; empty for a request built by hand.
+    pub fn of<B>(req: &Request<B>) -> &RequestContext {
+        req.extensions().get::<RequestContext>().unwrap_or(&EMPTY)
+    }
+
+    /// The caller of a protected route, or 401.
+    pub fn caller(&self) -> Result<AuthUser, AppError> {
+        self.user
+            .ok_or_else(|| AppError::Unauthorized("Missing bearer token".to_string()))
+    }
+
+    /// Time left before the deadline, `None` without one.
+    pub fn remaining_time(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// The context of the current task, empty outside of a request.
+    pub fn current() -> Self {
+        RequestContext {
+            request_id: RequestId::current(),
+            user: AuthUser::current(),
+            locale: Locale::current(),
+            ..RequestContext::default()
+        }
+    }
+
+    /// Runs `future` with the request ID, the caller and the locale of this context.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        // On the heap: each scope below would otherwise hold a copy of its size, and
+        // the future of a request is large
+        let future = Box::pin(future);
+        let locale = self.locale;
+        let future = async move {
+            match locale {
+                Some(locale) => locale.scope(future).await,
+                None => future.await,
+            }
+        };
+        let user = self.user;
+        let future = async move {
+            match user {
+                Some(user) => user.scope(future).await,
+                None => future.await,
+            }
+        };
+        match self.request_id {
+            Some(request_id) => request_id.scope(future).await,
+            None => future.await,
+        }
+    }
+
+    /// Span of the work done for the request, naming it and its caller in the logs.
+    pub fn span(&self) -> Span {
+        let span = info_span!(
+            "context",
+            request_id = tracing::field::Empty,
+            user_id = tracing::field::Empty,
+        );
+        if let Some(request_id) = &self.request_id {
+            span.record("request_id", request_id.0.as_str());
+        }
+        if let Some(user) = self.user {
+            span.record("user_id", user.id);
+        }
+        span
+    }
+}
+
+/// `limit`, shortened to the time left before the deadline of the request `db` is
+/// the database of.
+///
+/// The timeout of a call to another service, which is pointless once the request is
+/// dropped: the router answers 504 at the deadline.
+pub fn within_deadline(db: Db, limit: Duration) -> Duration {
+    db.remaining_time().map_or(limit, |left| limit.min(left))
+}
+
+/// Spawns a task running `future` with the context of the current one.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let context = RequestContext::current();
+    let span = context.span();
+    tokio::spawn(context.scope(future).instrument(span))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locale(header: &str) -> Option<String> {
+        let req = Request::get("/")
+            .header(ACCEPT_LANGUAGE, header)
+            .body(())
+            .unwrap();
+        Locale::from_request(&req).map(|locale| locale.0)
+    }
+
+    #[test]
+    fn picks_the_language_of_highest_weight() {
+        assert_eq!(locale("fr-CH, fr;q=0.9, en;q=0.8"), Some("fr-CH".into()));
+        assert_eq!(locale("en;q=0.5, de;q=0.7, *"), Some("de".into()));
+        assert_eq!(locale("*, en;q=0"), None);
+    }
+
+    #[tokio::test]
+    async fn spawned_tasks_keep_the_context() {
+        let context = RequestContext {
+            request_id: Some(RequestId("abc".to_string())),
+            user: Some(AuthUser { id: 7 }),
+            ..RequestContext::default()
+        };
+        let inherited = context
+            .scope(async { spawn(async { RequestContext::current() }).await.unwrap() })
+            .await;
+        assert_eq!(inherited.request_id.unwrap().0, "abc");
+        assert_eq!(inherited.user.unwrap().id, 7);
+        assert_eq!(inherited.locale, None);
+    }
+
+    #[test]
+    fn handlers_read_the_caller_from_the_request() {
+        let mut req = Request::get("/").body(()).unwrap();
+        assert!(RequestContext::of(&req).caller().is_err());
+        assert_eq!(RequestContext::of(&req).remaining_time(), None);
+
+        req.extensions_mut().insert(RequestContext {
+            user: Some(AuthUser { id: 7 }),
+            deadline: Some(Instant::now() + Duration::from_secs(30)),
+            ..RequestContext::default()
+        });
+        let context = RequestContext::of(&req);
+        assert_eq!(context.caller().unwrap().id, 7);
+        assert!(context.remaining_time().unwrap() > Duration::from_secs(29));
+    }
+
+    #[tokio::test]
+    async fn timeouts_stop_at_the_deadline() {
+        let limit = Duration::from_secs(5);
+        assert_eq!(within_deadline(Db::default(), limit), limit);
+
+        let db = Db::until(Instant::now() + Duration::from_secs(1));
+        assert!(within_deadline(db, limit) <= Duration::from_secs(1));
+        assert_eq!(within_deadline(db, Duration::ZERO), Duration::ZERO);
+    }
+}
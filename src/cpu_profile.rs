@@ -0,0 +1,118 @@
+//! CPU profiling of the running server.
+//!
+//! Built with the `pprof` feature, `GET /debug/pprof/profile?seconds=30` samples the
+//! stacks of every thread 99 times per second for the requested duration, then sends
+//! the profile:
+//!
+//! - in the pprof format by default, for `go tool pprof` or any pprof viewer
+//! - as a flame graph SVG with `?format=flamegraph`
+//!
+//! Sampling relies on `SIGPROF` and only costs while a profile is taken; one profile
+//! runs at a time. Without the feature, the endpoint answers 409 Conflict.
+
+use std::pin::Pin;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::error::{AppError, ErrorCode};
+
+/// A profile being taken, completing with its encoded content.
+pub type ProfileFuture = Pin<Box<dyn Future<Output = Result<Vec<u8>, AppError>> + Send>>;
+
+/// Encodings of a profile (`?format=`).
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileFormat {
+    /// Protocol Buffers, as served by Go's `net/http/pprof`
+    #[default]
+    Pprof,
+    /// SVG flame graph
+    Flamegraph,
+}
+
+/// Starts a CPU profile.
+///
+/// Errors are reported before the profile is taken: the returned future only
+/// samples for `duration` (less if the server starts shutting down) and encodes
+/// the result.
+///
+/// # Returns
+///
+/// * `Result<ProfileFuture, AppError>` - The profile being taken, or an
+///   `AppError::Conflict` if another profile is running
+#[cfg(feature = "pprof")]
+pub fn start(duration: Duration, format: ProfileFormat) -> Result<ProfileFuture, AppError> {
+    use pprof::ProfilerGuardBuilder;
+    use pprof::protos::Message;
+
+    use crate::shutdown::stopping;
+
+    /// Samples per second, off the usual timer frequencies to avoid lockstep sampling
+    const FREQUENCY: i32 = 99;
+
+    let guard = ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        // Unwinding through these libraries from the signal handler can deadlock
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| match e {
+            pprof::Error::Running => AppError::Conflict(
+                ErrorCode::ProfileInProgress,
+                "Another CPU profile is being taken".to_string(),
+            ),
+            e => AppError::Internal(format!("CPU profiler failed to start: {}", e)),
+        })?;
+
+    Ok(Box::pin(async move {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {}
+            _ = stopping() => {}
+        }
+
+        // Resolving the symbols of the samples takes a while
+        tokio::task::spawn_blocking(move || {
+            let report = guard
+                .report()
+                .build()
+                .map_err(|e| AppError::Internal(format!("CPU profile failed: {}", e)))?;
+            drop(guard);
+            // A flame graph can't be drawn without any stack
+            if format == ProfileFormat::Flamegraph && report.data.is_empty() {
+                return Err(AppError::Internal(
+                    "No CPU sample taken, the server was idle".to_string(),
+                ));
+            }
+
+            let encoding_error = |e: &dyn std::fmt::Display| {
+                AppError::Internal(format!("CPU profile encoding failed: {}", e))
+            };
+            let mut encoded = Vec::new();
+            match format {
+                ProfileFormat::Pprof => {
+                    let profile = report.pprof().map_err(|e| encoding_error(&e))?;
+                    profile
+                        .write_to_vec(&mut encoded)
+                        .map_err(|e| encoding_error(&e))?;
+                }
+                ProfileFormat::Flamegraph => {
+                    report
+                        .flamegraph(&mut encoded)
+                        .map_err(|e| encoding_error(&e))?;
+                }
+            }
+            Ok(encoded)
+        })
+        .await
+        .map_err(|e| AppError::Internal(format!("CPU profile task failed: {}", e)))?
+    }))
+}
+
+/// Starts a CPU profile: always an `AppError::Conflict` without the `pprof` feature.
+#[cfg(not(feature = "pprof"))]
+pub fn start(_duration: Duration, _format: ProfileFormat) -> Result<ProfileFuture, AppError> {
+    Err(AppError::Conflict(
+        ErrorCode::ProfilingDisabled,
+        "CPU profiling requires a build with the pprof feature".to_string(),
+    ))
+}
@@ -0,0 +1,175 @@
+//! Dashboard composed of independent parts (`GET /api/v1/dashboard`).
+//!
+//! The parts are fetched concurrently: the summary of the users, the stats of the
+//! products and the latest orders from the database, and one part per service of
+//! `DASHBOARD_UPSTREAMS`, whose JSON response is embedded as is:
+//!
+//! ```json
+//! {
+//!   "users": {"total": 120, "average_age": 34.5},
+//!   "products": {"total": 40, "total_stock": 900, "out_of_stock": 3, ...},
+//!   "recent_orders": [{"id": 17, "user_id": 4, "product_id": 9, ...}],
+//!   "upstreams": {"billing": {"balance": 1200}, "shipping": null},
+//!   "errors": {"shipping": "Timed out after 2000 ms"}
+//! }
+//! ```
+//!
+//! Each part has `DASHBOARD_PART_TIMEOUT` to complete. A part that fails or times out
+//! is `null` and the reason is in `errors` under its name, the other parts are still
+//! returned: a slow or broken dependency degrades the dashboard instead of failing it.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper::body::Bytes;
+use hyper::header::{ACCEPT, HeaderValue};
+use hyper::{Request, Uri};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio::time::timeout;
+use tracing::warn;
+
+use crate::config::DashboardConfig;
+use crate::context::within_deadline;
+use crate::db::Db;
+use crate::error::AppError;
+use crate::http_client::http_client;
+use crate::logging::{REQUEST_ID_HEADER, RequestId};
+use crate::repository::orders::{Order, OrderRepository, PgOrderRepo};
+use crate::repository::products::{PgProductRepo, ProductRepository, ProductStats};
+use crate::repository::users::{PgUserRepo, UserRepository, UserSummary};
+use crate::router::BoxError;
+
+/// Orders listed in `recent_orders`
+const RECENT_ORDERS: i64 = 10;
+/// Largest response of an upstream service, larger ones fail the part
+const MAX_UPSTREAM_BODY: usize = 1024 * 1024;
+
+// Set once at startup
+static DASHBOARD: OnceLock<DashboardConfig> = OnceLock::new();
+
+/// The composed dashboard, see the module documentation.
+#[derive(Serialize, Debug)]
+pub struct Dashboard {
+    pub users: Option<UserSummary>,
+    pub products: Option<ProductStats>,
+    pub recent_orders: Option<Vec<Order>>,
+    /// JSON response of every upstream service, by name
+    pub upstreams: Map<String, Value>,
+    /// Reason of every missing part, by name
+    pub errors: BTreeMap<String, String>,
+}
+
+/// Sets the upstream services and the timeout of the parts.
+/// This function should be called once at application startup.
+pub fn init_dashboard(config: &DashboardConfig) {
+    if DASHBOARD.set(config.clone()).is_err() {
+        warn!("Attempt to reset the dashboard configuration ignored");
+    }
+}
+
+/// Fetches every part of the dashboard concurrently.
+///
+/// # Arguments
+///
+/// * `request_id` - Sent to the upstream services as `X-Request-Id`
+pub async fn compose(db: Db, request_id: Option<&RequestId>) -> Dashboard {
+    let (upstreams, part_timeout) = match DASHBOARD.get() {
+        Some(config) => (config.upstreams.as_slice(), config.part_timeout),
+        None => (&[][..], Duration::from_secs(2)),
+    };
+    // Parts finished after the deadline would be thrown away
+    let part_timeout = within_deadline(db, part_timeout);
+
+    let (user_repo, product_repo, order_repo) =
+        (PgUserRepo(db), PgProductRepo(db), PgOrderRepo(db));
+    let (users, products, recent_orders, fetched) = tokio::join!(
+        part(part_timeout, user_repo.summary()),
+        part(part_timeout, product_repo.stats()),
+        part(part_timeout, order_repo.recent(RECENT_ORDERS)),
+        futures_util::future::join_all(upstreams.iter().map(|(name, url)| async move {
+            (name, part(part_timeout, fetch(url, request_id)).await)
+        })),
+    );
+
+    let mut dashboard = Dashboard {
+        users: None,
+        products: None,
+        recent_orders: None,
+        upstreams: Map::new(),
+        errors: BTreeMap::new(),
+    };
+    dashboard.users = dashboard.keep("users", users);
+    dashboard.products = dashboard.keep("products", products);
+    dashboard.recent_orders = dashboard.keep("recent_orders", recent_orders);
+    for (name, result) in fetched {
+        let value = dashboard.keep(name, result).unwrap_or(Value::Null);
+        dashboard.upstreams.insert(name.clone(), value);
+    }
+    dashboard
+}
+
+impl Dashboard {
+    /// The value of a part, or `None` with its error recorded.
+    fn keep<T>(&mut self, name: &str, result: Result<T, String>) -> Option<T> {
+        result
+            .map_err(|reason| {
+                warn!(part = name, "Dashboard part missing: {}", reason);
+                self.errors.insert(name.to_string(), reason);
+            })
+            .ok()
+    }
+}
+
+/// Runs a part within its time limit, the error being the reason shown to clients.
+async fn part<T>(
+    limit: Duration,
+    future: impl Future<Output = Result<T, AppError>>,
+) -> Result<T, String> {
+    match timeout(limit, future).await {
+        Ok(Ok(value)) => Ok(value),
+        // The details are logged, not shown
+        Ok(Err(AppError::BadGateway(reason))) => Err(reason),
+        Ok(Err(e)) => {
+            warn!("Dashboard part failed: {:?}", e);
+            Err("Unavailable".to_string())
+        }
+        Err(_) => Err(format!("Timed out after {} ms", limit.as_millis())),
+    }
+}
+
+/// `GET` of an upstream service, its response parsed as JSON.
+async fn fetch(url: &Uri, request_id: Option<&RequestId>) -> Result<Value, AppError> {
+    let mut req = Request::get(url.clone())
+        .header(ACCEPT, HeaderValue::from_static("application/json"))
+        .body(
+            Empty::<Bytes>::new()
+                .map_err(|never| match never {})
+                .boxed_unsync(),
+        )
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    if let Some(id) = request_id.and_then(|id| HeaderValue::from_str(&id.0).ok()) {
+        req.headers_mut().insert(REQUEST_ID_HEADER, id);
+    }
+
+    let res = http_client().request(req).await.map_err(|e| {
+        warn!(%url, "Dashboard upstream unreachable: {}", e);
+        AppError::BadGateway("Unreachable".to_string())
+    })?;
+    if !res.status().is_success() {
+        return Err(AppError::BadGateway(format!(
+            "Answered {}",
+            res.status().as_u16()
+        )));
+    }
+    let body = Limited::new(res.into_body(), MAX_UPSTREAM_BODY)
+        .collect()
+        .await
+        .map_err(|e: BoxError| AppError::BadGateway(format!("Invalid response: {}", e)))?
+        .to_bytes();
+    serde_json::from_slice(&body)
+        .map_err(|_| AppError::BadGateway("Invalid response: not JSON".to_string()))
+}
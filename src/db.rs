@@ -1,82 +1,518 @@
+mod breaker;
+mod consistency;
+mod cursor;
+mod deadline;
+mod lock;
+mod migrations;
+mod notify;
+pub mod probe;
+mod request_scope;
+mod statements;
+mod tls;
+
+pub use consistency::{CONSISTENCY_TOKEN_HEADER, Reads};
+pub use cursor::fetch_in_batches;
+pub use lock::DistributedLock;
+pub use migrations::{
+    MigrationStatus, Phase, PlannedMigration, migration_status, plan_migrations,
+    rollback_migration, run_migrations,
+};
+pub use notify::{listen, notify};
+use request_scope::Server;
+pub use request_scope::{DbConnection, request_scope};
+
 use bb8_postgres::PostgresConnectionManager;
-use bb8_postgres::bb8::{Pool, PooledConnection};
-use bb8_postgres::tokio_postgres::{Config, Error as PgError, NoTls};
-use std::env;
-// Arc (Atomic Reference Counting) allows safely sharing the pool between multiple threads
-// It maintains a count of references and only deallocates when all references are dropped
-use std::sync::Arc;
-// OnceLock ensures the pool is initialized exactly once in a thread-safe manner
-// Perfect for global resources that should only be created once
-use std::sync::OnceLock;
+use bb8_postgres::bb8::{Pool, PooledConnection, RunError};
+use bb8_postgres::tokio_postgres::{Config, Error as PgError};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+// RwLock allows many concurrent readers (every request getting a connection)
+// and a single writer (initialization at startup and closing at shutdown)
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use hyper::header::HeaderValue;
+use serde::Serialize;
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tracing::{info, warn};
+
+use crate::config::{DatabaseConfig, ReplicaConfig};
+use crate::error::AppError;
+use breaker::{BreakerSettings, CircuitBreaker};
+pub use statements::CachedTransaction;
+use statements::CachingConnectionManager;
+
+/// A connection checked out of the pool, returned to it when dropped
+type PooledClient = PooledConnection<'static, CachingConnectionManager>;
 
 // Static global variable to store the connection pool
-// This is initialized once and remains available throughout the application's lifecycle
-static DB_POOL: OnceLock<Arc<Pool<PostgresConnectionManager<NoTls>>>> = OnceLock::new();
+// It is set at startup and taken back at shutdown so the connections can be closed.
+// Pool is internally reference counted, so cloning it is cheap and every clone
+// shares the same connections.
+static DB_POOL: RwLock<Option<Pool<CachingConnectionManager>>> = RwLock::new(None);
+
+// Read replicas, empty when none is configured (see `consistency`)
+static REPLICAS: RwLock<Vec<Arc<Replica>>> = RwLock::new(Vec::new());
+
+// Replica the next read starts with, so the reads go to each of them in turn
+static NEXT_REPLICA: AtomicUsize = AtomicUsize::new(0);
+
+// Connection settings, kept after init_pool for the dedicated connections
+// of DistributedLock and the pool statistics
+static DB_CONFIG: OnceLock<DatabaseConfig> = OnceLock::new();
+
+// TLS connector of those connections, built from DB_SSLMODE by init_pool
+static DB_TLS: OnceLock<MakeRustlsConnect> = OnceLock::new();
+
+// Fail fast while the database behind a pool is down (see `breaker`)
+static PRIMARY_BREAKER: CircuitBreaker = CircuitBreaker::new("primary");
+
+// Requests currently waiting for a connection, which bb8 doesn't report
+static WAITING: AtomicU32 = AtomicU32::new(0);
+
+/// Counts a request as waiting for a connection until dropped (also when cancelled).
+struct WaitGuard;
+
+impl WaitGuard {
+    fn new() -> Self {
+        WAITING.fetch_add(1, Ordering::Relaxed);
+        WaitGuard
+    }
+}
+
+impl Drop for WaitGuard {
+    fn drop(&mut self) {
+        WAITING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A read replica, with its own pool and breaker.
+struct Replica {
+    pool: Pool<CachingConnectionManager>,
+    breaker: CircuitBreaker,
+}
+
+/// Snapshot of the connection pool state, reported by the readiness endpoint.
+#[derive(Debug, Serialize)]
+pub struct PoolStatus {
+    /// Maximum number of connections the pool can open
+    pub max_size: u32,
+    /// Connections currently open (idle + in use)
+    pub connections: u32,
+    /// Open connections waiting to be used
+    pub idle_connections: u32,
+    /// Connections currently checked out by requests
+    pub active_connections: u32,
+    /// Requests waiting for a connection right now
+    pub waiting: u32,
+    /// Requests that had to wait for a connection since startup
+    pub waited: u64,
+    /// Requests that timed out waiting for a connection since startup
+    pub timed_out: u64,
+    /// Gets served by the connection a request already held since startup, without
+    /// going through the pool (see `request_scope`)
+    pub reused: u64,
+    /// Total time requests spent waiting for a connection since startup
+    #[serde(skip)]
+    pub wait_time: std::time::Duration,
+    /// Queries that reused a statement prepared by their connection since startup
+    pub statement_cache_hits: u64,
+    /// Queries that had to prepare their statement since startup
+    pub statement_cache_misses: u64,
+}
+
+/// PostgreSQL connection configuration, shared by the pool and the dedicated
+/// connections of `DistributedLock`.
+fn pg_config(config: &DatabaseConfig) -> Config {
+    Config::new()
+        .host(&config.host)
+        .port(config.port)
+        .dbname(&config.name)
+        .user(&config.user)
+        .password(&config.password)
+        .ssl_mode(tls::pg_ssl_mode(config.ssl_mode))
+        .to_owned()
+}
 
 /// Initializes the PostgreSQL connection pool.
 /// This function should be called at application startup.
 ///
+/// # Arguments
+///
+/// * `config` - Connection and pool settings
+///
 /// # Returns
 ///
-/// * `Result<(), PgError>` - Success or a PostgreSQL error
-pub async fn init_pool() -> Result<(), PgError> {
-    // PostgreSQL connection configuration using environment variables
-    // with default values if they're not defined
-    let pg_config = Config::new()
-        .host(env::var("DB_HOST").unwrap_or_else(|_| "localhost".to_string()))
-        .port(env::var("DB_PORT").map_or(5432, |p| p.parse().unwrap_or(5432)))
-        .dbname(env::var("DB_NAME").unwrap_or_else(|_| "test-db".to_string()))
-        .user(env::var("DB_USER").unwrap_or_else(|_| "postgres".to_string()))
-        .password(env::var("DB_PASSWORD").unwrap_or_else(|_| "123456".to_string()))
-        .to_owned();
+/// * `Result<(), String>` - Success, or a PostgreSQL error or an unreadable
+///   `DB_SSLROOTCERT`
+pub async fn init_pool(config: &DatabaseConfig) -> Result<(), String> {
+    let tls = tls::connector(config)?;
+    let pool = build_pool(config, pg_config(config), tls.clone())
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut replicas = Vec::new();
+    for (i, replica) in config.replicas.iter().enumerate() {
+        let pool = build_pool(config, replica_config(config, replica), tls.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+        replicas.push(Arc::new(Replica {
+            pool,
+            // replica-1, replica-2... in the order of DB_READ_URLS
+            breaker: CircuitBreaker::named(format!("replica-{}", i + 1)),
+        }));
+    }
 
+    // Try to set the global pool only once
+    // If it's already set, ignore this attempt (protection against reinitialization)
+    let mut global = DB_POOL.write().unwrap();
+    if global.is_some() {
+        warn!("Attempt to restart ignored pool");
+    } else {
+        *global = Some(pool);
+        *REPLICAS.write().unwrap() = replicas;
+        let _ = DB_CONFIG.set(config.clone());
+        let _ = DB_TLS.set(tls);
+    }
+
+    info!("Connection to PostgreSQL established successfully");
+    for replica in &config.replicas {
+        info!(
+            "Reads served by the replica {}:{}",
+            replica.host, replica.port
+        );
+    }
+    Ok(())
+}
+
+/// PostgreSQL connection configuration of a replica.
+fn replica_config(config: &DatabaseConfig, replica: &ReplicaConfig) -> Config {
+    Config::new()
+        .host(&replica.host)
+        .port(replica.port)
+        .dbname(&replica.name)
+        .user(&replica.user)
+        .password(&replica.password)
+        .ssl_mode(tls::pg_ssl_mode(config.ssl_mode))
+        .to_owned()
+}
+
+/// Builds a pool of connections to the server of `pg_config`.
+async fn build_pool(
+    config: &DatabaseConfig,
+    pg_config: Config,
+    tls: MakeRustlsConnect,
+) -> Result<Pool<CachingConnectionManager>, PgError> {
     // Creating the PostgreSQL connection manager with the configuration
-    // NoTls indicates that TLS won't be used (unencrypted connection)
-    let manager = PostgresConnectionManager::new(pg_config, NoTls);
+    // The connections are encrypted according to DB_SSLMODE (see `tls`)
+    // Its connections keep the statements they prepare (see `statements`)
+    let manager = CachingConnectionManager::new(PostgresConnectionManager::new(pg_config, tls));
 
     // Building the pool with specific configurations
-    let pool = Pool::builder()
-        .max_size(15) // Maximum number of connections in the pool
-        .min_idle(Some(2)) // Keep at least 2 idle connections available
-        .connection_timeout(std::time::Duration::from_secs(15)) // Maximum time to obtain a connection
-        .idle_timeout(Some(std::time::Duration::from_secs(60 * 10))) // Maximum time a connection can remain idle
-        .max_lifetime(Some(std::time::Duration::from_secs(60 * 30))) // Maximum lifetime for any connection
+    Pool::builder()
+        .max_size(config.pool_max_size) // Maximum number of connections in the pool
+        .min_idle(Some(config.pool_min_idle)) // Idle connections kept available
+        .connection_timeout(config.connection_timeout) // Maximum time to obtain a connection
+        .idle_timeout(Some(Duration::from_secs(60 * 10))) // Maximum time a connection can remain idle
+        .max_lifetime(Some(Duration::from_secs(60 * 30))) // Maximum lifetime for any connection
         .build(manager)
-        .await?;
+        .await
+}
 
-    // Wrap the pool in Arc for thread-safe sharing
-    let pool = Arc::new(pool);
+/// Database of a request or of a job: the pools, and the deadline its queries are held
+/// to (see `deadline`). Every database interaction goes through one.
+///
+/// The router puts the one of each request in its `RequestContext`, and the handlers
+/// build their repositories on it (`PgUserRepo(context.db)`); work done outside of a
+/// request uses `Db::default()`, without a deadline. The connections of a request are
+/// the ones it keeps (see `request_scope`).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Db {
+    deadline: Option<Instant>,
+}
 
-    // Try to set the global pool only once
-    // If it's already set, ignore this attempt (protection against reinitialization)
-    DB_POOL.set(pool).unwrap_or_else(|_| {
-        eprintln!("Attempt to restart ignored pool");
-    });
+impl Db {
+    /// The database without a deadline (`Db::default()`), of the work done outside of
+    /// a request.
+    pub const UNBOUNDED: Db = Db { deadline: None };
 
-    println!("Connection to PostgreSQL established successfully");
-    Ok(())
+    /// The database of a request answered with 504 at `deadline`.
+    pub fn until(deadline: Instant) -> Self {
+        Db {
+            deadline: Some(deadline),
+        }
+    }
+
+    /// Time left before the deadline, `None` without one.
+    pub fn remaining_time(&self) -> Option<Duration> {
+        deadline::remaining_time(self.deadline)
+    }
+
+    /// Runs `future` until the deadline.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<F::Output, AppError>` - The output, or `AppError::Timeout` once the
+    ///   deadline has passed (`future` is then dropped)
+    pub async fn before_deadline<F: Future>(&self, future: F) -> Result<F::Output, AppError> {
+        deadline::before(self.deadline, future).await
+    }
+
+    /// Gets a connection from the pool, or the one the current request already used.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<DbConnection, AppError>` - A connection, `AppError::Unavailable` while
+    ///   the database is down or an `AppError::Pool` error
+    pub async fn connection(&self) -> Result<DbConnection, AppError> {
+        if let Some(conn) = DbConnection::take_held(Server::Primary) {
+            return Ok(conn);
+        }
+
+        // Clone the global pool so the lock is not held while waiting for a connection
+        // If the pool isn't initialized (or was already closed), return an error
+        let pool = DB_POOL
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| AppError::Pool("The pool is not initialized".to_string()))?;
+
+        let conn = checkout(&pool, &PRIMARY_BREAKER, self.deadline).await?;
+        Ok(DbConnection::new(conn, Server::Primary))
+    }
+
+    /// Gets a connection for a read-only query: to a replica when some are configured
+    /// and the current request allows it (see `consistency`), to the primary otherwise.
+    ///
+    /// The reads go to the replicas in turn. A replica whose breaker is open is
+    /// skipped, and the primary serves the reads while none of them is up.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<DbConnection, AppError>` - A connection or an `AppError::Pool` error
+    pub async fn read_connection(&self) -> Result<DbConnection, AppError> {
+        self.reads_connection(Reads::current()).await
+    }
+
+    /// Gets a connection for reads going to `reads`.
+    async fn reads_connection(&self, reads: Reads) -> Result<DbConnection, AppError> {
+        if reads == Reads::Primary {
+            return self.connection().await;
+        }
+        // The request keeps reading from the replica it started with
+        let conn = match DbConnection::take_held(Server::Replica) {
+            Some(conn) => conn,
+            None => match self.replica_connection().await? {
+                Some(conn) => conn,
+                None => return self.connection().await,
+            },
+        };
+        if let Reads::ReplicaAfter(lsn) = reads {
+            let max_wait = DB_CONFIG
+                .get()
+                .map_or(Duration::ZERO, |config| config.replica_max_wait);
+            if !consistency::caught_up(&conn, lsn, max_wait).await {
+                return self.connection().await;
+            }
+        }
+        Ok(conn)
+    }
+
+    /// Gets a connection to the next replica that is up.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<DbConnection>, AppError>` - A connection, `None` without a
+    ///   replica or while all of them are down, or an `AppError::Pool` error
+    async fn replica_connection(&self) -> Result<Option<DbConnection>, AppError> {
+        // Clone the list so the lock is not held while waiting for a connection
+        let replicas = REPLICAS.read().unwrap().clone();
+        if replicas.is_empty() {
+            return Ok(None);
+        }
+        let start = NEXT_REPLICA.fetch_add(1, Ordering::Relaxed);
+        for i in 0..replicas.len() {
+            let replica = &replicas[(start + i) % replicas.len()];
+            match checkout(&replica.pool, &replica.breaker, self.deadline).await {
+                Ok(conn) => return Ok(Some(DbConnection::new(conn, Server::Replica))),
+                Err(AppError::Unavailable(..)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Runs `f` inside a transaction on a pooled connection.
+    ///
+    /// The transaction is committed if `f` returns `Ok` and rolled back if it returns
+    /// `Err`. If `f` panics (or the request is cancelled), the transaction is dropped
+    /// without committing, which also rolls it back. Its statements can be prepared
+    /// with `tx.prepare_cached`, as those of the connection.
+    ///
+    /// ```ignore
+    /// let order = db.transaction(async |tx| {
+    ///     tx.execute("UPDATE products SET stock = stock - 1 WHERE id = $1", &[&id]).await?;
+    ///     tx.execute("INSERT INTO orders (product_id) VALUES ($1)", &[&id]).await?;
+    ///     Ok(())
+    /// })
+    /// .await?;
+    /// ```
+    ///
+    /// # Returns
+    ///
+    /// * `Result<T, AppError>` - The value returned by `f`, or its error (or the
+    ///   error of `BEGIN`/`COMMIT`)
+    pub async fn transaction<T, F>(&self, f: F) -> Result<T, AppError>
+    where
+        F: AsyncFnOnce(&CachedTransaction<'_>) -> Result<T, AppError>,
+    {
+        let mut conn = self.connection().await?;
+        let tx = conn.cached_transaction().await?;
+
+        match f(&tx).await {
+            Ok(value) => {
+                tx.commit().await?;
+                Ok(value)
+            }
+            Err(e) => {
+                // A failed rollback leaves nothing to undo: the server discards the
+                // transaction when the connection is reset
+                if let Err(rollback) = tx.rollback().await {
+                    warn!("Transaction rollback failed: {}", rollback);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Whether the primary is known to be down: its breaker is open, and the writes fail
+/// at once with `AppError::Unavailable`.
+pub fn primary_is_down() -> bool {
+    DB_CONFIG.get().is_some_and(|config| {
+        PRIMARY_BREAKER.is_open(BreakerSettings {
+            threshold: config.breaker_threshold,
+            cooldown: config.breaker_cooldown,
+        })
+    })
 }
 
-/// Gets a connection from the pool.
-/// This function should be used every time database interaction is needed.
+/// Gets a connection from `pool` before `deadline`, unless its breaker is open.
 ///
 /// # Returns
 ///
-/// * `Result<PooledConnection<'static, PostgresConnectionManager<NoTls>>, String>` - A connection
-///   from the pool or an error message
-pub async fn get_connection()
--> Result<PooledConnection<'static, PostgresConnectionManager<NoTls>>, String> {
-    // Try to get a reference to the global pool
-    // If the pool isn't initialized, return an error
-    let pool = DB_POOL
-        .get()
-        .ok_or_else(|| "The pool is not initialized".to_string())?;
-
-    // The 'static lifetime here indicates that the connection can exist for the entire
-    // duration of the program. This is important because the pool itself is static,
-    // and connections borrowed from it need to have a lifetime that can potentially
-    // match the pool's lifetime.
-
-    // Get a connection from the pool and convert any error to String
-    pool.get().await.map_err(|e| e.to_string())
+/// * `Result<PooledClient, AppError>` - A connection, `AppError::Unavailable` while
+///   the breaker is open or an `AppError::Pool` error
+async fn checkout(
+    pool: &Pool<CachingConnectionManager>,
+    breaker: &CircuitBreaker,
+    deadline: Option<Instant>,
+) -> Result<PooledClient, AppError> {
+    let Some(config) = DB_CONFIG.get() else {
+        return Err(AppError::Pool("The pool is not initialized".to_string()));
+    };
+    let settings = BreakerSettings {
+        threshold: config.breaker_threshold,
+        cooldown: config.breaker_cooldown,
+    };
+    breaker.admit(settings)?;
+
+    // The 'static lifetime here indicates that the connection is not tied to a borrow
+    // of the pool: get_owned() keeps its own reference to the pool, so the connection
+    // stays valid even if the global pool is closed while it's in use.
+    let _waiting = WaitGuard::new();
+    let result = deadline::before(deadline, pool.get_owned()).await?;
+    match &result {
+        Ok(_) => breaker.record(settings, true),
+        // Not a sign of a database down when every connection is in use
+        Err(RunError::TimedOut) if pool.state().connections >= config.pool_max_size => {}
+        Err(_) => breaker.record(settings, false),
+    }
+    // Converting any error into AppError::Pool
+    let mut conn = result?;
+    deadline::apply(&mut conn, deadline).await?;
+    Ok(conn)
+}
+
+/// The consistency token to return after a write, `None` without a replica (see
+/// `consistency`).
+pub async fn consistency_token() -> Option<HeaderValue> {
+    if REPLICAS.read().unwrap().is_empty() {
+        return None;
+    }
+    let token = async { consistency::token(&Db::default().connection().await?).await };
+    match token.await {
+        Ok(token) => Some(token),
+        Err(e) => {
+            warn!("Consistency token unavailable: {}", e);
+            None
+        }
+    }
+}
+
+/// Runs independent queries at the same time, returning all their results or the
+/// first error (the other queries are then dropped).
+///
+/// Each query is a future of a `Result` whose error converts into `AppError`, so
+/// repository calls and queries on a `Client` can be mixed. How the queries share
+/// connections depends on them:
+///
+/// - Repository calls take a connection each (the one held by the request, then pooled
+///   ones): N queries hold N connections for a moment
+/// - Queries on the same `Client` are pipelined by tokio-postgres, sent one after the
+///   other on that connection without waiting for the previous response
+///
+/// ```ignore
+/// let (total, users) = join_queries!(
+///     PgUserRepo(db).count(&filter),
+///     PgUserRepo(db).list(&page, &filter),
+/// )?;
+/// ```
+macro_rules! join_queries {
+    ($($query:expr),+ $(,)?) => {
+        ::tokio::try_join!($(async { $query.await.map_err($crate::error::AppError::from) }),+)
+    };
+}
+pub(crate) use join_queries;
+
+/// Closes the connection pool.
+/// This function should be called at shutdown, once in-flight requests have finished.
+///
+/// The pool is removed from the global state, so no new connections can be obtained.
+/// Idle connections are closed as soon as the last reference to the pool is dropped
+/// (connections still in use keep the pool alive until they are returned).
+pub fn close_pool() {
+    // The replica connections are closed the same way
+    REPLICAS.write().unwrap().clear();
+    if let Some(pool) = DB_POOL.write().unwrap().take() {
+        let state = pool.state();
+        info!(
+            "Closing PostgreSQL pool ({} connections, {} idle)",
+            state.connections, state.idle_connections
+        );
+    }
+}
+
+/// Reports the current state of the connection pool.
+///
+/// # Returns
+///
+/// * `Option<PoolStatus>` - The pool statistics, or `None` if the pool is not initialized
+pub fn pool_status() -> Option<PoolStatus> {
+    let pool = DB_POOL.read().unwrap().clone()?;
+    let state = pool.state();
+    let (statement_cache_hits, statement_cache_misses) = statements::cache_stats();
+
+    Some(PoolStatus {
+        max_size: DB_CONFIG.get()?.pool_max_size,
+        connections: state.connections,
+        idle_connections: state.idle_connections,
+        active_connections: state.connections - state.idle_connections,
+        waiting: WAITING.load(Ordering::Relaxed),
+        waited: state.statistics.get_waited,
+        timed_out: state.statistics.get_timed_out,
+        reused: request_scope::reused_count(),
+        wait_time: state.statistics.get_wait_time,
+        statement_cache_hits,
+        statement_cache_misses,
+    })
 }
@@ -0,0 +1,197 @@
+//! Circuit breaker of the connection pools.
+//!
+//! While PostgreSQL is down every get waits `DB_CONNECTION_TIMEOUT` for a connection
+//! that can't be opened, holding the request and its client that long, and each of
+//! them makes the pool try to connect again. The breaker of a pool counts the gets that
+//! failed because no connection could be opened; after `DB_BREAKER_THRESHOLD` in a
+//! row it opens, and the gets fail at once with `AppError::Unavailable` (503 with
+//! `Retry-After`) for `DB_BREAKER_COOLDOWN`.
+//!
+//! Then a single get is let through to probe the database (half-open): the breaker
+//! closes if it succeeds and opens again for another cooldown if it fails. A probe
+//! that never reports (its request was cancelled) is replaced after a cooldown.
+//!
+//! A get that times out while every connection of the pool is in use isn't counted:
+//! the pool is saturated, the database is fine.
+
+use std::borrow::Cow;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::error::AppError;
+use crate::metrics;
+
+/// Settings of a breaker, see the module documentation.
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerSettings {
+    /// Failed gets in a row opening the breaker, 0 disables it
+    pub threshold: u32,
+    pub cooldown: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { since: Instant },
+}
+
+impl State {
+    /// Value of the `db_circuit_state` metric
+    fn metric(self) -> i64 {
+        match self {
+            State::Closed { .. } => 0,
+            State::Open { .. } => 1,
+            State::HalfOpen { .. } => 2,
+        }
+    }
+}
+
+/// Breaker of one pool.
+pub struct CircuitBreaker {
+    /// `primary` or `replica-<n>`, for the logs and the metrics
+    pool: Cow<'static, str>,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub const fn new(pool: &'static str) -> Self {
+        CircuitBreaker {
+            pool: Cow::Borrowed(pool),
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Breaker of a pool named at runtime.
+    pub fn named(pool: String) -> Self {
+        CircuitBreaker {
+            pool: Cow::Owned(pool),
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Whether a get may try the pool, failing with `AppError::Unavailable` while the
+    /// breaker is open.
+    pub fn admit(&self, settings: BreakerSettings) -> Result<(), AppError> {
+        self.admit_at(settings, Instant::now())
+    }
+
+    fn admit_at(&self, settings: BreakerSettings, now: Instant) -> Result<(), AppError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let retry_after = match *state {
+            State::Closed { .. } => return Ok(()),
+            State::Open { until } if now < until => until - now,
+            State::HalfOpen { since } if now < since + settings.cooldown => {
+                since + settings.cooldown - now
+            }
+            // This get is the probe
+            State::Open { .. } | State::HalfOpen { .. } => {
+                self.set(&mut state, State::HalfOpen { since: now });
+                return Ok(());
+            }
+        };
+        metrics::observe_circuit_rejection(&self.pool);
+        Err(AppError::Unavailable(
+            "Database unavailable".to_string(),
+            retry_after,
+        ))
+    }
+
+    /// Whether the gets fail at once: the breaker is open, or half-open with its probe
+    /// running. Doesn't admit a probe.
+    pub fn is_open(&self, settings: BreakerSettings) -> bool {
+        let now = Instant::now();
+        match *self.state.lock().unwrap_or_else(|e| e.into_inner()) {
+            State::Closed { .. } => false,
+            State::Open { until } => now < until,
+            State::HalfOpen { since } => now < since + settings.cooldown,
+        }
+    }
+
+    /// Records the outcome of a get that was admitted.
+    ///
+    /// # Arguments
+    ///
+    /// * `healthy` - `false` when no connection could be opened
+    pub fn record(&self, settings: BreakerSettings, healthy: bool) {
+        self.record_at(settings, healthy, Instant::now());
+    }
+
+    fn record_at(&self, settings: BreakerSettings, healthy: bool, now: Instant) {
+        if settings.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let next = match (*state, healthy) {
+            (State::Closed { failures: 0 }, true) => return,
+            (_, true) => State::Closed { failures: 0 },
+            (State::Closed { failures }, false) if failures + 1 < settings.threshold => {
+                State::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (_, false) => State::Open {
+                until: now + settings.cooldown,
+            },
+        };
+        self.set(&mut state, next);
+    }
+
+    fn set(&self, state: &mut State, next: State) {
+        match (*state, next) {
+            (State::Closed { .. }, State::Open { .. }) => {
+                warn!("Circuit of the {} database opened, failing fast", self.pool)
+            }
+            (State::HalfOpen { .. }, State::Open { .. }) => {
+                warn!("Probe of the {} database failed", self.pool)
+            }
+            (State::HalfOpen { .. }, State::Closed { .. }) => {
+                info!("Circuit of the {} database closed", self.pool)
+            }
+            _ => {}
+        }
+        *state = next;
+        metrics::observe_circuit_state(&self.pool, next.metric());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_failures_and_closes_after_a_probe() {
+        let settings = BreakerSettings {
+            threshold: 2,
+            cooldown: Duration::from_secs(10),
+        };
+        let breaker = CircuitBreaker::new("primary");
+        let start = Instant::now();
+
+        breaker.record_at(settings, false, start);
+        assert!(breaker.admit_at(settings, start).is_ok());
+        breaker.record_at(settings, false, start);
+        let Err(AppError::Unavailable(_, retry_after)) =
+            breaker.admit_at(settings, start + Duration::from_secs(4))
+        else {
+            panic!("The breaker should be open");
+        };
+        assert_eq!(retry_after, Duration::from_secs(6));
+
+        // A single probe after the cooldown, which fails
+        let later = start + Duration::from_secs(10);
+        assert!(breaker.admit_at(settings, later).is_ok());
+        assert!(breaker.admit_at(settings, later).is_err());
+        breaker.record_at(settings, false, later);
+        assert!(breaker.admit_at(settings, later).is_err());
+
+        // The next probe succeeds
+        let recovered = later + Duration::from_secs(10);
+        assert!(breaker.admit_at(settings, recovered).is_ok());
+        breaker.record_at(settings, true, recovered);
+        assert!(breaker.admit_at(settings, recovered).is_ok());
+        assert!(breaker.admit_at(settings, recovered).is_ok());
+    }
+}
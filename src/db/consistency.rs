@@ -0,0 +1,130 @@
+//! Read-your-writes consistency with a read replica.
+//!
+//! With `DB_READ_URLS`, the reads of `GET` requests go to the replicas in turn, which
+//! replay the writes of the primary a little later. Writes, and the reads of the requests
+//! writing (`POST`, `PUT`, `PATCH`, `DELETE`), stay on the primary.
+//!
+//! So that a client reads what it just wrote, every successful write request answers
+//! with a consistency token in `X-Consistency-Token`: the position of the primary in
+//! its write-ahead log once the write is committed (an LSN, `0/16B3748`). A read sending
+//! the token back in the same header is served by the replica once it has replayed the
+//! log up to that position: the read waits up to `DB_REPLICA_MAX_WAIT` for it, then
+//! goes to the primary instead.
+//!
+//! Without a replica no token is sent, and the header of the requests is ignored.
+
+use std::time::{Duration, Instant};
+
+use bb8_postgres::tokio_postgres::types::PgLsn;
+use hyper::Request;
+use hyper::header::HeaderValue;
+use tracing::warn;
+
+use super::DbConnection;
+use crate::error::AppError;
+use crate::metrics;
+
+/// Header carrying the consistency token, in responses to writes and in reads
+pub const CONSISTENCY_TOKEN_HEADER: &str = "x-consistency-token";
+
+/// Delay between two checks of the replica while waiting for it to catch up
+const CATCH_UP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+tokio::task_local! {
+    /// Where the reads of the request handled by the current task go
+    static READS: Reads;
+}
+
+/// Where the reads of a request go.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reads {
+    /// The replica, however late it is
+    Replica,
+    /// The replica once it has replayed the log up to the token, the primary otherwise
+    ReplicaAfter(PgLsn),
+    /// The primary: write requests, background jobs and startup
+    Primary,
+}
+
+impl Reads {
+    /// Where the reads of a request go, according to its method and token.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Reads, AppError>` - The reads, or an `AppError::Validation` if the
+    ///   token isn't an LSN
+    pub fn of_request<B>(req: &Request<B>) -> Result<Reads, AppError> {
+        if !req.method().is_safe() {
+            return Ok(Reads::Primary);
+        }
+        let Some(token) = req.headers().get(CONSISTENCY_TOKEN_HEADER) else {
+            return Ok(Reads::Replica);
+        };
+        token
+            .to_str()
+            .ok()
+            .and_then(|token| token.trim().parse::<PgLsn>().ok())
+            .map(Reads::ReplicaAfter)
+            .ok_or_else(|| {
+                AppError::Validation(format!(
+                    "{} must be a token returned by a write",
+                    CONSISTENCY_TOKEN_HEADER
+                ))
+            })
+    }
+
+    /// Where the reads of the current task go; the primary outside of a request.
+    pub fn current() -> Reads {
+        READS.try_with(|reads| *reads).unwrap_or(Reads::Primary)
+    }
+
+    /// Runs a request handler with its reads going to `self`.
+    pub async fn scope<F: Future>(self, handler: F) -> F::Output {
+        READS.scope(self, handler).await
+    }
+}
+
+/// Whether the replica behind `conn` has replayed the log up to `lsn`, waiting for it
+/// at most `max_wait`.
+pub(super) async fn caught_up(conn: &DbConnection, lsn: PgLsn, max_wait: Duration) -> bool {
+    let start = Instant::now();
+    let mut waited = false;
+    loop {
+        match replayed(conn, lsn).await {
+            Ok(true) => {
+                metrics::observe_replica_read(if waited { "waited" } else { "replica" });
+                return true;
+            }
+            Ok(false) if start.elapsed() + CATCH_UP_POLL_INTERVAL <= max_wait => {
+                waited = true;
+                tokio::time::sleep(CATCH_UP_POLL_INTERVAL).await;
+            }
+            Ok(false) => break,
+            Err(e) => {
+                warn!("Replica position unavailable: {}", e);
+                break;
+            }
+        }
+    }
+    metrics::observe_replica_read("primary");
+    false
+}
+
+async fn replayed(conn: &DbConnection, lsn: PgLsn) -> Result<bool, AppError> {
+    // A server that isn't in recovery (promoted replica) is up to date
+    let statement = conn
+        .prepare_cached(
+            "SELECT NOT pg_is_in_recovery() \
+             OR COALESCE(pg_last_wal_replay_lsn() >= $1, false)",
+        )
+        .await?;
+    Ok(conn.query_one(&statement, &[&lsn]).await?.get(0))
+}
+
+/// The token of the writes committed so far, read from `conn`, a primary connection.
+pub(super) async fn token(conn: &DbConnection) -> Result<HeaderValue, AppError> {
+    let statement = conn.prepare_cached("SELECT pg_current_wal_lsn()").await?;
+    let lsn: PgLsn = conn.query_one(&statement, &[]).await?.get(0);
+    HeaderValue::from_str(&lsn.to_string())
+        .map_err(|e| AppError::Internal(format!("Invalid LSN: {}", e)))
+}
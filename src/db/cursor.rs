@@ -0,0 +1,110 @@
+//! Server-side cursors for result sets too large to be loaded at once.
+//!
+//! The query runs in a read-only transaction on a pooled connection and its rows are
+//! fetched in batches of `BATCH_SIZE`, so only one batch is in memory at a time and
+//! a slow client slows the fetching down instead of piling rows up.
+
+use bb8_postgres::tokio_postgres::Row;
+use bb8_postgres::tokio_postgres::types::ToSql;
+use futures_util::{Stream, stream};
+use tracing::warn;
+
+use super::{Db, DbConnection, Reads};
+use crate::error::AppError;
+
+/// Rows fetched per round trip
+const BATCH_SIZE: usize = 500;
+
+/// Cursors are scoped to their transaction, so one name per connection is enough
+const CURSOR_NAME: &str = "stream_cursor";
+
+/// Runs `sql` and yields its rows batch by batch.
+///
+/// The connection is taken from the pool when the first batch is requested and given
+/// back after the last one. A stream dropped before the end (client disconnected)
+/// rolls the transaction back first. It reads from the server chosen for the reads of
+/// the request creating the stream (see `consistency`), the body being sent after: the
+/// deadline of the request doesn't hold it.
+///
+/// # Arguments
+///
+/// * `sql` - A `SELECT`; every value in it must be trusted, the others are parameters
+/// * `params` - Values of the `$1`, `$2`... placeholders of `sql`
+pub fn fetch_in_batches(
+    sql: String,
+    params: Vec<Box<dyn ToSql + Send + Sync>>,
+) -> impl Stream<Item = Result<Vec<Row>, AppError>> + Send {
+    let cursor = Cursor {
+        sql,
+        params,
+        reads: Reads::current(),
+        conn: None,
+        finished: false,
+    };
+
+    stream::try_unfold(cursor, async |mut cursor| {
+        let batch = cursor.next_batch().await?;
+        Ok(batch.map(|rows| (rows, cursor)))
+    })
+}
+
+struct Cursor {
+    sql: String,
+    params: Vec<Box<dyn ToSql + Send + Sync>>,
+    reads: Reads,
+    /// `None` until the first batch is requested
+    conn: Option<DbConnection>,
+    /// Set once the transaction has been committed
+    finished: bool,
+}
+
+impl Cursor {
+    async fn next_batch(&mut self) -> Result<Option<Vec<Row>>, AppError> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => {
+                // Kept before the transaction starts, so a failure below rolls it back
+                let conn = self
+                    .conn
+                    .insert(Db::default().reads_connection(self.reads).await?);
+                conn.batch_execute("BEGIN READ ONLY").await?;
+                let params = self.params.iter().map(|p| &**p as _).collect::<Vec<_>>();
+                conn.execute(
+                    &format!("DECLARE {} NO SCROLL CURSOR FOR {}", CURSOR_NAME, self.sql),
+                    &params,
+                )
+                .await?;
+                conn
+            }
+        };
+
+        let rows = conn
+            .query(&format!("FETCH {} FROM {}", BATCH_SIZE, CURSOR_NAME), &[])
+            .await?;
+        if rows.len() < BATCH_SIZE {
+            conn.batch_execute("COMMIT").await?;
+            self.finished = true;
+        }
+
+        Ok((!rows.is_empty()).then_some(rows))
+    }
+}
+
+impl Drop for Cursor {
+    fn drop(&mut self) {
+        // The connection must not go back to the pool inside the transaction
+        if let Some(conn) = self.conn.take()
+            && !self.finished
+        {
+            tokio::spawn(async move {
+                if let Err(e) = conn.batch_execute("ROLLBACK").await {
+                    warn!("Rollback of an interrupted cursor failed: {}", e);
+                }
+            });
+        }
+    }
+}
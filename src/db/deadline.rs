@@ -0,0 +1,84 @@
+//! Deadline of the request the queries run for.
+//!
+//! The router gives every handler the timeout of its route (`REQUEST_TIMEOUT` or
+//! `ROUTE_TIMEOUTS`) and drops it once the time is up. That only cancels the queries on
+//! the client side: PostgreSQL would keep running them, holding their connection and
+//! locks, for a client that has given up. So the deadline is kept by the [`Db`] of the
+//! request (`RequestContext::db`), and the repositories built on it hold their queries
+//! to it:
+//!
+//! - a connection handed to the request gets a `statement_timeout` of the time left, so
+//!   PostgreSQL cancels a statement still running at the deadline (answered with 504
+//!   `TIMEOUT`)
+//! - waiting for a pool connection, and retrying a read, stop at the deadline
+//!
+//! Without a deadline (`Db::default()`: jobs, streamed bodies) the connections run
+//! without `statement_timeout`.
+//!
+//! [`Db`]: super::Db
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use tokio::time::timeout_at;
+
+use super::statements::Connection;
+use crate::error::AppError;
+
+/// Time left before `deadline`, `None` without a deadline.
+pub(super) fn remaining_time(deadline: Option<Instant>) -> Option<Duration> {
+    deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
+
+/// Runs `future` until `deadline`.
+///
+/// # Returns
+///
+/// * `Result<F::Output, AppError>` - The output, or `AppError::Timeout` once the
+///   deadline has passed (`future` is then dropped)
+pub(super) async fn before<F: Future>(
+    deadline: Option<Instant>,
+    future: F,
+) -> Result<F::Output, AppError> {
+    let Some(deadline) = deadline else {
+        return Ok(future.await);
+    };
+    timeout_at(deadline.into(), future)
+        .await
+        .map_err(|_| expired())
+}
+
+/// The error of a query that ran past the deadline of its request.
+fn expired() -> AppError {
+    AppError::Timeout("The request ran past its deadline".to_string())
+}
+
+/// Sets the `statement_timeout` of a connection to the time left before `deadline`, or
+/// removes it without a deadline.
+pub(super) async fn apply(
+    conn: &mut Connection,
+    deadline: Option<Instant>,
+) -> Result<(), AppError> {
+    let timeout = match remaining_time(deadline) {
+        Some(left) if left.is_zero() => return Err(expired()),
+        // Whole milliseconds, 0 would disable the timeout
+        Some(left) => left.as_millis().max(1) as u64,
+        None => 0,
+    };
+    Ok(conn.set_statement_timeout(timeout).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn futures_stop_at_the_deadline() {
+        assert_eq!(remaining_time(None), None);
+
+        let deadline = Some(Instant::now() + Duration::from_millis(50));
+        assert!(remaining_time(deadline).is_some_and(|left| left <= Duration::from_millis(50)));
+        let result = before(deadline, tokio::time::sleep(Duration::from_secs(5))).await;
+        assert!(matches!(result, Err(AppError::Timeout(_))));
+    }
+}
@@ -0,0 +1,88 @@
+//! Distributed mutex based on PostgreSQL advisory locks.
+//!
+//! Ensures that only one instance runs a singleton task (migrations, schedulers, ...)
+//! when several replicas share the database.
+//!
+//! The lock is held by a dedicated connection instead of a pooled one: a session-level
+//! advisory lock lives as long as its connection, so a pooled connection would keep it
+//! after being returned to the pool. When the holder releases the lock, drops it or
+//! crashes, the connection closes and PostgreSQL frees the lock, letting a waiting
+//! instance take over.
+//!
+//! The wait, the hold time and the contention of every lock are exported in `/metrics`.
+
+use std::time::Instant;
+
+use bb8_postgres::tokio_postgres::Client;
+use tracing::{info, warn};
+
+use super::{DB_CONFIG, DB_TLS, pg_config};
+use crate::error::AppError;
+use crate::metrics::{observe_lock_hold, observe_lock_wait};
+
+/// An acquired advisory lock, released when dropped.
+pub struct DistributedLock {
+    name: String,
+    acquired_at: Instant,
+    // Keeps the session (and therefore the lock) alive
+    _client: Client,
+}
+
+impl DistributedLock {
+    /// Acquires the lock named `name`, waiting while another instance holds it.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the lock, hashed by PostgreSQL (`hashtext`) into the lock key
+    ///
+    /// # Returns
+    ///
+    /// * `Result<DistributedLock, AppError>` - The held lock, or the connection error
+    pub async fn acquire(name: &str) -> Result<Self, AppError> {
+        let (Some(config), Some(tls)) = (DB_CONFIG.get(), DB_TLS.get()) else {
+            return Err(AppError::Pool("The pool is not initialized".to_string()));
+        };
+        let (client, connection) = pg_config(config).connect(tls.clone()).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                warn!("Advisory lock connection closed with error: {}", e);
+            }
+        });
+
+        // Tried first without waiting, to tell the contended acquisitions
+        let start = Instant::now();
+        let contended = !client
+            .query_one("SELECT pg_try_advisory_lock(hashtext($1))", &[&name])
+            .await?
+            .get::<_, bool>(0);
+        if contended {
+            client
+                .execute("SELECT pg_advisory_lock(hashtext($1))", &[&name])
+                .await?;
+        }
+        observe_lock_wait(name, start.elapsed(), contended);
+        info!(
+            "Acquired lock '{}' after waiting {} ms",
+            name,
+            start.elapsed().as_millis()
+        );
+
+        Ok(DistributedLock {
+            name: name.to_string(),
+            acquired_at: Instant::now(),
+            _client: client,
+        })
+    }
+}
+
+impl Drop for DistributedLock {
+    fn drop(&mut self) {
+        // Dropping the client closes the connection, which releases the lock
+        observe_lock_hold(&self.name, self.acquired_at.elapsed());
+        info!(
+            "Released lock '{}' after holding it {} ms",
+            self.name,
+            self.acquired_at.elapsed().as_millis()
+        );
+    }
+}
@@ -0,0 +1,568 @@
+//! Versioned database migrations.
+//!
+//! The SQL files of the `migrations/` directory are embedded in the binary and applied
+//! in version order at startup. Applied versions are recorded in the `schema_migrations`
+//! table, so each migration runs only once per database.
+//!
+//! To add a migration, create `migrations/V<version>__<name>.sql`, the script undoing
+//! it `migrations/U<version>__<name>.sql`, and append both to `MIGRATIONS` with its
+//! [`Phase`]. Never edit a migration that has already been applied somewhere.
+//!
+//! ## Blue/green deployments
+//! While a release rolls out, its instances and the ones of the previous release use
+//! the same database, so schema changes follow the expand/contract pattern:
+//!
+//! - `Expand` migrations only add (tables, nullable columns, indexes): the previous
+//!   release keeps working with them, and they're applied at startup
+//! - `Contract` migrations remove or rewrite what the previous release may use
+//!   (`DROP TABLE`, `DROP COLUMN`, renames, type changes, `SET NOT NULL`): they're only
+//!   applied by `migrate --contract`, run once no instance of the previous release is
+//!   left
+//!
+//! A pending contract migration holds back the ones after it, which may depend on it;
+//! a new database, which no previous release uses, gets every migration. The tests of
+//! this module check that the migrations with destructive statements are contract
+//! ones. [`plan_migrations`] (`migrate --plan`) tells what would be applied, without
+//! applying it.
+//!
+//! The latest migrations can be undone one by one with [`rollback_migration`]
+//! (`rust-backend migrate rollback`), and [`migration_status`] lists which ones a
+//! database has.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+use super::statements::Connection;
+use super::{Db, DistributedLock};
+use crate::error::AppError;
+
+/// When a migration can be applied, see the module documentation.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Compatible with the previous release, applied at startup
+    Expand,
+    /// Breaks the previous release, applied by `migrate --contract`
+    Contract,
+}
+
+impl Phase {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::Expand => "expand",
+            Phase::Contract => "contract",
+        }
+    }
+}
+
+/// A versioned SQL script.
+struct Migration {
+    version: i64,
+    name: &'static str,
+    phase: Phase,
+    sql: &'static str,
+    /// Script undoing `sql`
+    undo: &'static str,
+}
+
+/// A migration and whether a database has it.
+#[derive(Serialize, Debug)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: &'static str,
+    pub phase: Phase,
+    /// When it was applied (RFC 3339, UTC), `None` while pending
+    pub applied_at: Option<String>,
+}
+
+/// A pending migration, and whether [`run_migrations`] would apply it.
+#[derive(Debug)]
+pub struct PlannedMigration {
+    pub version: i64,
+    pub name: &'static str,
+    pub phase: Phase,
+    pub sql: &'static str,
+    /// `false` from the first contract migration on, unless they're allowed
+    pub runs: bool,
+}
+
+/// Every migration, in the order they must be applied
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_users",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V1__create_users.sql"),
+        undo: include_str!("../../migrations/U1__create_users.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "create_products",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V2__create_products.sql"),
+        undo: include_str!("../../migrations/U2__create_products.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "add_user_credentials",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V3__add_user_credentials.sql"),
+        undo: include_str!("../../migrations/U3__add_user_credentials.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "create_orders",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V4__create_orders.sql"),
+        undo: include_str!("../../migrations/U4__create_orders.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "create_collection_versions",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V5__create_collection_versions.sql"),
+        undo: include_str!("../../migrations/U5__create_collection_versions.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "track_user_changes",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V6__track_user_changes.sql"),
+        undo: include_str!("../../migrations/U6__track_user_changes.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "add_user_avatar",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V7__add_user_avatar.sql"),
+        undo: include_str!("../../migrations/U7__add_user_avatar.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "create_jobs",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V8__create_jobs.sql"),
+        undo: include_str!("../../migrations/U8__create_jobs.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "create_audit_log",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V9__create_audit_log.sql"),
+        undo: include_str!("../../migrations/U9__create_audit_log.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "soft_delete_users",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V10__soft_delete_users.sql"),
+        undo: include_str!("../../migrations/U10__soft_delete_users.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "add_user_roles",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V11__add_user_roles.sql"),
+        undo: include_str!("../../migrations/U11__add_user_roles.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "create_experiment_exposures",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V12__create_experiment_exposures.sql"),
+        undo: include_str!("../../migrations/U12__create_experiment_exposures.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "create_saved_views",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V13__create_saved_views.sql"),
+        undo: include_str!("../../migrations/U13__create_saved_views.sql"),
+    },
+    Migration {
+        version: 14,
+        name: "add_public_ids",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V14__add_public_ids.sql"),
+        undo: include_str!("../../migrations/U14__add_public_ids.sql"),
+    },
+    Migration {
+        version: 15,
+        name: "add_row_versions",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V15__add_row_versions.sql"),
+        undo: include_str!("../../migrations/U15__add_row_versions.sql"),
+    },
+    Migration {
+        version: 16,
+        name: "add_job_progress",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V16__add_job_progress.sql"),
+        undo: include_str!("../../migrations/U16__add_job_progress.sql"),
+    },
+    Migration {
+        version: 17,
+        name: "create_webhooks",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V17__create_webhooks.sql"),
+        undo: include_str!("../../migrations/U17__create_webhooks.sql"),
+    },
+    Migration {
+        version: 18,
+        name: "create_user_logins",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V18__create_user_logins.sql"),
+        undo: include_str!("../../migrations/U18__create_user_logins.sql"),
+    },
+    Migration {
+        version: 19,
+        name: "partition_by_month",
+        phase: Phase::Contract,
+        sql: include_str!("../../migrations/V19__partition_by_month.sql"),
+        undo: include_str!("../../migrations/U19__partition_by_month.sql"),
+    },
+    Migration {
+        version: 20,
+        name: "create_archives",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V20__create_archives.sql"),
+        undo: include_str!("../../migrations/U20__create_archives.sql"),
+    },
+    Migration {
+        version: 21,
+        name: "create_change_feed",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V21__create_change_feed.sql"),
+        undo: include_str!("../../migrations/U21__create_change_feed.sql"),
+    },
+    Migration {
+        version: 22,
+        name: "create_product_embeddings",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V22__create_product_embeddings.sql"),
+        undo: include_str!("../../migrations/U22__create_product_embeddings.sql"),
+    },
+    Migration {
+        version: 23,
+        name: "create_user_recommendations",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V23__create_user_recommendations.sql"),
+        undo: include_str!("../../migrations/U23__create_user_recommendations.sql"),
+    },
+    Migration {
+        version: 24,
+        name: "create_search_terms",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V24__create_search_terms.sql"),
+        undo: include_str!("../../migrations/U24__create_search_terms.sql"),
+    },
+    Migration {
+        version: 25,
+        name: "create_oidc",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V25__create_oidc.sql"),
+        undo: include_str!("../../migrations/U25__create_oidc.sql"),
+    },
+    Migration {
+        version: 26,
+        name: "add_scim_columns",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V26__add_scim_columns.sql"),
+        undo: include_str!("../../migrations/U26__add_scim_columns.sql"),
+    },
+    Migration {
+        version: 27,
+        name: "create_passkeys",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V27__create_passkeys.sql"),
+        undo: include_str!("../../migrations/U27__create_passkeys.sql"),
+    },
+    Migration {
+        version: 28,
+        name: "create_ledger",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V28__create_ledger.sql"),
+        undo: include_str!("../../migrations/U28__create_ledger.sql"),
+    },
+    Migration {
+        version: 29,
+        name: "create_coupons",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V29__create_coupons.sql"),
+        undo: include_str!("../../migrations/U29__create_coupons.sql"),
+    },
+    Migration {
+        version: 30,
+        name: "create_carts",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V30__create_carts.sql"),
+        undo: include_str!("../../migrations/U30__create_carts.sql"),
+    },
+    Migration {
+        version: 31,
+        name: "add_job_context",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V31__add_job_context.sql"),
+        undo: include_str!("../../migrations/U31__add_job_context.sql"),
+    },
+    Migration {
+        version: 32,
+        name: "add_audit_public_ids",
+        phase: Phase::Expand,
+        sql: include_str!("../../migrations/V32__add_audit_public_ids.sql"),
+        undo: include_str!("../../migrations/U32__add_audit_public_ids.sql"),
+    },
+];
+
+/// Name of the lock that serializes migrations between server instances
+/// starting at the same time
+const MIGRATION_LOCK: &str = "schema_migrations";
+
+/// Applies the pending migrations, up to the first contract one unless `contract`.
+/// Must be called after `init_pool` and before serving requests.
+///
+/// Each migration runs in its own transaction together with its `schema_migrations`
+/// record, so a failing migration leaves no partial changes and is retried on the next start.
+///
+/// # Returns
+///
+/// * `Result<(), AppError>` - Success or the error of the failing migration
+pub async fn run_migrations(contract: bool) -> Result<(), AppError> {
+    // Other instances wait here and then find every migration applied
+    let _lock = DistributedLock::acquire(MIGRATION_LOCK).await?;
+    let mut conn = Db::default().connection().await?;
+    let plan = plan(&conn, contract).await?;
+
+    let mut applied = 0;
+    for (i, migration) in plan.iter().enumerate() {
+        if !migration.runs {
+            warn!(
+                "{} migrations applied, migration V{}__{} contracts the schema: it and {} \
+                 more are left pending, apply them with `migrate --contract` once no \
+                 instance of the previous release runs",
+                applied,
+                migration.version,
+                migration.name,
+                plan.len() - i - 1
+            );
+            return Ok(());
+        }
+
+        info!(
+            "Applying migration V{}__{}",
+            migration.version, migration.name
+        );
+        let tx = conn.transaction().await?;
+        tx.batch_execute(migration.sql).await?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+            &[&migration.version, &migration.name],
+        )
+        .await?;
+        tx.commit().await?;
+        applied += 1;
+    }
+
+    info!(
+        "Database schema up to date ({} migrations applied)",
+        applied
+    );
+    Ok(())
+}
+
+/// Lists the pending migrations and whether `run_migrations(contract)` would apply
+/// them, without applying anything (`migrate --plan`).
+/// Must be called after `init_pool`.
+pub async fn plan_migrations(contract: bool) -> Result<Vec<PlannedMigration>, AppError> {
+    let conn = Db::default().connection().await?;
+    plan(&conn, contract).await
+}
+
+/// The pending migrations, the contract ones and the ones after them only running
+/// with `contract` or on a new database.
+async fn plan(conn: &Connection, contract: bool) -> Result<Vec<PlannedMigration>, AppError> {
+    create_history(conn).await?;
+    let rows = conn
+        .query("SELECT version FROM schema_migrations", &[])
+        .await?;
+    let applied: HashSet<i64> = rows.iter().map(|row| row.get(0)).collect();
+    // No previous release uses a new database
+    let contract = contract || applied.is_empty();
+
+    let mut runs = true;
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .map(|migration| {
+            runs &= contract || migration.phase == Phase::Expand;
+            PlannedMigration {
+                version: migration.version,
+                name: migration.name,
+                phase: migration.phase,
+                sql: migration.sql,
+                runs,
+            }
+        })
+        .collect())
+}
+
+/// Undoes the latest applied migration, in a transaction with its
+/// `schema_migrations` record.
+/// Must be called after `init_pool`, while no server uses the database.
+///
+/// # Returns
+///
+/// * `Result<Option<MigrationStatus>, AppError>` - The migration undone, `None` when no
+///   migration is applied, or the error of its undo script
+pub async fn rollback_migration() -> Result<Option<MigrationStatus>, AppError> {
+    let _lock = DistributedLock::acquire(MIGRATION_LOCK).await?;
+    let mut conn = Db::default().connection().await?;
+    create_history(&conn).await?;
+
+    let tx = conn.transaction().await?;
+    let Some(row) = tx
+        .query_opt(
+            "SELECT version FROM schema_migrations ORDER BY version DESC LIMIT 1",
+            &[],
+        )
+        .await?
+    else {
+        return Ok(None);
+    };
+    let version: i64 = row.get(0);
+    let Some(migration) = MIGRATIONS.iter().find(|m| m.version == version) else {
+        return Err(AppError::Internal(format!(
+            "Migration V{} is not known to this version of the server",
+            version
+        )));
+    };
+
+    info!(
+        "Undoing migration V{}__{}",
+        migration.version, migration.name
+    );
+    tx.batch_execute(migration.undo).await?;
+    tx.execute(
+        "DELETE FROM schema_migrations WHERE version = $1",
+        &[&migration.version],
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(Some(MigrationStatus {
+        version: migration.version,
+        name: migration.name,
+        phase: migration.phase,
+        applied_at: None,
+    }))
+}
+
+/// Lists every migration with the time it was applied to the database.
+/// Must be called after `init_pool`.
+pub async fn migration_status() -> Result<Vec<MigrationStatus>, AppError> {
+    let conn = Db::default().connection().await?;
+    create_history(&conn).await?;
+
+    let rows = conn
+        .query(
+            "SELECT version, \
+             to_char(applied_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') \
+             FROM schema_migrations",
+            &[],
+        )
+        .await?;
+    let applied: HashMap<i64, String> = rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+
+    Ok(MIGRATIONS
+        .iter()
+        .map(|migration| MigrationStatus {
+            version: migration.version,
+            name: migration.name,
+            phase: migration.phase,
+            applied_at: applied.get(&migration.version).cloned(),
+        })
+        .collect())
+}
+
+/// Creates the table recording the applied migrations, on a new database.
+async fn create_history(conn: &Connection) -> Result<(), AppError> {
+    conn.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+             version BIGINT PRIMARY KEY,
+             name TEXT NOT NULL,
+             applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+         )",
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Statements of a script, uppercase on one line, without the comments and the
+    /// bodies of the functions.
+    fn statements(sql: &str) -> Vec<String> {
+        let code = sql
+            .lines()
+            .map(|line| line.split("--").next().unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join(" ");
+        // The bodies are between the odd and the even `$$`
+        let code = code.split("$$").step_by(2).collect::<Vec<_>>().join(" ");
+        code.split(';')
+            .map(|statement| statement.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|statement| !statement.is_empty())
+            .map(|statement| statement.to_uppercase())
+            .collect()
+    }
+
+    /// Whether a statement removes or rewrites something the previous release may use.
+    fn is_destructive(statement: &str) -> bool {
+        ["DROP TABLE", "DROP VIEW", "TRUNCATE", "DELETE FROM"]
+            .iter()
+            .any(|prefix| statement.starts_with(prefix))
+            || statement.starts_with("ALTER TABLE")
+                && [" DROP COLUMN ", " RENAME ", " SET NOT NULL", " TYPE "]
+                    .iter()
+                    .any(|part| statement.contains(part))
+    }
+
+    #[test]
+    fn destructive_statements_are_found_outside_function_bodies() {
+        let sql = "-- DROP TABLE users;\n\
+                   CREATE FUNCTION f() RETURNS trigger AS $$ BEGIN DELETE FROM t; END $$;\n\
+                   ALTER TABLE users ADD COLUMN event_type TEXT NOT NULL DEFAULT '';\n\
+                   alter table users\n    alter column age type bigint;";
+        let destructive = statements(sql)
+            .into_iter()
+            .filter(|statement| is_destructive(statement))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            destructive,
+            ["ALTER TABLE USERS ALTER COLUMN AGE TYPE BIGINT"]
+        );
+    }
+
+    #[test]
+    fn destructive_migrations_are_contract_ones() {
+        for migration in MIGRATIONS {
+            if let Some(statement) = statements(migration.sql)
+                .into_iter()
+                .find(|statement| is_destructive(statement))
+            {
+                assert_eq!(
+                    migration.phase,
+                    Phase::Contract,
+                    "V{}__{} must be a contract migration: {}",
+                    migration.version,
+                    migration.name,
+                    statement
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Messages between the instances sharing the database (`LISTEN`/`NOTIFY`).
+//!
+//! [`notify`] sends a payload on a channel; PostgreSQL delivers it to every session
+//! listening on that channel, on every instance (this one included). [`listen`] keeps
+//! such a session open on a dedicated connection: like the advisory locks, `LISTEN`
+//! belongs to the session, so a pooled connection would keep listening after being
+//! returned to the pool.
+//!
+//! Delivery is best effort. Payloads sent while the listening connection is down are
+//! lost, it is opened again after [`RECONNECT_DELAY`].
+
+use std::time::Duration;
+
+use bb8_postgres::tokio_postgres::AsyncMessage;
+use futures_util::{StreamExt, stream};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use super::{DB_CONFIG, DB_TLS, Db, pg_config};
+use crate::error::AppError;
+use crate::shutdown::stopping;
+
+/// Wait before listening again after the connection was lost
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Sends a payload to the sessions listening on `channel`.
+///
+/// Outside a transaction the payload is delivered right away; PostgreSQL limits it to
+/// 8000 bytes.
+pub async fn notify(channel: &str, payload: &str) -> Result<(), AppError> {
+    let conn = Db::default().connection().await?;
+    conn.execute("SELECT pg_notify($1, $2)", &[&channel, &payload])
+        .await?;
+    Ok(())
+}
+
+/// Calls `on_payload` with every payload sent on `channel`, until the shutdown.
+///
+/// Meant to be spawned at startup, after `init_pool`. The connection is opened again
+/// when it is lost.
+///
+/// # Arguments
+///
+/// * `channel` - Name of the channel, an SQL identifier
+/// * `on_payload` - Called in the order the payloads were sent
+pub async fn listen(channel: &'static str, mut on_payload: impl FnMut(&str) + Send) {
+    loop {
+        tokio::select! {
+            result = listen_once(channel, &mut on_payload) => {
+                if let Err(e) = result {
+                    warn!("Stopped listening to '{}': {}", channel, e);
+                }
+            }
+            _ = stopping() => return,
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = stopping() => return,
+        }
+    }
+}
+
+/// Listens on a new connection until it is lost.
+async fn listen_once(channel: &str, on_payload: &mut impl FnMut(&str)) -> Result<(), AppError> {
+    let (Some(config), Some(tls)) = (DB_CONFIG.get(), DB_TLS.get()) else {
+        return Err(AppError::Pool("The pool is not initialized".to_string()));
+    };
+    let (client, mut connection) = pg_config(config).connect(tls.clone()).await?;
+
+    // The notifications arrive on the connection, which has to be polled for the
+    // client to work too
+    let (sender, mut notifications) = mpsc::unbounded_channel();
+    let driver = tokio::spawn(async move {
+        let mut messages = stream::poll_fn(|cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            if let AsyncMessage::Notification(notification) = message? {
+                let _ = sender.send(notification);
+            }
+        }
+        Ok::<_, bb8_postgres::tokio_postgres::Error>(())
+    });
+
+    client
+        .batch_execute(&format!("LISTEN \"{}\"", channel))
+        .await?;
+    info!("Listening to the notifications of '{}'", channel);
+    while let Some(notification) = notifications.recv().await {
+        on_payload(notification.payload());
+    }
+
+    // The sender is dropped when the connection ends
+    match driver.await {
+        Ok(Err(e)) => Err(e.into()),
+        _ => Err(AppError::Pool("Connection closed".to_string())),
+    }
+}
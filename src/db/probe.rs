@@ -0,0 +1,99 @@
+//! Checks of the database servers, run by `selftest` before a deployment takes traffic
+//! (see `tasks::selftest`).
+//!
+//! They go through the pools, so they also check the settings of the connections
+//! (TLS, credentials, database name) the server will use.
+
+use bb8_postgres::tokio_postgres::Client;
+use uuid::Uuid;
+
+use super::{Db, REPLICAS, checkout};
+use crate::error::AppError;
+
+/// Version of the server of `client`, and whether it replays another one.
+async fn describe(client: &Client) -> Result<String, AppError> {
+    let row = client
+        .query_one(
+            "SELECT current_setting('server_version'), pg_is_in_recovery()",
+            &[],
+        )
+        .await?;
+    let version: String = row.get(0);
+    let standby: bool = row.get(1);
+    Ok(format!(
+        "PostgreSQL {}{}",
+        version,
+        if standby { ", standby" } else { "" }
+    ))
+}
+
+/// Version of the primary server.
+pub async fn primary() -> Result<String, AppError> {
+    let conn = Db::default().connection().await?;
+    describe(&conn).await
+}
+
+/// Version of each replica, named `replica-<n>` in the order of `DB_READ_URLS`.
+pub async fn replicas() -> Vec<(String, Result<String, AppError>)> {
+    let replicas = REPLICAS.read().unwrap().clone();
+    let mut results = Vec::with_capacity(replicas.len());
+    for (i, replica) in replicas.iter().enumerate() {
+        let result = match checkout(&replica.pool, &replica.breaker, None).await {
+            Ok(conn) => describe(&conn).await,
+            Err(e) => Err(e),
+        };
+        results.push((format!("replica-{}", i + 1), result));
+    }
+    results
+}
+
+/// Writes a row to a scratch table of the primary, reads it back, updates and deletes
+/// it, in a transaction rolled back at the end: nothing is left behind.
+///
+/// The table is temporary, so the probe needs no migration and can't collide with
+/// another instance running it.
+pub async fn read_write() -> Result<(), AppError> {
+    let mut conn = Db::default().connection().await?;
+    // Not prepared with the cache, the table only exists in this transaction
+    let tx = conn.cached_transaction().await?;
+    tx.batch_execute(
+        "CREATE TEMP TABLE selftest_probe (id INT PRIMARY KEY, value TEXT NOT NULL) \
+         ON COMMIT DROP",
+    )
+    .await?;
+
+    let value = Uuid::new_v4().to_string();
+    tx.execute(
+        "INSERT INTO selftest_probe (id, value) VALUES (1, $1)",
+        &[&value],
+    )
+    .await?;
+    let read: String = tx
+        .query_one("SELECT value FROM selftest_probe WHERE id = 1", &[])
+        .await?
+        .get(0);
+    if read != value {
+        return Err(AppError::Internal(format!(
+            "Read '{}' back instead of '{}'",
+            read, value
+        )));
+    }
+    let updated = tx
+        .execute(
+            "UPDATE selftest_probe SET value = 'updated' WHERE id = 1",
+            &[],
+        )
+        .await?;
+    let deleted = tx
+        .execute("DELETE FROM selftest_probe WHERE id = 1", &[])
+        .await?;
+    if (updated, deleted) != (1, 1) {
+        return Err(AppError::Internal(format!(
+            "Updated {} and deleted {} rows instead of 1",
+            updated, deleted
+        )));
+    }
+
+    tx.rollback().await?;
+    Ok(())
+}
@@ -0,0 +1,124 @@
+//! Request-scoped connections.
+//!
+//! A handler often runs several repository calls (a list reads the collection version,
+//! counts the rows and fetches a page), each getting its own connection. Inside
+//! [`request_scope`] (every handler, see `Router`), a connection is kept by the
+//! request once released, and the next `Db::connection` of the same request takes it
+//! back instead of going through the pool. It returns to the pool when the request
+//! ends.
+//!
+//! Calls running at the same time in a request (`join!`) still get one connection
+//! each, the extra ones going back to the pool. Connections released outside of a
+//! request scope (streamed bodies, spawned tasks) go back to the pool as before.
+//!
+//! With a read replica, the request keeps one connection of each pool: reads take back
+//! the replica one, writes the primary one.
+
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::PooledClient;
+use super::statements::Connection;
+
+tokio::task_local! {
+    /// Connections released by previous calls of the request
+    static HELD: RefCell<Held>;
+}
+
+/// Database server a connection is open to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Server {
+    Primary,
+    Replica,
+}
+
+#[derive(Default)]
+struct Held {
+    primary: Option<PooledClient>,
+    replica: Option<PooledClient>,
+}
+
+impl Held {
+    fn slot(&mut self, server: Server) -> &mut Option<PooledClient> {
+        match server {
+            Server::Primary => &mut self.primary,
+            Server::Replica => &mut self.replica,
+        }
+    }
+}
+
+// Connections taken back from the request instead of the pool since startup
+static REUSED: AtomicU64 = AtomicU64::new(0);
+
+/// Runs a request handler with its own connection slot.
+pub async fn request_scope<F: Future>(handler: F) -> F::Output {
+    HELD.scope(RefCell::default(), handler).await
+}
+
+/// A connection of the pool, kept by the current request when dropped inside its
+/// scope and returned to the pool otherwise.
+pub struct DbConnection {
+    /// Always set until dropped
+    conn: Option<PooledClient>,
+    server: Server,
+}
+
+impl DbConnection {
+    pub(super) fn new(conn: PooledClient, server: Server) -> Self {
+        DbConnection {
+            conn: Some(conn),
+            server,
+        }
+    }
+
+    /// Takes the connection to `server` kept by the current request, if any.
+    pub(super) fn take_held(server: Server) -> Option<DbConnection> {
+        let conn = HELD
+            .try_with(|held| held.borrow_mut().slot(server).take())
+            .ok()??;
+        // A connection lost by the server is dropped, the pool replaces it
+        if conn.is_closed() {
+            return None;
+        }
+        REUSED.fetch_add(1, Ordering::Relaxed);
+        Some(DbConnection::new(conn, server))
+    }
+}
+
+impl Deref for DbConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("Connection used after drop")
+    }
+}
+
+impl DerefMut for DbConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().expect("Connection used after drop")
+    }
+}
+
+impl Drop for DbConnection {
+    fn drop(&mut self) {
+        let Some(conn) = self.conn.take() else {
+            return;
+        };
+        // Outside of a request scope, or when the request already keeps one, the
+        // connection is dropped here, which returns it to the pool
+        let server = self.server;
+        let _ = HELD.try_with(move |held| {
+            let mut held = held.borrow_mut();
+            let slot = held.slot(server);
+            if slot.is_none() && !conn.is_closed() {
+                *slot = Some(conn);
+            }
+        });
+    }
+}
+
+/// Connections reused within a request since startup.
+pub fn reused_count() -> u64 {
+    REUSED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,179 @@
+//! Prepared statement cache.
+//!
+//! Every query sent as SQL text is parsed and planned by PostgreSQL before it runs.
+//! Pooled connections keep the statements they prepared, keyed by their SQL text, so
+//! a hot query (`SELECT name, age FROM users WHERE id = $1`) is only prepared once per
+//! connection:
+//!
+//! ```ignore
+//! let conn = db.connection().await?;
+//! let statement = conn.prepare_cached("SELECT name, age FROM users WHERE id = $1").await?;
+//! let row = conn.query_opt(&statement, &[&id]).await?;
+//! ```
+//!
+//! The transactions of `Db::transaction` share the cache of their connection.
+//!
+//! Statements live as long as their connection (`max_lifetime` of the pool). Their
+//! result columns are fixed when they are prepared, so cached queries list their
+//! columns instead of using `SELECT *`, which a migration adding a column would break.
+
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bb8_postgres::PostgresConnectionManager;
+use bb8_postgres::bb8::ManageConnection;
+use bb8_postgres::tokio_postgres::{
+    Client, Error as PgError, GenericClient, Statement, Transaction,
+};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+/// Statements kept per connection; queries built at runtime beyond it are prepared
+/// without being cached
+const MAX_CACHED_STATEMENTS: usize = 128;
+
+// Lookups of the cache since startup, by every connection
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Connection manager of the pool: PostgreSQL connections with a statement cache.
+pub struct CachingConnectionManager {
+    inner: PostgresConnectionManager<MakeRustlsConnect>,
+}
+
+impl CachingConnectionManager {
+    pub fn new(inner: PostgresConnectionManager<MakeRustlsConnect>) -> Self {
+        CachingConnectionManager { inner }
+    }
+}
+
+impl ManageConnection for CachingConnectionManager {
+    type Connection = Connection;
+    type Error = PgError;
+
+    async fn connect(&self) -> Result<Connection, PgError> {
+        Ok(Connection {
+            client: self.inner.connect().await?,
+            statements: Mutex::new(HashMap::new()),
+            statement_timeout: 0,
+        })
+    }
+
+    async fn is_valid(&self, conn: &mut Connection) -> Result<(), PgError> {
+        self.inner.is_valid(&mut conn.client).await
+    }
+
+    fn has_broken(&self, conn: &mut Connection) -> bool {
+        self.inner.has_broken(&mut conn.client)
+    }
+}
+
+/// A pooled PostgreSQL connection, used as a `Client` (through `Deref`).
+pub struct Connection {
+    client: Client,
+    statements: Mutex<HashMap<String, Statement>>,
+    /// `statement_timeout` of the session in milliseconds, 0 for none (see `deadline`)
+    statement_timeout: u64,
+}
+
+impl Connection {
+    /// Prepares a statement, or returns the one this connection already prepared for
+    /// the same SQL.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Statement, PgError>` - The statement, or the error of its preparation
+    pub async fn prepare_cached(&self, sql: &str) -> Result<Statement, PgError> {
+        prepare_cached(&self.client, &self.statements, sql).await
+    }
+
+    /// Sets the `statement_timeout` of the session, unless it already has this value.
+    pub(super) async fn set_statement_timeout(&mut self, millis: u64) -> Result<(), PgError> {
+        if self.statement_timeout != millis {
+            self.client
+                .batch_execute(&format!("SET statement_timeout = {}", millis))
+                .await?;
+            self.statement_timeout = millis;
+        }
+        Ok(())
+    }
+
+    /// Starts a transaction, rolled back when dropped without being committed.
+    pub async fn cached_transaction(&mut self) -> Result<CachedTransaction<'_>, PgError> {
+        Ok(CachedTransaction {
+            transaction: self.client.transaction().await?,
+            statements: &self.statements,
+        })
+    }
+}
+
+/// A transaction preparing its statements with the cache of its connection, used as a
+/// `Transaction` (through `Deref`).
+pub struct CachedTransaction<'a> {
+    transaction: Transaction<'a>,
+    statements: &'a Mutex<HashMap<String, Statement>>,
+}
+
+impl CachedTransaction<'_> {
+    /// Prepares a statement, as [`Connection::prepare_cached`].
+    pub async fn prepare_cached(&self, sql: &str) -> Result<Statement, PgError> {
+        prepare_cached(&self.transaction, self.statements, sql).await
+    }
+
+    pub async fn commit(self) -> Result<(), PgError> {
+        self.transaction.commit().await
+    }
+
+    pub async fn rollback(self) -> Result<(), PgError> {
+        self.transaction.rollback().await
+    }
+}
+
+impl<'a> Deref for CachedTransaction<'a> {
+    type Target = Transaction<'a>;
+
+    fn deref(&self) -> &Transaction<'a> {
+        &self.transaction
+    }
+}
+
+async fn prepare_cached(
+    client: &impl GenericClient,
+    statements: &Mutex<HashMap<String, Statement>>,
+    sql: &str,
+) -> Result<Statement, PgError> {
+    let lock = || statements.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(statement) = lock().get(sql) {
+        HITS.fetch_add(1, Ordering::Relaxed);
+        return Ok(statement.clone());
+    }
+    MISSES.fetch_add(1, Ordering::Relaxed);
+
+    // Not locked while preparing: the connection is used by a single request
+    let statement = client.prepare(sql).await?;
+    let mut statements = lock();
+    if statements.len() < MAX_CACHED_STATEMENTS {
+        statements.insert(sql.to_string(), statement.clone());
+    }
+    Ok(statement)
+}
+
+impl Deref for Connection {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+impl DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut Client {
+        &mut self.client
+    }
+}
+
+/// Cache hits and misses since startup.
+pub fn cache_stats() -> (u64, u64) {
+    (HITS.load(Ordering::Relaxed), MISSES.load(Ordering::Relaxed))
+}
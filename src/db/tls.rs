@@ -0,0 +1,130 @@
+//! Encryption of the connections to PostgreSQL (`DB_SSLMODE`).
+//!
+//! Connections are made with rustls, the TLS library of the HTTPS listener. With
+//! `disable` the TLS connector is never used: the server is asked for plain connections.
+
+use std::sync::Arc;
+
+use bb8_postgres::tokio_postgres::config::SslMode as PgSslMode;
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{
+    CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature,
+};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    ClientConfig, DigitallySignedStruct, Error as TlsError, RootCertStore, SignatureScheme,
+};
+
+use crate::config::{DatabaseConfig, SslMode};
+
+/// The `sslmode` tokio-postgres negotiates with the server.
+pub(super) fn pg_ssl_mode(mode: SslMode) -> PgSslMode {
+    match mode {
+        SslMode::Disable => PgSslMode::Disable,
+        SslMode::Require | SslMode::VerifyFull => PgSslMode::Require,
+    }
+}
+
+/// Builds the TLS connector of the connections.
+///
+/// # Returns
+///
+/// * `Result<MakeRustlsConnect, String>` - The connector, or an error if
+///   `DB_SSLROOTCERT` can't be read
+pub(super) fn connector(config: &DatabaseConfig) -> Result<MakeRustlsConnect, String> {
+    let client = match config.ssl_mode {
+        SslMode::Disable => ClientConfig::builder()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth(),
+        SslMode::Require => ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AnyCertificate(Arc::new(
+                ring::default_provider(),
+            ))))
+            .with_no_client_auth(),
+        SslMode::VerifyFull => ClientConfig::builder()
+            .with_root_certificates(trusted_roots(config)?)
+            .with_no_client_auth(),
+    };
+    Ok(MakeRustlsConnect::new(client))
+}
+
+/// The CAs of `DB_SSLROOTCERT`, or the Mozilla root CAs.
+fn trusted_roots(config: &DatabaseConfig) -> Result<RootCertStore, String> {
+    let Some(path) = &config.ssl_root_cert else {
+        return Ok(RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        });
+    };
+
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Unable to read CA '{}': {}", path.display(), e))?;
+    let mut roots = RootCertStore::empty();
+    let (_, invalid) = roots.add_parsable_certificates(certs);
+    if roots.is_empty() {
+        return Err(format!("No valid CA in '{}'", path.display()));
+    }
+    if invalid > 0 {
+        return Err(format!(
+            "{} invalid certificates in '{}'",
+            invalid,
+            path.display()
+        ));
+    }
+    Ok(roots)
+}
+
+/// Accepts the certificate of any server (`require`); the handshake signatures are
+/// still checked, so the session is encrypted with the key of that certificate.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
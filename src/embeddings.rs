@@ -0,0 +1,525 @@
+//! Embeddings of the products, for the semantic search (`GET /products/semantic-search`).
+//!
+//! ## Embedding
+//! With `EMBEDDINGS_PROVIDER`, the text of each product, its name, is turned into an
+//! embedding by an [`EmbeddingProvider`]: an OpenAI-compatible service (`http`), or the
+//! words hashed locally (`hashing`), which needs no service but only matches spellings.
+//! The `embed_products` job embeds, `BATCH_SIZE` at a time, the products whose text
+//! changed since their last embedding or that another model embedded. It's enqueued at
+//! startup and when products are created or updated, never twice while one waits.
+//!
+//! ## Search
+//! A search blends two lists: the products whose embedding is the nearest to the one of
+//! the query, and those whose name contains words of the query. They're merged by
+//! reciprocal rank fusion, each product scoring `1 / (RRF_K + rank)` in each list it's
+//! in, so the ones high in both come first.
+//!
+//! With pgvector installed in the database (`CREATE EXTENSION vector`), the nearest
+//! embeddings are found by its operators; without it, the similarities are computed in
+//! plain SQL, over every product. Without a provider, or when it fails, the search
+//! matches the keywords only, and tells so (`semantic: false`).
+
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Bytes;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, HeaderValue};
+use hyper::{Request, Uri};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::json;
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::computed::{Resource, with_fields};
+use crate::config::EmbeddingsConfig;
+use crate::context::within_deadline;
+use crate::db::Db;
+use crate::error::AppError;
+use crate::events::{Action, ChangeEvent, Collection};
+use crate::http_client::http_client;
+use crate::jobs::{self, Job};
+use crate::repository::embeddings::{EmbeddingRepository, PgEmbeddingRepo};
+use crate::repository::products::{PgProductRepo, Product, ProductRepository};
+use crate::router::BoxError;
+
+/// Products embedded by each request to the provider
+const BATCH_SIZE: i64 = 100;
+
+/// Time the provider has to answer
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest response read from the provider
+const MAX_PROVIDER_BODY: usize = 16 * 1024 * 1024;
+
+/// Size of the embeddings of the `hashing` provider
+const HASHING_DIMENSIONS: usize = 256;
+
+/// Constant of the reciprocal rank fusion, damping the weight of the first ranks
+const RRF_K: f64 = 60.0;
+
+/// Products taken from each list for every result of a search
+const CANDIDATES_PER_RESULT: i64 = 3;
+
+/// Words of a query matched against the names
+const MAX_TERMS: usize = 10;
+
+// Set once at startup, unset without EMBEDDINGS_PROVIDER
+static EMBEDDINGS: OnceLock<Embeddings> = OnceLock::new();
+
+// Whether an `embed_products` job is waiting, which will embed the latest changes too
+static QUEUED: AtomicBool = AtomicBool::new(false);
+
+struct Embeddings {
+    provider: Provider,
+    /// Whether the database has the pgvector extension
+    pgvector: bool,
+}
+
+/// Turns texts into embeddings.
+pub trait EmbeddingProvider {
+    /// Name of the model, saved with the embeddings: the products embedded by another
+    /// model are embedded again.
+    fn model(&self) -> &str;
+
+    /// The embeddings of `texts` in the same order, unit vectors, within `limit`.
+    fn embed(
+        &self,
+        texts: &[String],
+        limit: Duration,
+    ) -> impl Future<Output = Result<Vec<Vec<f32>>, AppError>> + Send;
+}
+
+/// `EmbeddingProvider` calling an OpenAI-compatible `/embeddings` endpoint.
+#[derive(Debug)]
+pub struct HttpProvider {
+    url: Uri,
+    model: String,
+    api_key: Option<String>,
+}
+
+/// Response of an OpenAI-compatible `/embeddings` endpoint.
+#[derive(Deserialize, Debug)]
+struct EmbeddingsResponse {
+    data: Vec<EmbeddingItem>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EmbeddingItem {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl HttpProvider {
+    async fn request(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, AppError> {
+        let body = json!({"model": self.model, "input": texts}).to_string();
+        let mut req = Request::post(self.url.clone())
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+            .body(
+                Full::new(Bytes::from(body))
+                    .map_err(|never| match never {})
+                    .boxed_unsync(),
+            )
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        if let Some(key) = &self.api_key {
+            let value = HeaderValue::from_str(&format!("Bearer {}", key))
+                .map_err(|_| AppError::Internal("Invalid EMBEDDINGS_API_KEY".to_string()))?;
+            req.headers_mut().insert(AUTHORIZATION, value);
+        }
+
+        let res = http_client()
+            .request(req)
+            .await
+            .map_err(|e| AppError::BadGateway(format!("Embeddings provider unreachable: {}", e)))?;
+        if !res.status().is_success() {
+            return Err(AppError::BadGateway(format!(
+                "Embeddings provider answered {}",
+                res.status().as_u16()
+            )));
+        }
+        let body = Limited::new(res.into_body(), MAX_PROVIDER_BODY)
+            .collect()
+            .await
+            .map_err(|e: BoxError| {
+                AppError::BadGateway(format!("Invalid embeddings response: {}", e))
+            })?
+            .to_bytes();
+        let mut response = serde_json::from_slice::<EmbeddingsResponse>(&body)
+            .map_err(|e| AppError::BadGateway(format!("Invalid embeddings response: {}", e)))?;
+        if response.data.len() != texts.len() {
+            return Err(AppError::BadGateway(format!(
+                "Invalid embeddings response: {} embeddings for {} texts",
+                response.data.len(),
+                texts.len()
+            )));
+        }
+        response.data.sort_by_key(|item| item.index);
+        Ok(response
+            .data
+            .into_iter()
+            .map(|item| normalize(item.embedding))
+            .collect())
+    }
+}
+
+impl EmbeddingProvider for HttpProvider {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn embed(&self, texts: &[String], limit: Duration) -> Result<Vec<Vec<f32>>, AppError> {
+        match timeout(limit, self.request(texts)).await {
+            Ok(result) => result,
+            Err(_) => Err(AppError::BadGateway(format!(
+                "Embeddings provider didn't answer within {} ms",
+                limit.as_millis()
+            ))),
+        }
+    }
+}
+
+/// `EmbeddingProvider` hashing the words and their trigrams into a vector, locally.
+#[derive(Debug)]
+pub struct HashingProvider;
+
+impl EmbeddingProvider for HashingProvider {
+    fn model(&self) -> &str {
+        "hashing-256"
+    }
+
+    async fn embed(&self, texts: &[String], _limit: Duration) -> Result<Vec<Vec<f32>>, AppError> {
+        Ok(texts.iter().map(|text| hash_embedding(text)).collect())
+    }
+}
+
+/// The provider chosen by `EMBEDDINGS_PROVIDER`.
+#[derive(Debug)]
+pub enum Provider {
+    Http(HttpProvider),
+    Hashing(HashingProvider),
+}
+
+impl EmbeddingProvider for Provider {
+    fn model(&self) -> &str {
+        match self {
+            Provider::Http(provider) => provider.model(),
+            Provider::Hashing(provider) => provider.model(),
+        }
+    }
+
+    async fn embed(&self, texts: &[String], limit: Duration) -> Result<Vec<Vec<f32>>, AppError> {
+        match self {
+            Provider::Http(provider) => provider.embed(texts, limit).await,
+            Provider::Hashing(provider) => provider.embed(texts, limit).await,
+        }
+    }
+}
+
+/// `vector` scaled to a length of 1, unless it's null.
+fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+/// Adds `feature` to `vector`, at the index and with the sign of its hash.
+fn add_feature(vector: &mut [f32], feature: &str, weight: f32) {
+    // FNV-1a, stable across releases unlike the hasher of the standard library
+    let hash = feature
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    let index = (hash % vector.len() as u64) as usize;
+    let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+    vector[index] += sign * weight;
+}
+
+/// The embedding of the `hashing` provider: the lowercase words of `text` and their
+/// trigrams, so that close spellings (`expresso`, `espresso`) have close embeddings.
+fn hash_embedding(text: &str) -> Vec<f32> {
+    let mut vector = vec![0.0; HASHING_DIMENSIONS];
+    let words = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty());
+    for word in words {
+        let word = word.to_lowercase();
+        add_feature(&mut vector, &word, 1.0);
+        let padded = format!("#{}#", word).chars().collect::<Vec<_>>();
+        for trigram in padded.windows(3) {
+            add_feature(&mut vector, &trigram.iter().collect::<String>(), 0.5);
+        }
+    }
+    normalize(vector)
+}
+
+/// Sets the provider of the embeddings, and embeds the products not embedded yet.
+/// This function should be called once at application startup, after `init_jobs`.
+///
+/// # Arguments
+///
+/// * `config` - The provider, `None` when the products aren't embedded
+///
+/// # Returns
+///
+/// * `Result<(), String>` - Success, or the error of the database
+pub async fn init_embeddings(config: Option<&EmbeddingsConfig>) -> Result<(), String> {
+    let Some(config) = config else {
+        return Ok(());
+    };
+    let provider = match config {
+        EmbeddingsConfig::Http {
+            url,
+            model,
+            api_key,
+        } => Provider::Http(HttpProvider {
+            url: url.clone(),
+            model: model.clone(),
+            api_key: api_key.clone(),
+        }),
+        EmbeddingsConfig::Hashing => Provider::Hashing(HashingProvider),
+    };
+    let pgvector = PgEmbeddingRepo::default()
+        .has_pgvector()
+        .await
+        .map_err(|e| format!("Error looking for pgvector: {}", e))?;
+    info!(
+        "Products embedded by {}, searched {} pgvector",
+        provider.model(),
+        if pgvector { "with" } else { "without" }
+    );
+    if EMBEDDINGS.set(Embeddings { provider, pgvector }).is_err() {
+        warn!("Attempt to reset the embeddings ignored");
+        return Ok(());
+    }
+    schedule();
+    Ok(())
+}
+
+/// Embeds the product of an event published by this instance again, if its text may
+/// have changed. The deleted products lose their embedding with them.
+pub fn dispatch(event: &ChangeEvent) {
+    if event.collection == Collection::Products
+        && matches!(event.action, Action::Created | Action::Updated)
+        && EMBEDDINGS.get().is_some()
+    {
+        schedule();
+    }
+}
+
+/// Enqueues an `embed_products` job, unless one is waiting.
+fn schedule() {
+    if QUEUED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    tokio::spawn(async {
+        if let Err(e) = jobs::submit(Job::EmbedProducts).await {
+            QUEUED.store(false, Ordering::Release);
+            warn!("Products not embedded: {}", e);
+        }
+    });
+}
+
+/// Embeds the products whose text changed since their last embedding, or which another
+/// model embedded (job `id`).
+pub async fn embed_products(id: i64) -> Result<(), AppError> {
+    // The changes made from now on need another job
+    QUEUED.store(false, Ordering::Release);
+    let Some(embeddings) = EMBEDDINGS.get() else {
+        return Ok(());
+    };
+    let model = embeddings.provider.model();
+
+    let mut embedded = 0;
+    loop {
+        let stale = PgEmbeddingRepo::default().stale(model, BATCH_SIZE).await?;
+        if stale.is_empty() {
+            break;
+        }
+        let texts = stale
+            .iter()
+            .map(|(_, text)| text.clone())
+            .collect::<Vec<_>>();
+        let vectors = embeddings.provider.embed(&texts, PROVIDER_TIMEOUT).await?;
+        for ((product_id, text), vector) in stale.iter().zip(&vectors) {
+            PgEmbeddingRepo::default()
+                .save(*product_id, model, text, vector)
+                .await?;
+        }
+        embedded += stale.len();
+        if (stale.len() as i64) < BATCH_SIZE {
+            break;
+        }
+    }
+    if embedded > 0 {
+        info!("Job {} embedded {} products", id, embedded);
+    }
+    Ok(())
+}
+
+/// A product found by a search.
+#[derive(Serialize, Debug)]
+pub struct SearchHit {
+    /// The product, with its computed fields
+    #[serde(serialize_with = "serialize_product")]
+    product: Product,
+    /// Reciprocal rank fusion of its ranks, the higher the better
+    score: f64,
+    /// Cosine similarity of its embedding to the one of the query, `None` if it isn't
+    /// among the nearest
+    similarity: Option<f64>,
+    /// Whether its name contains words of the query
+    keyword: bool,
+}
+
+fn serialize_product<S: Serializer>(product: &Product, serializer: S) -> Result<S::Ok, S::Error> {
+    with_fields(Resource::Products, product).serialize(serializer)
+}
+
+/// Result of a search.
+#[derive(Serialize, Debug)]
+pub struct SearchResults {
+    /// The products, the best first
+    data: Vec<SearchHit>,
+    /// Whether the embeddings were searched, `false` when only the keywords were
+    semantic: bool,
+}
+
+/// The distinct words of `query`, in order.
+pub fn terms(query: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    for word in query.split_whitespace().map(str::to_lowercase) {
+        if !terms.contains(&word) && terms.len() < MAX_TERMS {
+            terms.push(word);
+        }
+    }
+    terms
+}
+
+/// Score of the rank `rank` (from 0) in a list.
+fn reciprocal_rank(rank: usize) -> f64 {
+    1.0 / (RRF_K + rank as f64 + 1.0)
+}
+
+/// The `limit` best products of the `nearest` embeddings and the `matching` names,
+/// ranked by reciprocal rank fusion.
+fn fuse(nearest: Vec<(Product, f64)>, matching: Vec<Product>, limit: usize) -> Vec<SearchHit> {
+    let mut hits = nearest
+        .into_iter()
+        .enumerate()
+        .map(|(rank, (product, similarity))| SearchHit {
+            product,
+            score: reciprocal_rank(rank),
+            similarity: Some(similarity),
+            keyword: false,
+        })
+        .collect::<Vec<_>>();
+    for (rank, product) in matching.into_iter().enumerate() {
+        match hits.iter_mut().find(|hit| hit.product.id == product.id) {
+            Some(hit) => {
+                hit.score += reciprocal_rank(rank);
+                hit.keyword = true;
+            }
+            None => hits.push(SearchHit {
+                product,
+                score: reciprocal_rank(rank),
+                similarity: None,
+                keyword: true,
+            }),
+        }
+    }
+    // Stable: on a tie, the nearest embedding first
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+    hits.truncate(limit);
+    hits
+}
+
+/// Searches the products by the meaning of `query` and by its words.
+///
+/// # Returns
+///
+/// * `Result<SearchResults, AppError>` - The `limit` best products, or the error of the
+///   database; a failing provider only leaves the embeddings out
+pub async fn search(db: Db, query: &str, limit: i64) -> Result<SearchResults, AppError> {
+    let candidates = limit * CANDIDATES_PER_RESULT;
+    let matching = PgProductRepo(db)
+        .search_by_name(&terms(query), candidates)
+        .await?
+        .into_iter()
+        .map(|(product, _)| product)
+        .collect();
+
+    let mut nearest = None;
+    if let Some(embeddings) = EMBEDDINGS.get() {
+        // Within the deadline of the request
+        let limit = within_deadline(db, PROVIDER_TIMEOUT);
+        match embeddings.provider.embed(&[query.to_string()], limit).await {
+            Ok(vectors) => {
+                let model = embeddings.provider.model();
+                let vector = vectors.into_iter().next().unwrap_or_default();
+                nearest = Some(
+                    PgEmbeddingRepo(db)
+                        .nearest(model, &vector, candidates, embeddings.pgvector)
+                        .await?,
+                );
+            }
+            Err(e) => warn!("Semantic search on the keywords only: {}", e),
+        }
+    }
+
+    Ok(SearchResults {
+        semantic: nearest.is_some(),
+        data: fuse(nearest.unwrap_or_default(), matching, limit as usize),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn product(id: i32) -> Product {
+        Product {
+            id,
+            public_id: Uuid::new_v4(),
+            name: format!("Product {}", id),
+            price: 1.0,
+            stock: 1,
+            version: 1,
+        }
+    }
+
+    fn similarity(a: &str, b: &str) -> f32 {
+        let (a, b) = (hash_embedding(a), hash_embedding(b));
+        a.iter().zip(&b).map(|(x, y)| x * y).sum()
+    }
+
+    #[test]
+    fn hashed_embeddings_are_close_for_close_spellings() {
+        let embedding = hash_embedding("Espresso machine");
+        let norm = embedding.iter().map(|x| x * x).sum::<f32>();
+        assert!((norm - 1.0).abs() < 1e-5);
+        assert!((similarity("Espresso machine", "espresso MACHINE") - 1.0).abs() < 1e-5);
+        assert!(
+            similarity("Espresso machine", "expresso") > similarity("Espresso machine", "hose")
+        );
+        assert_eq!(hash_embedding(""), vec![0.0; HASHING_DIMENSIONS]);
+    }
+
+    #[test]
+    fn products_in_both_lists_come_first() {
+        let hits = fuse(
+            vec![(product(1), 0.9), (product(2), 0.8), (product(3), 0.7)],
+            vec![product(3), product(4)],
+            3,
+        );
+        // 2 and 4 are both second of a list, the nearest embedding wins the tie
+        let ids = hits.iter().map(|hit| hit.product.id).collect::<Vec<_>>();
+        assert_eq!(ids, [3, 1, 2]);
+        assert!(hits[0].keyword && hits[0].similarity == Some(0.7));
+        assert!(!hits[2].keyword);
+    }
+}
@@ -0,0 +1,246 @@
+//! Application-wide error type.
+//!
+//! Every fallible operation of the handlers and the db layer returns an `AppError`,
+//! so `?` can be used everywhere. The router translates each variant into a JSON
+//! error response with the matching status code (see `router::error_response`).
+//!
+//! Every error response has the same body, an [`ErrorBody`]:
+//!
+//! ```json
+//! {"code": "USER_NOT_FOUND", "message": "User not found", "request_id": "..."}
+//! ```
+//!
+//! `code` is meant for programs and never changes for a given error, `message` is meant
+//! for humans and may. `details` is only present for some codes (`VALIDATION_FAILED`,
+//! `INSUFFICIENT_ROLE`).
+//! The codes are listed in [`ErrorCode`].
+
+use std::fmt;
+use std::time::Duration;
+
+use bb8_postgres::bb8::RunError;
+use bb8_postgres::tokio_postgres::Error as PgError;
+use hyper::Method;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::logging::RequestId;
+use crate::roles::Role;
+use crate::validation::ValidationErrors;
+
+#[derive(Debug)]
+pub enum AppError {
+    /// A query failed in PostgreSQL
+    Db(PgError),
+    /// No connection could be obtained from the pool
+    Pool(String),
+    /// The database is known to be down, retry after the duration (see
+    /// `db::breaker`)
+    Unavailable(String, Duration),
+    /// The request is malformed (invalid path parameter, invalid JSON, ...)
+    Validation(String),
+    /// The payload is well-formed but some fields have invalid values
+    Unprocessable(ValidationErrors),
+    /// The request lacks valid credentials
+    Unauthorized(String),
+    /// The role of the caller (second) is lower than the one the route requires (first)
+    Forbidden(Role, Role),
+    /// The requested resource does not exist
+    NotFound(ErrorCode, String),
+    /// The path has routes, for other methods than the one requested (listed)
+    MethodNotAllowed(Vec<Method>),
+    /// The request conflicts with the current state (e.g. duplicated unique value)
+    Conflict(ErrorCode, String),
+    /// The entity changed since the version named by `If-Match`
+    PreconditionFailed(String),
+    /// The request body exceeds the configured size limit
+    PayloadTooLarge(String),
+    /// A write lacks the `If-Match` header telling which version it applies to
+    PreconditionRequired(String),
+    /// The client exceeded its rate limit
+    TooManyRequests(String),
+    /// The handler didn't produce a response within the timeout of its route, or one of
+    /// its queries ran past it (see `db::deadline`)
+    Timeout(String),
+    /// A service the request is forwarded to couldn't be reached
+    BadGateway(String),
+    /// Any other unexpected failure
+    Internal(String),
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Db(e) => write!(f, "Database error: {}", e),
+            AppError::Pool(msg) => write!(f, "Connection pool error: {}", msg),
+            AppError::Unavailable(msg, retry_after) => {
+                write!(f, "Unavailable: {} (retry after {:?})", msg, retry_after)
+            }
+            AppError::Validation(msg) => write!(f, "Validation error: {}", msg),
+            AppError::Unprocessable(errors) => write!(f, "Invalid fields: {}", errors),
+            AppError::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+            AppError::Forbidden(required, role) => {
+                write!(f, "Forbidden: requires the {} role, not {}", required, role)
+            }
+            AppError::NotFound(_, msg) => write!(f, "Not found: {}", msg),
+            AppError::MethodNotAllowed(allowed) => {
+                write!(f, "Method not allowed (allowed: {:?})", allowed)
+            }
+            AppError::Conflict(_, msg) => write!(f, "Conflict: {}", msg),
+            AppError::PreconditionFailed(msg) => write!(f, "Precondition failed: {}", msg),
+            AppError::PayloadTooLarge(msg) => write!(f, "Payload too large: {}", msg),
+            AppError::PreconditionRequired(msg) => {
+                write!(f, "Precondition required: {}", msg)
+            }
+            AppError::TooManyRequests(msg) => write!(f, "Too many requests: {}", msg),
+            AppError::Timeout(msg) => write!(f, "Timeout: {}", msg),
+            AppError::BadGateway(msg) => write!(f, "Bad gateway: {}", msg),
+            AppError::Internal(msg) => write!(f, "Internal error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+/// Machine-readable error codes, sent as `SCREAMING_SNAKE_CASE` (`USER_NOT_FOUND`).
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// 400: malformed request (path parameter, query string, JSON syntax, ...)
+    InvalidRequest,
+    /// 422: some fields of the payload are invalid, listed by field in `details`
+    ValidationFailed,
+    /// 401: missing, invalid or expired access token, or wrong credentials
+    Unauthorized,
+    /// 403: the client region requires consent (`X-Consent: granted`)
+    ConsentRequired,
+    /// 403: the role of the caller is lower than the one the route requires, both are
+    /// in `details` (see `roles`)
+    InsufficientRole,
+    /// 404: no user has the requested ID
+    UserNotFound,
+    /// 404: no product has the requested ID
+    ProductNotFound,
+    /// 404: the user has no order with the requested ID
+    OrderNotFound,
+    /// 404: no coupon has the requested code or ID
+    CouponNotFound,
+    /// 404: the cart or the wishlist doesn't have the product
+    ItemNotFound,
+    /// 404: the user has no avatar
+    AvatarNotFound,
+    /// 404: the caller has no saved view with the name of `?view=`
+    ViewNotFound,
+    /// 404: no operation has the requested ID
+    OperationNotFound,
+    /// 404: the caller has no webhook with the requested ID
+    WebhookNotFound,
+    /// 404: the caller has no passkey with the requested ID
+    PasskeyNotFound,
+    /// 404: no archive has the requested ID
+    ArchiveNotFound,
+    /// 404: the table has no change feed
+    FeedNotFound,
+    /// 404: the consumer has no checkpoint in the change feed
+    CheckpointNotFound,
+    /// 404: the admin console has no query with the requested name
+    QueryNotFound,
+    /// 404: no tool has the requested name
+    ToolNotFound,
+    /// 404: no route matches the method and path
+    RouteNotFound,
+    /// 405: the path has no route for the method, the ones it has are listed in `Allow`
+    MethodNotAllowed,
+    /// 406: the resource isn't available in any of the accepted formats
+    NotAcceptable,
+    /// 409: the email address belongs to another account
+    EmailTaken,
+    /// 409: the passkey is already registered
+    PasskeyTaken,
+    /// 409: the account requires a passkey as second factor, and would have none
+    PasskeyRequired,
+    /// 409: the product doesn't have enough stock for the order
+    InsufficientStock,
+    /// 409: the user to restore isn't deleted
+    UserNotDeleted,
+    /// 409: the credit of the user is lower than the amount taken back
+    InsufficientCredit,
+    /// 409: the order was already refunded
+    OrderRefunded,
+    /// 409: the code belongs to another coupon
+    CouponTaken,
+    /// 409: the coupon expired, was withdrawn or used up, or the order is too small
+    CouponUnavailable,
+    /// 409: the cart has no item to order
+    CartEmpty,
+    /// 409: the `Idempotency-Key` was sent with another request, still queued (see
+    /// `offline_queue`)
+    IdempotencyKeyReused,
+    /// 409: profiling is unavailable (built without the `jemalloc` or `pprof` feature,
+    /// or heap profiling turned off with `prof:false`)
+    ProfilingDisabled,
+    /// 409: another CPU profile is being taken
+    #[cfg_attr(not(feature = "pprof"), allow(dead_code))]
+    ProfileInProgress,
+    /// 412: the entity changed since the version of `If-Match`, read it again
+    VersionMismatch,
+    /// 413: the request body exceeds `MAX_BODY_SIZE` (`MAX_UPLOAD_SIZE` for uploads)
+    PayloadTooLarge,
+    /// 428: the write must be sent with `If-Match` and the `ETag` of the entity
+    PreconditionRequired,
+    /// 429: the client exceeded its rate limit, see `Retry-After`
+    RateLimited,
+    /// 451: the resource is blocked in the client region
+    RegionBlocked,
+    /// 500: unexpected failure, the cause is only logged
+    InternalError,
+    /// 502: the legacy service behind `LEGACY_PATH_PREFIX` can't be reached
+    UpstreamUnavailable,
+    /// 503: no database connection is available, the request can be retried (after
+    /// `Retry-After` when sent)
+    DatabaseUnavailable,
+    /// 504: the request took longer than the timeout of its route (`REQUEST_TIMEOUT` or
+    /// `ROUTE_TIMEOUTS`)
+    Timeout,
+}
+
+/// Body of every error response.
+#[derive(Serialize, Debug)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+    /// ID of the request, to be quoted when reporting the error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl ErrorBody {
+    /// Creates the body of an error of the request being handled.
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ErrorBody {
+            code,
+            message: message.into(),
+            details: None,
+            request_id: RequestId::current().map(|id| id.0),
+        }
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+impl From<PgError> for AppError {
+    fn from(e: PgError) -> Self {
+        AppError::Db(e)
+    }
+}
+
+impl From<RunError<PgError>> for AppError {
+    fn from(e: RunError<PgError>) -> Self {
+        AppError::Pool(e.to_string())
+    }
+}
@@ -0,0 +1,287 @@
+//! Change notifications.
+//!
+//! Write handlers publish a `ChangeEvent` once a user or product has been created,
+//! updated or deleted, and every client connected to `GET /ws` or `GET /events`
+//! receives it as a versioned JSON envelope:
+//!
+//! ```json
+//! {"type": "users.updated", "version": 2, "data": {"id": "0192f4c8-5b1e-7a3c-9d2e-..."}}
+//! ```
+//!
+//! The payload of every event type is described in the `registry` module; consumers
+//! can rely on the fields of a version staying there in later versions.
+//!
+//! Events are shared with the other instances using the same database through
+//! `LISTEN`/`NOTIFY` (see `db::notify`): an event published here is sent on the
+//! `change_events` channel, and the events of the other instances received on it
+//! invalidate the cache and reach the clients connected here as if they were published
+//! here. Delivery between instances is best effort, events sent while an instance is
+//! reconnecting are lost to it.
+//!
+//! A client too slow to keep up loses the oldest events and is told so, it should then
+//! reload the collections it displays.
+
+mod registry;
+
+pub use registry::schemas;
+
+use std::sync::{LazyLock, OnceLock};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::sync::{broadcast, mpsc};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::cache;
+use crate::db;
+use crate::embeddings;
+use crate::search_engine;
+use crate::webhooks;
+
+/// Events buffered per subscriber before the slowest ones start losing events
+const CHANNEL_CAPACITY: usize = 256;
+
+/// `NOTIFY` channel of the events between instances
+const NOTIFY_CHANNEL: &str = "change_events";
+
+static CHANNEL: LazyLock<broadcast::Sender<ChangeEvent>> =
+    LazyLock::new(|| broadcast::channel(CHANNEL_CAPACITY).0);
+
+// Origin of the events published by this instance, so it ignores them when PostgreSQL
+// delivers them back
+static INSTANCE_ID: LazyLock<String> = LazyLock::new(|| Uuid::new_v4().to_string());
+
+// Events waiting to be sent to the other instances, unset before `init_bridge`
+static OUTBOX: OnceLock<mpsc::UnboundedSender<ChangeEvent>> = OnceLock::new();
+
+/// Collections whose changes are published.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Collection {
+    Users,
+    Products,
+}
+
+impl Collection {
+    fn as_str(self) -> &'static str {
+        match self {
+            Collection::Users => "users",
+            Collection::Products => "products",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "users" => Some(Collection::Users),
+            "products" => Some(Collection::Products),
+            _ => None,
+        }
+    }
+}
+
+/// Kind of change made to an entity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::Created => "created",
+            Action::Updated => "updated",
+            Action::Deleted => "deleted",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "created" => Some(Action::Created),
+            "updated" => Some(Action::Updated),
+            "deleted" => Some(Action::Deleted),
+            _ => None,
+        }
+    }
+}
+
+/// An event as sent to the other instances.
+#[derive(Serialize, Deserialize, Debug)]
+struct Notification {
+    /// `INSTANCE_ID` of the sender
+    origin: String,
+    /// `users.created`
+    r#type: String,
+    id: i32,
+    public_id: Uuid,
+}
+
+/// A user or product that changed.
+#[derive(Debug, Clone)]
+pub struct ChangeEvent {
+    pub collection: Collection,
+    pub action: Action,
+    /// Key of the row, which the cache is keyed by
+    pub id: i32,
+    /// Public ID of the row (see `repository::ids`), the only one consumers see
+    pub public_id: Uuid,
+}
+
+impl ChangeEvent {
+    /// Name of the event type (`users.created`), as listed in the registry.
+    pub fn event_type(&self) -> String {
+        format!("{}.{}", self.collection.as_str(), self.action.as_str())
+    }
+
+    /// The envelope sent to consumers, using the latest version of the event type.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the event type is missing from the registry
+    pub fn to_json(&self) -> Value {
+        envelope(&self.event_type(), json!({"id": self.public_id}))
+    }
+}
+
+/// The envelope of an event of type `event_type`, using its latest version.
+///
+/// # Panics
+///
+/// Will panic if the event type is missing from the registry
+pub fn envelope(event_type: &str, data: Value) -> Value {
+    let schema = registry::latest(event_type)
+        .unwrap_or_else(|| panic!("Event type '{}' is not registered", event_type));
+
+    json!({
+        "type": event_type,
+        "version": schema.version,
+        "data": data,
+    })
+}
+
+/// Sends an event to every connected client, of this instance and of the others.
+/// Nothing happens if no client is connected.
+///
+/// The entity is also removed from the response cache, before the event is sent so
+/// a client reacting to it reads the new version.
+pub fn publish(collection: Collection, action: Action, id: i32, public_id: Uuid) {
+    let event = ChangeEvent {
+        collection,
+        action,
+        id,
+        public_id,
+    };
+    if let Some(outbox) = OUTBOX.get() {
+        let _ = outbox.send(event.clone());
+    }
+    webhooks::dispatch(&event);
+    embeddings::dispatch(&event);
+    search_engine::dispatch(&event);
+    deliver(event);
+}
+
+/// Applies an event to this instance: cache first, then the connected clients.
+fn deliver(event: ChangeEvent) {
+    cache::invalidate(event.collection, event.id);
+
+    // Only fails when there are no subscribers
+    let _ = CHANNEL.send(event);
+}
+
+/// Shares the events with the other instances through the database.
+/// This function should be called once at application startup, after `init_pool`.
+///
+/// Spawns the task sending the events published here, in order, and the one receiving
+/// those of the other instances.
+pub fn init_bridge() {
+    let (outbox, mut pending) = mpsc::unbounded_channel::<ChangeEvent>();
+    if OUTBOX.set(outbox).is_err() {
+        warn!("Attempt to restart the event bridge ignored");
+        return;
+    }
+
+    tokio::spawn(async move {
+        while let Some(event) = pending.recv().await {
+            let payload = encode(&event, &INSTANCE_ID);
+            if let Err(e) = db::notify(NOTIFY_CHANNEL, &payload).await {
+                warn!(
+                    "{} not sent to the other instances: {}",
+                    event.event_type(),
+                    e
+                );
+            }
+        }
+    });
+    tokio::spawn(db::listen(NOTIFY_CHANNEL, |payload| {
+        if let Some(event) = decode(payload, &INSTANCE_ID) {
+            deliver(event);
+        }
+    }));
+}
+
+/// The `NOTIFY` payload of an event.
+fn encode(event: &ChangeEvent, origin: &str) -> String {
+    let notification = Notification {
+        origin: origin.to_string(),
+        r#type: event.event_type(),
+        id: event.id,
+        public_id: event.public_id,
+    };
+    serde_json::to_string(&notification).expect("Notifications are serializable")
+}
+
+/// The event of a `NOTIFY` payload, `None` if it was sent by `origin` or is invalid.
+fn decode(payload: &str, origin: &str) -> Option<ChangeEvent> {
+    let notification = match serde_json::from_str::<Notification>(payload) {
+        Ok(notification) => notification,
+        Err(e) => {
+            warn!("Invalid change notification '{}': {}", payload, e);
+            return None;
+        }
+    };
+    if notification.origin == origin {
+        return None;
+    }
+    let (collection, action) = notification.r#type.split_once('.')?;
+    Some(ChangeEvent {
+        collection: Collection::parse(collection)?,
+        action: Action::parse(action)?,
+        id: notification.id,
+        public_id: notification.public_id,
+    })
+}
+
+/// Receives the events published from now on.
+pub fn subscribe() -> broadcast::Receiver<ChangeEvent> {
+    CHANNEL.subscribe()
+}
+
+/// Number of connected clients (WebSockets and Server-Sent Events streams).
+pub fn subscriber_count() -> usize {
+    CHANNEL.receiver_count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifications_of_other_instances_are_delivered() {
+        let event = ChangeEvent {
+            collection: Collection::Products,
+            action: Action::Deleted,
+            id: 7,
+            public_id: Uuid::nil(),
+        };
+        let payload = encode(&event, "instance-a");
+
+        let received = decode(&payload, "instance-b").unwrap();
+        assert_eq!(received.event_type(), "products.deleted");
+        assert_eq!(received.id, 7);
+        assert_eq!(received.public_id, Uuid::nil());
+        // Already delivered when it was published
+        assert!(decode(&payload, "instance-a").is_none());
+        assert!(decode(r#"{"origin": "x", "type": "orders.created", "id": 1}"#, "a").is_none());
+    }
+}
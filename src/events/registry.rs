@@ -0,0 +1,381 @@
+//! Schemas of the event payloads (the `data` of the envelope).
+//!
+//! Every version of every event type is listed in `SCHEMAS`, oldest first. A published
+//! version is never edited nor removed: changing a payload means adding an entry with
+//! the next version. A new version must stay compatible with the previous one, which
+//! the tests of this module check:
+//!
+//! - every field of the previous version is still there, with the same type
+//! - a required field stays required
+//! - added fields are optional, so consumers written for the previous version still
+//!   understand the payload
+//!
+//! A version that can't be compatible is marked `breaking`, and its consumers must be
+//! updated before it is published: version 2 of the users and products events, whose
+//! `id` became their UUID (see `repository::ids`), is the only one.
+//!
+//! Consumers can read the registry from `GET /events/schemas`.
+
+use serde::Serialize;
+
+/// JSON type of a payload field.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+// Not every type is used by the current payloads
+#[allow(dead_code)]
+pub enum Kind {
+    Integer,
+    Number,
+    String,
+    Boolean,
+}
+
+/// A field of an event payload.
+#[derive(Serialize, Debug)]
+pub struct Field {
+    pub name: &'static str,
+    pub kind: Kind,
+    /// Optional fields may be missing or `null`
+    pub required: bool,
+}
+
+/// One version of the payload of an event type.
+#[derive(Serialize, Debug)]
+pub struct Schema {
+    pub event_type: &'static str,
+    pub version: u32,
+    pub fields: &'static [Field],
+    /// Consumers of the previous version can't read this one
+    pub breaking: bool,
+}
+
+/// Key of the user or product, up to version 1
+const KEY: Field = Field {
+    name: "id",
+    kind: Kind::Integer,
+    required: true,
+};
+
+/// UUID of the user or product, the ID of the API
+const ID: Field = Field {
+    name: "id",
+    kind: Kind::String,
+    required: true,
+};
+
+/// Alerts of a service level objective (see `metrics::slo`), sent to the webhooks only
+const SLO_ALERT: &[Field] = &[
+    Field {
+        name: "slo",
+        kind: Kind::String,
+        required: true,
+    },
+    Field {
+        name: "route",
+        kind: Kind::String,
+        required: true,
+    },
+    Field {
+        name: "objective",
+        kind: Kind::Number,
+        required: true,
+    },
+    // `ok`, `slow` or `fast`
+    Field {
+        name: "status",
+        kind: Kind::String,
+        required: true,
+    },
+    Field {
+        name: "burn_rate_5m",
+        kind: Kind::Number,
+        required: true,
+    },
+    Field {
+        name: "burn_rate_30m",
+        kind: Kind::Number,
+        required: true,
+    },
+    Field {
+        name: "burn_rate_1h",
+        kind: Kind::Number,
+        required: true,
+    },
+    Field {
+        name: "burn_rate_6h",
+        kind: Kind::Number,
+        required: true,
+    },
+];
+
+static SCHEMAS: &[Schema] = &[
+    Schema {
+        event_type: "users.created",
+        version: 1,
+        fields: &[KEY],
+        breaking: false,
+    },
+    Schema {
+        event_type: "users.created",
+        version: 2,
+        fields: &[ID],
+        breaking: true,
+    },
+    Schema {
+        event_type: "users.updated",
+        version: 1,
+        fields: &[KEY],
+        breaking: false,
+    },
+    Schema {
+        event_type: "users.updated",
+        version: 2,
+        fields: &[ID],
+        breaking: true,
+    },
+    Schema {
+        event_type: "users.deleted",
+        version: 1,
+        fields: &[KEY],
+        breaking: false,
+    },
+    Schema {
+        event_type: "users.deleted",
+        version: 2,
+        fields: &[ID],
+        breaking: true,
+    },
+    Schema {
+        event_type: "products.created",
+        version: 1,
+        fields: &[KEY],
+        breaking: false,
+    },
+    Schema {
+        event_type: "products.created",
+        version: 2,
+        fields: &[ID],
+        breaking: true,
+    },
+    Schema {
+        event_type: "products.updated",
+        version: 1,
+        fields: &[KEY],
+        breaking: false,
+    },
+    Schema {
+        event_type: "products.updated",
+        version: 2,
+        fields: &[ID],
+        breaking: true,
+    },
+    Schema {
+        event_type: "products.deleted",
+        version: 1,
+        fields: &[KEY],
+        breaking: false,
+    },
+    Schema {
+        event_type: "products.deleted",
+        version: 2,
+        fields: &[ID],
+        breaking: true,
+    },
+    Schema {
+        event_type: "slo.burning",
+        version: 1,
+        fields: SLO_ALERT,
+        breaking: false,
+    },
+    Schema {
+        event_type: "slo.recovered",
+        version: 1,
+        fields: SLO_ALERT,
+        breaking: false,
+    },
+];
+
+/// Every registered schema.
+pub fn schemas() -> &'static [Schema] {
+    SCHEMAS
+}
+
+/// The latest version of an event type, `None` if it isn't registered.
+pub fn latest(event_type: &str) -> Option<&'static Schema> {
+    SCHEMAS
+        .iter()
+        .filter(|schema| schema.event_type == event_type)
+        .max_by_key(|schema| schema.version)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::events::{Action, ChangeEvent, Collection};
+
+    fn matches(kind: Kind, value: &Value) -> bool {
+        match kind {
+            Kind::Integer => value.is_i64() || value.is_u64(),
+            Kind::Number => value.is_number(),
+            Kind::String => value.is_string(),
+            Kind::Boolean => value.is_boolean(),
+        }
+    }
+
+    /// Checks a payload against a schema; unknown fields are rejected so every field
+    /// sent to consumers is documented here.
+    fn validate(schema: &Schema, data: &Value) -> Result<(), String> {
+        let object = data.as_object().ok_or("the payload is not an object")?;
+
+        for field in schema.fields {
+            match object.get(field.name) {
+                None | Some(Value::Null) if field.required => {
+                    return Err(format!("missing required field '{}'", field.name));
+                }
+                None | Some(Value::Null) => {}
+                Some(value) if !matches(field.kind, value) => {
+                    return Err(format!("'{}' is not of type {:?}", field.name, field.kind));
+                }
+                Some(_) => {}
+            }
+        }
+        match object
+            .keys()
+            .find(|key| !schema.fields.iter().any(|field| field.name == *key))
+        {
+            Some(key) => Err(format!("unregistered field '{}'", key)),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that consumers of `old` can read payloads of `new`.
+    fn check_compatible(old: &Schema, new: &Schema) -> Result<(), String> {
+        for field in old.fields {
+            let Some(new_field) = new.fields.iter().find(|f| f.name == field.name) else {
+                return Err(format!("field '{}' was removed", field.name));
+            };
+            if new_field.kind != field.kind {
+                return Err(format!("field '{}' changed its type", field.name));
+            }
+            if field.required && !new_field.required {
+                return Err(format!("field '{}' is no longer required", field.name));
+            }
+        }
+        match new
+            .fields
+            .iter()
+            .find(|field| field.required && !old.fields.iter().any(|f| f.name == field.name))
+        {
+            Some(field) => Err(format!("new field '{}' must be optional", field.name)),
+            None => Ok(()),
+        }
+    }
+
+    fn all_events() -> Vec<ChangeEvent> {
+        let mut events = Vec::new();
+        for collection in [Collection::Users, Collection::Products] {
+            for action in [Action::Created, Action::Updated, Action::Deleted] {
+                events.push(ChangeEvent {
+                    collection,
+                    action,
+                    id: 42,
+                    public_id: uuid::Uuid::nil(),
+                });
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn every_published_event_matches_its_latest_schema() {
+        for event in all_events() {
+            let json = event.to_json();
+            let schema = latest(&event.event_type()).unwrap();
+
+            assert_eq!(json["type"], event.event_type());
+            assert_eq!(json["version"], schema.version);
+            if let Err(e) = validate(schema, &json["data"]) {
+                panic!("{} v{}: {}", schema.event_type, schema.version, e);
+            }
+        }
+    }
+
+    #[test]
+    fn versions_are_consecutive_and_compatible() {
+        for schema in SCHEMAS {
+            let versions: Vec<&Schema> = SCHEMAS
+                .iter()
+                .filter(|s| s.event_type == schema.event_type)
+                .collect();
+
+            for (index, version) in versions.iter().enumerate() {
+                assert_eq!(
+                    version.version as usize,
+                    index + 1,
+                    "{}: versions must start at 1 and be listed in order",
+                    schema.event_type
+                );
+            }
+            for pair in versions.windows(2).filter(|pair| !pair[1].breaking) {
+                if let Err(e) = check_compatible(pair[0], pair[1]) {
+                    panic!("{} v{}: {}", schema.event_type, pair[1].version, e);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn incompatible_changes_are_detected() {
+        const NAME: Field = Field {
+            name: "name",
+            kind: Kind::String,
+            required: true,
+        };
+        const OPTIONAL_NAME: Field = Field {
+            required: false,
+            ..NAME
+        };
+        let schema = |fields| Schema {
+            event_type: "test",
+            version: 1,
+            fields,
+            breaking: false,
+        };
+
+        let old = schema(&[ID, NAME]);
+        assert!(check_compatible(&old, &schema(&[ID, NAME])).is_ok());
+        assert!(check_compatible(&schema(&[ID]), &schema(&[ID, OPTIONAL_NAME])).is_ok());
+
+        // Removed field, optional field, new required field, changed type
+        assert!(check_compatible(&old, &schema(&[ID])).is_err());
+        assert!(check_compatible(&old, &schema(&[ID, OPTIONAL_NAME])).is_err());
+        assert!(check_compatible(&schema(&[ID]), &schema(&[ID, NAME])).is_err());
+        assert!(check_compatible(&schema(&[KEY]), &schema(&[ID])).is_err());
+        const NAME_AS_NUMBER: Field = Field {
+            kind: Kind::Number,
+            ..NAME
+        };
+        assert!(
+            check_compatible(
+                &old,
+                &Schema {
+                    fields: &[ID, NAME_AS_NUMBER],
+                    ..old
+                }
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn payloads_are_validated() {
+        let schema = latest("users.created").unwrap();
+        let id = uuid::Uuid::nil().to_string();
+        assert!(validate(schema, &serde_json::json!({"id": id})).is_ok());
+        assert!(validate(schema, &serde_json::json!({})).is_err());
+        assert!(validate(schema, &serde_json::json!({"id": 1})).is_err());
+        assert!(validate(schema, &serde_json::json!({"id": id, "extra": true})).is_err());
+    }
+}
@@ -0,0 +1,355 @@
+//! A/B experiments (`EXPERIMENTS_PATH`).
+//!
+//! ## Configuration
+//! Experiments are read from the JSON file pointed to by `EXPERIMENTS_PATH` at startup:
+//!
+//! ```json
+//! [
+//!   {"name": "checkout_button", "variants": [
+//!     {"name": "control", "weight": 50}, {"name": "green", "weight": 50}
+//!   ]},
+//!   {"name": "search_ranking", "salt": "2025-06", "variants": [
+//!     {"name": "control", "weight": 90}, {"name": "semantic", "weight": 10}
+//!   ]}
+//! ]
+//! ```
+//!
+//! ## Assignment
+//! Every request gets a variant of each experiment, chosen by hashing (SHA-256) the
+//! salt of the experiment (its name by default) with the subject of the request: its
+//! user (`user:<id>`) with a valid access token, its client IP (`ip:<address>`)
+//! otherwise. The hash picks the variant in proportion to the weights, so a subject
+//! keeps its variant across requests, instances and restarts, while the salts keep the
+//! experiments independent of each other. Changing the weights or the salt reassigns
+//! the subjects.
+//!
+//! Handlers read the variants with [`Assignments::of`], and clients get them in the
+//! `X-Experiments` header of the response:
+//!
+//! ```text
+//! X-Experiments: checkout_button=green, search_ranking=control
+//! ```
+//!
+//! ## Exposures
+//! The first exposure of every subject to an experiment is stored in the
+//! `experiment_exposures` table for the analysis, written in batches in the background
+//! (see [`flush_exposures`]). `experiment_exposures_total{experiment, variant}` counts
+//! the responses sent with each variant.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use hyper::Request;
+use hyper::header::{HeaderMap, HeaderValue};
+use ring::digest::{SHA256, digest};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::auth::authenticate;
+use crate::forwarded::ClientInfo;
+use crate::metrics;
+use crate::repository::experiments::{Exposure, ExposureRepository, PgExposureRepo};
+
+/// Header listing the variants of the request
+pub const EXPERIMENTS_HEADER: &str = "x-experiments";
+
+/// Longest time an exposure waits to be written
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+/// Exposures written per statement
+const BATCH_SIZE: usize = 500;
+/// Exposures waiting for the writer, past which new ones are dropped
+const QUEUE_CAPACITY: usize = 10_000;
+/// Exposures remembered as written, past which they are forgotten (and written again,
+/// as no-ops, when they come back)
+const MAX_REMEMBERED: usize = 100_000;
+
+// Set once at startup, empty without EXPERIMENTS_PATH
+static EXPERIMENTS: OnceLock<Vec<Experiment>> = OnceLock::new();
+
+// Queue of the exposure writer, closed by `flush_exposures`
+static EXPOSURES: Mutex<Option<mpsc::Sender<Exposure>>> = Mutex::new(None);
+static WRITER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// An experiment of the configuration file.
+#[derive(Debug, Deserialize)]
+pub struct Experiment {
+    pub name: String,
+    /// Hashed with the subject, the name when not set
+    pub salt: Option<String>,
+    pub variants: Vec<Variant>,
+}
+
+/// A variant of an experiment, assigned to `weight` subjects out of the total weight.
+#[derive(Debug, Deserialize)]
+pub struct Variant {
+    pub name: String,
+    pub weight: u32,
+}
+
+impl Experiment {
+    fn validate(&self) -> Result<(), String> {
+        for name in std::iter::once(&self.name).chain(self.variants.iter().map(|v| &v.name)) {
+            let valid = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+            if !valid {
+                return Err(format!("'{}': names are letters, digits, _, - and .", name));
+            }
+        }
+        if self
+            .variants
+            .iter()
+            .map(|v| u64::from(v.weight))
+            .sum::<u64>()
+            == 0
+        {
+            return Err(format!("'{}' has no variant with a weight", self.name));
+        }
+        Ok(())
+    }
+
+    /// The variant of `subject`.
+    fn assign(&self, subject: &str) -> &str {
+        let salt = self.salt.as_deref().unwrap_or(&self.name);
+        let hash = digest(&SHA256, format!("{}:{}", salt, subject).as_bytes());
+        let bytes: [u8; 8] = hash.as_ref()[..8].try_into().expect("SHA-256 has 32 bytes");
+        let total: u64 = self.variants.iter().map(|v| u64::from(v.weight)).sum();
+
+        let mut point = u64::from_be_bytes(bytes) % total;
+        for variant in &self.variants {
+            if point < u64::from(variant.weight) {
+                return &variant.name;
+            }
+            point -= u64::from(variant.weight);
+        }
+        unreachable!("The point is below the total weight")
+    }
+}
+
+/// The variants of a request, in the request extensions.
+#[derive(Debug, Clone)]
+pub struct Assignments {
+    /// `user:<id>` or `ip:<address>`
+    pub subject: String,
+    /// Experiment and variant, in the order of the configuration file
+    pub variants: Vec<(&'static str, &'static str)>,
+}
+
+impl Assignments {
+    /// The variants of a request, `None` without experiments or subject.
+    pub fn of<B>(req: &Request<B>) -> Option<&Assignments> {
+        req.extensions().get::<Assignments>()
+    }
+
+    /// The variant of an experiment, `None` if it isn't configured.
+    pub fn variant(&self, experiment: &str) -> Option<&'static str> {
+        self.variants
+            .iter()
+            .find(|(name, _)| *name == experiment)
+            .map(|(_, variant)| *variant)
+    }
+}
+
+/// Loads the experiments from `EXPERIMENTS_PATH` and starts the exposure writer.
+/// This function should be called once at application startup, after `init_pool`.
+///
+/// # Arguments
+///
+/// * `path` - Path of the JSON experiments file, `None` when no experiment runs
+///
+/// # Returns
+///
+/// * `Result<usize, String>` - Number of experiments, or an error message
+pub fn init_experiments(path: Option<&Path>) -> Result<usize, String> {
+    let experiments: Vec<Experiment> = match path {
+        Some(path) => {
+            let content = fs::read_to_string(path)
+                .map_err(|e| format!("Unable to read experiments '{}': {}", path.display(), e))?;
+            serde_json::from_str(&content)
+                .map_err(|e| format!("Invalid experiments '{}': {}", path.display(), e))?
+        }
+        None => Vec::new(),
+    };
+    let mut names = HashSet::new();
+    for experiment in &experiments {
+        experiment.validate()?;
+        if !names.insert(&experiment.name) {
+            return Err(format!("Experiment '{}' is defined twice", experiment.name));
+        }
+    }
+
+    let count = experiments.len();
+    if EXPERIMENTS.set(experiments).is_err() {
+        warn!("Attempt to reload the experiments ignored");
+        return Ok(count);
+    }
+    if count > 0 {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        *EXPOSURES.lock().unwrap() = Some(sender);
+        *WRITER.lock().unwrap() = Some(tokio::spawn(write_exposures(receiver)));
+    }
+    Ok(count)
+}
+
+/// Middleware attaching the [`Assignments`] of a request to its extensions.
+///
+/// Must run after `forwarded::resolve_client`. Does nothing without experiments, or
+/// for requests without a subject (not received by the accept loop).
+pub fn assign_experiments<B>(req: &mut Request<B>) {
+    let Some(experiments) = EXPERIMENTS.get().filter(|e| !e.is_empty()) else {
+        return;
+    };
+    let subject = match authenticate(req) {
+        Ok(user) => format!("user:{}", user.id),
+        Err(_) => match ClientInfo::of(req) {
+            Some(client) => format!("ip:{}", client.ip),
+            None => return,
+        },
+    };
+
+    let variants = experiments
+        .iter()
+        .map(|experiment| (experiment.name.as_str(), experiment.assign(&subject)))
+        .collect();
+    req.extensions_mut()
+        .insert(Assignments { subject, variants });
+}
+
+/// Sends the variants of a request in `X-Experiments`, recording their exposure.
+pub fn expose(assignments: &Assignments, headers: &mut HeaderMap) {
+    let list = assignments
+        .variants
+        .iter()
+        .map(|(experiment, variant)| format!("{}={}", experiment, variant))
+        .collect::<Vec<_>>()
+        .join(", ");
+    // Names are checked at startup, the header is always valid
+    if let Ok(value) = HeaderValue::from_str(&list) {
+        headers.insert(EXPERIMENTS_HEADER, value);
+    }
+
+    let sender = EXPOSURES.lock().unwrap().clone();
+    for (experiment, variant) in &assignments.variants {
+        metrics::observe_exposure(experiment, variant);
+        let Some(sender) = &sender else {
+            continue;
+        };
+        let exposure = Exposure {
+            experiment: experiment.to_string(),
+            subject: assignments.subject.clone(),
+            variant: variant.to_string(),
+        };
+        if sender.try_send(exposure).is_err() {
+            debug!("Exposure queue full, exposure dropped");
+        }
+    }
+}
+
+/// The experiments with their variants, `[]` without `EXPERIMENTS_PATH`.
+pub fn experiments() -> &'static [Experiment] {
+    EXPERIMENTS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Writes the exposures queued so far and stops the writer.
+/// This function should be called once, when the server has stopped.
+pub async fn flush_exposures() {
+    // The writer flushes and returns once the queue is closed
+    EXPOSURES.lock().unwrap().take();
+    let writer = WRITER.lock().unwrap().take();
+    if let Some(writer) = writer
+        && let Err(e) = writer.await
+    {
+        warn!("Exposure writer failed: {}", e);
+    }
+}
+
+/// Writes the exposures received, every `FLUSH_INTERVAL` or `BATCH_SIZE` exposures,
+/// until the queue is closed.
+async fn write_exposures(mut receiver: mpsc::Receiver<Exposure>) {
+    let mut written = HashSet::new();
+    let mut batch = Vec::new();
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            exposure = receiver.recv() => match exposure {
+                Some(exposure) => {
+                    if written.insert(exposure.clone()) {
+                        batch.push(exposure);
+                    }
+                    if batch.len() < BATCH_SIZE {
+                        continue;
+                    }
+                }
+                None => {
+                    write(&mut batch, &mut written).await;
+                    return;
+                }
+            },
+            _ = interval.tick() => {}
+        }
+        write(&mut batch, &mut written).await;
+        if written.len() > MAX_REMEMBERED {
+            written.clear();
+        }
+    }
+}
+
+/// Writes a batch, forgetting its exposures on failure so they are written when they
+/// come back.
+async fn write(batch: &mut Vec<Exposure>, written: &mut HashSet<Exposure>) {
+    if batch.is_empty() {
+        return;
+    }
+    if let Err(e) = PgExposureRepo::default().record(batch).await {
+        warn!("{} exposures not recorded: {}", batch.len(), e);
+        for exposure in batch.iter() {
+            written.remove(exposure);
+        }
+    }
+    batch.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn experiment(json: &str) -> Experiment {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn subjects_are_spread_by_weight() {
+        let checkout = experiment(
+            r#"{"name": "checkout", "variants": [
+                {"name": "control", "weight": 3}, {"name": "green", "weight": 1}
+            ]}"#,
+        );
+        let green = (0..4000)
+            .filter(|user| checkout.assign(&format!("user:{}", user)) == "green")
+            .count();
+        assert!((800..1200).contains(&green), "{} green out of 4000", green);
+        // Always the same variant for a subject
+        assert_eq!(checkout.assign("user:7"), checkout.assign("user:7"));
+
+        let off = experiment(
+            r#"{"name": "off", "variants": [
+                {"name": "control", "weight": 1}, {"name": "new", "weight": 0}
+            ]}"#,
+        );
+        assert!((0..100).all(|user| off.assign(&format!("user:{}", user)) == "control"));
+    }
+
+    #[test]
+    fn invalid_experiments_are_rejected() {
+        let unnamed = experiment(r#"{"name": "a b", "variants": [{"name": "x", "weight": 1}]}"#);
+        assert!(unnamed.validate().is_err());
+        let weightless = experiment(r#"{"name": "a", "variants": [{"name": "x", "weight": 0}]}"#);
+        assert!(weightless.validate().is_err());
+    }
+}
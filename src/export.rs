@@ -0,0 +1,129 @@
+//! Parquet export of the tables (`GET /admin/export/parquet`).
+//!
+//! A table is read with a cursor (see `db::cursor`) and written as a Parquet file
+//! compressed with Snappy, sent while it's written: each row group of
+//! `ROW_GROUP_SIZE` rows goes out once encoded, so only one is in memory at a time
+//! whatever the size of the table, and the file ends with its footer once the last
+//! row is read. The columns of each table are in `repository::export`.
+
+use std::mem;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use arrow_array::{
+    ArrayRef, Float64Array, Int32Array, RecordBatch, StringArray, TimestampMicrosecondArray,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use bb8_postgres::tokio_postgres::Row;
+use futures_util::{Stream, TryStreamExt, stream};
+use hyper::body::Bytes;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+
+use crate::error::AppError;
+use crate::repository::export::{Column, ColumnKind, ExportRepository, ExportTable, PgExportRepo};
+
+/// Rows of a row group, the unit of the reads of a Parquet file
+const ROW_GROUP_SIZE: usize = 64 * 1024;
+
+/// Writes `table` as a Parquet file.
+///
+/// # Returns
+///
+/// * `Result<impl Stream, AppError>` - The chunks of the file; the table is read as
+///   they're polled, a failure ends the stream with the error
+pub fn parquet(
+    table: ExportTable,
+) -> Result<impl Stream<Item = Result<Bytes, AppError>> + Send + 'static, AppError> {
+    let columns = table.columns();
+    let schema = Arc::new(schema(columns));
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_max_row_group_size(ROW_GROUP_SIZE)
+        .build();
+    let writer = ArrowWriter::try_new(Vec::new(), Arc::clone(&schema), Some(properties))
+        .map_err(export_error)?;
+
+    let encoder = Encoder {
+        rows: Box::pin(PgExportRepo.stream_rows(table)),
+        writer,
+        schema,
+        columns,
+    };
+    Ok(stream::try_unfold(Some(encoder), async |encoder| {
+        let Some(mut encoder) = encoder else {
+            return Ok(None);
+        };
+        while let Some(rows) = encoder.rows.try_next().await? {
+            let batch = record_batch(&encoder.schema, encoder.columns, &rows)?;
+            encoder.writer.write(&batch).map_err(export_error)?;
+            // The writer buffers the rows until its row group is full
+            let written = mem::take(encoder.writer.inner_mut());
+            if !written.is_empty() {
+                return Ok(Some((Bytes::from(written), Some(encoder))));
+            }
+        }
+        let written = encoder.writer.into_inner().map_err(export_error)?;
+        Ok(Some((Bytes::from(written), None)))
+    }))
+}
+
+struct Encoder<S> {
+    rows: Pin<Box<S>>,
+    writer: ArrowWriter<Vec<u8>>,
+    schema: SchemaRef,
+    columns: &'static [Column],
+}
+
+/// The Arrow schema of the values of `columns`.
+fn schema(columns: &[Column]) -> Schema {
+    let fields = columns.iter().map(|column| {
+        let data_type = match column.kind {
+            ColumnKind::Integer => DataType::Int32,
+            ColumnKind::Float => DataType::Float64,
+            ColumnKind::Text => DataType::Utf8,
+            ColumnKind::Timestamp => DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+        };
+        Field::new(column.name, data_type, column.nullable)
+    });
+    Schema::new(fields.collect::<Vec<_>>())
+}
+
+/// The values of `rows` as columns.
+fn record_batch(
+    schema: &SchemaRef,
+    columns: &[Column],
+    rows: &[Row],
+) -> Result<RecordBatch, AppError> {
+    let arrays = columns.iter().enumerate().map(|(i, column)| -> ArrayRef {
+        match column.kind {
+            ColumnKind::Integer => Arc::new(
+                rows.iter()
+                    .map(|row| row.get::<_, Option<i32>>(i))
+                    .collect::<Int32Array>(),
+            ),
+            ColumnKind::Float => Arc::new(
+                rows.iter()
+                    .map(|row| row.get::<_, Option<f64>>(i))
+                    .collect::<Float64Array>(),
+            ),
+            ColumnKind::Text => Arc::new(
+                rows.iter()
+                    .map(|row| row.get::<_, Option<&str>>(i))
+                    .collect::<StringArray>(),
+            ),
+            ColumnKind::Timestamp => Arc::new(
+                rows.iter()
+                    .map(|row| row.get::<_, Option<i64>>(i))
+                    .collect::<TimestampMicrosecondArray>()
+                    .with_timezone("UTC"),
+            ),
+        }
+    });
+    RecordBatch::try_new(Arc::clone(schema), arrays.collect()).map_err(export_error)
+}
+
+fn export_error(e: impl std::fmt::Display) -> AppError {
+    AppError::Internal(format!("Parquet export failed: {}", e))
+}
@@ -0,0 +1,296 @@
+//! The client of a request behind reverse proxies (`TRUSTED_PROXIES`).
+//!
+//! Behind nginx or a load balancer, the TCP peer of every request is the proxy. The
+//! proxy reports the client in `Forwarded` (RFC 7239), or else in `X-Forwarded-For`
+//! and `X-Forwarded-Proto`. Anyone can send these headers, so they are only believed
+//! when the peer is a trusted proxy: the addresses of `X-Forwarded-For` are then read
+//! from the right (the closest proxy appends last) until one isn't trusted either,
+//! which is the client. The scheme is the one of the same hop, the values of
+//! `X-Forwarded-Proto` lined up with the addresses from the right.
+//!
+//! The result, a [`ClientInfo`], is attached to the request extensions before any
+//! other middleware, and used by the rate limiter, the GeoIP lookup and the logs.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+
+use hyper::Request;
+use hyper::header::{FORWARDED, HeaderMap};
+use tracing::warn;
+
+use crate::config::Cidr;
+use crate::router::ClientAddr;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+// Set once at startup, empty when no proxy is trusted
+static TRUSTED_PROXIES: OnceLock<Vec<Cidr>> = OnceLock::new();
+
+/// Scheme of a request as sent by the client.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Scheme {
+    #[default]
+    Http,
+    Https,
+}
+
+impl Scheme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        }
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The client of a request, in the request extensions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientInfo {
+    /// Address of the client: the TCP peer, or the one reported by trusted proxies
+    pub ip: IpAddr,
+    /// Scheme the client used: of the connection, or the one reported by trusted
+    /// proxies (`https` when they terminate TLS)
+    pub scheme: Scheme,
+}
+
+impl ClientInfo {
+    /// The client of a request, `None` before [`resolve_client`] ran.
+    pub fn of<B>(req: &Request<B>) -> Option<ClientInfo> {
+        req.extensions().get::<ClientInfo>().copied()
+    }
+}
+
+/// Sets the proxies whose forwarding headers are believed.
+/// This function should be called once at application startup.
+pub fn init_trusted_proxies(proxies: &[Cidr]) {
+    if TRUSTED_PROXIES.set(proxies.to_vec()).is_err() {
+        warn!("Attempt to reset the trusted proxies ignored");
+    }
+}
+
+fn is_trusted(ip: IpAddr) -> bool {
+    TRUSTED_PROXIES
+        .get()
+        .is_some_and(|proxies| proxies.iter().any(|proxy| proxy.contains(ip)))
+}
+
+/// Middleware attaching the [`ClientInfo`] of a request to its extensions.
+///
+/// Does nothing for requests without `ClientAddr` (not received by the accept loop).
+pub fn resolve_client<B>(req: &mut Request<B>) {
+    let Some(ClientAddr(peer)) = req.extensions().get::<ClientAddr>().copied() else {
+        return;
+    };
+    let scheme = req
+        .extensions()
+        .get::<Scheme>()
+        .copied()
+        .unwrap_or_default();
+    let client = resolve(peer, scheme, req.headers(), is_trusted);
+    req.extensions_mut().insert(client);
+}
+
+/// The client of a request from `peer`, see the module documentation.
+fn resolve(
+    peer: SocketAddr,
+    scheme: Scheme,
+    headers: &HeaderMap,
+    is_trusted: impl Fn(IpAddr) -> bool,
+) -> ClientInfo {
+    let mut client = ClientInfo {
+        ip: peer.ip().to_canonical(),
+        scheme,
+    };
+    if !is_trusted(client.ip) {
+        return client;
+    }
+
+    // The hops from the client to the peer, the closest last
+    let hops: Vec<(Option<IpAddr>, Option<Scheme>)> = if headers.contains_key(FORWARDED) {
+        joined(headers, FORWARDED.as_str())
+            .map(|element| {
+                let mut hop = (None, None);
+                for pair in element.split(';') {
+                    let Some((key, value)) = pair.split_once('=') else {
+                        continue;
+                    };
+                    let value = value.trim().trim_matches('"');
+                    match key.trim().to_ascii_lowercase().as_str() {
+                        "for" => hop.0 = parse_node(value),
+                        "proto" => hop.1 = parse_scheme(value),
+                        _ => {}
+                    }
+                }
+                hop
+            })
+            .collect()
+    } else {
+        // Each proxy appends to both headers: the schemes line up with the addresses
+        // from the right, and the ones the client sent are never reached
+        let mut protos = joined(headers, X_FORWARDED_PROTO)
+            .map(parse_scheme)
+            .collect::<Vec<_>>();
+        let mut hops = joined(headers, X_FORWARDED_FOR)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .map(|node| (parse_node(node), protos.pop().flatten()))
+            .collect::<Vec<_>>();
+        hops.reverse();
+        hops
+    };
+
+    for (ip, proto) in hops.into_iter().rev() {
+        // An obfuscated or unknown hop, the client can't be told past it
+        let Some(ip) = ip else {
+            break;
+        };
+        client.ip = ip;
+        if let Some(proto) = proto {
+            client.scheme = proto;
+        }
+        if !is_trusted(ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// The comma-separated values of every line of a header.
+fn joined<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+/// The address of a node: `192.0.2.60`, `192.0.2.60:4711`, `[2001:db8::17]:4711`, or
+/// `None` for `unknown` and obfuscated identifiers (`_hidden`).
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Some(bracketed) = node.strip_prefix('[') {
+        let (ip, _) = bracketed.split_once(']')?;
+        return ip.parse().ok();
+    }
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .map(|ip| ip.to_canonical())
+}
+
+fn parse_scheme(value: &str) -> Option<Scheme> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "http" => Some(Scheme::Http),
+        "https" => Some(Scheme::Https),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+
+    fn networks(list: &str) -> Vec<Cidr> {
+        list.split(',').map(|cidr| cidr.parse().unwrap()).collect()
+    }
+
+    fn resolve_with(trusted: &str, headers: &[(&'static str, &'static str)]) -> ClientInfo {
+        let trusted = networks(trusted);
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, HeaderValue::from_static(value));
+        }
+        resolve(
+            "10.0.0.2:40000".parse().unwrap(),
+            Scheme::Http,
+            &map,
+            |ip| trusted.iter().any(|proxy| proxy.contains(ip)),
+        )
+    }
+
+    #[test]
+    fn headers_of_untrusted_peers_are_ignored() {
+        let client = resolve_with(
+            "192.168.0.0/16",
+            &[
+                ("x-forwarded-for", "1.2.3.4"),
+                ("x-forwarded-proto", "https"),
+            ],
+        );
+        assert_eq!(client.ip, "10.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(client.scheme, Scheme::Http);
+    }
+
+    #[test]
+    fn forwarded_for_is_read_up_to_the_first_untrusted_hop() {
+        // The client forged the first address, the trusted proxies appended the others
+        let client = resolve_with(
+            "10.0.0.0/8",
+            &[
+                ("x-forwarded-for", "6.6.6.6, 203.0.113.9"),
+                ("x-forwarded-for", "10.0.0.1"),
+                ("x-forwarded-proto", "https"),
+            ],
+        );
+        assert_eq!(client.ip, "203.0.113.9".parse::<IpAddr>().unwrap());
+        assert_eq!(client.scheme, Scheme::Https);
+    }
+
+    #[test]
+    fn forwarded_proto_of_the_client_is_ignored() {
+        // The client claimed https, the trusted proxy appended the scheme it received
+        let client = resolve_with(
+            "10.0.0.0/8",
+            &[
+                ("x-forwarded-for", "203.0.113.9"),
+                ("x-forwarded-proto", "https"),
+                ("x-forwarded-proto", "http"),
+            ],
+        );
+        assert_eq!(client.ip, "203.0.113.9".parse::<IpAddr>().unwrap());
+        assert_eq!(client.scheme, Scheme::Http);
+
+        // A forged hop and its scheme, left of the client
+        let client = resolve_with(
+            "10.0.0.0/8",
+            &[
+                ("x-forwarded-for", "6.6.6.6, 203.0.113.9, 10.0.0.1"),
+                ("x-forwarded-proto", "https, http, http"),
+            ],
+        );
+        assert_eq!(client.ip, "203.0.113.9".parse::<IpAddr>().unwrap());
+        assert_eq!(client.scheme, Scheme::Http);
+    }
+
+    #[test]
+    fn forwarded_takes_precedence() {
+        let client = resolve_with(
+            "10.0.0.0/8",
+            &[
+                (
+                    "forwarded",
+                    "for=\"[2001:db8:cafe::17]:4711\";proto=https, for=10.0.0.1",
+                ),
+                ("x-forwarded-for", "1.2.3.4"),
+            ],
+        );
+        assert_eq!(client.ip, "2001:db8:cafe::17".parse::<IpAddr>().unwrap());
+        assert_eq!(client.scheme, Scheme::Https);
+
+        // Nothing can be told past an obfuscated hop, the last proxy is the client
+        let client = resolve_with("10.0.0.0/8", &[("forwarded", "for=_hidden, for=10.0.0.1")]);
+        assert_eq!(client.ip, "10.0.0.1".parse::<IpAddr>().unwrap());
+    }
+}
@@ -0,0 +1,217 @@
+//! Per-country / legal-region request policies.
+//!
+//! Rules keyed by the GeoIP-derived location of the client (see the `geoip` module),
+//! evaluated by a middleware before routing. Used to restrict features for
+//! compliance reasons.
+//!
+//! ## Configuration
+//! Rules are read from the JSON file pointed to by `GEO_POLICY_PATH` at startup
+//! and reloaded at runtime when the process receives SIGHUP (no restart needed).
+//!
+//! ```json
+//! [
+//!   { "region": "KP", "action": "block" },
+//!   { "region": "EU", "action": "require_consent", "paths": ["/users"] },
+//!   { "region": "US-CA", "action": "defaults", "defaults": { "limit": "10" } }
+//! ]
+//! ```
+//!
+//! `region` is a country code (`ES`), a country and subdivision (`US-CA`) or `EU`
+//! (any member of the European Union). `paths` restricts a rule to some path
+//! prefixes; without it the rule applies to every route. The first matching
+//! rule of each action wins.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use hyper::{Request, Response, StatusCode, Uri};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::error::{ErrorBody, ErrorCode};
+use crate::geoip::GeoInfo;
+use crate::router::{Body, json_response};
+
+/// Header a client sends to confirm the user gave the consent required in its region
+pub const CONSENT_HEADER: &str = "x-consent";
+
+// Static global variable to store the active rules
+// RwLock so the rules can be replaced at runtime while requests read them
+static POLICIES: RwLock<Vec<PolicyRule>> = RwLock::new(Vec::new());
+
+/// What happens to a request matching a rule.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyAction {
+    /// Reject the request with 451 Unavailable For Legal Reasons
+    Block,
+    /// Reject the request with 403 unless it carries `X-Consent: granted`
+    RequireConsent,
+    /// Let the request through with region specific default query parameters
+    /// (added only when the client didn't send them, e.g. a smaller page size)
+    Defaults,
+}
+
+/// A single policy rule of the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PolicyRule {
+    pub region: String,
+    pub action: PolicyAction,
+    #[serde(default)]
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub defaults: HashMap<String, String>,
+}
+
+impl PolicyRule {
+    /// Whether the rule applies to a client located in `geo` calling `path`.
+    fn matches(&self, geo: &GeoInfo, path: &str) -> bool {
+        let region_matches = geo.is_in(&self.region);
+        let path_matches =
+            self.paths.is_empty() || self.paths.iter().any(|prefix| path.starts_with(prefix));
+
+        region_matches && path_matches
+    }
+}
+
+/// Loads (or reloads) the rules from `GEO_POLICY_PATH`.
+///
+/// Does nothing if the variable is not set. On error the current rules are kept.
+///
+/// # Arguments
+///
+/// * `path` - Path of the JSON rules file, `None` when no policies are configured
+///
+/// # Returns
+///
+/// * `Result<usize, String>` - Number of active rules, or an error message
+pub fn load_policies(path: Option<&Path>) -> Result<usize, String> {
+    let Some(path) = path else {
+        return Ok(0);
+    };
+
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read geo policies '{}': {}", path.display(), e))?;
+    let rules: Vec<PolicyRule> = serde_json::from_str(&content)
+        .map_err(|e| format!("Invalid geo policies '{}': {}", path.display(), e))?;
+
+    let count = rules.len();
+    *POLICIES.write().unwrap() = rules;
+
+    info!("{} geo policies loaded from {}", count, path.display());
+    Ok(count)
+}
+
+/// Reloads the rules every time the process receives SIGHUP.
+/// Spawned once at startup; does nothing on non-Unix systems.
+pub async fn reload_on_sighup(path: Option<PathBuf>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+            warn!("Unable to install SIGHUP handler, geo policies can't be reloaded");
+            return;
+        };
+
+        while hangup.recv().await.is_some() {
+            if let Err(e) = load_policies(path.as_deref()) {
+                warn!("Geo policies not reloaded: {}", e);
+            }
+        }
+    }
+}
+
+/// Middleware that applies the policy rules to a request.
+///
+/// Must run after `geoip::attach_geo_info`. Requests without a known location
+/// are never restricted.
+///
+/// # Returns
+///
+/// * `Option<Response<Body>>` - The rejection response if the request must not
+///   reach the router, `None` to continue
+pub fn enforce_geo_policies<B>(req: &mut Request<B>) -> Option<Response<Body>> {
+    let geo = req.extensions().get::<GeoInfo>()?.clone();
+    let path = req.uri().path().to_owned();
+
+    let (blocked, consent_required, defaults) = {
+        let rules = POLICIES.read().unwrap();
+        let first = |action: PolicyAction| {
+            rules
+                .iter()
+                .find(|rule| rule.action == action && rule.matches(&geo, &path))
+                .cloned()
+        };
+        (
+            first(PolicyAction::Block),
+            first(PolicyAction::RequireConsent),
+            first(PolicyAction::Defaults),
+        )
+    };
+
+    if let Some(rule) = blocked {
+        return Some(json_response(
+            StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+            ErrorBody::new(
+                ErrorCode::RegionBlocked,
+                format!("Not available in region {}", rule.region),
+            ),
+        ));
+    }
+
+    if consent_required.is_some() {
+        let consent = req
+            .headers()
+            .get(CONSENT_HEADER)
+            .and_then(|value| value.to_str().ok());
+        if consent != Some("granted") {
+            return Some(json_response(
+                StatusCode::FORBIDDEN,
+                ErrorBody::new(
+                    ErrorCode::ConsentRequired,
+                    "User consent is required in this region (X-Consent: granted)",
+                ),
+            ));
+        }
+    }
+
+    if let Some(rule) = defaults {
+        apply_default_query_params(req, &rule.defaults);
+    }
+
+    None
+}
+
+/// Adds the default query parameters missing from the request URI.
+/// Parameters sent by the client always take precedence.
+fn apply_default_query_params<B>(req: &mut Request<B>, defaults: &HashMap<String, String>) {
+    let mut params: Vec<(String, String)> =
+        serde_urlencoded::from_str(req.uri().query().unwrap_or("")).unwrap_or_default();
+
+    let missing: Vec<(String, String)> = defaults
+        .iter()
+        .filter(|(key, _)| !params.iter().any(|(existing, _)| existing == *key))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    if missing.is_empty() {
+        return;
+    }
+    params.extend(missing);
+
+    let Ok(query) = serde_urlencoded::to_string(&params) else {
+        return;
+    };
+    let path_and_query = format!("{}?{}", req.uri().path(), query);
+
+    let mut parts = req.uri().clone().into_parts();
+    match path_and_query.parse() {
+        Ok(pq) => parts.path_and_query = Some(pq),
+        Err(_) => return,
+    }
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *req.uri_mut() = uri;
+    }
+}
@@ -0,0 +1,116 @@
+//! GeoIP lookup.
+//!
+//! Optionally loads a MaxMind GeoLite2 database (Country or City edition) at startup
+//! and resolves the client IP of every request into its country and region.
+//! The result is attached to the request extensions as a `GeoInfo`, so any later
+//! layer (handlers, logs, geo-based rules) can read it without repeating the lookup.
+//!
+//! The lookup is disabled when `GEOIP_DB_PATH` is not set.
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use hyper::Request;
+use maxminddb::{Reader, geoip2};
+use tracing::{info, warn};
+
+use crate::forwarded::ClientInfo;
+
+// Static global variable to store the GeoIP database
+// Loaded once at startup and only read afterwards
+static GEOIP_READER: OnceLock<Reader<Vec<u8>>> = OnceLock::new();
+
+/// Location of a client resolved from its IP address.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GeoInfo {
+    /// ISO 3166-1 country code (`ES`, `US`, ...)
+    pub country: Option<String>,
+    /// ISO 3166-2 subdivision code without the country prefix (`MD`, `CA`, ...)
+    /// Only available with the City edition of the database
+    pub region: Option<String>,
+    /// Whether the country is a member of the European Union
+    pub in_eu: bool,
+}
+
+impl GeoInfo {
+    /// Whether the client is located in `area`: a country code (`ES`), a country and
+    /// subdivision (`US-CA`) or `EU` (any member of the European Union).
+    pub fn is_in(&self, area: &str) -> bool {
+        match area.split_once('-') {
+            Some((country, region)) => {
+                self.country.as_deref() == Some(country) && self.region.as_deref() == Some(region)
+            }
+            None if area == "EU" => self.in_eu,
+            None => self.country.as_deref() == Some(area),
+        }
+    }
+}
+
+/// Loads the GeoIP database if `GEOIP_DB_PATH` is set.
+/// This function should be called at application startup.
+///
+/// # Arguments
+///
+/// * `path` - Path of the `.mmdb` file, `None` to disable the lookup
+///
+/// # Returns
+///
+/// * `Result<bool, String>` - Whether a database was loaded, or an error message
+///   if the configured file can't be read
+pub fn init_geoip(path: Option<&Path>) -> Result<bool, String> {
+    let Some(path) = path else {
+        return Ok(false);
+    };
+
+    let reader = Reader::open_readfile(path)
+        .map_err(|e| format!("Unable to load GeoIP database '{}': {}", path.display(), e))?;
+
+    GEOIP_READER.set(reader).unwrap_or_else(|_| {
+        warn!("Attempt to reload GeoIP database ignored");
+    });
+
+    info!("GeoIP database loaded from {}", path.display());
+    Ok(true)
+}
+
+/// Resolves an IP address into its location.
+///
+/// # Returns
+///
+/// * `Option<GeoInfo>` - `None` if no database is loaded or the address is unknown
+///   (private networks, loopback, ...)
+pub fn lookup(ip: IpAddr) -> Option<GeoInfo> {
+    let reader = GEOIP_READER.get()?;
+    let city: geoip2::City = reader.lookup(ip).ok()?;
+
+    let country = city.country.as_ref();
+    let region = city
+        .subdivisions
+        .as_ref()
+        .and_then(|subdivisions| subdivisions.first())
+        .and_then(|subdivision| subdivision.iso_code)
+        .map(str::to_string);
+
+    Some(GeoInfo {
+        country: country.and_then(|c| c.iso_code).map(str::to_string),
+        region,
+        in_eu: country
+            .and_then(|c| c.is_in_european_union)
+            .unwrap_or(false),
+    })
+}
+
+/// Middleware that attaches the `GeoInfo` of the client to the request extensions.
+///
+/// Does nothing when the lookup is disabled or the client address is unknown,
+/// so handlers must treat a missing `GeoInfo` as "location unknown".
+pub fn attach_geo_info<B>(req: &mut Request<B>) {
+    let Some(client) = ClientInfo::of(req) else {
+        return;
+    };
+
+    if let Some(info) = lookup(client.ip) {
+        req.extensions_mut().insert(info);
+    }
+}
@@ -0,0 +1,56 @@
+//! Outbound HTTP client, for the requests the server sends to other services.
+//!
+//! A single client is shared by the whole process so its connections are pooled and
+//! reused between requests. It speaks plain HTTP, or HTTPS with the certificate of the
+//! service checked against the Mozilla root CAs; HTTP/2 is used when the service
+//! offers it during the TLS handshake.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use http_body_util::combinators::UnsyncBoxBody;
+use hyper::body::Bytes;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+use crate::router::BoxError;
+
+/// Time an unused connection is kept in the pool
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Body of the outbound requests, buffered or streamed.
+pub type OutboundBody = UnsyncBoxBody<Bytes, BoxError>;
+
+/// The client type, see [`http_client`].
+pub type HttpClient = Client<HttpsConnector<HttpConnector>, OutboundBody>;
+
+static HTTP_CLIENT: OnceLock<HttpClient> = OnceLock::new();
+
+/// The outbound HTTP client, built on first use.
+///
+/// Requests have no time limit of their own: callers bound them with
+/// `tokio::time::timeout`, and with `context::within_deadline` when they run for a
+/// request.
+pub fn http_client() -> &'static HttpClient {
+    HTTP_CLIENT.get_or_init(|| {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let tls = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = HttpsConnectorBuilder::new()
+            .with_tls_config(tls)
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .build();
+        Client::builder(TokioExecutor::new())
+            .pool_timer(TokioTimer::new())
+            .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+            .build(connector)
+    })
+}
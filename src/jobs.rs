@@ -0,0 +1,477 @@
+//! Background jobs.
+//!
+//! Work that shouldn't delay a response (the welcome email of a new account) is
+//! handed to [`enqueue`] and run by `JOB_WORKERS` worker tasks. Periodic work (the
+//! nightly cleanup, `JOB_CLEANUP_SCHEDULE`, the rules of `retention`, the monthly
+//! `partitions`, the `recommendations` and the consistency check of the `ledger`) is
+//! enqueued by the scheduler, see `schedule`.
+//!
+//! Every job is a row of the `jobs` table, written when it's enqueued and updated with
+//! its outcome, so it can be inspected (`GET /admin/jobs`). A job enqueued while
+//! handling a request runs with its request ID and its caller (see `context`). A job
+//! started by a request with [`submit`] is an operation its client follows with
+//! `GET /operations/:id`, including the progress a long job reports (the bulk delete
+//! of users, the restore of an archive). A failing job is tried again after 30
+//! seconds, then 2, 8 and 32 minutes; after `MAX_ATTEMPTS` it stays `failed` with its
+//! last error.
+//!
+//! On shutdown the scheduler stops first (see [`stop_scheduler`]), then the workers
+//! finish the jobs already queued, for at most `JOB_DRAIN_TIMEOUT` (see [`drain_jobs`]).
+//! Jobs left over, and those waiting for a retry, stay `queued` in the table and are
+//! picked up again at the next start.
+
+mod schedule;
+
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use futures_util::FutureExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::sync::{Mutex as AsyncMutex, mpsc};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{Instrument, debug, info, warn};
+use uuid::Uuid;
+
+use crate::archives;
+use crate::auth::AuthUser;
+use crate::config::JobsConfig;
+use crate::context::RequestContext;
+use crate::db::request_scope;
+use crate::embeddings;
+use crate::error::AppError;
+use crate::events::{self, Action, Collection};
+use crate::ledger;
+use crate::logging::RequestId;
+use crate::metrics;
+use crate::partitions::{self, init_partitions};
+use crate::recommendations;
+use crate::repository::filter::Filter;
+use crate::repository::jobs::{JobRepository, PgJobRepo};
+use crate::repository::users::{PgUserRepo, UserRepository};
+use crate::retention::{self, init_retention};
+use crate::routes::users::FILTERABLE_FIELDS;
+use crate::search_engine;
+use crate::webhooks;
+pub use schedule::Schedule;
+use schedule::run_scheduler;
+
+/// Attempts of a job before it's marked as failed
+const MAX_ATTEMPTS: i32 = 5;
+
+/// Delay before the first retry, multiplied by 4 for each of the next ones
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(30);
+
+/// Subject of the email sent to new accounts
+const WELCOME_SUBJECT: &str = "Welcome!";
+
+/// Users deleted by each transaction of a bulk delete, so that none holds its locks
+/// for long
+const BULK_DELETE_BATCH: i64 = 500;
+
+// Set once at startup
+static QUEUE: OnceLock<Queue> = OnceLock::new();
+
+/// Something to do in the background, stored as JSON in the `jobs` table.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Job {
+    /// Greets a new account
+    WelcomeEmail {
+        user_id: i32,
+        name: String,
+        email: String,
+    },
+    /// Deletes the jobs finished more than `JOB_RETENTION_DAYS` days ago
+    Cleanup,
+    /// Deletes the users matching a filter expression (`POST /users/bulk-delete`)
+    BulkDeleteUsers {
+        filter: String,
+        /// Users matching the filter when the request was made
+        total: i64,
+        /// Caller of the request, recorded in the audit log
+        actor_id: Option<i32>,
+    },
+    /// Applies a retention rule (see `retention`)
+    ApplyRetention { rule: String },
+    /// Creates the partitions of the next months and archives the old ones (see
+    /// `partitions`)
+    MaintainPartitions,
+    /// Exports a partition of the `archive` schema to the file storage (see `archives`)
+    ExportArchive { table: String, partition: String },
+    /// Restores an exported partition (`POST /admin/archives/:id/restore`)
+    RestoreArchive { archive_id: Uuid },
+    /// Delivers a change event to a webhook (see `webhooks`)
+    DeliverWebhook {
+        webhook_id: Uuid,
+        /// The event envelope
+        event: Value,
+    },
+    /// Embeds the products whose text changed since their last embedding (see
+    /// `embeddings`)
+    EmbedProducts,
+    /// Computes again the cached product recommendations of the users (see
+    /// `recommendations`)
+    RefreshRecommendations,
+    /// Indexes the changes of the users and products in the search engine (see
+    /// `search_engine`)
+    SyncSearchEngine,
+    /// Checks that the balances of the ledger match its postings (see `ledger`)
+    CheckLedger,
+}
+
+impl Job {
+    fn kind(&self) -> &'static str {
+        match self {
+            Job::WelcomeEmail { .. } => "welcome_email",
+            Job::Cleanup => "cleanup",
+            Job::BulkDeleteUsers { .. } => "bulk_delete_users",
+            Job::ApplyRetention { .. } => "apply_retention",
+            Job::MaintainPartitions => "maintain_partitions",
+            Job::ExportArchive { .. } => "export_archive",
+            Job::RestoreArchive { .. } => "restore_archive",
+            Job::DeliverWebhook { .. } => "deliver_webhook",
+            Job::EmbedProducts => "embed_products",
+            Job::RefreshRecommendations => "refresh_recommendations",
+            Job::SyncSearchEngine => "sync_search_engine",
+            Job::CheckLedger => "check_ledger",
+        }
+    }
+
+    /// Runs the job `id`.
+    async fn run(&self, id: i64) -> Result<(), AppError> {
+        match self {
+            Job::WelcomeEmail {
+                user_id,
+                name,
+                email,
+            } => {
+                // No mail transport is configured yet: the message goes to the log
+                info!(
+                    user_id,
+                    to = %email,
+                    subject = WELCOME_SUBJECT,
+                    "Hello {}, your account is ready",
+                    name
+                );
+                Ok(())
+            }
+            Job::Cleanup => {
+                let days = QUEUE.get().map_or(i32::MAX, |queue| queue.retention_days);
+                let deleted = PgJobRepo::default().purge(days).await?;
+                info!("Cleanup deleted {} finished jobs", deleted);
+                Ok(())
+            }
+            Job::BulkDeleteUsers {
+                filter,
+                total,
+                actor_id,
+            } => {
+                let run = bulk_delete_users(id, filter, *total);
+                match actor_id {
+                    Some(actor_id) => AuthUser { id: *actor_id }.scope(run).await,
+                    None => run.await,
+                }
+            }
+            Job::ApplyRetention { rule } => retention::apply(id, rule).await,
+            Job::MaintainPartitions => partitions::maintain(id).await,
+            Job::ExportArchive { table, partition } => archives::export(id, table, partition).await,
+            Job::RestoreArchive { archive_id } => archives::restore(id, *archive_id).await,
+            Job::DeliverWebhook { webhook_id, event } => {
+                webhooks::deliver(id, *webhook_id, event).await
+            }
+            Job::EmbedProducts => embeddings::embed_products(id).await,
+            Job::RefreshRecommendations => recommendations::refresh_all(id).await,
+            Job::SyncSearchEngine => search_engine::sync(id).await,
+            Job::CheckLedger => ledger::check(id).await,
+        }
+    }
+}
+
+/// Deletes the users matching `expression` in batches, recording the progress of the
+/// job `id` after each one.
+///
+/// A retry goes on where the failed attempt stopped: the users it deleted are left
+/// out by the filter.
+async fn bulk_delete_users(id: i64, expression: &str, total: i64) -> Result<(), AppError> {
+    let mut filter = Filter::default();
+    filter.add_expression(expression, FILTERABLE_FIELDS)?;
+    let mut deleted = (total - PgUserRepo::default().count(&filter).await?).max(0);
+
+    loop {
+        let batch = PgUserRepo::default()
+            .delete_batch(&filter, BULK_DELETE_BATCH)
+            .await?;
+        if batch.is_empty() {
+            info!("Bulk delete {} soft-deleted {} users", id, deleted);
+            return Ok(());
+        }
+        // Their avatars stay with them, to be restored
+        for &(key, public_id) in &batch {
+            events::publish(Collection::Users, Action::Deleted, key, public_id);
+        }
+        deleted += batch.len() as i64;
+        // Users created meanwhile may match too
+        let progress = json!({"total": total.max(deleted), "deleted": deleted});
+        PgJobRepo::default().set_progress(id, &progress).await?;
+    }
+}
+
+struct Queue {
+    /// IDs of the jobs to run; taken by `drain_jobs`, which ends the workers once
+    /// they have emptied the channel
+    sender: Mutex<Option<mpsc::Sender<i64>>>,
+    workers: AsyncMutex<JoinSet<()>>,
+    /// Taken by `stop_scheduler`
+    scheduler: Mutex<Option<JoinHandle<()>>>,
+    retention_days: i32,
+}
+
+impl Queue {
+    fn sender(&self) -> Option<mpsc::Sender<i64>> {
+        self.sender
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// Enqueues a job, run by a worker as soon as one is free.
+///
+/// Never fails: a job that can't be stored is logged and dropped, so the caller (a
+/// request handler) doesn't fail because of it.
+pub async fn enqueue(job: Job) {
+    let kind = job.kind();
+    if let Err(e) = submit(job).await {
+        warn!("Job {} dropped: {}", kind, e);
+    }
+}
+
+/// Enqueues a job the caller follows as an operation, see [`enqueue`].
+///
+/// # Returns
+///
+/// * `Result<i64, AppError>` - The ID of the job, or why it couldn't be stored
+pub async fn submit(job: Job) -> Result<i64, AppError> {
+    let Some(queue) = QUEUE.get() else {
+        return Err(AppError::Internal(
+            "The job queue is not started".to_string(),
+        ));
+    };
+    let payload = serde_json::to_string(&job)
+        .map_err(|e| AppError::Internal(format!("Serialization failed: {}", e)))?;
+    // Run later in the scope of the request enqueueing it, if any
+    let context = RequestContext::current();
+    let request_id = context.request_id.as_ref().map(|id| id.0.as_str());
+    let actor_id = context.user.map(|user| user.id);
+    let id = PgJobRepo::default()
+        .insert(job.kind(), &payload, request_id, actor_id)
+        .await?;
+
+    match queue.sender() {
+        // Waits for room when the workers are behind
+        Some(sender) if sender.send(id).await.is_ok() => {}
+        _ => debug!("Job {} kept for the next start, the queue is draining", id),
+    }
+    Ok(id)
+}
+
+/// Starts the workers and the scheduler, and queues again the jobs left over by the
+/// previous run.
+/// This function should be called once at application startup, after the migrations.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - Success, or the error of an invalid schedule or of the
+///   database
+pub async fn init_jobs(config: &JobsConfig) -> Result<(), String> {
+    let cleanup = config
+        .cleanup_schedule
+        .parse::<Schedule>()
+        .map_err(|e| format!("JOB_CLEANUP_SCHEDULE: {}", e))?;
+    let recommendations = config
+        .recommendations_schedule
+        .parse::<Schedule>()
+        .map_err(|e| format!("RECOMMENDATIONS_SCHEDULE: {}", e))?;
+    let ledger_check = config
+        .ledger_check_schedule
+        .parse::<Schedule>()
+        .map_err(|e| format!("LEDGER_CHECK_SCHEDULE: {}", e))?;
+    let mut schedules = vec![
+        (cleanup, Job::Cleanup),
+        (recommendations, Job::RefreshRecommendations),
+        (ledger_check, Job::CheckLedger),
+    ];
+    schedules.extend(init_retention(config.retention_path.as_deref())?);
+    schedules
+        .push(init_partitions(config.partition_archive_months, config.partition_export).await?);
+
+    let (sender, receiver) = mpsc::channel(config.queue_capacity);
+    let receiver = Arc::new(AsyncMutex::new(receiver));
+    let mut workers = JoinSet::new();
+    for _ in 0..config.workers {
+        workers.spawn(work(receiver.clone()));
+    }
+
+    // Before the queue is set, so no job can be queued twice
+    let pending = PgJobRepo::default()
+        .queued()
+        .await
+        .map_err(|e| format!("Error reading the queued jobs: {}", e))?;
+    let weak = sender.downgrade();
+
+    let queue = Queue {
+        sender: Mutex::new(Some(sender)),
+        workers: AsyncMutex::new(workers),
+        scheduler: Mutex::new(None),
+        retention_days: config.retention_days,
+    };
+    if QUEUE.set(queue).is_err() {
+        warn!("Attempt to restart the job queue ignored");
+        return Ok(());
+    }
+
+    if !pending.is_empty() {
+        info!("{} queued jobs resumed", pending.len());
+    }
+    for (id, wait) in pending {
+        requeue_after(weak.clone(), id, wait);
+    }
+
+    let scheduler = tokio::spawn(run_scheduler(schedules));
+    if let Some(queue) = QUEUE.get() {
+        *queue.scheduler.lock().unwrap_or_else(|e| e.into_inner()) = Some(scheduler);
+    }
+    info!("Job queue started ({} workers)", config.workers);
+    Ok(())
+}
+
+/// Waits for the scheduler to stop, releasing its lock to another instance.
+/// This function should be called once the shutdown has started, before
+/// [`drain_jobs`]: the scheduler enqueues jobs for the workers.
+pub async fn stop_scheduler() {
+    let Some(scheduler) = QUEUE.get().and_then(|queue| {
+        queue
+            .scheduler
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+    }) else {
+        return;
+    };
+    // The scheduler returns on `shutdown::stopping`
+    if let Err(e) = scheduler.await {
+        warn!("Job scheduler failed: {}", e);
+    }
+}
+
+/// Stops taking jobs and waits for the workers to finish the queued ones.
+/// This function should be called once, when the server and the scheduler have
+/// stopped.
+///
+/// The caller bounds the wait (`JOB_DRAIN_TIMEOUT`); jobs still queued then are run at
+/// the next start.
+pub async fn drain_jobs() {
+    let Some(queue) = QUEUE.get() else {
+        return;
+    };
+    // Workers stop once the channel is empty and closed
+    queue
+        .sender
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take();
+
+    let mut workers = queue.workers.lock().await;
+    while workers.join_next().await.is_some() {}
+}
+
+/// Runs the jobs received on the shared channel until it's closed.
+async fn work(receiver: Arc<AsyncMutex<mpsc::Receiver<i64>>>) {
+    loop {
+        // Released before running the job, so other workers keep receiving
+        let Some(id) = receiver.lock().await.recv().await else {
+            return;
+        };
+        // The queries of a job share a connection, as those of a request
+        if let Err(e) = request_scope(run(id)).await {
+            warn!("Job {} could not be updated: {}", id, e);
+        }
+    }
+}
+
+/// Claims a job, runs it and records the outcome.
+async fn run(id: i64) -> Result<(), AppError> {
+    // Already taken by another instance, or finished
+    let Some(claimed) = PgJobRepo::default().claim(id).await? else {
+        return Ok(());
+    };
+    let job = match serde_json::from_str::<Job>(&claimed.payload) {
+        Ok(job) => job,
+        Err(e) => {
+            let error = format!("Invalid payload: {}", e);
+            return PgJobRepo::default().fail(id, &error, None).await;
+        }
+    };
+
+    let context = RequestContext {
+        request_id: claimed.request_id.map(RequestId),
+        user: claimed.actor_id.map(|id| AuthUser { id }),
+        ..RequestContext::default()
+    };
+    let span = context.span();
+    let start = Instant::now();
+    let outcome = AssertUnwindSafe(context.scope(job.run(id)).instrument(span))
+        .catch_unwind()
+        .await
+        .unwrap_or_else(|_| Err(AppError::Internal("Job panicked".to_string())));
+    let elapsed = start.elapsed();
+    match outcome {
+        Ok(()) => {
+            metrics::observe_job(job.kind(), "succeeded");
+            debug!("Job {} ({}) succeeded in {:?}", id, job.kind(), elapsed);
+            PgJobRepo::default().complete(id).await
+        }
+        Err(e) if claimed.attempts < MAX_ATTEMPTS => {
+            let delay = RETRY_BASE_DELAY * 4u32.pow(claimed.attempts as u32 - 1);
+            metrics::observe_job(job.kind(), "retried");
+            warn!(
+                "Job {} ({}) failed, retrying in {:?}: {}",
+                id,
+                job.kind(),
+                delay,
+                e
+            );
+            PgJobRepo::default()
+                .fail(id, &e.to_string(), Some(delay))
+                .await?;
+            if let Some(sender) = QUEUE.get().and_then(Queue::sender) {
+                requeue_after(sender.downgrade(), id, delay);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            metrics::observe_job(job.kind(), "failed");
+            warn!(
+                "Job {} ({}) failed after {} attempts: {}",
+                id,
+                job.kind(),
+                claimed.attempts,
+                e
+            );
+            PgJobRepo::default().fail(id, &e.to_string(), None).await
+        }
+    }
+}
+
+/// Queues a job again after a delay, unless the queue is draining by then.
+fn requeue_after(sender: mpsc::WeakSender<i64>, id: i64, delay: Duration) {
+    // A weak sender doesn't keep the channel open, so it doesn't delay the drain
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        if let Some(sender) = sender.upgrade() {
+            let _ = sender.send(id).await;
+        }
+    });
+}
@@ -0,0 +1,270 @@
+//! Periodic jobs.
+//!
+//! Schedules are cron expressions evaluated in UTC, with five fields:
+//! `minute hour day-of-month month day-of-week`. Each field is `*`, a value, a range
+//! (`1-5`), a step (`*/15`, `0-30/10`) or a list of those (`0,30`). Days of the week
+//! go from 0 (Sunday) to 6, 7 is Sunday too. As in cron, when both day fields are
+//! restricted, a day matching either one runs.
+//!
+//! Only one instance runs the schedules: the scheduler holds the `job_scheduler`
+//! advisory lock (see `DistributedLock`) and the other replicas wait for it, taking
+//! over if the holder stops.
+
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures_util::future::join_all;
+use tracing::{info, warn};
+
+use super::{Job, enqueue};
+use crate::db::DistributedLock;
+use crate::shutdown::stopping;
+
+/// Name of the lock held by the instance running the schedules
+const SCHEDULER_LOCK: &str = "job_scheduler";
+
+/// Delay before trying again to take the lock after a connection error
+const LOCK_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Days searched for the next run; every valid date occurs within 8 years (Feb 29)
+const MAX_SEARCH_DAYS: u64 = 8 * 366;
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+/// A parsed cron expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    // One bit per allowed value of each field
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day fields are `*`, which changes how they combine
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, String> {
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "'{}' must have 5 fields (minute hour day month weekday)",
+                expression
+            ));
+        };
+
+        let mut weekdays = parse_field(weekday, 0, 7)?;
+        // 7 is another name of Sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        let schedule = Schedule {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days: parse_field(day, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        };
+        if schedule.next_after(UNIX_EPOCH).is_none() {
+            return Err(format!("'{}' never matches a date", expression));
+        }
+        Ok(schedule)
+    }
+}
+
+impl Schedule {
+    /// The first minute matching the schedule strictly after `after`, `None` if the
+    /// schedule never matches (`0 0 30 2 *`).
+    pub fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        let start = after
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / 60
+            + 1;
+        let mut minute = start;
+        while minute - start < MAX_SEARCH_DAYS * MINUTES_PER_DAY {
+            let day = minute / MINUTES_PER_DAY;
+            if !self.matches_day(day) {
+                minute = (day + 1) * MINUTES_PER_DAY;
+                continue;
+            }
+            if !bit(self.hours, minute % MINUTES_PER_DAY / 60) {
+                minute = (minute / 60 + 1) * 60;
+                continue;
+            }
+            if !bit(self.minutes, minute % 60) {
+                minute += 1;
+                continue;
+            }
+            return Some(UNIX_EPOCH + Duration::from_secs(minute * 60));
+        }
+        None
+    }
+
+    /// Whether a day (counted from the Unix epoch) matches the day and month fields.
+    fn matches_day(&self, day: u64) -> bool {
+        let (month, day_of_month) = month_and_day(day);
+        // 1970-01-01 was a Thursday
+        let weekday = (day + 4) % 7;
+        let day_matches = bit(self.days, day_of_month);
+        let weekday_matches = bit(self.weekdays, weekday);
+
+        bit(self.months, month)
+            && match (self.any_day, self.any_weekday) {
+                (false, false) => day_matches || weekday_matches,
+                _ => day_matches && weekday_matches,
+            }
+    }
+}
+
+fn bit(mask: u64, value: u64) -> bool {
+    mask & (1 << value) != 0
+}
+
+/// Parses one field into the mask of its allowed values.
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let invalid = || format!("invalid cron field '{}' (values {}-{})", field, min, max);
+    let number = |value: &str| value.parse::<u64>().map_err(|_| invalid());
+
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, number(step)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (number(start)?, number(end)?),
+            // `5/10` is every 10 from 5
+            None if part.contains('/') => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+/// Month (1-12) and day of the month (1-31) of a day counted from the Unix epoch.
+///
+/// See <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn month_and_day(day: u64) -> (u64, u64) {
+    // Days since 0000-03-01, so that leap days end the year
+    let z = day + 719_468;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    (month, day_of_month)
+}
+
+/// Enqueues each job at the times of its schedule, until the shutdown starts.
+///
+/// Waits for the scheduler lock first, so only one instance enqueues them.
+pub async fn run_scheduler(schedules: Vec<(Schedule, Job)>) {
+    let lock = async {
+        loop {
+            match DistributedLock::acquire(SCHEDULER_LOCK).await {
+                Ok(lock) => return lock,
+                Err(e) => {
+                    warn!("Job scheduler lock unavailable: {}", e);
+                    tokio::time::sleep(LOCK_RETRY_DELAY).await;
+                }
+            }
+        }
+    };
+    let _lock = tokio::select! {
+        lock = lock => lock,
+        _ = stopping() => return,
+    };
+
+    info!("Job scheduler started");
+    let runs = schedules
+        .into_iter()
+        .map(|(schedule, job)| run_schedule(schedule, job));
+    tokio::select! {
+        _ = join_all(runs) => {}
+        _ = stopping() => {}
+    }
+}
+
+async fn run_schedule(schedule: Schedule, job: Job) {
+    while let Some(next) = schedule.next_after(SystemTime::now()) {
+        let wait = next
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO);
+        tokio::time::sleep(wait).await;
+        enqueue(job.clone()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2025-03-14 15:09:26 UTC, a Friday
+    const NOW: u64 = 1_741_964_966;
+
+    fn next(expression: &str, after: u64) -> u64 {
+        let schedule = expression.parse::<Schedule>().unwrap();
+        let next = schedule
+            .next_after(UNIX_EPOCH + Duration::from_secs(after))
+            .unwrap();
+        next.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn finds_the_next_matching_minute() {
+        // Next minute, then 15:15, tomorrow 03:00 and the next Monday 09:30
+        assert_eq!(next("* * * * *", NOW), 1_741_965_000);
+        assert_eq!(next("*/15 * * * *", NOW), 1_741_965_300);
+        assert_eq!(next("0 3 * * *", NOW), 1_742_007_600);
+        assert_eq!(next("30 9 * * 1", NOW), 1_742_203_800);
+        // A run time is never returned again
+        assert_eq!(next("0 3 * * *", 1_742_007_600), 1_742_094_000);
+    }
+
+    #[test]
+    fn restricted_day_fields_match_either() {
+        // The 1st of April, or the next Sunday (March 16th) if sooner
+        assert_eq!(next("0 0 1 * 0", NOW), 1_742_083_200);
+        assert_eq!(next("0 0 1 4 *", NOW), 1_743_465_600);
+        // Leap day
+        assert_eq!(next("0 0 29 2 *", NOW), 1_835_395_200);
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expression in [
+            "",
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+            "0 0 30 2 *",
+        ] {
+            assert!(expression.parse::<Schedule>().is_err(), "{}", expression);
+        }
+    }
+}
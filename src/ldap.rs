@@ -0,0 +1,359 @@
+//! Logins checked by a directory, LDAP or Active Directory (`LDAP_URL`), for the
+//! deployments whose users already have a corporate account.
+//!
+//! ## Login
+//! What the user typed as email in the login (`POST /api/v1/auth/login`, the login
+//! form of `oidc`) is their username: their entry is searched under `LDAP_BASE_DN`
+//! with `LDAP_USER_FILTER`, by the account of `LDAP_BIND_DN` or anonymously, and the
+//! password is checked by binding as that entry. A login matching no entry is checked
+//! against the passwords of the local accounts instead, the administrators created by
+//! `create-admin` for instance; a login matching an entry never is.
+//!
+//! ## Accounts
+//! The first login of a user creates their account, without a local password, from
+//! the attributes of their entry:
+//!
+//! | Profile | Attributes (default) |
+//! |---|---|
+//! | `name` | `LDAP_NAME_ATTRIBUTES` (`displayName`, else `cn`), else the email |
+//! | `email` | `LDAP_EMAIL_ATTRIBUTES` (`mail`), required |
+//! | `age` | `LDAP_AGE_ATTRIBUTE` (none), else 0 |
+//!
+//! The next logins find the account by its email; the profile is then the one of the
+//! app, edited there. An account deactivated through SCIM (see `scim`) can't log in.
+
+use std::collections::HashMap;
+use std::slice;
+use std::sync::OnceLock;
+
+use bb8_postgres::tokio_postgres::error::SqlState;
+use ldap3::{Ldap, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry, ldap_escape};
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::config::LdapConfig;
+use crate::context::within_deadline;
+use crate::db::Db;
+use crate::error::AppError;
+use crate::events::{self, Action, Collection};
+use crate::repository::users::{NewAccount, PgUserRepo, UserRepository};
+use crate::validation::{MAX_NAME_LEN, ValidationErrors, check_age, check_email};
+
+/// Result code of a bind with a wrong password (RFC 4511)
+const INVALID_CREDENTIALS: u32 = 49;
+
+// Set once at startup, `None` without LDAP_URL
+static LDAP: OnceLock<Option<LdapConfig>> = OnceLock::new();
+
+/// Enables the directory of `LDAP_URL`.
+/// This function should be called once at application startup.
+pub fn init_ldap(config: Option<&LdapConfig>) {
+    if let Some(config) = config {
+        info!("Logins checked by the directory at {}", config.url);
+    }
+    if LDAP.set(config.cloned()).is_err() {
+        warn!("Attempt to reconfigure the directory ignored");
+    }
+}
+
+/// The profile of a user, from the attributes of their entry.
+#[derive(Debug, PartialEq)]
+struct DirectoryUser {
+    name: String,
+    age: i32,
+    email: String,
+}
+
+/// Same error as a wrong local password, so the users can't be enumerated.
+fn invalid() -> AppError {
+    AppError::Unauthorized("Invalid email or password".to_string())
+}
+
+fn unreachable(e: LdapError) -> AppError {
+    warn!("Directory error: {}", e);
+    AppError::BadGateway("The directory could not be reached".to_string())
+}
+
+/// The first value of the first of `names` the entry has, whatever their case.
+fn attribute<'a>(attrs: &'a HashMap<String, Vec<String>>, names: &[String]) -> Option<&'a str> {
+    names.iter().find_map(|name| {
+        attrs
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .and_then(|(_, values)| values.first())
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+    })
+}
+
+/// Maps the attributes of an entry to a profile.
+///
+/// # Returns
+///
+/// * `Result<DirectoryUser, String>` - The profile, or why the entry has none
+fn profile(
+    config: &LdapConfig,
+    attrs: &HashMap<String, Vec<String>>,
+) -> Result<DirectoryUser, String> {
+    let email = attribute(attrs, &config.email_attributes)
+        .ok_or_else(|| format!("no {}", config.email_attributes.join(" or ")))?
+        .to_lowercase();
+    let mut errors = ValidationErrors::default();
+    check_email(&mut errors, "email", &email);
+    if errors.into_result().is_err() {
+        return Err(format!("'{}' is not an email", email));
+    }
+    let name = attribute(attrs, &config.name_attributes).unwrap_or(&email);
+    // Longer names are cut rather than keeping the user out
+    let name = name.chars().take(MAX_NAME_LEN).collect();
+    let age = config
+        .age_attribute
+        .as_ref()
+        .and_then(|age| attribute(attrs, slice::from_ref(age)))
+        .and_then(|age| age.parse().ok())
+        .filter(|&age| {
+            let mut errors = ValidationErrors::default();
+            check_age(&mut errors, "age", age);
+            errors.into_result().is_ok()
+        })
+        .unwrap_or(0);
+    Ok(DirectoryUser { name, age, email })
+}
+
+/// `LDAP_USER_FILTER` for `username`, escaped so it can't change the filter.
+fn user_filter(config: &LdapConfig, username: &str) -> String {
+    config
+        .user_filter
+        .replace("{username}", &ldap_escape(username))
+}
+
+/// Connects to the directory, bound as the account of `LDAP_BIND_DN` if there's one.
+async fn connect(config: &LdapConfig) -> Result<Ldap, LdapError> {
+    let settings = LdapConnSettings::new()
+        .set_conn_timeout(config.timeout)
+        .set_starttls(config.starttls);
+    let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &config.url).await?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.drive().await {
+            warn!("Directory connection error: {}", e);
+        }
+    });
+    if let Some((dn, password)) = &config.bind {
+        ldap.simple_bind(dn, password).await?.success()?;
+    }
+    Ok(ldap)
+}
+
+/// Searches the entry of `username` and binds as it with `password`.
+///
+/// # Returns
+///
+/// * `Result<Option<SearchEntry>, AppError>` - The entry, `None` if no entry matches,
+///   or `AppError::Unauthorized` if the password is wrong
+async fn bind_user(
+    config: &LdapConfig,
+    username: &str,
+    password: &str,
+) -> Result<Option<SearchEntry>, AppError> {
+    let mut ldap = connect(config).await.map_err(unreachable)?;
+    let attributes = config
+        .name_attributes
+        .iter()
+        .chain(&config.email_attributes)
+        .chain(&config.age_attribute)
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let (entries, _) = ldap
+        .search(
+            &config.base_dn,
+            Scope::Subtree,
+            &user_filter(config, username),
+            attributes,
+        )
+        .await
+        .and_then(|result| result.success())
+        .map_err(unreachable)?;
+    // Active Directory adds references to the other partitions
+    let mut entries = entries
+        .into_iter()
+        .filter(|entry| !entry.is_ref() && !entry.is_intermediate())
+        .map(SearchEntry::construct);
+    let Some(entry) = entries.next() else {
+        return Ok(None);
+    };
+    if entries.next().is_some() {
+        warn!("Several directory entries match the login '{}'", username);
+        return Err(invalid());
+    }
+
+    let result = ldap
+        .simple_bind(&entry.dn, password)
+        .await
+        .and_then(|result| result.success());
+    let _ = ldap.unbind().await;
+    match result {
+        Ok(_) => Ok(Some(entry)),
+        Err(LdapError::LdapResult { result }) if result.rc == INVALID_CREDENTIALS => Err(invalid()),
+        Err(e) => Err(unreachable(e)),
+    }
+}
+
+/// The key of the account of `user`, created on their first login.
+async fn account(db: Db, user: DirectoryUser) -> Result<i32, AppError> {
+    if let Some(credentials) = PgUserRepo(db).find_credentials(&user.email).await? {
+        return Ok(credentials.id);
+    }
+    let account = NewAccount {
+        name: user.name,
+        age: user.age,
+        email: user.email,
+        password_hash: None,
+    };
+    match PgUserRepo(db).create_account(&account).await {
+        Ok((id, public_id)) => {
+            info!("Account of {} created from the directory", account.email);
+            events::publish(Collection::Users, Action::Created, id, public_id);
+            Ok(id)
+        }
+        // Deactivated, soft-deleted, or created by a concurrent login
+        Err(AppError::Db(e)) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => PgUserRepo(db)
+            .find_credentials(&account.email)
+            .await?
+            .map(|credentials| credentials.id)
+            .ok_or_else(invalid),
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks a login against the directory, creating the account of the user on their
+/// first login.
+///
+/// # Arguments
+///
+/// * `db` - The database of the login, whose deadline the directory is waited for
+/// * `username` - What the user typed as email, trimmed and lowercased
+/// * `password` - Their password
+///
+/// # Returns
+///
+/// * `Result<Option<i32>, AppError>` - The key of the user, `None` without `LDAP_URL`
+///   or if the directory has no entry for them, `AppError::Unauthorized` if the
+///   password is wrong or the account deactivated, or `AppError::BadGateway` if the
+///   directory can't be reached
+pub async fn authenticate(db: Db, username: &str, password: &str) -> Result<Option<i32>, AppError> {
+    let Some(Some(config)) = LDAP.get() else {
+        return Ok(None);
+    };
+    // Binding without a password is an anonymous bind, which the directories accept
+    if password.is_empty() {
+        return Err(invalid());
+    }
+    let entry = timeout(
+        within_deadline(db, config.timeout),
+        bind_user(config, username, password),
+    )
+    .await
+    .map_err(|_| AppError::BadGateway("The directory didn't answer in time".to_string()))??;
+    let Some(entry) = entry else {
+        return Ok(None);
+    };
+    let user = profile(config, &entry.attrs).map_err(|e| {
+        warn!("Directory entry {} can't log in: {}", entry.dn, e);
+        invalid()
+    })?;
+    account(db, user).await.map(Some)
+}
+
+/// Connects to the directory and reads `LDAP_BASE_DN`, for `selftest`.
+///
+/// # Returns
+///
+/// * `Result<String, String>` - What was checked, or the error
+pub async fn probe(config: &LdapConfig) -> Result<String, String> {
+    let read = async {
+        let mut ldap = connect(config).await?;
+        ldap.search(&config.base_dn, Scope::Base, "(objectClass=*)", vec!["1.1"])
+            .await?
+            .success()?;
+        ldap.unbind().await
+    };
+    match timeout(config.timeout, read).await {
+        Ok(Ok(())) => Ok(format!("{} bound, {} read", config.url, config.base_dn)),
+        Ok(Err(e)) => Err(format!("{}: {}", config.url, e)),
+        Err(_) => Err(format!(
+            "{} didn't answer within {} s",
+            config.url,
+            config.timeout.as_secs()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config() -> LdapConfig {
+        LdapConfig {
+            url: "ldap://localhost".to_string(),
+            starttls: false,
+            bind: None,
+            base_dn: "dc=example,dc=com".to_string(),
+            user_filter: "(|(uid={username})(mail={username}))".to_string(),
+            name_attributes: vec!["displayName".to_string(), "cn".to_string()],
+            email_attributes: vec!["mail".to_string()],
+            age_attribute: Some("employeeAge".to_string()),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    fn attrs(pairs: &[(&str, &str)]) -> HashMap<String, Vec<String>> {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.to_string(), vec![value.to_string()]))
+            .collect()
+    }
+
+    #[test]
+    fn entries_are_mapped_to_profiles() {
+        let user = profile(
+            &config(),
+            &attrs(&[
+                ("cn", "ann"),
+                ("MAIL", " Ann@Example.com"),
+                ("employeeAge", "41"),
+            ]),
+        );
+        assert_eq!(
+            user,
+            Ok(DirectoryUser {
+                name: "ann".to_string(),
+                age: 41,
+                email: "ann@example.com".to_string(),
+            })
+        );
+
+        let user = profile(
+            &config(),
+            &attrs(&[
+                ("displayName", "Ann Lee"),
+                ("cn", "ann"),
+                ("mail", "ann@example.com"),
+                ("employeeAge", "-1"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!((user.name.as_str(), user.age), ("Ann Lee", 0));
+
+        assert!(profile(&config(), &attrs(&[("cn", "ann")])).is_err());
+        assert!(profile(&config(), &attrs(&[("mail", "ann")])).is_err());
+    }
+
+    #[test]
+    fn usernames_are_escaped_in_the_filter() {
+        assert_eq!(
+            user_filter(&config(), "*)(uid=admin"),
+            "(|(uid=\\2a\\29\\28uid=admin)(mail=\\2a\\29\\28uid=admin))"
+        );
+    }
+}
@@ -0,0 +1,92 @@
+//! Double-entry ledger of the credits of the users.
+//!
+//! ## Accounts and entries
+//! Every movement of money is an entry of two postings or more, to the accounts it
+//! moves money between, summing to zero: debits are positive, credits negative, in
+//! cents. The credit of a user is a liability account (`credit:<key>`), what the shop
+//! owes them, growing with credits. The credits granted with `POST /users/:id/credits`
+//! are debited to the `promotions` expense account, and taken back the other way; the
+//! orders refunded with `POST /users/:id/orders/:order_id/refund` are debited to
+//! `refunds`. A credit account can't go below zero, and an order is refunded once.
+//!
+//! ## Invariants
+//! Entries are checked before they're written (see `NewEntry::check`), then by the
+//! database: a deferred trigger refuses at commit an entry whose postings don't sum to
+//! zero, a constraint keeps the credit accounts from going below zero, and the entries
+//! and postings can't be updated nor deleted, a mistake is undone by another entry.
+//! The balance of each account is kept in `ledger_accounts` in the transaction of each
+//! entry, the accounts locked in the order of their IDs, so that concurrent entries
+//! can't lose an update nor deadlock.
+//!
+//! ## Consistency check
+//! The `check_ledger` job (`LEDGER_CHECK_SCHEDULE`, nightly by default) and
+//! `GET /admin/ledger/check` recompute every balance from the postings, and look for
+//! unbalanced entries and a trial balance other than zero. The job reports in its
+//! `progress` and fails on a discrepancy, so that it's listed as failed in
+//! `GET /admin/jobs`.
+
+use serde_json::json;
+use tracing::{error, info};
+
+use crate::db::Db;
+use crate::error::AppError;
+use crate::repository::jobs::{JobRepository, PgJobRepo};
+use crate::repository::ledger::{Entry, LedgerRepository, NewEntry, PgLedgerRepo, Report};
+
+/// Grants credit to a user (`amount` > 0, in cents), or takes it back (`amount` < 0).
+///
+/// Fails with `AppError::Conflict` if the user has less credit than taken back.
+pub async fn grant_credit(
+    db: Db,
+    user_id: i32,
+    amount: i64,
+    description: String,
+) -> Result<Entry, AppError> {
+    let entry = PgLedgerRepo(db)
+        .post(&NewEntry::credit(user_id, amount, description))
+        .await?;
+    info!(
+        "Credit of user {} changed by {} cents ({} left)",
+        user_id, entry.amount, entry.balance
+    );
+    Ok(entry)
+}
+
+/// Refunds an order (by key) of a user to their credit.
+pub async fn refund_order(db: Db, user_id: i32, order_id: i32) -> Result<Entry, AppError> {
+    let entry = PgLedgerRepo(db).refund(user_id, order_id).await?;
+    info!(
+        "Order {} refunded to user {} ({} cents)",
+        order_id, user_id, entry.amount
+    );
+    Ok(entry)
+}
+
+/// Checks the consistency of the ledger (`GET /admin/ledger/check`).
+pub async fn report(db: Db) -> Result<Report, AppError> {
+    PgLedgerRepo(db).check().await
+}
+
+/// Checks the consistency of the ledger (job `id`), failing on a discrepancy.
+pub async fn check(id: i64) -> Result<(), AppError> {
+    let report = PgLedgerRepo::default().check().await?;
+    PgJobRepo::default()
+        .set_progress(id, &json!(report))
+        .await?;
+    if !report.consistent {
+        error!(
+            unbalanced_entries = report.unbalanced_entries.len(),
+            drifted_accounts = report.drifted_accounts.len(),
+            trial_balance = report.trial_balance,
+            "The ledger is inconsistent"
+        );
+        return Err(AppError::Internal(
+            "The ledger is inconsistent, see the progress of the job".to_string(),
+        ));
+    }
+    info!(
+        "Ledger checked: {} accounts and {} entries are consistent",
+        report.accounts, report.entries
+    );
+    Ok(())
+}
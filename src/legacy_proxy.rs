@@ -0,0 +1,339 @@
+//! Reverse proxy to a legacy service, while its endpoints are migrated here.
+//!
+//! With `LEGACY_UPSTREAM_URL`, every request whose path is under `LEGACY_PATH_PREFIX`
+//! (`/legacy` by default) is forwarded to the legacy service with the outbound HTTP
+//! client, the prefix replaced by the path of the URL:
+//!
+//! ```text
+//! LEGACY_UPSTREAM_URL=http://old-api:8080/v2
+//! GET /legacy/orders?page=2  ->  GET http://old-api:8080/v2/orders?page=2
+//! ```
+//!
+//! Bodies are streamed both ways, neither is buffered. The hop-by-hop headers
+//! (`Connection`, `Transfer-Encoding`, ...) are dropped in both directions, the
+//! request gets `X-Forwarded-For`, `X-Forwarded-Host`, `X-Forwarded-Proto`,
+//! `X-Forwarded-Prefix` and the
+//! `X-Request-Id` of the request, and a `Location` pointing into the legacy service is
+//! rewritten to go through the proxy.
+//!
+//! Proxied requests go through the rate limiter and the geo policies, but not through
+//! the authentication of the routes: the legacy service checks its own credentials.
+//! The legacy service has `REQUEST_TIMEOUT` to send its response headers, it is then
+//! answered with 504 (502 when it can't be reached).
+
+use std::sync::OnceLock;
+
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use hyper::header::{
+    CONNECTION, HOST, HeaderMap, HeaderName, HeaderValue, LOCATION, PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION, TE, TRAILER, TRANSFER_ENCODING, UPGRADE,
+};
+use hyper::{Request, Response, Uri, Version};
+use tracing::{info, warn};
+
+use crate::config::LegacyProxyConfig;
+use crate::error::AppError;
+use crate::forwarded::ClientInfo;
+use crate::http_client::http_client;
+use crate::logging::{REQUEST_ID_HEADER, RequestId};
+use crate::router::limits::request_timeout;
+use crate::router::{Body, BoxError, ClientAddr, MatchedRoute, error_response};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_PREFIX: &str = "x-forwarded-prefix";
+
+/// Headers describing a single connection, never forwarded
+const HOP_BY_HOP: [HeaderName; 8] = [
+    CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+// Set once at startup, unset without LEGACY_UPSTREAM_URL
+static LEGACY_PROXY: OnceLock<LegacyProxy> = OnceLock::new();
+
+struct LegacyProxy {
+    upstream: Uri,
+    /// Path prefix of the proxied requests, without trailing slash
+    prefix: String,
+    /// `MatchedRoute` of the proxied responses, the label of their metrics
+    route: String,
+}
+
+/// Enables the proxy to the legacy service.
+/// This function should be called once at application startup.
+///
+/// # Arguments
+///
+/// * `config` - Proxy settings, nothing is done without `LEGACY_UPSTREAM_URL`
+pub fn init_legacy_proxy(config: Option<&LegacyProxyConfig>) {
+    let Some(config) = config else {
+        return;
+    };
+    info!("{}/* is forwarded to {}", config.prefix, config.upstream);
+    if LEGACY_PROXY
+        .set(LegacyProxy {
+            upstream: config.upstream.clone(),
+            prefix: config.prefix.clone(),
+            route: format!("{}/*", config.prefix),
+        })
+        .is_err()
+    {
+        warn!("Attempt to reset the legacy proxy ignored");
+    }
+}
+
+/// Whether a request is under the prefix of the legacy service.
+pub fn is_proxied<B>(req: &Request<B>) -> bool {
+    LEGACY_PROXY
+        .get()
+        .is_some_and(|proxy| proxy.rest_of(req.uri().path()).is_some())
+}
+
+/// Forwards a request to the legacy service and streams back its response.
+///
+/// Must only be called when [`is_proxied`] is true.
+///
+/// # Returns
+///
+/// * `Response<Body>` - The response of the legacy service, or a 502 or 504 error
+pub async fn forward(req: Request<Incoming>) -> Response<Body> {
+    let Some(proxy) = LEGACY_PROXY.get() else {
+        return error_response(AppError::Internal("Legacy proxy not enabled".to_string()));
+    };
+    let mut res = proxy.forward(req).await.unwrap_or_else(error_response);
+    res.extensions_mut()
+        .insert(MatchedRoute(proxy.route.clone()));
+    res
+}
+
+impl LegacyProxy {
+    async fn forward(&self, req: Request<Incoming>) -> Result<Response<Body>, AppError> {
+        let (mut parts, body) = req.into_parts();
+        parts.uri = self
+            .upstream_uri(&parts.uri)
+            .ok_or_else(|| AppError::Validation("Invalid path".to_string()))?;
+        // The connection to the legacy service has its own version, chosen by ALPN
+        parts.version = Version::HTTP_11;
+
+        let headers = &mut parts.headers;
+        remove_hop_by_hop(headers);
+        // Set from the URI by the client
+        if let Some(host) = headers.remove(HOST) {
+            headers.insert(X_FORWARDED_HOST, host);
+        }
+        if let Some(ClientAddr(addr)) = parts.extensions.get::<ClientAddr>() {
+            let forwarded_for = match headers.get(X_FORWARDED_FOR) {
+                Some(previous) => {
+                    format!("{}, {}", previous.to_str().unwrap_or_default(), addr.ip())
+                }
+                None => addr.ip().to_string(),
+            };
+            if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+                headers.insert(X_FORWARDED_FOR, value);
+            }
+        }
+        if let Some(client) = parts.extensions.get::<ClientInfo>() {
+            headers.insert(
+                X_FORWARDED_PROTO,
+                HeaderValue::from_static(client.scheme.as_str()),
+            );
+        }
+        if let Ok(value) = HeaderValue::from_str(&self.prefix) {
+            headers.insert(X_FORWARDED_PREFIX, value);
+        }
+        if let Some(value) = RequestId::current().and_then(|id| id.header_value()) {
+            headers.insert(REQUEST_ID_HEADER, value);
+        }
+
+        let body = body.map_err(BoxError::from).boxed_unsync();
+        let timeout = request_timeout();
+        let res = match tokio::time::timeout(
+            timeout,
+            http_client().request(Request::from_parts(parts, body)),
+        )
+        .await
+        {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => {
+                return Err(AppError::BadGateway(format!(
+                    "Legacy service unreachable: {}",
+                    e
+                )));
+            }
+            Err(_) => {
+                return Err(AppError::Timeout(format!(
+                    "The legacy service took longer than {} seconds",
+                    timeout.as_secs()
+                )));
+            }
+        };
+
+        let (mut parts, body) = res.into_parts();
+        // Answered in the version of the client connection
+        parts.version = Version::HTTP_11;
+        remove_hop_by_hop(&mut parts.headers);
+        if let Some(location) = parts
+            .headers
+            .get(LOCATION)
+            .and_then(|value| value.to_str().ok())
+            && let Some(location) = self.rewrite_location(location)
+            && let Ok(value) = HeaderValue::from_str(&location)
+        {
+            parts.headers.insert(LOCATION, value);
+        }
+        let body = Body::Stream(body.map_err(BoxError::from).boxed_unsync());
+        Ok(Response::from_parts(parts, body))
+    }
+
+    /// The path after the prefix (`/orders` for `/legacy/orders`, empty for
+    /// `/legacy`), `None` if the path isn't under the prefix.
+    fn rest_of<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(&self.prefix)?;
+        (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+    }
+
+    /// The URI of a proxied request in the legacy service, with its query string.
+    fn upstream_uri(&self, uri: &Uri) -> Option<Uri> {
+        let rest = self.rest_of(uri.path())?;
+        let base = self.upstream.path().trim_end_matches('/');
+        let path = match (base, rest) {
+            ("", "") => "/",
+            _ => rest,
+        };
+        let path_and_query = match uri.query() {
+            Some(query) => format!("{}{}?{}", base, path, query),
+            None => format!("{}{}", base, path),
+        };
+        let mut parts = self.upstream.clone().into_parts();
+        parts.path_and_query = Some(path_and_query.parse().ok()?);
+        Uri::from_parts(parts).ok()
+    }
+
+    /// The `Location` of a response of the legacy service as seen through the proxy,
+    /// `None` when it points elsewhere.
+    fn rewrite_location(&self, location: &str) -> Option<String> {
+        let base = self.upstream.path().trim_end_matches('/');
+        // Absolute URLs of the legacy service become paths of the proxy
+        let path = match location.parse::<Uri>() {
+            Ok(uri) if uri.authority().is_some() => {
+                if uri.scheme() != self.upstream.scheme()
+                    || uri.authority() != self.upstream.authority()
+                {
+                    return None;
+                }
+                uri.path_and_query()
+                    .map_or("/", |path_and_query| path_and_query.as_str())
+                    .to_string()
+            }
+            _ if location.starts_with('/') => location.to_string(),
+            // Relative to the request, already right
+            _ => return None,
+        };
+        let rest = path.strip_prefix(base)?;
+        if !(rest.is_empty() || rest.starts_with('/') || rest.starts_with('?')) {
+            return None;
+        }
+        Some(format!("{}{}", self.prefix, rest))
+    }
+}
+
+/// Removes the headers of a single connection, those named by `Connection` included.
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in named.iter().chain(&HOP_BY_HOP) {
+        headers.remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy(upstream: &str) -> LegacyProxy {
+        LegacyProxy {
+            upstream: upstream.parse().unwrap(),
+            prefix: "/legacy".to_string(),
+            route: "/legacy/*".to_string(),
+        }
+    }
+
+    fn upstream_uri(proxy: &LegacyProxy, uri: &str) -> Option<String> {
+        proxy
+            .upstream_uri(&uri.parse().unwrap())
+            .map(|uri| uri.to_string())
+    }
+
+    #[test]
+    fn requests_under_the_prefix_are_mapped_to_the_upstream() {
+        let root = proxy("http://old-api:8080");
+        assert_eq!(
+            upstream_uri(&root, "/legacy/orders?page=2").as_deref(),
+            Some("http://old-api:8080/orders?page=2")
+        );
+        assert_eq!(
+            upstream_uri(&root, "/legacy").as_deref(),
+            Some("http://old-api:8080/")
+        );
+        assert_eq!(upstream_uri(&root, "/legacyorders"), None);
+        assert_eq!(upstream_uri(&root, "/api/v1/users"), None);
+
+        let nested = proxy("https://old-api.example.com/v2/");
+        assert_eq!(
+            upstream_uri(&nested, "/legacy/orders/7").as_deref(),
+            Some("https://old-api.example.com/v2/orders/7")
+        );
+        assert_eq!(
+            upstream_uri(&nested, "/legacy").as_deref(),
+            Some("https://old-api.example.com/v2")
+        );
+    }
+
+    #[test]
+    fn locations_of_the_upstream_go_through_the_proxy() {
+        let proxy = proxy("http://old-api:8080/v2");
+        assert_eq!(
+            proxy
+                .rewrite_location("http://old-api:8080/v2/orders/7")
+                .as_deref(),
+            Some("/legacy/orders/7")
+        );
+        assert_eq!(
+            proxy.rewrite_location("/v2/login?next=%2F").as_deref(),
+            Some("/legacy/login?next=%2F")
+        );
+        assert_eq!(
+            proxy.rewrite_location("https://example.com/v2/orders"),
+            None
+        );
+        assert_eq!(proxy.rewrite_location("/v20/orders"), None);
+        assert_eq!(proxy.rewrite_location("orders/7"), None);
+    }
+
+    #[test]
+    fn hop_by_hop_headers_are_removed() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, HeaderValue::from_static("keep-alive, x-trace"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("x-trace", HeaderValue::from_static("1"));
+        headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        headers.insert("accept", HeaderValue::from_static("*/*"));
+        remove_hop_by_hop(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key("accept"));
+    }
+}
@@ -0,0 +1,69 @@
+//! The server as a library: the binary (`main.rs`) and the integration tests (`tests/`)
+//! both start it through the `server` module.
+//!
+//! See the binary documentation for the API routes and the operational behavior.
+
+// The OpenAPI schemas are a single `json!` literal, deeper than the default limit
+#![recursion_limit = "512"]
+
+mod archives;
+mod auth;
+mod cache;
+mod carts;
+mod computed;
+pub mod config;
+mod console;
+mod context;
+mod cpu_profile;
+mod dashboard;
+mod db;
+mod embeddings;
+mod error;
+mod error_reporting;
+mod events;
+mod experiments;
+mod export;
+mod forwarded;
+mod geo_policy;
+mod geoip;
+mod http_client;
+mod jobs;
+mod ldap;
+mod ledger;
+mod legacy_proxy;
+mod logging;
+mod memory;
+mod metrics;
+mod offline_queue;
+mod oidc;
+mod partitions;
+mod passkeys;
+mod proxy_protocol;
+mod recommendations;
+mod region;
+mod repository;
+mod retention;
+mod roles;
+mod router;
+mod routes;
+mod scim;
+mod search_engine;
+pub mod server;
+#[cfg(feature = "service")]
+pub mod service;
+mod shutdown;
+mod signed_urls;
+mod static_files;
+mod storage;
+mod suggestions;
+pub mod tasks;
+mod tls;
+mod validation;
+mod webhooks;
+
+pub use db::close_pool;
+pub use error_reporting::flush_error_reports;
+pub use experiments::flush_exposures;
+pub use jobs::{drain_jobs, stop_scheduler};
+pub use logging::init_tracing;
+pub use shutdown::{ShutdownController, begin_shutdown, shutdown_signal};
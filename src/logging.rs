@@ -0,0 +1,119 @@
+//! Logging and request tracing.
+//!
+//! Configures the global `tracing` subscriber and provides the request ID attached
+//! to every request by the tracing middleware in `router::process_request_and_response`.
+//!
+//! ## Configuration
+//! - `LOG_LEVEL`: Filter directive (`info` by default), e.g. `debug` or `rust_backend=debug,info`.
+//!   `RUST_LOG` is used instead when set.
+//! - `LOG_FORMAT`: `plain` (default, human readable) or `json` (one JSON object per line,
+//!   for log aggregators)
+//!
+//! Logs go to the standard output, or to a file for a Windows service (see `service`).
+//!
+//! Built with the `console` feature (and `RUSTFLAGS="--cfg tokio_unstable"`), the tasks
+//! of the runtime can also be inspected live with `tokio-console`, which connects to
+//! `127.0.0.1:6669` (`TOKIO_CONSOLE_BIND` to change it). The log filter doesn't apply
+//! to this instrumentation.
+
+use std::env;
+use std::fs::File;
+use std::sync::Mutex;
+
+use hyper::Request;
+use hyper::header::HeaderValue;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{EnvFilter, Layer};
+use uuid::Uuid;
+
+/// Header used to receive and echo back the request ID
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Maximum length accepted for a request ID sent by the client
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    // ID of the request handled by the current task, see `RequestId::scope`
+    static CURRENT_REQUEST_ID: RequestId;
+}
+
+/// Installs the global tracing subscriber.
+/// This function should be called once, at application startup.
+///
+/// # Arguments
+///
+/// * `file` - File the logs are appended to instead of the standard output
+pub fn init_tracing(file: Option<File>) {
+    let filter = env::var("RUST_LOG")
+        .or_else(|_| env::var("LOG_LEVEL"))
+        .unwrap_or_else(|_| "info".to_string());
+    let filter = EnvFilter::try_new(&filter).unwrap_or_else(|e| {
+        eprintln!("Invalid log filter '{}' ({}), using 'info'", filter, e);
+        EnvFilter::new("info")
+    });
+
+    let logs = match (env::var("LOG_FORMAT").as_deref(), file) {
+        (Ok("json"), None) => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .boxed(),
+        (Ok("json"), Some(file)) => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_writer(Mutex::new(file))
+            .boxed(),
+        (_, None) => tracing_subscriber::fmt::layer().boxed(),
+        // No color codes in a file
+        (_, Some(file)) => tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_writer(Mutex::new(file))
+            .boxed(),
+    };
+    let subscriber = tracing_subscriber::registry().with(logs.with_filter(filter));
+
+    // Spawns the server tokio-console connects to
+    #[cfg(feature = "console")]
+    let subscriber = subscriber.with(console_subscriber::spawn());
+
+    subscriber.init();
+}
+
+/// Unique identifier of a request, stored in the request extensions.
+///
+/// Appears in every log line of the request and is returned to the client in the
+/// `X-App-Request-Id` header so a failing call can be matched with its logs.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Reuses the `X-Request-Id` sent by the client (e.g. set by a proxy) when it is a
+    /// reasonable value, otherwise generates a new random ID.
+    pub fn from_request<B>(req: &Request<B>) -> Self {
+        let incoming = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN);
+
+        match incoming {
+            Some(id) => RequestId(id.to_string()),
+            None => RequestId(Uuid::new_v4().to_string()),
+        }
+    }
+
+    /// Value of the `X-App-Request-Id` response header.
+    pub fn header_value(&self) -> Option<HeaderValue> {
+        HeaderValue::from_str(&self.0).ok()
+    }
+
+    /// Runs `future` with this ID as the current one, so code without access to the
+    /// request (error responses) can still read it with [`RequestId::current`].
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT_REQUEST_ID.scope(self, future).await
+    }
+
+    /// ID of the request handled by the current task, if any.
+    pub fn current() -> Option<RequestId> {
+        CURRENT_REQUEST_ID.try_with(RequestId::clone).ok()
+    }
+}
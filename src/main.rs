@@ -9,56 +9,536 @@
 //! - Concurrent request handling via lightweight tasks (instead of threads)
 //! - Efficient connection management
 //!
+//! Connections speak HTTP/1.1 or HTTP/2: chosen by ALPN on the HTTPS listener,
+//! detected from the first bytes on the plain one (HTTP/2 with prior knowledge).
+//! In development, `--dev-tls` serves HTTPS with a self-signed certificate for
+//! localhost (see the `tls` module).
+//!
 //! Operations are asynchronous, meaning they do not block the main thread while waiting for I/O.
 //! While processing a client request in a spawned task, the main loop can continue
 //! accepting new connections without waiting for previous clients to complete.
 //!
 //! ## API Routes
 //! - `GET /`: Basic greeting message
+//! - `GET /healthz`, `GET /readyz`: Liveness and readiness probes
+//! - `GET /metrics`: Prometheus metrics
+//! - `GET /admin/runtime`: Runtime diagnostics for stuck deployments (authenticated)
+//! - `GET /admin/heap-profile`: Heap profile, with the `jemalloc` feature (authenticated)
+//! - `GET /debug/pprof/profile`: CPU profile, with the `pprof` feature (authenticated)
+//! - `GET /openapi.json`, `GET /docs`: OpenAPI document and its Swagger UI page
+//!
+//! Under `/api/v1` (the unprefixed paths still work, as deprecated aliases):
+//! - `POST /auth/register`, `POST /auth/login`, `GET /auth/me`: Authentication
+//! - `GET /dashboard`: Overview composed of concurrent parts, each allowed to fail
+//! - `GET /experiments`: Running A/B experiments and the variants of the caller
 //! - `GET /users`: Retrieve all users
 //! - `POST /users`: Create a new user
-//! - `GET /users/{id}`: Get a specific user
+//! - `POST /users/bulk`: Create many users at once
+//! - `POST /users/bulk-delete`: Delete the users matching a filter, in the background
+//! - `POST /users/views`, `GET /users/views`: Save and list named filters and sorts of
+//!   the list (`GET /users?view=<name>`)
+//! - `GET /users/{id}`: Get a specific user, or as it was at `?as_of=<timestamp>`
+//! - `PUT /users/{id}`: Replace a user
+//! - `PATCH /users/{id}`: Partially update a user
+//! - `DELETE /users/{id}`: Soft-delete a user, or delete them for good
+//! - `POST /users/{id}/restore`: Undelete a soft-deleted user
+//! - `POST /users/{id}/orders`: Place an order for a user
+//! - `POST /users/{id}/avatar`, `GET /users/{id}/avatar`: Upload and download a user avatar
+//! - `GET /users/{id}/history`: Changes of a user recorded by the audit log
 //! - `GET /products`: Retrieve all products
+//! - `POST /products`: Create a new product
+//! - `POST /products/bulk`: Create many products at once
+//! - `POST /products/views`, `GET /products/views`: Saved views of the list
+//! - `GET /products/{id}`: Get a specific product
+//! - `PUT /products/{id}`: Replace a product
+//! - `DELETE /products/{id}`: Delete a product
+//! - `GET /products/{id}/history`: Changes of a product recorded by the audit log
+//! - `GET /operations/{id}`: Status and progress of a background operation
+//! - `POST /webhooks`, `GET /webhooks`: Register and list the webhooks receiving the changes
+//! - `GET /webhooks/{id}`, `PUT /webhooks/{id}`, `DELETE /webhooks/{id}`: Manage a webhook
+//! - `GET /webhooks/{id}/deliveries`: Delivery attempts of a webhook
+//! - `GET /ws`: WebSocket streaming user and product changes
+//! - `GET /events`: Server-Sent Events stream of the same changes
+//!
+//! With `LEGACY_UPSTREAM_URL`, the requests under `/legacy` are forwarded to the
+//! service being migrated (see the `legacy_proxy` module).
+//!
+//! See the `routes` module for detailed endpoint documentation,
+//! the `router` module for the routing subsystem and the `repository` module
+//! for the data access layer.
+//!
+//! ## Regions
+//! In a multi-region deployment every response names the region serving it, writes go
+//! to the database of the primary region and clients can be redirected to their home
+//! region (see the `region` module).
+//!
+//! ## Command line
+//! The binary serves by default (`serve`). Its other subcommands operate the deployment
+//! with the same settings:
+//! - `migrate [apply|rollback|status]`: apply the pending migrations and exit (the
+//!   contract ones with `--contract`, only listed with `--plan`), undo the latest one,
+//!   or list them
+//! - `create-admin --email <email>`: create an account with the admin role, its
+//!   password read from `ADMIN_PASSWORD` or the standard input
+//! - `routes`: print the route table
+//! - `selftest [--json]`: check the settings, the database, the file storage, the
+//!   files loaded at startup and the upstream services, and exit with a report
+//! - `config print-effective`: print every setting with its value and where it comes
+//!   from, secrets masked
 //!
-//! See the `router` module for detailed endpoint documentation.
+//! Every command reads its settings from `--set KEY=VALUE`, the environment, then the
+//! configuration file of `--config` (see the `config` module).
+//!
+//! ## Database migrations
+//! Pending migrations (`migrations/` directory) are applied at startup, or by
+//! `migrate` without starting the server (`--migrate-only` still works). The contract
+//! migrations, breaking the previous release, wait for `migrate --contract` (see
+//! `db::migrations`).
+//!
+//! ## Shutdown
+//! On SIGTERM/SIGINT the server stops accepting connections, lets in-flight requests
+//! finish (up to `SHUTDOWN_TIMEOUT` seconds) and closes the database pool before exiting.
+//! Server-Sent Events streams and WebSocket connections are closed as soon as the
+//! shutdown starts.
+//!
+//! The background subsystems are then stopped in dependency order, each with its own
+//! timeout: the job scheduler (which enqueues jobs), then the job workers (up to
+//! `JOB_DRAIN_TIMEOUT` seconds), and the database pool last, as both use it.
+//!
+//! ## Service mode
+//! Built with the `service` feature, the server runs as a Unix daemon with `--daemon`
+//! or as a Windows service with `--service` (see the `service` module).
 
 use std::env;
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::time::Duration;
 
+use clap::{Args, Parser, Subcommand};
 use dotenvy::dotenv;
 use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use rust_backend::config::{AppConfig, Overrides, ServiceConfig};
+use rust_backend::server::{self, bind_tls, prepare_database, serve};
+#[cfg(feature = "service")]
+use rust_backend::service;
+#[cfg(all(unix, feature = "service"))]
+use rust_backend::service::remove_pid_file;
+use rust_backend::tasks::{self, Phase};
+use rust_backend::{
+    ShutdownController, begin_shutdown, close_pool, drain_jobs, flush_error_reports,
+    flush_exposures, init_tracing, shutdown_signal, stop_scheduler,
+};
 
-use hyper::server::conn::http1;
-use hyper::service::service_fn;
-use hyper_util::rt::TokioIo;
+/// Longest wait for the job scheduler, which only has to release its lock
+const SCHEDULER_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest wait for the experiment exposures still queued to be written
+const EXPOSURES_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest wait for the error reports still queued to be sent
+const ERROR_REPORTS_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
 
-mod db;
-mod router;
+/// REST API of users, products and orders.
+#[derive(Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Options of `serve`, the default command
+    #[command(flatten)]
+    serve: ServeArgs,
+    /// Configuration file, TOML or YAML (`.yaml`, `.yml`), instead of CONFIG_PATH
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Setting taking precedence over the environment and the file (repeatable)
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE", value_parser = parse_setting)]
+    settings: Vec<(String, String)>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the server (default)
+    Serve(ServeArgs),
+    /// Manage the database schema
+    Migrate {
+        #[command(subcommand)]
+        action: Option<MigrateAction>,
+        /// Options of `apply`, the default action
+        #[command(flatten)]
+        apply: ApplyArgs,
+    },
+    /// Create an account able to call the protected routes, its password read from
+    /// ADMIN_PASSWORD or the standard input
+    CreateAdmin {
+        #[arg(long)]
+        email: String,
+        #[arg(long, default_value = "Admin")]
+        name: String,
+        #[arg(long, default_value_t = 0)]
+        age: i32,
+    },
+    /// Print the route table
+    Routes,
+    /// Check the settings and every dependency, exiting with 1 if a check fails
+    Selftest {
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Inspect the settings
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Args, Clone, Copy)]
+struct ServeArgs {
+    /// Go on in the background (Unix, `service` feature)
+    #[arg(long)]
+    daemon: bool,
+    /// Run as a Windows service (`service` feature)
+    #[arg(long)]
+    service: bool,
+    /// Serve HTTPS on TLS_PORT with a self-signed certificate for localhost, generated
+    /// at startup, for development (same as DEV_TLS=true)
+    #[arg(long)]
+    dev_tls: bool,
+    /// Same as `migrate apply`, for the scripts written before the subcommands
+    #[arg(long, hide = true)]
+    migrate_only: bool,
+}
+
+#[derive(Args, Clone, Copy, Default)]
+struct ApplyArgs {
+    /// Apply the contract migrations too, once no instance of the previous release runs
+    #[arg(long)]
+    contract: bool,
+    /// Print the migrations that would be applied and their SQL, without applying them
+    #[arg(long)]
+    plan: bool,
+}
+
+#[derive(Subcommand, Clone, Copy)]
+enum MigrateAction {
+    /// Apply the pending migrations (default)
+    Apply(ApplyArgs),
+    /// Undo the latest applied migration
+    Rollback,
+    /// List the migrations and when they were applied
+    Status,
+}
+
+#[derive(Subcommand, Clone, Copy)]
+enum ConfigAction {
+    /// Print every setting read, with its value and its source (cli, env, file,
+    /// default), secrets masked; exits with 1 if a setting is invalid
+    PrintEffective,
+}
 
-use db::init_pool;
-use router::process_request_and_response;
+/// Parses a `--set` value.
+fn parse_setting(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_uppercase(), value.to_string()))
+        }
+        _ => Err("expected KEY=VALUE".to_string()),
+    }
+}
 
 /// Main entry point of the application.
 ///
-/// Sets up an asynchronous HTTP server using Tokio and Hyper, then handles incoming
-/// connections in a non-blocking manner. All request routing logic is delegated to
-/// the `router` module.
+/// Parses the command line and loads the settings. To serve, becomes a daemon or a
+/// Windows service when asked to, then starts the Tokio runtime and runs the server
+/// (see [`serve_until_stopped`]); the other commands run a task and exit.
+///
+/// The runtime is started by hand rather than with `#[tokio::main]`: a daemon has to
+/// fork before any thread is started.
+fn main() {
+    let cli = Cli::parse();
+    let mut overrides = Overrides {
+        file: cli.config,
+        values: cli.settings,
+    };
+    let command = match cli.command.unwrap_or(Command::Serve(cli.serve)) {
+        Command::Serve(args) if args.migrate_only => Command::Migrate {
+            action: None,
+            apply: ApplyArgs::default(),
+        },
+        command => command,
+    };
+    // HTTPS with a self-signed certificate for localhost
+    if let Command::Serve(ServeArgs { dev_tls: true, .. }) = command {
+        overrides
+            .values
+            .push(("DEV_TLS".to_string(), "true".to_string()));
+    }
+
+    #[cfg(all(windows, feature = "service"))]
+    if matches!(command, Command::Serve(ServeArgs { service: true, .. }))
+        && let Err(e) = service::enter_executable_dir()
+    {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    // The route table needs no setting
+    if let Command::Routes = command {
+        print!("{}", tasks::route_table());
+        return;
+    }
+
+    // Load .env file
+    // .ok() ignore any errors if the file does not exist (production)
+    dotenv().ok();
+
+    if let Command::Config {
+        action: ConfigAction::PrintEffective,
+    } = command
+    {
+        match AppConfig::effective(&overrides) {
+            Ok(settings) => print!("{}", tasks::effective_config(&settings)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Load and validate every setting (command line, environment and optional
+    // configuration file), before daemonizing so that invalid settings are reported in
+    // the terminal
+    let config = match AppConfig::load(&overrides) {
+        Ok(config) => config,
+        Err(e) if matches!(command, Command::Selftest { .. }) => {
+            print_report(&command, &tasks::Report::invalid_config(&e.to_string()));
+            std::process::exit(1);
+        }
+        Err(e) => {
+            init_tracing(None);
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let args = match command {
+        Command::Serve(args) => args,
+        command => {
+            init_tracing(None);
+            let runtime =
+                tokio::runtime::Runtime::new().expect("Failed to start the Tokio runtime");
+            let result = runtime.block_on(run_task(&config, command));
+            close_pool();
+            if let Err(e) = result {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+    };
+
+    if args.service {
+        run_windows_service(config);
+        return;
+    }
+    let log_file = if args.daemon {
+        daemonize(&config.service)
+    } else {
+        None
+    };
+
+    // Configure logging (LOG_LEVEL, LOG_FORMAT) before anything else is logged
+    init_tracing(log_file);
+
+    let service = config.service.clone();
+    run(config);
+    if args.daemon {
+        remove_pid_file(&service);
+    }
+}
+
+/// Runs the server on a new Tokio runtime, until it's stopped.
+fn run(config: AppConfig) {
+    tokio::runtime::Runtime::new()
+        .expect("Failed to start the Tokio runtime")
+        .block_on(serve_until_stopped(config));
+}
+
+/// Runs a command other than `serve`.
+async fn run_task(config: &AppConfig, command: Command) -> Result<(), String> {
+    match command {
+        Command::Migrate { action, apply } => match action.unwrap_or(MigrateAction::Apply(apply)) {
+            _ if action.is_some() && (apply.contract || apply.plan) => {
+                Err("--contract and --plan go after `migrate apply`".to_string())
+            }
+            MigrateAction::Apply(ApplyArgs {
+                contract,
+                plan: true,
+            }) => {
+                tasks::connect(&config.database).await?;
+                let plan = tasks::plan(contract).await?;
+                if plan.is_empty() {
+                    println!("No pending migration");
+                }
+                for migration in plan {
+                    let outcome = if migration.runs {
+                        "to apply"
+                    } else if migration.phase == Phase::Contract {
+                        "pending, needs --contract"
+                    } else {
+                        "pending, after a contract migration"
+                    };
+                    println!(
+                        "V{:<4} {:<32} {:<9} {}",
+                        migration.version,
+                        migration.name,
+                        migration.phase.as_str(),
+                        outcome
+                    );
+                    if migration.runs {
+                        println!("{}", migration.sql.trim_end());
+                        println!();
+                    }
+                }
+                Ok(())
+            }
+            MigrateAction::Apply(ApplyArgs { contract, .. }) => {
+                prepare_database(&config.database, contract).await
+            }
+            MigrateAction::Rollback => {
+                tasks::connect(&config.database).await?;
+                match tasks::rollback().await? {
+                    Some(undone) => info!("Undid migration V{}__{}", undone.version, undone.name),
+                    None => info!("No migration to undo"),
+                }
+                Ok(())
+            }
+            MigrateAction::Status => {
+                tasks::connect(&config.database).await?;
+                print!("{}", tasks::migration_table(&tasks::migrations().await?));
+                Ok(())
+            }
+        },
+        Command::CreateAdmin { email, name, age } => {
+            let password = match env::var("ADMIN_PASSWORD") {
+                Ok(password) => password,
+                Err(_) => {
+                    eprint!("Password of {}: ", email);
+                    let mut line = String::new();
+                    io::stdin()
+                        .lock()
+                        .read_line(&mut line)
+                        .map_err(|e| format!("Error reading the password: {}", e))?;
+                    line.trim_end_matches(['\r', '\n']).to_string()
+                }
+            };
+            // The schema of a new database is created first
+            prepare_database(&config.database, false).await?;
+            let id = tasks::create_admin(name, age, email, password).await?;
+            info!("Account {} created", id);
+            Ok(())
+        }
+        Command::Selftest { .. } => {
+            let report = tasks::selftest(config).await;
+            print_report(&command, &report);
+            if report.passed {
+                Ok(())
+            } else {
+                Err("Self-test failed".to_string())
+            }
+        }
+        Command::Serve(_) | Command::Routes | Command::Config { .. } => {
+            unreachable!("Not a task")
+        }
+    }
+}
+
+/// Prints the report of `selftest`, as JSON with `--json`.
+fn print_report(command: &Command, report: &tasks::Report) {
+    if let Command::Selftest { json: true } = command {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(report).expect("The report is serializable")
+        );
+    } else {
+        println!("{}", report);
+    }
+}
+
+/// Goes on in the background (`--daemon`), exiting in the terminal.
+///
+/// Returns the log file of the daemon, whose standard output and error are also
+/// redirected there (panics).
+#[cfg(all(unix, feature = "service"))]
+fn daemonize(config: &ServiceConfig) -> Option<File> {
+    service::daemonize(config)
+        .and_then(|()| service::open_log_file(config))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        })
+}
+
+#[cfg(not(all(unix, feature = "service")))]
+fn daemonize(_config: &ServiceConfig) -> Option<File> {
+    eprintln!("--daemon requires a Unix build with the `service` feature");
+    std::process::exit(1);
+}
+
+#[cfg(not(all(unix, feature = "service")))]
+fn remove_pid_file(_config: &ServiceConfig) {}
+
+/// Runs the server as a Windows service (`--service`), until the service manager stops
+/// it.
+#[cfg(all(windows, feature = "service"))]
+fn run_windows_service(config: AppConfig) {
+    let log_file = service::open_log_file(&config.service).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    init_tracing(log_file);
+
+    // What `serve_until_stopped` may wait for once the shutdown has started
+    let stop_wait =
+        config.server.shutdown_timeout + SCHEDULER_STOP_TIMEOUT + config.jobs.drain_timeout;
+    let service = config.service.clone();
+    if let Err(e) = service::run_as_windows_service(&service, stop_wait, move || run(config)) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(not(all(windows, feature = "service")))]
+fn run_windows_service(_config: AppConfig) {
+    eprintln!("--service requires a Windows build with the `service` feature");
+    std::process::exit(1);
+}
+
+/// Sets up an asynchronous HTTP server (and optionally HTTPS, see the `tls` module)
+/// using Tokio and Hyper, then handles incoming connections in a non-blocking manner.
+/// All request routing logic is delegated to the `router` module.
+///
+/// Returns once the server and its background subsystems are stopped.
 ///
 /// # Panics
 ///
 /// Will panic if:
 /// - Unable to bind to the specified TCP port
 /// - Failed to accept a connection
-#[tokio::main]
-async fn main() {
+async fn serve_until_stopped(config: AppConfig) {
     // ==================== STARTING SERVER ====================
 
-    // Load .env file
-    // .ok() ignore any errors if the file does not exist (production)
-    dotenv().ok();
+    // Start the database pool and apply pending migrations
+    if let Err(e) = prepare_database(&config.database, false).await {
+        error!("{}", e);
+        std::process::exit(1);
+    }
 
-    // Start database pool
-    if let Err(e) = init_pool().await {
-        eprintln!("Error starting database pool: {}", e);
+    // Signing keys, geo data, file storage and request limits
+    if let Err(e) = server::init(&config).await {
+        error!("{}", e);
         std::process::exit(1);
     }
 
@@ -67,40 +547,60 @@ async fn main() {
     //   (allows both local and external connections)
     // - 127.0.0.1: Listen only for local connections
     // - When port is :0, the OS assigns port automatically
-    let port: u16 = env::var("PORT")
-        .unwrap_or("3000".to_owned())
-        .parse::<u16>()
-        .unwrap();
-    //let addr = SocketAddr::from(([0, 0, 0, 0], 3005));
+    let port = config.server.port;
     let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
         .await
-        .expect(&format!("Error binding to TCP port {}", port));
+        .unwrap_or_else(|_| panic!("Error binding to TCP port {}", port));
+
+    info!("Server initialized on port {}", port);
 
-    println!("Server initialized on port {}", port);
+    // Optional HTTPS listener, served at the same time as plain HTTP
+    let tls = match bind_tls(config.tls.as_ref()).await {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
     // ==================== HANDLE INCOMING CONNECTIONS ====================
-    // Main loop that accepts incoming connections
-    loop {
-        // Wait for and accept a new connection asynchronously
-        let (stream, _) = listener
-            .accept()
-            .await
-            .expect("Failed to accept connection");
-
-        // Adapt the TCP socket to Tokio's I/O interface
-        let io = TokioIo::new(stream);
-
-        // Each new connection is handled in its own asynchronous task,
-        // allowing the server to continue accepting new connections
-        // while processing existing ones concurrently
-        tokio::spawn(async move {
-            // Configure an HTTP service that routes requests to our handler function
-            if let Err(e) = http1::Builder::new()
-                .serve_connection(io, service_fn(process_request_and_response))
-                .await
-            {
-                eprintln!("Error in HTTP connection: {}", e);
-            }
-        });
-    }
+    // Stops the accept loops and the long-lived responses when SIGINT/SIGTERM is received
+    tokio::spawn(async {
+        shutdown_signal().await;
+        begin_shutdown();
+    });
+
+    // ==================== GRACEFUL SHUTDOWN ====================
+    // Returns once the connections are drained (or the drain timed out)
+    serve(listener, tls, &config.server).await;
+
+    // Stop the background subsystems, each before those it depends on
+    let mut shutdown = ShutdownController::start();
+    shutdown
+        .stop("Job scheduler", SCHEDULER_STOP_TIMEOUT, stop_scheduler())
+        .await;
+    // Let the workers finish the queued jobs, the others run at the next start
+    shutdown
+        .stop("Job workers", config.jobs.drain_timeout, drain_jobs())
+        .await;
+    shutdown
+        .stop(
+            "Experiment exposures",
+            EXPOSURES_FLUSH_TIMEOUT,
+            flush_exposures(),
+        )
+        .await;
+    // Last, the panics and errors of the subsystems above are reported too
+    shutdown
+        .stop(
+            "Error reports",
+            ERROR_REPORTS_FLUSH_TIMEOUT,
+            flush_error_reports(),
+        )
+        .await;
+    shutdown.finish();
+
+    // Release the database connections before exiting
+    close_pool();
+    info!("Server stopped");
 }
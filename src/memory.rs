@@ -0,0 +1,121 @@
+//! Memory allocator instrumentation, for diagnosing memory growth.
+//!
+//! Built with the `jemalloc` feature, the server allocates with jemalloc with its heap
+//! profiler on, sampling an allocation every 512 KiB on average, which is cheap enough
+//! for production:
+//!
+//! - the allocator statistics are exported in `/metrics` (see the `metrics` module)
+//! - `GET /admin/heap-profile` dumps the sampled live allocations, to be read with
+//!   `jeprof --svg <binary> <profile>`; two profiles can be compared with `--base`
+//!
+//! The profiler settings can be overridden with `_RJEM_MALLOC_CONF`
+//! (`prof:false` disables it). Without the feature, the system allocator is used and
+//! nothing is reported.
+
+use crate::error::AppError;
+
+/// Allocator of every binary linking the library
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+/// Default jemalloc options: profiling on, sampling every 2^19 bytes (512 KiB) on
+/// average. Overridden by the `_RJEM_MALLOC_CONF` environment variable.
+#[cfg(feature = "jemalloc")]
+#[unsafe(export_name = "_rjem_malloc_conf")]
+static MALLOC_CONF: &[u8; 45] = b"prof:true,prof_active:true,lg_prof_sample:19\0";
+
+/// Snapshot of the allocator statistics.
+#[derive(Debug, Clone, Copy)]
+pub struct AllocatorStats {
+    /// Bytes allocated by the application
+    pub allocated: usize,
+    /// Bytes in the pages holding allocations
+    pub active: usize,
+    /// Bytes of physical memory mapped by the allocator (roughly its share of the RSS)
+    pub resident: usize,
+    /// Bytes of virtual memory mapped by the allocator
+    pub mapped: usize,
+    /// Bytes of virtual memory kept for reuse instead of being returned to the system
+    pub retained: usize,
+    /// Allocations by the arenas since startup (thread cache refills count as one each)
+    pub allocations: u64,
+    /// Deallocations by the arenas since startup (thread cache flushes count as one each)
+    pub deallocations: u64,
+}
+
+/// Reads the allocator statistics, `None` without the `jemalloc` feature.
+#[cfg(feature = "jemalloc")]
+pub fn allocator_stats() -> Option<AllocatorStats> {
+    use tikv_jemalloc_ctl::{epoch, raw, stats};
+
+    // The statistics are cached by jemalloc until the epoch advances
+    epoch::advance().ok()?;
+    // Counters of every arena (MALLCTL_ARENAS_ALL), by size class
+    let count = |name: &[u8]| unsafe { raw::read::<u64>(name) }.unwrap_or_default();
+
+    Some(AllocatorStats {
+        allocated: stats::allocated::read().ok()?,
+        active: stats::active::read().ok()?,
+        resident: stats::resident::read().ok()?,
+        mapped: stats::mapped::read().ok()?,
+        retained: stats::retained::read().ok()?,
+        allocations: count(b"stats.arenas.4096.small.nmalloc\0")
+            + count(b"stats.arenas.4096.large.nmalloc\0"),
+        deallocations: count(b"stats.arenas.4096.small.ndalloc\0")
+            + count(b"stats.arenas.4096.large.ndalloc\0"),
+    })
+}
+
+/// Reads the allocator statistics, `None` without the `jemalloc` feature.
+#[cfg(not(feature = "jemalloc"))]
+pub fn allocator_stats() -> Option<AllocatorStats> {
+    None
+}
+
+/// Dumps a heap profile of the live sampled allocations.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>, AppError>` - The profile (jeprof format), an `AppError::Conflict`
+///   if profiling was disabled at startup, or an `AppError::Internal` if the dump fails
+#[cfg(feature = "jemalloc")]
+pub async fn heap_profile() -> Result<Vec<u8>, AppError> {
+    use std::ffi::CString;
+
+    use tikv_jemalloc_ctl::{profiling, raw};
+    use uuid::Uuid;
+
+    use crate::error::ErrorCode;
+
+    if !profiling::prof::read().unwrap_or(false) {
+        return Err(AppError::Conflict(
+            ErrorCode::ProfilingDisabled,
+            "Heap profiling is disabled (prof:false in _RJEM_MALLOC_CONF)".to_string(),
+        ));
+    }
+
+    // jemalloc only writes profiles to files
+    let path = std::env::temp_dir().join(format!("heap-{}.prof", Uuid::new_v4()));
+    tokio::task::spawn_blocking(move || {
+        let c_path = CString::new(path.to_string_lossy().into_owned())
+            .map_err(|e| AppError::Internal(format!("Invalid profile path: {}", e)))?;
+        unsafe { raw::write(b"prof.dump\0", c_path.as_ptr()) }
+            .map_err(|e| AppError::Internal(format!("Heap profile dump failed: {}", e)))?;
+
+        let profile = std::fs::read(&path);
+        let _ = std::fs::remove_file(&path);
+        profile.map_err(|e| AppError::Internal(format!("Heap profile unreadable: {}", e)))
+    })
+    .await
+    .map_err(|e| AppError::Internal(format!("Heap profile task failed: {}", e)))?
+}
+
+/// Dumps a heap profile: always an `AppError::Conflict` without the `jemalloc` feature.
+#[cfg(not(feature = "jemalloc"))]
+pub async fn heap_profile() -> Result<Vec<u8>, AppError> {
+    Err(AppError::Conflict(
+        crate::error::ErrorCode::ProfilingDisabled,
+        "Heap profiling requires a build with the jemalloc feature".to_string(),
+    ))
+}
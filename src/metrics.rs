@@ -0,0 +1,671 @@
+//! Prometheus metrics.
+//!
+//! Exposed by `GET /metrics`, in the OpenMetrics format to scrapers accepting it and in
+//! the Prometheus text format otherwise:
+//!
+//! - `http_requests_total{method, path, status}`: requests served. `path` is the route
+//!   pattern (`/users/:id`), or `unmatched`, so IDs don't create a series each
+//! - `http_request_duration_seconds{method, path}`: request latency histogram. In the
+//!   OpenMetrics format every bucket carries an exemplar: the ID of the latest request
+//!   that fell in it (`trace_id`), the same ID as in the logs and `X-App-Request-Id`
+//! - `http_active_connections`: open client connections (HTTP and HTTPS)
+//! - `db_pool_connections{state}`: pool connections `idle` and `in_use`
+//! - `db_pool_max_connections`: configured pool size
+//! - `db_pool_wait_seconds_total` / `db_pool_waits_total` / `db_pool_timeouts_total`:
+//!   time spent and number of gets that had to wait for a connection, or timed out
+//! - `db_pool_reuses_total`: gets served by the connection the request already held,
+//!   without going through the pool
+//! - `db_statement_cache_hits_total` / `db_statement_cache_misses_total`: queries that
+//!   reused the prepared statement of their connection, or had to prepare it
+//! - `allocator_bytes{state}`: memory of the allocator, `allocated` by the application,
+//!   `active`, `resident`, `mapped` and `retained` (see the `memory` module)
+//! - `allocator_operations_total{operation}`: `alloc` and `dealloc` operations of the
+//!   allocator arenas since startup. Small allocations served by the thread caches are
+//!   counted in batches, when a cache is refilled or flushed
+//! - `jobs_total{kind, outcome}`: background jobs run, by outcome: `succeeded`,
+//!   `retried` (failed, tried again later) and `failed` (after the last attempt)
+//! - `db_replica_reads_total{outcome}`: reads carrying a consistency token, served by
+//!   the `replica` at once, after it `waited` for the replica, or by the `primary`
+//! - `db_retries_total`: read queries run again after a transient error
+//! - `db_circuit_state{pool}`: breaker of the `primary` or a `replica-<n>` pool, 0 closed,
+//!   1 open (failing fast), 2 half-open (probing)
+//! - `db_circuit_rejections_total{pool}`: gets failed fast by an open breaker
+//! - `db_lock_wait_seconds{lock}` / `db_lock_hold_seconds{lock}`: histograms of the time
+//!   spent waiting for a distributed lock, and holding it (see `db::lock`)
+//! - `db_lock_contentions_total{lock}`: acquisitions that found the lock held by another
+//!   instance and had to wait
+//! - `offline_writes_total{outcome}`: writes accepted while the database was down,
+//!   `queued`, then `applied`, `failed` or `expired` (see the `offline_queue` module)
+//! - `error_reports_total{outcome}`: panics and server errors reported to Sentry, `sent`,
+//!   `failed` or `dropped` when the queue is full (see the `error_reporting` module)
+//! - `experiment_exposures_total{experiment, variant}`: responses sent with a variant
+//!   of an experiment (see the `experiments` module)
+//! - `retention_rows_total{rule, mode}`: rows a retention rule removed (`delete`,
+//!   `anonymize`), or found by its runs in `dry_run` mode (see the `retention` module)
+//! - `retention_last_run_timestamp_seconds{rule}`: Unix time of the latest complete run
+//!   of a retention rule
+//! - `slo_requests_total{slo, outcome}`: requests of the route of a service level
+//!   objective, `good` or `bad` (see the `slo` module)
+//! - `slo_objective_ratio{slo}`: share of good requests an objective aims at (`0.99`)
+//! - `slo_burn_rate{slo, window}`: rate at which an objective burns its error budget
+//!   over the last `5m`, `30m`, `1h` and `6h`
+//! - `slo_status{slo}`: 0 ok, 1 burning slowly, 2 burning fast
+//!
+//! Request metrics are recorded by the router, connection metrics by the accept loop,
+//! job metrics by the workers and replica reads, retries, breakers and locks by the db
+//! layer.
+//! The pool metrics are read from the db layer on every scrape, as are the allocator
+//! metrics, only reported by builds with the `jemalloc` feature, and the burn rates of
+//! the objectives.
+
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, SystemTime};
+
+use hyper::Method;
+use prometheus::{
+    DEFAULT_BUCKETS, Encoder, Gauge, GaugeVec, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::db::pool_status;
+use crate::memory::allocator_stats;
+use openmetrics::{Exemplar, Exemplars};
+
+mod openmetrics;
+mod slo;
+
+pub use slo::{init_slos, report as slo_report};
+
+/// Content type of the Prometheus text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Content type of the OpenMetrics text format
+pub use openmetrics::CONTENT_TYPE as OPENMETRICS_CONTENT_TYPE;
+
+/// Label used for requests that didn't match any route
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+const LATENCY_METRIC: &str = "http_request_duration_seconds";
+
+/// Formats `render` can produce.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Prometheus,
+    OpenMetrics,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    active_connections: IntGauge,
+    pool_connections: IntGaugeVec,
+    pool_max_connections: IntGauge,
+    pool_wait_seconds: Gauge,
+    pool_waits: IntGauge,
+    pool_timeouts: IntGauge,
+    pool_reuses: IntGauge,
+    statement_cache_hits: IntGauge,
+    statement_cache_misses: IntGauge,
+    allocator_bytes: IntGaugeVec,
+    allocator_operations: IntGaugeVec,
+    jobs: IntCounterVec,
+    replica_reads: IntCounterVec,
+    db_retries: IntCounter,
+    circuit_state: IntGaugeVec,
+    circuit_rejections: IntCounterVec,
+    lock_wait: HistogramVec,
+    lock_hold: HistogramVec,
+    lock_contentions: IntCounterVec,
+    offline_writes: IntCounterVec,
+    error_reports: IntCounterVec,
+    exposures: IntCounterVec,
+    retention_rows: IntCounterVec,
+    retention_last_run: IntGaugeVec,
+    slo_requests: IntCounterVec,
+    slo_objective: GaugeVec,
+    slo_burn_rate: GaugeVec,
+    slo_status: IntGaugeVec,
+    /// Latest request of every latency bucket
+    exemplars: Mutex<Exemplars>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Number of HTTP requests served"),
+            &["method", "path", "status"],
+        )
+        .unwrap();
+        let latency = HistogramVec::new(
+            HistogramOpts::new(LATENCY_METRIC, "Time to produce the HTTP response"),
+            &["method", "path"],
+        )
+        .unwrap();
+        let active_connections = IntGauge::new(
+            "http_active_connections",
+            "Number of open client connections",
+        )
+        .unwrap();
+        let pool_connections = IntGaugeVec::new(
+            Opts::new("db_pool_connections", "Database pool connections by state"),
+            &["state"],
+        )
+        .unwrap();
+        let pool_max_connections = IntGauge::new(
+            "db_pool_max_connections",
+            "Maximum number of database pool connections",
+        )
+        .unwrap();
+        // Gauges mirroring the cumulative counters of bb8, read on every scrape
+        let pool_wait_seconds = Gauge::new(
+            "db_pool_wait_seconds_total",
+            "Total time spent waiting for a database connection",
+        )
+        .unwrap();
+        let pool_waits = IntGauge::new(
+            "db_pool_waits_total",
+            "Number of gets that had to wait for a database connection",
+        )
+        .unwrap();
+        let pool_timeouts = IntGauge::new(
+            "db_pool_timeouts_total",
+            "Number of gets that timed out waiting for a database connection",
+        )
+        .unwrap();
+
+        let pool_reuses = IntGauge::new(
+            "db_pool_reuses_total",
+            "Number of gets served by the connection the request already held",
+        )
+        .unwrap();
+        let statement_cache_hits = IntGauge::new(
+            "db_statement_cache_hits_total",
+            "Number of queries that reused a statement prepared by their connection",
+        )
+        .unwrap();
+        let statement_cache_misses = IntGauge::new(
+            "db_statement_cache_misses_total",
+            "Number of queries that had to prepare their statement",
+        )
+        .unwrap();
+        let allocator_bytes = IntGaugeVec::new(
+            Opts::new(
+                "allocator_bytes",
+                "Memory of the allocator by state, in bytes",
+            ),
+            &["state"],
+        )
+        .unwrap();
+        let allocator_operations = IntGaugeVec::new(
+            Opts::new(
+                "allocator_operations_total",
+                "Number of allocations and deallocations since startup",
+            ),
+            &["operation"],
+        )
+        .unwrap();
+
+        let jobs = IntCounterVec::new(
+            Opts::new("jobs_total", "Number of background jobs run, by outcome"),
+            &["kind", "outcome"],
+        )
+        .unwrap();
+        let replica_reads = IntCounterVec::new(
+            Opts::new(
+                "db_replica_reads_total",
+                "Number of reads carrying a consistency token, by server",
+            ),
+            &["outcome"],
+        )
+        .unwrap();
+        let db_retries = IntCounter::new(
+            "db_retries_total",
+            "Number of read queries run again after a transient error",
+        )
+        .unwrap();
+        let circuit_state = IntGaugeVec::new(
+            Opts::new(
+                "db_circuit_state",
+                "Breaker of the database pool: 0 closed, 1 open, 2 half-open",
+            ),
+            &["pool"],
+        )
+        .unwrap();
+        let circuit_rejections = IntCounterVec::new(
+            Opts::new(
+                "db_circuit_rejections_total",
+                "Number of connection gets failed fast by an open breaker",
+            ),
+            &["pool"],
+        )
+        .unwrap();
+        let lock_wait = HistogramVec::new(
+            HistogramOpts::new(
+                "db_lock_wait_seconds",
+                "Time spent waiting for a distributed lock",
+            ),
+            &["lock"],
+        )
+        .unwrap();
+        let lock_hold = HistogramVec::new(
+            HistogramOpts::new("db_lock_hold_seconds", "Time a distributed lock was held"),
+            &["lock"],
+        )
+        .unwrap();
+        let lock_contentions = IntCounterVec::new(
+            Opts::new(
+                "db_lock_contentions_total",
+                "Number of acquisitions that waited for a lock held by another instance",
+            ),
+            &["lock"],
+        )
+        .unwrap();
+        let offline_writes = IntCounterVec::new(
+            Opts::new(
+                "offline_writes_total",
+                "Number of writes queued while the database was down, by outcome",
+            ),
+            &["outcome"],
+        )
+        .unwrap();
+        let error_reports = IntCounterVec::new(
+            Opts::new(
+                "error_reports_total",
+                "Number of panics and server errors reported, by outcome",
+            ),
+            &["outcome"],
+        )
+        .unwrap();
+        let exposures = IntCounterVec::new(
+            Opts::new(
+                "experiment_exposures_total",
+                "Number of responses sent with a variant of an experiment",
+            ),
+            &["experiment", "variant"],
+        )
+        .unwrap();
+        let retention_rows = IntCounterVec::new(
+            Opts::new(
+                "retention_rows_total",
+                "Number of rows removed by a retention rule, or found by its dry runs",
+            ),
+            &["rule", "mode"],
+        )
+        .unwrap();
+        let retention_last_run = IntGaugeVec::new(
+            Opts::new(
+                "retention_last_run_timestamp_seconds",
+                "Time of the latest complete run of a retention rule",
+            ),
+            &["rule"],
+        )
+        .unwrap();
+        let slo_requests = IntCounterVec::new(
+            Opts::new(
+                "slo_requests_total",
+                "Number of requests of the route of a service level objective, by outcome",
+            ),
+            &["slo", "outcome"],
+        )
+        .unwrap();
+        let slo_objective = GaugeVec::new(
+            Opts::new(
+                "slo_objective_ratio",
+                "Share of good requests a service level objective aims at",
+            ),
+            &["slo"],
+        )
+        .unwrap();
+        let slo_burn_rate = GaugeVec::new(
+            Opts::new(
+                "slo_burn_rate",
+                "Rate at which a service level objective burns its error budget",
+            ),
+            &["slo", "window"],
+        )
+        .unwrap();
+        let slo_status = IntGaugeVec::new(
+            Opts::new(
+                "slo_status",
+                "Service level objective: 0 ok, 1 burning slowly, 2 burning fast",
+            ),
+            &["slo"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        registry
+            .register(Box::new(active_connections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(pool_connections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(pool_max_connections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(pool_wait_seconds.clone()))
+            .unwrap();
+        registry.register(Box::new(pool_waits.clone())).unwrap();
+        registry.register(Box::new(pool_timeouts.clone())).unwrap();
+        registry.register(Box::new(pool_reuses.clone())).unwrap();
+        registry
+            .register(Box::new(statement_cache_hits.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(statement_cache_misses.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(allocator_bytes.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(allocator_operations.clone()))
+            .unwrap();
+        registry.register(Box::new(jobs.clone())).unwrap();
+        registry.register(Box::new(replica_reads.clone())).unwrap();
+        registry.register(Box::new(db_retries.clone())).unwrap();
+        registry.register(Box::new(circuit_state.clone())).unwrap();
+        registry
+            .register(Box::new(circuit_rejections.clone()))
+            .unwrap();
+        registry.register(Box::new(lock_wait.clone())).unwrap();
+        registry.register(Box::new(lock_hold.clone())).unwrap();
+        registry
+            .register(Box::new(lock_contentions.clone()))
+            .unwrap();
+        registry.register(Box::new(offline_writes.clone())).unwrap();
+        registry.register(Box::new(error_reports.clone())).unwrap();
+        registry.register(Box::new(exposures.clone())).unwrap();
+        registry.register(Box::new(retention_rows.clone())).unwrap();
+        registry
+            .register(Box::new(retention_last_run.clone()))
+            .unwrap();
+        registry.register(Box::new(slo_requests.clone())).unwrap();
+        registry.register(Box::new(slo_objective.clone())).unwrap();
+        registry.register(Box::new(slo_burn_rate.clone())).unwrap();
+        registry.register(Box::new(slo_status.clone())).unwrap();
+
+        Metrics {
+            registry,
+            requests,
+            latency,
+            active_connections,
+            pool_connections,
+            pool_max_connections,
+            pool_wait_seconds,
+            pool_waits,
+            pool_timeouts,
+            pool_reuses,
+            statement_cache_hits,
+            statement_cache_misses,
+            allocator_bytes,
+            allocator_operations,
+            jobs,
+            replica_reads,
+            db_retries,
+            circuit_state,
+            circuit_rejections,
+            lock_wait,
+            lock_hold,
+            lock_contentions,
+            offline_writes,
+            error_reports,
+            exposures,
+            retention_rows,
+            retention_last_run,
+            slo_requests,
+            slo_objective,
+            slo_burn_rate,
+            slo_status,
+            exemplars: Mutex::new(Exemplars::new()),
+        }
+    }
+}
+
+/// Records the outcome of a background job.
+pub fn observe_job(kind: &str, outcome: &str) {
+    METRICS.jobs.with_label_values(&[kind, outcome]).inc();
+}
+
+/// Records where a read carrying a consistency token was served.
+pub fn observe_replica_read(outcome: &str) {
+    METRICS.replica_reads.with_label_values(&[outcome]).inc();
+}
+
+/// Records a read query run again after a transient error.
+pub fn observe_db_retry() {
+    METRICS.db_retries.inc();
+}
+
+/// Records the state of the breaker of a pool (see `db::breaker`).
+pub fn observe_circuit_state(pool: &str, state: i64) {
+    METRICS.circuit_state.with_label_values(&[pool]).set(state);
+}
+
+/// Records a get failed fast by the breaker of a pool.
+pub fn observe_circuit_rejection(pool: &str) {
+    METRICS.circuit_rejections.with_label_values(&[pool]).inc();
+}
+
+/// Records the acquisition of a distributed lock (see `db::lock`), `contended` when
+/// another instance held it.
+pub fn observe_lock_wait(lock: &str, wait: Duration, contended: bool) {
+    METRICS
+        .lock_wait
+        .with_label_values(&[lock])
+        .observe(wait.as_secs_f64());
+    if contended {
+        METRICS.lock_contentions.with_label_values(&[lock]).inc();
+    }
+}
+
+/// Records the release of a distributed lock.
+pub fn observe_lock_hold(lock: &str, hold: Duration) {
+    METRICS
+        .lock_hold
+        .with_label_values(&[lock])
+        .observe(hold.as_secs_f64());
+}
+
+/// Records a write of the offline queue: `queued`, `applied`, `failed` or `expired`.
+pub fn observe_offline_write(outcome: &str) {
+    METRICS.offline_writes.with_label_values(&[outcome]).inc();
+}
+
+/// Records error reports: `sent`, `failed` or `dropped`.
+pub fn observe_error_reports(outcome: &str, count: usize) {
+    METRICS
+        .error_reports
+        .with_label_values(&[outcome])
+        .inc_by(count as u64);
+}
+
+/// Records a response sent with a variant of an experiment.
+pub fn observe_exposure(experiment: &str, variant: &str) {
+    METRICS
+        .exposures
+        .with_label_values(&[experiment, variant])
+        .inc();
+}
+
+/// Records rows removed by a retention rule (see the `retention` module), or found
+/// when `mode` is `dry_run`.
+pub fn observe_retention_rows(rule: &str, mode: &str, rows: u64) {
+    METRICS
+        .retention_rows
+        .with_label_values(&[rule, mode])
+        .inc_by(rows);
+}
+
+/// Records the end of a complete run of a retention rule.
+pub fn observe_retention_run(rule: &str) {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    METRICS
+        .retention_last_run
+        .with_label_values(&[rule])
+        .set(i64::try_from(now.as_secs()).unwrap_or(i64::MAX));
+}
+
+/// Records a served request.
+///
+/// # Arguments
+///
+/// * `method` - HTTP method of the request
+/// * `route` - Pattern of the matched route, or `UNMATCHED_ROUTE`
+/// * `status` - Status code of the response
+/// * `elapsed` - Time taken to produce the response
+/// * `request_id` - ID of the request, kept as exemplar of its latency bucket
+pub fn observe_request(
+    method: &Method,
+    route: &str,
+    status: u16,
+    elapsed: Duration,
+    request_id: &str,
+) {
+    let metrics = &*METRICS;
+    let seconds = elapsed.as_secs_f64();
+    metrics
+        .requests
+        .with_label_values(&[method.as_str(), route, &status.to_string()])
+        .inc();
+    metrics
+        .latency
+        .with_label_values(&[method.as_str(), route])
+        .observe(seconds);
+    slo::observe(method, route, status, elapsed);
+
+    if !openmetrics::fits_exemplar(request_id) {
+        return;
+    }
+    // Same bucket the histogram counted the observation in
+    let bucket = DEFAULT_BUCKETS
+        .iter()
+        .position(|bound| seconds <= *bound)
+        .unwrap_or(DEFAULT_BUCKETS.len());
+    // Labels sorted by name, as in the gathered metrics
+    let series = vec![
+        ("method".to_string(), method.to_string()),
+        ("path".to_string(), route.to_string()),
+    ];
+    let exemplar = Exemplar {
+        trace_id: request_id.to_string(),
+        value: seconds,
+        timestamp: SystemTime::now(),
+    };
+    metrics
+        .exemplars
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert((LATENCY_METRIC.to_string(), series, bucket), exemplar);
+}
+
+/// Counts a client connection as active until the guard is dropped.
+pub struct ConnectionGuard(());
+
+impl ConnectionGuard {
+    pub fn new() -> Self {
+        METRICS.active_connections.inc();
+        ConnectionGuard(())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        METRICS.active_connections.dec();
+    }
+}
+
+/// Number of open client connections.
+pub fn active_connections() -> i64 {
+    METRICS.active_connections.get()
+}
+
+/// Renders every metric in the requested format.
+pub fn render(format: Format) -> String {
+    let metrics = &*METRICS;
+
+    if let Some(pool) = pool_status() {
+        let in_use = i64::from(pool.active_connections);
+        metrics
+            .pool_connections
+            .with_label_values(&["idle"])
+            .set(i64::from(pool.idle_connections));
+        metrics
+            .pool_connections
+            .with_label_values(&["in_use"])
+            .set(in_use);
+        metrics.pool_max_connections.set(i64::from(pool.max_size));
+        metrics.pool_wait_seconds.set(pool.wait_time.as_secs_f64());
+        metrics.pool_waits.set(pool.waited as i64);
+        metrics.pool_timeouts.set(pool.timed_out as i64);
+        metrics.pool_reuses.set(pool.reused as i64);
+        metrics
+            .statement_cache_hits
+            .set(pool.statement_cache_hits as i64);
+        metrics
+            .statement_cache_misses
+            .set(pool.statement_cache_misses as i64);
+    }
+
+    if let Some(stats) = allocator_stats() {
+        for (state, bytes) in [
+            ("allocated", stats.allocated),
+            ("active", stats.active),
+            ("resident", stats.resident),
+            ("mapped", stats.mapped),
+            ("retained", stats.retained),
+        ] {
+            metrics
+                .allocator_bytes
+                .with_label_values(&[state])
+                .set(bytes as i64);
+        }
+        for (operation, count) in [
+            ("alloc", stats.allocations),
+            ("dealloc", stats.deallocations),
+        ] {
+            metrics
+                .allocator_operations
+                .with_label_values(&[operation])
+                .set(count as i64);
+        }
+    }
+
+    for slo in slo::report() {
+        metrics
+            .slo_objective
+            .with_label_values(&[slo.name])
+            .set(slo.objective / 100.0);
+        for (window, rate) in &slo.burn_rates {
+            metrics
+                .slo_burn_rate
+                .with_label_values(&[slo.name, window])
+                .set(rate.as_f64().unwrap_or_default());
+        }
+        metrics
+            .slo_status
+            .with_label_values(&[slo.name])
+            .set(slo.status.level());
+    }
+
+    let families = metrics.registry.gather();
+    match format {
+        Format::OpenMetrics => {
+            let exemplars = metrics.exemplars.lock().unwrap_or_else(|e| e.into_inner());
+            openmetrics::encode(&families, &exemplars)
+        }
+        Format::Prometheus => {
+            let mut buffer = Vec::new();
+            TextEncoder::new().encode(&families, &mut buffer).unwrap();
+            String::from_utf8(buffer).unwrap()
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! OpenMetrics text exposition format, with exemplars.
+//!
+//! The `prometheus` crate only encodes the older Prometheus text format, which can't
+//! carry exemplars. This encoder renders the gathered metric families the same way,
+//! with the differences required by OpenMetrics:
+//!
+//! - counters are declared without their `_total` suffix, which only the sample has.
+//!   Gauges named `*_total` mirror counters maintained elsewhere (the pool statistics)
+//!   and are declared as counters too, the suffix being reserved to them
+//! - histogram buckets may end with an exemplar: `# {trace_id="..."} <value> <timestamp>`
+//! - the exposition ends with `# EOF`
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::SystemTime;
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+
+/// Content type of the OpenMetrics text format
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Label of the exemplars holding the request ID
+const TRACE_ID_LABEL: &str = "trace_id";
+/// OpenMetrics limits the labels of an exemplar to 128 characters (names and values)
+const MAX_EXEMPLAR_LABELS_LEN: usize = 128;
+
+/// An observation kept as an example of its histogram bucket.
+#[derive(Debug, Clone)]
+pub struct Exemplar {
+    pub trace_id: String,
+    pub value: f64,
+    pub timestamp: SystemTime,
+}
+
+/// Identifies a histogram bucket: family name, labels of the series sorted by name,
+/// index of the bucket (the number of bounds for `+Inf`).
+pub type BucketKey = (String, Vec<(String, String)>, usize);
+
+/// Latest exemplar of every histogram bucket that has one.
+pub type Exemplars = HashMap<BucketKey, Exemplar>;
+
+/// Whether `trace_id` fits in the labels of an exemplar.
+pub fn fits_exemplar(trace_id: &str) -> bool {
+    TRACE_ID_LABEL.len() + trace_id.chars().count() <= MAX_EXEMPLAR_LABELS_LEN
+}
+
+/// Renders metric families in the OpenMetrics text format.
+pub fn encode(families: &[MetricFamily], exemplars: &Exemplars) -> String {
+    let mut out = String::new();
+
+    for family in families {
+        let name = family.name();
+        let metric_type = family.get_field_type();
+        let counter = metric_type == MetricType::COUNTER
+            || (metric_type == MetricType::GAUGE && name.ends_with("_total"));
+        let (declared, kind) = match metric_type {
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE if counter => {
+                (name.strip_suffix("_total").unwrap_or(name), "counter")
+            }
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+
+        let _ = writeln!(out, "# TYPE {} {}", declared, kind);
+        if !family.help().is_empty() {
+            let _ = writeln!(out, "# HELP {} {}", declared, escape(family.help()));
+        }
+
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            match metric_type {
+                MetricType::COUNTER => {
+                    let sample = format!("{}_total", declared);
+                    write_sample(
+                        &mut out,
+                        &sample,
+                        labels,
+                        None,
+                        metric.get_counter().get_value(),
+                    );
+                }
+                MetricType::GAUGE => {
+                    let sample = if counter {
+                        format!("{}_total", declared)
+                    } else {
+                        name.to_string()
+                    };
+                    write_sample(
+                        &mut out,
+                        &sample,
+                        labels,
+                        None,
+                        metric.get_gauge().get_value(),
+                    );
+                }
+                // Only produced by collectors this server doesn't register
+                MetricType::UNTYPED => {}
+                MetricType::HISTOGRAM => {
+                    write_histogram(&mut out, name, metric, exemplars);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        let q = format_float(quantile.quantile());
+                        let extra = Some(("quantile", q.as_str()));
+                        write_sample(&mut out, name, labels, extra, quantile.value());
+                    }
+                    let count = summary.sample_count() as f64;
+                    write_sample(
+                        &mut out,
+                        &format!("{}_sum", name),
+                        labels,
+                        None,
+                        summary.sample_sum(),
+                    );
+                    write_sample(&mut out, &format!("{}_count", name), labels, None, count);
+                }
+            }
+        }
+    }
+
+    out.push_str("# EOF\n");
+    out
+}
+
+fn write_histogram(out: &mut String, name: &str, metric: &Metric, exemplars: &Exemplars) {
+    let labels = metric.get_label();
+    let histogram = metric.get_histogram();
+    let bucket_name = format!("{}_bucket", name);
+    let mut series: Vec<(String, String)> = labels
+        .iter()
+        .map(|pair| (pair.name().to_string(), pair.value().to_string()))
+        .collect();
+    series.sort();
+    let mut key = (name.to_string(), series, 0);
+
+    let buckets = histogram.get_bucket();
+    // The +Inf bucket isn't part of the gathered buckets
+    let bounds = buckets
+        .iter()
+        .map(|bucket| (bucket.upper_bound(), bucket.cumulative_count()))
+        .filter(|(bound, _)| bound.is_finite())
+        .chain([(f64::INFINITY, histogram.get_sample_count())]);
+
+    for (index, (bound, count)) in bounds.enumerate() {
+        key.2 = index;
+        let le = format_float(bound);
+        write_sample(
+            out,
+            &bucket_name,
+            labels,
+            Some(("le", le.as_str())),
+            count as f64,
+        );
+        if let Some(exemplar) = exemplars.get(&key) {
+            // Replace the line break of the sample with the exemplar
+            out.pop();
+            let timestamp = exemplar
+                .timestamp
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            let _ = writeln!(
+                out,
+                " # {{{}=\"{}\"}} {} {:.3}",
+                TRACE_ID_LABEL,
+                escape(&exemplar.trace_id),
+                format_float(exemplar.value),
+                timestamp
+            );
+        }
+    }
+
+    let count = histogram.get_sample_count() as f64;
+    write_sample(
+        out,
+        &format!("{}_sum", name),
+        labels,
+        None,
+        histogram.get_sample_sum(),
+    );
+    write_sample(out, &format!("{}_count", name), labels, None, count);
+}
+
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    labels: &[LabelPair],
+    extra: Option<(&str, &str)>,
+    value: f64,
+) {
+    out.push_str(name);
+    let pairs = labels
+        .iter()
+        .map(|pair| (pair.name(), pair.value()))
+        .chain(extra);
+    let mut separator = "{";
+    for (label, label_value) in pairs {
+        let _ = write!(out, "{}{}=\"{}\"", separator, label, escape(label_value));
+        separator = ",";
+    }
+    if separator == "," {
+        out.push('}');
+    }
+    let _ = writeln!(out, " {}", format_float(value));
+}
+
+/// Floats as OpenMetrics expects them (`+Inf`, `1.0`).
+fn format_float(value: f64) -> String {
+    if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else if value.is_nan() {
+        "NaN".to_string()
+    } else if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{:.1}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+/// Escapes label values and help texts.
+fn escape(text: &str) -> String {
+    text.replace('\\', r"\\")
+        .replace('\n', r"\n")
+        .replace('"', "\\\"")
+}
@@ -0,0 +1,510 @@
+//! Service level objectives of the routes (`SLO_PATH`).
+//!
+//! ## Configuration
+//! The objectives are read at startup from the JSON file pointed to by `SLO_PATH`:
+//!
+//! ```json
+//! [
+//!   {"name": "users-list", "route": "GET /api/v1/users", "objective": 99.0,
+//!    "latency_ms": 200},
+//!   {"name": "orders-placed", "route": "POST /api/v1/users/:id/orders",
+//!    "objective": 99.9}
+//! ]
+//! ```
+//!
+//! A request to the route (its method and pattern, as in the route table) is good when
+//! its status is below 500 and, with `latency_ms`, it was answered within that time:
+//! the first objective reads "99% of the lists of users answered in 200 ms". The error
+//! budget is the share of bad requests the objective allows, 1% there.
+//!
+//! ## Burn rates
+//! The burn rate of a window is the share of bad requests over it, divided by the error
+//! budget: at 1 the budget lasts exactly the period of the objective, at 14.4 the budget
+//! of 30 days is gone in 2. It is computed over the last 5 and 30 minutes, 1 and 6
+//! hours, from the requests this instance served. An objective is burning:
+//!
+//! - `fast` when the rates of 1 hour and 5 minutes are both above [`FAST_BURN`]: 2% of
+//!   a 30-day budget went in the last hour
+//! - `slow` when the rates of 6 hours and 30 minutes are both above [`SLOW_BURN`]: 5%
+//!   of the budget went in the last 6 hours
+//!
+//! The short window of each pair makes the status go back to `ok` soon after the
+//! problem is fixed.
+//!
+//! ## Alerts
+//! The objectives are evaluated every minute. One starting to burn, or burning faster,
+//! is sent to the webhooks as a `slo.burning` event, and as `slo.recovered` once back
+//! to `ok` (see the `webhooks` module). Each instance alerts about its own requests.
+
+use std::collections::HashSet;
+use std::fs;
+use std::mem;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use hyper::Method;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use tracing::{info, warn};
+
+use super::METRICS;
+use crate::router::router;
+use crate::shutdown::stopping;
+use crate::webhooks;
+
+/// Burn rate of the 1 hour and 5 minute windows above which an objective burns fast
+pub const FAST_BURN: f64 = 14.4;
+
+/// Burn rate of the 6 hour and 30 minute windows above which an objective burns slowly
+pub const SLOW_BURN: f64 = 6.0;
+
+/// Minutes of requests counted, the longest window
+const MINUTES: usize = 6 * 60;
+
+/// The windows of the burn rates, and their length in minutes
+const WINDOWS: [(&str, u64); 4] = [("5m", 5), ("30m", 30), ("1h", 60), ("6h", 360)];
+
+/// Time between two evaluations of the alerts
+const EVALUATION_INTERVAL: Duration = Duration::from_secs(60);
+
+// Set once at startup, empty without SLO_PATH
+static TRACKERS: OnceLock<Vec<Tracker>> = OnceLock::new();
+
+// The minutes of the counts start with the process
+static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// An objective of the configuration file.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Objective {
+    pub name: String,
+    /// `METHOD /pattern`
+    pub route: String,
+    /// Percentage of good requests, below 100
+    pub objective: f64,
+    /// Longest time of a good request, any without it
+    #[serde(default)]
+    pub latency_ms: Option<u64>,
+}
+
+impl Objective {
+    /// The method and pattern of the route.
+    fn parse_route(&self) -> Result<(Method, String), String> {
+        let route = self
+            .route
+            .trim()
+            .split_once(' ')
+            .and_then(|(method, pattern)| {
+                let method = method.parse::<Method>().ok()?;
+                let pattern = pattern.trim();
+                pattern
+                    .starts_with('/')
+                    .then(|| (method, pattern.to_string()))
+            });
+        let Some((method, pattern)) = route else {
+            return Err(format!(
+                "SLO '{}': route must be METHOD /pattern",
+                self.name
+            ));
+        };
+        if !router()
+            .routes()
+            .any(|route| route.method == method && route.pattern == pattern)
+        {
+            return Err(format!(
+                "SLO '{}': no route {} {}",
+                self.name, method, pattern
+            ));
+        }
+        Ok((method, pattern))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("SLO names can't be empty".to_string());
+        }
+        if !(self.objective > 0.0 && self.objective < 100.0) {
+            return Err(format!(
+                "SLO '{}': objective must be a percentage between 0 and 100, excluded",
+                self.name
+            ));
+        }
+        if self.latency_ms == Some(0) {
+            return Err(format!(
+                "SLO '{}': latency_ms must be greater than 0",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+
+    /// Share of bad requests allowed.
+    fn budget(&self) -> f64 {
+        1.0 - self.objective / 100.0
+    }
+}
+
+/// How fast an objective burns its error budget, from the slowest.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Slow,
+    Fast,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Slow => "slow",
+            Status::Fast => "fast",
+        }
+    }
+
+    /// Value of the `slo_status` metric.
+    pub fn level(self) -> i64 {
+        self as i64
+    }
+
+    /// The status of an objective given its burn rates, in the order of `WINDOWS`.
+    fn of(rates: [f64; 4]) -> Status {
+        let [five_minutes, thirty_minutes, one_hour, six_hours] = rates;
+        if one_hour > FAST_BURN && five_minutes > FAST_BURN {
+            Status::Fast
+        } else if six_hours > SLOW_BURN && thirty_minutes > SLOW_BURN {
+            Status::Slow
+        } else {
+            Status::Ok
+        }
+    }
+}
+
+/// Requests of a minute.
+#[derive(Debug, Clone, Copy, Default)]
+struct Minute {
+    /// Minutes since `START`
+    at: u64,
+    good: u64,
+    bad: u64,
+}
+
+/// Requests of the last `MINUTES` minutes, one slot per minute reused as time goes.
+#[derive(Debug)]
+struct Counts {
+    minutes: Vec<Minute>,
+}
+
+impl Counts {
+    fn new() -> Self {
+        Counts {
+            minutes: vec![Minute::default(); MINUTES],
+        }
+    }
+
+    fn record(&mut self, now: u64, good: bool) {
+        let slot = &mut self.minutes[(now % MINUTES as u64) as usize];
+        if slot.at != now {
+            *slot = Minute {
+                at: now,
+                ..Minute::default()
+            };
+        }
+        if good {
+            slot.good += 1;
+        } else {
+            slot.bad += 1;
+        }
+    }
+
+    /// Good and bad requests of the last `length` minutes, the current one included.
+    fn window(&self, now: u64, length: u64) -> (u64, u64) {
+        self.minutes
+            .iter()
+            .filter(|minute| minute.at <= now && now - minute.at < length)
+            .fold((0, 0), |(good, bad), minute| {
+                (good + minute.good, bad + minute.bad)
+            })
+    }
+}
+
+/// An objective, the requests of its route and the status last alerted.
+struct Tracker {
+    objective: Objective,
+    method: Method,
+    pattern: String,
+    counts: Mutex<Counts>,
+    alerted: Mutex<Status>,
+}
+
+impl Tracker {
+    fn report(&self, now: u64) -> SloReport<'_> {
+        let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let mut rates = [0.0; 4];
+        for (rate, (_, length)) in rates.iter_mut().zip(WINDOWS) {
+            let (good, bad) = counts.window(now, length);
+            if good + bad > 0 {
+                *rate = bad as f64 / (good + bad) as f64 / self.objective.budget();
+            }
+        }
+        let (good, bad) = counts.window(now, MINUTES as u64);
+
+        SloReport {
+            name: &self.objective.name,
+            route: &self.objective.route,
+            objective: self.objective.objective,
+            latency_ms: self.objective.latency_ms,
+            status: Status::of(rates),
+            burn_rates: WINDOWS
+                .iter()
+                .zip(rates)
+                .map(|((window, _), rate)| (window.to_string(), json!(rate)))
+                .collect(),
+            requests: good + bad,
+            bad_requests: bad,
+        }
+    }
+}
+
+/// An objective and how it's doing, for `GET /admin/slo` and the metrics.
+#[derive(Serialize, Debug)]
+pub struct SloReport<'a> {
+    pub name: &'a str,
+    pub route: &'a str,
+    pub objective: f64,
+    pub latency_ms: Option<u64>,
+    pub status: Status,
+    /// Burn rate of each window: `5m`, `30m`, `1h` and `6h`
+    pub burn_rates: Map<String, Value>,
+    /// Requests of the last 6 hours
+    pub requests: u64,
+    /// Bad requests of the last 6 hours
+    pub bad_requests: u64,
+}
+
+/// Loads the objectives of `SLO_PATH`, and starts evaluating their alerts.
+/// This function should be called once at application startup, after `init_jobs`.
+///
+/// # Arguments
+///
+/// * `path` - Path of the JSON objectives file, `None` when no route has one
+///
+/// # Returns
+///
+/// * `Result<usize, String>` - Number of objectives, or an error message
+pub fn init_slos(path: Option<&Path>) -> Result<usize, String> {
+    let objectives: Vec<Objective> = match path {
+        Some(path) => {
+            let content = fs::read_to_string(path)
+                .map_err(|e| format!("Unable to read SLOs '{}': {}", path.display(), e))?;
+            serde_json::from_str(&content)
+                .map_err(|e| format!("Invalid SLOs '{}': {}", path.display(), e))?
+        }
+        None => Vec::new(),
+    };
+    let mut names = HashSet::new();
+    let mut trackers = Vec::with_capacity(objectives.len());
+    for objective in objectives {
+        objective.validate()?;
+        let (method, pattern) = objective.parse_route()?;
+        if !names.insert(objective.name.clone()) {
+            return Err(format!("SLO '{}' is defined twice", objective.name));
+        }
+        trackers.push(Tracker {
+            objective,
+            method,
+            pattern,
+            counts: Mutex::new(Counts::new()),
+            alerted: Mutex::new(Status::Ok),
+        });
+    }
+
+    let count = trackers.len();
+    if TRACKERS.set(trackers).is_err() {
+        warn!("Attempt to reload the SLOs ignored");
+        return Ok(count);
+    }
+    if count > 0 {
+        LazyLock::force(&START);
+        tokio::spawn(evaluate_periodically());
+    }
+    Ok(count)
+}
+
+fn trackers() -> &'static [Tracker] {
+    TRACKERS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Minutes since `START`.
+fn now() -> u64 {
+    START.elapsed().as_secs() / 60
+}
+
+/// Counts a request served by a route with objectives.
+pub fn observe(method: &Method, route: &str, status: u16, elapsed: Duration) {
+    for tracker in trackers() {
+        if tracker.method != method || tracker.pattern != route {
+            continue;
+        }
+        let good = status < 500
+            && tracker
+                .objective
+                .latency_ms
+                .is_none_or(|limit| elapsed <= Duration::from_millis(limit));
+        tracker
+            .counts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .record(now(), good);
+        METRICS
+            .slo_requests
+            .with_label_values(&[
+                tracker.objective.name.as_str(),
+                if good { "good" } else { "bad" },
+            ])
+            .inc();
+    }
+}
+
+/// How every objective is doing, in the order of `SLO_PATH`.
+pub fn report() -> Vec<SloReport<'static>> {
+    let now = now();
+    trackers()
+        .iter()
+        .map(|tracker| tracker.report(now))
+        .collect()
+}
+
+/// The event to send when the status of an objective goes from `previous` to
+/// `current`, if any.
+fn alert_type(previous: Status, current: Status) -> Option<&'static str> {
+    if current > previous {
+        Some("slo.burning")
+    } else if current == Status::Ok && previous != Status::Ok {
+        Some("slo.recovered")
+    } else {
+        None
+    }
+}
+
+/// Evaluates the alerts every `EVALUATION_INTERVAL`, until the shutdown.
+async fn evaluate_periodically() {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(EVALUATION_INTERVAL) => {}
+            _ = stopping() => return,
+        }
+        let now = now();
+        for tracker in trackers() {
+            let report = tracker.report(now);
+            let previous = mem::replace(
+                &mut *tracker.alerted.lock().unwrap_or_else(|e| e.into_inner()),
+                report.status,
+            );
+            let Some(event_type) = alert_type(previous, report.status) else {
+                continue;
+            };
+
+            let rate = |window: &str| report.burn_rates[window].as_f64().unwrap_or_default();
+            if report.status == Status::Ok {
+                info!("SLO '{}' recovered", report.name);
+            } else {
+                warn!(
+                    "SLO '{}' burning {}: burn rate {:.1} over 1h, {:.1} over 6h",
+                    report.name,
+                    report.status.as_str(),
+                    rate("1h"),
+                    rate("6h")
+                );
+            }
+            let data = json!({
+                "slo": report.name,
+                "route": report.route,
+                "objective": report.objective,
+                "status": report.status.as_str(),
+                "burn_rate_5m": rate("5m"),
+                "burn_rate_30m": rate("30m"),
+                "burn_rate_1h": rate("1h"),
+                "burn_rate_6h": rate("6h"),
+            });
+            if let Err(e) = webhooks::alert(event_type, data).await {
+                warn!("{} not sent to the webhooks: {}", event_type, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(objective: f64) -> Tracker {
+        Tracker {
+            objective: Objective {
+                name: "test".to_string(),
+                route: "GET /users".to_string(),
+                objective,
+                latency_ms: None,
+            },
+            method: Method::GET,
+            pattern: "/users".to_string(),
+            counts: Mutex::new(Counts::new()),
+            alerted: Mutex::new(Status::Ok),
+        }
+    }
+
+    fn record(tracker: &Tracker, minute: u64, good: u64, bad: u64) {
+        let mut counts = tracker.counts.lock().unwrap();
+        for _ in 0..good {
+            counts.record(minute, true);
+        }
+        for _ in 0..bad {
+            counts.record(minute, false);
+        }
+    }
+
+    fn rate(report: &SloReport<'_>, window: &str) -> f64 {
+        report.burn_rates[window].as_f64().unwrap()
+    }
+
+    #[test]
+    fn burn_rates_are_the_bad_share_over_the_budget() {
+        let tracker = tracker(99.0);
+        // 2% bad over the last 6 hours, 20% in the current minute
+        record(&tracker, 100, 900, 0);
+        record(&tracker, 400, 80, 20);
+
+        let report = tracker.report(400);
+        assert!((rate(&report, "5m") - 20.0).abs() < 1e-9);
+        assert!((rate(&report, "1h") - 20.0).abs() < 1e-9);
+        assert!((rate(&report, "6h") - 2.0).abs() < 1e-9);
+        assert_eq!((report.requests, report.bad_requests), (1000, 20));
+        assert_eq!(report.status, Status::Fast);
+
+        // The minutes older than 6 hours are forgotten, slots included
+        record(&tracker, 400 + MINUTES as u64, 10, 0);
+        let report = tracker.report(400 + MINUTES as u64);
+        assert_eq!((report.requests, report.bad_requests), (10, 0));
+        assert_eq!(report.status, Status::Ok);
+    }
+
+    #[test]
+    fn both_windows_of_a_pair_must_burn() {
+        // A burst of errors in the last minute only
+        assert_eq!(Status::of([100.0, 16.0, 2.0, 1.0]), Status::Ok);
+        // Fixed 10 minutes ago: the short windows are back under their threshold
+        assert_eq!(Status::of([0.0, 5.0, 14.0, 7.0]), Status::Ok);
+        assert_eq!(Status::of([7.0, 7.0, 7.0, 7.0]), Status::Slow);
+        assert_eq!(Status::of([15.0, 15.0, 15.0, 15.0]), Status::Fast);
+    }
+
+    #[test]
+    fn alerts_are_sent_on_escalations_and_recoveries() {
+        assert_eq!(alert_type(Status::Ok, Status::Slow), Some("slo.burning"));
+        assert_eq!(alert_type(Status::Slow, Status::Fast), Some("slo.burning"));
+        assert_eq!(alert_type(Status::Fast, Status::Slow), None);
+        assert_eq!(alert_type(Status::Fast, Status::Ok), Some("slo.recovered"));
+        assert_eq!(alert_type(Status::Ok, Status::Ok), None);
+    }
+}
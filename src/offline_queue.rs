@@ -0,0 +1,539 @@
+//! Writes accepted while the database is down, applied once it's back
+//! (store-and-forward).
+//!
+//! With `OFFLINE_QUEUE_PATH`, the routes registered with `Router::queue_when_offline`
+//! don't fail with 503 while the breaker of the primary is open (see `db::breaker`): a
+//! request sent with an `Idempotency-Key` is appended to a local file, synced to disk,
+//! and answered with 202 Accepted and the ID it was queued with. A background task
+//! replays the queued requests in order once the database answers again, through the
+//! router, as if their clients sent them again: same method, path, headers and body,
+//! and the same caller, even if its access token expired in the meantime.
+//!
+//! ## Safeguards
+//! - Only the routes opting in are queued, whose requests can be applied twice (`PUT`,
+//!   `DELETE`): a request queued but not yet marked as replayed when the process stops
+//!   is replayed again on restart. Without `Idempotency-Key`, a request fails with 503
+//!   as usual.
+//! - A key the caller already queued is answered with the same 202 and not queued
+//!   again, also after the database is back and until the request is replayed. A key
+//!   reused for another method, path or body fails with 409 `IDEMPOTENCY_KEY_REUSED`.
+//! - A request older than `OFFLINE_QUEUE_MAX_AGE` when the database is back is dropped
+//!   rather than applied late. Past `OFFLINE_QUEUE_MAX_ENTRIES` waiting requests, the
+//!   next ones fail with 503 again.
+//! - The role a route requires is checked before the request is queued, against the
+//!   last role read for the caller, and again when it's replayed (see `roles`).
+//! - A replayed request is never queued again: when it fails with 503 or 429, the
+//!   replay stops and starts over with it later. Any other answer is final, failures
+//!   (4xx, 5xx) are only logged.
+//!
+//! ## File
+//! The file is a write-ahead log of JSON lines: a `queued` record per request, and a
+//! `done` record once it was replayed or dropped. It is read back on startup, a last
+//! line cut short by a crash ignored, and emptied whenever no request is left. It holds
+//! the headers of the requests, access tokens included, and is only readable by its
+//! owner.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::client::conn::http1 as client_http1;
+use hyper::header::{CONNECTION, CONTENT_LENGTH, HeaderName, TE, TRANSFER_ENCODING, UPGRADE};
+use hyper::server::conn::http1 as server_http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::auth::AuthUser;
+use crate::config::OfflineQueueConfig;
+use crate::db;
+use crate::error::{AppError, ErrorCode};
+use crate::forwarded::Scheme;
+use crate::metrics;
+use crate::router::{
+    Body, ClientAddr, HandlerResult, collect_body, json_response, limits, serve_request,
+};
+use crate::shutdown::stopping;
+
+/// Header naming a write, unique for its caller
+pub const IDEMPOTENCY_KEY_HEADER: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Longest `Idempotency-Key`, in bytes
+const MAX_KEY_LEN: usize = 255;
+
+/// Time between two checks of the database while requests are queued
+const REPLAY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `Retry-After` of the requests refused by a full queue
+const FULL_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Headers of a connection rather than of a request, not replayed
+const CONNECTION_HEADERS: [HeaderName; 5] =
+    [CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING, TE, UPGRADE];
+
+// Set once at startup, unset without OFFLINE_QUEUE_PATH
+static QUEUE: OnceLock<Mutex<Queue>> = OnceLock::new();
+
+/// Marks a request replayed from the queue, with the caller it was queued by.
+///
+/// Only inserted in the request extensions by the replay, a client can't send it.
+#[derive(Debug, Clone, Copy)]
+pub struct Replay {
+    pub user: Option<AuthUser>,
+}
+
+/// What the router does with a request of a route queuing its writes.
+pub enum Offer {
+    /// Handle it now
+    Handle(Request<Incoming>),
+    /// It's queued, or was already: 202 Accepted, or the error refusing it
+    Queued(HandlerResult),
+}
+
+/// A queued request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    id: Uuid,
+    /// `Idempotency-Key` of the request
+    key: String,
+    /// Caller, `None` on the routes without authentication
+    user: Option<i32>,
+    method: String,
+    /// Path and query
+    uri: String,
+    headers: Vec<(String, String)>,
+    /// Body, in base64
+    body: String,
+    client: SocketAddr,
+    https: bool,
+    /// Unix time the request was queued at
+    queued_at: u64,
+}
+
+/// A line of the file.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "record", rename_all = "snake_case")]
+enum Record {
+    Queued(Entry),
+    Done { id: Uuid },
+}
+
+struct Queue {
+    file: File,
+    /// Requests waiting to be replayed, in the order they were queued
+    pending: VecDeque<Entry>,
+    max_entries: usize,
+    max_age: Duration,
+}
+
+impl Queue {
+    /// The request the caller queued with `key`.
+    fn find(&self, key: &str, user: Option<i32>) -> Option<&Entry> {
+        self.pending
+            .iter()
+            .find(|entry| entry.key == key && entry.user == user)
+    }
+
+    /// Appends a record to the file, synced to disk before returning.
+    async fn append(&mut self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line).await?;
+        self.file.sync_data().await
+    }
+
+    /// Removes the first request, replayed or dropped.
+    async fn finish(&mut self, id: Uuid) -> io::Result<()> {
+        self.pending.pop_front();
+        if self.pending.is_empty() {
+            // Appends go on at the new end of the file
+            self.file.set_len(0).await?;
+            self.file.sync_data().await
+        } else {
+            self.append(&Record::Done { id }).await
+        }
+    }
+}
+
+/// Opens the queue of `OFFLINE_QUEUE_PATH` and starts replaying the requests queued
+/// before a restart. This function should be called once, after the database pool
+/// is started.
+///
+/// # Returns
+///
+/// * `Result<usize, String>` - The number of requests queued before a restart, or the
+///   error of an unreadable or unwritable file
+pub async fn init_offline_queue(config: Option<&OfflineQueueConfig>) -> Result<usize, String> {
+    let Some(config) = config else {
+        return Ok(0);
+    };
+    let error = |e: io::Error| format!("'{}': {}", config.path.display(), e);
+
+    let pending = load(&config.path).map_err(error)?;
+    compact(&config.path, &pending).map_err(error)?;
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let queue = Queue {
+        file: options.open(&config.path).await.map_err(error)?,
+        pending,
+        max_entries: config.max_entries,
+        max_age: config.max_age,
+    };
+    let count = queue.pending.len();
+    if QUEUE.set(Mutex::new(queue)).is_err() {
+        warn!("Attempt to reset the offline queue ignored");
+        return Ok(0);
+    }
+    tokio::spawn(replay_loop());
+    Ok(count)
+}
+
+/// Queues a request of a route queuing its writes (see `Router::queue_when_offline`),
+/// if the database is down and it has an `Idempotency-Key`.
+pub async fn offer(req: Request<Incoming>, user: Option<AuthUser>) -> Offer {
+    let Some(queue) = QUEUE.get() else {
+        return Offer::Handle(req);
+    };
+    let Some(key) = req.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return Offer::Handle(req);
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => key.to_string(),
+        _ => {
+            return Offer::Queued(Err(AppError::Validation(format!(
+                "Idempotency-Key must have 1 to {} visible ASCII characters",
+                MAX_KEY_LEN
+            ))));
+        }
+    };
+    let user = user.map(|user| user.id);
+
+    // Waiting to be replayed, even if the database is back: handling it now would
+    // apply it twice
+    let queued = queue.lock().await.find(&key, user).cloned();
+    if let Some(entry) = queued {
+        return Offer::Queued(accepted(&entry, req).await);
+    }
+    if !db::primary_is_down() {
+        return Offer::Handle(req);
+    }
+    Offer::Queued(enqueue(queue, req, key, user).await)
+}
+
+/// Appends a request to the queue.
+async fn enqueue(
+    queue: &Mutex<Queue>,
+    req: Request<Incoming>,
+    key: String,
+    user: Option<i32>,
+) -> HandlerResult {
+    let headers = req
+        .headers()
+        .iter()
+        .filter(|(name, _)| !CONNECTION_HEADERS.contains(name))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let client = req
+        .extensions()
+        .get::<ClientAddr>()
+        .map_or(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), |addr| addr.0);
+    let https = req.extensions().get::<Scheme>() == Some(&Scheme::Https);
+    let method = req.method().to_string();
+    let uri = path_and_query(&req);
+    let body = collect_body(req, limits::max_body_size()).await?;
+    let entry = Entry {
+        id: Uuid::new_v4(),
+        key,
+        user,
+        method,
+        uri,
+        headers,
+        body: STANDARD.encode(&body),
+        client,
+        https,
+        queued_at: unix_time(),
+    };
+
+    let mut queue = queue.lock().await;
+    // The same request sent twice at the same time
+    if let Some(queued) = queue.find(&entry.key, user) {
+        return same_request(queued, &entry.method, &entry.uri, &entry.body)
+            .map(|()| accepted_response(queued));
+    }
+    if queue.pending.len() >= queue.max_entries {
+        return Err(AppError::Unavailable(
+            "Database unavailable, and too many writes are queued".to_string(),
+            FULL_RETRY_AFTER,
+        ));
+    }
+    queue
+        .append(&Record::Queued(entry.clone()))
+        .await
+        .map_err(|e| AppError::Internal(format!("Unable to queue the request: {}", e)))?;
+    info!(
+        "{} {} queued as {} while the database is down",
+        entry.method, entry.uri, entry.id
+    );
+    metrics::observe_offline_write("queued");
+    let res = accepted_response(&entry);
+    queue.pending.push_back(entry);
+    Ok(res)
+}
+
+/// The 202 of a request queued already, if it's the same request.
+async fn accepted(entry: &Entry, req: Request<Incoming>) -> HandlerResult {
+    let method = req.method().to_string();
+    let uri = path_and_query(&req);
+    let body = collect_body(req, limits::max_body_size()).await?;
+    same_request(entry, &method, &uri, &STANDARD.encode(&body))?;
+    Ok(accepted_response(entry))
+}
+
+/// Fails unless the queued request has the method, URI and body (in base64) of the one
+/// sent with its key.
+fn same_request(entry: &Entry, method: &str, uri: &str, body: &str) -> Result<(), AppError> {
+    if entry.method != method || entry.uri != uri || entry.body != body {
+        return Err(AppError::Conflict(
+            ErrorCode::IdempotencyKeyReused,
+            "The Idempotency-Key was sent with another request".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Path and query of a request, as they're replayed.
+fn path_and_query<B>(req: &Request<B>) -> String {
+    req.uri()
+        .path_and_query()
+        .map_or_else(|| req.uri().path().to_string(), |path| path.to_string())
+}
+
+fn accepted_response(entry: &Entry) -> Response<Body> {
+    json_response(
+        StatusCode::ACCEPTED,
+        json!({"id": entry.id, "status": "queued"}),
+    )
+}
+
+/// Replays the queued requests whenever the database is up, until a shutdown.
+async fn replay_loop() {
+    let mut interval = tokio::time::interval(REPLAY_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = stopping() => break,
+        }
+        if let Some(queue) = QUEUE.get()
+            && !db::primary_is_down()
+        {
+            replay(queue).await;
+        }
+    }
+}
+
+/// Replays the queued requests in order, until none is left or the database is down
+/// again.
+async fn replay(queue: &Mutex<Queue>) {
+    loop {
+        let (entry, max_age) = {
+            let queue = queue.lock().await;
+            match queue.pending.front() {
+                Some(entry) => (entry.clone(), queue.max_age),
+                None => return,
+            }
+        };
+
+        let outcome = if unix_time().saturating_sub(entry.queued_at) > max_age.as_secs() {
+            warn!(
+                "{} {} queued as {} dropped, older than {}s",
+                entry.method,
+                entry.uri,
+                entry.id,
+                max_age.as_secs()
+            );
+            "expired"
+        } else {
+            match send(&entry).await {
+                // Replayed again on the next tick
+                Ok(StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS) => return,
+                Ok(status) if status.is_success() => {
+                    info!(
+                        "{} {} queued as {} applied",
+                        entry.method, entry.uri, entry.id
+                    );
+                    "applied"
+                }
+                Ok(status) => {
+                    warn!(
+                        "{} {} queued as {} failed with {}",
+                        entry.method, entry.uri, entry.id, status
+                    );
+                    "failed"
+                }
+                Err(e) => {
+                    warn!("Replay of {} interrupted: {}", entry.id, e);
+                    return;
+                }
+            }
+        };
+        metrics::observe_offline_write(outcome);
+        if let Err(e) = queue.lock().await.finish(entry.id).await {
+            warn!(
+                "Replay of {} not recorded, it will be replayed again after a restart: {}",
+                entry.id, e
+            );
+        }
+    }
+}
+
+/// Sends a queued request to the router, over an in-memory connection.
+///
+/// # Returns
+///
+/// * `Result<StatusCode, String>` - The status of the response, or the error of a
+///   request that couldn't be sent
+async fn send(entry: &Entry) -> Result<StatusCode, String> {
+    let body = STANDARD.decode(&entry.body).map_err(|e| e.to_string())?;
+    let mut builder = Request::builder()
+        .method(entry.method.as_str())
+        .uri(entry.uri.as_str());
+    for (name, value) in &entry.headers {
+        builder = builder.header(name, value);
+    }
+    let req = builder
+        .body(Full::new(Bytes::from(body)))
+        .map_err(|e| e.to_string())?;
+
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    let addr = ClientAddr(entry.client);
+    let scheme = if entry.https {
+        Scheme::Https
+    } else {
+        Scheme::Http
+    };
+    let replay = Replay {
+        user: entry.user.map(|id| AuthUser { id }),
+    };
+    let service = service_fn(move |mut req: Request<Incoming>| {
+        req.extensions_mut().insert(addr);
+        req.extensions_mut().insert(scheme);
+        req.extensions_mut().insert(replay);
+        serve_request(req)
+    });
+    tokio::spawn(server_http1::Builder::new().serve_connection(TokioIo::new(server_io), service));
+
+    let (mut sender, connection) = client_http1::handshake(TokioIo::new(client_io))
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::spawn(connection);
+    let res = sender.send_request(req).await.map_err(|e| e.to_string())?;
+    Ok(res.status())
+}
+
+/// The requests of the file not replayed yet, in the order they were queued.
+fn load(path: &Path) -> io::Result<VecDeque<Entry>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(VecDeque::new()),
+        Err(e) => return Err(e),
+    };
+    let mut pending = VecDeque::new();
+    let lines = content.lines().filter(|line| !line.trim().is_empty());
+    for (i, line) in lines.enumerate() {
+        match serde_json::from_str::<Record>(line) {
+            Ok(Record::Queued(entry)) => pending.push_back(entry),
+            Ok(Record::Done { id }) => pending.retain(|entry: &Entry| entry.id != id),
+            // Cut short by a crash, the request wasn't acknowledged
+            Err(e) => warn!("Line {} of '{}' ignored: {}", i + 1, path.display(), e),
+        }
+    }
+    Ok(pending)
+}
+
+/// Rewrites the file with the requests not replayed yet only.
+fn compact(path: &Path, pending: &VecDeque<Entry>) -> io::Result<()> {
+    let mut content = Vec::new();
+    for entry in pending {
+        serde_json::to_writer(&mut content, &Record::Queued(entry.clone()))?;
+        content.push(b'\n');
+    }
+    let temp = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temp)?;
+    io::Write::write_all(&mut file, &content)?;
+    file.sync_all()?;
+    std::fs::rename(&temp, path)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &str) -> Entry {
+        Entry {
+            id: Uuid::new_v4(),
+            key: key.to_string(),
+            user: Some(1),
+            method: "PUT".to_string(),
+            uri: "/api/v1/wishlist/x".to_string(),
+            headers: Vec::new(),
+            body: String::new(),
+            client: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            https: false,
+            queued_at: unix_time(),
+        }
+    }
+
+    #[test]
+    fn loads_the_requests_not_replayed_and_skips_a_torn_line() {
+        let path = std::env::temp_dir().join(format!("offline-queue-{}.jsonl", Uuid::new_v4()));
+        let (first, second) = (entry("a"), entry("b"));
+        let mut content = String::new();
+        for record in [
+            Record::Queued(first.clone()),
+            Record::Queued(second.clone()),
+            Record::Done { id: first.id },
+        ] {
+            content.push_str(&serde_json::to_string(&record).unwrap());
+            content.push('\n');
+        }
+        content.push_str(r#"{"record":"queued","id":"#);
+        std::fs::write(&path, content).unwrap();
+
+        let pending = load(&path).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, second.id);
+
+        // Only the pending request is kept
+        compact(&path, &pending).unwrap();
+        let pending = load(&path).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].key, "b");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
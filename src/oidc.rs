@@ -0,0 +1,567 @@
+//! OpenID Connect provider (`OIDC_ISSUER`), so internal apps log their users in with
+//! the accounts of this backend instead of keeping their own.
+//!
+//! ## Flow
+//! The authorization code flow with PKCE, and only that:
+//!
+//! 1. The app sends the browser to `/oauth/authorize` with its `client_id`, one of its
+//!    `redirect_uris`, `scope=openid ...`, a `state`, a `nonce` and the S256
+//!    `code_challenge` of a secret verifier.
+//! 2. The user logs in with the email and password of the account, in a form of the
+//!    backend, and the browser is sent back to the `redirect_uri` with a `code`.
+//! 3. The app exchanges the `code` and the verifier at `/oauth/token` for an ID token,
+//!    and an access token for the app: a client token of the granted scopes (see
+//!    `auth`), accepted by `/oauth/userinfo` only, never by the routes of the API.
+//!
+//! The codes are single use and expire after `CODE_LIFETIME_SECS`; they are kept
+//! hashed in the database, so any instance can take them.
+//!
+//! ## Keys
+//! The ID tokens are signed with ES256, with a P-256 key created by the first instance
+//! to start and kept in the database for the others. Its public half is served at
+//! `/oauth/jwks`, and the endpoints at `/.well-known/openid-configuration`.
+//!
+//! ## Clients
+//! The apps are listed in the JSON file of `OIDC_CLIENTS_PATH`, read at startup:
+//!
+//! ```json
+//! [
+//!   {"client_id": "wiki", "name": "Wiki", "redirect_uris": ["https://wiki.example.com/callback"]},
+//!   {"client_id": "crm", "redirect_uris": ["https://crm.example.com/oidc"],
+//!    "client_secret": "..."}
+//! ]
+//! ```
+//!
+//! An app with a `client_secret` must send it to `/oauth/token`, in the form or with
+//! HTTP Basic; the others are public clients, a single-page app for instance, which
+//! PKCE protects.
+
+use std::collections::HashSet;
+use std::fs;
+use std::sync::OnceLock;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+use ring::digest::{SHA256, digest};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+use crate::auth::{ClientGrant, issue_client_token};
+use crate::config::OidcConfig;
+use crate::db::Db;
+use crate::error::{AppError, ErrorCode};
+use crate::repository::oidc::{NewAuthorizationCode, OidcRepository, PgOidcRepo, SigningKey};
+use crate::repository::users::{PgUserRepo, UserRepository};
+
+/// Seconds an authorization code can be exchanged for tokens
+const CODE_LIFETIME_SECS: i32 = 60;
+
+/// Scopes granted, the others requested are ignored
+const SCOPES: [&str; 3] = ["openid", "profile", "email"];
+
+// Set once at startup, unset without OIDC_ISSUER
+static PROVIDER: OnceLock<Provider> = OnceLock::new();
+
+/// An app allowed to log its users in.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Client {
+    pub client_id: String,
+    /// Shown on the login form, the `client_id` otherwise
+    name: Option<String>,
+    /// Where the browser can be sent back, compared exactly
+    redirect_uris: Vec<String>,
+    /// `None` for a public client
+    client_secret: Option<String>,
+}
+
+impl Client {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.client_id)
+    }
+}
+
+struct Provider {
+    issuer: String,
+    clients: Vec<Client>,
+    kid: String,
+    key: EncodingKey,
+    /// Public key, as a JWK
+    jwk: Value,
+}
+
+/// Claims of the ID tokens.
+#[derive(Serialize, Debug)]
+struct IdClaims {
+    iss: String,
+    /// Public ID of the user
+    sub: String,
+    aud: String,
+    iat: u64,
+    exp: u64,
+    auth_time: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    /// With the `profile` scope
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// With the `email` scope
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email_verified: Option<bool>,
+}
+
+/// Parameters of `/oauth/authorize`.
+#[derive(Deserialize, Debug)]
+pub struct AuthorizationRequest {
+    #[serde(default)]
+    pub response_type: String,
+    #[serde(default)]
+    pub client_id: String,
+    #[serde(default)]
+    pub redirect_uri: String,
+    #[serde(default)]
+    pub scope: String,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    #[serde(default)]
+    pub code_challenge: String,
+    pub code_challenge_method: Option<String>,
+}
+
+/// Form of `/oauth/token`.
+#[derive(Deserialize, Debug)]
+pub struct TokenRequest {
+    #[serde(default)]
+    grant_type: String,
+    #[serde(default)]
+    code: String,
+    #[serde(default)]
+    redirect_uri: String,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    #[serde(default)]
+    code_verifier: String,
+}
+
+/// An error of the OAuth 2.0 protocol, sent as `{"error", "error_description"}` by the
+/// token endpoint and as query parameters of the redirect by the authorization one.
+#[derive(Debug)]
+pub enum OAuthError {
+    Protocol {
+        /// `invalid_request`, `invalid_client`, `invalid_grant`, ...
+        error: &'static str,
+        description: String,
+    },
+    App(AppError),
+}
+
+impl OAuthError {
+    fn new(error: &'static str, description: impl Into<String>) -> Self {
+        OAuthError::Protocol {
+            error,
+            description: description.into(),
+        }
+    }
+}
+
+impl From<AppError> for OAuthError {
+    fn from(e: AppError) -> Self {
+        OAuthError::App(e)
+    }
+}
+
+/// Loads the clients and the signing key, creating it if no instance did.
+/// This function should be called once at application startup, after the migrations.
+///
+/// # Arguments
+///
+/// * `config` - The provider, `None` when this backend isn't one
+///
+/// # Returns
+///
+/// * `Result<usize, String>` - Number of clients, or an error message
+pub async fn init_oidc(config: Option<&OidcConfig>) -> Result<usize, String> {
+    let Some(config) = config else {
+        return Ok(0);
+    };
+    let path = &config.clients_path;
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read OIDC clients '{}': {}", path.display(), e))?;
+    let clients: Vec<Client> = serde_json::from_str(&content)
+        .map_err(|e| format!("Invalid OIDC clients '{}': {}", path.display(), e))?;
+    let mut ids = HashSet::new();
+    for client in &clients {
+        if !ids.insert(client.client_id.as_str()) {
+            return Err(format!(
+                "OIDC client '{}' is listed twice",
+                client.client_id
+            ));
+        }
+        if client.redirect_uris.is_empty() {
+            return Err(format!(
+                "OIDC client '{}' has no redirect_uris",
+                client.client_id
+            ));
+        }
+    }
+
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+        .map_err(|e| format!("Unable to generate the OIDC signing key: {}", e))?;
+    let candidate = signing_key(pkcs8.as_ref().to_vec(), &rng)?;
+    let saved = PgOidcRepo::default()
+        .signing_key(&candidate)
+        .await
+        .map_err(|e| format!("Unable to save the OIDC signing key: {}", e))?;
+    let key_pair =
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &saved.private_key, &rng)
+            .map_err(|e| format!("Invalid OIDC signing key: {}", e))?;
+
+    let count = clients.len();
+    let provider = Provider {
+        issuer: config.issuer.clone(),
+        clients,
+        jwk: jwk(&key_pair, &saved.kid),
+        kid: saved.kid,
+        key: EncodingKey::from_ec_der(&saved.private_key),
+    };
+    info!("OpenID Connect provider at {}", provider.issuer);
+    if PROVIDER.set(provider).is_err() {
+        warn!("Attempt to reset the OpenID Connect provider ignored");
+    }
+    Ok(count)
+}
+
+/// The signing key of the PKCS#8 document `pkcs8`, its ID the JWK thumbprint.
+fn signing_key(pkcs8: Vec<u8>, rng: &SystemRandom) -> Result<SigningKey, String> {
+    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, rng)
+        .map_err(|e| format!("Invalid OIDC signing key: {}", e))?;
+    // RFC 7638: the required members in lexicographic order, without spaces
+    let (x, y) = coordinates(&key_pair);
+    let members = json!({"crv": "P-256", "kty": "EC", "x": x, "y": y});
+    let thumbprint = digest(&SHA256, members.to_string().as_bytes());
+    Ok(SigningKey {
+        kid: URL_SAFE_NO_PAD.encode(thumbprint),
+        private_key: pkcs8,
+    })
+}
+
+/// The coordinates of the public key of `key_pair`, base64url-encoded.
+fn coordinates(key_pair: &EcdsaKeyPair) -> (String, String) {
+    // An uncompressed point: 0x04, then x and y
+    let point = key_pair.public_key().as_ref();
+    (
+        URL_SAFE_NO_PAD.encode(&point[1..33]),
+        URL_SAFE_NO_PAD.encode(&point[33..]),
+    )
+}
+
+/// The public key of `key_pair` as a JWK with the ID `kid`.
+fn jwk(key_pair: &EcdsaKeyPair, kid: &str) -> Value {
+    let (x, y) = coordinates(key_pair);
+    json!({"kty": "EC", "crv": "P-256", "x": x, "y": y, "use": "sig", "alg": "ES256", "kid": kid})
+}
+
+fn provider() -> Result<&'static Provider, AppError> {
+    PROVIDER.get().ok_or_else(|| {
+        AppError::NotFound(
+            ErrorCode::RouteNotFound,
+            "This backend isn't an OpenID Connect provider (OIDC_ISSUER)".to_string(),
+        )
+    })
+}
+
+/// The discovery document of the provider.
+pub fn discovery() -> Result<Value, AppError> {
+    let issuer = &provider()?.issuer;
+    Ok(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/oauth/authorize", issuer),
+        "token_endpoint": format!("{}/oauth/token", issuer),
+        "userinfo_endpoint": format!("{}/oauth/userinfo", issuer),
+        "jwks_uri": format!("{}/oauth/jwks", issuer),
+        "scopes_supported": SCOPES,
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["ES256"],
+        "token_endpoint_auth_methods_supported":
+            ["none", "client_secret_post", "client_secret_basic"],
+        "code_challenge_methods_supported": ["S256"],
+        "claims_supported":
+            ["iss", "sub", "aud", "iat", "exp", "auth_time", "nonce", "name", "email"],
+    }))
+}
+
+/// The public keys of the ID tokens, as a JWK Set.
+pub fn jwks() -> Result<Value, AppError> {
+    Ok(json!({"keys": [provider()?.jwk]}))
+}
+
+/// The client of an authorization request, if its `redirect_uri` is one of the client.
+///
+/// # Returns
+///
+/// * `Result<&Client, AppError>` - The client, or `AppError::Validation` when the
+///   browser can't be sent back to the app: the error is then shown to the user
+pub fn client(request: &AuthorizationRequest) -> Result<&'static Client, AppError> {
+    let client = provider()?
+        .clients
+        .iter()
+        .find(|client| client.client_id == request.client_id)
+        .ok_or_else(|| AppError::Validation(format!("Unknown client '{}'", request.client_id)))?;
+    if !client.redirect_uris.contains(&request.redirect_uri) {
+        return Err(AppError::Validation(format!(
+            "'{}' is not a redirect URI of the client '{}'",
+            request.redirect_uri, client.client_id
+        )));
+    }
+    Ok(client)
+}
+
+/// Checks the parameters of an authorization request sent back to the app on error.
+///
+/// # Returns
+///
+/// * `Result<(), OAuthError>` - The `invalid_request`, `unsupported_response_type` or
+///   `invalid_scope` error of the first invalid parameter
+pub fn check_authorization(request: &AuthorizationRequest) -> Result<(), OAuthError> {
+    if request.response_type != "code" {
+        return Err(OAuthError::new(
+            "unsupported_response_type",
+            "Only the authorization code flow is supported (response_type=code)",
+        ));
+    }
+    if !request.scope.split(' ').any(|scope| scope == "openid") {
+        return Err(OAuthError::new(
+            "invalid_scope",
+            "The scope must include openid",
+        ));
+    }
+    if request.code_challenge_method.as_deref() != Some("S256") {
+        return Err(OAuthError::new(
+            "invalid_request",
+            "PKCE is required, with code_challenge_method=S256",
+        ));
+    }
+    // The base64url of a SHA-256
+    if request.code_challenge.len() != 43 {
+        return Err(OAuthError::new(
+            "invalid_request",
+            "code_challenge must be the base64url S256 of the verifier",
+        ));
+    }
+    Ok(())
+}
+
+/// Gives the user `user_id` a code for the app of `request`, checked by `client` and
+/// `check_authorization`.
+///
+/// # Returns
+///
+/// * `Result<String, AppError>` - The URL the browser is sent back to, with the code
+///   and the state
+pub async fn authorize(
+    db: Db,
+    request: &AuthorizationRequest,
+    user_id: i32,
+) -> Result<String, AppError> {
+    let mut code = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut code)
+        .map_err(|_| AppError::Internal("Unable to generate a code".to_string()))?;
+    let code = URL_SAFE_NO_PAD.encode(code);
+    let scope = request
+        .scope
+        .split(' ')
+        .filter(|scope| SCOPES.contains(scope))
+        .collect::<Vec<_>>()
+        .join(" ");
+    PgOidcRepo(db)
+        .create_code(&NewAuthorizationCode {
+            code_hash: &hash(&code),
+            client_id: &request.client_id,
+            user_id,
+            redirect_uri: &request.redirect_uri,
+            code_challenge: &request.code_challenge,
+            scope: &scope,
+            nonce: request.nonce.as_deref(),
+            lifetime_secs: CODE_LIFETIME_SECS,
+        })
+        .await?;
+    info!(
+        "Code given to the OIDC client '{}' for user {}",
+        request.client_id, user_id
+    );
+
+    let mut params = vec![("code", code.as_str())];
+    if let Some(state) = &request.state {
+        params.push(("state", state));
+    }
+    Ok(redirect_url(&request.redirect_uri, &params))
+}
+
+/// `redirect_uri` with the query parameters `params` added.
+pub fn redirect_url(redirect_uri: &str, params: &[(&str, &str)]) -> String {
+    let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+    let query = serde_urlencoded::to_string(params).unwrap_or_default();
+    format!("{}{}{}", redirect_uri, separator, query)
+}
+
+/// Hex SHA-256 of a code, the form it's stored in.
+fn hash(code: &str) -> String {
+    digest(&SHA256, code.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Exchanges an authorization code for tokens.
+///
+/// # Arguments
+///
+/// * `request` - The form of the token request
+/// * `basic` - The client ID and secret of the `Authorization: Basic` header, if sent
+///
+/// # Returns
+///
+/// * `Result<Value, OAuthError>` - The token response, with `id_token`, or the
+///   `invalid_client`, `invalid_grant` or `invalid_request` error
+pub async fn exchange(
+    db: Db,
+    request: TokenRequest,
+    basic: Option<(String, String)>,
+) -> Result<Value, OAuthError> {
+    let provider = provider()?;
+    if request.grant_type != "authorization_code" {
+        return Err(OAuthError::new(
+            "unsupported_grant_type",
+            "Only the authorization_code grant is supported",
+        ));
+    }
+    let (client_id, secret) = match basic {
+        Some((id, secret)) => (Some(id), Some(secret)),
+        None => (request.client_id, request.client_secret),
+    };
+    let client = client_id
+        .and_then(|id| {
+            provider
+                .clients
+                .iter()
+                .find(|client| client.client_id == id)
+        })
+        .ok_or_else(|| OAuthError::new("invalid_client", "Unknown client"))?;
+    if let Some(expected) = &client.client_secret {
+        // Compared by digest, so the time taken tells nothing of the secret
+        let sent = secret.unwrap_or_default();
+        if digest(&SHA256, sent.as_bytes()).as_ref()
+            != digest(&SHA256, expected.as_bytes()).as_ref()
+        {
+            return Err(OAuthError::new("invalid_client", "Wrong client secret"));
+        }
+    }
+
+    let invalid_grant = || OAuthError::new("invalid_grant", "Invalid, expired or used code");
+    let code = PgOidcRepo(db)
+        .take_code(&hash(&request.code))
+        .await?
+        .ok_or_else(invalid_grant)?;
+    if code.client_id != client.client_id || code.redirect_uri != request.redirect_uri {
+        return Err(invalid_grant());
+    }
+    let challenge = URL_SAFE_NO_PAD.encode(digest(&SHA256, request.code_verifier.as_bytes()));
+    if challenge != code.code_challenge {
+        return Err(OAuthError::new(
+            "invalid_grant",
+            "code_verifier doesn't match the code_challenge",
+        ));
+    }
+
+    let profile = PgUserRepo(db)
+        .find_profile(code.user_id)
+        .await?
+        .ok_or_else(invalid_grant)?;
+    let access = issue_client_token(code.user_id, &client.client_id, &code.scope)?;
+    let scopes = code.scope.split(' ').collect::<Vec<_>>();
+    let email = profile.email.filter(|_| scopes.contains(&"email"));
+    let iat = jsonwebtoken::get_current_timestamp();
+    let claims = IdClaims {
+        iss: provider.issuer.clone(),
+        sub: profile.public_id.to_string(),
+        aud: client.client_id.clone(),
+        iat,
+        exp: iat + access.expires_in,
+        auth_time: code.auth_time,
+        nonce: code.nonce,
+        name: scopes.contains(&"profile").then_some(profile.name),
+        // Addresses aren't verified
+        email_verified: email.as_ref().map(|_| false),
+        email,
+    };
+    let mut header = Header::new(Algorithm::ES256);
+    header.kid = Some(provider.kid.clone());
+    let id_token = encode(&header, &claims, &provider.key)
+        .map_err(|e| AppError::Internal(format!("Unable to sign the ID token: {}", e)))?;
+
+    Ok(json!({
+        "access_token": access.access_token,
+        "token_type": access.token_type,
+        "expires_in": access.expires_in,
+        "id_token": id_token,
+        "scope": code.scope,
+    }))
+}
+
+/// The claims of the user of `grant` for `/oauth/userinfo`, the ones of its scopes.
+pub async fn userinfo(db: Db, grant: &ClientGrant) -> Result<Value, AppError> {
+    provider()?;
+    let profile = PgUserRepo(db)
+        .find_profile(grant.user.id)
+        .await?
+        .ok_or_else(|| AppError::Unauthorized("The user of the token was deleted".to_string()))?;
+    let mut claims = json!({"sub": profile.public_id});
+    if grant.allows("profile") {
+        claims["name"] = json!(profile.name);
+    }
+    if grant.allows("email") {
+        claims["email_verified"] = json!(profile.email.as_ref().map(|_| false));
+        claims["email"] = json!(profile.email);
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_ids_are_the_thumbprints_of_the_keys() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key = signing_key(pkcs8.as_ref().to_vec(), &rng).unwrap();
+        assert_eq!(key.kid.len(), 43);
+        assert_eq!(
+            signing_key(pkcs8.as_ref().to_vec(), &rng).unwrap().kid,
+            key.kid
+        );
+    }
+
+    #[test]
+    fn redirects_keep_the_query_of_the_uri() {
+        assert_eq!(
+            redirect_url("https://app/cb", &[("code", "a b"), ("state", "s")]),
+            "https://app/cb?code=a+b&state=s"
+        );
+        assert_eq!(
+            redirect_url("https://app/cb?tenant=1", &[("error", "access_denied")]),
+            "https://app/cb?tenant=1&error=access_denied"
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! Monthly partitions of the tables growing with every write.
+//!
+//! The audit log and the webhook deliveries are partitioned by month of `created_at`
+//! (see `V19__partition_by_month`), so their indexes stay the size of a month and the
+//! old months are removed as whole tables. A row can only be written once the
+//! partition of its month exists: the partitions of the current month and of the
+//! `PARTITIONS_AHEAD` next ones are created at startup, then every night by the
+//! scheduler, long before they're needed.
+//!
+//! With `PARTITION_ARCHIVE_MONTHS`, the partitions of the months before the last
+//! `PARTITION_ARCHIVE_MONTHS` full months are detached by the same run and moved to
+//! the `archive` schema: their rows leave the table at once, without the row by row
+//! deletes of a retention rule, and stay in the database for export until they're
+//! dropped (`DROP TABLE archive.audit_log_p2024_01`). With `PARTITION_EXPORT`, the
+//! run also starts the export of each partition of the `archive` schema to the file
+//! storage, which drops it (see `archives`).
+
+use std::sync::OnceLock;
+
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::db::DistributedLock;
+use crate::error::AppError;
+use crate::jobs::{self, Job, Schedule};
+use crate::repository::archives::{ArchiveRepository, PgArchiveRepo};
+use crate::repository::jobs::{JobRepository, PgJobRepo};
+use crate::repository::partitions::{PartitionRepository, PgPartitionRepo};
+
+/// Tables partitioned by month
+pub const PARTITIONED: &[&str] = &["audit_log", "webhook_deliveries"];
+
+/// Months after the current one with a partition ready
+const PARTITIONS_AHEAD: i32 = 3;
+
+/// Schedule of the maintenance: every night, before the cleanup
+const SCHEDULE: &str = "0 2 * * *";
+
+/// Lock serializing the maintenance of the instances starting together
+const PARTITIONS_LOCK: &str = "partitions";
+
+// Set once at startup
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+#[derive(Debug)]
+struct Settings {
+    /// `None` without PARTITION_ARCHIVE_MONTHS
+    archive_months: Option<i32>,
+    export: bool,
+}
+
+/// Creates the partitions needed soon, and returns the periodic job keeping them
+/// ahead.
+/// This function is called once by `init_jobs`, which schedules the job.
+///
+/// # Arguments
+///
+/// * `archive_months` - Full months of rows kept in the tables, `None` to keep them all
+/// * `export` - Whether the archived partitions are exported to the file storage
+///
+/// # Returns
+///
+/// * `Result<(Schedule, Job), String>` - The job for the scheduler, or the error of the
+///   database
+pub async fn init_partitions(
+    archive_months: Option<i32>,
+    export: bool,
+) -> Result<(Schedule, Job), String> {
+    let settings = Settings {
+        archive_months,
+        export,
+    };
+    if SETTINGS.set(settings).is_err() {
+        warn!("Attempt to reset the partition archiving ignored");
+    }
+    create_upcoming()
+        .await
+        .map_err(|e| format!("Error creating the partitions: {}", e))?;
+
+    let schedule = SCHEDULE
+        .parse::<Schedule>()
+        .map_err(|e| format!("Partition schedule: {}", e))?;
+    Ok((schedule, Job::MaintainPartitions))
+}
+
+/// Creates the missing partitions of the next months.
+///
+/// # Returns
+///
+/// * `Result<Vec<String>, AppError>` - The partitions created
+async fn create_upcoming() -> Result<Vec<String>, AppError> {
+    let _lock = DistributedLock::acquire(PARTITIONS_LOCK).await?;
+    let mut created = Vec::new();
+    for table in PARTITIONED {
+        created.extend(
+            PgPartitionRepo::default()
+                .create_upcoming(table, PARTITIONS_AHEAD)
+                .await?,
+        );
+    }
+    if !created.is_empty() {
+        info!("Partitions created: {}", created.join(", "));
+    }
+    Ok(created)
+}
+
+/// Runs the maintenance as the job `id`: creates the partitions of the next months,
+/// archives the expired ones and starts their export, reporting them in the job
+/// progress.
+pub async fn maintain(id: i64) -> Result<(), AppError> {
+    let created = create_upcoming().await?;
+    let settings = SETTINGS.get();
+
+    let mut archived = Vec::new();
+    if let Some(months) = settings.and_then(|settings| settings.archive_months) {
+        let _lock = DistributedLock::acquire(PARTITIONS_LOCK).await?;
+        for table in PARTITIONED {
+            for partition in PgPartitionRepo::default().expired(table, months).await? {
+                PgPartitionRepo::default()
+                    .archive(table, &partition)
+                    .await?;
+                info!("Partition {} archived", partition);
+                archived.push(partition);
+            }
+        }
+    }
+
+    // Including the partitions archived before, or whose export failed
+    let mut exporting = Vec::new();
+    if settings.is_some_and(|settings| settings.export) {
+        for table in PARTITIONED {
+            for partition in PgArchiveRepo::default().unexported(table).await? {
+                jobs::submit(Job::ExportArchive {
+                    table: table.to_string(),
+                    partition: partition.clone(),
+                })
+                .await?;
+                exporting.push(partition);
+            }
+        }
+    }
+
+    let progress = json!({"created": created, "archived": archived, "exporting": exporting});
+    PgJobRepo::default().set_progress(id, &progress).await?;
+    Ok(())
+}
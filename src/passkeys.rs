@@ -0,0 +1,798 @@
+//! Passkeys (WebAuthn), enabled by `WEBAUTHN_RP_ID`: logins with an authenticator,
+//! the one of the device, a phone or a security key, instead of the password or after
+//! it.
+//!
+//! ## Ceremonies
+//! Registering a passkey and logging in with one take two requests each. The first
+//! one saves a random challenge and returns its ID with the options of
+//! `navigator.credentials.create()` or `navigator.credentials.get()`. The second one
+//! sends the answer of the browser, whose client data (type, challenge, origin among
+//! `WEBAUTHN_ORIGINS`), authenticator data (relying party, flags, counter) and, at
+//! login, signature are checked here. A challenge is answered once, within
+//! `CHALLENGE_LIFETIME_SECS`.
+//!
+//! The registrations ask for no attestation: which model of authenticator made a
+//! passkey isn't checked. Its key may be ES256 (P-256), EdDSA (Ed25519) or RS256.
+//!
+//! ## Logins
+//! - Without password: the browser offers the passkeys it has for `WEBAUTHN_RP_ID`,
+//!   the user unlocks the authenticator (PIN, biometrics), and the passkey tells who
+//!   they are.
+//! - As second factor, once the user enabled it (`PUT /auth/second-factor`): the right
+//!   password answers with a challenge for the passkeys of the user rather than with a
+//!   token, touching the authenticator is enough.
+//!
+//! The authenticators keeping a signature counter must send a higher one at each
+//! login, a lower one tells a cloned authenticator.
+
+use std::sync::OnceLock;
+
+use base64::Engine;
+use base64::alphabet::URL_SAFE;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use bb8_postgres::tokio_postgres::error::SqlState;
+use ciborium::Value as Cbor;
+use ring::digest::{SHA256, digest};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::PasskeysConfig;
+use crate::db::Db;
+use crate::error::{AppError, ErrorCode};
+use crate::repository::passkeys::{
+    NewChallenge, NewPasskey, Passkey, PasskeyRepository, PgPasskeyRepo, Purpose, StoredPasskey,
+};
+use crate::repository::users::{PgUserRepo, UserRepository};
+
+/// Seconds the browser has to answer a challenge
+const CHALLENGE_LIFETIME_SECS: i32 = 300;
+
+/// Random bytes of a challenge
+const CHALLENGE_LEN: usize = 32;
+
+/// Base64url without padding, decoding it with or without
+const BASE64URL: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+// Flags of the authenticator data
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL: u8 = 0x40;
+
+// COSE algorithms (RFC 9053), in the order they're offered
+const ES256: i128 = -7;
+const EDDSA: i128 = -8;
+const RS256: i128 = -257;
+
+// Set once at startup, `None` without WEBAUTHN_RP_ID
+static PASSKEYS: OnceLock<Option<PasskeysConfig>> = OnceLock::new();
+
+/// Enables the passkeys of `WEBAUTHN_RP_ID`.
+/// This function should be called once at application startup.
+pub fn init_passkeys(config: Option<&PasskeysConfig>) {
+    if let Some(config) = config {
+        info!(
+            "Passkeys of {} for {}",
+            config.rp_id,
+            config.origins.join(", ")
+        );
+    }
+    if PASSKEYS.set(config.cloned()).is_err() {
+        warn!("Attempt to reconfigure the passkeys ignored");
+    }
+}
+
+fn config() -> Result<&'static PasskeysConfig, AppError> {
+    PASSKEYS.get().and_then(Option::as_ref).ok_or_else(|| {
+        AppError::NotFound(
+            ErrorCode::RouteNotFound,
+            "Passkeys are disabled (WEBAUTHN_RP_ID)".to_string(),
+        )
+    })
+}
+
+/// Answer of `navigator.credentials.create()`, as serialized by its `toJSON()`: the
+/// binary fields in base64url.
+#[derive(Deserialize, Debug)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+/// Answer of `navigator.credentials.get()`, as serialized by its `toJSON()`.
+#[derive(Deserialize, Debug)]
+pub struct LoginCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    /// Public ID of the user, sent by the passkeys stored on the authenticator
+    #[serde(default)]
+    pub user_handle: Option<String>,
+}
+
+fn base64url(field: &str, value: &str) -> Result<Vec<u8>, String> {
+    BASE64URL
+        .decode(value)
+        .map_err(|_| format!("{} is not base64url", field))
+}
+
+/// Checks the client data of a ceremony: its type, challenge and origin.
+fn check_client_data(
+    config: &PasskeysConfig,
+    client_data: &[u8],
+    kind: &str,
+    challenge: &[u8],
+) -> Result<(), String> {
+    #[derive(Deserialize)]
+    struct ClientData {
+        #[serde(rename = "type")]
+        kind: String,
+        challenge: String,
+        origin: String,
+    }
+
+    let data: ClientData = serde_json::from_slice(client_data)
+        .map_err(|e| format!("clientDataJSON is invalid: {}", e))?;
+    if data.kind != kind {
+        return Err(format!("type is '{}' instead of '{}'", data.kind, kind));
+    }
+    if base64url("challenge", &data.challenge)? != challenge {
+        return Err("the challenge isn't the one sent".to_string());
+    }
+    if !config.origins.contains(&data.origin) {
+        return Err(format!("origin {} is not allowed", data.origin));
+    }
+    Ok(())
+}
+
+/// What the authenticator data tells.
+#[derive(Debug)]
+struct AuthenticatorData {
+    sign_count: u32,
+    /// The credential ID and its COSE_Key, at registration
+    credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Parses and checks authenticator data: the hash of `WEBAUTHN_RP_ID`, the presence
+/// of the user, and their verification if `user_verification`.
+fn parse_authenticator_data(
+    config: &PasskeysConfig,
+    data: &[u8],
+    user_verification: bool,
+) -> Result<AuthenticatorData, String> {
+    // rpIdHash (32 bytes), flags (1), signCount (4), then the attested credential
+    if data.len() < 37 {
+        return Err("authenticatorData is too short".to_string());
+    }
+    if data[..32] != *digest(&SHA256, config.rp_id.as_bytes()).as_ref() {
+        return Err(format!("the passkey isn't one of {}", config.rp_id));
+    }
+    let flags = data[32];
+    if flags & USER_PRESENT == 0 {
+        return Err("the user wasn't present".to_string());
+    }
+    if user_verification && flags & USER_VERIFIED == 0 {
+        return Err("the user wasn't verified".to_string());
+    }
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+    let credential = if flags & ATTESTED_CREDENTIAL != 0 {
+        // AAGUID (16 bytes), length of the credential ID (2), the ID, the key
+        let rest = &data[37..];
+        let truncated = || "the attested credential is truncated".to_string();
+        let len = rest.get(16..18).ok_or_else(truncated)?;
+        let len = usize::from(u16::from_be_bytes([len[0], len[1]]));
+        let rest = &rest[18..];
+        let id = rest.get(..len).ok_or_else(truncated)?;
+        let key = &rest[len..];
+        // The key is followed by the extensions, if any
+        let mut remaining = key;
+        ciborium::from_reader::<Cbor, _>(&mut remaining)
+            .map_err(|_| "the public key is not CBOR".to_string())?;
+        let key = &key[..key.len() - remaining.len()];
+        Some((id.to_vec(), key.to_vec()))
+    } else {
+        None
+    };
+    Ok(AuthenticatorData {
+        sign_count,
+        credential,
+    })
+}
+
+/// A public key of the supported algorithms.
+#[derive(Debug)]
+enum PublicKey {
+    /// Uncompressed P-256 point
+    Es256(Vec<u8>),
+    Ed25519(Vec<u8>),
+    Rs256 {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+}
+
+impl PublicKey {
+    /// Reads a COSE_Key (RFC 9052).
+    fn from_cose(cose_key: &[u8]) -> Result<PublicKey, String> {
+        let key: Cbor =
+            ciborium::from_reader(cose_key).map_err(|_| "the public key is not CBOR")?;
+        let map = key.as_map().ok_or("the public key is not a COSE_Key")?;
+        let param = |label: i128| {
+            map.iter()
+                .find(|(key, _)| key.as_integer().is_some_and(|key| i128::from(key) == label))
+                .map(|(_, value)| value)
+        };
+        let int = |label| param(label).and_then(Cbor::as_integer).map(i128::from);
+        let bytes = |label| {
+            param(label)
+                .and_then(Cbor::as_bytes)
+                .cloned()
+                .ok_or_else(|| format!("the public key has no parameter {}", label))
+        };
+        // kty (1): 1 OKP, 2 EC2, 3 RSA; alg (3); crv (-1): 1 P-256, 6 Ed25519
+        match (int(1), int(3)) {
+            (Some(2), Some(ES256)) if int(-1) == Some(1) => {
+                let point = [vec![0x04], bytes(-2)?, bytes(-3)?].concat();
+                Ok(PublicKey::Es256(point))
+            }
+            (Some(1), Some(EDDSA)) if int(-1) == Some(6) => Ok(PublicKey::Ed25519(bytes(-2)?)),
+            (Some(3), Some(RS256)) => Ok(PublicKey::Rs256 {
+                n: bytes(-1)?,
+                e: bytes(-2)?,
+            }),
+            (kty, alg) => Err(format!(
+                "keys of type {:?} and algorithm {:?} are not supported",
+                kty, alg
+            )),
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match self {
+            PublicKey::Es256(point) => {
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point)
+                    .verify(message, signature)
+                    .is_ok()
+            }
+            PublicKey::Ed25519(key) => UnparsedPublicKey::new(&signature::ED25519, key)
+                .verify(message, signature)
+                .is_ok(),
+            PublicKey::Rs256 { n, e } => RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature)
+                .is_ok(),
+        }
+    }
+}
+
+/// Checks the answer of the browser to a registration challenge.
+///
+/// # Returns
+///
+/// * `Result<NewPasskey, String>` - The passkey to save, or why it's invalid
+fn verify_registration(
+    config: &PasskeysConfig,
+    challenge: &[u8],
+    credential: &RegistrationCredential,
+) -> Result<NewPasskey, String> {
+    let response = &credential.response;
+    let client_data = base64url("clientDataJSON", &response.client_data_json)?;
+    check_client_data(config, &client_data, "webauthn.create", challenge)?;
+
+    let attestation = base64url("attestationObject", &response.attestation_object)?;
+    let attestation: Cbor = ciborium::from_reader(attestation.as_slice())
+        .map_err(|_| "attestationObject is not CBOR".to_string())?;
+    let auth_data = attestation
+        .as_map()
+        .and_then(|map| {
+            map.iter()
+                .find(|(key, _)| key.as_text() == Some("authData"))
+        })
+        .and_then(|(_, value)| value.as_bytes())
+        .ok_or("attestationObject has no authData")?;
+    let data = parse_authenticator_data(config, auth_data, false)?;
+    let (credential_id, public_key) = data.credential.ok_or("no credential was attested")?;
+    if base64url("id", &credential.id)? != credential_id {
+        return Err("id isn't the one of the attested credential".to_string());
+    }
+    PublicKey::from_cose(&public_key)?;
+    Ok(NewPasskey {
+        credential_id,
+        public_key,
+        sign_count: data.sign_count,
+    })
+}
+
+/// Checks the answer of the browser to a login challenge with the passkey it used.
+///
+/// # Returns
+///
+/// * `Result<u32, String>` - The new value of the signature counter, or why the
+///   login is invalid
+fn verify_login(
+    config: &PasskeysConfig,
+    challenge: &[u8],
+    credential: &LoginCredential,
+    passkey: &StoredPasskey,
+    user_verification: bool,
+) -> Result<u32, String> {
+    let response = &credential.response;
+    let client_data = base64url("clientDataJSON", &response.client_data_json)?;
+    check_client_data(config, &client_data, "webauthn.get", challenge)?;
+    let auth_data = base64url("authenticatorData", &response.authenticator_data)?;
+    let data = parse_authenticator_data(config, &auth_data, user_verification)?;
+
+    // The signature covers the authenticator data and the hash of the client data
+    let message = [&auth_data[..], digest(&SHA256, &client_data).as_ref()].concat();
+    let signature = base64url("signature", &response.signature)?;
+    if !PublicKey::from_cose(&passkey.public_key)?.verify(&message, &signature) {
+        return Err("the signature is invalid".to_string());
+    }
+    if let Some(handle) = &response.user_handle
+        && !handle.is_empty()
+        && base64url("userHandle", handle)? != passkey.user_public_id.as_bytes()
+    {
+        return Err("userHandle isn't the owner of the passkey".to_string());
+    }
+    if (data.sign_count != 0 || passkey.sign_count != 0) && data.sign_count <= passkey.sign_count {
+        return Err(format!(
+            "the signature counter went from {} to {}, the authenticator may be cloned",
+            passkey.sign_count, data.sign_count
+        ));
+    }
+    Ok(data.sign_count)
+}
+
+fn descriptors(credential_ids: &[Vec<u8>]) -> Vec<Value> {
+    credential_ids
+        .iter()
+        .map(|id| json!({"type": "public-key", "id": BASE64URL.encode(id)}))
+        .collect()
+}
+
+/// Saves a new random challenge.
+///
+/// # Returns
+///
+/// * `Result<(Uuid, Vec<u8>), AppError>` - Its ID and its bytes
+async fn new_challenge(
+    db: Db,
+    purpose: Purpose,
+    user_id: Option<i32>,
+) -> Result<(Uuid, Vec<u8>), AppError> {
+    let mut challenge = vec![0; CHALLENGE_LEN];
+    SystemRandom::new()
+        .fill(&mut challenge)
+        .map_err(|_| AppError::Internal("No random bytes for the challenge".to_string()))?;
+    let id = PgPasskeyRepo(db)
+        .create_challenge(&NewChallenge {
+            purpose,
+            user_id,
+            challenge: &challenge,
+            lifetime_secs: CHALLENGE_LIFETIME_SECS,
+        })
+        .await?;
+    Ok((id, challenge))
+}
+
+/// Starts the registration of a passkey of the user.
+///
+/// # Returns
+///
+/// * `Result<Value, AppError>` - `{challenge_id, publicKey}`, `publicKey` being the
+///   options of `navigator.credentials.create()`
+pub async fn start_registration(db: Db, user_id: i32) -> Result<Value, AppError> {
+    let config = config()?;
+    let profile = PgUserRepo(db)
+        .find_profile(user_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()))?;
+    let registered = PgPasskeyRepo(db).credential_ids(user_id).await?;
+    let (id, challenge) = new_challenge(db, Purpose::Registration, Some(user_id)).await?;
+    let algorithms = [ES256, EDDSA, RS256]
+        .iter()
+        .map(|&alg| json!({"type": "public-key", "alg": alg as i64}))
+        .collect::<Vec<_>>();
+    Ok(json!({
+        "challenge_id": id,
+        "publicKey": {
+            "rp": {"id": config.rp_id, "name": config.rp_name},
+            "user": {
+                "id": BASE64URL.encode(profile.public_id.as_bytes()),
+                "name": profile.email.as_deref().unwrap_or(&profile.name),
+                "displayName": profile.name,
+            },
+            "challenge": BASE64URL.encode(&challenge),
+            "pubKeyCredParams": algorithms,
+            "timeout": CHALLENGE_LIFETIME_SECS * 1000,
+            "excludeCredentials": descriptors(&registered),
+            // Security keys without PIN can still be a second factor
+            "authenticatorSelection": {"residentKey": "preferred", "userVerification": "preferred"},
+            "attestation": "none",
+        },
+    }))
+}
+
+/// Finishes the registration of a passkey of the user.
+///
+/// # Arguments
+///
+/// * `user_id` - The user who started the registration
+/// * `challenge_id` - The ID returned by `start_registration`
+/// * `credential` - The answer of the browser
+/// * `name` - The name of the passkey
+///
+/// # Returns
+///
+/// * `Result<Passkey, AppError>` - The passkey, `AppError::Validation` if the challenge
+///   or the answer are invalid, or `AppError::Conflict` if the passkey is already
+///   registered
+pub async fn finish_registration(
+    db: Db,
+    user_id: i32,
+    challenge_id: Uuid,
+    credential: &RegistrationCredential,
+    name: &str,
+) -> Result<Passkey, AppError> {
+    let config = config()?;
+    let challenge = PgPasskeyRepo(db)
+        .take_challenge(challenge_id)
+        .await?
+        .filter(|challenge| {
+            challenge.purpose == Purpose::Registration && challenge.user_id == Some(user_id)
+        })
+        .ok_or_else(|| {
+            AppError::Validation("The challenge is unknown, expired or already answered".into())
+        })?;
+    let passkey = verify_registration(config, &challenge.challenge, credential)
+        .map_err(|e| AppError::Validation(format!("Invalid passkey: {}", e)))?;
+    match PgPasskeyRepo(db).create(user_id, name, &passkey).await {
+        Ok(passkey) => {
+            info!(
+                "Passkey {} of user {} registered",
+                passkey.public_id, user_id
+            );
+            Ok(passkey)
+        }
+        Err(AppError::Db(e)) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+            Err(AppError::Conflict(
+                ErrorCode::PasskeyTaken,
+                "This passkey is already registered".to_string(),
+            ))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Starts a login without password.
+///
+/// # Returns
+///
+/// * `Result<Value, AppError>` - `{challenge_id, publicKey}`, `publicKey` being the
+///   options of `navigator.credentials.get()`
+pub async fn start_login(db: Db) -> Result<Value, AppError> {
+    let config = config()?;
+    let (id, challenge) = new_challenge(db, Purpose::Authentication, None).await?;
+    Ok(json!({
+        "challenge_id": id,
+        "publicKey": {
+            "challenge": BASE64URL.encode(&challenge),
+            "rpId": config.rp_id,
+            "timeout": CHALLENGE_LIFETIME_SECS * 1000,
+            "allowCredentials": [],
+            "userVerification": "required",
+        },
+    }))
+}
+
+/// Starts the second factor of a password login, if the user enabled it.
+///
+/// # Returns
+///
+/// * `Result<Option<Value>, AppError>` - `{second_factor: "passkey", challenge_id,
+///   publicKey}`, or `None` if the password is enough (always without
+///   `WEBAUTHN_RP_ID`)
+pub async fn start_second_factor(db: Db, user_id: i32) -> Result<Option<Value>, AppError> {
+    let Ok(config) = config() else {
+        return Ok(None);
+    };
+    if !PgPasskeyRepo(db).second_factor(user_id).await? {
+        return Ok(None);
+    }
+    let passkeys = PgPasskeyRepo(db).credential_ids(user_id).await?;
+    let (id, challenge) = new_challenge(db, Purpose::SecondFactor, Some(user_id)).await?;
+    Ok(Some(json!({
+        "second_factor": "passkey",
+        "challenge_id": id,
+        "publicKey": {
+            "challenge": BASE64URL.encode(&challenge),
+            "rpId": config.rp_id,
+            "timeout": CHALLENGE_LIFETIME_SECS * 1000,
+            "allowCredentials": descriptors(&passkeys),
+            "userVerification": "discouraged",
+        },
+    })))
+}
+
+/// Finishes a login, without password or as second factor.
+///
+/// # Arguments
+///
+/// * `challenge_id` - The ID returned by `start_login` or `start_second_factor`
+/// * `credential` - The answer of the browser
+///
+/// # Returns
+///
+/// * `Result<i32, AppError>` - The key of the user, or `AppError::Unauthorized` if the
+///   challenge, the passkey or the answer are invalid
+pub async fn finish_login(
+    db: Db,
+    challenge_id: Uuid,
+    credential: &LoginCredential,
+) -> Result<i32, AppError> {
+    let config = config()?;
+    let invalid = || AppError::Unauthorized("Invalid passkey".to_string());
+    let challenge = PgPasskeyRepo(db)
+        .take_challenge(challenge_id)
+        .await?
+        .filter(|challenge| challenge.purpose != Purpose::Registration)
+        .ok_or_else(|| {
+            AppError::Unauthorized("The challenge is unknown, expired or already answered".into())
+        })?;
+    let credential_id = BASE64URL.decode(&credential.id).map_err(|_| invalid())?;
+    let passkey = PgPasskeyRepo(db)
+        .find(&credential_id)
+        .await?
+        .ok_or_else(invalid)?;
+    // The second factor must be a passkey of the user who typed the password
+    if challenge
+        .user_id
+        .is_some_and(|user_id| user_id != passkey.user_id)
+    {
+        return Err(invalid());
+    }
+    let user_verification = challenge.purpose == Purpose::Authentication;
+    let sign_count = verify_login(
+        config,
+        &challenge.challenge,
+        credential,
+        &passkey,
+        user_verification,
+    )
+    .map_err(|e| {
+        warn!("Passkey login of user {} refused: {}", passkey.user_id, e);
+        invalid()
+    })?;
+    PgPasskeyRepo(db)
+        .record_use(&credential_id, sign_count)
+        .await?;
+    Ok(passkey.user_id)
+}
+
+/// Retrieves the passkeys of the user.
+pub async fn list(db: Db, user_id: i32) -> Result<Vec<Passkey>, AppError> {
+    config()?;
+    PgPasskeyRepo(db).list(user_id).await
+}
+
+/// Removes a passkey of the user, unless it's their last one and they log in with a
+/// passkey as second factor.
+///
+/// # Returns
+///
+/// * `Result<(), AppError>` - `AppError::NotFound` if the user has no passkey with
+///   this ID, or `AppError::Conflict` if it's their last one
+pub async fn delete(db: Db, user_id: i32, id: Uuid) -> Result<(), AppError> {
+    config()?;
+    let passkeys = PgPasskeyRepo(db).list(user_id).await?;
+    if !passkeys.iter().any(|passkey| passkey.public_id == id) {
+        return Err(AppError::NotFound(
+            ErrorCode::PasskeyNotFound,
+            "Passkey not found".to_string(),
+        ));
+    }
+    if passkeys.len() == 1 && PgPasskeyRepo(db).second_factor(user_id).await? {
+        return Err(AppError::Conflict(
+            ErrorCode::PasskeyRequired,
+            "This is the last passkey of an account requiring one as second factor".to_string(),
+        ));
+    }
+    PgPasskeyRepo(db).delete(user_id, id).await?;
+    info!("Passkey {} of user {} removed", id, user_id);
+    Ok(())
+}
+
+/// Sets whether the password logins of the user need a passkey too.
+///
+/// # Returns
+///
+/// * `Result<(), AppError>` - `AppError::Conflict` when enabling it without a passkey
+pub async fn set_second_factor(db: Db, user_id: i32, enabled: bool) -> Result<(), AppError> {
+    config()?;
+    if enabled && PgPasskeyRepo(db).credential_ids(user_id).await?.is_empty() {
+        return Err(AppError::Conflict(
+            ErrorCode::PasskeyRequired,
+            "Register a passkey before requiring one as second factor".to_string(),
+        ));
+    }
+    PgPasskeyRepo(db).set_second_factor(user_id, enabled).await
+}
+
+/// Whether the password logins of the user need a passkey too, for the login forms
+/// that can't ask for one.
+pub async fn requires_second_factor(db: Db, user_id: i32) -> Result<bool, AppError> {
+    if config().is_err() {
+        return Ok(false);
+    }
+    PgPasskeyRepo(db).second_factor(user_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{ECDSA_P256_SHA256_ASN1_SIGNING, EcdsaKeyPair, KeyPair};
+
+    const CHALLENGE: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn config() -> PasskeysConfig {
+        PasskeysConfig {
+            rp_id: "app.test".to_string(),
+            rp_name: "App".to_string(),
+            origins: vec!["https://app.test".to_string()],
+        }
+    }
+
+    fn key_pair() -> EcdsaKeyPair {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap()
+    }
+
+    fn cose_key(key_pair: &EcdsaKeyPair) -> Vec<u8> {
+        let point = key_pair.public_key().as_ref();
+        let int = |value: i64| Cbor::Integer(value.into());
+        let key = Cbor::Map(vec![
+            (int(1), int(2)),
+            (int(3), int(-7)),
+            (int(-1), int(1)),
+            (int(-2), Cbor::Bytes(point[1..33].to_vec())),
+            (int(-3), Cbor::Bytes(point[33..].to_vec())),
+        ]);
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&key, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn auth_data(flags: u8, sign_count: u32, credential: Option<(&[u8], &[u8])>) -> Vec<u8> {
+        let mut data = digest(&SHA256, b"app.test").as_ref().to_vec();
+        data.push(flags);
+        data.extend(sign_count.to_be_bytes());
+        if let Some((id, key)) = credential {
+            data.extend([0; 16]);
+            data.extend((id.len() as u16).to_be_bytes());
+            data.extend(id);
+            data.extend(key);
+        }
+        data
+    }
+
+    fn client_data(kind: &str, origin: &str) -> String {
+        let data =
+            json!({"type": kind, "challenge": BASE64URL.encode(CHALLENGE), "origin": origin});
+        BASE64URL.encode(data.to_string())
+    }
+
+    fn registration(key: &[u8], origin: &str) -> RegistrationCredential {
+        let data = auth_data(USER_PRESENT | ATTESTED_CREDENTIAL, 0, Some((b"key-1", key)));
+        let object = Cbor::Map(vec![
+            (Cbor::Text("fmt".into()), Cbor::Text("none".into())),
+            (Cbor::Text("attStmt".into()), Cbor::Map(vec![])),
+            (Cbor::Text("authData".into()), Cbor::Bytes(data)),
+        ]);
+        let mut attestation = Vec::new();
+        ciborium::into_writer(&object, &mut attestation).unwrap();
+        RegistrationCredential {
+            id: BASE64URL.encode(b"key-1"),
+            response: AttestationResponse {
+                client_data_json: client_data("webauthn.create", origin),
+                attestation_object: BASE64URL.encode(attestation),
+            },
+        }
+    }
+
+    fn login(key_pair: &EcdsaKeyPair, flags: u8, sign_count: u32) -> LoginCredential {
+        let data = auth_data(flags, sign_count, None);
+        let client_data_json = client_data("webauthn.get", "https://app.test");
+        let client_data = BASE64URL.decode(&client_data_json).unwrap();
+        let message = [&data[..], digest(&SHA256, &client_data).as_ref()].concat();
+        let signature = key_pair.sign(&SystemRandom::new(), &message).unwrap();
+        LoginCredential {
+            id: BASE64URL.encode(b"key-1"),
+            response: AssertionResponse {
+                client_data_json,
+                authenticator_data: BASE64URL.encode(data),
+                signature: BASE64URL.encode(signature),
+                user_handle: None,
+            },
+        }
+    }
+
+    #[test]
+    fn registrations_are_checked() {
+        let key = cose_key(&key_pair());
+        let passkey = verify_registration(
+            &config(),
+            CHALLENGE,
+            &registration(&key, "https://app.test"),
+        )
+        .unwrap();
+        assert_eq!(passkey.credential_id, b"key-1");
+        assert_eq!(passkey.public_key, key);
+
+        let error = verify_registration(
+            &config(),
+            CHALLENGE,
+            &registration(&key, "https://evil.test"),
+        )
+        .unwrap_err();
+        assert!(error.contains("origin"), "{}", error);
+        let error =
+            verify_registration(&config(), b"other", &registration(&key, "https://app.test"))
+                .unwrap_err();
+        assert!(error.contains("challenge"), "{}", error);
+    }
+
+    #[test]
+    fn logins_are_checked() {
+        let key_pair = key_pair();
+        let passkey = StoredPasskey {
+            user_id: 1,
+            user_public_id: Uuid::new_v4(),
+            public_key: cose_key(&key_pair),
+            sign_count: 4,
+        };
+        let verified = USER_PRESENT | USER_VERIFIED;
+        let credential = login(&key_pair, verified, 5);
+        assert_eq!(
+            verify_login(&config(), CHALLENGE, &credential, &passkey, true),
+            Ok(5)
+        );
+
+        // Another key, a counter going back, no user verification
+        let credential = login(&self::key_pair(), verified, 5);
+        assert!(verify_login(&config(), CHALLENGE, &credential, &passkey, true).is_err());
+        let credential = login(&key_pair, verified, 4);
+        assert!(verify_login(&config(), CHALLENGE, &credential, &passkey, true).is_err());
+        let credential = login(&key_pair, USER_PRESENT, 5);
+        assert!(verify_login(&config(), CHALLENGE, &credential, &passkey, true).is_err());
+        assert_eq!(
+            verify_login(&config(), CHALLENGE, &credential, &passkey, false),
+            Ok(5)
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! PROXY protocol (`PROXY_PROTOCOL`).
+//!
+//! TCP load balancers (HAProxy, AWS NLB, ...) can't add `X-Forwarded-For` to the
+//! requests they forward, so the server only sees their address. With the PROXY
+//! protocol they announce the address of the client in a header sent before anything
+//! else on the connection, in text (v1) or binary (v2), see
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+//!
+//! That address becomes the `ClientAddr` of the requests of the connection, which the
+//! rate limiter, the GeoIP lookup and the logs use. Once enabled, every connection
+//! must start with a header: the others are closed.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Start of a v1 header
+const V1_PREFIX: &[u8] = b"PROXY";
+/// Longest v1 header, `\r\n` included
+const V1_MAX_LEN: usize = 107;
+/// Start of a v2 header
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Reads the PROXY protocol header at the start of a connection.
+///
+/// # Arguments
+///
+/// * `timeout` - Longest time the header may take to arrive
+///
+/// # Returns
+///
+/// * `Result<Option<SocketAddr>, String>` - The address of the client, `None` for a
+///   connection of the load balancer itself (health checks) or of an unknown protocol,
+///   or an error if the connection doesn't start with a valid header
+pub async fn read_header<R>(stream: &mut R, timeout: Duration) -> Result<Option<SocketAddr>, String>
+where
+    R: AsyncRead + Unpin,
+{
+    tokio::time::timeout(timeout, read_any_header(stream))
+        .await
+        .map_err(|_| "timed out".to_string())?
+}
+
+async fn read_any_header<R>(stream: &mut R) -> Result<Option<SocketAddr>, String>
+where
+    R: AsyncRead + Unpin,
+{
+    // As many bytes as both versions start with
+    let mut start = [0; 5];
+    stream
+        .read_exact(&mut start)
+        .await
+        .map_err(|e| e.to_string())?;
+    if start == V1_PREFIX {
+        read_v1(stream).await
+    } else if start == V2_SIGNATURE[..5] {
+        read_v2(stream).await
+    } else {
+        Err("no PROXY protocol header".to_string())
+    }
+}
+
+/// Reads the rest of a v1 header: `PROXY TCP4 <source> <destination> <source port>
+/// <destination port>\r\n`, or `PROXY UNKNOWN ...\r\n`.
+async fn read_v1<R>(stream: &mut R) -> Result<Option<SocketAddr>, String>
+where
+    R: AsyncRead + Unpin,
+{
+    // Byte by byte, so that nothing past the header is consumed
+    let mut line = V1_PREFIX.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err("v1 header too long".to_string());
+        }
+        line.push(stream.read_u8().await.map_err(|e| e.to_string())?);
+    }
+    parse_v1(&line[..line.len() - 2])
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, String> {
+    let invalid = || "invalid v1 header".to_string();
+    let line = std::str::from_utf8(line).map_err(|_| invalid())?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid())?;
+            let port: u16 = port.parse().map_err(|_| invalid())?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Reads the rest of a v2 header: the signature, the version and command, the address
+/// family, the length of the addresses, then the addresses and optional TLVs.
+async fn read_v2<R>(stream: &mut R) -> Result<Option<SocketAddr>, String>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0; 16];
+    header[..5].copy_from_slice(&V2_SIGNATURE[..5]);
+    stream
+        .read_exact(&mut header[5..])
+        .await
+        .map_err(|e| e.to_string())?;
+    if header[..12] != V2_SIGNATURE[..] {
+        return Err("invalid v2 signature".to_string());
+    }
+    let length = u16::from_be_bytes([header[14], header[15]]);
+    let mut addresses = vec![0; usize::from(length)];
+    stream
+        .read_exact(&mut addresses)
+        .await
+        .map_err(|e| e.to_string())?;
+    parse_v2(header[12], header[13], &addresses)
+}
+
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    addresses: &[u8],
+) -> Result<Option<SocketAddr>, String> {
+    if version_command >> 4 != 2 {
+        return Err("unsupported v2 version".to_string());
+    }
+    match version_command & 0x0f {
+        // LOCAL: the load balancer speaking for itself
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err("unsupported v2 command".to_string()),
+    }
+
+    let truncated = || "truncated v2 addresses".to_string();
+    match family >> 4 {
+        // IPv4: source, destination, source port, destination port
+        0x1 => {
+            let addresses: &[u8; 12] = addresses
+                .get(..12)
+                .and_then(|addresses| addresses.try_into().ok())
+                .ok_or_else(truncated)?;
+            let ip = Ipv4Addr::from([addresses[0], addresses[1], addresses[2], addresses[3]]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // IPv6, same layout
+        0x2 => {
+            let addresses: &[u8; 36] = addresses
+                .get(..36)
+                .and_then(|addresses| addresses.try_into().ok())
+                .ok_or_else(truncated)?;
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)))
+        }
+        // Unspecified or Unix sockets, no IP to tell
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut bytes: &[u8]) -> (Result<Option<SocketAddr>, String>, Vec<u8>) {
+        let result = read_header(&mut bytes, Duration::from_secs(1)).await;
+        (result, bytes.to_vec())
+    }
+
+    #[tokio::test]
+    async fn v1_header_gives_the_client() {
+        let (client, rest) = read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\nGET /").await;
+        assert_eq!(client, Ok(Some("203.0.113.7:51234".parse().unwrap())));
+        assert_eq!(rest, b"GET /");
+
+        let (client, _) = read(b"PROXY TCP6 2001:db8::1 ::1 8000 80\r\n").await;
+        assert_eq!(client, Ok(Some("[2001:db8::1]:8000".parse().unwrap())));
+
+        let (client, _) = read(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(client, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn v2_header_gives_the_client() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0, 12]);
+        header.extend([203, 0, 113, 7, 10, 0, 0, 1]);
+        header.extend(51234u16.to_be_bytes());
+        header.extend(443u16.to_be_bytes());
+        header.extend(b"GET /");
+        let (client, rest) = read(&header).await;
+        assert_eq!(client, Ok(Some("203.0.113.7:51234".parse().unwrap())));
+        assert_eq!(rest, b"GET /");
+
+        // LOCAL, from a health check
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(read(&header).await.0, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn connections_without_header_are_rejected() {
+        assert!(read(b"GET / HTTP/1.1\r\n").await.0.is_err());
+        assert!(read(b"PROXY TCP4 nonsense\r\n").await.0.is_err());
+        assert!(read(&[b'P'; 200]).await.0.is_err());
+    }
+}
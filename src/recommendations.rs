@@ -0,0 +1,77 @@
+//! Product recommendations of the users (`GET /users/:id/recommendations`).
+//!
+//! ## Strategy
+//! The recommendations are computed from the orders, in SQL. Co-purchases come first:
+//! the products bought by the users who bought a product of the user, scored by the
+//! number of those users. The popular products, by units ordered in the last
+//! `POPULAR_DAYS` days, complete them, so that a user without orders gets some too.
+//! The products the user bought and those out of stock are left out.
+//!
+//! ## Cache
+//! The `RECOMMENDATIONS` best ones are cached per user in `user_recommendations`,
+//! computed on the first request of the user, then again by the
+//! `refresh_recommendations` job (`RECOMMENDATIONS_SCHEDULE`, hourly by default) for
+//! every user who has some. Between two refreshes, the products deleted or sold out
+//! meanwhile are skipped when reading the cache.
+//!
+//! The embeddings of the products (see `embeddings`) could add a third reason, the
+//! products similar to those bought, in the same cache.
+
+use tracing::info;
+
+use crate::db::Db;
+use crate::error::AppError;
+use crate::repository::recommendations::{
+    PgRecommendationRepo, RecommendationRepository, Recommendations,
+};
+
+/// Recommendations cached for each user
+pub const RECOMMENDATIONS: i64 = 20;
+
+/// Days of orders counted to find the popular products
+const POPULAR_DAYS: i32 = 90;
+
+/// Users refreshed per query of the `refresh_recommendations` job
+const BATCH_SIZE: i64 = 100;
+
+/// The recommendations of a user, computed first if none are cached.
+///
+/// # Returns
+///
+/// * `Result<Option<Recommendations>, AppError>` - The recommendations, or `None` if
+///   the user doesn't exist
+pub async fn for_user(db: Db, user_id: i32) -> Result<Option<Recommendations>, AppError> {
+    if let Some(cached) = PgRecommendationRepo(db).cached(user_id).await? {
+        return Ok(Some(cached));
+    }
+    PgRecommendationRepo(db)
+        .refresh(user_id, RECOMMENDATIONS, POPULAR_DAYS)
+        .await?;
+    PgRecommendationRepo(db).cached(user_id).await
+}
+
+/// Computes again the recommendations of every user who has some cached (job `id`).
+pub async fn refresh_all(id: i64) -> Result<(), AppError> {
+    let mut after = 0;
+    let mut refreshed = 0;
+    loop {
+        let users = PgRecommendationRepo::default()
+            .users(after, BATCH_SIZE)
+            .await?;
+        for user_id in &users {
+            PgRecommendationRepo::default()
+                .refresh(*user_id, RECOMMENDATIONS, POPULAR_DAYS)
+                .await?;
+        }
+        refreshed += users.len();
+        match users.last() {
+            Some(last) if (users.len() as i64) == BATCH_SIZE => after = *last,
+            _ => break,
+        }
+    }
+    info!(
+        "Job {} refreshed the recommendations of {} users",
+        id, refreshed
+    );
+    Ok(())
+}
@@ -0,0 +1,214 @@
+//! Multi-region deployments.
+//!
+//! Every instance knows the region it serves (`REGION`) and the region of the primary
+//! database (`PRIMARY_REGION`). In the other regions `DB_HOST` is the database of the
+//! primary region, which every write goes to, and `DB_READ_URLS` replicas in the
+//! region, which serves the reads of `GET` requests (see `db::consistency`).
+//!
+//! Every response carries the region that served it in `X-App-Region`.
+//!
+//! ## Home regions
+//! With `REGION_ROUTING_PATH`, requests of clients living in another region are
+//! redirected there with 307 Temporary Redirect (method and body are kept). The home
+//! region of a client is the one it pins in `X-Home-Region`, or else the first rule
+//! matching its GeoIP location (see the `geoip` module). Clients without a known home
+//! region are served where they are.
+//!
+//! ```json
+//! {
+//!   "regions": {
+//!     "eu-west": "https://eu.api.example.com",
+//!     "us-east": "https://us.api.example.com"
+//!   },
+//!   "homes": [
+//!     { "location": "EU", "region": "eu-west", "paths": ["/api"] },
+//!     { "location": "US", "region": "us-east" }
+//!   ]
+//! }
+//! ```
+//!
+//! `location` is matched as the `region` of the geo policies (`ES`, `US-CA`, `EU`) and
+//! `paths` restricts a rule to some path prefixes.
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::OnceLock;
+
+use hyper::header::{HeaderValue, LOCATION};
+use hyper::{Request, Response, StatusCode};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::config::RegionConfig;
+use crate::error::AppError;
+use crate::geoip::GeoInfo;
+use crate::router::app_headers::{self, AppHeader};
+use crate::router::{Body, empty_response, error_response};
+
+/// Header a client sends to be served by a given region
+pub const HOME_REGION_HEADER: &str = "x-home-region";
+
+// Set once at startup, unset in a single region deployment
+static REGION: OnceLock<Region> = OnceLock::new();
+
+struct Region {
+    /// `X-App-Region` of every response
+    name: HeaderValue,
+    /// `None` without `REGION_ROUTING_PATH`
+    routing: Option<RoutingTable>,
+}
+
+/// The content of the `REGION_ROUTING_PATH` file.
+#[derive(Debug, Deserialize)]
+struct RoutingTable {
+    /// Base URL of every region, this one included
+    regions: HashMap<String, String>,
+    #[serde(default)]
+    homes: Vec<HomeRule>,
+}
+
+/// A client location and its home region.
+#[derive(Debug, Deserialize)]
+struct HomeRule {
+    location: String,
+    region: String,
+    #[serde(default)]
+    paths: Vec<String>,
+}
+
+/// Sets the region of this instance and loads its routing table.
+/// This function should be called once at application startup.
+///
+/// # Arguments
+///
+/// * `config` - Region settings, nothing is done without `REGION`
+/// * `has_replica` - Whether reads have a replica (`DB_READ_URLS`)
+///
+/// # Returns
+///
+/// * `Result<(), String>` - Success, or the error of an unreadable or inconsistent
+///   routing table
+pub fn init_region(config: &RegionConfig, has_replica: bool) -> Result<(), String> {
+    let Some(name) = &config.name else {
+        return Ok(());
+    };
+    let header = HeaderValue::from_str(name).map_err(|_| format!("Invalid region '{}'", name))?;
+
+    let routing = match &config.routing_path {
+        Some(path) => {
+            let content = fs::read_to_string(path).map_err(|e| {
+                format!("Unable to read region routing '{}': {}", path.display(), e)
+            })?;
+            let table: RoutingTable = serde_json::from_str(&content)
+                .map_err(|e| format!("Invalid region routing '{}': {}", path.display(), e))?;
+            if !table.regions.contains_key(name) {
+                return Err(format!("Region routing has no URL for region {}", name));
+            }
+            if let Some(rule) = table
+                .homes
+                .iter()
+                .find(|rule| !table.regions.contains_key(&rule.region))
+            {
+                return Err(format!(
+                    "Region routing has no URL for region {}",
+                    rule.region
+                ));
+            }
+            info!(
+                "Region routing loaded from {} ({} regions)",
+                path.display(),
+                table.regions.len()
+            );
+            Some(table)
+        }
+        None => None,
+    };
+
+    if config.is_secondary() {
+        let primary = config.primary.as_deref().unwrap_or_default();
+        info!("Region {}: writes go to the database of {}", name, primary);
+        if !has_replica {
+            warn!(
+                "No DB_READ_URLS in region {}, every read goes to {}",
+                name, primary
+            );
+        }
+    } else {
+        info!("Region {} (primary)", name);
+    }
+
+    if REGION
+        .set(Region {
+            name: header,
+            routing,
+        })
+        .is_err()
+    {
+        warn!("Attempt to reset the region ignored");
+    }
+    Ok(())
+}
+
+/// Middleware redirecting a request to the home region of the client.
+///
+/// Must run after `geoip::attach_geo_info`.
+///
+/// # Returns
+///
+/// * `Option<Response<Body>>` - The 307 redirect, a 400 response for an unknown
+///   `X-Home-Region`, or `None` to serve the request here
+pub fn redirect_to_home_region<B>(req: &Request<B>) -> Option<Response<Body>> {
+    let region = REGION.get()?;
+    let routing = region.routing.as_ref()?;
+
+    let home = match req.headers().get(HOME_REGION_HEADER) {
+        Some(pinned) => {
+            let pinned = pinned.to_str().unwrap_or_default().trim();
+            if !routing.regions.contains_key(pinned) {
+                return Some(error_response(AppError::Validation(format!(
+                    "Unknown region in {}: '{}'",
+                    HOME_REGION_HEADER, pinned
+                ))));
+            }
+            pinned
+        }
+        None => {
+            let geo = req.extensions().get::<GeoInfo>()?;
+            let path = req.uri().path();
+            routing
+                .homes
+                .iter()
+                .find(|rule| {
+                    geo.is_in(&rule.location)
+                        && (rule.paths.is_empty()
+                            || rule.paths.iter().any(|prefix| path.starts_with(prefix)))
+                })?
+                .region
+                .as_str()
+        }
+    };
+    if region.name == home {
+        return None;
+    }
+
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    let url = format!(
+        "{}{}",
+        routing.regions[home].trim_end_matches('/'),
+        path_and_query
+    );
+    let location = HeaderValue::from_str(&url).ok()?;
+    let mut res = empty_response(StatusCode::TEMPORARY_REDIRECT);
+    res.headers_mut().insert(LOCATION, location);
+    Some(res)
+}
+
+/// Registers `X-App-Region` on a response, in a multi-region deployment.
+pub fn apply_region_header<B>(res: &mut Response<B>) {
+    if let Some(region) = REGION.get() {
+        app_headers::set(res, AppHeader::Region, region.name.clone());
+    }
+}
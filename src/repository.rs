@@ -0,0 +1,76 @@
+//! Data access layer.
+//!
+//! Every SQL query of the application lives here; route handlers only deal with HTTP
+//! (parsing, validation, status codes) and call the repositories for the data.
+//!
+//! Each resource exposes a trait describing its operations (`UserRepository`,
+//! `ProductRepository`) and a PostgreSQL implementation (`PgUserRepo`, `PgProductRepo`)
+//! built on the `Db` it takes its connections from: the one of the request
+//! (`PgUserRepo(RequestContext::of(&req).db)`), holding its queries to the deadline, or
+//! `PgUserRepo::default()` outside of a request. Code depending on the traits can be
+//! exercised with an in-memory implementation instead of a live database.
+//!
+//! List queries take a `Filter` (see `filter`), a `WHERE` clause built from a whitelist
+//! of columns with its values passed as parameters.
+//!
+//! Read-only queries are retried on transient errors (see `retry`), and run on the read
+//! replica when there is one (`Db::read_connection`, see `db::consistency`).
+//!
+//! Writes to users, products and orders run in a transaction with their record in the
+//! audit log (see `audit`). Users and products have a version, bumped by every write;
+//! updates and deletes take the versions they may apply to (`If-Match`, see
+//! `router::conditional`) and check them in the same statement.
+
+pub mod archives;
+pub mod audit;
+pub mod carts;
+pub mod cdc;
+pub mod console;
+pub mod coupons;
+pub mod embeddings;
+pub mod experiments;
+pub mod export;
+pub mod filter;
+pub mod ids;
+pub mod jobs;
+pub mod ledger;
+pub mod oidc;
+pub mod orders;
+pub mod partitions;
+pub mod passkeys;
+pub mod products;
+pub mod recommendations;
+pub mod retention;
+mod retry;
+pub mod scim;
+pub mod search_terms;
+pub mod users;
+pub mod versions;
+pub mod views;
+pub mod webhooks;
+
+use crate::db::CachedTransaction;
+use crate::error::AppError;
+
+/// Rows inserted per statement by the bulk inserts (`create_many`)
+const INSERT_BATCH_SIZE: usize = 1000;
+
+/// Result of an update or delete conditioned on versions that changed no row.
+///
+/// # Returns
+///
+/// * `Result<Option<T>, AppError>` - `None` if the row doesn't exist, or an
+///   `AppError::PreconditionFailed` if it has another version
+async fn version_mismatch<T>(
+    tx: &CachedTransaction<'_>,
+    table: &str,
+    id: i32,
+) -> Result<Option<T>, AppError> {
+    let sql = format!("SELECT 1 FROM {} WHERE id = $1", table);
+    if tx.query_opt(&sql, &[&id]).await?.is_none() {
+        return Ok(None);
+    }
+    Err(AppError::PreconditionFailed(
+        "The entity changed since the version of If-Match, read it again".to_string(),
+    ))
+}
@@ -0,0 +1,288 @@
+//! Archives repository.
+//!
+//! The partitions moved to the `archive` schema (see `partitions`) are exported as CSV
+//! with `COPY`, and their manifests kept in the `archives` table once the file is
+//! stored (see the `archives` module). The partition names come from the catalog or
+//! the manifests, never from a request, and the column names are checked, as they're
+//! written in the statements.
+
+use std::future::Future;
+use std::pin::{Pin, pin};
+
+use bb8_postgres::tokio_postgres::Row;
+use futures_util::{SinkExt, Stream, StreamExt, stream};
+use hyper::body::Bytes;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::ids::new_public_id;
+use super::retry::with_retry;
+use crate::db::Db;
+use crate::error::AppError;
+
+/// Columns of an `Archive`
+const ARCHIVE_COLUMNS: &str = "id, table_name, partition_name, location, row_count, \
+     byte_size, sha256, \
+     to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS created_at, \
+     to_char(restored_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS restored_at";
+
+/// Rows of a partition as CSV, with a header line, read chunk by chunk.
+pub type CsvChunks = Pin<Box<dyn Stream<Item = Result<Bytes, AppError>> + Send>>;
+
+/// The manifest of an exported partition, as listed by `GET /admin/archives`.
+#[derive(Serialize, Debug)]
+pub struct Archive {
+    pub id: Uuid,
+    pub table_name: String,
+    pub partition_name: String,
+    /// Location of the gzipped CSV in the file storage
+    pub location: String,
+    pub row_count: i64,
+    pub byte_size: i64,
+    /// Hex SHA-256 of the file
+    pub sha256: String,
+    /// Timestamps in RFC 3339, UTC
+    pub created_at: String,
+    /// Latest restore into the `archive` schema, `None` if never restored
+    pub restored_at: Option<String>,
+}
+
+impl From<&Row> for Archive {
+    fn from(row: &Row) -> Self {
+        Archive {
+            id: row.get("id"),
+            table_name: row.get("table_name"),
+            partition_name: row.get("partition_name"),
+            location: row.get("location"),
+            row_count: row.get("row_count"),
+            byte_size: row.get("byte_size"),
+            sha256: row.get("sha256"),
+            created_at: row.get("created_at"),
+            restored_at: row.get("restored_at"),
+        }
+    }
+}
+
+/// The manifest of a stored file, as it is recorded.
+#[derive(Debug)]
+pub struct NewArchive<'a> {
+    pub table_name: &'a str,
+    pub partition_name: &'a str,
+    pub location: &'a str,
+    pub row_count: i64,
+    pub byte_size: i64,
+    pub sha256: &'a str,
+}
+
+/// Operations exporting the archived partitions and restoring them.
+pub trait ArchiveRepository {
+    /// Partitions of `table` in the `archive` schema never exported, the oldest first.
+    /// The restored partitions have a manifest already.
+    fn unexported(
+        &self,
+        table: &'static str,
+    ) -> impl Future<Output = Result<Vec<String>, AppError>> + Send;
+
+    /// Counts the rows of a partition of the `archive` schema, `None` without it.
+    fn count(&self, partition: &str) -> impl Future<Output = Result<Option<i64>, AppError>> + Send;
+
+    /// Reads the rows of a partition of the `archive` schema as CSV. The connection is
+    /// held until the stream ends or is dropped.
+    fn copy_out(&self, partition: &str)
+    -> impl Future<Output = Result<CsvChunks, AppError>> + Send;
+
+    /// Records the manifest of an exported partition and drops the partition, in one
+    /// transaction: the file is the only copy from then on.
+    fn record(
+        &self,
+        archive: &NewArchive<'_>,
+    ) -> impl Future<Output = Result<Archive, AppError>> + Send;
+
+    /// Retrieves the latest manifests, the latest first.
+    fn list(&self, limit: i64) -> impl Future<Output = Result<Vec<Archive>, AppError>> + Send;
+
+    /// Retrieves a manifest.
+    fn find(&self, id: Uuid) -> impl Future<Output = Result<Option<Archive>, AppError>> + Send;
+
+    /// Creates the partition of `archive` in the `archive` schema again, shaped like
+    /// its table, from the CSV of its file, and marks the manifest restored. Nothing
+    /// is kept if `csv` fails.
+    ///
+    /// `columns` are those of the file, from its header line: a column added to the
+    /// table since the export gets its default.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool, AppError>` - `false` if the partition was in the `archive`
+    ///   schema already, and is left as it is
+    fn restore<S>(
+        &self,
+        archive: &Archive,
+        columns: &[String],
+        csv: S,
+    ) -> impl Future<Output = Result<bool, AppError>> + Send
+    where
+        S: Stream<Item = Result<Bytes, AppError>> + Send;
+}
+
+/// `ArchiveRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgArchiveRepo(pub Db);
+
+impl ArchiveRepository for PgArchiveRepo {
+    async fn unexported(&self, table: &'static str) -> Result<Vec<String>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT c.relname FROM pg_class c \
+                     JOIN pg_namespace n ON n.oid = c.relnamespace \
+                     WHERE n.nspname = 'archive' AND c.relkind = 'r' \
+                     AND c.relname ~ ('^' || $1 || '_p[0-9]{4}_[0-9]{2}$') \
+                     AND NOT EXISTS (SELECT 1 FROM archives a WHERE a.partition_name = c.relname) \
+                     ORDER BY c.relname",
+                )
+                .await?;
+            let rows = conn.query(&statement, &[&table]).await?;
+            Ok(rows.iter().map(|row| row.get(0)).collect())
+        })
+        .await
+    }
+
+    async fn count(&self, partition: &str) -> Result<Option<i64>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.connection().await?;
+            let exists = conn
+                .query_one(
+                    "SELECT to_regclass('archive.' || $1::text) IS NOT NULL",
+                    &[&partition],
+                )
+                .await?
+                .get::<_, bool>(0);
+            if !exists {
+                return Ok(None);
+            }
+            let sql = format!("SELECT count(*) FROM archive.{}", partition);
+            Ok(Some(conn.query_one(&sql, &[]).await?.get(0)))
+        })
+        .await
+    }
+
+    async fn copy_out(&self, partition: &str) -> Result<CsvChunks, AppError> {
+        let conn = self.0.connection().await?;
+        let sql = format!(
+            "COPY archive.{} TO STDOUT WITH (FORMAT csv, HEADER)",
+            partition
+        );
+        let rows = Box::pin(conn.copy_out(&sql).await?);
+        // The connection goes back to the pool with the stream
+        let chunks = stream::unfold((conn, rows), async |(conn, mut rows)| {
+            let chunk = rows.next().await?;
+            Some((chunk.map_err(AppError::from), (conn, rows)))
+        });
+        Ok(Box::pin(chunks))
+    }
+
+    async fn record(&self, archive: &NewArchive<'_>) -> Result<Archive, AppError> {
+        let sql = format!(
+            "INSERT INTO archives (id, table_name, partition_name, location, row_count, \
+             byte_size, sha256) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING {}",
+            ARCHIVE_COLUMNS
+        );
+        let drop = format!("DROP TABLE archive.{}", archive.partition_name);
+        self.0
+            .transaction(async |tx| {
+                let row = tx
+                    .query_one(
+                        &sql,
+                        &[
+                            &new_public_id(),
+                            &archive.table_name,
+                            &archive.partition_name,
+                            &archive.location,
+                            &archive.row_count,
+                            &archive.byte_size,
+                            &archive.sha256,
+                        ],
+                    )
+                    .await?;
+                tx.batch_execute(&drop).await?;
+                Ok(Archive::from(&row))
+            })
+            .await
+    }
+
+    async fn list(&self, limit: i64) -> Result<Vec<Archive>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let sql = format!(
+                "SELECT {} FROM archives ORDER BY created_at DESC, id DESC LIMIT $1",
+                ARCHIVE_COLUMNS
+            );
+            let statement = conn.prepare_cached(&sql).await?;
+            let rows = conn.query(&statement, &[&limit]).await?;
+            Ok(rows.iter().map(Archive::from).collect())
+        })
+        .await
+    }
+
+    async fn find(&self, id: Uuid) -> Result<Option<Archive>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let sql = format!("SELECT {} FROM archives WHERE id = $1", ARCHIVE_COLUMNS);
+            let statement = conn.prepare_cached(&sql).await?;
+            let row = conn.query_opt(&statement, &[&id]).await?;
+            Ok(row.as_ref().map(Archive::from))
+        })
+        .await
+    }
+
+    async fn restore<S>(
+        &self,
+        archive: &Archive,
+        columns: &[String],
+        csv: S,
+    ) -> Result<bool, AppError>
+    where
+        S: Stream<Item = Result<Bytes, AppError>> + Send,
+    {
+        let create = format!(
+            "CREATE TABLE IF NOT EXISTS archive.{} (LIKE {} INCLUDING DEFAULTS)",
+            archive.partition_name, archive.table_name
+        );
+        let copy = format!(
+            "COPY archive.{} ({}) FROM STDIN WITH (FORMAT csv, HEADER)",
+            archive.partition_name,
+            columns.join(", ")
+        );
+        self.0
+            .transaction(async |tx| {
+                let exists = tx
+                    .query_one(
+                        "SELECT to_regclass('archive.' || $1::text) IS NOT NULL",
+                        &[&archive.partition_name],
+                    )
+                    .await?
+                    .get::<_, bool>(0);
+                if exists {
+                    return Ok(false);
+                }
+                tx.batch_execute(&create).await?;
+
+                let mut sink = pin!(tx.copy_in::<_, Bytes>(&copy).await?);
+                let mut csv = pin!(csv);
+                while let Some(chunk) = csv.next().await {
+                    sink.send(chunk?).await?;
+                }
+                sink.as_mut().finish().await?;
+
+                tx.execute(
+                    "UPDATE archives SET restored_at = now() WHERE id = $1",
+                    &[&archive.id],
+                )
+                .await?;
+                Ok(true)
+            })
+            .await
+    }
+}
@@ -0,0 +1,535 @@
+//! Audit log repository.
+//!
+//! Every insert, update and delete of a user, product or order is recorded in the
+//! `audit_log` table by [`record`], in the transaction of the change: a change can't be
+//! committed without its record. A record names the caller (see `AuthUser::current`),
+//! the request and the changed fields with their old and new values. Users and products
+//! are named by their UUID, the orders by their key.
+//!
+//! The records of one entity are its history, listed by `GET /users/:id/history` and
+//! `GET /products/:id/history`. Undoing the records made after a moment gives back the
+//! state the entity had then ([`rewind`], `GET /users/:id?as_of=`); entities that
+//! predate the log are rewound as far as their records go.
+//!
+//! Passwords never reach the log: accounts are recorded without their hash.
+
+use std::future::Future;
+
+use bb8_postgres::tokio_postgres::Row;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use super::ids::ResourceId;
+use super::retry::with_retry;
+use crate::auth::AuthUser;
+use crate::db::{CachedTransaction, Db};
+use crate::error::AppError;
+use crate::logging::RequestId;
+use crate::router::query::Pagination;
+
+/// Kind of the audited rows.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Entity {
+    User,
+    Product,
+    Order,
+}
+
+impl Entity {
+    pub(super) fn as_str(self) -> &'static str {
+        match self {
+            Entity::User => "user",
+            Entity::Product => "product",
+            Entity::Order => "order",
+        }
+    }
+}
+
+/// A record as listed by `GET /admin/audit`.
+#[derive(Serialize, Debug)]
+pub struct AuditRecord {
+    pub id: i64,
+    /// UUID of the caller, `None` for registrations and background jobs
+    pub actor_id: Option<Uuid>,
+    /// `insert`, `update` or `delete`
+    pub action: String,
+    pub entity: String,
+    /// UUID of a user or product, key of an order
+    pub entity_id: ResourceId,
+    /// Key of the entity, whatever its kind
+    #[serde(skip)]
+    pub entity_key: i32,
+    /// `{"field": {"old": ..., "new": ...}}`, without `old` for an insert and without
+    /// `new` for a delete
+    pub changes: Value,
+    pub request_id: Option<String>,
+    /// Timestamp in RFC 3339, UTC
+    pub created_at: String,
+}
+
+impl From<&Row> for AuditRecord {
+    fn from(row: &Row) -> Self {
+        let entity_key = row.get("entity_id");
+        let public_id: Option<Uuid> = row.get("entity_public_id");
+        AuditRecord {
+            id: row.get("id"),
+            actor_id: row.get("actor_public_id"),
+            action: row.get("action"),
+            entity: row.get("entity"),
+            entity_id: public_id.map_or(ResourceId::Key(entity_key), ResourceId::Public),
+            entity_key,
+            changes: serde_json::from_str(row.get("changes")).unwrap_or_default(),
+            request_id: row.get("request_id"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+/// Records a change of an entity, inside the transaction making it.
+///
+/// # Arguments
+///
+/// * `old` - The fields before the change, `None` for an insert
+/// * `new` - The fields after the change, `None` for a delete
+///
+/// An update changing nothing isn't recorded.
+pub(super) async fn record<T: Serialize>(
+    tx: &CachedTransaction<'_>,
+    entity: Entity,
+    entity_id: i32,
+    old: Option<&T>,
+    new: Option<&T>,
+) -> Result<(), AppError> {
+    let (old, new) = (snapshot(old)?, snapshot(new)?);
+    let action = match (&old, &new) {
+        (None, _) => "insert",
+        (_, None) => "delete",
+        _ => "update",
+    };
+    let changes = diff(old.as_ref(), new.as_ref());
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let statement = tx
+        .prepare_cached(
+            "INSERT INTO audit_log (actor_id, actor_public_id, action, entity, entity_id, \
+             entity_public_id, changes, request_id) \
+             VALUES ($1, (SELECT public_id FROM users WHERE id = $1), $2, $3, $4, $5, \
+             $6::text::jsonb, $7)",
+        )
+        .await?;
+    let public_id = public_id(new.as_ref().or(old.as_ref()));
+    let actor_id = AuthUser::current().map(|user| user.id);
+    let changes = Value::Object(changes).to_string();
+    let request_id = RequestId::current().map(|id| id.0);
+    tx.execute(
+        &statement,
+        &[
+            &actor_id,
+            &action,
+            &entity.as_str(),
+            &entity_id,
+            &public_id,
+            &changes,
+            &request_id,
+        ],
+    )
+    .await?;
+    Ok(())
+}
+
+/// Records the insert of many entities with a single statement, inside the
+/// transaction making them.
+///
+/// # Arguments
+///
+/// * `ids` - The IDs of the inserted entities
+/// * `rows` - Their fields, in the same order
+pub(super) async fn record_inserts<T: Serialize>(
+    tx: &CachedTransaction<'_>,
+    entity: Entity,
+    ids: &[i32],
+    rows: &[T],
+) -> Result<(), AppError> {
+    let snapshots = rows
+        .iter()
+        .map(|row| snapshot(Some(row)))
+        .collect::<Result<Vec<_>, AppError>>()?;
+    let changes = snapshots
+        .iter()
+        .map(|fields| Value::Object(diff(None, fields.as_ref())).to_string())
+        .collect::<Vec<_>>();
+    let public_ids = snapshots
+        .iter()
+        .map(|fields| public_id(fields.as_ref()))
+        .collect::<Vec<_>>();
+
+    let statement = tx
+        .prepare_cached(
+            "INSERT INTO audit_log (actor_id, actor_public_id, action, entity, entity_id, \
+             entity_public_id, changes, request_id) \
+             SELECT $1, (SELECT public_id FROM users WHERE id = $1), 'insert', $2, \
+             new.entity_id, new.entity_public_id, new.changes::jsonb, $3 \
+             FROM UNNEST($4::int[], $5::uuid[], $6::text[]) \
+             AS new(entity_id, entity_public_id, changes)",
+        )
+        .await?;
+    let actor_id = AuthUser::current().map(|user| user.id);
+    let request_id = RequestId::current().map(|id| id.0);
+    tx.execute(
+        &statement,
+        &[
+            &actor_id,
+            &entity.as_str(),
+            &request_id,
+            &ids,
+            &public_ids,
+            &changes,
+        ],
+    )
+    .await?;
+    Ok(())
+}
+
+/// Retrieves the records of an entity made after `as_of`, the latest first, inside a
+/// transaction reading the entity as well.
+///
+/// `as_of` is a timestamp parsed by PostgreSQL (an invalid one fails with the
+/// `INVALID_DATETIME_FORMAT` database error).
+pub(super) async fn records_after(
+    tx: &CachedTransaction<'_>,
+    entity: Entity,
+    entity_id: i32,
+    as_of: &str,
+) -> Result<Vec<AuditRecord>, AppError> {
+    let statement = tx
+        .prepare_cached(
+            "SELECT id, actor_public_id, action, entity, entity_id, entity_public_id, \
+             changes::text AS changes, request_id, \
+             to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') \
+             AS created_at \
+             FROM audit_log WHERE entity = $1 AND entity_id = $2 \
+             AND created_at > $3::text::timestamptz ORDER BY id DESC",
+        )
+        .await?;
+    let rows = tx
+        .query(&statement, &[&entity.as_str(), &entity_id, &as_of])
+        .await?;
+    Ok(rows.iter().map(AuditRecord::from).collect())
+}
+
+/// Undoes records of an entity, the latest first.
+///
+/// # Arguments
+///
+/// * `fields` - The fields of the entity after the records, `None` if it doesn't exist
+///   (anymore)
+///
+/// # Returns
+///
+/// * `Option<Map<String, Value>>` - The fields before the records, `None` if the entity
+///   didn't exist yet
+pub(super) fn rewind(
+    mut fields: Option<Map<String, Value>>,
+    records: &[AuditRecord],
+) -> Option<Map<String, Value>> {
+    let old = |change: &Value| change.get("old").cloned();
+    for record in records {
+        let changes = record.changes.as_object().cloned().unwrap_or_default();
+        match record.action.as_str() {
+            "insert" => fields = None,
+            // A delete records every field with its last value
+            "delete" => {
+                let snapshot = changes
+                    .iter()
+                    .filter_map(|(key, change)| old(change).map(|value| (key.clone(), value)));
+                fields = Some(snapshot.collect());
+            }
+            _ => {
+                let Some(fields) = fields.as_mut() else {
+                    continue;
+                };
+                for (key, change) in &changes {
+                    // Without an old value, the field didn't exist before the update
+                    match old(change) {
+                        Some(value) => fields.insert(key.clone(), value),
+                        None => fields.remove(key),
+                    };
+                }
+            }
+        }
+    }
+    fields
+}
+
+/// The fields of an entity as recorded, a JSON object.
+fn snapshot<T: Serialize>(fields: Option<&T>) -> Result<Option<Value>, AppError> {
+    fields
+        .map(serde_json::to_value)
+        .transpose()
+        .map_err(|e| AppError::Internal(format!("Audit snapshot failed: {}", e)))
+}
+
+/// UUID of the entity of a snapshot, `None` for an order, whose `id` is its key.
+fn public_id(fields: Option<&Value>) -> Option<Uuid> {
+    fields?.get("id")?.as_str()?.parse().ok()
+}
+
+/// The fields of `old` and `new` (JSON objects) whose values differ.
+fn diff(old: Option<&Value>, new: Option<&Value>) -> Map<String, Value> {
+    let fields = |value: Option<&Value>| value.and_then(Value::as_object).cloned();
+    let (old, new) = (
+        fields(old).unwrap_or_default(),
+        fields(new).unwrap_or_default(),
+    );
+
+    let mut changes = Map::new();
+    for key in old
+        .keys()
+        .chain(new.keys().filter(|key| !old.contains_key(*key)))
+    {
+        let (before, after) = (old.get(key), new.get(key));
+        if before == after {
+            continue;
+        }
+        let mut change = Map::new();
+        if let Some(before) = before {
+            change.insert("old".to_string(), before.clone());
+        }
+        if let Some(after) = after {
+            change.insert("new".to_string(), after.clone());
+        }
+        changes.insert(key.clone(), Value::Object(change));
+    }
+    changes
+}
+
+/// Operations on the `audit_log` table.
+pub trait AuditRepository {
+    /// Retrieves the latest records, optionally of an entity kind or a single entity,
+    /// by its UUID or its key.
+    fn list(
+        &self,
+        entity: Option<Entity>,
+        entity_id: Option<ResourceId>,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<AuditRecord>, AppError>> + Send;
+
+    /// Counts the records of an entity.
+    fn count_history(
+        &self,
+        entity: Entity,
+        entity_id: i32,
+    ) -> impl Future<Output = Result<i64, AppError>> + Send;
+
+    /// Finds the key of a deleted user or product by its public ID, from the record of
+    /// its delete.
+    fn deleted_key(
+        &self,
+        entity: Entity,
+        public_id: Uuid,
+    ) -> impl Future<Output = Result<Option<i32>, AppError>> + Send;
+
+    /// Retrieves a page of the records of an entity, sorted by `id` (the order of the
+    /// changes).
+    fn history(
+        &self,
+        entity: Entity,
+        entity_id: i32,
+        page: &Pagination,
+    ) -> impl Future<Output = Result<Vec<AuditRecord>, AppError>> + Send;
+}
+
+/// `AuditRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgAuditRepo(pub Db);
+
+impl AuditRepository for PgAuditRepo {
+    async fn list(
+        &self,
+        entity: Option<Entity>,
+        entity_id: Option<ResourceId>,
+        limit: i64,
+    ) -> Result<Vec<AuditRecord>, AppError> {
+        let (public_id, key) = match entity_id {
+            Some(ResourceId::Public(uuid)) => (Some(uuid), None),
+            Some(ResourceId::Key(key)) => (None, Some(key)),
+            None => (None, None),
+        };
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT id, actor_public_id, action, entity, entity_id, entity_public_id, \
+                     changes::text AS changes, request_id, \
+                     to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') \
+                     AS created_at \
+                     FROM audit_log \
+                     WHERE ($1::text IS NULL OR entity = $1) AND ($2::int IS NULL OR entity_id = $2) \
+                     AND ($3::uuid IS NULL OR entity_public_id = $3) \
+                     ORDER BY id DESC LIMIT $4",
+                )
+                .await?;
+            let entity = entity.map(Entity::as_str);
+            let rows = conn
+                .query(&statement, &[&entity, &key, &public_id, &limit])
+                .await?;
+            Ok(rows.iter().map(AuditRecord::from).collect())
+        })
+        .await
+    }
+
+    async fn count_history(&self, entity: Entity, entity_id: i32) -> Result<i64, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT COUNT(*) FROM audit_log WHERE entity = $1 AND entity_id = $2",
+                )
+                .await?;
+            let row = conn
+                .query_one(&statement, &[&entity.as_str(), &entity_id])
+                .await?;
+            Ok(row.get(0))
+        })
+        .await
+    }
+
+    async fn deleted_key(&self, entity: Entity, public_id: Uuid) -> Result<Option<i32>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            // Not indexed: deletes are few, and looked up for investigations only
+            let statement = conn
+                .prepare_cached(
+                    "SELECT entity_id FROM audit_log \
+                     WHERE entity = $1 AND action = 'delete' AND changes->'id'->>'old' = $2 \
+                     ORDER BY id DESC LIMIT 1",
+                )
+                .await?;
+            let row = conn
+                .query_opt(&statement, &[&entity.as_str(), &public_id.to_string()])
+                .await?;
+            Ok(row.map(|row| row.get("entity_id")))
+        })
+        .await
+    }
+
+    async fn history(
+        &self,
+        entity: Entity,
+        entity_id: i32,
+        page: &Pagination,
+    ) -> Result<Vec<AuditRecord>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let sql = format!(
+                "SELECT id, actor_public_id, action, entity, entity_id, entity_public_id, \
+                 changes::text AS changes, request_id, \
+                 to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') \
+                 AS created_at \
+                 FROM audit_log WHERE entity = $1 AND entity_id = $2 {} LIMIT $3 OFFSET $4",
+                page.order_by_clause()
+            );
+            // One statement per order
+            let statement = conn.prepare_cached(&sql).await?;
+            let rows = conn
+                .query(
+                    &statement,
+                    &[&entity.as_str(), &entity_id, &page.limit, &page.offset],
+                )
+                .await?;
+            Ok(rows.iter().map(AuditRecord::from).collect())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn diff_keeps_the_changed_fields() {
+        let old = json!({"name": "Ada", "age": 36});
+        let new = json!({"name": "Ada", "age": 37});
+        assert_eq!(
+            Value::Object(diff(Some(&old), Some(&new))),
+            json!({"age": {"old": 36, "new": 37}})
+        );
+        assert!(diff(Some(&old), Some(&old)).is_empty());
+    }
+
+    fn record(action: &str, changes: Value) -> AuditRecord {
+        AuditRecord {
+            id: 0,
+            actor_id: None,
+            action: action.to_string(),
+            entity: "user".to_string(),
+            entity_id: ResourceId::Public(Uuid::nil()),
+            entity_key: 1,
+            changes,
+            request_id: None,
+            created_at: String::new(),
+        }
+    }
+
+    #[test]
+    fn rewind_undoes_the_latest_records_first() {
+        let current = json!({"name": "Grace", "age": 46});
+        let records = [
+            record("update", json!({"age": {"old": 45, "new": 46}})),
+            record("update", json!({"avatar_path": {"new": "avatars/1.png"}})),
+            record(
+                "update",
+                json!({"name": {"old": "Ada", "new": "Grace"}, "age": {"old": 36, "new": 45}}),
+            ),
+        ];
+        let fields = |value: Value| value.as_object().cloned();
+
+        assert_eq!(
+            rewind(fields(current.clone()), &records[..1]),
+            fields(json!({"name": "Grace", "age": 45}))
+        );
+        assert_eq!(
+            rewind(fields(current), &records),
+            fields(json!({"name": "Ada", "age": 36}))
+        );
+    }
+
+    #[test]
+    fn rewind_brings_back_deleted_entities_only_after_their_insert() {
+        let records = [
+            record(
+                "delete",
+                json!({"name": {"old": "Ada"}, "age": {"old": 36}}),
+            ),
+            record(
+                "insert",
+                json!({"name": {"new": "Ada"}, "age": {"new": 36}}),
+            ),
+        ];
+        assert_eq!(
+            rewind(None, &records[..1]),
+            json!({"name": "Ada", "age": 36}).as_object().cloned()
+        );
+        assert_eq!(rewind(None, &records), None);
+    }
+
+    #[test]
+    fn diff_of_an_insert_or_a_delete_has_every_field() {
+        let fields = json!({"name": "Ada", "age": 36});
+        assert_eq!(
+            Value::Object(diff(None, Some(&fields))),
+            json!({"name": {"new": "Ada"}, "age": {"new": 36}})
+        );
+        assert_eq!(
+            Value::Object(diff(Some(&fields), None)),
+            json!({"name": {"old": "Ada"}, "age": {"old": 36}})
+        );
+    }
+}
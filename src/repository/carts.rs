@@ -0,0 +1,391 @@
+//! Carts repository.
+//!
+//! The carts of the users and of the anonymous visitors, and the wishlists of the
+//! users (see `carts`), in the tables of the `V30__create_carts` migration. A cart is
+//! created by its first item, and read from the primary: it's shown right after
+//! being changed.
+
+use std::future::Future;
+
+use bb8_postgres::tokio_postgres::Row;
+use bb8_postgres::tokio_postgres::error::SqlState;
+use bb8_postgres::tokio_postgres::types::ToSql;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::orders::{self, Order};
+use super::retry::with_retry;
+use crate::db::{CachedTransaction, Db};
+use crate::error::{AppError, ErrorCode};
+
+/// Most units of a product in a cart, merged carts included
+pub const MAX_QUANTITY: i32 = 100;
+
+/// Owner of a cart.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Owner {
+    /// A user, by key
+    User(i32),
+    /// The holder of a `cart` cookie, by the SHA-256 of the cookie
+    Anonymous(Vec<u8>),
+}
+
+impl Owner {
+    /// Column of `carts` identifying the cart of the owner, and its value.
+    fn key(&self) -> (&'static str, &(dyn ToSql + Sync)) {
+        match self {
+            Owner::User(id) => ("user_id", id),
+            Owner::Anonymous(digest) => ("token_digest", digest),
+        }
+    }
+}
+
+/// A product in a cart, at its current price.
+#[derive(Serialize, Debug)]
+pub struct CartItem {
+    /// Public ID of the product
+    pub product_id: Uuid,
+    pub name: String,
+    pub unit_price: f64,
+    pub quantity: i32,
+    pub subtotal: f64,
+    /// Whether the stock has the quantity now, checked again by the checkout
+    pub in_stock: bool,
+}
+
+/// A cart, its items in the order they were added.
+#[derive(Serialize, Debug, Default)]
+pub struct Cart {
+    pub items: Vec<CartItem>,
+    pub total: f64,
+}
+
+/// A product in a wishlist.
+#[derive(Serialize, Debug)]
+pub struct WishlistItem {
+    /// Public ID of the product
+    pub product_id: Uuid,
+    pub name: String,
+    pub price: f64,
+    pub stock: i32,
+    pub added_at: String,
+}
+
+/// Rounds an amount to the cent.
+fn cents(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0
+}
+
+/// Operations on the `carts`, `cart_items` and `wishlist_items` tables.
+pub trait CartRepository {
+    /// Retrieves the cart of an owner, empty if they have none.
+    fn find(&self, owner: &Owner) -> impl Future<Output = Result<Cart, AppError>> + Send;
+
+    /// Sets the quantity of a product (by key) in the cart of an owner, adding it if
+    /// needed, and returns the cart.
+    ///
+    /// Fails with `AppError::NotFound` if the product or the user doesn't exist.
+    fn set_quantity(
+        &self,
+        owner: &Owner,
+        product_id: i32,
+        quantity: i32,
+    ) -> impl Future<Output = Result<Cart, AppError>> + Send;
+
+    /// Removes a product (by key) from the cart of an owner, returning `false` if it
+    /// wasn't in it.
+    fn remove(
+        &self,
+        owner: &Owner,
+        product_id: i32,
+    ) -> impl Future<Output = Result<bool, AppError>> + Send;
+
+    /// Moves the items of an anonymous cart (by the digest of its cookie) to the cart
+    /// of a user, adding up the quantities of the products in both, up to
+    /// [`MAX_QUANTITY`]; the anonymous cart is deleted.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64, AppError>` - The number of products merged, 0 without such a cart
+    fn merge(
+        &self,
+        digest: &[u8],
+        user_id: i32,
+    ) -> impl Future<Output = Result<u64, AppError>> + Send;
+
+    /// Places an order of each item of the cart of a user, then empties it, in a
+    /// single transaction: either every order is placed or none is.
+    ///
+    /// Fails with `AppError::Conflict` if the cart is empty or a product doesn't have
+    /// enough stock.
+    fn checkout(&self, user_id: i32) -> impl Future<Output = Result<Vec<Order>, AppError>> + Send;
+
+    /// Retrieves the wishlist of a user, the latest product added first.
+    fn wishlist(
+        &self,
+        user_id: i32,
+    ) -> impl Future<Output = Result<Vec<WishlistItem>, AppError>> + Send;
+
+    /// Adds a product (by key) to the wishlist of a user, returning `false` if it was
+    /// in it already.
+    ///
+    /// Fails with `AppError::NotFound` if the product or the user doesn't exist.
+    fn wish(
+        &self,
+        user_id: i32,
+        product_id: i32,
+    ) -> impl Future<Output = Result<bool, AppError>> + Send;
+
+    /// Removes a product (by key) from the wishlist of a user, returning `false` if it
+    /// wasn't in it.
+    fn unwish(
+        &self,
+        user_id: i32,
+        product_id: i32,
+    ) -> impl Future<Output = Result<bool, AppError>> + Send;
+}
+
+/// `CartRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgCartRepo(pub Db);
+
+impl CartRepository for PgCartRepo {
+    async fn find(&self, owner: &Owner) -> Result<Cart, AppError> {
+        let (column, value) = owner.key();
+        with_retry(self.0, || async move {
+            let conn = self.0.connection().await?;
+            let statement = conn
+                .prepare_cached(&format!(
+                    "SELECT p.public_id, p.name, p.price, p.stock, i.quantity \
+                     FROM carts c \
+                     JOIN cart_items i ON i.cart_id = c.id \
+                     JOIN products p ON p.id = i.product_id \
+                     WHERE c.{} = $1 ORDER BY i.added_at, p.id",
+                    column
+                ))
+                .await?;
+            let rows = conn.query(&statement, &[value]).await?;
+            let items: Vec<CartItem> = rows
+                .iter()
+                .map(|row| {
+                    let unit_price: f64 = row.get("price");
+                    let quantity: i32 = row.get("quantity");
+                    CartItem {
+                        product_id: row.get("public_id"),
+                        name: row.get("name"),
+                        unit_price,
+                        quantity,
+                        subtotal: cents(unit_price * f64::from(quantity)),
+                        in_stock: row.get::<_, i32>("stock") >= quantity,
+                    }
+                })
+                .collect();
+            let total = cents(items.iter().map(|item| item.subtotal).sum());
+            Ok(Cart { items, total })
+        })
+        .await
+    }
+
+    async fn set_quantity(
+        &self,
+        owner: &Owner,
+        product_id: i32,
+        quantity: i32,
+    ) -> Result<Cart, AppError> {
+        let result = self
+            .0
+            .transaction(async |tx| {
+                let cart_id = cart_of(tx, owner).await?;
+                tx.execute(
+                    "INSERT INTO cart_items (cart_id, product_id, quantity) VALUES ($1, $2, $3) \
+                 ON CONFLICT (cart_id, product_id) DO UPDATE SET quantity = EXCLUDED.quantity",
+                    &[&cart_id, &product_id, &quantity],
+                )
+                .await?;
+                Ok(())
+            })
+            .await;
+        match result {
+            Err(AppError::Db(e)) if e.code() == Some(&SqlState::FOREIGN_KEY_VIOLATION) => {
+                Err(missing(&e))
+            }
+            result => result,
+        }?;
+        self.find(owner).await
+    }
+
+    async fn remove(&self, owner: &Owner, product_id: i32) -> Result<bool, AppError> {
+        let (column, value) = owner.key();
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(&format!(
+                "WITH removed AS (\
+                     DELETE FROM cart_items i USING carts c \
+                     WHERE i.cart_id = c.id AND c.{} = $1 AND i.product_id = $2 \
+                     RETURNING c.id\
+                 ) \
+                 UPDATE carts SET updated_at = now() WHERE id IN (SELECT id FROM removed)",
+                column
+            ))
+            .await?;
+        Ok(conn.execute(&statement, &[value, &product_id]).await? > 0)
+    }
+
+    async fn merge(&self, digest: &[u8], user_id: i32) -> Result<u64, AppError> {
+        self.0
+            .transaction(async |tx| {
+                let Some(anonymous) = tx
+                    .query_opt(
+                        "SELECT id FROM carts WHERE token_digest = $1 FOR UPDATE",
+                        &[&digest],
+                    )
+                    .await?
+                else {
+                    return Ok(0);
+                };
+                let anonymous: i32 = anonymous.get("id");
+                let cart_id = cart_of(tx, &Owner::User(user_id)).await?;
+                let merged = tx
+                    .execute(
+                        "INSERT INTO cart_items (cart_id, product_id, quantity, added_at) \
+                     SELECT $1, product_id, quantity, added_at FROM cart_items \
+                     WHERE cart_id = $2 \
+                     ON CONFLICT (cart_id, product_id) DO UPDATE \
+                     SET quantity = LEAST(cart_items.quantity + EXCLUDED.quantity, $3)",
+                        &[&cart_id, &anonymous, &MAX_QUANTITY],
+                    )
+                    .await?;
+                tx.execute("DELETE FROM carts WHERE id = $1", &[&anonymous])
+                    .await?;
+                Ok(merged)
+            })
+            .await
+    }
+
+    async fn checkout(&self, user_id: i32) -> Result<Vec<Order>, AppError> {
+        self.0
+            .transaction(async |tx| {
+                // Locks the cart, so that concurrent checkouts don't order it twice
+                let cart = tx
+                    .query_opt(
+                        "SELECT id FROM carts WHERE user_id = $1 FOR UPDATE",
+                        &[&user_id],
+                    )
+                    .await?;
+                let empty =
+                    || AppError::Conflict(ErrorCode::CartEmpty, "The cart is empty".to_string());
+                let cart_id: i32 = cart.ok_or_else(empty)?.get("id");
+                // By product, the order in which every order locks them, so that a checkout
+                // and other orders can't deadlock
+                let items = tx
+                    .query(
+                        "SELECT product_id, quantity FROM cart_items WHERE cart_id = $1 \
+                     ORDER BY product_id",
+                        &[&cart_id],
+                    )
+                    .await?;
+                if items.is_empty() {
+                    return Err(empty());
+                }
+
+                let mut placed = Vec::with_capacity(items.len());
+                for item in &items {
+                    let order = orders::place_in(
+                        tx,
+                        user_id,
+                        item.get("product_id"),
+                        item.get("quantity"),
+                        None,
+                    )
+                    .await?;
+                    placed.push(order);
+                }
+                tx.execute("DELETE FROM carts WHERE id = $1", &[&cart_id])
+                    .await?;
+                Ok(placed)
+            })
+            .await
+    }
+
+    async fn wishlist(&self, user_id: i32) -> Result<Vec<WishlistItem>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT p.public_id, p.name, p.price, p.stock, \
+                     to_char(w.added_at AT TIME ZONE 'UTC', \
+                     'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS added_at \
+                     FROM wishlist_items w JOIN products p ON p.id = w.product_id \
+                     WHERE w.user_id = $1 ORDER BY w.added_at DESC, p.id DESC",
+                )
+                .await?;
+            let rows = conn.query(&statement, &[&user_id]).await?;
+            Ok(rows.iter().map(WishlistItem::from).collect())
+        })
+        .await
+    }
+
+    async fn wish(&self, user_id: i32, product_id: i32) -> Result<bool, AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "INSERT INTO wishlist_items (user_id, product_id) VALUES ($1, $2) \
+                 ON CONFLICT DO NOTHING",
+            )
+            .await?;
+        match conn.execute(&statement, &[&user_id, &product_id]).await {
+            Ok(added) => Ok(added > 0),
+            Err(e) if e.code() == Some(&SqlState::FOREIGN_KEY_VIOLATION) => Err(missing(&e)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn unwish(&self, user_id: i32, product_id: i32) -> Result<bool, AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached("DELETE FROM wishlist_items WHERE user_id = $1 AND product_id = $2")
+            .await?;
+        Ok(conn.execute(&statement, &[&user_id, &product_id]).await? > 0)
+    }
+}
+
+impl From<&Row> for WishlistItem {
+    fn from(row: &Row) -> Self {
+        WishlistItem {
+            product_id: row.get("public_id"),
+            name: row.get("name"),
+            price: row.get("price"),
+            stock: row.get("stock"),
+            added_at: row.get("added_at"),
+        }
+    }
+}
+
+/// The cart of an owner (by key), created if they have none, marked as changed.
+async fn cart_of(tx: &CachedTransaction<'_>, owner: &Owner) -> Result<i32, AppError> {
+    let (column, value) = owner.key();
+    let row = tx
+        .query_one(
+            &format!(
+                "INSERT INTO carts ({0}) VALUES ($1) \
+                 ON CONFLICT ({0}) DO UPDATE SET updated_at = now() RETURNING id",
+                column
+            ),
+            &[value],
+        )
+        .await?;
+    Ok(row.get("id"))
+}
+
+/// The error of a foreign key violation: the product or the user is missing.
+fn missing(e: &bb8_postgres::tokio_postgres::Error) -> AppError {
+    let constraint = e
+        .as_db_error()
+        .and_then(|e| e.constraint())
+        .unwrap_or_default();
+    if constraint.ends_with("user_id_fkey") {
+        AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string())
+    } else {
+        AppError::NotFound(ErrorCode::ProductNotFound, "Product not found".to_string())
+    }
+}
@@ -0,0 +1,252 @@
+//! Change feed repository.
+//!
+//! The feed of a table is its records in the audit log (see `audit`), sorted by
+//! position: the transaction of the record, then its ID. Transactions don't commit in
+//! the order of their IDs, so a record is only read once every transaction that could
+//! still write before it is finished (the `xmin` of the snapshot): a record never
+//! appears behind a position already read, and a consumer resuming from the last one
+//! it read misses nothing. A long transaction holds the feed back until it ends.
+//!
+//! The feed goes as far back as the audit log: the records purged by the retention
+//! rules or archived with their partition are gone from it.
+
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+
+use bb8_postgres::tokio_postgres::Row;
+use serde::Serialize;
+
+use super::audit::{AuditRecord, Entity};
+use super::retry::with_retry;
+use crate::db::Db;
+use crate::error::AppError;
+
+/// Where a consumer is in a feed, `<transaction>-<record>` (`0-0` before the first
+/// record).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    xid: u64,
+    id: i64,
+}
+
+impl FromStr for Position {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid position '{}' (as in `next`)", s);
+        let (xid, id) = s.split_once('-').ok_or_else(invalid)?;
+        Ok(Position {
+            xid: xid.parse().map_err(|_| invalid())?,
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.xid, self.id)
+    }
+}
+
+impl Serialize for Position {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A record of the feed.
+#[derive(Serialize, Debug)]
+pub struct Change {
+    pub position: Position,
+    #[serde(flatten)]
+    pub record: AuditRecord,
+}
+
+impl From<&Row> for Change {
+    fn from(row: &Row) -> Self {
+        let xid: String = row.get("xid");
+        Change {
+            position: Position {
+                xid: xid.parse().unwrap_or_default(),
+                id: row.get("id"),
+            },
+            record: AuditRecord::from(row),
+        }
+    }
+}
+
+/// The saved position of a consumer in the feed of a table.
+#[derive(Serialize, Debug)]
+pub struct Checkpoint {
+    pub consumer: String,
+    pub table: String,
+    pub position: Position,
+    /// Timestamp in RFC 3339, UTC
+    pub updated_at: String,
+}
+
+impl From<&Row> for Checkpoint {
+    fn from(row: &Row) -> Self {
+        let position: String = row.get("position");
+        Checkpoint {
+            consumer: row.get("consumer"),
+            table: row.get("table_name"),
+            // Only valid positions are saved
+            position: position.parse().unwrap_or_default(),
+            updated_at: row.get("updated_at"),
+        }
+    }
+}
+
+/// Operations on the change feeds and the checkpoints of their consumers.
+pub trait CdcRepository {
+    /// Retrieves the records of `entity` after `from`, in the order of the feed.
+    fn changes(
+        &self,
+        entity: Entity,
+        from: Position,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<Change>, AppError>> + Send;
+
+    /// Retrieves the position of the last record of `entity` in the feed, `0-0` when
+    /// it has none.
+    fn head(&self, entity: Entity) -> impl Future<Output = Result<Position, AppError>> + Send;
+
+    /// Retrieves the checkpoint of `consumer` in the feed of `table`.
+    fn checkpoint(
+        &self,
+        consumer: &str,
+        table: &str,
+    ) -> impl Future<Output = Result<Option<Checkpoint>, AppError>> + Send;
+
+    /// Saves the position of `consumer` in the feed of `table`.
+    fn save_checkpoint(
+        &self,
+        consumer: &str,
+        table: &str,
+        position: Position,
+    ) -> impl Future<Output = Result<Checkpoint, AppError>> + Send;
+}
+
+/// `CdcRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgCdcRepo(pub Db);
+
+impl CdcRepository for PgCdcRepo {
+    async fn changes(
+        &self,
+        entity: Entity,
+        from: Position,
+        limit: i64,
+    ) -> Result<Vec<Change>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT xid::text AS xid, id, actor_public_id, action, entity, entity_id, \
+                     entity_public_id, changes::text AS changes, request_id, \
+                     to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') \
+                     AS created_at \
+                     FROM audit_log \
+                     WHERE entity = $1 AND (xid, id) > ($2::text::xid8, $3) \
+                     AND xid < pg_snapshot_xmin(pg_current_snapshot()) \
+                     ORDER BY xid, id LIMIT $4",
+                )
+                .await?;
+            let rows = conn
+                .query(
+                    &statement,
+                    &[&entity.as_str(), &from.xid.to_string(), &from.id, &limit],
+                )
+                .await?;
+            Ok(rows.iter().map(Change::from).collect())
+        })
+        .await
+    }
+
+    async fn head(&self, entity: Entity) -> Result<Position, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT xid::text AS xid, id FROM audit_log \
+                     WHERE entity = $1 AND xid < pg_snapshot_xmin(pg_current_snapshot()) \
+                     ORDER BY xid DESC, id DESC LIMIT 1",
+                )
+                .await?;
+            let row = conn.query_opt(&statement, &[&entity.as_str()]).await?;
+            Ok(row.map_or_else(Position::default, |row| {
+                let xid: String = row.get("xid");
+                Position {
+                    xid: xid.parse().unwrap_or_default(),
+                    id: row.get("id"),
+                }
+            }))
+        })
+        .await
+    }
+
+    async fn checkpoint(
+        &self,
+        consumer: &str,
+        table: &str,
+    ) -> Result<Option<Checkpoint>, AppError> {
+        with_retry(self.0, || async move {
+            // From the primary: a consumer resumes from the position it just saved
+            let conn = self.0.connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT consumer, table_name, position, \
+                     to_char(updated_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') \
+                     AS updated_at \
+                     FROM cdc_checkpoints WHERE consumer = $1 AND table_name = $2",
+                )
+                .await?;
+            let row = conn.query_opt(&statement, &[&consumer, &table]).await?;
+            Ok(row.as_ref().map(Checkpoint::from))
+        })
+        .await
+    }
+
+    async fn save_checkpoint(
+        &self,
+        consumer: &str,
+        table: &str,
+        position: Position,
+    ) -> Result<Checkpoint, AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "INSERT INTO cdc_checkpoints (consumer, table_name, position) \
+                 VALUES ($1, $2, $3) \
+                 ON CONFLICT (consumer, table_name) \
+                 DO UPDATE SET position = EXCLUDED.position, updated_at = now() \
+                 RETURNING consumer, table_name, position, \
+                 to_char(updated_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') \
+                 AS updated_at",
+            )
+            .await?;
+        let row = conn
+            .query_one(&statement, &[&consumer, &table, &position.to_string()])
+            .await?;
+        Ok(Checkpoint::from(&row))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_round_trip_and_sort_by_transaction_first() {
+        let position = "742-1234".parse::<Position>().unwrap();
+        assert_eq!(position.to_string(), "742-1234");
+        assert!(position < "743-5".parse().unwrap());
+        assert!(position > "742-1233".parse().unwrap());
+        assert_eq!("0-0".parse::<Position>().unwrap(), Position::default());
+        for invalid in ["", "742", "742-", "-5", "a-1", "1-2-3"] {
+            assert!(invalid.parse::<Position>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Console repository.
+//!
+//! Runs the queries of the admin console (see the `console` module), whose SQL comes
+//! from the configuration only, in read-only transactions.
+
+use std::future::Future;
+
+use bb8_postgres::tokio_postgres::types::{ToSql, Type};
+use serde_json::Value;
+
+use super::retry::with_retry;
+use crate::db::Db;
+use crate::error::AppError;
+
+/// Operations running the queries of the console.
+pub trait ConsoleRepository {
+    /// Runs `sql` with `params`, sent as `types`, in a read-only transaction.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<Value>, AppError>` - At most `limit` rows, each an object of its
+    ///   columns in their order
+    fn run(
+        &self,
+        sql: &str,
+        types: &[Type],
+        params: &[Box<dyn ToSql + Send + Sync>],
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<Value>, AppError>> + Send;
+}
+
+/// `ConsoleRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgConsoleRepo(pub Db);
+
+impl ConsoleRepository for PgConsoleRepo {
+    async fn run(
+        &self,
+        sql: &str,
+        types: &[Type],
+        params: &[Box<dyn ToSql + Send + Sync>],
+        limit: i64,
+    ) -> Result<Vec<Value>, AppError> {
+        // On its own line, so a comment ending the query doesn't hide the parenthesis
+        let sql = format!(
+            "SELECT row_to_json(q)::text FROM ({}\n) q LIMIT {}",
+            sql, limit
+        );
+        with_retry(self.0, || async {
+            let mut conn = self.0.read_connection().await?;
+            let tx = conn.cached_transaction().await?;
+            tx.batch_execute("SET TRANSACTION READ ONLY").await?;
+            // Not cached: each query is run once in a while
+            let statement = tx.prepare_typed(&sql, types).await?;
+            if statement.params().len() != types.len() {
+                return Err(AppError::Internal(format!(
+                    "The query has {} parameters, {} are declared",
+                    statement.params().len(),
+                    types.len()
+                )));
+            }
+            let params = params
+                .iter()
+                .map(|param| param.as_ref() as &(dyn ToSql + Sync))
+                .collect::<Vec<_>>();
+            let rows = tx.query(&statement, &params).await?;
+            tx.commit().await?;
+
+            rows.iter()
+                .map(|row| {
+                    serde_json::from_str(row.get(0))
+                        .map_err(|e| AppError::Internal(format!("Invalid row: {}", e)))
+                })
+                .collect()
+        })
+        .await
+    }
+}
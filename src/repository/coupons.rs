@@ -0,0 +1,372 @@
+//! Coupons repository.
+//!
+//! Codes taking a percentage or a fixed amount off an order, in the tables of the
+//! `V29__create_coupons` migration. A coupon is redeemed in the transaction placing
+//! the order (see `orders`): the conditional update counting the redemption locks the
+//! coupon, so concurrent orders can't redeem it past its limits, and the order, the
+//! count and the record of the redemption are written together or not at all.
+//!
+//! Amounts are in cents.
+
+use std::future::Future;
+
+use bb8_postgres::tokio_postgres::Row;
+use bb8_postgres::tokio_postgres::error::SqlState;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::ids::new_public_id;
+use super::retry::with_retry;
+use crate::auth::AuthUser;
+use crate::db::{CachedTransaction, Db};
+use crate::error::{AppError, ErrorCode};
+use crate::validation::ValidationErrors;
+
+/// Columns of a `Coupon`
+const COUPON_COLUMNS: &str = "public_id, code, percent_off, amount_off, min_order_amount, \
+     to_char(expires_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS expires_at, \
+     max_redemptions, max_per_user, redemptions, active, \
+     to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS created_at";
+
+/// A coupon, as listed to the administrators.
+#[derive(Serialize, Debug)]
+pub struct Coupon {
+    #[serde(rename = "id")]
+    pub public_id: Uuid,
+    pub code: String,
+    /// Either `percent_off` or `amount_off` is set
+    pub percent_off: Option<i32>,
+    pub amount_off: Option<i64>,
+    pub min_order_amount: i64,
+    /// Timestamps in RFC 3339, UTC
+    pub expires_at: Option<String>,
+    pub max_redemptions: Option<i32>,
+    pub max_per_user: Option<i32>,
+    pub redemptions: i32,
+    /// `false` once deleted, the redemptions are kept
+    pub active: bool,
+    pub created_at: String,
+}
+
+impl From<&Row> for Coupon {
+    fn from(row: &Row) -> Self {
+        Coupon {
+            public_id: row.get("public_id"),
+            code: row.get("code"),
+            percent_off: row.get("percent_off"),
+            amount_off: row.get("amount_off"),
+            min_order_amount: row.get("min_order_amount"),
+            expires_at: row.get("expires_at"),
+            max_redemptions: row.get("max_redemptions"),
+            max_per_user: row.get("max_per_user"),
+            redemptions: row.get("redemptions"),
+            active: row.get("active"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+/// Fields of a coupon set by clients.
+#[derive(Deserialize, Debug)]
+pub struct NewCoupon {
+    pub code: String,
+    #[serde(default)]
+    pub percent_off: Option<i32>,
+    #[serde(default)]
+    pub amount_off: Option<i64>,
+    #[serde(default)]
+    pub min_order_amount: i64,
+    /// RFC 3339
+    #[serde(default)]
+    pub expires_at: Option<String>,
+    #[serde(default)]
+    pub max_redemptions: Option<i32>,
+    #[serde(default)]
+    pub max_per_user: Option<i32>,
+}
+
+/// A redemption of a coupon, from its audit trail.
+#[derive(Serialize, Debug)]
+pub struct Redemption {
+    /// Public ID of the user, `None` once deleted
+    pub user_id: Option<Uuid>,
+    pub order_id: i32,
+    pub discount: i64,
+    pub created_at: String,
+}
+
+/// A coupon redeemed by an order being placed.
+#[derive(Debug, Clone)]
+pub struct Redeemed {
+    /// Key of the coupon
+    pub coupon_id: i32,
+    pub code: String,
+    pub discount: i64,
+}
+
+/// Discount of a coupon on a subtotal: a percentage of it rounded to the nearest
+/// cent, or a fixed amount, never more than the subtotal.
+pub fn discount(percent_off: Option<i32>, amount_off: Option<i64>, subtotal: i64) -> i64 {
+    let discount = match (percent_off, amount_off) {
+        (Some(percent), _) => (subtotal * i64::from(percent) + 50) / 100,
+        (None, Some(amount)) => amount,
+        (None, None) => 0,
+    };
+    discount.clamp(0, subtotal.max(0))
+}
+
+/// Operations on the `coupons` and `coupon_redemptions` tables.
+pub trait CouponRepository {
+    /// Creates a coupon, its code in upper case.
+    ///
+    /// Fails with `AppError::Conflict` if the code is taken, and with
+    /// `AppError::Unprocessable` if `expires_at` isn't a future timestamp.
+    fn create(&self, coupon: &NewCoupon) -> impl Future<Output = Result<Coupon, AppError>> + Send;
+
+    /// Retrieves the coupons, the latest first.
+    fn list(&self) -> impl Future<Output = Result<Vec<Coupon>, AppError>> + Send;
+
+    /// Deactivates a coupon (by public ID), returning `false` if it doesn't exist.
+    fn deactivate(&self, id: Uuid) -> impl Future<Output = Result<bool, AppError>> + Send;
+
+    /// Retrieves the latest redemptions of a coupon (by public ID), the latest first;
+    /// `None` if it doesn't exist.
+    fn redemptions(
+        &self,
+        id: Uuid,
+        limit: i64,
+    ) -> impl Future<Output = Result<Option<Vec<Redemption>>, AppError>> + Send;
+}
+
+/// `CouponRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgCouponRepo(pub Db);
+
+impl CouponRepository for PgCouponRepo {
+    async fn create(&self, coupon: &NewCoupon) -> Result<Coupon, AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(&format!(
+                "INSERT INTO coupons (public_id, code, percent_off, amount_off, \
+                 min_order_amount, expires_at, max_redemptions, max_per_user, created_by) \
+                 SELECT $1, $2, $3, $4, $5, expires_at, $7, $8, $9 \
+                 FROM (SELECT $6::text::timestamptz AS expires_at) AS given \
+                 WHERE expires_at IS NULL OR expires_at > now() \
+                 RETURNING {}",
+                COUPON_COLUMNS
+            ))
+            .await?;
+        let created_by = AuthUser::current().map(|user| user.id);
+        let result = conn
+            .query_opt(
+                &statement,
+                &[
+                    &new_public_id(),
+                    &coupon.code.trim().to_uppercase(),
+                    &coupon.percent_off,
+                    &coupon.amount_off,
+                    &coupon.min_order_amount,
+                    &coupon.expires_at,
+                    &coupon.max_redemptions,
+                    &coupon.max_per_user,
+                    &created_by,
+                ],
+            )
+            .await;
+
+        let invalid_expiry = || {
+            let mut errors = ValidationErrors::default();
+            errors.check("expires_at", false, "must be a future RFC 3339 timestamp");
+            AppError::Unprocessable(errors)
+        };
+        match result {
+            Ok(Some(row)) => Ok(Coupon::from(&row)),
+            // In the past
+            Ok(None) => Err(invalid_expiry()),
+            Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => Err(AppError::Conflict(
+                ErrorCode::CouponTaken,
+                "Another coupon has this code".to_string(),
+            )),
+            Err(e)
+                if e.code() == Some(&SqlState::INVALID_DATETIME_FORMAT)
+                    || e.code() == Some(&SqlState::DATETIME_FIELD_OVERFLOW) =>
+            {
+                Err(invalid_expiry())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<Coupon>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(&format!(
+                    "SELECT {} FROM coupons ORDER BY id DESC",
+                    COUPON_COLUMNS
+                ))
+                .await?;
+            let rows = conn.query(&statement, &[]).await?;
+            Ok(rows.iter().map(Coupon::from).collect())
+        })
+        .await
+    }
+
+    async fn deactivate(&self, id: Uuid) -> Result<bool, AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached("UPDATE coupons SET active = false WHERE public_id = $1")
+            .await?;
+        Ok(conn.execute(&statement, &[&id]).await? > 0)
+    }
+
+    async fn redemptions(&self, id: Uuid, limit: i64) -> Result<Option<Vec<Redemption>>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached("SELECT id FROM coupons WHERE public_id = $1")
+                .await?;
+            let Some(coupon) = conn.query_opt(&statement, &[&id]).await? else {
+                return Ok(None);
+            };
+            let coupon_id: i32 = coupon.get("id");
+            let statement = conn
+                .prepare_cached(
+                    "SELECT users.public_id AS user_id, r.order_id, r.discount, \
+                     to_char(r.created_at AT TIME ZONE 'UTC', \
+                     'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS created_at \
+                     FROM coupon_redemptions r LEFT JOIN users ON users.id = r.user_id \
+                     WHERE r.coupon_id = $1 ORDER BY r.id DESC LIMIT $2",
+                )
+                .await?;
+            let rows = conn.query(&statement, &[&coupon_id, &limit]).await?;
+            let redemptions = rows
+                .iter()
+                .map(|row| Redemption {
+                    user_id: row.get("user_id"),
+                    order_id: row.get("order_id"),
+                    discount: row.get("discount"),
+                    created_at: row.get("created_at"),
+                })
+                .collect();
+            Ok(Some(redemptions))
+        })
+        .await
+    }
+}
+
+/// Redeems a coupon for an order of `subtotal` cents by a user, in the transaction
+/// placing it; the order must then be recorded with [`record_redemption`].
+///
+/// The redemption is counted by a conditional update, which locks the coupon until
+/// the transaction ends: a concurrent order waits, then sees the count of this one.
+///
+/// # Returns
+///
+/// * `Result<Redeemed, AppError>` - The coupon and its discount, an
+///   `AppError::NotFound` if no coupon has the code, or an `AppError::Conflict` if
+///   it's expired, deactivated, used up (by everyone or by the user), or the order too
+///   small
+pub async fn redeem(
+    tx: &CachedTransaction<'_>,
+    code: &str,
+    user_id: i32,
+    subtotal: i64,
+) -> Result<Redeemed, AppError> {
+    let code = code.trim().to_uppercase();
+    let unavailable =
+        |message: &str| AppError::Conflict(ErrorCode::CouponUnavailable, message.to_string());
+
+    let counted = tx
+        .query_opt(
+            "UPDATE coupons SET redemptions = redemptions + 1 \
+             WHERE code = $1 AND active AND (expires_at IS NULL OR expires_at > now()) \
+             AND (max_redemptions IS NULL OR redemptions < max_redemptions) \
+             RETURNING id, percent_off, amount_off, min_order_amount, max_per_user",
+            &[&code],
+        )
+        .await?;
+    let Some(coupon) = counted else {
+        // Why, for the client
+        let coupon = tx
+            .query_opt(
+                "SELECT active, expires_at IS NOT NULL AND expires_at <= now() AS expired \
+                 FROM coupons WHERE code = $1",
+                &[&code],
+            )
+            .await?
+            .ok_or_else(|| {
+                AppError::NotFound(ErrorCode::CouponNotFound, "Coupon not found".to_string())
+            })?;
+        return Err(if !coupon.get::<_, bool>("active") {
+            unavailable("The coupon was withdrawn")
+        } else if coupon.get::<_, bool>("expired") {
+            unavailable("The coupon expired")
+        } else {
+            unavailable("The coupon was used up")
+        });
+    };
+
+    let coupon_id: i32 = coupon.get("id");
+    let min_order_amount: i64 = coupon.get("min_order_amount");
+    if subtotal < min_order_amount {
+        return Err(unavailable(&format!(
+            "The coupon needs an order of {} cents or more",
+            min_order_amount
+        )));
+    }
+    // The coupon is locked, the redemptions of the user can't change meanwhile
+    if let Some(max_per_user) = coupon.get::<_, Option<i32>>("max_per_user") {
+        let used: i64 = tx
+            .query_one(
+                "SELECT count(*) FROM coupon_redemptions WHERE coupon_id = $1 AND user_id = $2",
+                &[&coupon_id, &user_id],
+            )
+            .await?
+            .get(0);
+        if used >= i64::from(max_per_user) {
+            return Err(unavailable("The user already used the coupon"));
+        }
+    }
+
+    Ok(Redeemed {
+        coupon_id,
+        code,
+        discount: discount(
+            coupon.get("percent_off"),
+            coupon.get("amount_off"),
+            subtotal,
+        ),
+    })
+}
+
+/// Records the redemption of a coupon by an order, in the transaction placing it.
+pub async fn record_redemption(
+    tx: &CachedTransaction<'_>,
+    redeemed: &Redeemed,
+    user_id: i32,
+    order_id: i32,
+) -> Result<(), AppError> {
+    tx.execute(
+        "INSERT INTO coupon_redemptions (coupon_id, user_id, order_id, discount) \
+         VALUES ($1, $2, $3, $4)",
+        &[&redeemed.coupon_id, &user_id, &order_id, &redeemed.discount],
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discounts_never_exceed_the_subtotal() {
+        assert_eq!(discount(Some(10), None, 1999), 200);
+        assert_eq!(discount(Some(15), None, 333), 50);
+        assert_eq!(discount(Some(100), None, 1999), 1999);
+        assert_eq!(discount(None, Some(500), 1999), 500);
+        assert_eq!(discount(None, Some(500), 300), 300);
+        assert_eq!(discount(None, Some(500), 0), 0);
+    }
+}
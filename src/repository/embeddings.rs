@@ -0,0 +1,140 @@
+//! Product embeddings repository.
+//!
+//! The embedding of each product's text, computed by a model (see `embeddings`), and
+//! the products nearest to the embedding of a search.
+
+use std::future::Future;
+
+use super::products::Product;
+use super::retry::with_retry;
+use crate::db::Db;
+use crate::error::AppError;
+
+/// Operations on the `product_embeddings` table.
+///
+/// Embeddings are unit vectors, so their similarity is their dot product.
+pub trait EmbeddingRepository {
+    /// Whether the pgvector extension is installed in the database.
+    fn has_pgvector(&self) -> impl Future<Output = Result<bool, AppError>> + Send;
+
+    /// Retrieves the products without an embedding of their current text by `model`,
+    /// at most `limit`, with that text.
+    fn stale(
+        &self,
+        model: &str,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<(i32, String)>, AppError>> + Send;
+
+    /// Saves the embedding of `content`, the text of a product, replacing the previous
+    /// one. Nothing is saved if the product was deleted meanwhile.
+    fn save(
+        &self,
+        product_id: i32,
+        model: &str,
+        content: &str,
+        embedding: &[f32],
+    ) -> impl Future<Output = Result<(), AppError>> + Send;
+
+    /// Retrieves the products whose embedding by `model` is the nearest to `embedding`,
+    /// with their cosine similarity, the nearest first.
+    ///
+    /// # Arguments
+    ///
+    /// * `pgvector` - Whether to compare with the operators of pgvector, or with plain
+    ///   SQL when the extension isn't installed
+    fn nearest(
+        &self,
+        model: &str,
+        embedding: &[f32],
+        limit: i64,
+        pgvector: bool,
+    ) -> impl Future<Output = Result<Vec<(Product, f64)>, AppError>> + Send;
+}
+
+/// `EmbeddingRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgEmbeddingRepo(pub Db);
+
+impl EmbeddingRepository for PgEmbeddingRepo {
+    async fn has_pgvector(&self) -> Result<bool, AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached("SELECT EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'vector')")
+            .await?;
+        let row = conn.query_one(&statement, &[]).await?;
+        Ok(row.get(0))
+    }
+
+    async fn stale(&self, model: &str, limit: i64) -> Result<Vec<(i32, String)>, AppError> {
+        // On the primary: the embeddings just saved may not be on the replica yet
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "SELECT p.id, p.name FROM products p \
+                 LEFT JOIN product_embeddings e ON e.product_id = p.id \
+                 WHERE e.product_id IS NULL OR e.model <> $1 OR e.content <> p.name \
+                 ORDER BY p.id LIMIT $2",
+            )
+            .await?;
+        let rows = conn.query(&statement, &[&model, &limit]).await?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    async fn save(
+        &self,
+        product_id: i32,
+        model: &str,
+        content: &str,
+        embedding: &[f32],
+    ) -> Result<(), AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "INSERT INTO product_embeddings (product_id, model, content, embedding) \
+                 SELECT id, $2, $3, $4 FROM products WHERE id = $1 \
+                 ON CONFLICT (product_id) DO UPDATE \
+                 SET model = EXCLUDED.model, content = EXCLUDED.content, \
+                 embedding = EXCLUDED.embedding, updated_at = now()",
+            )
+            .await?;
+        conn.execute(&statement, &[&product_id, &model, &content, &embedding])
+            .await?;
+        Ok(())
+    }
+
+    async fn nearest(
+        &self,
+        model: &str,
+        embedding: &[f32],
+        limit: i64,
+        pgvector: bool,
+    ) -> Result<Vec<(Product, f64)>, AppError> {
+        // Embeddings of another size, from a model with the same name, can't be compared
+        let sql = if pgvector {
+            "SELECT p.id, p.public_id, p.name, p.price, p.stock, p.version, \
+             1 - (e.embedding::vector <=> $2::real[]::vector) AS similarity \
+             FROM product_embeddings e JOIN products p ON p.id = e.product_id \
+             WHERE e.model = $1 AND cardinality(e.embedding) = cardinality($2::real[]) \
+             ORDER BY e.embedding::vector <=> $2::real[]::vector, p.id LIMIT $3"
+        } else {
+            "SELECT p.id, p.public_id, p.name, p.price, p.stock, p.version, \
+             s.similarity FROM product_embeddings e JOIN products p ON p.id = e.product_id \
+             CROSS JOIN LATERAL (SELECT sum(a::float8 * b) AS similarity \
+             FROM unnest(e.embedding, $2::real[]) AS v (a, b)) AS s \
+             WHERE e.model = $1 AND cardinality(e.embedding) = cardinality($2::real[]) \
+             ORDER BY s.similarity DESC, p.id LIMIT $3"
+        };
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn.prepare_cached(sql).await?;
+            let rows = conn
+                .query(&statement, &[&model, &embedding, &limit])
+                .await?;
+            Ok(rows
+                .iter()
+                .map(|row| (Product::from(row), row.get("similarity")))
+                .collect())
+        })
+        .await
+    }
+}
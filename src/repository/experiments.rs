@@ -0,0 +1,52 @@
+//! Experiment exposures repository.
+//!
+//! Rows of the `experiment_exposures` table, written in batches by the `experiments`
+//! module.
+
+use std::future::Future;
+
+use crate::db::Db;
+use crate::error::AppError;
+
+/// A subject that was sent its variant of an experiment.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Exposure {
+    pub experiment: String,
+    /// `user:<id>` or `ip:<address>`
+    pub subject: String,
+    pub variant: String,
+}
+
+/// Operations on the `experiment_exposures` table.
+pub trait ExposureRepository {
+    /// Inserts the exposures of subjects not exposed yet, returning how many.
+    /// The earlier exposures of a subject are kept.
+    fn record(&self, exposures: &[Exposure]) -> impl Future<Output = Result<u64, AppError>> + Send;
+}
+
+/// `ExposureRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgExposureRepo(pub Db);
+
+impl ExposureRepository for PgExposureRepo {
+    async fn record(&self, exposures: &[Exposure]) -> Result<u64, AppError> {
+        let (mut experiments, mut subjects, mut variants) = (Vec::new(), Vec::new(), Vec::new());
+        for exposure in exposures {
+            experiments.push(exposure.experiment.as_str());
+            subjects.push(exposure.subject.as_str());
+            variants.push(exposure.variant.as_str());
+        }
+
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "INSERT INTO experiment_exposures (experiment, subject, variant) \
+                 SELECT * FROM UNNEST($1::text[], $2::text[], $3::text[]) \
+                 ON CONFLICT DO NOTHING",
+            )
+            .await?;
+        Ok(conn
+            .execute(&statement, &[&experiments, &subjects, &variants])
+            .await?)
+    }
+}
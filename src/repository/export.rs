@@ -0,0 +1,148 @@
+//! Export repository.
+//!
+//! The columns of each table exported by `GET /admin/export/parquet` (see the `export`
+//! module), read with a cursor. Secrets and internal columns (password hashes, avatar
+//! paths, row versions) are left out, the identifiers are the public UUIDs.
+
+use bb8_postgres::tokio_postgres::Row;
+use futures_util::Stream;
+use serde::Deserialize;
+
+use crate::db::fetch_in_batches;
+use crate::error::AppError;
+
+/// A table that can be exported, `?table=` of the export.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportTable {
+    Users,
+    Products,
+    Orders,
+}
+
+/// Type of an exported column, and of the value read from its expression.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnKind {
+    /// `i32`
+    Integer,
+    /// `f64`
+    Float,
+    /// `String`
+    Text,
+    /// `i64` microseconds since the epoch, in UTC
+    Timestamp,
+}
+
+/// A column of an exported table.
+#[derive(Debug)]
+pub struct Column {
+    pub name: &'static str,
+    pub kind: ColumnKind,
+    pub nullable: bool,
+    /// SQL of the value, of the Rust type of `kind`
+    expression: &'static str,
+}
+
+const fn column(name: &'static str, kind: ColumnKind, expression: &'static str) -> Column {
+    Column {
+        name,
+        kind,
+        nullable: false,
+        expression,
+    }
+}
+
+const USERS: &[Column] = &[
+    column("id", ColumnKind::Text, "public_id::text"),
+    column("name", ColumnKind::Text, "name"),
+    column("age", ColumnKind::Integer, "age"),
+    Column {
+        nullable: true,
+        ..column("email", ColumnKind::Text, "email")
+    },
+    column(
+        "updated_at",
+        ColumnKind::Timestamp,
+        "(extract(epoch FROM updated_at) * 1000000)::bigint",
+    ),
+];
+
+const PRODUCTS: &[Column] = &[
+    column("id", ColumnKind::Text, "public_id::text"),
+    column("name", ColumnKind::Text, "name"),
+    column("price", ColumnKind::Float, "price"),
+    column("stock", ColumnKind::Integer, "stock"),
+];
+
+const ORDERS: &[Column] = &[
+    column("id", ColumnKind::Integer, "o.id"),
+    column("user_id", ColumnKind::Text, "u.public_id::text"),
+    column("product_id", ColumnKind::Text, "p.public_id::text"),
+    column("quantity", ColumnKind::Integer, "o.quantity"),
+    column("unit_price", ColumnKind::Float, "o.unit_price"),
+    column(
+        "created_at",
+        ColumnKind::Timestamp,
+        "(extract(epoch FROM o.created_at) * 1000000)::bigint",
+    ),
+];
+
+impl ExportTable {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExportTable::Users => "users",
+            ExportTable::Products => "products",
+            ExportTable::Orders => "orders",
+        }
+    }
+
+    /// The exported columns, in the order of the values of each row.
+    pub fn columns(&self) -> &'static [Column] {
+        match self {
+            ExportTable::Users => USERS,
+            ExportTable::Products => PRODUCTS,
+            ExportTable::Orders => ORDERS,
+        }
+    }
+
+    /// Rows of the table, in the order of their primary key (the soft-deleted users
+    /// left out).
+    fn source(&self) -> &'static str {
+        match self {
+            ExportTable::Users => "FROM users WHERE deleted_at IS NULL ORDER BY id",
+            ExportTable::Products => "FROM products ORDER BY id",
+            ExportTable::Orders => {
+                "FROM orders o JOIN users u ON u.id = o.user_id \
+                 JOIN products p ON p.id = o.product_id ORDER BY o.id"
+            }
+        }
+    }
+}
+
+/// Operations reading whole tables for their export.
+pub trait ExportRepository {
+    /// Reads every row of `table`, with the values of its `columns`, batch by batch.
+    fn stream_rows(
+        &self,
+        table: ExportTable,
+    ) -> impl Stream<Item = Result<Vec<Row>, AppError>> + Send + 'static;
+}
+
+/// `ExportRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgExportRepo;
+
+impl ExportRepository for PgExportRepo {
+    fn stream_rows(
+        &self,
+        table: ExportTable,
+    ) -> impl Stream<Item = Result<Vec<Row>, AppError>> + Send + 'static {
+        let expressions = table
+            .columns()
+            .iter()
+            .map(|column| column.expression)
+            .collect::<Vec<_>>();
+        let sql = format!("SELECT {} {}", expressions.join(", "), table.source());
+        fetch_in_batches(sql, Vec::new())
+    }
+}
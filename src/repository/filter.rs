@@ -0,0 +1,798 @@
+//! Filters of the list endpoints.
+//!
+//! A filter is a query parameter named after a field and an operator:
+//! `?name_like=ann&age_gte=18&price_lte=100`. A field alone (`?name=Ann`) means `eq`.
+//! Every filter must hold for an item to be listed.
+//!
+//! | Operator | SQL | Fields |
+//! |----------|-----|--------|
+//! | `eq`, `ne` | `=`, `<>` | all |
+//! | `gt`, `gte`, `lt`, `lte` | `>`, `>=`, `<`, `<=` | numbers |
+//! | `like` | `ILIKE '%value%'` | text |
+//!
+//! Conditions that don't fit this form go in a `filter` expression:
+//! `?filter=age>=18 AND (name~"ali" OR NOT id=3)`. It compares fields with `=`, `!=`,
+//! `>`, `>=`, `<`, `<=` and `~` (`like`), combined with `AND`, `OR`, `NOT` and
+//! parentheses (`AND` binds tighter than `OR`). Values are numbers, words or quoted
+//! strings (`"Ann \"Jr\""`); keywords are case insensitive.
+//!
+//! Fields come from the whitelist of each resource and values are always sent as
+//! query parameters (`$1`, `$2`...), so nothing from the client is written in the SQL.
+//!
+//! The SCIM filters of the provisioning routes (`userName eq "ann@example.com"`) are
+//! parsed into the same clauses, see [`scim`].
+
+use bb8_postgres::tokio_postgres::types::ToSql;
+use uuid::Uuid;
+
+use crate::error::AppError;
+
+mod scim;
+
+/// Operators accepted after a field name, in the order they are listed in errors
+const OPERATORS: &[&str] = &["eq", "ne", "gt", "gte", "lt", "lte", "like"];
+/// Query parameter holding a filter expression
+const EXPRESSION_PARAMETER: &str = "filter";
+/// Comparisons of a filter expression, bounding the size of the SQL it becomes
+const MAX_COMPARISONS: usize = 20;
+/// Nesting of the parentheses and `NOT` of a filter expression
+const MAX_DEPTH: usize = 10;
+/// Query parameter asking for the soft-deleted rows too
+const INCLUDE_DELETED_PARAMETER: &str = "include_deleted";
+
+/// Type of a filterable column, deciding how values are parsed and which operators
+/// apply.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldType {
+    /// `TEXT`
+    Text,
+    /// `INTEGER`
+    Integer,
+    /// `DOUBLE PRECISION`
+    Float,
+    /// `UUID`, compared with `eq` and `ne` only
+    Uuid,
+}
+
+/// A column a resource can be filtered by.
+#[derive(Debug, Clone, Copy)]
+pub struct FilterField {
+    /// Name of the field in the parameters and expressions
+    pub name: &'static str,
+    /// Name of the column, the name of the field unless told otherwise
+    pub column: &'static str,
+    pub field_type: FieldType,
+}
+
+impl FilterField {
+    pub const fn text(column: &'static str) -> Self {
+        FilterField {
+            name: column,
+            column,
+            field_type: FieldType::Text,
+        }
+    }
+
+    pub const fn integer(column: &'static str) -> Self {
+        FilterField {
+            name: column,
+            column,
+            field_type: FieldType::Integer,
+        }
+    }
+
+    pub const fn float(column: &'static str) -> Self {
+        FilterField {
+            name: column,
+            column,
+            field_type: FieldType::Float,
+        }
+    }
+
+    /// A UUID column sent under another name, such as `public_id` as `id`.
+    pub const fn uuid(name: &'static str, column: &'static str) -> Self {
+        FilterField {
+            name,
+            column,
+            field_type: FieldType::Uuid,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Like,
+}
+
+impl Operator {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "eq" => Operator::Eq,
+            "ne" => Operator::Ne,
+            "gt" => Operator::Gt,
+            "gte" => Operator::Gte,
+            "lt" => Operator::Lt,
+            "lte" => Operator::Lte,
+            "like" => Operator::Like,
+            _ => return None,
+        })
+    }
+
+    /// Operator of a filter expression (`>=`).
+    fn parse_symbol(symbol: &str) -> Option<Self> {
+        Some(match symbol {
+            "=" => Operator::Eq,
+            "!=" => Operator::Ne,
+            ">" => Operator::Gt,
+            ">=" => Operator::Gte,
+            "<" => Operator::Lt,
+            "<=" => Operator::Lte,
+            "~" => Operator::Like,
+            _ => return None,
+        })
+    }
+
+    fn as_sql(self) -> &'static str {
+        match self {
+            Operator::Eq => "=",
+            Operator::Ne => "<>",
+            Operator::Gt => ">",
+            Operator::Gte => ">=",
+            Operator::Lt => "<",
+            Operator::Lte => "<=",
+            Operator::Like => "ILIKE",
+        }
+    }
+
+    fn applies_to(self, field_type: FieldType) -> bool {
+        match self {
+            Operator::Eq | Operator::Ne => true,
+            Operator::Gt | Operator::Gte | Operator::Lt | Operator::Lte => {
+                matches!(field_type, FieldType::Integer | FieldType::Float)
+            }
+            Operator::Like => field_type == FieldType::Text,
+        }
+    }
+}
+
+/// Value of a condition, typed like its column.
+#[derive(Debug, Clone)]
+enum Value {
+    Text(String),
+    Integer(i32),
+    Float(f64),
+    Uuid(Uuid),
+}
+
+impl Value {
+    fn as_param(&self) -> &(dyn ToSql + Sync) {
+        match self {
+            Value::Text(value) => value,
+            Value::Integer(value) => value,
+            Value::Float(value) => value,
+            Value::Uuid(value) => value,
+        }
+    }
+
+    fn into_param(self) -> Box<dyn ToSql + Send + Sync> {
+        match self {
+            Value::Text(value) => Box::new(value),
+            Value::Integer(value) => Box::new(value),
+            Value::Float(value) => Box::new(value),
+            Value::Uuid(value) => Box::new(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Condition {
+    column: &'static str,
+    operator: Operator,
+    value: Value,
+}
+
+/// A condition, or conditions combined by a filter expression.
+#[derive(Debug, Clone)]
+enum Expr {
+    Condition(Condition),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    /// The field has a value: not null, and not empty for text (SCIM `pr`)
+    Present(FilterField),
+}
+
+impl Expr {
+    /// Writes the SQL of the expression, numbering its placeholders from `next`.
+    fn write_sql(&self, sql: &mut String, next: &mut usize) {
+        let mut write_all = |sql: &mut String, exprs: &[Expr], separator: &str| {
+            sql.push('(');
+            for (i, expr) in exprs.iter().enumerate() {
+                if i > 0 {
+                    sql.push_str(separator);
+                }
+                expr.write_sql(sql, next);
+            }
+            sql.push(')');
+        };
+        match self {
+            Expr::Condition(condition) => {
+                sql.push_str(&format!(
+                    "{} {} ${}",
+                    condition.column,
+                    condition.operator.as_sql(),
+                    next
+                ));
+                *next += 1;
+            }
+            Expr::And(exprs) => write_all(sql, exprs, " AND "),
+            Expr::Or(exprs) => write_all(sql, exprs, " OR "),
+            Expr::Not(expr) => {
+                sql.push_str("NOT ");
+                write_all(sql, std::slice::from_ref(expr.as_ref()), "");
+            }
+            Expr::Present(field) if field.field_type == FieldType::Text => {
+                sql.push_str(&format!("({0} IS NOT NULL AND {0} <> '')", field.column));
+            }
+            Expr::Present(field) => sql.push_str(&format!("{} IS NOT NULL", field.column)),
+        }
+    }
+
+    /// The conditions of the expression, in the order of their placeholders.
+    fn conditions<'a>(&'a self, conditions: &mut Vec<&'a Condition>) {
+        match self {
+            Expr::Condition(condition) => conditions.push(condition),
+            Expr::And(exprs) | Expr::Or(exprs) => {
+                exprs.iter().for_each(|expr| expr.conditions(conditions))
+            }
+            Expr::Not(expr) => expr.conditions(conditions),
+            Expr::Present(_) => {}
+        }
+    }
+}
+
+/// Validated filters of a list request, turned into a parameterized `WHERE` clause.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// One per parameter, all of them must hold
+    exprs: Vec<Expr>,
+    /// Parameters as received, repeated in the pagination links
+    params: Vec<(String, String)>,
+    /// The soft-deleted rows match too (`?include_deleted=true`)
+    include_deleted: bool,
+}
+
+impl Filter {
+    /// Parses the filter parameters of a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - Query parameters that aren't pagination or sorting ones
+    /// * `fields` - Whitelist of filterable columns; only these names ever reach the SQL
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Filter, AppError>` - The filter, or an `AppError::Validation` naming the
+    ///   unknown field, the invalid operator or the malformed value
+    pub fn parse(params: Vec<(String, String)>, fields: &[FilterField]) -> Result<Self, AppError> {
+        let exprs = params
+            .iter()
+            .map(|(name, value)| match name.as_str() {
+                EXPRESSION_PARAMETER => ExprParser::parse(value, fields),
+                _ => Condition::parse(name, value, fields).map(Expr::Condition),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Filter {
+            exprs,
+            params,
+            include_deleted: false,
+        })
+    }
+
+    /// Makes the soft-deleted rows match too, for the tables keeping them (see
+    /// `repository::users`); kept in the pagination links.
+    pub fn include_deleted(&mut self) {
+        self.include_deleted = true;
+        self.params
+            .push((INCLUDE_DELETED_PARAMETER.to_string(), "true".to_string()));
+    }
+
+    pub fn includes_deleted(&self) -> bool {
+        self.include_deleted
+    }
+
+    /// Adds a filter expression (a saved view), which must hold too.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), AppError>` - `AppError::Validation` if the expression is invalid
+    ///   for these fields
+    pub fn add_expression(
+        &mut self,
+        expression: &str,
+        fields: &[FilterField],
+    ) -> Result<(), AppError> {
+        self.exprs.push(ExprParser::parse(expression, fields)?);
+        self.params
+            .push((EXPRESSION_PARAMETER.to_string(), expression.to_string()));
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exprs.is_empty()
+    }
+
+    fn conditions(&self) -> Vec<&Condition> {
+        let mut conditions = Vec::new();
+        for expr in &self.exprs {
+            expr.conditions(&mut conditions);
+        }
+        conditions
+    }
+
+    /// Builds the `WHERE` clause, empty without conditions.
+    ///
+    /// # Arguments
+    ///
+    /// * `first` - Number of the first placeholder (`$1` when the query has no other
+    ///   parameter before the filters)
+    pub fn where_clause(&self, first: usize) -> String {
+        if self.exprs.is_empty() {
+            return String::new();
+        }
+        let mut sql = String::from("WHERE ");
+        let mut next = first;
+        for (i, expr) in self.exprs.iter().enumerate() {
+            if i > 0 {
+                sql.push_str(" AND ");
+            }
+            expr.write_sql(&mut sql, &mut next);
+        }
+        sql
+    }
+
+    /// Values of the placeholders of `where_clause`, in order.
+    pub fn params(&self) -> Vec<&(dyn ToSql + Sync)> {
+        self.conditions()
+            .into_iter()
+            .map(|c| c.value.as_param())
+            .collect()
+    }
+
+    /// Values of the placeholders, owned by queries outliving the request (streams).
+    pub fn owned_params(&self) -> Vec<Box<dyn ToSql + Send + Sync>> {
+        self.conditions()
+            .into_iter()
+            .map(|c| c.value.clone().into_param())
+            .collect()
+    }
+
+    /// The filter parameters, encoded to be appended to a query string.
+    pub fn query_string(&self) -> String {
+        serde_urlencoded::to_string(&self.params).unwrap_or_default()
+    }
+}
+
+impl Condition {
+    fn parse(name: &str, value: &str, fields: &[FilterField]) -> Result<Self, AppError> {
+        // `age_gte` is `age` and `gte`, `age` alone is `age` and `eq`
+        let (field, operator) = fields
+            .iter()
+            .find_map(|field| {
+                let rest = name.strip_prefix(field.name)?;
+                match rest.strip_prefix('_') {
+                    Some(operator) => Some((field, operator)),
+                    None => rest.is_empty().then_some((field, "eq")),
+                }
+            })
+            .ok_or_else(|| {
+                let names = fields.iter().map(|f| f.name).collect::<Vec<_>>();
+                AppError::Validation(format!(
+                    "Unknown filter field '{}', expected one of: {}",
+                    name,
+                    names.join(", ")
+                ))
+            })?;
+
+        let operator = Operator::parse(operator).ok_or_else(|| {
+            AppError::Validation(format!(
+                "Invalid filter operator '{}' in '{}', expected one of: {}",
+                operator,
+                name,
+                OPERATORS.join(", ")
+            ))
+        })?;
+        Self::new(field, operator, value, name)
+    }
+
+    /// Builds the condition of a whitelisted field.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - How the client wrote the comparison, for the errors
+    fn new(
+        field: &FilterField,
+        operator: Operator,
+        value: &str,
+        name: &str,
+    ) -> Result<Self, AppError> {
+        if !operator.applies_to(field.field_type) {
+            return Err(AppError::Validation(format!(
+                "Filter '{}' is not supported, '{}' can't be compared that way",
+                name, field.name
+            )));
+        }
+
+        let invalid = |expected: &str| {
+            AppError::Validation(format!("Filter '{}' must be {}", name, expected))
+        };
+        let value = match field.field_type {
+            FieldType::Text if operator == Operator::Like => {
+                Value::Text(format!("%{}%", escape_like(value)))
+            }
+            FieldType::Text => Value::Text(value.to_string()),
+            FieldType::Integer => Value::Integer(
+                value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid("an integer (i32)"))?,
+            ),
+            FieldType::Float => Value::Float(
+                value
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|v| v.is_finite())
+                    .ok_or_else(|| invalid("a number"))?,
+            ),
+            FieldType::Uuid => {
+                Value::Uuid(Uuid::parse_str(value.trim()).map_err(|_| invalid("a UUID"))?)
+            }
+        };
+
+        Ok(Condition {
+            column: field.column,
+            operator,
+            value,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// Field name, keyword or unquoted value
+    Word(String),
+    Quoted(String),
+    Operator(&'static str),
+    Open,
+    Close,
+}
+
+/// Characters ending an unquoted word
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | '"' | '=' | '!' | '<' | '>' | '~')
+}
+
+/// Splits a filter expression into tokens, with their position (in characters) for
+/// the errors.
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, AppError> {
+    let chars = input.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let token = match chars[i] {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => {
+                i += 1;
+                Token::Open
+            }
+            ')' => {
+                i += 1;
+                Token::Close
+            }
+            '"' => {
+                let mut value = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(invalid_expression(start, "unterminated string")),
+                        Some('"') => break,
+                        Some('\\') if i + 1 < chars.len() => {
+                            value.push(chars[i + 1]);
+                            i += 2;
+                        }
+                        Some(&c) => {
+                            value.push(c);
+                            i += 1;
+                        }
+                    }
+                }
+                i += 1;
+                Token::Quoted(value)
+            }
+            '=' | '!' | '<' | '>' | '~' => {
+                let two = chars[i..].iter().take(2).collect::<String>();
+                let symbol = ["!=", ">=", "<=", "=", ">", "<", "~"]
+                    .into_iter()
+                    .find(|symbol| two.starts_with(symbol))
+                    .ok_or_else(|| invalid_expression(start, "unknown operator '!'"))?;
+                i += symbol.len();
+                Token::Operator(symbol)
+            }
+            _ => {
+                while i < chars.len() && !is_delimiter(chars[i]) {
+                    i += 1;
+                }
+                Token::Word(chars[start..i].iter().collect())
+            }
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+fn invalid_expression(position: usize, reason: &str) -> AppError {
+    AppError::Validation(format!(
+        "Invalid filter expression at character {}: {}",
+        position + 1,
+        reason
+    ))
+}
+
+/// Recursive descent parser of a filter expression:
+///
+/// ```text
+/// or         = and ("OR" and)*
+/// and        = unary ("AND" unary)*
+/// unary      = "NOT" unary | "(" or ")" | comparison
+/// comparison = field operator value
+/// ```
+struct ExprParser<'a> {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    /// Length of the expression, the position of its end in errors
+    len: usize,
+    fields: &'a [FilterField],
+    comparisons: usize,
+}
+
+impl<'a> ExprParser<'a> {
+    fn parse(input: &str, fields: &'a [FilterField]) -> Result<Expr, AppError> {
+        let mut parser = ExprParser {
+            tokens: tokenize(input)?,
+            next: 0,
+            len: input.chars().count(),
+            fields,
+            comparisons: 0,
+        };
+        if parser.tokens.is_empty() {
+            return Err(invalid_expression(0, "empty expression"));
+        }
+        let expr = parser.or(0)?;
+        match parser.tokens.get(parser.next) {
+            None => Ok(expr),
+            Some((position, _)) => Err(invalid_expression(*position, "expected AND or OR")),
+        }
+    }
+
+    /// Position of the next token, or of the end of the expression.
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.len, |(position, _)| *position)
+    }
+
+    /// Consumes the next token if it's the keyword `keyword`.
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = matches!(
+            self.tokens.get(self.next),
+            Some((_, Token::Word(word))) if word.eq_ignore_ascii_case(keyword)
+        );
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn or(&mut self, depth: usize) -> Result<Expr, AppError> {
+        let mut exprs = vec![self.and(depth)?];
+        while self.keyword("OR") {
+            exprs.push(self.and(depth)?);
+        }
+        Ok(combine(exprs, Expr::Or))
+    }
+
+    fn and(&mut self, depth: usize) -> Result<Expr, AppError> {
+        let mut exprs = vec![self.unary(depth)?];
+        while self.keyword("AND") {
+            exprs.push(self.unary(depth)?);
+        }
+        Ok(combine(exprs, Expr::And))
+    }
+
+    fn unary(&mut self, depth: usize) -> Result<Expr, AppError> {
+        if depth >= MAX_DEPTH {
+            return Err(invalid_expression(
+                self.position(),
+                &format!("more than {} nested levels", MAX_DEPTH),
+            ));
+        }
+        if self.keyword("NOT") {
+            return Ok(Expr::Not(Box::new(self.unary(depth + 1)?)));
+        }
+        if let Some((_, Token::Open)) = self.tokens.get(self.next) {
+            self.next += 1;
+            let expr = self.or(depth + 1)?;
+            return match self.tokens.get(self.next) {
+                Some((_, Token::Close)) => {
+                    self.next += 1;
+                    Ok(expr)
+                }
+                _ => Err(invalid_expression(self.position(), "expected ')'")),
+            };
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, AppError> {
+        let position = self.position();
+        let field = match self.tokens.get(self.next) {
+            Some((_, Token::Word(name))) => self
+                .fields
+                .iter()
+                .find(|field| field.name == name)
+                .ok_or_else(|| {
+                    let names = self.fields.iter().map(|f| f.name).collect::<Vec<_>>();
+                    invalid_expression(
+                        position,
+                        &format!(
+                            "unknown field '{}', expected one of: {}",
+                            name,
+                            names.join(", ")
+                        ),
+                    )
+                })?,
+            _ => return Err(invalid_expression(position, "expected a field")),
+        };
+        self.next += 1;
+
+        let operator = match self.tokens.get(self.next) {
+            Some((_, Token::Operator(symbol))) => symbol,
+            _ => {
+                return Err(invalid_expression(
+                    self.position(),
+                    "expected one of =, !=, >, >=, <, <=, ~",
+                ));
+            }
+        };
+        let name = format!("{}{}", field.name, operator);
+        let operator = Operator::parse_symbol(operator).expect("Operator of the tokenizer");
+        self.next += 1;
+
+        let value = match self.tokens.get(self.next) {
+            Some((_, Token::Word(value) | Token::Quoted(value))) => value,
+            _ => return Err(invalid_expression(self.position(), "expected a value")),
+        };
+        let condition = Condition::new(field, operator, value, &name)?;
+        self.next += 1;
+
+        self.comparisons += 1;
+        if self.comparisons > MAX_COMPARISONS {
+            return Err(invalid_expression(
+                position,
+                &format!("more than {} comparisons", MAX_COMPARISONS),
+            ));
+        }
+        Ok(Expr::Condition(condition))
+    }
+}
+
+/// A single expression as is, several ones combined by `combinator`.
+fn combine(mut exprs: Vec<Expr>, combinator: fn(Vec<Expr>) -> Expr) -> Expr {
+    if exprs.len() == 1 {
+        exprs.remove(0)
+    } else {
+        combinator(exprs)
+    }
+}
+
+/// Escapes the wildcards of `ILIKE` (`%`, `_`) so the value matches literally.
+pub(super) fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIELDS: &[FilterField] = &[
+        FilterField::integer("id"),
+        FilterField::text("name"),
+        FilterField::integer("age"),
+    ];
+
+    fn parse(params: &[(&str, &str)]) -> Result<Filter, AppError> {
+        let params = params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Filter::parse(params, FIELDS)
+    }
+
+    #[test]
+    fn expressions_become_parameterized_sql() {
+        let filter = parse(&[
+            ("id_gt", "2"),
+            (
+                "filter",
+                r#"age>=18 and (name~"a \"b\"" OR NOT id = 3) or name=Ann"#,
+            ),
+        ])
+        .unwrap();
+        assert_eq!(
+            filter.where_clause(3),
+            "WHERE id > $3 AND ((age >= $4 AND (name ILIKE $5 OR NOT (id = $6))) OR name = $7)"
+        );
+        let values = filter
+            .conditions()
+            .iter()
+            .map(|c| format!("{:?}", c.value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            [
+                "Integer(2)",
+                "Integer(18)",
+                r#"Text("%a \"b\"%")"#,
+                "Integer(3)",
+                r#"Text("Ann")"#
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_expressions_are_rejected() {
+        let nested = format!("{}age=1{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH));
+        let many = vec!["age=1"; MAX_COMPARISONS + 1].join(" OR ");
+        for expression in [
+            "",
+            "age",
+            "age>=",
+            "age>=old",
+            "age~1",
+            "email=a",
+            "age=1 AND",
+            "age=1 name=a",
+            "(age=1",
+            "name=\"Ann",
+            "age!1",
+            "age=1; DROP TABLE users",
+            &nested,
+            &many,
+        ] {
+            let result = parse(&[("filter", expression)]);
+            assert!(
+                matches!(result, Err(AppError::Validation(_))),
+                "{}",
+                expression
+            );
+        }
+    }
+}
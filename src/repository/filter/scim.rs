@@ -0,0 +1,483 @@
+//! SCIM filters (RFC 7644 §3.4.2.2), the `filter` of `GET /scim/v2/Users`:
+//!
+//! ```text
+//! userName eq "ann@example.com"
+//! emails[value ew "@example.com"] and not (externalId pr)
+//! ```
+//!
+//! Attributes are compared with `eq`, `ne`, `co` (contains), `sw` (starts with), `ew`
+//! (ends with), `gt`, `ge`, `lt` and `le`, or tested with `pr` (has a value), and the
+//! comparisons combined with `and`, `or`, `not (...)` and parentheses (`and` binds
+//! tighter than `or`). A multi-valued attribute takes a filter of its sub-attributes in
+//! brackets. Attribute names, operators and keywords are case insensitive, and the
+//! names may be prefixed by the URN of the core User schema; values are JSON strings
+//! and numbers.
+//!
+//! Strings are compared case insensitively, as SCIM wants for the user attributes: the
+//! columns of the text fields are lowered (`lower(email)`), and the values here.
+
+use uuid::Uuid;
+
+use super::{
+    Condition, EXPRESSION_PARAMETER, Expr, FieldType, Filter, FilterField, MAX_COMPARISONS,
+    MAX_DEPTH, Operator, Value, combine, escape_like,
+};
+use crate::error::AppError;
+
+/// Operators of the comparisons, in the order they are listed in errors
+const OPERATORS: &[&str] = &["eq", "ne", "co", "sw", "ew", "gt", "ge", "lt", "le", "pr"];
+/// Prefix of the attributes of the core User schema, optional in filters
+const CORE_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User:";
+
+impl Filter {
+    /// Parses the `filter` of a SCIM list request.
+    ///
+    /// # Arguments
+    ///
+    /// * `expression` - The SCIM filter
+    /// * `fields` - Whitelist of the attributes, named by their path (`name.formatted`);
+    ///   the columns of the text ones must be lowered
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Filter, AppError>` - The filter, or an `AppError::Validation` telling
+    ///   where the filter is invalid
+    pub fn parse_scim(expression: &str, fields: &[FilterField]) -> Result<Self, AppError> {
+        Ok(Filter {
+            exprs: vec![ScimParser::parse(expression, fields)?],
+            params: vec![(EXPRESSION_PARAMETER.to_string(), expression.to_string())],
+            include_deleted: false,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// Attribute path, operator, keyword or number
+    Word(String),
+    /// JSON string, decoded
+    Quoted(String),
+    Open,
+    Close,
+    OpenBracket,
+    CloseBracket,
+}
+
+/// Characters ending a word
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | '[' | ']' | '"')
+}
+
+/// Splits a SCIM filter into tokens, with their position (in characters) for the errors.
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, AppError> {
+    let chars = input.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let token = match chars[i] {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => {
+                i += 1;
+                Token::Open
+            }
+            ')' => {
+                i += 1;
+                Token::Close
+            }
+            '[' => {
+                i += 1;
+                Token::OpenBracket
+            }
+            ']' => {
+                i += 1;
+                Token::CloseBracket
+            }
+            '"' => {
+                // Up to the first unescaped quote, then decoded as JSON
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                if i >= chars.len() {
+                    return Err(invalid_filter(start, "unterminated string"));
+                }
+                i += 1;
+                let literal = chars[start..i].iter().collect::<String>();
+                Token::Quoted(
+                    serde_json::from_str(&literal)
+                        .map_err(|_| invalid_filter(start, "invalid string"))?,
+                )
+            }
+            _ => {
+                while i < chars.len() && !is_delimiter(chars[i]) {
+                    i += 1;
+                }
+                Token::Word(chars[start..i].iter().collect())
+            }
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+fn invalid_filter(position: usize, reason: &str) -> AppError {
+    AppError::Validation(format!(
+        "Invalid SCIM filter at character {}: {}",
+        position + 1,
+        reason
+    ))
+}
+
+/// `path` without the URN of the core schema.
+fn strip_schema(path: &str) -> &str {
+    match path.get(..CORE_SCHEMA.len()) {
+        Some(prefix) if prefix.eq_ignore_ascii_case(CORE_SCHEMA) => &path[CORE_SCHEMA.len()..],
+        _ => path,
+    }
+}
+
+/// Recursive descent parser of a SCIM filter:
+///
+/// ```text
+/// or        = and ("or" and)*
+/// and       = unary ("and" unary)*
+/// unary     = "not" "(" or ")" | "(" or ")" | attribute
+/// attribute = path "[" or "]" | path "pr" | path operator value
+/// ```
+struct ScimParser<'a> {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    /// Length of the filter, the position of its end in errors
+    len: usize,
+    fields: &'a [FilterField],
+    comparisons: usize,
+    /// The multi-valued attribute whose brackets are being parsed
+    parent: Option<String>,
+}
+
+impl<'a> ScimParser<'a> {
+    fn parse(input: &str, fields: &'a [FilterField]) -> Result<Expr, AppError> {
+        let mut parser = ScimParser {
+            tokens: tokenize(input)?,
+            next: 0,
+            len: input.chars().count(),
+            fields,
+            comparisons: 0,
+            parent: None,
+        };
+        if parser.tokens.is_empty() {
+            return Err(invalid_filter(0, "empty filter"));
+        }
+        let expr = parser.or(0)?;
+        match parser.tokens.get(parser.next) {
+            None => Ok(expr),
+            Some((position, _)) => Err(invalid_filter(*position, "expected and or or")),
+        }
+    }
+
+    /// Position of the next token, or of the end of the filter.
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.len, |(position, _)| *position)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    /// Consumes the next token if it's the keyword `keyword`.
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found =
+            matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword));
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    /// Consumes the next token, which must be `token` (`expected` in the error).
+    fn expect(&mut self, token: Token, expected: &str) -> Result<(), AppError> {
+        if self.peek() != Some(&token) {
+            return Err(invalid_filter(
+                self.position(),
+                &format!("expected {}", expected),
+            ));
+        }
+        self.next += 1;
+        Ok(())
+    }
+
+    fn or(&mut self, depth: usize) -> Result<Expr, AppError> {
+        let mut exprs = vec![self.and(depth)?];
+        while self.keyword("or") {
+            exprs.push(self.and(depth)?);
+        }
+        Ok(combine(exprs, Expr::Or))
+    }
+
+    fn and(&mut self, depth: usize) -> Result<Expr, AppError> {
+        let mut exprs = vec![self.unary(depth)?];
+        while self.keyword("and") {
+            exprs.push(self.unary(depth)?);
+        }
+        Ok(combine(exprs, Expr::And))
+    }
+
+    fn unary(&mut self, depth: usize) -> Result<Expr, AppError> {
+        if depth >= MAX_DEPTH {
+            return Err(invalid_filter(
+                self.position(),
+                &format!("more than {} nested levels", MAX_DEPTH),
+            ));
+        }
+        if self.keyword("not") {
+            self.expect(Token::Open, "'(' after not")?;
+            let expr = self.or(depth + 1)?;
+            self.expect(Token::Close, "')'")?;
+            return Ok(Expr::Not(Box::new(expr)));
+        }
+        if self.peek() == Some(&Token::Open) {
+            self.next += 1;
+            let expr = self.or(depth + 1)?;
+            self.expect(Token::Close, "')'")?;
+            return Ok(expr);
+        }
+        self.attribute(depth)
+    }
+
+    fn attribute(&mut self, depth: usize) -> Result<Expr, AppError> {
+        let position = self.position();
+        let path = match self.peek() {
+            Some(Token::Word(path)) => strip_schema(path).to_string(),
+            _ => return Err(invalid_filter(position, "expected an attribute")),
+        };
+        self.next += 1;
+
+        if self.peek() == Some(&Token::OpenBracket) {
+            if self.parent.is_some() {
+                return Err(invalid_filter(position, "brackets can't be nested"));
+            }
+            self.next += 1;
+            self.parent = Some(path);
+            let expr = self.or(depth + 1)?;
+            self.parent = None;
+            self.expect(Token::CloseBracket, "']'")?;
+            return Ok(expr);
+        }
+
+        let path = match &self.parent {
+            Some(parent) => format!("{}.{}", parent, path),
+            None => path,
+        };
+        let field = self
+            .fields
+            .iter()
+            .find(|field| field.name.eq_ignore_ascii_case(&path))
+            .ok_or_else(|| {
+                let names = self.fields.iter().map(|f| f.name).collect::<Vec<_>>();
+                invalid_filter(
+                    position,
+                    &format!(
+                        "unknown attribute '{}', expected one of: {}",
+                        path,
+                        names.join(", ")
+                    ),
+                )
+            })?;
+
+        let operator = match self.peek() {
+            Some(Token::Word(operator)) => operator.to_ascii_lowercase(),
+            _ => String::new(),
+        };
+        if !OPERATORS.contains(&operator.as_str()) {
+            return Err(invalid_filter(
+                self.position(),
+                &format!("expected one of {}", OPERATORS.join(", ")),
+            ));
+        }
+        self.next += 1;
+
+        self.comparisons += 1;
+        if self.comparisons > MAX_COMPARISONS {
+            return Err(invalid_filter(
+                position,
+                &format!("more than {} comparisons", MAX_COMPARISONS),
+            ));
+        }
+        if operator == "pr" {
+            return Ok(Expr::Present(*field));
+        }
+
+        let value_position = self.position();
+        let value = self
+            .peek()
+            .ok_or_else(|| invalid_filter(value_position, "expected a value"))?;
+        let condition = condition(field, &operator, value)
+            .map_err(|reason| invalid_filter(value_position, &reason))?;
+        self.next += 1;
+        Ok(Expr::Condition(condition))
+    }
+}
+
+/// Operator of `eq`, `ne` and the orderings.
+fn comparison(operator: &str) -> Option<Operator> {
+    Some(match operator {
+        "eq" => Operator::Eq,
+        "ne" => Operator::Ne,
+        "gt" => Operator::Gt,
+        "ge" => Operator::Gte,
+        "lt" => Operator::Lt,
+        "le" => Operator::Lte,
+        _ => return None,
+    })
+}
+
+/// The condition comparing `field` with `operator` to `value`, or why it can't be.
+fn condition(field: &FilterField, operator: &str, value: &Token) -> Result<Condition, String> {
+    let unsupported = || format!("'{}' can't be compared with {}", field.name, operator);
+    let (operator, value) = match (field.field_type, value) {
+        (FieldType::Text, Token::Quoted(text)) => {
+            let text = text.to_lowercase();
+            let (operator, text) = match operator {
+                "co" => (Operator::Like, format!("%{}%", escape_like(&text))),
+                "sw" => (Operator::Like, format!("{}%", escape_like(&text))),
+                "ew" => (Operator::Like, format!("%{}", escape_like(&text))),
+                _ => (comparison(operator).ok_or_else(unsupported)?, text),
+            };
+            (operator, Value::Text(text))
+        }
+        (FieldType::Uuid, Token::Quoted(text)) => {
+            let operator = match operator {
+                "eq" => Operator::Eq,
+                "ne" => Operator::Ne,
+                _ => return Err(unsupported()),
+            };
+            let id = Uuid::parse_str(text)
+                .map_err(|_| format!("'{}' must be compared with a UUID", field.name))?;
+            (operator, Value::Uuid(id))
+        }
+        (FieldType::Integer, Token::Word(word)) => {
+            let operator = comparison(operator).ok_or_else(unsupported)?;
+            let value = word
+                .parse()
+                .map_err(|_| format!("'{}' must be compared with an integer", field.name))?;
+            (operator, Value::Integer(value))
+        }
+        (FieldType::Float, Token::Word(word)) => {
+            let operator = comparison(operator).ok_or_else(unsupported)?;
+            let value = word
+                .parse::<f64>()
+                .ok()
+                .filter(|value| value.is_finite())
+                .ok_or_else(|| format!("'{}' must be compared with a number", field.name))?;
+            (operator, Value::Float(value))
+        }
+        (FieldType::Text | FieldType::Uuid, _) => {
+            return Err(format!("'{}' must be compared with a string", field.name));
+        }
+        (FieldType::Integer | FieldType::Float, _) => {
+            return Err(format!("'{}' must be compared with a number", field.name));
+        }
+    };
+    Ok(Condition {
+        column: field.column,
+        operator,
+        value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIELDS: &[FilterField] = &[
+        FilterField::uuid("id", "public_id"),
+        FilterField {
+            name: "userName",
+            column: "lower(email)",
+            field_type: FieldType::Text,
+        },
+        FilterField {
+            name: "emails.value",
+            column: "lower(email)",
+            field_type: FieldType::Text,
+        },
+        FilterField {
+            name: "externalId",
+            column: "lower(external_id)",
+            field_type: FieldType::Text,
+        },
+        FilterField::integer("age"),
+    ];
+
+    #[test]
+    fn filters_become_parameterized_sql() {
+        let filter = Filter::parse_scim(
+            r#"urn:ietf:params:scim:schemas:core:2.0:User:UserName EQ "Ann@Example.com" and
+               (emails[value co "50%" or value ew "\u0040x.org"] OR not (externalId pr))
+               and age ge 18"#,
+            FIELDS,
+        )
+        .unwrap();
+        assert_eq!(
+            filter.where_clause(2),
+            "WHERE (lower(email) = $2 AND ((lower(email) ILIKE $3 OR lower(email) ILIKE $4) \
+             OR NOT ((lower(external_id) IS NOT NULL AND lower(external_id) <> ''))) \
+             AND age >= $5)"
+        );
+        let values = filter
+            .conditions()
+            .iter()
+            .map(|c| format!("{:?}", c.value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            [
+                r#"Text("ann@example.com")"#,
+                r#"Text("%50\\%%")"#,
+                r#"Text("%@x.org")"#,
+                "Integer(18)"
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_filters_are_rejected() {
+        let nested = format!("{}age eq 1{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH));
+        let many = vec!["age eq 1"; MAX_COMPARISONS + 1].join(" or ");
+        for filter in [
+            "",
+            "userName",
+            "userName eq",
+            "userName is \"a\"",
+            "userName eq 1",
+            "userName eq \"a",
+            "userName eq \"\\x\"",
+            "password eq \"secret\"",
+            "id eq \"nope\"",
+            "id co \"0\"",
+            "age co 1",
+            "age eq \"1\"",
+            "userName eq \"a\" userName eq \"b\"",
+            "not userName pr",
+            "emails[value eq \"a\"",
+            "emails[value[value pr]]",
+            "(userName pr",
+            &nested,
+            &many,
+        ] {
+            let result = Filter::parse_scim(filter, FIELDS);
+            assert!(
+                matches!(result, Err(AppError::Validation(_))),
+                "'{}' should be invalid, got {:?}",
+                filter,
+                result
+            );
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Public identifiers of users and products.
+//!
+//! Rows keep their `SERIAL` key, used by the foreign keys, the audit log and the response
+//! cache, but the API identifies them by a UUID (`public_id`, sent as `id`): integer
+//! keys tell how many rows a table has and collide when the data of two environments is
+//! merged. No response, event or change feed carries the keys. New rows get a version
+//! 7 UUID, ordered by creation time ([`new_public_id`]); the rows that existed before
+//! got a random one from the migration.
+//!
+//! The `:id` path parameters, the `id` filters and the `product_id` of an order take
+//! the UUID. While the clients migrate, `ACCEPT_INTEGER_IDS` (default true) lets them
+//! send the integer key instead; without it, a path with an integer key names no row
+//! (404).
+//!
+//! A UUID is turned into its key with one query, then remembered: the pair never
+//! changes, and a deleted row doesn't give its key or its UUID to another one.
+
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::sync::{LazyLock, Mutex, OnceLock};
+
+use lru::LruCache;
+use serde::{Deserialize, Deserializer, Serialize, de};
+use tracing::warn;
+use uuid::Uuid;
+
+use super::retry::with_retry;
+
+use crate::db::Db;
+use crate::error::AppError;
+use crate::events::Collection;
+
+/// Keys of the UUIDs resolved lately
+const RESOLVED_CAPACITY: NonZeroUsize = NonZeroUsize::new(10_000).unwrap();
+
+// Set once at startup with `init_ids`
+static ACCEPT_INTEGER_IDS: OnceLock<bool> = OnceLock::new();
+
+static RESOLVED: LazyLock<Mutex<LruCache<(Collection, Uuid), i32>>> =
+    LazyLock::new(|| Mutex::new(LruCache::new(RESOLVED_CAPACITY)));
+
+/// Sets whether the integer keys are still accepted in place of the UUIDs
+/// (`ACCEPT_INTEGER_IDS`).
+/// This function should be called at application startup, before serving requests.
+pub fn init_ids(accept_integer_ids: bool) {
+    if ACCEPT_INTEGER_IDS.set(accept_integer_ids).is_err() {
+        warn!("Attempt to reset the accepted IDs ignored");
+    }
+}
+
+fn accept_integer_ids() -> bool {
+    *ACCEPT_INTEGER_IDS
+        .get()
+        .expect("Accepted IDs are not initialized")
+}
+
+/// Public identifier of a new row.
+pub fn new_public_id() -> Uuid {
+    Uuid::now_v7()
+}
+
+/// A user or product as a client identifies it, serialized as the UUID or the key.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum ResourceId {
+    /// Its UUID
+    Public(Uuid),
+    /// Its integer key, resolved with `ACCEPT_INTEGER_IDS` only
+    Key(i32),
+}
+
+impl ResourceId {
+    /// Error of an ID that isn't a UUID (or an i32 with `ACCEPT_INTEGER_IDS`).
+    pub fn invalid() -> AppError {
+        AppError::Validation(Self::expected().to_string())
+    }
+
+    fn expected() -> &'static str {
+        if accept_integer_ids() {
+            "ID must be a UUID or an i32"
+        } else {
+            "ID must be a UUID"
+        }
+    }
+}
+
+/// A path parameter: a UUID or an integer key, which [`resolve`] finds with
+/// `ACCEPT_INTEGER_IDS` only.
+impl FromStr for ResourceId {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Ok(uuid) = Uuid::parse_str(value) {
+            return Ok(ResourceId::Public(uuid));
+        }
+        value
+            .parse()
+            .map(ResourceId::Key)
+            .map_err(|_| ResourceId::invalid())
+    }
+}
+
+/// A UUID string, or an integer key with `ACCEPT_INTEGER_IDS`.
+impl<'de> Deserialize<'de> for ResourceId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = ResourceId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a UUID")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<ResourceId, E> {
+                Uuid::parse_str(value)
+                    .map(ResourceId::Public)
+                    .map_err(|_| E::custom(ResourceId::expected()))
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<ResourceId, E> {
+                i32::try_from(value)
+                    .ok()
+                    .filter(|_| accept_integer_ids())
+                    .map(ResourceId::Key)
+                    .ok_or_else(|| E::custom(ResourceId::expected()))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<ResourceId, E> {
+                self.visit_i64(i64::try_from(value).unwrap_or(i64::MAX))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// Finds the key of a user or product in `db`.
+///
+/// # Returns
+///
+/// * `Result<Option<i32>, AppError>` - The key, `None` if no row has this UUID. An
+///   integer key is returned as is, whether its row exists or not, and never without
+///   `ACCEPT_INTEGER_IDS`.
+pub async fn resolve(
+    db: Db,
+    collection: Collection,
+    id: ResourceId,
+) -> Result<Option<i32>, AppError> {
+    let uuid = match id {
+        ResourceId::Key(key) => return Ok(accept_integer_ids().then_some(key)),
+        ResourceId::Public(uuid) => uuid,
+    };
+    let resolved = || RESOLVED.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(key) = resolved().get(&(collection, uuid)) {
+        return Ok(Some(*key));
+    }
+
+    let sql = match collection {
+        Collection::Users => "SELECT id FROM users WHERE public_id = $1",
+        Collection::Products => "SELECT id FROM products WHERE public_id = $1",
+    };
+    let key = with_retry(db, || async move {
+        let conn = db.read_connection().await?;
+        let statement = conn.prepare_cached(sql).await?;
+        let row = conn.query_opt(&statement, &[&uuid]).await?;
+        Ok(row.map(|row| row.get::<_, i32>("id")))
+    })
+    .await?;
+
+    if let Some(key) = key {
+        resolved().put((collection, uuid), key);
+    }
+    Ok(key)
+}
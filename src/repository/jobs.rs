@@ -0,0 +1,303 @@
+//! Jobs repository.
+//!
+//! Rows of the `jobs` table, written by the `jobs` module. Payloads are JSON documents
+//! sent and read as text, the table stores them as `JSONB` so they can be queried.
+
+use std::future::Future;
+use std::time::Duration;
+
+use bb8_postgres::tokio_postgres::Row;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::retry::with_retry;
+use crate::db::Db;
+use crate::error::AppError;
+
+/// State of a job.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Waiting for a worker, or for its next attempt
+    Queued,
+    Running,
+    Succeeded,
+    /// Every attempt failed
+    Failed,
+}
+
+impl JobStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+/// A job taken by a worker.
+#[derive(Debug)]
+pub struct ClaimedJob {
+    pub payload: String,
+    /// Including the one starting
+    pub attempts: i32,
+    /// Request that enqueued the job, if any
+    pub request_id: Option<String>,
+    /// Caller of that request
+    pub actor_id: Option<i32>,
+}
+
+/// A job as listed by `GET /admin/jobs`, or an operation of `GET /operations/:id`.
+#[derive(Serialize, Debug)]
+pub struct JobRecord {
+    pub id: i64,
+    pub kind: String,
+    pub payload: Value,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    /// Request that enqueued the job, `None` for the scheduled ones
+    pub request_id: Option<String>,
+    /// How far a long job went, as it reports it
+    pub progress: Option<Value>,
+    /// Timestamps in RFC 3339, UTC
+    pub created_at: String,
+    pub finished_at: Option<String>,
+}
+
+impl From<&Row> for JobRecord {
+    fn from(row: &Row) -> Self {
+        JobRecord {
+            id: row.get("id"),
+            kind: row.get("kind"),
+            payload: serde_json::from_str(row.get("payload")).unwrap_or_default(),
+            status: row.get("status"),
+            attempts: row.get("attempts"),
+            last_error: row.get("last_error"),
+            request_id: row.get("request_id"),
+            progress: row
+                .get::<_, Option<&str>>("progress")
+                .and_then(|progress| serde_json::from_str(progress).ok()),
+            created_at: row.get("created_at"),
+            finished_at: row.get("finished_at"),
+        }
+    }
+}
+
+/// Operations on the `jobs` table.
+///
+/// Methods returning `Option` or `bool` report a missing job that way,
+/// so the caller decides which error (if any) it maps to.
+pub trait JobRepository {
+    /// Inserts a queued job, returning its ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `request_id`, `actor_id` - Request that enqueued the job and its caller
+    fn insert(
+        &self,
+        kind: &str,
+        payload: &str,
+        request_id: Option<&str>,
+        actor_id: Option<i32>,
+    ) -> impl Future<Output = Result<i64, AppError>> + Send;
+
+    /// Marks a queued job as running and counts the attempt.
+    /// `None` if the job isn't queued anymore (taken by another instance).
+    fn claim(&self, id: i64) -> impl Future<Output = Result<Option<ClaimedJob>, AppError>> + Send;
+
+    /// Marks a running job as succeeded.
+    fn complete(&self, id: i64) -> impl Future<Output = Result<(), AppError>> + Send;
+
+    /// Records the error of a running job, queued again to run after `retry_in`, or
+    /// failed for good when `None`.
+    fn fail(
+        &self,
+        id: i64,
+        error: &str,
+        retry_in: Option<Duration>,
+    ) -> impl Future<Output = Result<(), AppError>> + Send;
+
+    /// Records how far a running job went.
+    fn set_progress(
+        &self,
+        id: i64,
+        progress: &Value,
+    ) -> impl Future<Output = Result<(), AppError>> + Send;
+
+    /// Lists the queued jobs, with the time left before each one can run.
+    fn queued(&self) -> impl Future<Output = Result<Vec<(i64, Duration)>, AppError>> + Send;
+
+    /// Deletes the jobs finished more than `days` days ago, returning how many.
+    fn purge(&self, days: i32) -> impl Future<Output = Result<u64, AppError>> + Send;
+
+    /// Retrieves the latest jobs, optionally only those with a status.
+    fn list(
+        &self,
+        status: Option<JobStatus>,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<JobRecord>, AppError>> + Send;
+
+    /// Retrieves a job by ID.
+    fn find(&self, id: i64) -> impl Future<Output = Result<Option<JobRecord>, AppError>> + Send;
+}
+
+/// Columns of a `JobRecord`
+const RECORD_COLUMNS: &str = "id, kind, payload::text AS payload, status, attempts, last_error, \
+     request_id, progress::text AS progress, \
+     to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS created_at, \
+     to_char(finished_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS finished_at";
+
+/// `JobRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgJobRepo(pub Db);
+
+impl JobRepository for PgJobRepo {
+    async fn insert(
+        &self,
+        kind: &str,
+        payload: &str,
+        request_id: Option<&str>,
+        actor_id: Option<i32>,
+    ) -> Result<i64, AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "INSERT INTO jobs (kind, payload, request_id, actor_id) \
+                 VALUES ($1, $2::text::jsonb, $3, $4) RETURNING id",
+            )
+            .await?;
+        let row = conn
+            .query_one(&statement, &[&kind, &payload, &request_id, &actor_id])
+            .await?;
+        Ok(row.get("id"))
+    }
+
+    async fn claim(&self, id: i64) -> Result<Option<ClaimedJob>, AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "UPDATE jobs SET status = 'running', attempts = attempts + 1 \
+                 WHERE id = $1 AND status = 'queued' \
+                 RETURNING payload::text AS payload, attempts, request_id, actor_id",
+            )
+            .await?;
+        let row = conn.query_opt(&statement, &[&id]).await?;
+        Ok(row.map(|row| ClaimedJob {
+            payload: row.get("payload"),
+            attempts: row.get("attempts"),
+            request_id: row.get("request_id"),
+            actor_id: row.get("actor_id"),
+        }))
+    }
+
+    async fn complete(&self, id: i64) -> Result<(), AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "UPDATE jobs SET status = 'succeeded', finished_at = now(), last_error = NULL \
+                 WHERE id = $1",
+            )
+            .await?;
+        conn.execute(&statement, &[&id]).await?;
+        Ok(())
+    }
+
+    async fn fail(&self, id: i64, error: &str, retry_in: Option<Duration>) -> Result<(), AppError> {
+        let conn = self.0.connection().await?;
+        match retry_in {
+            Some(delay) => {
+                let statement = conn
+                    .prepare_cached(
+                        "UPDATE jobs SET status = 'queued', last_error = $2, \
+                         run_at = now() + make_interval(secs => $3) WHERE id = $1",
+                    )
+                    .await?;
+                let seconds = delay.as_secs_f64();
+                conn.execute(&statement, &[&id, &error, &seconds]).await?;
+            }
+            None => {
+                let statement = conn
+                    .prepare_cached(
+                        "UPDATE jobs SET status = 'failed', last_error = $2, finished_at = now() \
+                         WHERE id = $1",
+                    )
+                    .await?;
+                conn.execute(&statement, &[&id, &error]).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_progress(&self, id: i64, progress: &Value) -> Result<(), AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached("UPDATE jobs SET progress = $2::text::jsonb WHERE id = $1")
+            .await?;
+        conn.execute(&statement, &[&id, &progress.to_string()])
+            .await?;
+        Ok(())
+    }
+
+    async fn queued(&self) -> Result<Vec<(i64, Duration)>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT id, GREATEST(EXTRACT(EPOCH FROM run_at - now()), 0)::float8 AS wait \
+                     FROM jobs WHERE status = 'queued' ORDER BY run_at",
+                )
+                .await?;
+            let rows = conn.query(&statement, &[]).await?;
+            Ok(rows
+                .iter()
+                .map(|row| (row.get("id"), Duration::from_secs_f64(row.get("wait"))))
+                .collect())
+        })
+        .await
+    }
+
+    async fn purge(&self, days: i32) -> Result<u64, AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "DELETE FROM jobs WHERE status IN ('succeeded', 'failed') \
+                 AND finished_at < now() - make_interval(days => $1)",
+            )
+            .await?;
+        Ok(conn.execute(&statement, &[&days]).await?)
+    }
+
+    async fn list(
+        &self,
+        status: Option<JobStatus>,
+        limit: i64,
+    ) -> Result<Vec<JobRecord>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.connection().await?;
+            let sql = format!(
+                "SELECT {} FROM jobs WHERE $1::text IS NULL OR status = $1 \
+                 ORDER BY id DESC LIMIT $2",
+                RECORD_COLUMNS
+            );
+            let statement = conn.prepare_cached(&sql).await?;
+            let status = status.map(JobStatus::as_str);
+            let rows = conn.query(&statement, &[&status, &limit]).await?;
+            Ok(rows.iter().map(JobRecord::from).collect())
+        })
+        .await
+    }
+
+    async fn find(&self, id: i64) -> Result<Option<JobRecord>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.connection().await?;
+            let sql = format!("SELECT {} FROM jobs WHERE id = $1", RECORD_COLUMNS);
+            let statement = conn.prepare_cached(&sql).await?;
+            let row = conn.query_opt(&statement, &[&id]).await?;
+            Ok(row.as_ref().map(JobRecord::from))
+        })
+        .await
+    }
+}
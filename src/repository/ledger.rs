@@ -0,0 +1,581 @@
+//! Ledger repository.
+//!
+//! The accounts, entries and postings of the double-entry ledger (see `ledger`), in the
+//! tables of the `V28__create_ledger` migration. Amounts are in cents, debits positive
+//! and credits negative.
+
+use std::future::Future;
+
+use bb8_postgres::tokio_postgres::Row;
+use bb8_postgres::tokio_postgres::error::SqlState;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::ids::new_public_id;
+use super::retry::with_retry;
+use crate::auth::AuthUser;
+use crate::db::{CachedTransaction, Db};
+use crate::error::{AppError, ErrorCode};
+
+/// Columns of an `Entry`, seen from the credit account of a user, from the postings
+/// `p` to it, with the balance after each one
+const USER_ENTRY_COLUMNS: &str = "e.public_id, e.kind, e.description, e.order_id, \
+     -p.amount AS amount, \
+     -(sum(p.amount) OVER (ORDER BY p.entry_id))::bigint AS balance, \
+     to_char(e.created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS created_at";
+
+/// Discrepancies listed by the consistency check, of each kind
+const REPORTED_DISCREPANCIES: i64 = 100;
+
+/// An account of the ledger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Account {
+    /// Expense of the credits granted to the users
+    Promotions,
+    /// Expense of the orders refunded
+    Refunds,
+    /// Credit of a user (by key), what the shop owes them; a liability that can't go
+    /// below zero
+    Credit(i32),
+}
+
+impl Account {
+    /// Name of the account in `ledger_accounts`.
+    pub fn name(self) -> String {
+        match self {
+            Account::Promotions => "promotions".to_string(),
+            Account::Refunds => "refunds".to_string(),
+            Account::Credit(user_id) => format!("credit:{}", user_id),
+        }
+    }
+
+    fn kind(self) -> &'static str {
+        match self {
+            Account::Promotions | Account::Refunds => "expense",
+            Account::Credit(_) => "liability",
+        }
+    }
+
+    fn user_id(self) -> Option<i32> {
+        match self {
+            Account::Credit(user_id) => Some(user_id),
+            _ => None,
+        }
+    }
+}
+
+/// Amount moved to or from an account by an entry: positive for a debit, negative for
+/// a credit, in cents.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Posting {
+    pub account: Account,
+    pub amount: i64,
+}
+
+/// What an entry records.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind {
+    /// Credit granted to a user, or taken back
+    Credit,
+    /// Order refunded to the credit of its user
+    Refund,
+}
+
+impl EntryKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            EntryKind::Credit => "credit",
+            EntryKind::Refund => "refund",
+        }
+    }
+}
+
+/// An entry to post, moving money to or from the credit of a user.
+#[derive(Debug)]
+pub struct NewEntry {
+    pub kind: EntryKind,
+    pub user_id: i32,
+    pub description: String,
+    pub order_id: Option<i32>,
+    pub postings: Vec<Posting>,
+}
+
+impl NewEntry {
+    /// Credit granted to a user (`amount` > 0), or taken back (`amount` < 0), charged
+    /// to the promotions.
+    pub fn credit(user_id: i32, amount: i64, description: String) -> Self {
+        NewEntry {
+            kind: EntryKind::Credit,
+            user_id,
+            description,
+            order_id: None,
+            postings: vec![
+                Posting {
+                    account: Account::Promotions,
+                    amount,
+                },
+                Posting {
+                    account: Account::Credit(user_id),
+                    amount: -amount,
+                },
+            ],
+        }
+    }
+
+    /// Refund of the `amount` of an order to the credit of its user.
+    pub fn refund(user_id: i32, order_id: i32, amount: i64) -> Self {
+        NewEntry {
+            kind: EntryKind::Refund,
+            user_id,
+            description: format!("Refund of order {}", order_id),
+            order_id: Some(order_id),
+            postings: vec![
+                Posting {
+                    account: Account::Refunds,
+                    amount,
+                },
+                Posting {
+                    account: Account::Credit(user_id),
+                    amount: -amount,
+                },
+            ],
+        }
+    }
+
+    /// Checks the invariants of a double entry: two postings or more, to distinct
+    /// accounts, none of them zero, summing to zero.
+    pub fn check(&self) -> Result<(), String> {
+        if self.postings.len() < 2 {
+            return Err("An entry needs two postings or more".to_string());
+        }
+        let mut accounts = self
+            .postings
+            .iter()
+            .map(|posting| posting.account)
+            .collect::<Vec<_>>();
+        accounts.sort();
+        accounts.dedup();
+        if accounts.len() != self.postings.len() {
+            return Err("An entry posts once to each account".to_string());
+        }
+        if self.postings.iter().any(|posting| posting.amount == 0) {
+            return Err("A posting can't be zero".to_string());
+        }
+        let sum = self
+            .postings
+            .iter()
+            .try_fold(0i64, |sum, posting| sum.checked_add(posting.amount));
+        match sum {
+            Some(0) => Ok(()),
+            Some(sum) => Err(format!("The postings sum to {} instead of 0", sum)),
+            None => Err("The postings overflow".to_string()),
+        }
+    }
+}
+
+/// An entry, as listed to the user whose credit it changed.
+#[derive(Serialize, Debug)]
+pub struct Entry {
+    #[serde(rename = "id")]
+    pub public_id: Uuid,
+    /// `credit` or `refund`
+    pub kind: String,
+    pub description: String,
+    /// The order refunded
+    pub order_id: Option<i32>,
+    /// Change of the credit of the user, in cents, negative when taken back
+    pub amount: i64,
+    /// Credit of the user after the entry, in cents
+    pub balance: i64,
+    /// RFC 3339, UTC
+    pub created_at: String,
+}
+
+impl From<&Row> for Entry {
+    fn from(row: &Row) -> Self {
+        Entry {
+            public_id: row.get("public_id"),
+            kind: row.get("kind"),
+            description: row.get("description"),
+            order_id: row.get("order_id"),
+            amount: row.get("amount"),
+            balance: row.get("balance"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+/// The credit of a user and its latest entries.
+#[derive(Serialize, Debug)]
+pub struct Balance {
+    /// In cents
+    pub balance: i64,
+    /// The latest first
+    pub entries: Vec<Entry>,
+}
+
+/// An account whose balance isn't the sum of its postings.
+#[derive(Serialize, Debug)]
+pub struct Drift {
+    pub account: String,
+    pub balance: i64,
+    /// Sum of the postings
+    pub expected: i64,
+}
+
+/// Outcome of the consistency check of the ledger, from a single snapshot.
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub accounts: i64,
+    pub entries: i64,
+    /// Entries whose postings don't sum to zero, or with fewer than two (at most
+    /// `REPORTED_DISCREPANCIES`)
+    pub unbalanced_entries: Vec<Uuid>,
+    /// Accounts whose balance isn't the sum of their postings (at most
+    /// `REPORTED_DISCREPANCIES`)
+    pub drifted_accounts: Vec<Drift>,
+    /// Sum of the balances of every account, zero in a consistent ledger
+    pub trial_balance: i64,
+    /// Whether no discrepancy was found
+    pub consistent: bool,
+}
+
+/// Operations on the `ledger_accounts`, `ledger_entries` and `ledger_postings` tables.
+pub trait LedgerRepository {
+    /// Writes an entry and adds its postings to the balances of their accounts, in one
+    /// transaction; the accounts are created by their first entry.
+    ///
+    /// Fails with `AppError::Conflict` if a credit account would go below zero, and
+    /// with `AppError::NotFound` if the user doesn't exist; nothing is written then.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Entry, AppError>` - The entry, as seen from the credit of its user
+    fn post(&self, entry: &NewEntry) -> impl Future<Output = Result<Entry, AppError>> + Send;
+
+    /// Refunds the total of an order (by key) of a user to their credit.
+    ///
+    /// Fails with `AppError::NotFound` if the user has no such order, and with
+    /// `AppError::Conflict` if it was already refunded.
+    fn refund(
+        &self,
+        user_id: i32,
+        order_id: i32,
+    ) -> impl Future<Output = Result<Entry, AppError>> + Send;
+
+    /// Retrieves the credit of a user and their `limit` latest entries; a user without
+    /// entries has none.
+    fn balance(
+        &self,
+        user_id: i32,
+        limit: i64,
+    ) -> impl Future<Output = Result<Balance, AppError>> + Send;
+
+    /// Recomputes the balances from the postings and looks for unbalanced entries.
+    fn check(&self) -> impl Future<Output = Result<Report, AppError>> + Send;
+}
+
+/// `LedgerRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgLedgerRepo(pub Db);
+
+impl LedgerRepository for PgLedgerRepo {
+    async fn post(&self, entry: &NewEntry) -> Result<Entry, AppError> {
+        self.0
+            .transaction(async |tx| post_in(tx, entry).await)
+            .await
+    }
+
+    async fn refund(&self, user_id: i32, order_id: i32) -> Result<Entry, AppError> {
+        let result = self
+            .0
+            .transaction(async |tx| {
+                // FOR UPDATE serializes the refunds of the order, the second one then finds
+                // the entry of the first
+                let total: i64 = tx
+                    .query_opt(
+                        "SELECT round(quantity * unit_price * 100)::bigint - discount AS total \
+                     FROM orders WHERE id = $1 AND user_id = $2 FOR UPDATE",
+                        &[&order_id, &user_id],
+                    )
+                    .await?
+                    .ok_or_else(|| {
+                        AppError::NotFound(ErrorCode::OrderNotFound, "Order not found".to_string())
+                    })?
+                    .get("total");
+                let refunded = tx
+                    .query_opt(
+                        "SELECT 1 FROM ledger_entries WHERE kind = 'refund' AND order_id = $1",
+                        &[&order_id],
+                    )
+                    .await?;
+                if refunded.is_some() {
+                    return Err(already_refunded());
+                }
+                if total <= 0 {
+                    return Err(AppError::Validation(
+                        "The order is free, there is nothing to refund".to_string(),
+                    ));
+                }
+                post_in(tx, &NewEntry::refund(user_id, order_id, total)).await
+            })
+            .await;
+
+        match result {
+            // ledger_entries_refund_idx, should the lock of the order be missed
+            Err(AppError::Db(e)) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+                Err(already_refunded())
+            }
+            result => result,
+        }
+    }
+
+    async fn balance(&self, user_id: i32, limit: i64) -> Result<Balance, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(&format!(
+                    "SELECT {} FROM ledger_postings p \
+                     JOIN ledger_accounts a ON a.id = p.account_id \
+                     JOIN ledger_entries e ON e.id = p.entry_id \
+                     WHERE a.user_id = $1 \
+                     ORDER BY p.entry_id DESC LIMIT $2",
+                    USER_ENTRY_COLUMNS
+                ))
+                .await?;
+            let rows = conn.query(&statement, &[&user_id, &limit]).await?;
+            let entries = rows.iter().map(Entry::from).collect::<Vec<_>>();
+            Ok(Balance {
+                balance: entries.first().map_or(0, |latest| latest.balance),
+                entries,
+            })
+        })
+        .await
+    }
+
+    async fn check(&self) -> Result<Report, AppError> {
+        with_retry(self.0, || async move {
+            // The primary, with everything committed, in a single snapshot
+            let mut conn = self.0.connection().await?;
+            let tx = conn.cached_transaction().await?;
+            tx.batch_execute("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY")
+                .await?;
+            let totals = tx
+                .query_one(
+                    "SELECT (SELECT count(*) FROM ledger_accounts) AS accounts, \
+                     (SELECT count(*) FROM ledger_entries) AS entries, \
+                     (SELECT COALESCE(sum(balance), 0)::bigint FROM ledger_accounts) \
+                     AS trial_balance",
+                    &[],
+                )
+                .await?;
+            let unbalanced = tx
+                .query(
+                    "SELECT e.public_id FROM ledger_entries e \
+                     LEFT JOIN ledger_postings p ON p.entry_id = e.id \
+                     GROUP BY e.id \
+                     HAVING COALESCE(sum(p.amount), 0) <> 0 OR count(p.id) < 2 \
+                     ORDER BY e.id LIMIT $1",
+                    &[&REPORTED_DISCREPANCIES],
+                )
+                .await?;
+            let drifted = tx
+                .query(
+                    "SELECT a.name, a.balance, COALESCE(sum(p.amount), 0)::bigint AS expected \
+                     FROM ledger_accounts a \
+                     LEFT JOIN ledger_postings p ON p.account_id = a.id \
+                     GROUP BY a.id \
+                     HAVING a.balance <> COALESCE(sum(p.amount), 0) \
+                     ORDER BY a.id LIMIT $1",
+                    &[&REPORTED_DISCREPANCIES],
+                )
+                .await?;
+            tx.commit().await?;
+
+            let trial_balance: i64 = totals.get("trial_balance");
+            Ok(Report {
+                accounts: totals.get("accounts"),
+                entries: totals.get("entries"),
+                consistent: unbalanced.is_empty() && drifted.is_empty() && trial_balance == 0,
+                unbalanced_entries: unbalanced.iter().map(|row| row.get(0)).collect(),
+                drifted_accounts: drifted
+                    .iter()
+                    .map(|row| Drift {
+                        account: row.get("name"),
+                        balance: row.get("balance"),
+                        expected: row.get("expected"),
+                    })
+                    .collect(),
+                trial_balance,
+            })
+        })
+        .await
+    }
+}
+
+fn already_refunded() -> AppError {
+    AppError::Conflict(
+        ErrorCode::OrderRefunded,
+        "The order was already refunded".to_string(),
+    )
+}
+
+/// Posts an entry in the transaction `tx`, see `LedgerRepository::post`.
+async fn post_in(tx: &CachedTransaction<'_>, entry: &NewEntry) -> Result<Entry, AppError> {
+    entry
+        .check()
+        .map_err(|e| AppError::Internal(format!("Invalid ledger entry: {}", e)))?;
+
+    let mut postings = entry.postings.clone();
+    postings.sort_by_key(|posting| posting.account);
+    for posting in &postings {
+        let account = posting.account;
+        let created = tx
+            .execute(
+                "INSERT INTO ledger_accounts (name, kind, user_id, may_overdraw) \
+                 VALUES ($1, $2, $3, $4) ON CONFLICT (name) DO NOTHING",
+                &[
+                    &account.name(),
+                    &account.kind(),
+                    &account.user_id(),
+                    &account.user_id().is_none(),
+                ],
+            )
+            .await;
+        match created {
+            Err(e) if e.code() == Some(&SqlState::FOREIGN_KEY_VIOLATION) => {
+                return Err(AppError::NotFound(
+                    ErrorCode::UserNotFound,
+                    "User not found".to_string(),
+                ));
+            }
+            created => created?,
+        };
+    }
+
+    // Locked in the order of their IDs, so that concurrent entries wait for each other
+    // instead of deadlocking, and none of them loses the update of another
+    let names = postings
+        .iter()
+        .map(|posting| posting.account.name())
+        .collect::<Vec<_>>();
+    let accounts = tx
+        .query(
+            "SELECT id, name, balance, may_overdraw FROM ledger_accounts \
+             WHERE name = ANY($1) ORDER BY id FOR UPDATE",
+            &[&names],
+        )
+        .await?;
+    for posting in &postings {
+        let name = posting.account.name();
+        let account = accounts
+            .iter()
+            .find(|row| row.get::<_, &str>("name") == name)
+            .ok_or_else(|| AppError::Internal(format!("Ledger account {} is missing", name)))?;
+        let balance: i64 = account.get("balance");
+        // The credit accounts, the only ones that may not overdraw, grow with credits
+        if !account.get::<_, bool>("may_overdraw") && balance + posting.amount > 0 {
+            return Err(AppError::Conflict(
+                ErrorCode::InsufficientCredit,
+                format!("Not enough credit ({} cents available)", -balance),
+            ));
+        }
+    }
+
+    let actor_id = AuthUser::current().map(|user| user.id);
+    let row = tx
+        .query_one(
+            "INSERT INTO ledger_entries (public_id, kind, description, order_id, actor_id) \
+             VALUES ($1, $2, $3, $4, $5) RETURNING id",
+            &[
+                &new_public_id(),
+                &entry.kind.as_str(),
+                &entry.description,
+                &entry.order_id,
+                &actor_id,
+            ],
+        )
+        .await?;
+    let entry_id: i64 = row.get("id");
+    for posting in &postings {
+        tx.execute(
+            "WITH account AS (\
+                 UPDATE ledger_accounts SET balance = balance + $3 WHERE name = $2 \
+                 RETURNING id) \
+             INSERT INTO ledger_postings (entry_id, account_id, amount) \
+             SELECT $1, id, $3 FROM account",
+            &[&entry_id, &posting.account.name(), &posting.amount],
+        )
+        .await?;
+    }
+
+    let row = tx
+        .query_one(
+            &format!(
+                "SELECT {} FROM ledger_postings p \
+                 JOIN ledger_accounts a ON a.id = p.account_id \
+                 JOIN ledger_entries e ON e.id = p.entry_id \
+                 WHERE a.name = $1 \
+                 ORDER BY p.entry_id DESC LIMIT 1",
+                USER_ENTRY_COLUMNS
+            ),
+            &[&Account::Credit(entry.user_id).name()],
+        )
+        .await?;
+    Ok(Entry::from(&row))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(postings: &[(Account, i64)]) -> NewEntry {
+        NewEntry {
+            kind: EntryKind::Credit,
+            user_id: 1,
+            description: String::new(),
+            order_id: None,
+            postings: postings
+                .iter()
+                .map(|&(account, amount)| Posting { account, amount })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn entries_must_balance() {
+        assert_eq!(NewEntry::credit(1, 500, String::new()).check(), Ok(()));
+        assert_eq!(NewEntry::credit(1, -500, String::new()).check(), Ok(()));
+        assert_eq!(NewEntry::refund(1, 7, 1999).check(), Ok(()));
+        assert!(NewEntry::credit(1, 0, String::new()).check().is_err());
+
+        assert!(entry(&[(Account::Credit(1), 0)]).check().is_err());
+        assert_eq!(
+            entry(&[(Account::Promotions, 500), (Account::Credit(1), -499)]).check(),
+            Err("The postings sum to 1 instead of 0".to_string())
+        );
+        assert!(
+            entry(&[(Account::Credit(1), 500), (Account::Credit(1), -500)])
+                .check()
+                .is_err()
+        );
+        assert!(
+            entry(&[
+                (Account::Promotions, i64::MAX),
+                (Account::Refunds, 1),
+                (Account::Credit(1), i64::MIN),
+            ])
+            .check()
+            .is_err()
+        );
+        assert_eq!(
+            entry(&[
+                (Account::Promotions, 300),
+                (Account::Refunds, 200),
+                (Account::Credit(1), -500),
+            ])
+            .check(),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! OpenID Connect provider repository.
+//!
+//! The key signing the ID tokens and the authorization codes of the clients (see
+//! `oidc`), in the tables of the `V25__create_oidc` migration.
+
+use std::future::Future;
+
+use crate::db::Db;
+use crate::error::AppError;
+
+/// The key signing the ID tokens.
+#[derive(Debug, Clone)]
+pub struct SigningKey {
+    /// Key ID of the JWKS and of the token headers
+    pub kid: String,
+    /// PKCS#8 document of the P-256 key
+    pub private_key: Vec<u8>,
+}
+
+/// An authorization code to save, given to a client after the user logged in.
+#[derive(Debug)]
+pub struct NewAuthorizationCode<'a> {
+    /// SHA-256 of the code, hex-encoded
+    pub code_hash: &'a str,
+    pub client_id: &'a str,
+    pub user_id: i32,
+    pub redirect_uri: &'a str,
+    /// PKCE challenge, the S256 of the verifier the client will send
+    pub code_challenge: &'a str,
+    pub scope: &'a str,
+    pub nonce: Option<&'a str>,
+    /// Seconds the code can be exchanged for tokens
+    pub lifetime_secs: i32,
+}
+
+/// An authorization code being exchanged for tokens.
+#[derive(Debug)]
+pub struct AuthorizationCode {
+    pub client_id: String,
+    pub user_id: i32,
+    pub redirect_uri: String,
+    pub code_challenge: String,
+    pub scope: String,
+    pub nonce: Option<String>,
+    /// When the user logged in, in seconds since the Unix epoch
+    pub auth_time: i64,
+}
+
+/// Operations on the `oidc_signing_key` and `oidc_authorization_codes` tables.
+pub trait OidcRepository {
+    /// Saves `candidate` as the signing key unless one was saved, by another instance
+    /// maybe, and retrieves the saved one.
+    fn signing_key(
+        &self,
+        candidate: &SigningKey,
+    ) -> impl Future<Output = Result<SigningKey, AppError>> + Send;
+
+    /// Saves an authorization code, removing the expired ones.
+    fn create_code(
+        &self,
+        code: &NewAuthorizationCode<'_>,
+    ) -> impl Future<Output = Result<(), AppError>> + Send;
+
+    /// Removes an authorization code and retrieves it, `None` if it doesn't exist,
+    /// was already taken, or expired.
+    fn take_code(
+        &self,
+        code_hash: &str,
+    ) -> impl Future<Output = Result<Option<AuthorizationCode>, AppError>> + Send;
+}
+
+/// `OidcRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgOidcRepo(pub Db);
+
+impl OidcRepository for PgOidcRepo {
+    async fn signing_key(&self, candidate: &SigningKey) -> Result<SigningKey, AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "INSERT INTO oidc_signing_key (kid, private_key) VALUES ($1, $2) \
+                 ON CONFLICT (singleton) DO NOTHING",
+            )
+            .await?;
+        conn.execute(&statement, &[&candidate.kid, &candidate.private_key])
+            .await?;
+        let statement = conn
+            .prepare_cached("SELECT kid, private_key FROM oidc_signing_key")
+            .await?;
+        let row = conn.query_one(&statement, &[]).await?;
+        Ok(SigningKey {
+            kid: row.get("kid"),
+            private_key: row.get("private_key"),
+        })
+    }
+
+    async fn create_code(&self, code: &NewAuthorizationCode<'_>) -> Result<(), AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "WITH expired AS (\
+                     DELETE FROM oidc_authorization_codes WHERE expires_at <= now()) \
+                 INSERT INTO oidc_authorization_codes (code_hash, client_id, user_id, \
+                 redirect_uri, code_challenge, scope, nonce, expires_at) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, now() + make_interval(secs => $8))",
+            )
+            .await?;
+        conn.execute(
+            &statement,
+            &[
+                &code.code_hash,
+                &code.client_id,
+                &code.user_id,
+                &code.redirect_uri,
+                &code.code_challenge,
+                &code.scope,
+                &code.nonce,
+                &f64::from(code.lifetime_secs),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn take_code(&self, code_hash: &str) -> Result<Option<AuthorizationCode>, AppError> {
+        // On the primary, and never retried: a code is taken once
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "WITH taken AS (\
+                     DELETE FROM oidc_authorization_codes WHERE code_hash = $1 RETURNING *) \
+                 SELECT client_id, user_id, redirect_uri, code_challenge, scope, nonce, \
+                 extract(epoch FROM auth_time)::bigint AS auth_time \
+                 FROM taken WHERE expires_at > now()",
+            )
+            .await?;
+        let row = conn.query_opt(&statement, &[&code_hash]).await?;
+        Ok(row.map(|row| AuthorizationCode {
+            client_id: row.get("client_id"),
+            user_id: row.get("user_id"),
+            redirect_uri: row.get("redirect_uri"),
+            code_challenge: row.get("code_challenge"),
+            scope: row.get("scope"),
+            nonce: row.get("nonce"),
+            auth_time: row.get("auth_time"),
+        }))
+    }
+}
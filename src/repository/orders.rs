@@ -0,0 +1,203 @@
+//! Orders repository.
+
+use std::future::Future;
+
+use bb8_postgres::tokio_postgres::Row;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use super::audit::{self, Entity};
+use super::coupons;
+use super::ids::ResourceId;
+use super::retry::with_retry;
+use crate::db::{CachedTransaction, Db};
+use crate::error::{AppError, ErrorCode};
+
+/// A placed order.
+#[derive(Serialize, Clone, Debug)]
+pub struct Order {
+    pub id: i32,
+    /// Public ID of the user
+    pub user_id: Uuid,
+    /// Public ID of the product
+    pub product_id: Uuid,
+    pub quantity: i32,
+    pub unit_price: f64,
+    /// Taken off `quantity * unit_price` by the coupon
+    pub discount: f64,
+    /// Code of the coupon redeemed
+    pub coupon: Option<String>,
+}
+
+/// Fields of an order set by clients.
+#[derive(Deserialize, Debug)]
+pub struct NewOrder {
+    pub product_id: ResourceId,
+    pub quantity: i32,
+    /// Code of a coupon to redeem
+    #[serde(default)]
+    pub coupon: Option<String>,
+}
+
+impl From<&Row> for Order {
+    fn from(row: &Row) -> Self {
+        Order {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            product_id: row.get("product_id"),
+            quantity: row.get("quantity"),
+            unit_price: row.get("unit_price"),
+            discount: row.get("discount"),
+            coupon: row.get("coupon"),
+        }
+    }
+}
+
+/// Operations on the `orders` table.
+pub trait OrderRepository {
+    /// Places an order of a product for a user (both by key), taking the quantity from
+    /// the product stock, and redeeming a coupon if given.
+    ///
+    /// Fails with `AppError::NotFound` if the user, the product or the coupon doesn't
+    /// exist and with `AppError::Conflict` if there isn't enough stock or the coupon
+    /// can't be redeemed; nothing is changed then.
+    fn place(
+        &self,
+        user_id: i32,
+        product_id: i32,
+        quantity: i32,
+        coupon: Option<&str>,
+    ) -> impl Future<Output = Result<Order, AppError>> + Send;
+
+    /// Retrieves the latest orders of every user, the latest first.
+    fn recent(&self, limit: i64) -> impl Future<Output = Result<Vec<Order>, AppError>> + Send;
+}
+
+/// `OrderRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgOrderRepo(pub Db);
+
+impl OrderRepository for PgOrderRepo {
+    async fn place(
+        &self,
+        user_id: i32,
+        product_id: i32,
+        quantity: i32,
+        coupon: Option<&str>,
+    ) -> Result<Order, AppError> {
+        self.0
+            .transaction(async |tx| place_in(tx, user_id, product_id, quantity, coupon).await)
+            .await
+    }
+
+    async fn recent(&self, limit: i64) -> Result<Vec<Order>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT orders.id, users.public_id AS user_id, \
+                     products.public_id AS product_id, quantity, unit_price, \
+                     orders.discount::float8 / 100 AS discount, coupons.code AS coupon \
+                     FROM orders \
+                     JOIN users ON users.id = orders.user_id \
+                     JOIN products ON products.id = orders.product_id \
+                     LEFT JOIN coupons ON coupons.id = orders.coupon_id \
+                     ORDER BY orders.created_at DESC, orders.id DESC LIMIT $1",
+                )
+                .await?;
+            let rows = conn.query(&statement, &[&limit]).await?;
+            Ok(rows.iter().map(Order::from).collect())
+        })
+        .await
+    }
+}
+
+/// Places an order, as [`OrderRepository::place`], in a transaction placing others too
+/// (see `carts`).
+pub(super) async fn place_in(
+    tx: &CachedTransaction<'_>,
+    user_id: i32,
+    product_id: i32,
+    quantity: i32,
+    coupon: Option<&str>,
+) -> Result<Order, AppError> {
+    let user = tx
+        .query_opt(
+            "SELECT public_id FROM users WHERE id = $1 AND deleted_at IS NULL",
+            &[&user_id],
+        )
+        .await?
+        .ok_or_else(|| AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()))?;
+
+    // FOR UPDATE locks the product row until the transaction ends, so concurrent
+    // orders of the same product can't both take the last units
+    let product = tx
+        .query_opt(
+            "SELECT public_id, price, stock, \
+             round($2::integer * price * 100)::bigint AS subtotal \
+             FROM products WHERE id = $1 FOR UPDATE",
+            &[&product_id, &quantity],
+        )
+        .await?
+        .ok_or_else(|| {
+            AppError::NotFound(ErrorCode::ProductNotFound, "Product not found".to_string())
+        })?;
+    let price: f64 = product.get("price");
+    let stock: i32 = product.get("stock");
+
+    if stock < quantity {
+        return Err(AppError::Conflict(
+            ErrorCode::InsufficientStock,
+            format!("Not enough stock ({} available)", stock),
+        ));
+    }
+
+    tx.execute(
+        "UPDATE products SET stock = stock - $1, version = version + 1 WHERE id = $2",
+        &[&quantity, &product_id],
+    )
+    .await?;
+    let (before, after) = (json!({"stock": stock}), json!({"stock": stock - quantity}));
+    audit::record(tx, Entity::Product, product_id, Some(&before), Some(&after)).await?;
+
+    let redeemed = match coupon {
+        Some(code) => Some(coupons::redeem(tx, code, user_id, product.get("subtotal")).await?),
+        None => None,
+    };
+    let discount = redeemed.as_ref().map_or(0, |redeemed| redeemed.discount);
+    let coupon_id = redeemed.as_ref().map(|redeemed| redeemed.coupon_id);
+
+    let row = tx
+        .query_one(
+            "INSERT INTO orders (user_id, product_id, quantity, unit_price, discount, \
+             coupon_id) \
+             VALUES ($1, $2, $3, $4, $5, $6) \
+             RETURNING id",
+            &[
+                &user_id,
+                &product_id,
+                &quantity,
+                &price,
+                &discount,
+                &coupon_id,
+            ],
+        )
+        .await?;
+    let id: i32 = row.get("id");
+    if let Some(redeemed) = &redeemed {
+        coupons::record_redemption(tx, redeemed, user_id, id).await?;
+    }
+
+    let placed = Order {
+        id,
+        user_id: user.get("public_id"),
+        product_id: product.get("public_id"),
+        quantity,
+        unit_price: price,
+        discount: discount as f64 / 100.0,
+        coupon: redeemed.map(|redeemed| redeemed.code),
+    };
+    audit::record(tx, Entity::Order, placed.id, None, Some(&placed)).await?;
+    Ok(placed)
+}
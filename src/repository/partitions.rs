@@ -0,0 +1,117 @@
+//! Partitions repository.
+//!
+//! The tables partitioned by month of `created_at` (see the `partitions` module and
+//! `V19__partition_by_month`): each partition is named `<table>_pYYYY_MM` and holds the
+//! rows of that month, in UTC. The table names come from `partitions::PARTITIONED`,
+//! never from a request, as they're written in the statements.
+
+use std::future::Future;
+
+use super::retry::with_retry;
+use crate::db::Db;
+use crate::error::AppError;
+
+/// Operations creating and archiving the monthly partitions of a table.
+pub trait PartitionRepository {
+    /// Creates the partitions of `table` for the current month and the `ahead` next
+    /// ones, when missing.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<String>, AppError>` - The partitions created
+    fn create_upcoming(
+        &self,
+        table: &'static str,
+        ahead: i32,
+    ) -> impl Future<Output = Result<Vec<String>, AppError>> + Send;
+
+    /// Partitions of `table` for the months before the last `months` full months, the
+    /// oldest first.
+    fn expired(
+        &self,
+        table: &'static str,
+        months: i32,
+    ) -> impl Future<Output = Result<Vec<String>, AppError>> + Send;
+
+    /// Detaches a partition of `table`, and moves it to the `archive` schema: its rows
+    /// leave the table, but stay in the database until it's dropped.
+    fn archive(
+        &self,
+        table: &'static str,
+        partition: &str,
+    ) -> impl Future<Output = Result<(), AppError>> + Send;
+}
+
+/// `PartitionRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgPartitionRepo(pub Db);
+
+impl PartitionRepository for PgPartitionRepo {
+    async fn create_upcoming(
+        &self,
+        table: &'static str,
+        ahead: i32,
+    ) -> Result<Vec<String>, AppError> {
+        let conn = self.0.connection().await?;
+        // Bounds of each month, and whether its partition exists
+        let statement = conn
+            .prepare_cached(
+                "SELECT $1 || '_p' || to_char(m, 'YYYY_MM') AS name, \
+                 to_char(m, 'YYYY-MM-DD') AS since, \
+                 to_char(m + interval '1 month', 'YYYY-MM-DD') AS until, \
+                 to_regclass($1 || '_p' || to_char(m, 'YYYY_MM')) IS NOT NULL AS present \
+                 FROM generate_series(date_trunc('month', now() AT TIME ZONE 'UTC'), \
+                     date_trunc('month', now() AT TIME ZONE 'UTC') + make_interval(months => $2), \
+                     interval '1 month') AS m",
+            )
+            .await?;
+        let rows = conn.query(&statement, &[&table, &ahead]).await?;
+
+        let mut created = Vec::new();
+        for row in rows.iter().filter(|row| !row.get::<_, bool>("present")) {
+            let name: String = row.get("name");
+            let since: String = row.get("since");
+            let until: String = row.get("until");
+            conn.batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS {} PARTITION OF {} \
+                 FOR VALUES FROM ('{} 00:00:00+00') TO ('{} 00:00:00+00')",
+                name, table, since, until
+            ))
+            .await?;
+            created.push(name);
+        }
+        Ok(created)
+    }
+
+    async fn expired(&self, table: &'static str, months: i32) -> Result<Vec<String>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT c.relname FROM pg_inherits i JOIN pg_class c ON c.oid = i.inhrelid \
+                     WHERE i.inhparent = $1::text::regclass \
+                     AND c.relname ~ ('^' || $1 || '_p[0-9]{4}_[0-9]{2}$') \
+                     AND to_date(right(c.relname, 7), 'YYYY_MM') \
+                         < date_trunc('month', now() AT TIME ZONE 'UTC') - make_interval(months => $2) \
+                     ORDER BY c.relname",
+                )
+                .await?;
+            let rows = conn.query(&statement, &[&table, &months]).await?;
+            Ok(rows.iter().map(|row| row.get(0)).collect())
+        })
+        .await
+    }
+
+    async fn archive(&self, table: &'static str, partition: &str) -> Result<(), AppError> {
+        let sql = format!(
+            "ALTER TABLE {table} DETACH PARTITION {partition}; \
+             ALTER TABLE {partition} SET SCHEMA archive"
+        );
+        self.0
+            .transaction(async |tx| {
+                tx.batch_execute(&sql).await?;
+                Ok(())
+            })
+            .await
+    }
+}
@@ -0,0 +1,341 @@
+//! Passkeys repository.
+//!
+//! The public keys of the authenticators of the users, the challenges of the
+//! registrations and logins (see `passkeys`), in the tables of the
+//! `V27__create_passkeys` migration, and the `passkey_second_factor` of the users.
+
+use std::future::Future;
+
+use bb8_postgres::tokio_postgres::Row;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::ids::new_public_id;
+use super::retry::with_retry;
+use crate::db::Db;
+use crate::error::AppError;
+
+/// Columns of a `Passkey`
+const PASSKEY_COLUMNS: &str = "public_id, name, \
+     to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS created_at, \
+     to_char(last_used_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS last_used_at";
+
+/// A passkey as listed to its owner, without its key.
+#[derive(Serialize, Debug)]
+pub struct Passkey {
+    #[serde(rename = "id")]
+    pub public_id: Uuid,
+    pub name: String,
+    /// Timestamps in RFC 3339, UTC
+    pub created_at: String,
+    /// `None` until the first login with the passkey
+    pub last_used_at: Option<String>,
+}
+
+impl From<&Row> for Passkey {
+    fn from(row: &Row) -> Self {
+        Passkey {
+            public_id: row.get("public_id"),
+            name: row.get("name"),
+            created_at: row.get("created_at"),
+            last_used_at: row.get("last_used_at"),
+        }
+    }
+}
+
+/// A passkey to save, checked by `passkeys::verify_registration`.
+#[derive(Debug)]
+pub struct NewPasskey {
+    /// ID the authenticator gave the credential
+    pub credential_id: Vec<u8>,
+    /// COSE_Key of the authenticator
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+/// A passkey a login is checked against.
+#[derive(Debug)]
+pub struct StoredPasskey {
+    pub user_id: i32,
+    /// Public ID of the user, the user handle of the authenticator
+    pub user_public_id: Uuid,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+/// What a challenge was sent for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Purpose {
+    Registration,
+    /// A login without password
+    Authentication,
+    /// A login after the password
+    SecondFactor,
+}
+
+impl Purpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            Purpose::Registration => "registration",
+            Purpose::Authentication => "authentication",
+            Purpose::SecondFactor => "second_factor",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Purpose> {
+        [
+            Purpose::Registration,
+            Purpose::Authentication,
+            Purpose::SecondFactor,
+        ]
+        .into_iter()
+        .find(|purpose| purpose.as_str() == value)
+    }
+}
+
+/// A challenge to save, sent to the browser.
+#[derive(Debug)]
+pub struct NewChallenge<'a> {
+    pub purpose: Purpose,
+    /// `None` for the passwordless logins, whose user isn't known yet
+    pub user_id: Option<i32>,
+    pub challenge: &'a [u8],
+    /// Seconds the browser has to answer
+    pub lifetime_secs: i32,
+}
+
+/// A challenge being answered.
+#[derive(Debug)]
+pub struct Challenge {
+    pub purpose: Purpose,
+    pub user_id: Option<i32>,
+    pub challenge: Vec<u8>,
+}
+
+/// Operations on the `passkeys` and `passkey_challenges` tables. Passkeys belong to a
+/// user, who is the only one to see and remove them.
+pub trait PasskeyRepository {
+    /// Saves a challenge, removing the expired ones, and returns its ID.
+    fn create_challenge(
+        &self,
+        challenge: &NewChallenge<'_>,
+    ) -> impl Future<Output = Result<Uuid, AppError>> + Send;
+
+    /// Removes a challenge and retrieves it, `None` if it doesn't exist, was already
+    /// taken, or expired.
+    fn take_challenge(
+        &self,
+        id: Uuid,
+    ) -> impl Future<Output = Result<Option<Challenge>, AppError>> + Send;
+
+    /// Saves a passkey of the user. Fails with a unique violation if the credential is
+    /// already registered.
+    fn create(
+        &self,
+        user_id: i32,
+        name: &str,
+        passkey: &NewPasskey,
+    ) -> impl Future<Output = Result<Passkey, AppError>> + Send;
+
+    /// Retrieves the passkeys of the user, the oldest first.
+    fn list(&self, user_id: i32) -> impl Future<Output = Result<Vec<Passkey>, AppError>> + Send;
+
+    /// Retrieves the credential IDs of the passkeys of the user.
+    fn credential_ids(
+        &self,
+        user_id: i32,
+    ) -> impl Future<Output = Result<Vec<Vec<u8>>, AppError>> + Send;
+
+    /// Retrieves the passkey of a credential ID, `None` if it isn't registered or its
+    /// user was deactivated.
+    fn find(
+        &self,
+        credential_id: &[u8],
+    ) -> impl Future<Output = Result<Option<StoredPasskey>, AppError>> + Send;
+
+    /// Records a login with a passkey and the new value of its signature counter.
+    fn record_use(
+        &self,
+        credential_id: &[u8],
+        sign_count: u32,
+    ) -> impl Future<Output = Result<(), AppError>> + Send;
+
+    /// Removes a passkey of the user, `false` if they have no passkey with this ID.
+    fn delete(&self, user_id: i32, id: Uuid)
+    -> impl Future<Output = Result<bool, AppError>> + Send;
+
+    /// Whether the password logins of the user need a passkey too.
+    fn second_factor(&self, user_id: i32) -> impl Future<Output = Result<bool, AppError>> + Send;
+
+    /// Sets whether the password logins of the user need a passkey too.
+    fn set_second_factor(
+        &self,
+        user_id: i32,
+        enabled: bool,
+    ) -> impl Future<Output = Result<(), AppError>> + Send;
+}
+
+/// `PasskeyRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgPasskeyRepo(pub Db);
+
+impl PasskeyRepository for PgPasskeyRepo {
+    async fn create_challenge(&self, challenge: &NewChallenge<'_>) -> Result<Uuid, AppError> {
+        let id = Uuid::new_v4();
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "WITH expired AS (\
+                     DELETE FROM passkey_challenges WHERE expires_at <= now()) \
+                 INSERT INTO passkey_challenges (id, purpose, user_id, challenge, expires_at) \
+                 VALUES ($1, $2, $3, $4, now() + make_interval(secs => $5))",
+            )
+            .await?;
+        conn.execute(
+            &statement,
+            &[
+                &id,
+                &challenge.purpose.as_str(),
+                &challenge.user_id,
+                &challenge.challenge,
+                &f64::from(challenge.lifetime_secs),
+            ],
+        )
+        .await?;
+        Ok(id)
+    }
+
+    async fn take_challenge(&self, id: Uuid) -> Result<Option<Challenge>, AppError> {
+        // On the primary, and never retried: a challenge is taken once
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "WITH taken AS (\
+                     DELETE FROM passkey_challenges WHERE id = $1 RETURNING *) \
+                 SELECT purpose, user_id, challenge FROM taken WHERE expires_at > now()",
+            )
+            .await?;
+        let row = conn.query_opt(&statement, &[&id]).await?;
+        Ok(row.and_then(|row| {
+            Some(Challenge {
+                purpose: Purpose::parse(row.get("purpose"))?,
+                user_id: row.get("user_id"),
+                challenge: row.get("challenge"),
+            })
+        }))
+    }
+
+    async fn create(
+        &self,
+        user_id: i32,
+        name: &str,
+        passkey: &NewPasskey,
+    ) -> Result<Passkey, AppError> {
+        let conn = self.0.connection().await?;
+        let sql = format!(
+            "INSERT INTO passkeys (public_id, user_id, credential_id, public_key, sign_count, \
+             name) VALUES ($1, $2, $3, $4, $5, $6) RETURNING {}",
+            PASSKEY_COLUMNS
+        );
+        let statement = conn.prepare_cached(&sql).await?;
+        let row = conn
+            .query_one(
+                &statement,
+                &[
+                    &new_public_id(),
+                    &user_id,
+                    &passkey.credential_id,
+                    &passkey.public_key,
+                    &i64::from(passkey.sign_count),
+                    &name,
+                ],
+            )
+            .await?;
+        Ok(Passkey::from(&row))
+    }
+
+    async fn list(&self, user_id: i32) -> Result<Vec<Passkey>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let sql = format!(
+                "SELECT {} FROM passkeys WHERE user_id = $1 ORDER BY id",
+                PASSKEY_COLUMNS
+            );
+            let statement = conn.prepare_cached(&sql).await?;
+            let rows = conn.query(&statement, &[&user_id]).await?;
+            Ok(rows.iter().map(Passkey::from).collect())
+        })
+        .await
+    }
+
+    async fn credential_ids(&self, user_id: i32) -> Result<Vec<Vec<u8>>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached("SELECT credential_id FROM passkeys WHERE user_id = $1 ORDER BY id")
+                .await?;
+            let rows = conn.query(&statement, &[&user_id]).await?;
+            Ok(rows.iter().map(|row| row.get("credential_id")).collect())
+        })
+        .await
+    }
+
+    async fn find(&self, credential_id: &[u8]) -> Result<Option<StoredPasskey>, AppError> {
+        // On the primary: the counter must be the latest one
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "SELECT p.user_id, u.public_id AS user_public_id, p.public_key, p.sign_count \
+                 FROM passkeys p JOIN users u ON u.id = p.user_id \
+                 WHERE p.credential_id = $1 AND u.active AND u.deleted_at IS NULL",
+            )
+            .await?;
+        let row = conn.query_opt(&statement, &[&credential_id]).await?;
+        Ok(row.map(|row| StoredPasskey {
+            user_id: row.get("user_id"),
+            user_public_id: row.get("user_public_id"),
+            public_key: row.get("public_key"),
+            sign_count: u32::try_from(row.get::<_, i64>("sign_count")).unwrap_or(u32::MAX),
+        }))
+    }
+
+    async fn record_use(&self, credential_id: &[u8], sign_count: u32) -> Result<(), AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "UPDATE passkeys SET sign_count = $2, last_used_at = now() \
+                 WHERE credential_id = $1",
+            )
+            .await?;
+        conn.execute(&statement, &[&credential_id, &i64::from(sign_count)])
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, user_id: i32, id: Uuid) -> Result<bool, AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached("DELETE FROM passkeys WHERE public_id = $1 AND user_id = $2")
+            .await?;
+        Ok(conn.execute(&statement, &[&id, &user_id]).await? > 0)
+    }
+
+    async fn second_factor(&self, user_id: i32) -> Result<bool, AppError> {
+        // On the primary: a login right after enabling it must require it
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached("SELECT passkey_second_factor FROM users WHERE id = $1")
+            .await?;
+        let row = conn.query_opt(&statement, &[&user_id]).await?;
+        Ok(row.is_some_and(|row| row.get("passkey_second_factor")))
+    }
+
+    async fn set_second_factor(&self, user_id: i32, enabled: bool) -> Result<(), AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached("UPDATE users SET passkey_second_factor = $2 WHERE id = $1")
+            .await?;
+        conn.execute(&statement, &[&user_id, &enabled]).await?;
+        Ok(())
+    }
+}
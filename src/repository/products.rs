@@ -0,0 +1,386 @@
+//! Products repository.
+
+use std::future::Future;
+
+use bb8_postgres::tokio_postgres::Row;
+use bb8_postgres::tokio_postgres::types::ToSql;
+use futures_util::{Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::audit::{self, Entity};
+use super::filter::{Filter, escape_like};
+use super::ids::new_public_id;
+use super::retry::with_retry;
+use super::{INSERT_BATCH_SIZE, version_mismatch};
+use crate::db::{Db, fetch_in_batches};
+use crate::error::AppError;
+use crate::router::query::Pagination;
+
+/// A stored product.
+#[derive(Serialize, Clone, Debug)]
+pub struct Product {
+    /// Key of the row, internal
+    #[serde(skip)]
+    pub id: i32,
+    /// Public ID, sent as `id` (see `ids`)
+    #[serde(rename = "id")]
+    pub public_id: Uuid,
+    pub name: String,
+    pub price: f64,
+    pub stock: i32,
+    /// Version of the row, sent as the `ETag`
+    #[serde(skip)]
+    pub version: i32,
+}
+
+/// Fields of a product set by clients.
+/// The ID is assigned by the database, so it is not part of them.
+#[derive(Deserialize, Debug)]
+pub struct NewProduct {
+    pub name: String,
+    pub price: f64,
+    pub stock: i32,
+}
+
+/// Aggregates over every product.
+#[derive(Serialize, Debug)]
+pub struct ProductStats {
+    pub total: i64,
+    /// Units in stock of every product
+    pub total_stock: i64,
+    /// Products without stock
+    pub out_of_stock: i64,
+    /// `None` without products
+    pub average_price: Option<f64>,
+    /// Price of every unit in stock
+    pub stock_value: f64,
+}
+
+impl From<&Row> for Product {
+    fn from(row: &Row) -> Self {
+        Product {
+            id: row.get("id"),
+            public_id: row.get("public_id"),
+            name: row.get("name"),
+            price: row.get("price"),
+            stock: row.get("stock"),
+            version: row.get("version"),
+        }
+    }
+}
+
+/// Operations on the `products` table.
+///
+/// Methods returning `Option` or `bool` report a missing product that way,
+/// so the caller decides which error (if any) it maps to.
+pub trait ProductRepository {
+    /// Counts the products matching a filter.
+    fn count(&self, filter: &Filter) -> impl Future<Output = Result<i64, AppError>> + Send;
+
+    /// Retrieves a page of the products matching a filter, sorted as requested.
+    fn list(
+        &self,
+        page: &Pagination,
+        filter: &Filter,
+    ) -> impl Future<Output = Result<Vec<Product>, AppError>> + Send;
+
+    /// Streams every product matching a filter in the requested order, batch by
+    /// batch (`limit` and `offset` are ignored).
+    fn stream_all(
+        &self,
+        page: &Pagination,
+        filter: &Filter,
+    ) -> impl Stream<Item = Result<Vec<Product>, AppError>> + Send + 'static + use<Self>;
+
+    /// Retrieves a product by ID.
+    fn find_by_id(&self, id: i32)
+    -> impl Future<Output = Result<Option<Product>, AppError>> + Send;
+
+    /// Retrieves the products whose name contains some of `terms` (case insensitive),
+    /// with the number of terms they contain, the products containing the most first.
+    fn search_by_name(
+        &self,
+        terms: &[String],
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<(Product, i64)>, AppError>> + Send;
+
+    /// Inserts a product, returning it with its new ID.
+    fn create(
+        &self,
+        product: &NewProduct,
+    ) -> impl Future<Output = Result<Product, AppError>> + Send;
+
+    /// Inserts products in a single transaction, returning them with their new IDs in
+    /// the same order.
+    fn create_many(
+        &self,
+        products: &[NewProduct],
+    ) -> impl Future<Output = Result<Vec<Product>, AppError>> + Send;
+
+    /// Replaces every field of a product, returning the updated product.
+    ///
+    /// Only applies to a product with one of `versions` (any version when `None`), and
+    /// fails with `AppError::PreconditionFailed` otherwise; the same goes for `delete`.
+    fn update(
+        &self,
+        id: i32,
+        product: &NewProduct,
+        versions: Option<&[i32]>,
+    ) -> impl Future<Output = Result<Option<Product>, AppError>> + Send;
+
+    /// Deletes a product, returning the deleted product.
+    fn delete(
+        &self,
+        id: i32,
+        versions: Option<&[i32]>,
+    ) -> impl Future<Output = Result<Option<Product>, AppError>> + Send;
+
+    /// Computes the aggregates over every product.
+    fn stats(&self) -> impl Future<Output = Result<ProductStats, AppError>> + Send;
+}
+
+/// `ProductRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgProductRepo(pub Db);
+
+impl ProductRepository for PgProductRepo {
+    async fn count(&self, filter: &Filter) -> Result<i64, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let sql = format!("SELECT COUNT(*) FROM products {}", filter.where_clause(1));
+            let statement = conn.prepare_cached(&sql).await?;
+            let row = conn.query_one(&statement, &filter.params()).await?;
+            Ok(row.get(0))
+        })
+        .await
+    }
+
+    async fn list(&self, page: &Pagination, filter: &Filter) -> Result<Vec<Product>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let sql = format!(
+                "SELECT id, public_id, name, price, stock, version FROM products {} {} LIMIT $1 OFFSET $2",
+                filter.where_clause(3),
+                page.order_by_clause()
+            );
+            // One statement per sort order and set of filters
+            let statement = conn.prepare_cached(&sql).await?;
+            let mut params: Vec<&(dyn ToSql + Sync)> = vec![&page.limit, &page.offset];
+            params.extend(filter.params());
+            let rows = conn.query(&statement, &params).await?;
+            Ok(rows.iter().map(Product::from).collect())
+        })
+        .await
+    }
+
+    fn stream_all(
+        &self,
+        page: &Pagination,
+        filter: &Filter,
+    ) -> impl Stream<Item = Result<Vec<Product>, AppError>> + Send + 'static + use<> {
+        let sql = format!(
+            "SELECT id, public_id, name, price, stock, version FROM products {} {}",
+            filter.where_clause(1),
+            page.order_by_clause()
+        );
+        fetch_in_batches(sql, filter.owned_params())
+            .map_ok(|rows| rows.iter().map(Product::from).collect())
+    }
+
+    async fn find_by_id(&self, id: i32) -> Result<Option<Product>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT id, public_id, name, price, stock, version FROM products WHERE id = $1",
+                )
+                .await?;
+            let row = conn.query_opt(&statement, &[&id]).await?;
+            Ok(row.as_ref().map(Product::from))
+        })
+        .await
+    }
+
+    async fn search_by_name(
+        &self,
+        terms: &[String],
+        limit: i64,
+    ) -> Result<Vec<(Product, i64)>, AppError> {
+        let patterns = terms
+            .iter()
+            .map(|term| format!("%{}%", escape_like(term)))
+            .collect::<Vec<_>>();
+        let patterns = &patterns;
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT * FROM (\
+                     SELECT id, public_id, name, price, stock, version, \
+                     (SELECT COUNT(*) FROM unnest($1::text[]) AS terms (pattern) \
+                     WHERE name ILIKE pattern) AS matches FROM products) AS found \
+                     WHERE matches > 0 ORDER BY matches DESC, length(name), id LIMIT $2",
+                )
+                .await?;
+            let rows = conn.query(&statement, &[patterns, &limit]).await?;
+            Ok(rows
+                .iter()
+                .map(|row| (Product::from(row), row.get("matches")))
+                .collect())
+        })
+        .await
+    }
+
+    async fn create(&self, product: &NewProduct) -> Result<Product, AppError> {
+        self.0
+            .transaction(async |tx| {
+                let statement = tx
+                    .prepare_cached(
+                        "INSERT INTO products (public_id, name, price, stock) \
+                     VALUES ($1, $2, $3, $4) RETURNING id, public_id, name, price, stock, version",
+                    )
+                    .await?;
+                let row = tx
+                    .query_one(
+                        &statement,
+                        &[
+                            &new_public_id(),
+                            &product.name,
+                            &product.price,
+                            &product.stock,
+                        ],
+                    )
+                    .await?;
+                let product = Product::from(&row);
+                audit::record(tx, Entity::Product, product.id, None, Some(&product)).await?;
+                Ok(product)
+            })
+            .await
+    }
+
+    async fn create_many(&self, products: &[NewProduct]) -> Result<Vec<Product>, AppError> {
+        // ORDER BY gives the products in the order of the arrays
+        self.0
+            .transaction(async |tx| {
+                let statement = tx
+                    .prepare_cached(
+                        "INSERT INTO products (public_id, name, price, stock) \
+                     SELECT new.public_id, new.name, new.price, new.stock \
+                     FROM UNNEST($1::uuid[], $2::text[], $3::float8[], $4::int[]) \
+                     WITH ORDINALITY AS new(public_id, name, price, stock, position) \
+                     ORDER BY new.position RETURNING id, public_id, name, price, stock, version",
+                    )
+                    .await?;
+                let mut created = Vec::with_capacity(products.len());
+                for batch in products.chunks(INSERT_BATCH_SIZE) {
+                    let public_ids: Vec<Uuid> = batch.iter().map(|_| new_public_id()).collect();
+                    let names: Vec<&str> =
+                        batch.iter().map(|product| product.name.as_str()).collect();
+                    let prices: Vec<f64> = batch.iter().map(|product| product.price).collect();
+                    let stocks: Vec<i32> = batch.iter().map(|product| product.stock).collect();
+                    let rows = tx
+                        .query(&statement, &[&public_ids, &names, &prices, &stocks])
+                        .await?;
+                    let batch: Vec<Product> = rows.iter().map(Product::from).collect();
+                    let ids: Vec<i32> = batch.iter().map(|product| product.id).collect();
+                    audit::record_inserts(tx, Entity::Product, &ids, &batch).await?;
+                    created.extend(batch);
+                }
+                Ok(created)
+            })
+            .await
+    }
+
+    async fn update(
+        &self,
+        id: i32,
+        product: &NewProduct,
+        versions: Option<&[i32]>,
+    ) -> Result<Option<Product>, AppError> {
+        // The subquery locks the row and reads the values it had before the update,
+        // which only happens if the row still has one of the versions
+        self.0.transaction(async |tx| {
+            let statement = tx
+                .prepare_cached(
+                    "UPDATE products SET name = $1, price = $2, stock = $3, \
+                     version = products.version + 1 \
+                     FROM (SELECT name, price, stock, version FROM products \
+                     WHERE id = $4 FOR UPDATE) AS previous \
+                     WHERE products.id = $4 \
+                     AND ($5::int[] IS NULL OR previous.version = ANY($5)) \
+                     RETURNING products.id, products.public_id, products.name, products.price, products.stock, \
+                     products.version, previous.name AS previous_name, \
+                     previous.price AS previous_price, previous.stock AS previous_stock, \
+                     previous.version AS previous_version",
+                )
+                .await?;
+            let Some(row) = tx
+                .query_opt(
+                    &statement,
+                    &[&product.name, &product.price, &product.stock, &id, &versions],
+                )
+                .await?
+            else {
+                return version_mismatch(tx, "products", id).await;
+            };
+            let previous = Product {
+                id,
+                public_id: row.get("public_id"),
+                name: row.get("previous_name"),
+                price: row.get("previous_price"),
+                stock: row.get("previous_stock"),
+                version: row.get("previous_version"),
+            };
+            let product = Product::from(&row);
+            audit::record(tx, Entity::Product, id, Some(&previous), Some(&product)).await?;
+            Ok(Some(product))
+        })
+        .await
+    }
+
+    async fn delete(&self, id: i32, versions: Option<&[i32]>) -> Result<Option<Product>, AppError> {
+        self.0
+            .transaction(async |tx| {
+                let statement = tx
+                    .prepare_cached(
+                        "DELETE FROM products \
+                     WHERE id = $1 AND ($2::int[] IS NULL OR version = ANY($2)) \
+                     RETURNING id, public_id, name, price, stock, version",
+                    )
+                    .await?;
+                let Some(row) = tx.query_opt(&statement, &[&id, &versions]).await? else {
+                    return version_mismatch(tx, "products", id).await;
+                };
+                let product = Product::from(&row);
+                audit::record(tx, Entity::Product, id, Some(&product), None).await?;
+                Ok(Some(product))
+            })
+            .await
+    }
+
+    async fn stats(&self) -> Result<ProductStats, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT COUNT(*), \
+                         COALESCE(SUM(stock), 0)::BIGINT, \
+                         COUNT(*) FILTER (WHERE stock = 0), \
+                         AVG(price), \
+                         COALESCE(SUM(price * stock), 0) \
+                     FROM products",
+                )
+                .await?;
+            let row = conn.query_one(&statement, &[]).await?;
+            Ok(ProductStats {
+                total: row.get(0),
+                total_stock: row.get(1),
+                out_of_stock: row.get(2),
+                average_price: row.get(3),
+                stock_value: row.get(4),
+            })
+        })
+        .await
+    }
+}
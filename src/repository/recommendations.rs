@@ -0,0 +1,156 @@
+//! Product recommendations repository.
+//!
+//! The recommendations of a user are computed from the orders, in SQL, and cached in
+//! the `user_recommendations` table (see `recommendations`).
+
+use std::future::Future;
+
+use super::products::Product;
+use super::retry::with_retry;
+use crate::db::Db;
+use crate::error::AppError;
+
+/// Computes the `$2` recommendations of the user `$1` and caches them, if the user
+/// exists.
+///
+/// Co-purchases first: the products bought by the users who bought a product of the
+/// user, scored by the number of those users. Then the popular products: the units
+/// ordered in the last `$3` days. Products the user bought, or without stock, are left
+/// out.
+const REFRESH: &str = "WITH bought AS (\
+         SELECT DISTINCT product_id FROM orders WHERE user_id = $1), \
+     neighbors AS (\
+         SELECT DISTINCT user_id FROM orders \
+         WHERE product_id IN (SELECT product_id FROM bought) AND user_id <> $1), \
+     candidates AS (\
+         SELECT product_id, 'co_purchase' AS reason, COUNT(DISTINCT user_id)::float8 AS score \
+         FROM orders WHERE user_id IN (SELECT user_id FROM neighbors) GROUP BY product_id \
+         UNION ALL \
+         SELECT product_id, 'popular', SUM(quantity)::float8 FROM orders \
+         WHERE created_at > now() - make_interval(days => $3) GROUP BY product_id), \
+     best AS (\
+         SELECT DISTINCT ON (c.product_id) c.product_id, c.reason, c.score \
+         FROM candidates c JOIN products p ON p.id = c.product_id \
+         WHERE p.stock > 0 AND c.product_id NOT IN (SELECT product_id FROM bought) \
+         ORDER BY c.product_id, c.reason = 'popular', c.score DESC), \
+     top AS (\
+         SELECT * FROM best ORDER BY reason = 'popular', score DESC, product_id LIMIT $2) \
+     INSERT INTO user_recommendations (user_id, items) \
+     SELECT $1, COALESCE(jsonb_agg(jsonb_build_object(\
+         'product_id', product_id, 'reason', reason, 'score', score) \
+         ORDER BY reason = 'popular', score DESC, product_id), '[]'::jsonb) \
+     FROM top HAVING EXISTS (SELECT 1 FROM users WHERE id = $1 AND deleted_at IS NULL) \
+     ON CONFLICT (user_id) DO UPDATE SET items = EXCLUDED.items, computed_at = now()";
+
+/// A recommended product.
+#[derive(Debug)]
+pub struct Recommendation {
+    pub product: Product,
+    /// `co_purchase` or `popular`
+    pub reason: String,
+    /// Buyers of the products of the user who bought it (`co_purchase`), or units
+    /// ordered lately (`popular`)
+    pub score: f64,
+}
+
+/// The cached recommendations of a user.
+#[derive(Debug)]
+pub struct Recommendations {
+    /// The best first, without the products deleted or sold out since
+    pub items: Vec<Recommendation>,
+    pub computed_at: String,
+}
+
+/// Operations on the `user_recommendations` table.
+pub trait RecommendationRepository {
+    /// Computes the `limit` best recommendations of a user and caches them, replacing
+    /// the previous ones; nothing is cached if the user doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `popular_days` - Days of orders counted to find the popular products
+    fn refresh(
+        &self,
+        user_id: i32,
+        limit: i64,
+        popular_days: i32,
+    ) -> impl Future<Output = Result<(), AppError>> + Send;
+
+    /// Retrieves the cached recommendations of a user, `None` if none were computed.
+    fn cached(
+        &self,
+        user_id: i32,
+    ) -> impl Future<Output = Result<Option<Recommendations>, AppError>> + Send;
+
+    /// Retrieves the users with cached recommendations, by key, after `after`.
+    fn users(
+        &self,
+        after: i32,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<i32>, AppError>> + Send;
+}
+
+/// `RecommendationRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgRecommendationRepo(pub Db);
+
+impl RecommendationRepository for PgRecommendationRepo {
+    async fn refresh(&self, user_id: i32, limit: i64, popular_days: i32) -> Result<(), AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn.prepare_cached(REFRESH).await?;
+        conn.execute(&statement, &[&user_id, &limit, &popular_days])
+            .await?;
+        Ok(())
+    }
+
+    async fn cached(&self, user_id: i32) -> Result<Option<Recommendations>, AppError> {
+        // On the primary: they may have been computed by this request
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "SELECT to_char(u.computed_at AT TIME ZONE 'UTC', \
+                 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS computed_at, found.* \
+                 FROM user_recommendations u \
+                 LEFT JOIN LATERAL (\
+                     SELECT p.id, p.public_id, p.name, p.price, p.stock, p.version, \
+                     r.item->>'reason' AS reason, (r.item->>'score')::float8 AS score, r.rank \
+                     FROM jsonb_array_elements(u.items) WITH ORDINALITY AS r (item, rank) \
+                     JOIN products p ON p.id = (r.item->>'product_id')::integer \
+                     WHERE p.stock > 0) AS found \
+                 ON true WHERE u.user_id = $1 ORDER BY found.rank",
+            )
+            .await?;
+        let rows = conn.query(&statement, &[&user_id]).await?;
+        let Some(first) = rows.first() else {
+            return Ok(None);
+        };
+        Ok(Some(Recommendations {
+            computed_at: first.get("computed_at"),
+            // A single row without product when none is left
+            items: rows
+                .iter()
+                .filter(|row| row.get::<_, Option<i32>>("id").is_some())
+                .map(|row| Recommendation {
+                    product: Product::from(row),
+                    reason: row.get("reason"),
+                    score: row.get("score"),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn users(&self, after: i32, limit: i64) -> Result<Vec<i32>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT user_id FROM user_recommendations WHERE user_id > $1 \
+                     ORDER BY user_id LIMIT $2",
+                )
+                .await?;
+            let rows = conn.query(&statement, &[&after, &limit]).await?;
+            Ok(rows.iter().map(|row| row.get(0)).collect())
+        })
+        .await
+    }
+}
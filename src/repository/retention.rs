@@ -0,0 +1,197 @@
+//! Retention repository.
+//!
+//! The rows the retention rules remove once they're older than their limit (see the
+//! `retention` module): each [`Target`] names a table and the timestamp its rows are
+//! aged by. Rows go in batches, one statement each, so none holds its locks for long.
+
+use std::future::Future;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::retry::with_retry;
+use crate::db::Db;
+use crate::error::AppError;
+
+/// Name given to the anonymized users
+pub const ANONYMIZED_NAME: &str = "Anonymized user";
+
+/// Users not changed and not logged in since the limit, and not anonymized yet
+const INACTIVE_USERS: &str = "FROM users u LEFT JOIN user_logins l ON l.user_id = u.id \
+     WHERE GREATEST(u.updated_at, l.last_login_at) < now() - make_interval(days => $1) \
+     AND (u.name <> $2 OR u.email IS NOT NULL OR u.avatar_path IS NOT NULL)";
+
+/// Rows a retention rule applies to.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    /// Records of the audit log, by creation
+    AuditLog,
+    /// Delivery attempts of the webhooks, by creation
+    WebhookDeliveries,
+    /// Exposures to the experiments, by first exposure
+    ExperimentExposures,
+    /// Carts, anonymous or not, by last change; their items go with them
+    Carts,
+    /// Users neither changed nor logged in since, anonymized instead of deleted
+    InactiveUsers,
+}
+
+impl Target {
+    /// What the rule does to the rows, `delete` or `anonymize`.
+    pub fn action(self) -> &'static str {
+        match self {
+            Target::InactiveUsers => "anonymize",
+            _ => "delete",
+        }
+    }
+
+    /// Table and timestamp column of the deleted rows, `None` for the users.
+    fn aged_by(self) -> Option<(&'static str, &'static str)> {
+        match self {
+            Target::AuditLog => Some(("audit_log", "created_at")),
+            Target::WebhookDeliveries => Some(("webhook_deliveries", "created_at")),
+            Target::ExperimentExposures => Some(("experiment_exposures", "first_seen")),
+            Target::Carts => Some(("carts", "updated_at")),
+            Target::InactiveUsers => None,
+        }
+    }
+}
+
+/// A user anonymized by [`RetentionRepository::anonymize_batch`].
+#[derive(Debug)]
+pub struct AnonymizedUser {
+    pub id: i32,
+    pub public_id: Uuid,
+    /// Location of the avatar it had, to remove from the file storage
+    pub avatar_path: Option<String>,
+}
+
+/// Operations removing the rows older than a retention limit.
+pub trait RetentionRepository {
+    /// Counts the rows of `target` older than `days` days, which a rule would remove.
+    fn count(
+        &self,
+        target: Target,
+        days: i32,
+    ) -> impl Future<Output = Result<i64, AppError>> + Send;
+
+    /// Deletes at most `limit` rows of `target` older than `days` days.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64, AppError>` - The rows deleted; fewer than `limit` once none is
+    ///   left. `AppError::Internal` for the users, which are anonymized instead.
+    fn delete_batch(
+        &self,
+        target: Target,
+        days: i32,
+        limit: i64,
+    ) -> impl Future<Output = Result<u64, AppError>> + Send;
+
+    /// Anonymizes at most `limit` users inactive for `days` days: their name becomes
+    /// [`ANONYMIZED_NAME`], their email, password and avatar are removed, so they can't
+    /// log in any more. The name and email in their audit records are replaced the
+    /// same way, in the same transaction.
+    fn anonymize_batch(
+        &self,
+        days: i32,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<AnonymizedUser>, AppError>> + Send;
+}
+
+/// `RetentionRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgRetentionRepo(pub Db);
+
+impl RetentionRepository for PgRetentionRepo {
+    async fn count(&self, target: Target, days: i32) -> Result<i64, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let row = match target.aged_by() {
+                Some((table, column)) => {
+                    let sql = format!(
+                        "SELECT count(*) FROM {} WHERE {} < now() - make_interval(days => $1)",
+                        table, column
+                    );
+                    let statement = conn.prepare_cached(&sql).await?;
+                    conn.query_one(&statement, &[&days]).await?
+                }
+                None => {
+                    let sql = format!("SELECT count(*) {}", INACTIVE_USERS);
+                    let statement = conn.prepare_cached(&sql).await?;
+                    conn.query_one(&statement, &[&days, &ANONYMIZED_NAME])
+                        .await?
+                }
+            };
+            Ok(row.get(0))
+        })
+        .await
+    }
+
+    async fn delete_batch(&self, target: Target, days: i32, limit: i64) -> Result<u64, AppError> {
+        let Some((table, column)) = target.aged_by() else {
+            return Err(AppError::Internal(
+                "Users are anonymized, not deleted".to_string(),
+            ));
+        };
+        let conn = self.0.connection().await?;
+        // ctid, as the exposures have no single-column key, with the partition it's a
+        // position in (see `partitions`)
+        let sql = format!(
+            "DELETE FROM {table} WHERE (tableoid, ctid) IN (SELECT tableoid, ctid FROM {table} \
+             WHERE {column} < now() - make_interval(days => $1) LIMIT $2)"
+        );
+        let statement = conn.prepare_cached(&sql).await?;
+        Ok(conn.execute(&statement, &[&days, &limit]).await?)
+    }
+
+    async fn anonymize_batch(
+        &self,
+        days: i32,
+        limit: i64,
+    ) -> Result<Vec<AnonymizedUser>, AppError> {
+        self.0
+            .transaction(async |tx| {
+                let sql = format!(
+                    "WITH inactive AS (SELECT u.id, u.avatar_path {} \
+                 ORDER BY u.id LIMIT $3 FOR UPDATE OF u SKIP LOCKED) \
+                 UPDATE users SET name = $2, email = NULL, password_hash = NULL, \
+                 avatar_path = NULL, passkey_second_factor = false, version = version + 1 \
+                 FROM inactive WHERE users.id = inactive.id \
+                 RETURNING users.id, users.public_id, inactive.avatar_path",
+                    INACTIVE_USERS
+                );
+                let rows = tx.query(&sql, &[&days, &ANONYMIZED_NAME, &limit]).await?;
+                let users = rows
+                    .iter()
+                    .map(|row| AnonymizedUser {
+                        id: row.get("id"),
+                        public_id: row.get("public_id"),
+                        avatar_path: row.get("avatar_path"),
+                    })
+                    .collect::<Vec<_>>();
+
+                // The old and new values of the records, whichever they have
+                let ids = users.iter().map(|user| user.id).collect::<Vec<_>>();
+                tx.execute(
+                    "UPDATE audit_log SET changes = ( \
+                     SELECT jsonb_object_agg(field, CASE WHEN field IN ('name', 'email') THEN \
+                         (SELECT jsonb_object_agg(side, CASE field \
+                              WHEN 'name' THEN to_jsonb($2::text) ELSE 'null'::jsonb END) \
+                          FROM jsonb_object_keys(change) AS side) \
+                     ELSE change END) \
+                     FROM jsonb_each(changes) AS c(field, change)) \
+                 WHERE entity = 'user' AND entity_id = ANY($1) \
+                 AND changes ?| ARRAY['name', 'email']",
+                    &[&ids, &ANONYMIZED_NAME],
+                )
+                .await?;
+                // Their authenticators would still log in, and tell who they were
+                tx.execute("DELETE FROM passkeys WHERE user_id = ANY($1)", &[&ids])
+                    .await?;
+                Ok(users)
+            })
+            .await
+    }
+}
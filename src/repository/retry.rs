@@ -0,0 +1,97 @@
+//! Retry of transient database errors.
+//!
+//! Only wrap operations that are safe to run twice (read-only queries): a connection
+//! reset can happen after PostgreSQL has already applied a write.
+//!
+//! The attempts stop at the deadline of the `Db` they run on (see `db::deadline`).
+
+use std::error::Error;
+use std::future::Future;
+use std::time::Duration;
+
+use bb8_postgres::tokio_postgres::error::SqlState;
+use tracing::warn;
+
+use crate::db::Db;
+use crate::error::AppError;
+use crate::metrics;
+
+/// Maximum number of times an operation runs, including the first attempt
+const MAX_ATTEMPTS: u32 = 3;
+/// Delay before the first retry, doubled on every following retry
+const BASE_DELAY: Duration = Duration::from_millis(50);
+
+/// Runs `op`, retrying it with exponential backoff and jitter while it fails
+/// with a transient error, up to `MAX_ATTEMPTS` times.
+///
+/// # Arguments
+///
+/// * `db` - The database `op` queries, whose deadline stops the attempts
+/// * `op` - Builds the future of a new attempt; must be retry-safe
+///
+/// # Returns
+///
+/// * `Result<T, AppError>` - The first success, or the last error
+pub async fn with_retry<T, F, Fut>(db: Db, mut op: F) -> Result<T, AppError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, AppError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match db.before_deadline(op()).await.and_then(|result| result) {
+            Err(e) if attempt < MAX_ATTEMPTS && is_transient(&e) => {
+                let delay = backoff(attempt);
+                // No time left for another attempt
+                if db.remaining_time().is_some_and(|left| left <= delay) {
+                    return Err(e);
+                }
+                warn!(
+                    "Transient database error (attempt {}/{}), retrying in {:?}: {}",
+                    attempt, MAX_ATTEMPTS, delay, e
+                );
+                metrics::observe_db_retry();
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether an error is likely to go away if the operation is retried:
+/// serialization failures, deadlocks and lost/refused connections.
+///
+/// Pool errors are not retried, the pool has already waited `connection_timeout`.
+fn is_transient(err: &AppError) -> bool {
+    let AppError::Db(e) = err else {
+        return false;
+    };
+
+    if e.is_closed() {
+        return true;
+    }
+
+    match e.code() {
+        Some(code) => {
+            *code == SqlState::T_R_SERIALIZATION_FAILURE
+                || *code == SqlState::T_R_DEADLOCK_DETECTED
+                || *code == SqlState::ADMIN_SHUTDOWN
+                || *code == SqlState::CANNOT_CONNECT_NOW
+                // Class 08: connection exceptions
+                || code.code().starts_with("08")
+        }
+        // Errors raised on the client side are only transient if they come from the socket
+        None => e
+            .source()
+            .is_some_and(|source| source.is::<std::io::Error>()),
+    }
+}
+
+/// Delay before retry number `attempt`: `BASE_DELAY * 2^(attempt - 1)`
+/// plus up to 50% of random jitter, so concurrent retries don't hit the database together.
+fn backoff(attempt: u32) -> Duration {
+    let delay = BASE_DELAY * 2u32.pow(attempt - 1);
+    let jitter = rand::random_range(0..=delay.as_millis() as u64 / 2);
+    delay + Duration::from_millis(jitter)
+}
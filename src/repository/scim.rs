@@ -0,0 +1,285 @@
+//! SCIM provisioning repository.
+//!
+//! The accounts, the users with an email, as the identity systems provisioning them see
+//! them (see `scim`), with the `external_id` and `active` columns of the
+//! `V26__add_scim_columns` migration.
+
+use std::future::Future;
+
+use bb8_postgres::tokio_postgres::Row;
+use bb8_postgres::tokio_postgres::types::ToSql;
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+use super::audit::{self, Entity};
+use super::filter::Filter;
+use super::ids::new_public_id;
+use super::retry::with_retry;
+use super::version_mismatch;
+use crate::db::Db;
+use crate::error::AppError;
+
+/// Columns of a `ScimUser`
+const COLUMNS: &str = "users.id, users.public_id, users.name, users.age, users.email, \
+     users.external_id, users.active, users.version, \
+     to_char(users.updated_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') \
+     AS updated_at";
+
+/// An account, as provisioned.
+#[derive(Debug, Clone)]
+pub struct ScimUser {
+    pub id: i32,
+    pub public_id: Uuid,
+    pub name: String,
+    pub age: i32,
+    pub email: String,
+    /// ID of the user in the identity system
+    pub external_id: Option<String>,
+    /// Inactive accounts can't log in
+    pub active: bool,
+    pub version: i32,
+    /// Timestamp in RFC 3339, UTC
+    pub updated_at: String,
+}
+
+/// Fields of an account written by the identity system.
+#[derive(Debug)]
+pub struct ScimAccount {
+    pub name: String,
+    pub age: i32,
+    /// Normalized email
+    pub email: String,
+    pub external_id: Option<String>,
+    pub active: bool,
+    /// `None` keeps the current password, none for a new account
+    pub password_hash: Option<String>,
+}
+
+impl From<&Row> for ScimUser {
+    fn from(row: &Row) -> Self {
+        ScimUser {
+            id: row.get("id"),
+            public_id: row.get("public_id"),
+            name: row.get("name"),
+            age: row.get("age"),
+            email: row.get("email"),
+            external_id: row.get("external_id"),
+            active: row.get("active"),
+            version: row.get("version"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+}
+
+/// Fields of an account kept in the audit log.
+fn audited(public_id: Uuid, account: &ScimAccount) -> Value {
+    json!({
+        "id": public_id,
+        "name": account.name,
+        "age": account.age,
+        "email": account.email,
+        "external_id": account.external_id,
+        "active": account.active,
+    })
+}
+
+/// Condition of the accounts: the users with an email, not soft-deleted
+const ACCOUNTS: &str = "users.email IS NOT NULL AND users.deleted_at IS NULL";
+
+/// `WHERE` clause of the accounts matching `filter`.
+fn where_accounts(filter: &Filter, first: usize) -> String {
+    match filter.where_clause(first) {
+        clause if clause.is_empty() => format!("WHERE {}", ACCOUNTS),
+        clause => format!("{} AND {}", clause, ACCOUNTS),
+    }
+}
+
+/// Operations on the accounts of the `users` table.
+///
+/// Users without an email aren't accounts, and are never returned, nor the
+/// soft-deleted ones.
+pub trait ScimRepository {
+    /// Counts the accounts matching a filter.
+    fn count(&self, filter: &Filter) -> impl Future<Output = Result<i64, AppError>> + Send;
+
+    /// Retrieves a page of the accounts matching a filter, the oldest first.
+    fn list(
+        &self,
+        filter: &Filter,
+        offset: i64,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<ScimUser>, AppError>> + Send;
+
+    /// Retrieves an account by public ID.
+    fn find(
+        &self,
+        public_id: Uuid,
+    ) -> impl Future<Output = Result<Option<ScimUser>, AppError>> + Send;
+
+    /// Inserts an account. A duplicated email fails with the `UNIQUE_VIOLATION`
+    /// database error.
+    fn create(
+        &self,
+        account: &ScimAccount,
+    ) -> impl Future<Output = Result<ScimUser, AppError>> + Send;
+
+    /// Replaces the fields of an account, returning the updated account.
+    ///
+    /// Only applies to an account with one of `versions` (any version when `None`), and
+    /// fails with `AppError::PreconditionFailed` otherwise. A duplicated email fails
+    /// with the `UNIQUE_VIOLATION` database error.
+    fn replace(
+        &self,
+        id: i32,
+        account: &ScimAccount,
+        versions: Option<&[i32]>,
+    ) -> impl Future<Output = Result<Option<ScimUser>, AppError>> + Send;
+}
+
+/// `ScimRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgScimRepo(pub Db);
+
+impl ScimRepository for PgScimRepo {
+    async fn count(&self, filter: &Filter) -> Result<i64, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let sql = format!("SELECT COUNT(*) FROM users {}", where_accounts(filter, 1));
+            let statement = conn.prepare_cached(&sql).await?;
+            let row = conn.query_one(&statement, &filter.params()).await?;
+            Ok(row.get(0))
+        })
+        .await
+    }
+
+    async fn list(
+        &self,
+        filter: &Filter,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<ScimUser>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let sql = format!(
+                "SELECT {} FROM users {} ORDER BY users.id LIMIT $1 OFFSET $2",
+                COLUMNS,
+                where_accounts(filter, 3)
+            );
+            let statement = conn.prepare_cached(&sql).await?;
+            let mut params: Vec<&(dyn ToSql + Sync)> = vec![&limit, &offset];
+            params.extend(filter.params());
+            let rows = conn.query(&statement, &params).await?;
+            Ok(rows.iter().map(ScimUser::from).collect())
+        })
+        .await
+    }
+
+    async fn find(&self, public_id: Uuid) -> Result<Option<ScimUser>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let sql = format!(
+                "SELECT {} FROM users WHERE {} AND users.public_id = $1",
+                COLUMNS, ACCOUNTS
+            );
+            let statement = conn.prepare_cached(&sql).await?;
+            let row = conn.query_opt(&statement, &[&public_id]).await?;
+            Ok(row.as_ref().map(ScimUser::from))
+        })
+        .await
+    }
+
+    async fn create(&self, account: &ScimAccount) -> Result<ScimUser, AppError> {
+        let public_id = new_public_id();
+        self.0
+            .transaction(async |tx| {
+                let sql = format!(
+                    "INSERT INTO users (public_id, name, age, email, external_id, active, \
+                 password_hash) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING {}",
+                    COLUMNS
+                );
+                let statement = tx.prepare_cached(&sql).await?;
+                let row = tx
+                    .query_one(
+                        &statement,
+                        &[
+                            &public_id,
+                            &account.name,
+                            &account.age,
+                            &account.email,
+                            &account.external_id,
+                            &account.active,
+                            &account.password_hash,
+                        ],
+                    )
+                    .await?;
+                let user = ScimUser::from(&row);
+                let fields = audited(public_id, account);
+                audit::record(tx, Entity::User, user.id, None, Some(&fields)).await?;
+                Ok(user)
+            })
+            .await
+    }
+
+    async fn replace(
+        &self,
+        id: i32,
+        account: &ScimAccount,
+        versions: Option<&[i32]>,
+    ) -> Result<Option<ScimUser>, AppError> {
+        self.0
+            .transaction(async |tx| {
+                let sql = format!(
+                    "UPDATE users SET name = $1, age = $2, email = $3, external_id = $4, \
+                 active = $5, password_hash = COALESCE($6, users.password_hash), \
+                 version = users.version + 1 \
+                 FROM (SELECT name, age, email, external_id, active, version FROM users \
+                       WHERE id = $7 AND email IS NOT NULL AND deleted_at IS NULL \
+                       FOR UPDATE) AS previous \
+                 WHERE users.id = $7 AND ($8::int[] IS NULL OR previous.version = ANY($8)) \
+                 RETURNING {}, previous.name AS previous_name, previous.age AS previous_age, \
+                 previous.email AS previous_email, \
+                 previous.external_id AS previous_external_id, \
+                 previous.active AS previous_active",
+                    COLUMNS
+                );
+                let statement = tx.prepare_cached(&sql).await?;
+                let row = tx
+                    .query_opt(
+                        &statement,
+                        &[
+                            &account.name,
+                            &account.age,
+                            &account.email,
+                            &account.external_id,
+                            &account.active,
+                            &account.password_hash,
+                            &id,
+                            &versions,
+                        ],
+                    )
+                    .await?;
+                let Some(row) = row else {
+                    return version_mismatch(tx, "users", id).await;
+                };
+                let user = ScimUser::from(&row);
+                let previous = ScimAccount {
+                    name: row.get("previous_name"),
+                    age: row.get("previous_age"),
+                    email: row.get("previous_email"),
+                    external_id: row.get("previous_external_id"),
+                    active: row.get("previous_active"),
+                    password_hash: None,
+                };
+                audit::record(
+                    tx,
+                    Entity::User,
+                    id,
+                    Some(&audited(user.public_id, &previous)),
+                    Some(&audited(user.public_id, account)),
+                )
+                .await?;
+                Ok(Some(user))
+            })
+            .await
+    }
+}
@@ -0,0 +1,103 @@
+//! Search terms repository.
+//!
+//! The words of the product and user names, with the number of names having each,
+//! kept up to date by triggers (see the `V24__create_search_terms` migration).
+
+use std::future::Future;
+
+use super::filter::escape_like;
+use super::retry::with_retry;
+use crate::db::Db;
+use crate::error::AppError;
+
+/// Operations on the `search_terms` table.
+pub trait SearchTermRepository {
+    /// Retrieves the terms starting with `prefix`, at most `limit`, with their
+    /// frequency, the most frequent first.
+    fn complete(
+        &self,
+        prefix: &str,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<(String, i32)>, AppError>> + Send;
+
+    /// Retrieves which of `words` are terms.
+    fn known(&self, words: &[String])
+    -> impl Future<Output = Result<Vec<String>, AppError>> + Send;
+
+    /// Retrieves the terms whose trigrams are similar to the ones of `word`, with their
+    /// similarity, the most similar first and then the most frequent.
+    ///
+    /// # Arguments
+    ///
+    /// * `min_similarity` - The least similarity of the terms, from 0 to 1: the shared
+    ///   trigrams over all the trigrams of both words, like pg_trgm's `similarity`
+    fn similar(
+        &self,
+        word: &str,
+        min_similarity: f64,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<(String, f64)>, AppError>> + Send;
+}
+
+/// `SearchTermRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgSearchTermRepo(pub Db);
+
+impl SearchTermRepository for PgSearchTermRepo {
+    async fn complete(&self, prefix: &str, limit: i64) -> Result<Vec<(String, i32)>, AppError> {
+        let pattern = format!("{}%", escape_like(prefix));
+        with_retry(self.0, || async {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT term, frequency FROM search_terms WHERE term LIKE $1 \
+                     ORDER BY frequency DESC, term LIMIT $2",
+                )
+                .await?;
+            let rows = conn.query(&statement, &[&pattern, &limit]).await?;
+            Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+        })
+        .await
+    }
+
+    async fn known(&self, words: &[String]) -> Result<Vec<String>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached("SELECT term FROM search_terms WHERE term = ANY($1)")
+                .await?;
+            let rows = conn.query(&statement, &[&words]).await?;
+            Ok(rows.iter().map(|row| row.get(0)).collect())
+        })
+        .await
+    }
+
+    async fn similar(
+        &self,
+        word: &str,
+        min_similarity: f64,
+        limit: i64,
+    ) -> Result<Vec<(String, f64)>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "WITH q AS (SELECT search_trigrams($1) AS trigram), \
+                     n AS (SELECT count(*) AS trigrams FROM q) \
+                     SELECT t.term, \
+                     count(*)::float8 / (t.trigrams + n.trigrams - count(*)) AS similarity \
+                     FROM q JOIN search_term_trigrams g ON g.trigram = q.trigram \
+                     JOIN search_terms t ON t.term = g.term CROSS JOIN n \
+                     GROUP BY t.term, t.frequency, t.trigrams, n.trigrams \
+                     HAVING count(*)::float8 / (t.trigrams + n.trigrams - count(*)) >= $2 \
+                     ORDER BY similarity DESC, t.frequency DESC, t.term LIMIT $3",
+                )
+                .await?;
+            let rows = conn
+                .query(&statement, &[&word, &min_similarity, &limit])
+                .await?;
+            Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+        })
+        .await
+    }
+}
@@ -0,0 +1,1026 @@
+//! Users repository.
+
+use std::future::Future;
+
+use bb8_postgres::tokio_postgres::Row;
+use bb8_postgres::tokio_postgres::error::SqlState;
+use bb8_postgres::tokio_postgres::types::ToSql;
+use futures_util::{Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+use super::audit::{self, Entity};
+use super::filter::{Filter, escape_like};
+use super::ids::new_public_id;
+use super::retry::with_retry;
+use super::{INSERT_BATCH_SIZE, version_mismatch};
+use crate::db::{CachedTransaction, Db, fetch_in_batches, join_queries};
+use crate::error::{AppError, ErrorCode};
+use crate::roles::Role;
+use crate::router::query::Pagination;
+
+/// Public fields of a user.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct User {
+    /// Public ID, sent as `id` (see `ids`); assigned on insert, ignored in requests
+    #[serde(rename = "id", skip_deserializing)]
+    pub public_id: Uuid,
+    pub name: String,
+    pub age: i32,
+    /// Version of the row, sent as the `ETag`; ignored in requests
+    #[serde(skip)]
+    pub version: i32,
+    /// When the user was soft-deleted, sent only for them (`?include_deleted=true`);
+    /// ignored in requests
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<String>,
+}
+
+/// What's left of a user deleted for good, to clean up after them.
+#[derive(Debug)]
+pub struct DeletedUser {
+    pub public_id: Uuid,
+    /// Location of their avatar in the storage, if they had one
+    pub avatar_path: Option<String>,
+}
+
+/// Columns of a `User`, `deleted_at` formatted as RFC 3339
+const USER_COLUMNS: &str = "public_id, name, age, version, \
+     to_char(deleted_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS deleted_at";
+
+/// Partial update of a user; `None` fields keep their current value.
+#[derive(Deserialize, Default, Debug)]
+pub struct UserPatch {
+    pub name: Option<String>,
+    pub age: Option<i32>,
+}
+
+/// Account data created by `POST /auth/register`, or by the first login of a user of
+/// the directory (see `ldap`).
+#[derive(Debug)]
+pub struct NewAccount {
+    pub name: String,
+    pub age: i32,
+    pub email: String,
+    /// `None` for the users of the directory, whose password it checks
+    pub password_hash: Option<String>,
+}
+
+/// Credentials used to verify a login.
+#[derive(Debug)]
+pub struct Credentials {
+    pub id: i32,
+    /// `None` for users created without an account (`POST /users`), and the users of
+    /// the directory (see `ldap`)
+    pub password_hash: Option<String>,
+}
+
+/// Profile of an authenticated user.
+#[derive(Serialize, Debug)]
+pub struct Profile {
+    #[serde(rename = "id")]
+    pub public_id: Uuid,
+    pub name: String,
+    pub age: i32,
+    pub email: Option<String>,
+}
+
+/// Point after which changes are requested (`?modified_since=`).
+#[derive(Debug)]
+pub enum ModifiedSince {
+    /// A `version` returned by a previous delta response
+    Version(i64),
+    /// A timestamp, parsed by PostgreSQL (e.g. RFC 3339 `2025-01-31T12:00:00Z`)
+    Timestamp(String),
+}
+
+/// A user created or updated after the requested point.
+#[derive(Serialize, Debug)]
+pub struct ChangedUser {
+    #[serde(rename = "id")]
+    pub public_id: Uuid,
+    pub name: String,
+    pub age: i32,
+}
+
+/// A user as it was at a past moment (`?as_of=`), rebuilt from the audit log.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PastUser {
+    pub id: Uuid,
+    pub name: String,
+    pub age: i32,
+}
+
+/// Every change made to the users after the requested point.
+#[derive(Serialize, Debug)]
+pub struct UserChanges {
+    /// Created or updated users, oldest change first
+    pub data: Vec<ChangedUser>,
+    /// IDs of the deleted users
+    pub deleted: Vec<Uuid>,
+    /// Version to send as `modified_since` on the next poll
+    pub version: i64,
+}
+
+/// Aggregates over every user.
+#[derive(Serialize, Debug)]
+pub struct UserSummary {
+    pub total: i64,
+    /// `None` without users
+    pub average_age: Option<f64>,
+}
+
+impl From<&Row> for User {
+    fn from(row: &Row) -> Self {
+        User {
+            public_id: row.get("public_id"),
+            name: row.get("name"),
+            age: row.get("age"),
+            version: row.get("version"),
+            // Only selected by the queries that can return soft-deleted users
+            deleted_at: row.try_get("deleted_at").unwrap_or_default(),
+        }
+    }
+}
+
+impl From<&Row> for Profile {
+    fn from(row: &Row) -> Self {
+        Profile {
+            public_id: row.get("public_id"),
+            name: row.get("name"),
+            age: row.get("age"),
+            email: row.get("email"),
+        }
+    }
+}
+
+/// Operations on the `users` table.
+///
+/// Methods returning `Option` or `bool` report a missing user that way,
+/// so the caller decides which error (if any) it maps to.
+///
+/// A soft-deleted user (`deleted_at` set, see `soft_delete`) is missing for every
+/// method but `find_any`, `restore` and `delete`, and for the lists unless their
+/// filter includes the deleted users (`Filter::include_deleted`).
+pub trait UserRepository {
+    /// Counts the users matching a filter.
+    fn count(&self, filter: &Filter) -> impl Future<Output = Result<i64, AppError>> + Send;
+
+    /// Retrieves a page of the users matching a filter, sorted as requested.
+    fn list(
+        &self,
+        page: &Pagination,
+        filter: &Filter,
+    ) -> impl Future<Output = Result<Vec<User>, AppError>> + Send;
+
+    /// Streams every user matching a filter in the requested order, batch by
+    /// batch (`limit` and `offset` are ignored).
+    fn stream_all(
+        &self,
+        page: &Pagination,
+        filter: &Filter,
+    ) -> impl Stream<Item = Result<Vec<User>, AppError>> + Send + 'static + use<Self>;
+
+    /// Retrieves a user by ID.
+    fn find_by_id(&self, id: i32) -> impl Future<Output = Result<Option<User>, AppError>> + Send;
+
+    /// Retrieves a user by ID, soft-deleted or not.
+    fn find_any(&self, id: i32) -> impl Future<Output = Result<Option<User>, AppError>> + Send;
+    /// Retrieves the users whose name contains some of `terms` (case insensitive), the
+    /// users containing the most first.
+    fn search_by_name(
+        &self,
+        terms: &[String],
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<User>, AppError>> + Send;
+
+    /// Inserts a user without credentials, returning its key and its public ID.
+    fn create(&self, user: &User) -> impl Future<Output = Result<(i32, Uuid), AppError>> + Send;
+
+    /// Inserts users without credentials in a single transaction, returning their keys
+    /// and public IDs in the same order.
+    fn create_many(
+        &self,
+        users: &[User],
+    ) -> impl Future<Output = Result<Vec<(i32, Uuid)>, AppError>> + Send;
+
+    /// Replaces every field of a user, returning the updated user.
+    ///
+    /// Only applies to a user with one of `versions` (any version when `None`), and
+    /// fails with `AppError::PreconditionFailed` otherwise; the same goes for `patch`
+    /// and `delete`.
+    fn update(
+        &self,
+        id: i32,
+        user: &User,
+        versions: Option<&[i32]>,
+    ) -> impl Future<Output = Result<Option<User>, AppError>> + Send;
+
+    /// Updates the fields present in `patch`, returning the updated user.
+    fn patch(
+        &self,
+        id: i32,
+        patch: &UserPatch,
+        versions: Option<&[i32]>,
+    ) -> impl Future<Output = Result<Option<User>, AppError>> + Send;
+
+    /// Deletes a user for good, soft-deleted or not, returning the deleted user.
+    fn delete(
+        &self,
+        id: i32,
+        versions: Option<&[i32]>,
+    ) -> impl Future<Output = Result<Option<DeletedUser>, AppError>> + Send;
+
+    /// Marks a user as deleted, keeping the row to be restored, and returns it.
+    fn soft_delete(
+        &self,
+        id: i32,
+        versions: Option<&[i32]>,
+    ) -> impl Future<Output = Result<Option<User>, AppError>> + Send;
+
+    /// Undeletes a soft-deleted user, returning the restored user. A user that isn't
+    /// deleted fails with `AppError::Conflict`.
+    fn restore(&self, id: i32) -> impl Future<Output = Result<Option<User>, AppError>> + Send;
+
+    /// Soft-deletes up to `limit` users matching `filter` in one transaction, returning
+    /// their keys and public IDs. Rows locked by another transaction are skipped, and
+    /// left to a later batch.
+    fn delete_batch(
+        &self,
+        filter: &Filter,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<(i32, Uuid)>, AppError>> + Send;
+
+    /// Inserts a user with credentials, returning its key and its public ID.
+    /// A duplicated email fails with the `UNIQUE_VIOLATION` database error.
+    fn create_account(
+        &self,
+        account: &NewAccount,
+    ) -> impl Future<Output = Result<(i32, Uuid), AppError>> + Send;
+
+    /// Retrieves the credentials of the user with the given (normalized) email, `None`
+    /// if the account was deactivated by the identity system (see `scim`).
+    fn find_credentials(
+        &self,
+        email: &str,
+    ) -> impl Future<Output = Result<Option<Credentials>, AppError>> + Send;
+
+    /// Records a login of the user, which keeps it from being found inactive by the
+    /// retention rules (see `retention`).
+    fn record_login(&self, id: i32) -> impl Future<Output = Result<(), AppError>> + Send;
+
+    /// Retrieves the profile of a user by ID.
+    fn find_profile(
+        &self,
+        id: i32,
+    ) -> impl Future<Output = Result<Option<Profile>, AppError>> + Send;
+
+    /// Retrieves the location of the avatar of a user in the file storage,
+    /// `None` if the user doesn't exist or has no avatar.
+    fn find_avatar(&self, id: i32)
+    -> impl Future<Output = Result<Option<String>, AppError>> + Send;
+
+    /// Sets the location of the avatar of a user.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Option<String>>, AppError>` - `None` if the user doesn't exist,
+    ///   otherwise the location of the previous avatar, if any
+    fn set_avatar(
+        &self,
+        id: i32,
+        location: &str,
+    ) -> impl Future<Output = Result<Option<Option<String>>, AppError>> + Send;
+
+    /// Retrieves the role of a user (see `roles`).
+    fn find_role(&self, id: i32) -> impl Future<Output = Result<Option<Role>, AppError>> + Send;
+
+    /// Sets the role of a user, returning the role they had.
+    fn set_role(
+        &self,
+        id: i32,
+        role: Role,
+    ) -> impl Future<Output = Result<Option<Role>, AppError>> + Send;
+
+    /// Retrieves the users changed and deleted after `since`, soft-deleted or for good.
+    /// An invalid timestamp fails with `AppError::Validation`.
+    fn changes_since(
+        &self,
+        since: &ModifiedSince,
+    ) -> impl Future<Output = Result<UserChanges, AppError>> + Send;
+
+    /// Rebuilds a user as it was at `as_of`, a timestamp parsed by PostgreSQL, by
+    /// undoing its audit records made since (see `audit::rewind`). It works for deleted
+    /// users too; `None` if the user didn't exist then. An invalid timestamp fails with
+    /// `AppError::Validation`.
+    fn find_as_of(
+        &self,
+        id: i32,
+        as_of: &str,
+    ) -> impl Future<Output = Result<Option<PastUser>, AppError>> + Send;
+
+    /// Computes the aggregates over every user.
+    fn summary(&self) -> impl Future<Output = Result<UserSummary, AppError>> + Send;
+}
+
+/// `UserRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgUserRepo(pub Db);
+
+impl UserRepository for PgUserRepo {
+    async fn count(&self, filter: &Filter) -> Result<i64, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let sql = format!("SELECT COUNT(*) FROM users {}", where_clause(filter, 1));
+            let statement = conn.prepare_cached(&sql).await?;
+            let row = conn.query_one(&statement, &filter.params()).await?;
+            Ok(row.get(0))
+        })
+        .await
+    }
+
+    async fn list(&self, page: &Pagination, filter: &Filter) -> Result<Vec<User>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let sql = format!(
+                "SELECT {} FROM users {} {} LIMIT $1 OFFSET $2",
+                USER_COLUMNS,
+                where_clause(filter, 3),
+                page.order_by_clause()
+            );
+            // One statement per sort order and set of filters
+            let statement = conn.prepare_cached(&sql).await?;
+            let mut params: Vec<&(dyn ToSql + Sync)> = vec![&page.limit, &page.offset];
+            params.extend(filter.params());
+            let rows = conn.query(&statement, &params).await?;
+            Ok(rows.iter().map(User::from).collect())
+        })
+        .await
+    }
+
+    fn stream_all(
+        &self,
+        page: &Pagination,
+        filter: &Filter,
+    ) -> impl Stream<Item = Result<Vec<User>, AppError>> + Send + 'static + use<> {
+        let sql = format!(
+            "SELECT {} FROM users {} {}",
+            USER_COLUMNS,
+            where_clause(filter, 1),
+            page.order_by_clause()
+        );
+        fetch_in_batches(sql, filter.owned_params())
+            .map_ok(|rows| rows.iter().map(User::from).collect())
+    }
+
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT public_id, name, age, version FROM users \
+                     WHERE id = $1 AND deleted_at IS NULL",
+                )
+                .await?;
+            let row = conn.query_opt(&statement, &[&id]).await?;
+            Ok(row.as_ref().map(User::from))
+        })
+        .await
+    }
+
+    async fn find_any(&self, id: i32) -> Result<Option<User>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let sql = format!("SELECT {} FROM users WHERE id = $1", USER_COLUMNS);
+            let statement = conn.prepare_cached(&sql).await?;
+            let row = conn.query_opt(&statement, &[&id]).await?;
+            Ok(row.as_ref().map(User::from))
+        })
+        .await
+    }
+
+    async fn search_by_name(&self, terms: &[String], limit: i64) -> Result<Vec<User>, AppError> {
+        let patterns = terms
+            .iter()
+            .map(|term| format!("%{}%", escape_like(term)))
+            .collect::<Vec<_>>();
+        let patterns = &patterns;
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT * FROM (\
+                     SELECT public_id, name, age, version, \
+                     (SELECT COUNT(*) FROM unnest($1::text[]) AS terms (pattern) \
+                     WHERE name ILIKE pattern) AS matches FROM users \
+                     WHERE deleted_at IS NULL) AS found \
+                     WHERE matches > 0 ORDER BY matches DESC, length(name), public_id LIMIT $2",
+                )
+                .await?;
+            let rows = conn.query(&statement, &[patterns, &limit]).await?;
+            Ok(rows.iter().map(User::from).collect())
+        })
+        .await
+    }
+
+    async fn create(&self, user: &User) -> Result<(i32, Uuid), AppError> {
+        let user = User {
+            public_id: new_public_id(),
+            ..user.clone()
+        };
+        self.0
+            .transaction(async |tx| {
+                let statement = tx
+                    .prepare_cached(
+                        "INSERT INTO users (public_id, name, age) VALUES ($1, $2, $3) RETURNING id",
+                    )
+                    .await?;
+                let row = tx
+                    .query_one(&statement, &[&user.public_id, &user.name, &user.age])
+                    .await?;
+                let id = row.get("id");
+                audit::record(tx, Entity::User, id, None, Some(&user)).await?;
+                Ok((id, user.public_id))
+            })
+            .await
+    }
+
+    async fn create_many(&self, users: &[User]) -> Result<Vec<(i32, Uuid)>, AppError> {
+        let users: Vec<User> = users
+            .iter()
+            .map(|user| User {
+                public_id: new_public_id(),
+                ..user.clone()
+            })
+            .collect();
+        // ORDER BY gives the IDs in the order of the arrays
+        self.0
+            .transaction(async |tx| {
+                let statement = tx
+                    .prepare_cached(
+                        "INSERT INTO users (public_id, name, age) \
+                     SELECT new.public_id, new.name, new.age \
+                     FROM UNNEST($1::uuid[], $2::text[], $3::int[]) WITH ORDINALITY \
+                     AS new(public_id, name, age, position) \
+                     ORDER BY new.position RETURNING id",
+                    )
+                    .await?;
+                let mut ids = Vec::with_capacity(users.len());
+                for batch in users.chunks(INSERT_BATCH_SIZE) {
+                    let public_ids: Vec<Uuid> = batch.iter().map(|user| user.public_id).collect();
+                    let names: Vec<&str> = batch.iter().map(|user| user.name.as_str()).collect();
+                    let ages: Vec<i32> = batch.iter().map(|user| user.age).collect();
+                    let rows = tx.query(&statement, &[&public_ids, &names, &ages]).await?;
+                    let batch_ids: Vec<i32> = rows.iter().map(|row| row.get("id")).collect();
+                    audit::record_inserts(tx, Entity::User, &batch_ids, batch).await?;
+                    ids.extend(batch_ids.into_iter().zip(public_ids));
+                }
+                Ok(ids)
+            })
+            .await
+    }
+
+    async fn update(
+        &self,
+        id: i32,
+        user: &User,
+        versions: Option<&[i32]>,
+    ) -> Result<Option<User>, AppError> {
+        // The subquery locks the row and reads the values it had before the update,
+        // which only happens if the row still has one of the versions
+        self.0
+            .transaction(async |tx| {
+                let statement = tx
+                    .prepare_cached(
+                        "UPDATE users SET name = $1, age = $2, version = users.version + 1 \
+                     FROM (SELECT name, age, version FROM users \
+                     WHERE id = $3 AND deleted_at IS NULL FOR UPDATE) AS previous \
+                     WHERE users.id = $3 AND ($4::int[] IS NULL OR previous.version = ANY($4)) \
+                     RETURNING users.public_id, users.name, users.age, users.version, \
+                     previous.name AS previous_name, previous.age AS previous_age, \
+                     previous.version AS previous_version",
+                    )
+                    .await?;
+                let row = tx
+                    .query_opt(&statement, &[&user.name, &user.age, &id, &versions])
+                    .await?;
+                record_update(tx, id, row).await
+            })
+            .await
+    }
+
+    async fn patch(
+        &self,
+        id: i32,
+        patch: &UserPatch,
+        versions: Option<&[i32]>,
+    ) -> Result<Option<User>, AppError> {
+        // COALESCE keeps the current value when the parameter is NULL (field not sent)
+        self.0
+            .transaction(async |tx| {
+                let statement = tx
+                    .prepare_cached(
+                        "UPDATE users SET name = COALESCE($1, users.name), \
+                     age = COALESCE($2, users.age), version = users.version + 1 \
+                     FROM (SELECT name, age, version FROM users \
+                     WHERE id = $3 AND deleted_at IS NULL FOR UPDATE) AS previous \
+                     WHERE users.id = $3 AND ($4::int[] IS NULL OR previous.version = ANY($4)) \
+                     RETURNING users.public_id, users.name, users.age, users.version, \
+                     previous.name AS previous_name, previous.age AS previous_age, \
+                     previous.version AS previous_version",
+                    )
+                    .await?;
+                let row = tx
+                    .query_opt(&statement, &[&patch.name, &patch.age, &id, &versions])
+                    .await?;
+                record_update(tx, id, row).await
+            })
+            .await
+    }
+
+    async fn delete(
+        &self,
+        id: i32,
+        versions: Option<&[i32]>,
+    ) -> Result<Option<DeletedUser>, AppError> {
+        self.0
+            .transaction(async |tx| {
+                let sql = format!(
+                    "DELETE FROM users \
+                 WHERE id = $1 AND ($2::int[] IS NULL OR version = ANY($2)) \
+                 RETURNING {}, avatar_path",
+                    USER_COLUMNS
+                );
+                let statement = tx.prepare_cached(&sql).await?;
+                let Some(row) = tx.query_opt(&statement, &[&id, &versions]).await? else {
+                    return version_mismatch(tx, "users", id).await;
+                };
+                audit::record(tx, Entity::User, id, Some(&User::from(&row)), None).await?;
+                Ok(Some(DeletedUser {
+                    public_id: row.get("public_id"),
+                    avatar_path: row.get("avatar_path"),
+                }))
+            })
+            .await
+    }
+
+    async fn soft_delete(
+        &self,
+        id: i32,
+        versions: Option<&[i32]>,
+    ) -> Result<Option<User>, AppError> {
+        self.0
+            .transaction(async |tx| {
+                let sql = format!(
+                    "UPDATE users SET deleted_at = now(), version = version + 1 \
+                 WHERE id = $1 AND deleted_at IS NULL \
+                 AND ($2::int[] IS NULL OR version = ANY($2)) RETURNING {}",
+                    USER_COLUMNS
+                );
+                let statement = tx.prepare_cached(&sql).await?;
+                let Some(row) = tx.query_opt(&statement, &[&id, &versions]).await? else {
+                    return mismatch_or_deleted(tx, id).await;
+                };
+                let user = User::from(&row);
+                let previous = User {
+                    deleted_at: None,
+                    ..user.clone()
+                };
+                audit::record(tx, Entity::User, id, Some(&previous), Some(&user)).await?;
+                Ok(Some(user))
+            })
+            .await
+    }
+
+    async fn restore(&self, id: i32) -> Result<Option<User>, AppError> {
+        // The subquery locks the row and reads when it was deleted, which only happens
+        // if it was
+        self.0
+            .transaction(async |tx| {
+                let statement = tx
+                    .prepare_cached(
+                        "UPDATE users SET deleted_at = NULL, version = users.version + 1 \
+                     FROM (SELECT to_char(deleted_at AT TIME ZONE 'UTC', \
+                     'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS deleted_at FROM users \
+                     WHERE id = $1 AND deleted_at IS NOT NULL FOR UPDATE) AS previous \
+                     WHERE users.id = $1 \
+                     RETURNING users.public_id, users.name, users.age, users.version, \
+                     previous.deleted_at AS previous_deleted_at",
+                    )
+                    .await?;
+                let Some(row) = tx.query_opt(&statement, &[&id]).await? else {
+                    let statement = tx
+                        .prepare_cached("SELECT 1 FROM users WHERE id = $1")
+                        .await?;
+                    if tx.query_opt(&statement, &[&id]).await?.is_none() {
+                        return Ok(None);
+                    }
+                    return Err(AppError::Conflict(
+                        ErrorCode::UserNotDeleted,
+                        "The user isn't deleted".to_string(),
+                    ));
+                };
+                let user = User::from(&row);
+                let previous = User {
+                    deleted_at: row.get("previous_deleted_at"),
+                    ..user.clone()
+                };
+                audit::record(tx, Entity::User, id, Some(&previous), Some(&user)).await?;
+                Ok(Some(user))
+            })
+            .await
+    }
+
+    async fn delete_batch(
+        &self,
+        filter: &Filter,
+        limit: i64,
+    ) -> Result<Vec<(i32, Uuid)>, AppError> {
+        self.0
+            .transaction(async |tx| {
+                // The soft-deleted users are left out by the filter, and kept as
+                // `soft_delete` keeps them
+                let sql = format!(
+                    "UPDATE users SET deleted_at = now(), version = version + 1 WHERE id IN \
+                 (SELECT id FROM users {} ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED) \
+                 AND deleted_at IS NULL RETURNING id, {}",
+                    where_clause(filter, 2),
+                    USER_COLUMNS
+                );
+                let statement = tx.prepare_cached(&sql).await?;
+                let mut params: Vec<&(dyn ToSql + Sync)> = vec![&limit];
+                params.extend(filter.params());
+                let rows = tx.query(&statement, &params).await?;
+
+                let mut deleted = Vec::with_capacity(rows.len());
+                for row in &rows {
+                    let id = row.get("id");
+                    let user = User::from(row);
+                    let previous = User {
+                        deleted_at: None,
+                        ..user.clone()
+                    };
+                    audit::record(tx, Entity::User, id, Some(&previous), Some(&user)).await?;
+                    deleted.push((id, user.public_id));
+                }
+                Ok(deleted)
+            })
+            .await
+    }
+
+    async fn create_account(&self, account: &NewAccount) -> Result<(i32, Uuid), AppError> {
+        let public_id = new_public_id();
+        self.0
+            .transaction(async |tx| {
+                let statement = tx
+                    .prepare_cached(
+                        "INSERT INTO users (public_id, name, age, email, password_hash) \
+                     VALUES ($1, $2, $3, $4, $5) RETURNING id",
+                    )
+                    .await?;
+                let row = tx
+                    .query_one(
+                        &statement,
+                        &[
+                            &public_id,
+                            &account.name,
+                            &account.age,
+                            &account.email,
+                            &account.password_hash,
+                        ],
+                    )
+                    .await?;
+                let id = row.get("id");
+                let fields = json!({
+                    "id": public_id,
+                    "name": account.name,
+                    "age": account.age,
+                    "email": account.email,
+                });
+                audit::record(tx, Entity::User, id, None, Some(&fields)).await?;
+                Ok((id, public_id))
+            })
+            .await
+    }
+
+    async fn find_credentials(&self, email: &str) -> Result<Option<Credentials>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT id, password_hash FROM users \
+                     WHERE email = $1 AND active AND deleted_at IS NULL",
+                )
+                .await?;
+            let row = conn.query_opt(&statement, &[&email]).await?;
+            Ok(row.map(|row| Credentials {
+                id: row.get("id"),
+                password_hash: row.get("password_hash"),
+            }))
+        })
+        .await
+    }
+
+    async fn record_login(&self, id: i32) -> Result<(), AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "INSERT INTO user_logins (user_id) VALUES ($1) \
+                 ON CONFLICT (user_id) DO UPDATE SET last_login_at = now()",
+            )
+            .await?;
+        conn.execute(&statement, &[&id]).await?;
+        Ok(())
+    }
+
+    async fn find_profile(&self, id: i32) -> Result<Option<Profile>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT public_id, name, age, email FROM users \
+                     WHERE id = $1 AND deleted_at IS NULL",
+                )
+                .await?;
+            let row = conn.query_opt(&statement, &[&id]).await?;
+            Ok(row.as_ref().map(Profile::from))
+        })
+        .await
+    }
+
+    async fn find_avatar(&self, id: i32) -> Result<Option<String>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT avatar_path FROM users WHERE id = $1 AND deleted_at IS NULL",
+                )
+                .await?;
+            let row = conn.query_opt(&statement, &[&id]).await?;
+            Ok(row.and_then(|row| row.get("avatar_path")))
+        })
+        .await
+    }
+
+    async fn set_avatar(
+        &self,
+        id: i32,
+        location: &str,
+    ) -> Result<Option<Option<String>>, AppError> {
+        // The subquery locks the row and reads the value it had before the update
+        self.0
+            .transaction(async |tx| {
+                let statement = tx
+                    .prepare_cached(
+                        "UPDATE users SET avatar_path = $1 \
+                     FROM (SELECT avatar_path FROM users \
+                     WHERE id = $2 AND deleted_at IS NULL FOR UPDATE) AS previous \
+                     WHERE users.id = $2 RETURNING previous.avatar_path",
+                    )
+                    .await?;
+                let Some(row) = tx.query_opt(&statement, &[&location, &id]).await? else {
+                    return Ok(None);
+                };
+                let previous: Option<String> = row.get("avatar_path");
+                let (old, new) = (
+                    json!({"avatar_path": previous}),
+                    json!({"avatar_path": location}),
+                );
+                audit::record(tx, Entity::User, id, Some(&old), Some(&new)).await?;
+                Ok(Some(previous))
+            })
+            .await
+    }
+
+    async fn find_role(&self, id: i32) -> Result<Option<Role>, AppError> {
+        // From the primary: a role taken away applies at once
+        let row = with_retry(self.0, || async move {
+            let conn = self.0.connection().await?;
+            let statement = conn
+                .prepare_cached("SELECT role FROM users WHERE id = $1 AND deleted_at IS NULL")
+                .await?;
+            Ok(conn.query_opt(&statement, &[&id]).await?)
+        })
+        .await?;
+        row.map(|row| parse_role(row.get("role"))).transpose()
+    }
+
+    async fn set_role(&self, id: i32, role: Role) -> Result<Option<Role>, AppError> {
+        // The subquery locks the row and reads the value it had before the update
+        self.0
+            .transaction(async |tx| {
+                let statement = tx
+                    .prepare_cached(
+                        "UPDATE users SET role = $1 \
+                     FROM (SELECT role FROM users \
+                     WHERE id = $2 AND deleted_at IS NULL FOR UPDATE) AS previous \
+                     WHERE users.id = $2 RETURNING previous.role",
+                    )
+                    .await?;
+                let Some(row) = tx.query_opt(&statement, &[&role.as_str(), &id]).await? else {
+                    return Ok(None);
+                };
+                let previous = parse_role(row.get("role"))?;
+                let (old, new) = (json!({"role": previous}), json!({"role": role}));
+                audit::record(tx, Entity::User, id, Some(&old), Some(&new)).await?;
+                Ok(Some(previous))
+            })
+            .await
+    }
+
+    async fn changes_since(&self, since: &ModifiedSince) -> Result<UserChanges, AppError> {
+        // Timestamps are sent as text and parsed by PostgreSQL
+        let (users_filter, tombstones_filter, since): (_, _, &(dyn ToSql + Sync)) = match since {
+            ModifiedSince::Version(version) => {
+                ("change_version > $1", "change_version > $1", version)
+            }
+            ModifiedSince::Timestamp(timestamp) => (
+                "updated_at > $1::text::timestamptz",
+                "deleted_at > $1::text::timestamptz",
+                timestamp,
+            ),
+        };
+
+        let result = with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+
+            // Read first: a change committed while the rows are read has a greater
+            // version, so it is sent again on the next poll instead of being missed.
+            // Versions are taken on write, not on commit, so a slow transaction could
+            // still commit one lower than this; user writes are single statements.
+            let statement = conn
+                .prepare_cached(
+                    "SELECT GREATEST( \
+                         (SELECT COALESCE(MAX(change_version), 0) FROM users), \
+                         (SELECT COALESCE(MAX(change_version), 0) FROM user_tombstones))",
+                )
+                .await?;
+            let version: i64 = conn.query_one(&statement, &[]).await?.get(0);
+
+            // Pipelined on the connection: one round trip for both. The soft-deleted
+            // users are among the changed rows, and sent as deleted
+            let changed_sql = format!(
+                "SELECT public_id, name, age, deleted_at IS NOT NULL AS deleted FROM users \
+                 WHERE {} ORDER BY change_version",
+                users_filter
+            );
+            let deleted_sql = format!(
+                "SELECT public_id FROM user_tombstones \
+                 WHERE {} AND public_id IS NOT NULL ORDER BY change_version",
+                tombstones_filter
+            );
+            let (changed, deleted) = join_queries!(
+                conn.query(&changed_sql, &[since]),
+                conn.query(&deleted_sql, &[since]),
+            )?;
+
+            let (soft_deleted, changed): (Vec<_>, Vec<_>) = changed
+                .iter()
+                .partition(|row| row.get::<_, bool>("deleted"));
+            Ok(UserChanges {
+                data: changed
+                    .into_iter()
+                    .map(|row| ChangedUser {
+                        public_id: row.get("public_id"),
+                        name: row.get("name"),
+                        age: row.get("age"),
+                    })
+                    .collect(),
+                deleted: deleted
+                    .iter()
+                    .chain(soft_deleted)
+                    .map(|row| row.get("public_id"))
+                    .collect(),
+                version,
+            })
+        })
+        .await;
+
+        match result {
+            Err(AppError::Db(e))
+                if e.code() == Some(&SqlState::INVALID_DATETIME_FORMAT)
+                    || e.code() == Some(&SqlState::DATETIME_FIELD_OVERFLOW) =>
+            {
+                Err(AppError::Validation(
+                    "modified_since must be a version or a timestamp".to_string(),
+                ))
+            }
+            result => result,
+        }
+    }
+
+    async fn find_as_of(&self, id: i32, as_of: &str) -> Result<Option<PastUser>, AppError> {
+        let result = with_retry(self.0, || async move {
+            let mut conn = self.0.read_connection().await?;
+            // The row and the records undone must come from the same snapshot
+            let tx = conn.cached_transaction().await?;
+            tx.batch_execute("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY")
+                .await?;
+            let statement = tx
+                .prepare_cached("SELECT public_id, name, age, version FROM users WHERE id = $1")
+                .await?;
+            let current = tx.query_opt(&statement, &[&id]).await?;
+            let records = audit::records_after(&tx, Entity::User, id, as_of).await?;
+            tx.commit().await?;
+
+            let current = current
+                .map(|row| serde_json::to_value(User::from(&row)))
+                .transpose()
+                .map_err(|e| AppError::Internal(format!("Serialization failed: {}", e)))?;
+            let current = current.and_then(|value| value.as_object().cloned());
+            audit::rewind(current, &records)
+                .map(|fields| {
+                    serde_json::from_value(Value::Object(fields)).map_err(|e| {
+                        AppError::Internal(format!("Audit records of user {}: {}", id, e))
+                    })
+                })
+                .transpose()
+        })
+        .await;
+
+        match result {
+            Err(AppError::Db(e))
+                if e.code() == Some(&SqlState::INVALID_DATETIME_FORMAT)
+                    || e.code() == Some(&SqlState::DATETIME_FIELD_OVERFLOW) =>
+            {
+                Err(AppError::Validation(
+                    "as_of must be a timestamp".to_string(),
+                ))
+            }
+            result => result,
+        }
+    }
+
+    async fn summary(&self) -> Result<UserSummary, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT COUNT(*), AVG(age)::DOUBLE PRECISION FROM users \
+                     WHERE deleted_at IS NULL",
+                )
+                .await?;
+            let row = conn.query_one(&statement, &[]).await?;
+            Ok(UserSummary {
+                total: row.get(0),
+                average_age: row.get(1),
+            })
+        })
+        .await
+    }
+}
+
+/// Records the update of a user from the row returned by the `UPDATE`, with its
+/// `previous_name`, `previous_age` and `previous_version`.
+async fn record_update(
+    tx: &CachedTransaction<'_>,
+    id: i32,
+    row: Option<Row>,
+) -> Result<Option<User>, AppError> {
+    let Some(row) = row else {
+        return mismatch_or_deleted(tx, id).await;
+    };
+    let previous = User {
+        public_id: row.get("public_id"),
+        name: row.get("previous_name"),
+        age: row.get("previous_age"),
+        version: row.get("previous_version"),
+        deleted_at: None,
+    };
+    let user = User::from(&row);
+    audit::record(tx, Entity::User, id, Some(&previous), Some(&user)).await?;
+    Ok(Some(user))
+}
+
+/// The role of the `role` column, which only holds known ones.
+fn parse_role(role: &str) -> Result<Role, AppError> {
+    role.parse().map_err(AppError::Internal)
+}
+
+/// `version_mismatch` of a write to a user that may be soft-deleted: missing then.
+async fn mismatch_or_deleted(
+    tx: &CachedTransaction<'_>,
+    id: i32,
+) -> Result<Option<User>, AppError> {
+    let statement = tx
+        .prepare_cached("SELECT 1 FROM users WHERE id = $1 AND deleted_at IS NOT NULL")
+        .await?;
+    if tx.query_opt(&statement, &[&id]).await?.is_some() {
+        return Ok(None);
+    }
+    version_mismatch(tx, "users", id).await
+}
+
+/// The `WHERE` clause of `filter`, leaving out the soft-deleted users unless it
+/// includes them.
+fn where_clause(filter: &Filter, first: usize) -> String {
+    let clause = filter.where_clause(first);
+    if filter.includes_deleted() {
+        return clause;
+    }
+    match clause.strip_prefix("WHERE ") {
+        Some(conditions) => format!("WHERE deleted_at IS NULL AND {}", conditions),
+        None => "WHERE deleted_at IS NULL".to_string(),
+    }
+}
@@ -0,0 +1,27 @@
+//! Collection versions.
+//!
+//! `collection_versions` holds a counter per table, bumped by a trigger on every
+//! statement writing to it (`V5__create_collection_versions` migration), so readers
+//! can detect changes with a single-row query.
+
+use crate::db::Db;
+use crate::error::AppError;
+
+use super::retry::with_retry;
+
+/// Reads the current version of a collection (`users`, `products`).
+///
+/// # Returns
+///
+/// * `Result<i64, AppError>` - The version, 0 for a collection without counter
+pub async fn collection_version(db: Db, name: &str) -> Result<i64, AppError> {
+    with_retry(db, || async move {
+        let conn = db.read_connection().await?;
+        let statement = conn
+            .prepare_cached("SELECT version FROM collection_versions WHERE name = $1")
+            .await?;
+        let row = conn.query_opt(&statement, &[&name]).await?;
+        Ok(row.map_or(0, |row| row.get("version")))
+    })
+    .await
+}
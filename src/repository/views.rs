@@ -0,0 +1,140 @@
+//! Saved views repository.
+//!
+//! Named filter and sort combinations of a list, saved by a user (see `routes::views`).
+
+use std::future::Future;
+
+use bb8_postgres::tokio_postgres::Row;
+use serde::{Deserialize, Serialize};
+
+use super::retry::with_retry;
+use crate::db::Db;
+use crate::error::AppError;
+use crate::router::query::SortOrder;
+
+/// A named filter and sort of a list.
+#[derive(Serialize, Deserialize, Clone, Debug, Hash)]
+pub struct SavedView {
+    pub name: String,
+    /// Filter expression (`?filter=`), empty for none
+    #[serde(default)]
+    pub filter: String,
+    /// Sort column, the default one of the list when `None`
+    #[serde(default)]
+    pub sort: Option<String>,
+    #[serde(default)]
+    pub order: Option<SortOrder>,
+}
+
+impl From<&Row> for SavedView {
+    fn from(row: &Row) -> Self {
+        SavedView {
+            name: row.get("name"),
+            filter: row.get("filter"),
+            sort: row.get("sort"),
+            order: row
+                .get::<_, Option<&str>>("sort_order")
+                .map(|order| match order {
+                    "desc" => SortOrder::Desc,
+                    _ => SortOrder::Asc,
+                }),
+        }
+    }
+}
+
+/// Operations on the `saved_views` table. Views belong to a user and a list
+/// (`collection`: `users`, `products`).
+pub trait ViewRepository {
+    /// Saves a view, replacing the one of the user with the same name.
+    fn save(
+        &self,
+        user_id: i32,
+        collection: &str,
+        view: &SavedView,
+    ) -> impl Future<Output = Result<(), AppError>> + Send;
+
+    /// Retrieves a view of the user by name.
+    fn find(
+        &self,
+        user_id: i32,
+        collection: &str,
+        name: &str,
+    ) -> impl Future<Output = Result<Option<SavedView>, AppError>> + Send;
+
+    /// Retrieves the views of the user, by name.
+    fn list(
+        &self,
+        user_id: i32,
+        collection: &str,
+    ) -> impl Future<Output = Result<Vec<SavedView>, AppError>> + Send;
+}
+
+/// `ViewRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgViewRepo(pub Db);
+
+impl ViewRepository for PgViewRepo {
+    async fn save(&self, user_id: i32, collection: &str, view: &SavedView) -> Result<(), AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "INSERT INTO saved_views (user_id, collection, name, filter, sort, sort_order) \
+                 VALUES ($1, $2, $3, $4, $5, $6) \
+                 ON CONFLICT (user_id, collection, name) DO UPDATE \
+                 SET filter = EXCLUDED.filter, sort = EXCLUDED.sort, \
+                 sort_order = EXCLUDED.sort_order, updated_at = now()",
+            )
+            .await?;
+        let order = view.order.map(SortOrder::as_query);
+        conn.execute(
+            &statement,
+            &[
+                &user_id,
+                &collection,
+                &view.name,
+                &view.filter,
+                &view.sort,
+                &order,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn find(
+        &self,
+        user_id: i32,
+        collection: &str,
+        name: &str,
+    ) -> Result<Option<SavedView>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT name, filter, sort, sort_order FROM saved_views \
+                     WHERE user_id = $1 AND collection = $2 AND name = $3",
+                )
+                .await?;
+            let row = conn
+                .query_opt(&statement, &[&user_id, &collection, &name])
+                .await?;
+            Ok(row.as_ref().map(SavedView::from))
+        })
+        .await
+    }
+
+    async fn list(&self, user_id: i32, collection: &str) -> Result<Vec<SavedView>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT name, filter, sort, sort_order FROM saved_views \
+                     WHERE user_id = $1 AND collection = $2 ORDER BY name",
+                )
+                .await?;
+            let rows = conn.query(&statement, &[&user_id, &collection]).await?;
+            Ok(rows.iter().map(SavedView::from).collect())
+        })
+        .await
+    }
+}
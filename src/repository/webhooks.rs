@@ -0,0 +1,351 @@
+//! Webhooks repository.
+//!
+//! Subscriptions of the users to the change events, and the log of the attempts to
+//! deliver them (see the `webhooks` module).
+
+use std::future::Future;
+
+use bb8_postgres::tokio_postgres::Row;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use super::ids::new_public_id;
+use super::retry::with_retry;
+use crate::db::Db;
+use crate::error::AppError;
+
+/// Columns of a `Webhook`
+const WEBHOOK_COLUMNS: &str = "id, url, events, \
+     to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS created_at, \
+     to_char(updated_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS updated_at";
+
+/// A webhook as sent to its owner, without its secret.
+#[derive(Serialize, Debug)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub url: String,
+    /// Event types delivered, empty for all of them
+    pub events: Vec<String>,
+    /// Timestamps in RFC 3339, UTC
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl From<&Row> for Webhook {
+    fn from(row: &Row) -> Self {
+        Webhook {
+            id: row.get("id"),
+            url: row.get("url"),
+            events: row.get("events"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+}
+
+/// Fields of a webhook, sent to register or replace it.
+#[derive(Deserialize, Debug)]
+pub struct WebhookSettings {
+    pub url: String,
+    /// Key of the signature of the deliveries
+    pub secret: String,
+    /// Event types to deliver (`users.created`), all of them when empty
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+/// Where a webhook delivers, and the key of its signature.
+#[derive(Debug)]
+pub struct WebhookTarget {
+    pub url: String,
+    pub secret: String,
+}
+
+/// An attempt to deliver an event, as it is recorded.
+#[derive(Debug)]
+pub struct NewDelivery<'a> {
+    pub webhook_id: Uuid,
+    /// Job delivering the event, whose attempts are numbered
+    pub job_id: i64,
+    pub event_type: &'a str,
+    /// The JSON body sent
+    pub payload: &'a str,
+    /// `None` when no response was received
+    pub status_code: Option<i32>,
+    pub error: Option<&'a str>,
+    pub duration_ms: i32,
+}
+
+/// An attempt as listed by `GET /webhooks/:id/deliveries`.
+#[derive(Serialize, Debug)]
+pub struct Delivery {
+    pub id: i64,
+    pub job_id: i64,
+    pub attempt: i32,
+    pub event_type: String,
+    pub payload: Value,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub duration_ms: i32,
+    /// RFC 3339, UTC
+    pub created_at: String,
+}
+
+impl From<&Row> for Delivery {
+    fn from(row: &Row) -> Self {
+        Delivery {
+            id: row.get("id"),
+            job_id: row.get("job_id"),
+            attempt: row.get("attempt"),
+            event_type: row.get("event_type"),
+            payload: serde_json::from_str(row.get("payload")).unwrap_or_default(),
+            status_code: row.get("status_code"),
+            error: row.get("error"),
+            duration_ms: row.get("duration_ms"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+/// Operations on the `webhooks` and `webhook_deliveries` tables. Webhooks belong to
+/// a user, who is the only one to see and change them.
+///
+/// Methods returning `Option` or `bool` report a missing webhook that way,
+/// so the caller decides which error (if any) it maps to.
+pub trait WebhookRepository {
+    /// Registers a webhook of the user.
+    fn create(
+        &self,
+        user_id: i32,
+        settings: &WebhookSettings,
+    ) -> impl Future<Output = Result<Webhook, AppError>> + Send;
+
+    /// Retrieves the webhooks of the user, the oldest first.
+    fn list(&self, user_id: i32) -> impl Future<Output = Result<Vec<Webhook>, AppError>> + Send;
+
+    /// Retrieves a webhook of the user.
+    fn find(
+        &self,
+        user_id: i32,
+        id: Uuid,
+    ) -> impl Future<Output = Result<Option<Webhook>, AppError>> + Send;
+
+    /// Replaces the fields of a webhook of the user, returning it.
+    fn update(
+        &self,
+        user_id: i32,
+        id: Uuid,
+        settings: &WebhookSettings,
+    ) -> impl Future<Output = Result<Option<Webhook>, AppError>> + Send;
+
+    /// Deletes a webhook of the user with its deliveries, `false` if it doesn't exist.
+    fn delete(&self, user_id: i32, id: Uuid)
+    -> impl Future<Output = Result<bool, AppError>> + Send;
+
+    /// IDs of the webhooks of every user delivering `event_type`.
+    fn subscribed(
+        &self,
+        event_type: &str,
+    ) -> impl Future<Output = Result<Vec<Uuid>, AppError>> + Send;
+
+    /// Where a webhook delivers, `None` once it's deleted.
+    fn target(
+        &self,
+        id: Uuid,
+    ) -> impl Future<Output = Result<Option<WebhookTarget>, AppError>> + Send;
+
+    /// Records an attempt to deliver an event, numbered after the previous ones of
+    /// its job.
+    fn record_delivery(
+        &self,
+        delivery: &NewDelivery<'_>,
+    ) -> impl Future<Output = Result<(), AppError>> + Send;
+
+    /// Retrieves the latest attempts of a webhook of the user, `None` if the user has
+    /// no such webhook.
+    fn deliveries(
+        &self,
+        user_id: i32,
+        id: Uuid,
+        limit: i64,
+    ) -> impl Future<Output = Result<Option<Vec<Delivery>>, AppError>> + Send;
+}
+
+/// `WebhookRepository` backed by PostgreSQL.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgWebhookRepo(pub Db);
+
+impl WebhookRepository for PgWebhookRepo {
+    async fn create(&self, user_id: i32, settings: &WebhookSettings) -> Result<Webhook, AppError> {
+        let conn = self.0.connection().await?;
+        let sql = format!(
+            "INSERT INTO webhooks (id, user_id, url, secret, events) \
+             VALUES ($1, $2, $3, $4, $5) RETURNING {}",
+            WEBHOOK_COLUMNS
+        );
+        let statement = conn.prepare_cached(&sql).await?;
+        let row = conn
+            .query_one(
+                &statement,
+                &[
+                    &new_public_id(),
+                    &user_id,
+                    &settings.url,
+                    &settings.secret,
+                    &settings.events,
+                ],
+            )
+            .await?;
+        Ok(Webhook::from(&row))
+    }
+
+    async fn list(&self, user_id: i32) -> Result<Vec<Webhook>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let sql = format!(
+                "SELECT {} FROM webhooks WHERE user_id = $1 ORDER BY id",
+                WEBHOOK_COLUMNS
+            );
+            let statement = conn.prepare_cached(&sql).await?;
+            let rows = conn.query(&statement, &[&user_id]).await?;
+            Ok(rows.iter().map(Webhook::from).collect())
+        })
+        .await
+    }
+
+    async fn find(&self, user_id: i32, id: Uuid) -> Result<Option<Webhook>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let sql = format!(
+                "SELECT {} FROM webhooks WHERE id = $1 AND user_id = $2",
+                WEBHOOK_COLUMNS
+            );
+            let statement = conn.prepare_cached(&sql).await?;
+            let row = conn.query_opt(&statement, &[&id, &user_id]).await?;
+            Ok(row.as_ref().map(Webhook::from))
+        })
+        .await
+    }
+
+    async fn update(
+        &self,
+        user_id: i32,
+        id: Uuid,
+        settings: &WebhookSettings,
+    ) -> Result<Option<Webhook>, AppError> {
+        let conn = self.0.connection().await?;
+        let sql = format!(
+            "UPDATE webhooks SET url = $3, secret = $4, events = $5, updated_at = now() \
+             WHERE id = $1 AND user_id = $2 RETURNING {}",
+            WEBHOOK_COLUMNS
+        );
+        let statement = conn.prepare_cached(&sql).await?;
+        let row = conn
+            .query_opt(
+                &statement,
+                &[
+                    &id,
+                    &user_id,
+                    &settings.url,
+                    &settings.secret,
+                    &settings.events,
+                ],
+            )
+            .await?;
+        Ok(row.as_ref().map(Webhook::from))
+    }
+
+    async fn delete(&self, user_id: i32, id: Uuid) -> Result<bool, AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached("DELETE FROM webhooks WHERE id = $1 AND user_id = $2")
+            .await?;
+        Ok(conn.execute(&statement, &[&id, &user_id]).await? > 0)
+    }
+
+    async fn subscribed(&self, event_type: &str) -> Result<Vec<Uuid>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.connection().await?;
+            let statement = conn
+                .prepare_cached(
+                    "SELECT id FROM webhooks \
+                     WHERE cardinality(events) = 0 OR $1 = ANY(events) ORDER BY id",
+                )
+                .await?;
+            let rows = conn.query(&statement, &[&event_type]).await?;
+            Ok(rows.iter().map(|row| row.get("id")).collect())
+        })
+        .await
+    }
+
+    async fn target(&self, id: Uuid) -> Result<Option<WebhookTarget>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.connection().await?;
+            let statement = conn
+                .prepare_cached("SELECT url, secret FROM webhooks WHERE id = $1")
+                .await?;
+            let row = conn.query_opt(&statement, &[&id]).await?;
+            Ok(row.map(|row| WebhookTarget {
+                url: row.get("url"),
+                secret: row.get("secret"),
+            }))
+        })
+        .await
+    }
+
+    async fn record_delivery(&self, delivery: &NewDelivery<'_>) -> Result<(), AppError> {
+        let conn = self.0.connection().await?;
+        let statement = conn
+            .prepare_cached(
+                "INSERT INTO webhook_deliveries (webhook_id, job_id, attempt, event_type, \
+                 payload, status_code, error, duration_ms) \
+                 SELECT $1, $2, count(*)::int + 1, $3, $4::text::jsonb, $5, $6, $7 \
+                 FROM webhook_deliveries WHERE job_id = $2",
+            )
+            .await?;
+        conn.execute(
+            &statement,
+            &[
+                &delivery.webhook_id,
+                &delivery.job_id,
+                &delivery.event_type,
+                &delivery.payload,
+                &delivery.status_code,
+                &delivery.error,
+                &delivery.duration_ms,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn deliveries(
+        &self,
+        user_id: i32,
+        id: Uuid,
+        limit: i64,
+    ) -> Result<Option<Vec<Delivery>>, AppError> {
+        with_retry(self.0, || async move {
+            let conn = self.0.read_connection().await?;
+            let owned = conn
+                .prepare_cached("SELECT 1 FROM webhooks WHERE id = $1 AND user_id = $2")
+                .await?;
+            if conn.query_opt(&owned, &[&id, &user_id]).await?.is_none() {
+                return Ok(None);
+            }
+            let statement = conn
+                .prepare_cached(
+                    "SELECT id, job_id, attempt, event_type, payload::text AS payload, \
+                     status_code, error, duration_ms, \
+                     to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') \
+                     AS created_at \
+                     FROM webhook_deliveries WHERE webhook_id = $1 ORDER BY id DESC LIMIT $2",
+                )
+                .await?;
+            let rows = conn.query(&statement, &[&id, &limit]).await?;
+            Ok(Some(rows.iter().map(Delivery::from).collect()))
+        })
+        .await
+    }
+}
@@ -0,0 +1,237 @@
+//! Data retention rules (`RETENTION_RULES_PATH`).
+//!
+//! ## Configuration
+//! Rules are read from the JSON file pointed to by `RETENTION_RULES_PATH` at startup:
+//!
+//! ```json
+//! [
+//!   {"name": "audit-log-1y", "target": "audit_log", "older_than_days": 365},
+//!   {"name": "inactive-users-3y", "target": "inactive_users", "older_than_days": 1095,
+//!    "schedule": "0 5 * * 0", "dry_run": true}
+//! ]
+//! ```
+//!
+//! A rule removes the rows of its target older than `older_than_days` days (see
+//! `repository::retention::Target`): the records of the `audit_log`, the
+//! `webhook_deliveries` and the `experiment_exposures`, and the abandoned `carts` are
+//! deleted, the `inactive_users` (neither changed nor logged in since) are anonymized,
+//! as the orders and the audit log refer to them.
+//!
+//! ## Runs
+//! Each rule is a periodic job, enqueued by the scheduler at the times of its cron
+//! `schedule` (default `0 4 * * *`, in UTC) and run by the workers in batches of
+//! `RETENTION_BATCH` rows; a failing run is retried like any job. A rule with
+//! `dry_run` only counts the rows it would remove, so its effect can be checked
+//! before it's enabled.
+//!
+//! Every run reports its rows in the `progress` of its job (`GET /admin/jobs`) and in
+//! `retention_rows_total{rule, mode}`. `GET /admin/retention` reports what each rule
+//! would remove at the moment.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::db::Db;
+use crate::error::AppError;
+use crate::events::{self, Action, Collection};
+use crate::jobs::{Job, Schedule};
+use crate::metrics;
+use crate::repository::jobs::{JobRepository, PgJobRepo};
+use crate::repository::retention::{PgRetentionRepo, RetentionRepository, Target};
+use crate::storage::{ObjectStore, store};
+
+/// Rows removed by each statement of a run
+const RETENTION_BATCH: i64 = 1000;
+
+/// Schedule of the rules without one: every night
+const DEFAULT_SCHEDULE: &str = "0 4 * * *";
+
+// Set once at startup, empty without RETENTION_RULES_PATH
+static RULES: OnceLock<Vec<Rule>> = OnceLock::new();
+
+/// A rule of the configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    pub target: Target,
+    pub older_than_days: i32,
+    /// Cron expression of the runs, `DEFAULT_SCHEDULE` when not set
+    pub schedule: Option<String>,
+    /// Whether the runs only count the rows
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl Rule {
+    fn validate(&self) -> Result<Schedule, String> {
+        let valid = !self.name.is_empty()
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+        if !valid {
+            return Err(format!(
+                "'{}': names are letters, digits, _, - and .",
+                self.name
+            ));
+        }
+        if self.older_than_days < 1 {
+            return Err(format!("'{}': older_than_days must be >= 1", self.name));
+        }
+        self.schedule
+            .as_deref()
+            .unwrap_or(DEFAULT_SCHEDULE)
+            .parse::<Schedule>()
+            .map_err(|e| format!("'{}': schedule: {}", self.name, e))
+    }
+}
+
+/// What a rule would remove now, as reported by `GET /admin/retention`.
+#[derive(Serialize, Debug)]
+pub struct RuleReport {
+    pub name: &'static str,
+    pub target: Target,
+    /// `delete` or `anonymize`
+    pub action: &'static str,
+    pub older_than_days: i32,
+    pub schedule: &'static str,
+    pub dry_run: bool,
+    /// Rows older than the limit
+    pub matching: i64,
+}
+
+/// Loads the retention rules of `path`, none without it.
+/// This function is called once by `init_jobs`, which schedules the rules.
+///
+/// # Returns
+///
+/// * `Result<Vec<(Schedule, Job)>, String>` - The periodic job of each rule, for the
+///   scheduler, or the error of an unreadable file or an invalid rule
+pub fn init_retention(path: Option<&Path>) -> Result<Vec<(Schedule, Job)>, String> {
+    let rules: Vec<Rule> = match path {
+        Some(path) => {
+            let content = fs::read_to_string(path).map_err(|e| {
+                format!("Unable to read retention rules '{}': {}", path.display(), e)
+            })?;
+            serde_json::from_str(&content)
+                .map_err(|e| format!("Invalid retention rules '{}': {}", path.display(), e))?
+        }
+        None => Vec::new(),
+    };
+    let mut names = HashSet::new();
+    let mut schedules = Vec::with_capacity(rules.len());
+    for rule in &rules {
+        let schedule = rule.validate()?;
+        if !names.insert(&rule.name) {
+            return Err(format!("Retention rule '{}' is defined twice", rule.name));
+        }
+        let job = Job::ApplyRetention {
+            rule: rule.name.clone(),
+        };
+        schedules.push((schedule, job));
+    }
+
+    if RULES.set(rules).is_err() {
+        warn!("Attempt to reload the retention rules ignored");
+        return Ok(Vec::new());
+    }
+    Ok(schedules)
+}
+
+fn rules() -> &'static [Rule] {
+    RULES.get().map_or(&[], Vec::as_slice)
+}
+
+/// Reports what each rule would remove now.
+pub async fn report(db: Db) -> Result<Vec<RuleReport>, AppError> {
+    let mut reports = Vec::with_capacity(rules().len());
+    for rule in rules() {
+        reports.push(RuleReport {
+            name: &rule.name,
+            target: rule.target,
+            action: rule.target.action(),
+            older_than_days: rule.older_than_days,
+            schedule: rule.schedule.as_deref().unwrap_or(DEFAULT_SCHEDULE),
+            dry_run: rule.dry_run,
+            matching: PgRetentionRepo(db)
+                .count(rule.target, rule.older_than_days)
+                .await?,
+        });
+    }
+    Ok(reports)
+}
+
+/// Runs the rule `name` as the job `id`, reporting its rows in the job progress.
+pub async fn apply(id: i64, name: &str) -> Result<(), AppError> {
+    // Left in the queue by a configuration that had it
+    let Some(rule) = rules().iter().find(|rule| rule.name == name) else {
+        warn!("Retention rule '{}' is no longer configured", name);
+        return Ok(());
+    };
+    let days = rule.older_than_days;
+
+    if rule.dry_run {
+        let matching = PgRetentionRepo::default().count(rule.target, days).await?;
+        PgJobRepo::default()
+            .set_progress(id, &json!({"dry_run": true, "matching": matching}))
+            .await?;
+        metrics::observe_retention_rows(name, "dry_run", matching.unsigned_abs());
+        metrics::observe_retention_run(name);
+        info!(
+            "Retention rule '{}' would {} {} rows",
+            name,
+            rule.target.action(),
+            matching
+        );
+        return Ok(());
+    }
+
+    let mut removed = 0;
+    loop {
+        let batch = match rule.target {
+            Target::InactiveUsers => {
+                let users = PgRetentionRepo::default()
+                    .anonymize_batch(days, RETENTION_BATCH)
+                    .await?;
+                for user in &users {
+                    events::publish(Collection::Users, Action::Updated, user.id, user.public_id);
+                    if let Some(location) = &user.avatar_path
+                        && let Err(e) = store().delete(location).await
+                    {
+                        warn!("Avatar of anonymized user {} not deleted: {}", user.id, e);
+                    }
+                }
+                users.len() as u64
+            }
+            target => {
+                PgRetentionRepo::default()
+                    .delete_batch(target, days, RETENTION_BATCH)
+                    .await?
+            }
+        };
+        removed += batch;
+        // The count is kept by a run failing in a later batch
+        metrics::observe_retention_rows(name, rule.target.action(), batch);
+        PgJobRepo::default()
+            .set_progress(id, &json!({"dry_run": false, "removed": removed}))
+            .await?;
+        if batch < RETENTION_BATCH as u64 {
+            break;
+        }
+    }
+    info!(
+        "Retention rule '{}' ({}) removed {} rows",
+        name,
+        rule.target.action(),
+        removed
+    );
+    metrics::observe_retention_run(name);
+    Ok(())
+}
@@ -0,0 +1,128 @@
+//! Roles of the users.
+//!
+//! Every user has a role, stored with them (`users.role`): `viewer` (the default),
+//! `editor` or `admin`, each allowed what the ones below it are. A route registered with
+//! `Router::require_role` is only handled for the callers with that role or a higher
+//! one; the others get a 403 naming both roles:
+//!
+//! ```json
+//! {"code": "INSUFFICIENT_ROLE", "message": "The route requires the editor role",
+//!  "details": {"required_role": "editor", "role": "viewer"}}
+//! ```
+//!
+//! The product writes and the coupons require `editor`, the `/admin` routes, the
+//! histories and change feeds of the audit log, the bulk deletes and the hard deletes of
+//! the users, and the credits granted and the refunds of the ledger `admin`.
+//! The role is read from the database on each request (the last one read while the
+//! primary is down) instead of being carried by the token, so a change applies at
+//! once, to the tokens already issued too.
+//! `PUT /admin/users/:id/role` changes it, and `create-admin` creates the first
+//! administrator.
+
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::sync::{LazyLock, Mutex};
+
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::AuthUser;
+use crate::db::Db;
+use crate::error::AppError;
+use crate::repository::users::{PgUserRepo, UserRepository};
+
+/// Role of a user, ordered from the least to the most allowed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Editor,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "viewer" => Ok(Role::Viewer),
+            "editor" => Ok(Role::Editor),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("Unknown role '{}'", s)),
+        }
+    }
+}
+
+/// Roles remembered for when the primary is down, the least recently checked forgotten
+/// first
+const KNOWN_ROLES_CAPACITY: NonZeroUsize = NonZeroUsize::new(10_000).unwrap();
+
+// The last role read of each caller
+static KNOWN_ROLES: LazyLock<Mutex<LruCache<i32, Role>>> =
+    LazyLock::new(|| Mutex::new(LruCache::new(KNOWN_ROLES_CAPACITY)));
+
+/// Checks that the caller has `required` or a higher role.
+///
+/// While the primary is down, the role last read for the caller is used, so the writes
+/// queued offline are checked before being queued (see `offline_queue`), and again
+/// against the current role once replayed. A caller whose role was never read gets the
+/// `AppError::Unavailable` of the primary.
+///
+/// # Returns
+///
+/// * `Result<(), AppError>` - `AppError::Forbidden` if the role of the caller is lower,
+///   `AppError::Unauthorized` if their account no longer exists
+pub async fn require(db: Db, user: AuthUser, required: Role) -> Result<(), AppError> {
+    let role = match PgUserRepo(db).find_role(user.id).await {
+        Ok(Some(role)) => {
+            KNOWN_ROLES.lock().unwrap().put(user.id, role);
+            role
+        }
+        Ok(None) => {
+            KNOWN_ROLES.lock().unwrap().pop(&user.id);
+            return Err(AppError::Unauthorized(
+                "The account of the token no longer exists".to_string(),
+            ));
+        }
+        Err(e @ AppError::Unavailable(..)) => {
+            let known = KNOWN_ROLES.lock().unwrap().get(&user.id).copied();
+            known.ok_or(e)?
+        }
+        Err(e) => return Err(e),
+    };
+    if role < required {
+        return Err(AppError::Forbidden(required, role));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn higher_roles_are_allowed_what_the_lower_ones_are() {
+        assert!(Role::Admin > Role::Editor && Role::Editor > Role::Viewer);
+        for role in [Role::Viewer, Role::Editor, Role::Admin] {
+            assert_eq!(role.as_str().parse::<Role>(), Ok(role));
+        }
+        assert!("root".parse::<Role>().is_err());
+    }
+}
@@ -1,20 +1,104 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Instant;
 
-use http_body_util::BodyExt;
+use bb8_postgres::tokio_postgres::error::SqlState;
+use futures_util::{FutureExt, Stream, StreamExt, stream};
+use http_body_util::{BodyExt, Either, Full, LengthLimitError, Limited};
 use hyper::{
-    Method, Request, Response, StatusCode,
-    body::{Buf, Incoming},
-    header::CONTENT_TYPE,
+    Method, Request, Response, StatusCode, Uri,
+    body::{Body as _, Bytes, Incoming},
+    header::{
+        ALLOW, CONTENT_LENGTH, CONTENT_TYPE, HeaderValue, LINK, LOCATION, ORIGIN, RETRY_AFTER,
+        WWW_AUTHENTICATE,
+    },
 };
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde::{Serialize, de::DeserializeOwned};
+use tracing::{Instrument, error, info, info_span, warn};
 
-use crate::db::get_connection;
+use crate::auth::authenticate;
+use crate::config::TrailingSlash;
+use crate::context::{Locale, RequestContext};
+use crate::db::{CONSISTENCY_TOKEN_HEADER, Db, Reads, consistency_token, request_scope};
+use crate::error::{AppError, ErrorBody, ErrorCode};
+use crate::error_reporting::{self, ErrorReport, ReportKind};
+use crate::experiments::{Assignments, assign_experiments, expose};
+use crate::forwarded::{ClientInfo, resolve_client};
+use crate::geo_policy::enforce_geo_policies;
+use crate::geoip::attach_geo_info;
+use crate::legacy_proxy::{self, is_proxied};
+use crate::logging::RequestId;
+use crate::metrics;
+use crate::offline_queue::{self, Offer};
+use crate::region::{apply_region_header, redirect_to_home_region};
+use crate::roles::{self, Role};
+use crate::routes::build_router;
+use crate::signed_urls;
+use crate::static_files;
+use crate::tls::answer_http_challenge;
+use crate::validation::Validate;
+
+use app_headers::AppHeader;
+pub use body::{Body, BoxError, ResponseBody};
+
+pub mod app_headers;
+mod body;
+pub mod bulk;
+pub mod conditional;
+pub mod cors;
+pub mod error_pages;
+pub mod limits;
+pub mod multipart;
+mod negotiation;
+pub mod query;
+pub mod rate_limit;
+
+// Static global router, built once on the first request
+// Routes are registered at startup and never change afterwards
+static ROUTER: OnceLock<Router> = OnceLock::new();
+
+// Set once at startup (`TRAILING_SLASH`)
+static TRAILING_SLASH: OnceLock<TrailingSlash> = OnceLock::new();
+
+/// Entry point of the HTTP service.
+///
+/// Runs [`process_request_and_response`] and post-processes its response according to
+/// the `Accept` and `Accept-Encoding` headers of the request (CSV lists, gzip/brotli
+/// compression, see the `negotiation` module). The response to a `HEAD` request loses
+/// its body last, so its headers are the ones of the `GET` response.
+pub async fn serve_request(req: Request<Incoming>) -> Result<Response<ResponseBody>, Infallible> {
+    let prefs = negotiation::Preferences::from_request(&req);
+    let head = req.method() == Method::HEAD;
+    let res = process_request_and_response(req).await?;
+
+    let res = error_pages::render(prefs.prefers_html(), res);
+    let res = negotiation::negotiate(&prefs, res);
+    Ok(if head { without_body(res) } else { res })
+}
+
+/// The response without its body, announcing its length when it's known.
+fn without_body(res: Response<ResponseBody>) -> Response<ResponseBody> {
+    let (mut parts, body) = res.into_parts();
+    if let Some(length) = body.size_hint().exact()
+        && !parts.headers.contains_key(CONTENT_LENGTH)
+    {
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(length));
+    }
+    Response::from_parts(parts, Either::Left(Full::default()))
+}
 
 /// Processes incoming HTTP requests and routes them to the appropriate handler.
 ///
-/// This function serves as the main router for the HTTP server, examining the request
-/// method and path to determine which handler should process the request.
+/// This function serves as the entry point for the HTTP server. It delegates the
+/// method/path matching to the global [`Router`], built once by `routes::build_router`.
 ///
 /// # Arguments
 ///
@@ -25,32 +109,781 @@ use crate::db::get_connection;
 /// A `Result` containing the HTTP response or an `Infallible` error type
 /// (which means the function will never return an error).
 ///
-/// # Implemented Routes
+/// # Middlewares
 ///
-/// - `GET /`: Basic greeting message
-/// - `GET /users`: List all users (currently returns empty list)
-/// - `POST /users`: Create a new user with JSON data
-/// - `GET /users/{id}`: Get information for a specific user
-/// - `GET /products`: Get all products (currently returns a mock error)
+/// Run in order around routing:
+/// - Client: resolves the address and scheme of the client, through the trusted
+///   proxies (see the `forwarded` module)
+/// - Tracing: assigns a `RequestId`, opens a span for the request and logs
+///   method, path, status and latency once the response is ready
+/// - Context: attaches the `RequestContext` of the request, read by its handler (see
+///   the `context` module)
+/// - Headers: emits the `X-App-*` headers registered on the response by the other
+///   layers and the handlers (see the `app_headers` module)
+/// - Metrics: counts the request and observes its latency (see the `metrics` module)
+/// - ACME challenges: answers the certificate authority validating a domain (see
+///   `tls::acme`)
+/// - CORS: answers preflight requests and adds the `Access-Control-*` headers
+///   to every response (see the `cors` module)
+/// - Rate limiting: rejects clients exceeding their quota with 429 and adds the
+///   `X-App-RateLimit-*` headers (see the `rate_limit` module)
+/// - GeoIP lookup: attaches the client `GeoInfo` to the request extensions
+/// - Home region: redirects clients living in another region there, and names the
+///   region serving in `X-App-Region` (see the `region` module)
+/// - Geo policies: blocks or restricts requests according to the client region
+/// - Legacy proxy: forwards the requests under `LEGACY_PATH_PREFIX` to the legacy
+///   service instead of routing them (see the `legacy_proxy` module)
+/// - Experiments: assigns the request a variant of every experiment, sent back in
+///   `X-Experiments` (see the `experiments` module)
+/// - Consistency: sends the reads of the request to the read replica or the primary,
+///   and returns a consistency token after a write (see `db::consistency`)
 ///
-/// # Examples
+/// # Implemented Routes
 ///
-/// All routes return JSON responses except for the root path.
+/// See the `routes` module for the full route table.
 pub async fn process_request_and_response(
-    req: Request<Incoming>,
-) -> Result<Response<String>, Infallible> {
-    let res = match (req.method(), req.uri().path()) {
-        (&Method::GET, "/") => Response::new("Hello World".to_owned()),
-        (&Method::GET, "/users") => handle_get_all_users().await,
-        (&Method::GET, path) if path.starts_with("/users/") => handle_get_user(req).await,
-        (&Method::POST, "/users") => handle_create_user(req).await,
-        (&Method::GET, "/products") => handle_get_all_products().await,
-        _ => json_response(StatusCode::NOT_FOUND, json!({"message": "Not found"})),
+    mut req: Request<Incoming>,
+) -> Result<Response<Body>, Infallible> {
+    let router = router();
+
+    // Every log line emitted while handling the request is attached to this span,
+    // so they can all be found by request ID
+    let request_id = RequestId::from_request(&req);
+    resolve_client(&mut req);
+    let span = info_span!(
+        "request",
+        request_id = %request_id.0,
+        method = %req.method(),
+        path = %req.uri().path(),
+        client = tracing::field::Empty,
+    );
+    if let Some(client) = ClientInfo::of(&req) {
+        span.record("client", tracing::field::display(client.ip));
+    }
+    let method = req.method().clone();
+    let origin = req.headers().get(ORIGIN).cloned();
+
+    let start = Instant::now();
+    // Error responses built anywhere below read the request ID from the scope, and
+    // the tasks spawned by the handler inherit it (see `context`); the handler reads
+    // the context from the request, completed with its caller once authenticated
+    let context = RequestContext {
+        request_id: Some(request_id.clone()),
+        locale: Locale::from_request(&req),
+        ..RequestContext::default()
+    };
+    let deadline = start + limits::request_timeout();
+    req.extensions_mut().insert(RequestContext {
+        deadline: Some(deadline),
+        db: Db::until(deadline),
+        ..context.clone()
+    });
+    let mut limit = None;
+    let handling = async {
+        // The CA validating a domain is subject to no policy
+        if let Some(answer) = answer_http_challenge(&req) {
+            return answer;
+        }
+        // Preflights carry no credentials nor consent, answer them before any policy
+        if let Some(preflight) = cors::preflight(&req) {
+            return preflight;
+        }
+        limit = rate_limit::check_rate_limit(&req).await;
+        if let Some(decision) = &limit
+            && !decision.allowed
+        {
+            return error_response(rate_limit::rate_limited(decision));
+        }
+        attach_geo_info(&mut req);
+        // The home region applies its own policies
+        if let Some(redirect) = redirect_to_home_region(&req) {
+            return redirect;
+        }
+        if let Some(rejection) = enforce_geo_policies(&mut req) {
+            return rejection;
+        }
+        // Routes not migrated yet, answered by the legacy service
+        if is_proxied(&req) {
+            return legacy_proxy::forward(req).await;
+        }
+        assign_experiments(&mut req);
+        let assignments = Assignments::of(&req).cloned();
+        let reads = match Reads::of_request(&req) {
+            Ok(reads) => reads,
+            Err(e) => return error_response(e),
+        };
+        let mut res = reads.scope(router.dispatch(req)).await;
+        if let Some(assignments) = &assignments {
+            expose(assignments, res.headers_mut());
+        }
+        if !method.is_safe()
+            && res.status().is_success()
+            && let Some(token) = consistency_token().await
+        {
+            res.headers_mut().insert(CONSISTENCY_TOKEN_HEADER, token);
+        }
+        res
     };
+    let mut res = context.scope(handling.instrument(span.clone())).await;
+
+    // Echo the request ID so clients can report it
+    if let Some(value) = request_id.header_value() {
+        app_headers::set(&mut res, AppHeader::RequestId, value);
+    }
+    apply_region_header(&mut res);
+    if let Some(decision) = &limit {
+        rate_limit::apply_rate_limit_headers(decision, &mut res);
+    }
+    app_headers::emit(&mut res);
+    cors::apply_cors_headers(origin.as_ref(), res.headers_mut());
+
+    let elapsed = start.elapsed();
+    span.in_scope(|| {
+        info!(
+            status = res.status().as_u16(),
+            latency_ms = elapsed.as_secs_f64() * 1000.0,
+            "request completed"
+        )
+    });
+
+    let report = res.extensions_mut().remove::<ErrorReport>();
+    let route = res
+        .extensions()
+        .get::<MatchedRoute>()
+        .map_or(metrics::UNMATCHED_ROUTE, |route| route.0.as_str());
+    metrics::observe_request(
+        &method,
+        route,
+        res.status().as_u16(),
+        elapsed,
+        &request_id.0,
+    );
+    // Server errors are reported with the request they answer (see `error_reporting`)
+    if let Some(mut report) = report {
+        report.request_id = Some(request_id);
+        report.method = Some(method.to_string());
+        report.route = Some(route.to_string());
+        report.status = Some(res.status().as_u16());
+        error_reporting::report_error(report);
+    }
 
     Ok(res)
 }
 
+/// The router of the API, built on first use by `routes::build_router`.
+pub fn router() -> &'static Router {
+    ROUTER.get_or_init(build_router)
+}
+
+/// Sets how the paths with a trailing slash are routed (`TRAILING_SLASH`).
+/// This function should be called at application startup, before serving requests.
+pub fn init_trailing_slash(mode: TrailingSlash) {
+    if TRAILING_SLASH.set(mode).is_err() {
+        warn!("Attempt to reset the trailing slash mode ignored");
+    }
+}
+
+fn trailing_slash() -> TrailingSlash {
+    *TRAILING_SLASH
+        .get()
+        .expect("Trailing slash mode is not initialized")
+}
+
+/// Pattern of the route that produced a response (`/users/:id`),
+/// inserted in the response extensions by [`Router::dispatch`].
+#[derive(Debug, Clone)]
+pub struct MatchedRoute(pub String);
+
+/// Address of the TCP peer of the connection, inserted in the request extensions
+/// by the accept loop. Behind a reverse proxy the peer is the proxy: middlewares and
+/// handlers wanting the client use the `ClientInfo` resolved from it.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr(pub SocketAddr);
+
+// ==================== ROUTER ====================
+
+/// Result returned by every handler.
+/// Errors are translated into JSON responses by the router (see [`error_response`]).
+///
+/// The body is usually buffered (`json_response`), long-lived or large responses
+/// use a streamed [`Body`].
+pub type HandlerResult = Result<Response<Body>, AppError>;
+
+/// Boxed future returned by every handler.
+/// Boxing allows storing handlers with different concrete future types in the same table.
+pub type HandlerFuture = Pin<Box<dyn Future<Output = HandlerResult> + Send>>;
+
+/// A request handler that can be registered in the [`Router`].
+///
+/// Implemented automatically for any async function with the signature
+/// `async fn(Request<Incoming>, Params) -> HandlerResult`.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, req: Request<Incoming>, params: Params) -> HandlerFuture;
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(Request<Incoming>, Params) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = HandlerResult> + Send + 'static,
+{
+    fn call(&self, req: Request<Incoming>, params: Params) -> HandlerFuture {
+        Box::pin(self(req, params))
+    }
+}
+
+/// Path parameters extracted from the matched route (e.g. `:id` in `/users/:id`).
+#[derive(Debug, Default, Clone)]
+pub struct Params {
+    values: HashMap<String, String>,
+}
+
+impl Params {
+    /// Returns the raw value of a path parameter.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    /// Parses a path parameter into any type implementing `FromStr`
+    /// (`i32`, `String`, `Uuid`, ...).
+    ///
+    /// # Returns
+    ///
+    /// * `Option<T>` - `None` if the parameter is missing or cannot be parsed
+    pub fn parse<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.get(name).and_then(|value| value.parse::<T>().ok())
+    }
+}
+
+// Path parameters of a request built by the server itself (see `routes::tools`)
+impl FromIterator<(String, String)> for Params {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(values: I) -> Self {
+        Params {
+            values: values.into_iter().collect(),
+        }
+    }
+}
+
+/// A single segment of a route pattern.
+enum Segment {
+    /// Must match the path segment exactly (`users`)
+    Static(String),
+    /// Matches any non-empty path segment and captures it (`:id`)
+    Param(String),
+    /// Last segment of a pattern: matches the rest of the path, one or more non-empty
+    /// segments, and captures them joined by `/` (`*path`)
+    Rest(String),
+}
+
+struct Route {
+    method: Method,
+    /// Pattern the route was registered with (`/users/:id`), used as metrics label
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: Box<dyn Handler>,
+    /// Whether a valid bearer token is required (see `Router::require_auth`)
+    requires_auth: bool,
+    /// Lowest role of the callers handled (see `Router::require_role`)
+    required_role: Option<Role>,
+    /// Whether a signed URL may replace the token (see `Router::allow_signed_urls`)
+    accepts_signed_urls: bool,
+    /// Whether the writes are queued while the database is down (see
+    /// `Router::queue_when_offline`)
+    queues_when_offline: bool,
+}
+
+impl Route {
+    /// Tries to match the route against the path segments of a request.
+    /// Returns the captured parameters if the route matches.
+    fn matches(&self, path: &[&str]) -> Option<Params> {
+        let rest = matches!(self.segments.last(), Some(Segment::Rest(_)));
+        if self.segments.len() != path.len() && !(rest && path.len() > self.segments.len()) {
+            return None;
+        }
+
+        let mut params = Params::default();
+        for (i, (segment, value)) in self.segments.iter().zip(path).enumerate() {
+            match segment {
+                Segment::Static(expected) if expected == value => {}
+                Segment::Static(_) => return None,
+                Segment::Param(_) if value.is_empty() => return None,
+                Segment::Param(name) => {
+                    params.values.insert(name.clone(), value.to_string());
+                }
+                Segment::Rest(_) if path[i..].iter().any(|value| value.is_empty()) => {
+                    return None;
+                }
+                Segment::Rest(name) => {
+                    params.values.insert(name.clone(), path[i..].join("/"));
+                }
+            }
+        }
+
+        Some(params)
+    }
+
+    /// Runs the route and marks the response with its pattern.
+    async fn respond(&self, req: Request<Incoming>, params: Params) -> Response<Body> {
+        let mut res = self.run(req, params).await;
+        // Lets the outer layers (metrics) know which route answered
+        res.extensions_mut()
+            .insert(MatchedRoute(self.pattern.clone()));
+        res
+    }
+
+    /// Runs the handler of the route, with the authentication check if required.
+    async fn run(&self, mut req: Request<Incoming>, params: Params) -> Response<Body> {
+        // Authentication middleware for protected routes and signed URLs. A replayed
+        // write keeps the caller it was queued by, whose token may have expired since.
+        let mut user = None;
+        let replay = req.extensions().get::<offline_queue::Replay>().copied();
+        let authenticated = if let Some(replay) = replay {
+            replay.user.map(Ok)
+        } else if self.accepts_signed_urls && signed_urls::is_signed(&req) {
+            Some(signed_urls::verify(&req))
+        } else if self.requires_auth {
+            Some(authenticate(&req))
+        } else {
+            None
+        };
+        if let Some(authenticated) = authenticated {
+            match authenticated {
+                Ok(authenticated) => {
+                    req.extensions_mut()
+                        .get_or_insert_default::<RequestContext>()
+                        .user = Some(authenticated);
+                    user = Some(authenticated);
+                }
+                Err(e) => return error_response(e),
+            }
+        }
+
+        // Right after authentication, before anything is done for the request: against
+        // the role the caller has when the request is handled, a queued write once
+        // replayed too
+        if let (Some(required), Some(user)) = (self.required_role, user)
+            && let Err(e) = roles::require(RequestContext::of(&req).db, user, required).await
+        {
+            return error_response(e);
+        }
+
+        // While the database is down, the writes of the routes opting in are queued
+        // instead of failing (see `offline_queue`)
+        if self.queues_when_offline && replay.is_none() {
+            req = match offline_queue::offer(req, user).await {
+                Offer::Handle(req) => req,
+                Offer::Queued(result) => return result.unwrap_or_else(error_response),
+            };
+        }
+
+        // The timeout of the route, `REQUEST_TIMEOUT` unless `ROUTE_TIMEOUTS` names it
+        let timeout = limits::route_timeout(&self.method, &self.pattern);
+        let deadline = Instant::now() + timeout;
+        let context = req
+            .extensions_mut()
+            .get_or_insert_default::<RequestContext>();
+        context.deadline = Some(deadline);
+        context.db = Db::until(deadline);
+
+        // A panic inside a handler is caught and reported as a 500 response
+        // instead of dropping the connection without an answer. The repository calls
+        // of the handler share their database connection (see `db::request_scope`)
+        // and know its caller (see `AuthUser::current`).
+        let handler = request_scope(self.handler.call(req, params));
+        let handler = async move {
+            match user {
+                Some(user) => user.scope(handler).await,
+                None => handler.await,
+            }
+        };
+        let handler = AssertUnwindSafe(handler).catch_unwind();
+
+        // A handler running past the deadline is dropped, PostgreSQL cancels its
+        // statements at the same time (see `db::deadline`)
+        let result = match tokio::time::timeout_at(deadline.into(), handler).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => {
+                // Already reported by the panic hook, with its location
+                let mut res = error_response(AppError::Internal("Handler panicked".to_string()));
+                res.extensions_mut().remove::<ErrorReport>();
+                return res;
+            }
+            Err(_) => Err(AppError::Timeout(format!(
+                "The request took longer than {} seconds",
+                timeout.as_secs()
+            ))),
+        };
+
+        result.unwrap_or_else(error_response)
+    }
+}
+
+/// A registered route, as listed by [`Router::routes`].
+#[derive(Debug, Clone, Copy)]
+pub struct RouteInfo<'a> {
+    pub method: &'a Method,
+    /// Pattern the route was registered with, including the prefix of `nest`
+    pub pattern: &'a str,
+    pub requires_auth: bool,
+    pub required_role: Option<Role>,
+    pub accepts_signed_urls: bool,
+    pub queues_when_offline: bool,
+}
+
+/// Table of routes with parameterized path segments.
+///
+/// Routes are registered with a builder-style API and matched in registration order:
+///
+/// ```ignore
+/// let router = Router::new()
+///     .get("/users", handle_get_all_users)
+///     .get("/users/:id", handle_get_user);
+/// ```
+///
+/// Versions of the API are separate routers mounted side by side with [`Router::nest`].
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    /// Prefix tried for requests matching no route (see [`Router::legacy_prefix`])
+    legacy_prefix: Option<String>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a handler for the given method and path pattern.
+    /// Segments starting with `:` are captured as parameters, and a last segment
+    /// starting with `*` captures the rest of the path (`/static/*path`).
+    pub fn route(mut self, method: Method, pattern: &str, handler: impl Handler) -> Self {
+        let segments = split_path(pattern)
+            .into_iter()
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = segment.strip_prefix('*') {
+                    Segment::Rest(name.to_string())
+                } else {
+                    Segment::Static(segment.to_string())
+                }
+            })
+            .collect();
+
+        self.routes.push(Route {
+            method,
+            pattern: pattern.to_string(),
+            segments,
+            handler: Box::new(handler),
+            requires_auth: false,
+            required_role: None,
+            accepts_signed_urls: false,
+            queues_when_offline: false,
+        });
+        self
+    }
+
+    /// Mounts every route of `router` under `prefix`.
+    ///
+    /// ```ignore
+    /// Router::new()
+    ///     .nest("/api/v1", v1::routes())
+    ///     .nest("/api/v2", v2::routes())
+    /// ```
+    pub fn nest(mut self, prefix: &str, router: Router) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        for mut route in router.routes {
+            route.pattern = format!("{}{}", prefix, route.pattern);
+            route.segments = split_path(prefix)
+                .into_iter()
+                .map(|segment| Segment::Static(segment.to_string()))
+                .chain(route.segments)
+                .collect();
+            self.routes.push(route);
+        }
+        self
+    }
+
+    /// Keeps the paths of an unversioned API working after its routes moved under
+    /// `prefix`: a request matching no route is dispatched again with the prefix added
+    /// to its path, and the response tells the client where the route moved
+    /// (`Deprecation: true` and a `Link` with `rel="successor-version"`).
+    pub fn legacy_prefix(mut self, prefix: &str) -> Self {
+        self.legacy_prefix = Some(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Requires authentication on the last registered route.
+    ///
+    /// The router validates the bearer token before calling the handler, answers
+    /// 401 Unauthorized when it's missing or invalid, and otherwise inserts the
+    /// `AuthUser` in the request extensions.
+    ///
+    /// ```ignore
+    /// Router::new().post("/users", handle_create_user).require_auth()
+    /// ```
+    pub fn require_auth(mut self) -> Self {
+        if let Some(route) = self.routes.last_mut() {
+            route.requires_auth = true;
+        }
+        self
+    }
+
+    /// Requires a role on the last registered route, and authentication with it.
+    ///
+    /// The router reads the role of the caller once authenticated, and answers 403
+    /// Forbidden with `INSUFFICIENT_ROLE` when it's lower than `role` (see `roles`).
+    ///
+    /// ```ignore
+    /// Router::new().post("/products", handle_create_product).require_role(Role::Editor)
+    /// ```
+    pub fn require_role(mut self, role: Role) -> Self {
+        if let Some(route) = self.routes.last_mut() {
+            route.requires_auth = true;
+            route.required_role = Some(role);
+        }
+        self
+    }
+
+    /// Accepts signed URLs on the last registered route, a `GET` one.
+    ///
+    /// The router checks the signature of the requests sent to a signed URL, answers
+    /// 401 Unauthorized when it's invalid or expired, and otherwise handles them as
+    /// requests of the user who signed it, with or without `require_auth` (see
+    /// `signed_urls`).
+    ///
+    /// ```ignore
+    /// Router::new().get("/reports/:id", handle_get_report).require_auth().allow_signed_urls()
+    /// ```
+    pub fn allow_signed_urls(mut self) -> Self {
+        if let Some(route) = self.routes.last_mut() {
+            route.accepts_signed_urls = true;
+        }
+        self
+    }
+
+    /// Queues the writes of the last registered route while the database is down,
+    /// a route whose requests can be applied twice (`PUT`, `DELETE`).
+    ///
+    /// With `OFFLINE_QUEUE_PATH`, a request sent with an `Idempotency-Key` while the
+    /// breaker of the primary is open is answered with 202 Accepted, and handled once
+    /// the database is back (see `offline_queue`).
+    ///
+    /// ```ignore
+    /// Router::new()
+    ///     .put("/wishlist/:product_id", handle_wish)
+    ///     .require_auth()
+    ///     .queue_when_offline()
+    /// ```
+    pub fn queue_when_offline(mut self) -> Self {
+        if let Some(route) = self.routes.last_mut() {
+            route.queues_when_offline = true;
+        }
+        self
+    }
+
+    pub fn get(self, pattern: &str, handler: impl Handler) -> Self {
+        self.route(Method::GET, pattern, handler)
+    }
+
+    pub fn post(self, pattern: &str, handler: impl Handler) -> Self {
+        self.route(Method::POST, pattern, handler)
+    }
+
+    pub fn put(self, pattern: &str, handler: impl Handler) -> Self {
+        self.route(Method::PUT, pattern, handler)
+    }
+
+    pub fn patch(self, pattern: &str, handler: impl Handler) -> Self {
+        self.route(Method::PATCH, pattern, handler)
+    }
+
+    pub fn delete(self, pattern: &str, handler: impl Handler) -> Self {
+        self.route(Method::DELETE, pattern, handler)
+    }
+
+    /// Every route, in registration order (the API description is built from them).
+    pub fn routes(&self) -> impl Iterator<Item = RouteInfo<'_>> {
+        self.routes.iter().map(|route| RouteInfo {
+            method: &route.method,
+            pattern: &route.pattern,
+            requires_auth: route.requires_auth,
+            required_role: route.required_role,
+            accepts_signed_urls: route.accepts_signed_urls,
+            queues_when_offline: route.queues_when_offline,
+        })
+    }
+
+    /// Whether the `GET` route serving `path` accepts signed URLs.
+    pub fn accepts_signed_url(&self, path: &str) -> bool {
+        let segments = split_path(path);
+        self.routes
+            .iter()
+            .filter(|route| route.method == Method::GET)
+            .find(|route| route.matches(&segments).is_some())
+            .is_some_and(|route| route.accepts_signed_urls)
+    }
+
+    /// Finds the first route matching the request and runs its handler.
+    /// Handler errors and unknown routes are turned into JSON error responses.
+    ///
+    /// Besides the registered routes:
+    /// - a path with a trailing slash (`/users/`) is redirected to the path without it,
+    ///   or served as that path (see `TrailingSlash`), when only that one has routes
+    /// - `HEAD` is answered by the `GET` route of the path when it has no `HEAD` route
+    /// - a path with routes for other methods only is answered 405 Method Not Allowed,
+    ///   with its methods in `Allow`
+    /// - with `SPA_FALLBACK`, a browser asking for a path without routes gets the
+    ///   `index.html` of the static files (see `static_files`)
+    pub async fn dispatch(&self, mut req: Request<Incoming>) -> Response<Body> {
+        if let Some(uri) = trimmed_uri(req.uri())
+            && !self.has_routes(req.uri().path())
+            && self.has_routes(uri.path())
+        {
+            match trailing_slash() {
+                TrailingSlash::Redirect => {
+                    let mut res = empty_response(StatusCode::PERMANENT_REDIRECT);
+                    if let Ok(location) = HeaderValue::from_str(&uri.to_string()) {
+                        res.headers_mut().insert(LOCATION, location);
+                    }
+                    return res;
+                }
+                TrailingSlash::Match => *req.uri_mut() = uri,
+            }
+        }
+
+        if let Some((route, params)) = self.find(&req) {
+            return route.respond(req, params).await;
+        }
+        let mut allowed = self.allowed_methods(req.uri().path());
+
+        if let Some(prefix) = &self.legacy_prefix
+            && let Some(uri) = prefixed_uri(prefix, req.uri())
+        {
+            let old_path = req.uri().path().to_owned();
+            let new_path = uri.path().to_owned();
+            // Handlers see the current path, e.g. in the pagination links
+            let original = std::mem::replace(req.uri_mut(), uri);
+            if let Some((route, params)) = self.find(&req) {
+                info!("Deprecated path {} served by {}", old_path, route.pattern);
+                let mut res = route.respond(req, params).await;
+                let headers = res.headers_mut();
+                headers.insert("deprecation", HeaderValue::from_static("true"));
+                if let Ok(link) =
+                    HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", new_path))
+                {
+                    headers.append(LINK, link);
+                }
+                return res;
+            }
+            if allowed.is_empty() {
+                allowed = self.allowed_methods(req.uri().path());
+            }
+            *req.uri_mut() = original;
+        }
+
+        if !allowed.is_empty() {
+            return error_response(AppError::MethodNotAllowed(allowed));
+        }
+        // The client-side routes of a single-page application
+        let prefers_html = negotiation::Preferences::from_request(&req).prefers_html();
+        if let Some(result) = static_files::spa_fallback(&req, prefers_html).await {
+            return result.unwrap_or_else(error_response);
+        }
+        error_response(AppError::NotFound(
+            ErrorCode::RouteNotFound,
+            "Not found".to_string(),
+        ))
+    }
+
+    /// The first route matching the method and path of a request, with its parameters.
+    /// The `GET` routes match `HEAD` requests too, after the `HEAD` routes.
+    fn find<B>(&self, req: &Request<B>) -> Option<(&Route, Params)> {
+        let segments = split_path(req.uri().path());
+        let find = |method: &Method| {
+            self.routes
+                .iter()
+                .filter(|route| route.method == method)
+                .find_map(|route| Some((route, route.matches(&segments)?)))
+        };
+        find(req.method()).or_else(|| match *req.method() {
+            Method::HEAD => find(&Method::GET),
+            _ => None,
+        })
+    }
+
+    /// The methods of the routes matching `path`, in registration order, with `HEAD`
+    /// after `GET`.
+    fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let segments = split_path(path);
+        let mut methods = Vec::new();
+        for route in &self.routes {
+            if methods.contains(&route.method) || route.matches(&segments).is_none() {
+                continue;
+            }
+            methods.push(route.method.clone());
+            if route.method == Method::GET {
+                methods.push(Method::HEAD);
+            }
+        }
+        methods
+    }
+
+    /// Whether some route matches `path`, as it is or with the legacy prefix.
+    fn has_routes(&self, path: &str) -> bool {
+        let known = |path: &str| {
+            let segments = split_path(path);
+            self.routes
+                .iter()
+                .any(|route| route.matches(&segments).is_some())
+        };
+        known(path)
+            || self
+                .legacy_prefix
+                .as_ref()
+                .is_some_and(|prefix| known(&format!("{}{}", prefix, path)))
+    }
+}
+
+/// The URI without the trailing slashes of its path, keeping the query string.
+/// `None` if the path has none, or is `/`.
+fn trimmed_uri(uri: &Uri) -> Option<Uri> {
+    let path = uri.path();
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.is_empty() || trimmed == path {
+        return None;
+    }
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", trimmed, query),
+        None => trimmed.to_string(),
+    };
+    path_and_query.parse().ok()
+}
+
+/// The URI with `prefix` added to its path, keeping the query string.
+/// `None` if the path already starts with the prefix.
+fn prefixed_uri(prefix: &str, uri: &Uri) -> Option<Uri> {
+    let path = uri.path();
+    if path == prefix || path.starts_with(&format!("{}/", prefix)) {
+        return None;
+    }
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}{}?{}", prefix, path, query),
+        None => format!("{}{}", prefix, path),
+    };
+    path_and_query.parse().ok()
+}
+
+/// Splits a path into its segments, ignoring the leading slash.
+/// `/` becomes an empty list, `/users/1` becomes `["users", "1"]`.
+fn split_path(path: &str) -> Vec<&str> {
+    let trimmed = path.strip_prefix('/').unwrap_or(path);
+    if trimmed.is_empty() {
+        Vec::new()
+    } else {
+        trimmed.split('/').collect()
+    }
+}
+
 // ==================== UTILITY FUNCTIONS ====================
 
 /// Creates a JSON HTTP response with the specified status code and body.
@@ -69,155 +902,287 @@ pub async fn process_request_and_response(
 /// Will panic if:
 /// - The body cannot be serialized to JSON
 /// - The response cannot be built
-fn json_response<T: Serialize>(status: StatusCode, body: T) -> Response<String> {
+pub fn json_response<T: Serialize>(status: StatusCode, body: T) -> Response<Body> {
     Response::builder()
         .status(status)
         .header(CONTENT_TYPE, "application/json")
-        .body(serde_json::to_string(&body).unwrap())
+        .body(Body::from(serde_json::to_string(&body).unwrap()))
         .unwrap()
 }
 
-// ==================== USER ROUTES ====================
-#[derive(Serialize, Deserialize)]
-struct User {
-    name: String,
-    age: i32,
-}
-
-/// Handles GET requests to retrieve all users.
-///
-/// # Route
-///
-/// `GET /users`
+/// Creates a JSON array response sent while `batches` produces its items.
 ///
-/// # Response
-///
-/// Returns a 200 OK response with an empty array of users.
-async fn handle_get_all_users() -> Response<String> {
-    let mut users: Vec<User> = Vec::new(); //vec![];
+/// The status is sent before the first item is read, so an error in the middle of
+/// the stream can't be reported: it is logged and the response is cut short, which
+/// the client sees as an incomplete body.
+pub fn json_stream_response<T, S>(batches: S) -> Response<Body>
+where
+    T: Serialize,
+    S: Stream<Item = Result<Vec<T>, AppError>> + Send + 'static,
+{
+    let mut separator = "";
+    let items = batches.map(move |batch| {
+        let batch = batch.map_err(|e| {
+            error!("Streamed response interrupted: {}", e);
+            BoxError::from(e)
+        })?;
+        let mut chunk = String::new();
+        for item in batch {
+            chunk.push_str(separator);
+            chunk.push_str(&serde_json::to_string(&item)?);
+            separator = ",";
+        }
+        Ok(Bytes::from(chunk))
+    });
 
-    let conn = get_connection().await.unwrap();
-    let rows = conn.query("SELECT * FROM users", &[]).await.unwrap();
+    let body = stream::once(async { Ok(Bytes::from_static(b"[")) })
+        .chain(items)
+        .chain(stream::once(async { Ok(Bytes::from_static(b"]")) }));
 
-    for row in rows {
-        users.push(User {
-            name: row.get("name"),
-            age: row.get("age"),
-        });
-    }
-
-    json_response(StatusCode::OK, users)
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::stream(body))
+        .unwrap()
 }
 
-/// Handles GET requests to retrieve a specific user by ID.
+/// Translates an `AppError` into a JSON error response.
+///
+/// This is the only place where errors are mapped to status codes and error codes, so
+/// every error path of the API produces the same body (see `ErrorBody`). Unexpected
+/// errors are logged with their cause and answered with a generic message, so database
+/// errors and the like don't leak to clients.
 ///
-/// # Route
+/// # Arguments
 ///
-/// `GET /users/{id}` where `{id}` must be a positive integer (u32)
+/// * `err` - The error returned by a handler or by the router itself
 ///
-/// # Response
+/// # Returns
 ///
-/// - 200 OK with user data if the ID is valid
-/// - 400 Bad Request if the ID is not a valid u32
-async fn handle_get_user(req: Request<Incoming>) -> Response<String> {
-    // Extract and validate the ID from the URL
-    let last_segment = req.uri().path().split("/").last().unwrap_or("default");
-    let id: i32 = match last_segment.parse::<i32>() {
-        Ok(id) => id,
-        Err(_) => {
-            return json_response(StatusCode::BAD_REQUEST, json!({"error": "ID must be u32"}));
+/// The HTTP response with the status code matching the error variant
+pub fn error_response(err: AppError) -> Response<Body> {
+    // The errors logged below are reported too, the router adds the request
+    let report = (error_reporting::is_enabled()
+        && matches!(
+            err,
+            AppError::Timeout(_)
+                | AppError::BadGateway(_)
+                | AppError::Pool(_)
+                | AppError::Db(_)
+                | AppError::Internal(_)
+        ))
+    .then(|| ErrorReport::new(ReportKind::Error, err.to_string()));
+    let (status, body) = match err {
+        AppError::Validation(msg) => (
+            StatusCode::BAD_REQUEST,
+            ErrorBody::new(ErrorCode::InvalidRequest, msg),
+        ),
+        // Every invalid field is listed in the details instead of a single message
+        AppError::Unprocessable(errors) => {
+            let body = ErrorBody::new(
+                ErrorCode::ValidationFailed,
+                format!("Invalid fields: {}", errors),
+            )
+            .with_details(serde_json::to_value(errors).unwrap_or_default());
+            (StatusCode::UNPROCESSABLE_ENTITY, body)
+        }
+        AppError::Unauthorized(msg) => {
+            let body = ErrorBody::new(ErrorCode::Unauthorized, msg);
+            // Tell the client which authentication scheme is expected
+            let mut res = json_response(StatusCode::UNAUTHORIZED, body);
+            res.headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return res;
+        }
+        // Both roles in the details, for the client to tell the user what they lack
+        AppError::Forbidden(required, role) => {
+            let body = ErrorBody::new(
+                ErrorCode::InsufficientRole,
+                format!("The route requires the {} role", required),
+            )
+            .with_details(serde_json::json!({"required_role": required, "role": role}));
+            (StatusCode::FORBIDDEN, body)
+        }
+        AppError::NotFound(code, msg) => (StatusCode::NOT_FOUND, ErrorBody::new(code, msg)),
+        AppError::MethodNotAllowed(allowed) => {
+            let allow = allowed
+                .iter()
+                .map(Method::as_str)
+                .collect::<Vec<_>>()
+                .join(", ");
+            let body = ErrorBody::new(
+                ErrorCode::MethodNotAllowed,
+                format!("The path only supports {}", allow),
+            );
+            let mut res = json_response(StatusCode::METHOD_NOT_ALLOWED, body);
+            if let Ok(value) = HeaderValue::from_str(&allow) {
+                res.headers_mut().insert(ALLOW, value);
+            }
+            return res;
+        }
+        AppError::Conflict(code, msg) => (StatusCode::CONFLICT, ErrorBody::new(code, msg)),
+        AppError::PreconditionFailed(msg) => (
+            StatusCode::PRECONDITION_FAILED,
+            ErrorBody::new(ErrorCode::VersionMismatch, msg),
+        ),
+        AppError::PayloadTooLarge(msg) => (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorBody::new(ErrorCode::PayloadTooLarge, msg),
+        ),
+        AppError::PreconditionRequired(msg) => (
+            StatusCode::PRECONDITION_REQUIRED,
+            ErrorBody::new(ErrorCode::PreconditionRequired, msg),
+        ),
+        AppError::TooManyRequests(msg) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            ErrorBody::new(ErrorCode::RateLimited, msg),
+        ),
+        AppError::Timeout(msg) => {
+            error!("Request timed out: {}", msg);
+            (
+                StatusCode::GATEWAY_TIMEOUT,
+                ErrorBody::new(ErrorCode::Timeout, msg),
+            )
+        }
+        AppError::BadGateway(msg) => {
+            error!("Bad gateway: {}", msg);
+            (
+                StatusCode::BAD_GATEWAY,
+                ErrorBody::new(
+                    ErrorCode::UpstreamUnavailable,
+                    "Upstream service unavailable",
+                ),
+            )
+        }
+        AppError::Pool(msg) => {
+            error!("Connection pool error: {}", msg);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorBody::new(ErrorCode::DatabaseUnavailable, "Database unavailable"),
+            )
+        }
+        // Expected while the database is down, the breaker logs it once
+        AppError::Unavailable(msg, retry_after) => {
+            let body = ErrorBody::new(ErrorCode::DatabaseUnavailable, msg);
+            let mut res = json_response(StatusCode::SERVICE_UNAVAILABLE, body);
+            // Whole seconds, rounded up
+            let seconds = retry_after.as_millis().div_ceil(1000).max(1);
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds as u64));
+            return res;
+        }
+        // Cancelled by the `statement_timeout` of the request deadline
+        AppError::Db(e) if e.code() == Some(&SqlState::QUERY_CANCELED) => {
+            error!("Query cancelled at the request deadline: {}", e);
+            (
+                StatusCode::GATEWAY_TIMEOUT,
+                ErrorBody::new(ErrorCode::Timeout, "The request ran past its deadline"),
+            )
+        }
+        AppError::Db(e) => {
+            error!("Database error: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorBody::new(ErrorCode::InternalError, "Internal Server Error"),
+            )
+        }
+        AppError::Internal(msg) => {
+            error!("Internal error: {}", msg);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorBody::new(ErrorCode::InternalError, "Internal Server Error"),
+            )
         }
     };
 
-    let conn = get_connection().await.unwrap();
-    let data = conn
-        .query("SELECT * FROM users WHERE id = $1", &[&id])
-        .await
-        .unwrap();
-
-    if data.is_empty() {
-        return json_response(StatusCode::NOT_FOUND, json!({"message": "User not found"}));
+    let mut res = json_response(status, body);
+    if let Some(report) = report {
+        res.extensions_mut().insert(report);
     }
-
-    let user = User {
-        name: data[0].get(1),
-        age: data[0].get(2),
-    };
-
-    json_response(StatusCode::OK, user)
+    res
 }
 
-/// Handles POST requests to create a new user.
+/// Creates an HTTP response without body (e.g. 204 No Content).
 ///
-/// # Route
+/// # Panics
+///
+/// Will panic if the response cannot be built
+pub fn empty_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::default())
+        .unwrap()
+}
+
+/// Collects the request body and deserializes it from JSON.
 ///
-/// `POST /users`
+/// # Arguments
 ///
-/// # Request Body
-/// Any valid JSON data
+/// * `req` - The incoming HTTP request whose body is consumed
 ///
-/// # Response
+/// # Returns
 ///
-/// - 200 OK with the parsed JSON if valid
-/// - 400 Bad Request if the JSON is malformed or body collection fails
-async fn handle_create_user(req: Request<Incoming>) -> Response<String> {
-    // whole_body is basically a buffer containing all the data from the request body.
-    // Collect all fragments of the request body into a single buffer
-    // The HTTP body may arrive in multiple parts that need to be aggregated
-    let whole_body = match req.collect().await {
-        // aggregate() combines all the chunks into a single buffer.
-        Ok(collected) => collected.aggregate(),
-        Err(_) => {
-            return json_response(
-                StatusCode::BAD_REQUEST,
-                json!({"error": "Failed to collect the request body"}),
-            );
-        }
-    };
+/// * `Result<T, AppError>` - The parsed value, an `AppError::PayloadTooLarge` if the body
+///   exceeds `MAX_BODY_SIZE`, or an `AppError::Validation` if it cannot be collected or parsed
+pub async fn parse_json_body<T: DeserializeOwned>(req: Request<Incoming>) -> Result<T, AppError> {
+    let whole_body = collect_body(req, limits::max_body_size()).await?;
 
     // Attempt to parse the JSON body
-    // chunk() returns a reference to the bytes in the buffer
-    let data = match serde_json::from_slice::<User>(whole_body.chunk()) {
-        Ok(json) => json,
-        Err(_) => {
-            return json_response(
-                StatusCode::BAD_REQUEST,
-                json!({"error": "Invalid user data"}),
-            );
-        }
+    serde_json::from_slice::<T>(&whole_body)
+        .map_err(|_| AppError::Validation("Invalid JSON data".to_string()))
+}
+
+/// Collects the request body into a single buffer.
+///
+/// # Returns
+///
+/// * `Result<Bytes, AppError>` - The body, an `AppError::PayloadTooLarge` if it exceeds
+///   `max_size`, or an `AppError::Validation` if it cannot be collected
+pub(crate) async fn collect_body(
+    req: Request<Incoming>,
+    max_size: usize,
+) -> Result<Bytes, AppError> {
+    let too_large = || {
+        AppError::PayloadTooLarge(format!(
+            "The request body exceeds the limit of {} bytes",
+            max_size
+        ))
     };
 
-    let conn = get_connection().await.unwrap();
-    let result = conn
-        .query(
-            "INSERT INTO users (name, age) VALUES ($1, $2)",
-            &[&data.name, &data.age],
-        )
-        .await;
+    // Reject early when the client announces a body larger than the limit
+    let declared_size = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if declared_size.is_some_and(|size| size > max_size) {
+        return Err(too_large());
+    }
 
-    match result {
-        Ok(_) => json_response(StatusCode::OK, json!({"message": "User added"})),
-        Err(e) => json_response(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            json!({"error": format!("ERROR: {}", e)}),
-        ),
+    // whole_body is basically a buffer containing all the data from the request body.
+    // Collect all fragments of the request body into a single buffer
+    // The HTTP body may arrive in multiple parts that need to be aggregated.
+    // Limited stops reading as soon as the limit is exceeded (chunked bodies have no
+    // Content-Length), so the body is never buffered beyond max_size.
+    match Limited::new(req.into_body(), max_size).collect().await {
+        // to_bytes() combines all the chunks into a single buffer.
+        Ok(collected) => Ok(collected.to_bytes()),
+        Err(e) if e.is::<LengthLimitError>() => Err(too_large()),
+        Err(_) => Err(AppError::Validation(
+            "Failed to collect the request body".to_string(),
+        )),
     }
 }
 
-// ==================== PRODUCT ROUTES ====================
-
-/// Handles GET requests to retrieve all products.
-///
-/// # Route
+/// Collects the request body, deserializes it from JSON and validates it.
 ///
-/// `GET /products`
-///
-/// # Response
+/// # Returns
 ///
-/// Currently returns a 500 Internal Server Error response as a placeholder.
-async fn handle_get_all_products() -> Response<String> {
-    json_response(
-        StatusCode::INTERNAL_SERVER_ERROR,
-        json!({"error": "Internal Server Error"}),
-    )
+/// * `Result<T, AppError>` - The valid value, the errors of [`parse_json_body`], or an
+///   `AppError::Unprocessable` listing the invalid fields
+pub async fn parse_validated_body<T: DeserializeOwned + Validate>(
+    req: Request<Incoming>,
+) -> Result<T, AppError> {
+    let value = parse_json_body::<T>(req).await?;
+    value.validate()?;
+    Ok(value)
 }
@@ -0,0 +1,163 @@
+//! `X-App-*` response headers.
+//!
+//! The headers the service adds to its responses, besides the standard ones, share the
+//! `X-App-` namespace and are sent lowercase, as HTTP/2 requires:
+//! - `X-App-Request-Id`: ID of the request, as in the logs (see the `logging` module)
+//! - `X-App-Region`: region that served the request, in a multi-region deployment (see
+//!   the `region` module)
+//! - `X-App-Version`: version of the service
+//! - `X-App-RateLimit-Limit`, `X-App-RateLimit-Remaining` and `X-App-RateLimit-Reset`:
+//!   bucket of the client (see the `rate_limit` module)
+//!
+//! Subsystems don't insert them in the responses: they register their value with
+//! [`set`], and the router emits them all at once with [`emit`], once the response is
+//! complete. A value registered later replaces the earlier one, as the global rate limit
+//! replaces the one of the tools.
+//!
+//! With `LEGACY_HEADERS` (default true) each header is also sent under its former name
+//! (`X-Request-Id`, `X-Region`, `X-RateLimit-*`), while the clients migrate.
+
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+use hyper::Response;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use tracing::warn;
+
+// Set once at startup (`LEGACY_HEADERS`)
+static LEGACY_HEADERS: OnceLock<bool> = OnceLock::new();
+
+/// Header of the `X-App-` namespace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AppHeader {
+    RequestId,
+    Region,
+    Version,
+    RateLimitLimit,
+    RateLimitRemaining,
+    RateLimitReset,
+}
+
+impl AppHeader {
+    pub const ALL: [AppHeader; 6] = [
+        AppHeader::RequestId,
+        AppHeader::Region,
+        AppHeader::Version,
+        AppHeader::RateLimitLimit,
+        AppHeader::RateLimitRemaining,
+        AppHeader::RateLimitReset,
+    ];
+
+    pub fn name(self) -> HeaderName {
+        HeaderName::from_static(match self {
+            AppHeader::RequestId => "x-app-request-id",
+            AppHeader::Region => "x-app-region",
+            AppHeader::Version => "x-app-version",
+            AppHeader::RateLimitLimit => "x-app-ratelimit-limit",
+            AppHeader::RateLimitRemaining => "x-app-ratelimit-remaining",
+            AppHeader::RateLimitReset => "x-app-ratelimit-reset",
+        })
+    }
+
+    /// Name of the header before the namespace, `None` for the new ones
+    fn legacy_name(self) -> Option<HeaderName> {
+        let name = match self {
+            AppHeader::RequestId => "x-request-id",
+            AppHeader::Region => "x-region",
+            AppHeader::Version => return None,
+            AppHeader::RateLimitLimit => "x-ratelimit-limit",
+            AppHeader::RateLimitRemaining => "x-ratelimit-remaining",
+            AppHeader::RateLimitReset => "x-ratelimit-reset",
+        };
+        Some(HeaderName::from_static(name))
+    }
+}
+
+/// Values registered on a response, in its extensions until [`emit`].
+#[derive(Debug, Clone, Default)]
+struct AppHeaders(BTreeMap<AppHeader, HeaderValue>);
+
+/// Sets whether the headers are also sent under their former names (`LEGACY_HEADERS`).
+/// This function should be called once at application startup.
+pub fn init_app_headers(legacy: bool) {
+    if LEGACY_HEADERS.set(legacy).is_err() {
+        warn!("Attempt to reset the legacy headers setting ignored");
+    }
+}
+
+fn legacy_headers() -> bool {
+    LEGACY_HEADERS.get().copied().unwrap_or(true)
+}
+
+/// Registers the value of a header on a response, replacing the one registered before.
+pub fn set<B>(res: &mut Response<B>, header: AppHeader, value: HeaderValue) {
+    res.extensions_mut()
+        .get_or_insert_default::<AppHeaders>()
+        .0
+        .insert(header, value);
+}
+
+/// Writes the headers registered on a response, and `X-App-Version`.
+pub fn emit<B>(res: &mut Response<B>) {
+    let mut registered = res
+        .extensions_mut()
+        .remove::<AppHeaders>()
+        .unwrap_or_default()
+        .0;
+    registered.insert(
+        AppHeader::Version,
+        HeaderValue::from_static(env!("CARGO_PKG_VERSION")),
+    );
+    write(res.headers_mut(), registered, legacy_headers());
+}
+
+fn write(headers: &mut HeaderMap, registered: BTreeMap<AppHeader, HeaderValue>, legacy: bool) {
+    for (header, value) in registered {
+        if legacy && let Some(name) = header.legacy_name() {
+            headers.insert(name, value.clone());
+        }
+        headers.insert(header.name(), value);
+    }
+}
+
+/// Names of the headers readable by browser scripts, for `Access-Control-Expose-Headers`.
+pub fn exposed_names() -> impl Iterator<Item = HeaderName> {
+    let legacy = legacy_headers();
+    AppHeader::ALL.into_iter().flat_map(move |header| {
+        [Some(header.name()), header.legacy_name().filter(|_| legacy)]
+            .into_iter()
+            .flatten()
+    })
+}
+
+/// The value of a header emitted on a response, under either name.
+pub fn get(headers: &HeaderMap, header: AppHeader) -> Option<&HeaderValue> {
+    headers
+        .get(header.name())
+        .or_else(|| headers.get(header.legacy_name()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_the_legacy_names_too() {
+        let registered = BTreeMap::from([
+            (AppHeader::RequestId, HeaderValue::from_static("abc")),
+            (AppHeader::Version, HeaderValue::from_static("1.0.0")),
+        ]);
+
+        let mut headers = HeaderMap::new();
+        write(&mut headers, registered.clone(), true);
+        assert_eq!(headers["x-app-request-id"], "abc");
+        assert_eq!(headers["x-request-id"], "abc");
+        assert_eq!(headers["x-app-version"], "1.0.0");
+        assert_eq!(headers.len(), 3);
+
+        let mut headers = HeaderMap::new();
+        write(&mut headers, registered, false);
+        assert!(!headers.contains_key("x-request-id"));
+        assert_eq!(get(&headers, AppHeader::RequestId).unwrap(), "abc");
+    }
+}
@@ -0,0 +1,58 @@
+//! Response bodies.
+//!
+//! Handlers answer with a buffered body, which the negotiation layer can still convert
+//! (CSV) and compress, or with a stream that is sent while it is produced
+//! (Server-Sent Events, large lists) and passed through untouched.
+
+use futures_util::{Stream, TryStreamExt};
+use http_body_util::{BodyExt, Either, Full, StreamBody, combinators::UnsyncBoxBody};
+use hyper::body::{Bytes, Frame};
+
+/// Error ending a streamed body; hyper aborts the response when it happens
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Body type sent by the HTTP service.
+pub type ResponseBody = Either<Full<Bytes>, UnsyncBoxBody<Bytes, BoxError>>;
+
+/// Body of the responses produced by handlers and middlewares.
+pub enum Body {
+    /// Complete body, available for content negotiation
+    Buffered(String),
+    /// Body sent chunk by chunk as the stream yields them
+    Stream(UnsyncBoxBody<Bytes, BoxError>),
+}
+
+impl Body {
+    /// Creates a body sending every chunk of `stream` as soon as it is ready.
+    pub fn stream<S>(stream: S) -> Body
+    where
+        S: Stream<Item = Result<Bytes, BoxError>> + Send + 'static,
+    {
+        Body::Stream(StreamBody::new(stream.map_ok(Frame::data)).boxed_unsync())
+    }
+
+    pub(crate) fn into_response_body(self) -> ResponseBody {
+        match self {
+            Body::Buffered(text) => Either::Left(Full::new(Bytes::from(text))),
+            Body::Stream(stream) => Either::Right(stream),
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::Buffered(String::new())
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Body::Buffered(text)
+    }
+}
+
+impl From<&'static str> for Body {
+    fn from(text: &'static str) -> Self {
+        Body::Buffered(text.to_string())
+    }
+}
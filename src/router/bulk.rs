@@ -0,0 +1,158 @@
+//! Bulk request bodies: many items of a kind created by a single request.
+//!
+//! The body is a JSON array of items, or NDJSON (`Content-Type: application/x-ndjson`,
+//! one JSON item per line). Every item is parsed and validated on its own: an invalid
+//! item is reported at its index in the response without failing the others.
+//!
+//! ```json
+//! {
+//!   "created": 1,
+//!   "failed": 1,
+//!   "results": [
+//!     {"index": 0, "status": 201, "data": {"id": "0192f4c8-..."}},
+//!     {"index": 1, "status": 422, "error": {"code": "VALIDATION_FAILED", ...}}
+//!   ]
+//! }
+//! ```
+//!
+//! Bulk bodies are limited by `MAX_UPLOAD_SIZE` instead of `MAX_BODY_SIZE`, and to
+//! [`MAX_BULK_ITEMS`] items.
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Request, Response, StatusCode, body::Incoming};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+
+use super::limits::max_upload_size;
+use super::{Body, collect_body, json_response};
+use crate::error::{AppError, ErrorBody, ErrorCode};
+use crate::validation::Validate;
+
+/// Items accepted in a bulk request
+pub const MAX_BULK_ITEMS: usize = 10_000;
+
+/// Media type of NDJSON bodies
+const NDJSON: &str = "application/x-ndjson";
+
+/// The items of a bulk request, sorted out.
+#[derive(Debug)]
+pub struct BulkItems<T> {
+    /// Valid items, in the request order
+    pub valid: Vec<T>,
+    /// Index in the request of each valid item
+    indexes: Vec<usize>,
+    /// Invalid items, by index
+    invalid: Vec<(usize, StatusCode, ErrorBody)>,
+}
+
+impl<T: DeserializeOwned + Validate> BulkItems<T> {
+    /// Collects the body of a bulk request, then parses and validates every item.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<BulkItems<T>, AppError>` - The items, an `AppError::PayloadTooLarge` if
+    ///   the body exceeds `MAX_UPLOAD_SIZE` or has more than `MAX_BULK_ITEMS` items, or
+    ///   an `AppError::Validation` if the body isn't an array of items
+    pub async fn from_request(req: Request<Incoming>) -> Result<Self, AppError> {
+        let ndjson = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with(NDJSON));
+        let body = collect_body(req, max_upload_size()).await?;
+
+        // A line of NDJSON that isn't JSON is an invalid item, an invalid array fails it all
+        let values: Vec<Result<Value, String>> = if ndjson {
+            body.split(|&byte| byte == b'\n')
+                .filter(|line| !line.trim_ascii().is_empty())
+                .map(|line| serde_json::from_slice(line).map_err(|e| e.to_string()))
+                .collect()
+        } else {
+            serde_json::from_slice::<Vec<Value>>(&body)
+                .map_err(|_| AppError::Validation("Expected a JSON array".to_string()))?
+                .into_iter()
+                .map(Ok)
+                .collect()
+        };
+        if values.is_empty() {
+            return Err(AppError::Validation("No items in the request".to_string()));
+        }
+        if values.len() > MAX_BULK_ITEMS {
+            return Err(AppError::PayloadTooLarge(format!(
+                "At most {} items per request",
+                MAX_BULK_ITEMS
+            )));
+        }
+
+        let mut items = BulkItems {
+            valid: Vec::with_capacity(values.len()),
+            indexes: Vec::with_capacity(values.len()),
+            invalid: Vec::new(),
+        };
+        for (index, value) in values.into_iter().enumerate() {
+            let item = value
+                .and_then(|value| serde_json::from_value::<T>(value).map_err(|e| e.to_string()));
+            let invalid = match item {
+                Ok(item) => match item.validate() {
+                    Ok(()) => {
+                        items.valid.push(item);
+                        items.indexes.push(index);
+                        continue;
+                    }
+                    Err(errors) => (
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        ErrorBody::new(
+                            ErrorCode::ValidationFailed,
+                            format!("Invalid fields: {}", errors),
+                        )
+                        .with_details(serde_json::to_value(errors).unwrap_or_default()),
+                    ),
+                },
+                Err(e) => (
+                    StatusCode::BAD_REQUEST,
+                    ErrorBody::new(ErrorCode::InvalidRequest, format!("Invalid item: {}", e)),
+                ),
+            };
+            let (status, mut error) = invalid;
+            // Already in the headers of the response
+            error.request_id = None;
+            items.invalid.push((index, status, error));
+        }
+        Ok(items)
+    }
+}
+
+impl<T> BulkItems<T> {
+    /// Responds with the outcome of every item, in the request order.
+    ///
+    /// # Arguments
+    ///
+    /// * `created` - What to answer for each valid item, in the order of `valid`
+    pub fn into_response<R: Serialize>(self, created: Vec<R>) -> Response<Body> {
+        let (created_count, failed_count) = (created.len(), self.invalid.len());
+        let mut results: Vec<(usize, Value)> = self
+            .indexes
+            .into_iter()
+            .zip(created)
+            .map(|(index, data)| {
+                let result = json!({"index": index, "status": 201, "data": data});
+                (index, result)
+            })
+            .chain(self.invalid.into_iter().map(|(index, status, error)| {
+                let result = json!({"index": index, "status": status.as_u16(), "error": error});
+                (index, result)
+            }))
+            .collect();
+        results.sort_unstable_by_key(|(index, _)| *index);
+
+        json_response(
+            StatusCode::OK,
+            json!({
+                "created": created_count,
+                "failed": failed_count,
+                "results": results.into_iter().map(|(_, result)| result).collect::<Vec<_>>(),
+            }),
+        )
+    }
+}
@@ -0,0 +1,106 @@
+//! Conditional requests (`ETag` / `If-None-Match` / `If-Match`).
+//!
+//! List endpoints tag their responses with the version of the collection (see
+//! `repository::versions`) and answer 304 Not Modified without querying the rows
+//! when the client already has the current version. Entity endpoints tag their body
+//! with the version of its row, bumped by every write (see `cache`).
+//!
+//! Writes to an entity must send that tag back in `If-Match` (or `*`): the repository
+//! applies them only if the row still has one of the versions listed, in the same
+//! `UPDATE` or `DELETE`, so two clients can't overwrite each other's changes unknowingly.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use hyper::{
+    Request, Response, StatusCode,
+    header::{ETAG, HeaderValue, IF_MATCH, IF_NONE_MATCH},
+};
+
+use super::{Body, empty_response};
+use crate::error::AppError;
+
+/// Builds the weak ETag of a page of a collection.
+///
+/// The query string is part of the tag: every page, sort order and filter of the
+/// same collection version is a different representation.
+///
+/// # Arguments
+///
+/// * `collection` - Name of the collection (`users`)
+/// * `version` - Current version of the collection
+/// * `req` - The list request
+/// * `variant` - What else the page depends on: the saved view of `?view=`, which can
+///   change while the query string stays the same
+pub fn collection_etag<B>(
+    collection: &str,
+    version: i64,
+    req: &Request<B>,
+    variant: impl Hash,
+) -> HeaderValue {
+    let mut hasher = DefaultHasher::new();
+    req.uri().query().unwrap_or("").hash(&mut hasher);
+    variant.hash(&mut hasher);
+
+    let tag = format!("W/\"{}-{}-{:x}\"", collection, version, hasher.finish());
+    HeaderValue::from_str(&tag).expect("ETag contains only visible ASCII")
+}
+
+/// An entity tagged with the version of its row.
+pub trait Versioned {
+    fn version(&self) -> i32;
+}
+
+/// Builds the strong ETag of an entity from the version of its row.
+pub fn version_etag(version: i32) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{}\"", version)).expect("ETag contains only digits")
+}
+
+/// Reads the versions a write may apply to from the request's `If-Match`.
+///
+/// Only strong tags match (RFC 9110): a weak one, or one this server didn't send,
+/// never matches a version.
+///
+/// # Returns
+///
+/// * `Result<Option<Vec<i32>>, AppError>` - The versions of the listed tags, `None` for
+///   `*` (any version), or an `AppError::PreconditionRequired` without the header
+pub fn if_match<B>(req: &Request<B>) -> Result<Option<Vec<i32>>, AppError> {
+    let header = req.headers().get(IF_MATCH).ok_or_else(|| {
+        AppError::PreconditionRequired(
+            "If-Match is required, send the ETag of the entity (or *)".to_string(),
+        )
+    })?;
+    let header = header.to_str().unwrap_or_default();
+    let candidates = || header.split(',').map(str::trim);
+    if candidates().any(|candidate| candidate == "*") {
+        return Ok(None);
+    }
+    let versions = candidates()
+        .filter_map(|candidate| candidate.strip_prefix('"')?.strip_suffix('"')?.parse().ok())
+        .collect();
+    Ok(Some(versions))
+}
+
+/// Answers 304 Not Modified if the request's `If-None-Match` contains `etag`.
+///
+/// # Returns
+///
+/// * `Option<Response<Body>>` - The 304 response, or `None` if the client must
+///   receive the full response
+pub fn not_modified<B>(req: &Request<B>, etag: &HeaderValue) -> Option<Response<Body>> {
+    let header = req.headers().get(IF_NONE_MATCH)?.to_str().ok()?;
+    // Weak comparison (RFC 9110): the W/ prefix is ignored on both sides
+    let wanted = etag.to_str().ok()?.trim_start_matches("W/");
+    let matches = header
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == wanted);
+
+    matches.then(|| with_etag(empty_response(StatusCode::NOT_MODIFIED), etag))
+}
+
+/// Adds the `ETag` header to a response.
+pub fn with_etag(mut res: Response<Body>, etag: &HeaderValue) -> Response<Body> {
+    res.headers_mut().insert(ETAG, etag.clone());
+    res
+}
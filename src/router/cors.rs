@@ -0,0 +1,144 @@
+//! CORS (Cross-Origin Resource Sharing) support.
+//!
+//! Enabled with the `ALLOWED_ORIGINS` environment variable: a comma-separated list of
+//! origins (`https://app.example.com,http://localhost:5173`) or `*` for any origin.
+//! When it is not set, no CORS headers are emitted and browsers block cross-origin calls.
+
+use std::sync::OnceLock;
+
+use hyper::{
+    HeaderMap, Method, Request, Response, StatusCode,
+    header::{
+        ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+        ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+        ACCESS_CONTROL_REQUEST_METHOD, HeaderValue, ORIGIN, VARY,
+    },
+};
+
+use super::{Body, app_headers, empty_response};
+
+/// Methods allowed in cross-origin requests
+const ALLOWED_METHODS: &str = "GET, POST, PUT, PATCH, DELETE, OPTIONS";
+/// Request headers allowed when the preflight doesn't list any
+const ALLOWED_HEADERS: &str =
+    "authorization, content-type, x-request-id, x-consent, x-consistency-token, x-home-region";
+/// Response headers readable by browser scripts, besides the CORS-safelisted ones and
+/// the `X-App-*` headers
+const EXPOSED_HEADERS: &str = "retry-after, x-consistency-token, x-experiments";
+/// How long browsers may cache a preflight response (seconds)
+const MAX_AGE: &str = "86400";
+
+// Set once at startup from ALLOWED_ORIGINS, unset when CORS is disabled
+static ALLOWED_ORIGINS: OnceLock<AllowedOrigins> = OnceLock::new();
+
+// `EXPOSED_HEADERS` and the `X-App-*` headers, built on first use
+static EXPOSED: OnceLock<HeaderValue> = OnceLock::new();
+
+enum AllowedOrigins {
+    Any,
+    List(Vec<String>),
+}
+
+/// Enables CORS for the configured origins.
+/// This function should be called at application startup; an empty list keeps CORS disabled.
+///
+/// # Arguments
+///
+/// * `origins` - Allowed origins, `*` allows any origin
+pub fn init_cors(origins: &[String]) {
+    if origins.is_empty() {
+        return;
+    }
+
+    let allowed = if origins.iter().any(|origin| origin == "*") {
+        AllowedOrigins::Any
+    } else {
+        AllowedOrigins::List(origins.to_vec())
+    };
+    let _ = ALLOWED_ORIGINS.set(allowed);
+}
+
+fn exposed_headers() -> &'static HeaderValue {
+    EXPOSED.get_or_init(|| {
+        let names = app_headers::exposed_names()
+            .map(|name| name.to_string())
+            .chain([EXPOSED_HEADERS.to_string()])
+            .collect::<Vec<_>>();
+        HeaderValue::from_str(&names.join(", ")).expect("header names")
+    })
+}
+
+fn allowed_origins() -> Option<&'static AllowedOrigins> {
+    ALLOWED_ORIGINS.get()
+}
+
+/// Returns the `Access-Control-Allow-Origin` value for a request origin,
+/// or `None` if the origin is not allowed.
+fn allow_origin_value(origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+    match allowed_origins()? {
+        AllowedOrigins::Any => Some(HeaderValue::from_static("*")),
+        AllowedOrigins::List(list) => {
+            let origin = origin?;
+            let allowed = list.iter().any(|o| o.as_bytes() == origin.as_bytes());
+            allowed.then(|| origin.clone())
+        }
+    }
+}
+
+/// Answers CORS preflight requests (`OPTIONS` with `Access-Control-Request-Method`).
+///
+/// # Returns
+///
+/// * `Option<Response<Body>>` - 204 No Content with the allowed methods and headers
+///   (without them if the origin is not allowed), or `None` if the request is not a
+///   preflight or CORS is disabled
+pub fn preflight<B>(req: &Request<B>) -> Option<Response<Body>> {
+    allowed_origins()?;
+    if req.method() != Method::OPTIONS || !req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        return None;
+    }
+
+    let mut res = empty_response(StatusCode::NO_CONTENT);
+    let origin = req.headers().get(ORIGIN);
+    if allow_origin_value(origin).is_some() {
+        let headers = res.headers_mut();
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static(ALLOWED_METHODS),
+        );
+        // Echo the requested headers, the API doesn't restrict them
+        let allow_headers = req
+            .headers()
+            .get(ACCESS_CONTROL_REQUEST_HEADERS)
+            .cloned()
+            .unwrap_or(HeaderValue::from_static(ALLOWED_HEADERS));
+        headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static(MAX_AGE));
+    }
+    apply_cors_headers(origin, res.headers_mut());
+
+    Some(res)
+}
+
+/// Adds the CORS headers of a response (preflight or actual response, including errors).
+///
+/// # Arguments
+///
+/// * `origin` - `Origin` header of the request
+/// * `headers` - Headers of the response
+pub fn apply_cors_headers(origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+    let Some(allowed) = allowed_origins() else {
+        return;
+    };
+
+    // The response depends on the Origin header, caches must not share it across origins
+    if let AllowedOrigins::List(_) = allowed {
+        headers.append(VARY, HeaderValue::from_static("origin"));
+    }
+
+    if let Some(value) = allow_origin_value(origin) {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, value);
+        headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, exposed_headers().clone());
+    }
+}
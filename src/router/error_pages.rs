@@ -0,0 +1,370 @@
+//! Custom bodies of the 429 and 503 responses (`ERROR_PAGES_PATH`), for operators who
+//! need their own message when clients are rate limited or the service is unavailable.
+//!
+//! ## Configuration
+//! The pages are read at startup from the JSON file pointed to by `ERROR_PAGES_PATH`,
+//! by status, each with a JSON template, an HTML file (relative to the JSON file), or
+//! both:
+//!
+//! ```json
+//! {
+//!   "429": {"json": {"error": "Slow down", "retry_in": "{{retry_after}}"}},
+//!   "503": {
+//!     "json": {"error": "Back soon", "id": "{{request_id}}"},
+//!     "html": "503.html"
+//!   }
+//! }
+//! ```
+//!
+//! The templates can use the variables `{{status}}`, `{{code}}`, `{{message}}`,
+//! `{{request_id}}` and `{{retry_after}}` (seconds, from `Retry-After`), checked at
+//! startup. In a JSON template, a string made of one variable is replaced by its
+//! value, a number for `status` and `retry_after` and `null` when it's missing; in
+//! longer strings and in HTML the variables are replaced by their text, escaped in
+//! HTML.
+//!
+//! ## Responses
+//! The HTML page is sent to the clients preferring `text/html` to JSON, browsers, and
+//! the JSON template to the others. A client getting neither keeps the usual error
+//! body. Only the error bodies are replaced, health checks answering 503 keep theirs;
+//! the status and the headers (`Retry-After`, `X-App-Request-Id`, ...) are kept too.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderValue, RETRY_AFTER};
+use hyper::{Response, StatusCode};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tracing::warn;
+
+use super::Body;
+use crate::router::app_headers::{self, AppHeader};
+
+/// Statuses whose body can be replaced
+const STATUSES: [StatusCode; 2] = [
+    StatusCode::TOO_MANY_REQUESTS,
+    StatusCode::SERVICE_UNAVAILABLE,
+];
+
+// Set once at startup, empty without ERROR_PAGES_PATH
+static PAGES: OnceLock<HashMap<StatusCode, Page>> = OnceLock::new();
+
+/// A page of the file, as written.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct PageFile {
+    json: Option<Value>,
+    /// Path of the HTML file
+    html: Option<String>,
+}
+
+/// The bodies replacing the error body of a status.
+#[derive(Debug)]
+struct Page {
+    json: Option<Value>,
+    html: Option<String>,
+}
+
+/// A variable of the templates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Variable {
+    Status,
+    Code,
+    Message,
+    RequestId,
+    RetryAfter,
+}
+
+impl Variable {
+    fn from_name(name: &str) -> Option<Variable> {
+        match name {
+            "status" => Some(Variable::Status),
+            "code" => Some(Variable::Code),
+            "message" => Some(Variable::Message),
+            "request_id" => Some(Variable::RequestId),
+            "retry_after" => Some(Variable::RetryAfter),
+            _ => None,
+        }
+    }
+}
+
+/// A piece of a template: text, or a variable replaced by its value.
+#[derive(Debug, PartialEq)]
+enum Part<'a> {
+    Text(&'a str),
+    Variable(Variable),
+}
+
+/// Splits `template` into text and variables (`{{name}}`).
+fn parse(template: &str) -> Result<Vec<Part<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            return Err(format!("'{{{{' without '}}}}' in '{}'", template));
+        };
+        if start > 0 {
+            parts.push(Part::Text(&rest[..start]));
+        }
+        let name = rest[start + 2..start + end].trim();
+        let variable = Variable::from_name(name)
+            .ok_or_else(|| format!("Unknown variable '{}' in '{}'", name, template))?;
+        parts.push(Part::Variable(variable));
+        rest = &rest[start + end + 2..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest));
+    }
+    Ok(parts)
+}
+
+/// Checks the templates of the strings of `value`.
+fn check_json(value: &Value) -> Result<(), String> {
+    match value {
+        Value::String(text) => parse(text).map(|_| ()),
+        Value::Array(items) => items.iter().try_for_each(check_json),
+        Value::Object(object) => object.values().try_for_each(check_json),
+        _ => Ok(()),
+    }
+}
+
+/// Loads the pages from `ERROR_PAGES_PATH`.
+/// This function should be called once at application startup.
+///
+/// # Arguments
+///
+/// * `path` - Path of the JSON file of the pages, `None` when the error bodies are kept
+///
+/// # Returns
+///
+/// * `Result<usize, String>` - Number of pages, or an error message
+pub fn init_error_pages(path: Option<&Path>) -> Result<usize, String> {
+    let mut pages = HashMap::new();
+    if let Some(path) = path {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read error pages '{}': {}", path.display(), e))?;
+        let file: HashMap<String, PageFile> = serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid error pages '{}': {}", path.display(), e))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        for (status, page) in file {
+            let status = status
+                .parse::<StatusCode>()
+                .ok()
+                .filter(|status| STATUSES.contains(status))
+                .ok_or_else(|| format!("'{}': only 429 and 503 have error pages", status))?;
+            if let Some(json) = &page.json {
+                check_json(json).map_err(|e| format!("{}: {}", status.as_u16(), e))?;
+            }
+            let html = match page.html {
+                Some(file) => {
+                    let html = fs::read_to_string(dir.join(&file)).map_err(|e| {
+                        format!("Unable to read the page of {}: {}", status.as_u16(), e)
+                    })?;
+                    parse(&html).map_err(|e| format!("{}: {}", file, e))?;
+                    Some(html)
+                }
+                None => None,
+            };
+            pages.insert(
+                status,
+                Page {
+                    json: page.json,
+                    html,
+                },
+            );
+        }
+    }
+
+    let count = pages.len();
+    if PAGES.set(pages).is_err() {
+        warn!("Attempt to reload the error pages ignored");
+    }
+    Ok(count)
+}
+
+/// Values of the variables for an error response.
+#[derive(Debug, Default)]
+struct Values {
+    status: u16,
+    code: Option<String>,
+    message: Option<String>,
+    request_id: Option<String>,
+    retry_after: Option<u64>,
+}
+
+impl Values {
+    fn value(&self, variable: Variable) -> Value {
+        match variable {
+            Variable::Status => self.status.into(),
+            Variable::Code => self.code.clone().into(),
+            Variable::Message => self.message.clone().into(),
+            Variable::RequestId => self.request_id.clone().into(),
+            Variable::RetryAfter => self.retry_after.into(),
+        }
+    }
+
+    /// The value as text, empty when it's missing.
+    fn text(&self, variable: Variable) -> String {
+        match self.value(variable) {
+            Value::String(text) => text,
+            Value::Null => String::new(),
+            value => value.to_string(),
+        }
+    }
+}
+
+/// `template` with its variables replaced, escaped for HTML when `html` is set.
+fn render_text(template: &str, values: &Values, html: bool) -> String {
+    let Ok(parts) = parse(template) else {
+        return template.to_string();
+    };
+    parts
+        .into_iter()
+        .map(|part| match part {
+            Part::Text(text) => text.to_string(),
+            Part::Variable(variable) if html => escape_html(&values.text(variable)),
+            Part::Variable(variable) => values.text(variable),
+        })
+        .collect()
+}
+
+/// The JSON `template` with its variables replaced.
+fn render_json(template: &Value, values: &Values) -> Value {
+    match template {
+        Value::String(text) => match parse(text).as_deref() {
+            Ok([Part::Variable(variable)]) => values.value(*variable),
+            _ => render_text(text, values, false).into(),
+        },
+        Value::Array(items) => items.iter().map(|item| render_json(item, values)).collect(),
+        Value::Object(object) => object
+            .iter()
+            .map(|(key, value)| (key.clone(), render_json(value, values)))
+            .collect::<Map<_, _>>()
+            .into(),
+        value => value.clone(),
+    }
+}
+
+/// `text` with the characters special in HTML escaped, also used by the login form of
+/// the OpenID Connect provider.
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Replaces the body of a 429 or 503 error response by its page, if it has one.
+///
+/// # Arguments
+///
+/// * `html` - Whether the client prefers HTML to JSON
+/// * `res` - Response produced by the router
+pub fn render(html: bool, res: Response<Body>) -> Response<Body> {
+    let Some(page) = PAGES.get().and_then(|pages| pages.get(&res.status())) else {
+        return res;
+    };
+    let Body::Buffered(text) = res.body() else {
+        return res;
+    };
+    // Error bodies only, with their code
+    let Ok(error) = serde_json::from_str::<Value>(text) else {
+        return res;
+    };
+    let Some(code) = error["code"].as_str() else {
+        return res;
+    };
+
+    let header = |name: &str| res.headers().get(name).and_then(|v| v.to_str().ok());
+    let values = Values {
+        status: res.status().as_u16(),
+        code: Some(code.to_string()),
+        message: error["message"].as_str().map(str::to_string),
+        // Rendered after the request scope, the ID is taken from the response
+        request_id: app_headers::get(res.headers(), AppHeader::RequestId)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+        retry_after: header(RETRY_AFTER.as_str()).and_then(|v| v.parse().ok()),
+    };
+    let (content_type, body) = match (&page.html, &page.json) {
+        (Some(template), _) if html => (
+            "text/html; charset=utf-8",
+            render_text(template, &values, true),
+        ),
+        (_, Some(template)) => (
+            "application/json",
+            render_json(template, &values).to_string(),
+        ),
+        _ => return res,
+    };
+
+    let (mut parts, _) = res.into_parts();
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, Body::Buffered(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn values() -> Values {
+        Values {
+            status: 429,
+            code: Some("RATE_LIMITED".to_string()),
+            message: Some("Too many requests".to_string()),
+            request_id: Some("<id>".to_string()),
+            retry_after: None,
+        }
+    }
+
+    #[test]
+    fn json_templates_keep_the_types_of_the_values() {
+        let template = json!({
+            "error": "{{ code }}: {{message}}",
+            "status": "{{status}}",
+            "retry_in": ["{{retry_after}}"],
+        });
+        assert_eq!(
+            render_json(&template, &values()),
+            json!({
+                "error": "RATE_LIMITED: Too many requests",
+                "status": 429,
+                "retry_in": [null],
+            })
+        );
+    }
+
+    #[test]
+    fn pages_escape_the_values() {
+        assert_eq!(
+            render_text(
+                "<p>Request {{request_id}}, retry in {{retry_after}}s</p>",
+                &values(),
+                true
+            ),
+            "<p>Request &lt;id&gt;, retry in s</p>"
+        );
+    }
+
+    #[test]
+    fn unknown_variables_are_rejected() {
+        assert!(parse("{{retry}}").is_err());
+        assert!(parse("{{status").is_err());
+        assert!(check_json(&json!({"a": ["{{request_id}}"]})).is_ok());
+    }
+}
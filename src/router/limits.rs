@@ -0,0 +1,82 @@
+//! Request size and time limits.
+//!
+//! - `MAX_BODY_SIZE`: maximum request body size in bytes (default 1 MiB), larger
+//!   bodies are rejected with 413 Payload Too Large
+//! - `MAX_UPLOAD_SIZE`: maximum size of file uploads in bytes (default 5 MiB), which
+//!   replaces `MAX_BODY_SIZE` for them
+//! - `REQUEST_TIMEOUT`: seconds a handler has to produce the response (default 30),
+//!   answered with 504 Gateway Timeout when it expires. Its queries are held to the
+//!   same deadline (see `db::deadline`)
+//! - `ROUTE_TIMEOUTS`: timeouts of the routes that need another one than
+//!   `REQUEST_TIMEOUT`, as `GET /api/v1/dashboard=5,POST /api/v1/users/bulk=120`
+//!
+//! Limits of the connections themselves (header size and read timeout) are applied when
+//! they are accepted, see `server`.
+//!
+//! The values are loaded by the `config` module and set once at startup with `init_limits`.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use hyper::Method;
+use tracing::warn;
+
+use crate::config::ServerConfig;
+
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+struct Limits {
+    max_body_size: usize,
+    max_upload_size: usize,
+    request_timeout: Duration,
+    /// Method and pattern of a route, with its timeout
+    route_timeouts: Vec<(Method, String, Duration)>,
+}
+
+/// Sets the limits applied to every request.
+/// This function should be called at application startup, before serving requests.
+pub fn init_limits(config: &ServerConfig) {
+    let limits = Limits {
+        max_body_size: config.max_body_size,
+        max_upload_size: config.max_upload_size,
+        request_timeout: config.request_timeout,
+        route_timeouts: config.route_timeouts.clone(),
+    };
+    if LIMITS.set(limits).is_err() {
+        warn!("Attempt to reset request limits ignored");
+    }
+}
+
+fn limits() -> &'static Limits {
+    LIMITS.get().expect("Request limits are not initialized")
+}
+
+/// Maximum size of a request body in bytes.
+pub fn max_body_size() -> usize {
+    limits().max_body_size
+}
+
+/// Maximum size of a file upload request body in bytes.
+pub fn max_upload_size() -> usize {
+    limits().max_upload_size
+}
+
+/// Time a handler has to produce its response.
+pub fn request_timeout() -> Duration {
+    limits().request_timeout
+}
+
+/// Time the handler of a route has to produce its response: from `ROUTE_TIMEOUTS`, or
+/// `REQUEST_TIMEOUT`.
+///
+/// # Arguments
+///
+/// * `pattern` - Pattern the route was registered with, prefix included
+pub fn route_timeout(method: &Method, pattern: &str) -> Duration {
+    let limits = limits();
+    limits
+        .route_timeouts
+        .iter()
+        .find(|(m, p, _)| m == method && p == pattern)
+        .map_or(limits.request_timeout, |(_, _, timeout)| *timeout)
+}
@@ -0,0 +1,251 @@
+//! Streaming `multipart/form-data` request bodies.
+//!
+//! The body is parsed while it is received: the data of a part is handed over chunk by
+//! chunk, so a file upload is written to its destination without being held in memory.
+//!
+//! ```ignore
+//! let mut multipart = Multipart::from_request(req, max_size)?;
+//! while let Some(part) = multipart.next_part().await? {
+//!     if part.name.as_deref() == Some("file") {
+//!         store.put(key, multipart.chunks()).await?;
+//!     }
+//! }
+//! ```
+
+use futures_util::{Stream, stream};
+use http_body_util::BodyExt;
+use hyper::{
+    Request,
+    body::{Bytes, Incoming},
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+};
+
+use crate::error::AppError;
+
+/// Maximum size of the headers of a part
+const MAX_PART_HEADERS_SIZE: usize = 8 * 1024;
+
+/// Headers of a part.
+#[derive(Debug, Default)]
+pub struct Part {
+    /// `name` of the `Content-Disposition` header (form field name)
+    pub name: Option<String>,
+    /// `filename` of the `Content-Disposition` header, set for file fields
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+/// A `multipart/form-data` body being read.
+pub struct Multipart {
+    body: Incoming,
+    /// `\r\n--<boundary>`, which precedes every part and the end of the body. The body is
+    /// read as if it started with `\r\n` so the first boundary matches it too.
+    delimiter: Vec<u8>,
+    /// Received bytes not handed over yet
+    buffer: Vec<u8>,
+    /// Whether the data of a part (or the preamble before the first one) is being read
+    in_part: bool,
+    /// Set once the closing delimiter has been read
+    finished: bool,
+    received: usize,
+    max_size: usize,
+}
+
+impl Multipart {
+    /// Starts reading the body of a `multipart/form-data` request.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The request, whose body is consumed
+    /// * `max_size` - Maximum size of the body in bytes
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Multipart, AppError>` - The reader, an `AppError::Validation` if the
+    ///   request isn't multipart, or an `AppError::PayloadTooLarge` if it announces
+    ///   a body larger than `max_size`
+    pub fn from_request(req: Request<Incoming>, max_size: usize) -> Result<Multipart, AppError> {
+        let headers = req.headers();
+        let boundary = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(form_data_boundary)
+            .ok_or_else(|| {
+                AppError::Validation("Expected a multipart/form-data body".to_string())
+            })?;
+
+        let declared_size = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+        if declared_size.is_some_and(|size| size > max_size) {
+            return Err(too_large(max_size));
+        }
+
+        Ok(Multipart {
+            body: req.into_body(),
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            buffer: b"\r\n".to_vec(),
+            in_part: true,
+            finished: false,
+            received: 0,
+            max_size,
+        })
+    }
+
+    /// Moves to the next part, skipping what is left of the current one.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Part>, AppError>` - The headers of the part, `None` after the
+    ///   last one, or an error if the body is malformed, too large or interrupted
+    pub async fn next_part(&mut self) -> Result<Option<Part>, AppError> {
+        while self.read_chunk().await?.is_some() {}
+        if self.finished {
+            return Ok(None);
+        }
+
+        // The buffer starts with the delimiter, followed by `--` at the end of the body
+        // or by the headers of the part and an empty line
+        let start = self.delimiter.len();
+        while self.buffer.len() < start + 2 {
+            self.fill().await?;
+        }
+        if &self.buffer[start..start + 2] == b"--" {
+            self.finished = true;
+            return Ok(None);
+        }
+        if &self.buffer[start..start + 2] != b"\r\n" {
+            return Err(malformed());
+        }
+
+        let end = loop {
+            if let Some(end) = find(&self.buffer[start..], b"\r\n\r\n") {
+                break start + end;
+            }
+            if self.buffer.len() > start + MAX_PART_HEADERS_SIZE {
+                return Err(malformed());
+            }
+            self.fill().await?;
+        };
+        let part = std::str::from_utf8(self.buffer.get(start + 2..end).unwrap_or_default())
+            .map(parse_part_headers)
+            .map_err(|_| malformed())?;
+
+        self.buffer.drain(..end + 4);
+        self.in_part = true;
+        Ok(Some(part))
+    }
+
+    /// Reads the next chunk of data of the current part, `None` at its end.
+    pub async fn read_chunk(&mut self) -> Result<Option<Bytes>, AppError> {
+        if !self.in_part {
+            return Ok(None);
+        }
+
+        loop {
+            if let Some(end) = find(&self.buffer, &self.delimiter) {
+                self.in_part = false;
+                let data: Vec<u8> = self.buffer.drain(..end).collect();
+                return Ok((!data.is_empty()).then(|| Bytes::from(data)));
+            }
+            // The end of the buffer may be the beginning of the delimiter
+            let keep = self.delimiter.len() - 1;
+            if self.buffer.len() > keep {
+                let data: Vec<u8> = self.buffer.drain(..self.buffer.len() - keep).collect();
+                return Ok(Some(Bytes::from(data)));
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// The data of the current part as a stream of chunks.
+    pub fn chunks(&mut self) -> impl Stream<Item = Result<Bytes, AppError>> + Send + '_ {
+        stream::try_unfold(self, async |multipart| {
+            let chunk = multipart.read_chunk().await?;
+            Ok(chunk.map(|chunk| (chunk, multipart)))
+        })
+    }
+
+    /// Appends the next data frame of the body to the buffer.
+    /// Reaching the end of the body here means it was cut short.
+    async fn fill(&mut self) -> Result<(), AppError> {
+        loop {
+            let frame = match self.body.frame().await {
+                Some(Ok(frame)) => frame,
+                Some(Err(_)) => {
+                    return Err(AppError::Validation(
+                        "Failed to collect the request body".to_string(),
+                    ));
+                }
+                None => return Err(malformed()),
+            };
+            if let Ok(data) = frame.into_data() {
+                self.received += data.len();
+                if self.received > self.max_size {
+                    return Err(too_large(self.max_size));
+                }
+                self.buffer.extend_from_slice(&data);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The boundary of a `multipart/form-data` content type.
+fn form_data_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let media_type = params.next()?.trim();
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+fn parse_part_headers(headers: &str) -> Part {
+    let mut part = Part::default();
+    for line in headers.split("\r\n") {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.trim().eq_ignore_ascii_case("content-type") {
+            part.content_type = Some(value.to_string());
+        } else if name.trim().eq_ignore_ascii_case("content-disposition") {
+            for param in value.split(';').skip(1) {
+                let Some((key, param_value)) = param.split_once('=') else {
+                    continue;
+                };
+                let param_value = param_value.trim().trim_matches('"').to_string();
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "name" => part.name = Some(param_value),
+                    "filename" => part.filename = Some(param_value),
+                    _ => {}
+                }
+            }
+        }
+    }
+    part
+}
+
+/// Position of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn malformed() -> AppError {
+    AppError::Validation("Malformed multipart/form-data body".to_string())
+}
+
+fn too_large(max_size: usize) -> AppError {
+    AppError::PayloadTooLarge(format!(
+        "The request body exceeds the limit of {} bytes",
+        max_size
+    ))
+}
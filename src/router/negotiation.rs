@@ -0,0 +1,332 @@
+//! Content negotiation and response compression.
+//!
+//! Post-processes the responses of the router according to the request headers:
+//!
+//! - `Accept`: list endpoints can be returned as CSV (`text/csv`) instead of JSON
+//!   (`application/json`, the default). The pagination metadata is then sent in the
+//!   `X-Total-Count` and `Link` headers.
+//! - `Accept-Encoding`: bodies of at least `MIN_COMPRESS_SIZE` bytes are compressed
+//!   with brotli (`br`) or gzip, whichever the client prefers.
+//!
+//! Error responses are JSON, or the pages of `ERROR_PAGES_PATH` (see `error_pages`),
+//! streamed responses are sent untouched.
+
+use std::io::Write;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use http_body_util::{Either, Full};
+use hyper::{
+    HeaderMap, Request, Response, StatusCode,
+    body::Bytes,
+    header::{
+        ACCEPT, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HeaderValue, LINK,
+        VARY,
+    },
+};
+use serde_json::{Map, Value};
+use tracing::warn;
+
+use super::{Body, ResponseBody};
+use crate::error::{ErrorBody, ErrorCode};
+use crate::router::app_headers::{self, AppHeader};
+
+/// Smaller bodies are sent uncompressed, the encoding overhead isn't worth it
+const MIN_COMPRESS_SIZE: usize = 1024;
+/// Brotli quality (0-11); 5 compresses well enough without slowing responses down
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+/// Header carrying the total number of items when a list is returned as CSV
+const TOTAL_COUNT_HEADER: &str = "x-total-count";
+
+/// Representations a response can be converted to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Csv,
+}
+
+/// Content encodings the server can produce.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+/// What the client accepts, read from the request before it is consumed by the router.
+#[derive(Debug, Default)]
+pub struct Preferences {
+    /// Acceptable formats, most preferred first; empty if none of ours is acceptable
+    formats: Vec<Format>,
+    encoding: Option<Encoding>,
+    /// Whether HTML is preferred to JSON, as by browsers
+    html: bool,
+}
+
+impl Preferences {
+    /// Reads the `Accept` and `Accept-Encoding` headers of a request.
+    pub fn from_request<B>(req: &Request<B>) -> Preferences {
+        let headers = req.headers();
+
+        let mut html = false;
+        let formats = match header_str(headers, ACCEPT) {
+            Some(accept) => {
+                let entries = parse_quality_list(accept);
+                html = media_quality(&entries, "text", "html")
+                    > media_quality(&entries, "application", "json");
+                let mut formats: Vec<(Format, f32)> = [
+                    (Format::Json, media_quality(&entries, "application", "json")),
+                    (Format::Csv, media_quality(&entries, "text", "csv")),
+                ]
+                .into_iter()
+                .filter(|(_, q)| *q > 0.0)
+                .collect();
+                // Stable sort: JSON wins ties
+                formats.sort_by(|a, b| b.1.total_cmp(&a.1));
+                formats.into_iter().map(|(format, _)| format).collect()
+            }
+            None => vec![Format::Json],
+        };
+
+        let encoding = header_str(headers, ACCEPT_ENCODING).and_then(|accept| {
+            let entries = parse_quality_list(accept);
+            let quality = |name: &str| {
+                entries
+                    .iter()
+                    .find(|(value, _)| value == name)
+                    .or_else(|| entries.iter().find(|(value, _)| value == "*"))
+                    .map_or(0.0, |(_, q)| *q)
+            };
+            let (br, gzip) = (quality("br"), quality("gzip"));
+            if br > 0.0 && br >= gzip {
+                Some(Encoding::Brotli)
+            } else if gzip > 0.0 {
+                Some(Encoding::Gzip)
+            } else {
+                None
+            }
+        });
+
+        Preferences {
+            formats,
+            encoding,
+            html,
+        }
+    }
+
+    /// Whether the client prefers HTML to JSON.
+    pub fn prefers_html(&self) -> bool {
+        self.html
+    }
+}
+
+/// Converts and compresses a response according to the client preferences.
+///
+/// # Arguments
+///
+/// * `prefs` - Preferences read from the request
+/// * `res` - Response produced by the router
+///
+/// # Returns
+///
+/// The final response, with 406 Not Acceptable if a successful response can't be
+/// produced in any of the accepted formats
+pub fn negotiate(prefs: &Preferences, res: Response<Body>) -> Response<ResponseBody> {
+    // Streams are sent as they are produced, in the format chosen by the handler
+    let (parts, body) = res.into_parts();
+    let res = match body {
+        Body::Buffered(text) => Response::from_parts(parts, text),
+        stream => return Response::from_parts(parts, stream.into_response_body()),
+    };
+
+    let res = if res.status().is_success() && is_json(res.headers()) {
+        select_format(prefs, res)
+    } else {
+        res
+    };
+
+    let (mut parts, body) = res.into_parts();
+    let mut body = Bytes::from(body);
+
+    if let Some(encoding) = prefs.encoding
+        && body.len() >= MIN_COMPRESS_SIZE
+        && !parts.headers.contains_key(CONTENT_ENCODING)
+    {
+        match compress(encoding, &body) {
+            Ok(compressed) => {
+                body = Bytes::from(compressed);
+                let value = match encoding {
+                    Encoding::Brotli => "br",
+                    Encoding::Gzip => "gzip",
+                };
+                parts
+                    .headers
+                    .insert(CONTENT_ENCODING, HeaderValue::from_static(value));
+                parts.headers.remove(CONTENT_LENGTH);
+            }
+            Err(e) => warn!("Response not compressed: {}", e),
+        }
+    }
+
+    // Caches must store a separate copy for every Accept/Accept-Encoding combination
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept, accept-encoding"));
+
+    Response::from_parts(parts, Either::Left(Full::new(body)))
+}
+
+/// Returns the response in the most preferred format that applies to it.
+fn select_format(prefs: &Preferences, res: Response<String>) -> Response<String> {
+    for format in &prefs.formats {
+        match format {
+            Format::Json => return res,
+            Format::Csv => {
+                if let Some(csv) = list_to_csv(&res) {
+                    return csv;
+                }
+            }
+        }
+    }
+
+    let mut body = ErrorBody::new(
+        ErrorCode::NotAcceptable,
+        "The resource is only available as application/json or text/csv (lists)",
+    );
+    // Negotiation runs after the request scope, the ID is taken from the response
+    body.request_id = app_headers::get(res.headers(), AppHeader::RequestId)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let mut not_acceptable = Response::builder()
+        .status(StatusCode::NOT_ACCEPTABLE)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(&body).unwrap())
+        .unwrap();
+    copy_headers(res.headers(), not_acceptable.headers_mut());
+    not_acceptable
+}
+
+/// Keeps the headers added by the middlewares (request ID, CORS, ...) when a response
+/// is replaced by another representation.
+fn copy_headers(from: &HeaderMap, to: &mut HeaderMap) {
+    for (name, value) in from {
+        if name != CONTENT_TYPE && name != CONTENT_LENGTH {
+            to.append(name, value.clone());
+        }
+    }
+}
+
+/// Converts a list response (`{"data": [...], "pagination": {...}}`) into CSV,
+/// or returns `None` if the response is not a list.
+fn list_to_csv(res: &Response<String>) -> Option<Response<String>> {
+    let value: Value = serde_json::from_str(res.body()).ok()?;
+    let items = value.get("data")?.as_array()?;
+    let pagination = value.get("pagination")?;
+
+    // Columns of every item, in the order they first appear
+    let mut columns: Vec<&str> = Vec::new();
+    for item in items {
+        for key in item.as_object()?.keys() {
+            if !columns.contains(&key.as_str()) {
+                columns.push(key);
+            }
+        }
+    }
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(&columns).ok()?;
+    for item in items {
+        let object: &Map<String, Value> = item.as_object()?;
+        let record = columns.iter().map(|column| match object.get(*column) {
+            None | Some(Value::Null) => String::new(),
+            Some(Value::String(s)) => s.clone(),
+            Some(other) => other.to_string(),
+        });
+        writer.write_record(record).ok()?;
+    }
+    let body = String::from_utf8(writer.into_inner().ok()?).ok()?;
+
+    let mut builder = Response::builder()
+        .status(res.status())
+        .header(CONTENT_TYPE, "text/csv; charset=utf-8");
+
+    if let Some(total) = pagination.get("total").and_then(Value::as_i64) {
+        builder = builder.header(TOTAL_COUNT_HEADER, total);
+    }
+    let links: Vec<String> = ["next", "prev"]
+        .iter()
+        .filter_map(|rel| {
+            let url = pagination.get(*rel)?.as_str()?;
+            Some(format!("<{}>; rel=\"{}\"", url, rel))
+        })
+        .collect();
+    if !links.is_empty() {
+        builder = builder.header(LINK, links.join(", "));
+    }
+
+    let mut csv = builder.body(body).ok()?;
+    copy_headers(res.headers(), csv.headers_mut());
+    Some(csv)
+}
+
+fn compress(encoding: Encoding, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        Encoding::Brotli => {
+            let mut encoder =
+                brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+            encoder.write_all(data)?;
+            encoder.flush()?;
+            Ok(encoder.into_inner())
+        }
+    }
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    header_str(headers, CONTENT_TYPE).is_some_and(|value| value.starts_with("application/json"))
+}
+
+fn header_str(headers: &HeaderMap, name: hyper::header::HeaderName) -> Option<&str> {
+    headers.get(name)?.to_str().ok()
+}
+
+/// Parses a header like `gzip;q=0.8, br` into lowercase values and their quality.
+fn parse_quality_list(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let value = parts.next()?.trim().to_ascii_lowercase();
+            if value.is_empty() {
+                return None;
+            }
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((value, quality))
+        })
+        .collect()
+}
+
+/// Quality of a media type in a parsed `Accept` header, using the most specific match
+/// (`text/csv` over `text/*` over `*/*`).
+fn media_quality(entries: &[(String, f32)], kind: &str, subtype: &str) -> f32 {
+    let exact = format!("{}/{}", kind, subtype);
+    let wildcard = format!("{}/*", kind);
+
+    [exact.as_str(), wildcard.as_str(), "*/*"]
+        .iter()
+        .find_map(|candidate| {
+            entries
+                .iter()
+                .find(|(value, _)| value == candidate)
+                .map(|(_, q)| *q)
+        })
+        .unwrap_or(0.0)
+}
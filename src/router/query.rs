@@ -0,0 +1,229 @@
+//! Query string parsing utilities.
+//!
+//! Provides a generic `parse` function that deserializes the query string of a request
+//! into any `Deserialize` type, the pagination/sorting parameters shared by the
+//! list endpoints (`?limit=&offset=&sort=&order=`) and their filters (`?age_gte=18`).
+
+use hyper::Request;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::error::AppError;
+use crate::repository::filter::{Filter, FilterField};
+
+/// Number of items returned when `limit` is not specified
+pub const DEFAULT_LIMIT: i64 = 20;
+/// Maximum number of items a client can request in a single page
+pub const MAX_LIMIT: i64 = 100;
+
+/// Parameters of the list endpoints that aren't filters
+const LIST_PARAMETERS: &[&str] = &[
+    "limit",
+    "offset",
+    "sort",
+    "order",
+    "stream",
+    "modified_since",
+    "include_deleted",
+    "view",
+];
+
+/// Deserializes the query string of a request.
+///
+/// A request without query string is parsed as an empty one, so every field
+/// of `T` must be optional (or have a default) to accept it.
+///
+/// # Arguments
+///
+/// * `req` - The request whose URI contains the query string
+///
+/// # Returns
+///
+/// * `Result<T, AppError>` - The parsed value, or an `AppError::Validation`
+///   describing the invalid parameter
+pub fn parse<T: DeserializeOwned, B>(req: &Request<B>) -> Result<T, AppError> {
+    let query = req.uri().query().unwrap_or("");
+
+    serde_urlencoded::from_str::<T>(query)
+        .map_err(|e| AppError::Validation(format!("Invalid query parameters: {}", e)))
+}
+
+/// Parses the filters of a list request: every query parameter that isn't a
+/// pagination, sorting or delta one.
+///
+/// # Arguments
+///
+/// * `req` - The request whose URI contains the query string
+/// * `fields` - Whitelist of the columns the resource can be filtered by
+///
+/// # Returns
+///
+/// * `Result<Filter, AppError>` - The filter, or an `AppError::Validation` for an
+///   unknown field, an invalid operator or a malformed value
+pub fn filter<B>(req: &Request<B>, fields: &[FilterField]) -> Result<Filter, AppError> {
+    let params = parse::<Vec<(String, String)>, _>(req)?
+        .into_iter()
+        .filter(|(name, _)| !LIST_PARAMETERS.contains(&name.as_str()))
+        .collect();
+    Filter::parse(params, fields)
+}
+
+/// Sort direction of a list endpoint (`?order=asc|desc`).
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    fn as_sql(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+
+    pub fn as_query(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+}
+
+/// Raw pagination and sorting parameters of a list endpoint.
+///
+/// `?limit=20&offset=40&sort=name&order=desc`
+#[derive(Deserialize, Default, Debug)]
+pub struct ListQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+    pub sort: Option<String>,
+    pub order: Option<SortOrder>,
+    /// `?stream=true`: every item in a streamed JSON array instead of a page
+    pub stream: Option<bool>,
+}
+
+impl ListQuery {
+    /// Validates the parameters against the columns a resource can be sorted by.
+    ///
+    /// # Arguments
+    ///
+    /// * `sortable` - Whitelist of sortable columns; the first one is the default sort.
+    ///   Only these values ever reach the SQL `ORDER BY` clause.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Pagination, AppError>` - The normalized pagination, or a validation
+    ///   error for a negative offset or an unknown sort column
+    pub fn pagination(&self, sortable: &[&'static str]) -> Result<Pagination, AppError> {
+        // Out of range limits are clamped instead of rejected
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+        let offset = self.offset.unwrap_or(0);
+        if offset < 0 {
+            return Err(AppError::Validation("offset must be >= 0".to_string()));
+        }
+
+        let sort = match &self.sort {
+            Some(sort) => *sortable.iter().find(|col| **col == sort).ok_or_else(|| {
+                AppError::Validation(format!(
+                    "Invalid sort field '{}', expected one of: {}",
+                    sort,
+                    sortable.join(", ")
+                ))
+            })?,
+            None => sortable[0],
+        };
+
+        Ok(Pagination {
+            limit,
+            offset,
+            sort,
+            order: self.order.unwrap_or_default(),
+        })
+    }
+}
+
+/// Validated pagination and sorting, safe to be used in SQL.
+#[derive(Debug)]
+pub struct Pagination {
+    pub limit: i64,
+    pub offset: i64,
+    pub sort: &'static str,
+    pub order: SortOrder,
+}
+
+impl Pagination {
+    /// Builds the `ORDER BY` clause. The column always comes from the whitelist
+    /// given to `ListQuery::pagination`, so it can't be used for SQL injection.
+    pub fn order_by_clause(&self) -> String {
+        format!("ORDER BY {} {}", self.sort, self.order.as_sql())
+    }
+
+    /// Wraps a page of items in the JSON envelope returned by list endpoints.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the endpoint, used to build the `next`/`prev` links
+    /// * `filter` - Filters of the request, kept in the links
+    /// * `data` - Items of the current page
+    /// * `total` - Total number of matching items across all pages
+    pub fn page<T: Serialize>(
+        &self,
+        path: &str,
+        filter: &Filter,
+        data: Vec<T>,
+        total: i64,
+    ) -> Page<T> {
+        let link = |offset| self.link(path, filter, offset);
+        let next = (self.offset + self.limit < total).then(|| link(self.offset + self.limit));
+        let prev = (self.offset > 0).then(|| link((self.offset - self.limit).max(0)));
+
+        Page {
+            data,
+            pagination: PageMeta {
+                total,
+                limit: self.limit,
+                offset: self.offset,
+                next,
+                prev,
+            },
+        }
+    }
+
+    /// Link to another page keeping the same limit, sorting and filters.
+    fn link(&self, path: &str, filter: &Filter, offset: i64) -> String {
+        let mut link = format!(
+            "{}?limit={}&offset={}&sort={}&order={}",
+            path,
+            self.limit,
+            offset,
+            self.sort,
+            self.order.as_query()
+        );
+        let filters = filter.query_string();
+        if !filters.is_empty() {
+            link.push('&');
+            link.push_str(&filters);
+        }
+        link
+    }
+}
+
+/// JSON envelope of a paginated list.
+#[derive(Serialize)]
+pub struct Page<T> {
+    data: Vec<T>,
+    pagination: PageMeta,
+}
+
+#[derive(Serialize)]
+struct PageMeta {
+    total: i64,
+    limit: i64,
+    offset: i64,
+    next: Option<String>,
+    prev: Option<String>,
+}
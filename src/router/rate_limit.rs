@@ -0,0 +1,237 @@
+//! Rate limiting.
+//!
+//! Enabled with `RATE_LIMIT_PER_MINUTE`: every client gets a token bucket holding that
+//! many requests, refilled continuously over a minute. A request finding the bucket
+//! empty is answered with 429 Too Many Requests and a `Retry-After` header.
+//!
+//! Clients are identified by:
+//! - the user of the access token, when the request carries a valid one (so a client
+//!   behind a shared NAT isn't limited by its neighbours)
+//! - otherwise the IP address of the client: the TCP peer, the address of the PROXY
+//!   protocol header with `PROXY_PROTOCOL`, or the address reported by the reverse
+//!   proxies of `TRUSTED_PROXIES` (see the `forwarded` module)
+//!
+//! Every response of a limited request carries the state of its bucket:
+//! `X-App-RateLimit-Limit`, `X-App-RateLimit-Remaining` and `X-App-RateLimit-Reset`
+//! (seconds until the bucket is full again), see the `app_headers` module.
+//!
+//! Buckets live in memory, so each instance limits the requests it receives. Sharing
+//! them between instances means implementing [`RateLimitStore`] on a shared backend.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use hyper::{
+    Request, Response,
+    header::{HeaderValue, RETRY_AFTER},
+};
+use tracing::warn;
+
+use crate::auth::authenticate;
+use crate::config::ServerConfig;
+use crate::error::AppError;
+use crate::forwarded::ClientInfo;
+use crate::router::app_headers::{self, AppHeader};
+
+/// How often buckets that filled up again are dropped from memory
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Set once at startup, unset when rate limiting is disabled
+static RATE_LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+
+struct RateLimiter {
+    quota: Quota,
+    store: Box<dyn RateLimitStore>,
+}
+
+/// Requests allowed per client.
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    pub per_minute: u32,
+}
+
+impl Quota {
+    /// Tokens added to a bucket per second
+    fn refill_rate(self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+}
+
+/// State of a bucket after a request took (or failed to take) a token from it.
+#[derive(Debug, Clone, Copy)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until the bucket is full again
+    pub reset: Duration,
+    /// Time until the next request is allowed, zero when it already is
+    pub retry_after: Duration,
+}
+
+/// Boxed future returned by [`RateLimitStore::acquire`].
+pub type AcquireFuture<'a> = Pin<Box<dyn Future<Output = Result<Decision, AppError>> + Send + 'a>>;
+
+/// Storage of the token buckets.
+///
+/// The future is boxed so stores doing network calls (Redis, ...) can be plugged in
+/// next to the in-memory one.
+pub trait RateLimitStore: Send + Sync + 'static {
+    /// Takes a token from the bucket of `key`, creating a full bucket if it has none.
+    fn acquire<'a>(&'a self, key: &'a str, quota: Quota) -> AcquireFuture<'a>;
+}
+
+/// Buckets kept in the memory of this instance.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    buckets: HashMap<String, Bucket>,
+    last_sweep: Option<Instant>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Tokens available at `now`, the bucket filling up since its last update.
+    fn tokens_at(&self, now: Instant, quota: Quota) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * quota.refill_rate()).min(f64::from(quota.per_minute))
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn acquire<'a>(&'a self, key: &'a str, quota: Quota) -> AcquireFuture<'a> {
+        Box::pin(async move {
+            let now = Instant::now();
+            let capacity = f64::from(quota.per_minute);
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+            // Full buckets hold no information, drop them so the map doesn't grow
+            // with every client ever seen
+            if state
+                .last_sweep
+                .is_none_or(|last| now.duration_since(last) >= SWEEP_INTERVAL)
+            {
+                state
+                    .buckets
+                    .retain(|_, bucket| bucket.tokens_at(now, quota) < capacity);
+                state.last_sweep = Some(now);
+            }
+
+            let bucket = state.buckets.entry(key.to_string()).or_insert(Bucket {
+                tokens: capacity,
+                updated: now,
+            });
+            bucket.tokens = bucket.tokens_at(now, quota);
+            bucket.updated = now;
+
+            let allowed = bucket.tokens >= 1.0;
+            if allowed {
+                bucket.tokens -= 1.0;
+            }
+            let rate = quota.refill_rate();
+            Ok(Decision {
+                allowed,
+                limit: quota.per_minute,
+                remaining: bucket.tokens as u32,
+                reset: Duration::from_secs_f64((capacity - bucket.tokens) / rate),
+                retry_after: if allowed {
+                    Duration::ZERO
+                } else {
+                    Duration::from_secs_f64((1.0 - bucket.tokens) / rate)
+                },
+            })
+        })
+    }
+}
+
+/// Enables rate limiting if `RATE_LIMIT_PER_MINUTE` is set.
+/// This function should be called at application startup, before serving requests.
+pub fn init_rate_limit(config: &ServerConfig) {
+    let Some(per_minute) = config.rate_limit_per_minute else {
+        return;
+    };
+
+    let limiter = RateLimiter {
+        quota: Quota { per_minute },
+        store: Box::new(MemoryStore::default()),
+    };
+    if RATE_LIMITER.set(limiter).is_err() {
+        warn!("Attempt to reset the rate limiter ignored");
+    }
+}
+
+/// Takes a token from the bucket of the client sending `req`.
+///
+/// # Returns
+///
+/// * `Option<Decision>` - The state of the bucket, `None` if rate limiting is disabled,
+///   the client can't be identified or the store failed (requests are let through
+///   rather than rejected when the limit can't be checked)
+pub async fn check_rate_limit<B>(req: &Request<B>) -> Option<Decision> {
+    let limiter = RATE_LIMITER.get()?;
+    let key = client_key(req)?;
+
+    match limiter.store.acquire(&key, limiter.quota).await {
+        Ok(decision) => Some(decision),
+        Err(e) => {
+            warn!("Rate limit check failed, request let through: {}", e);
+            None
+        }
+    }
+}
+
+/// Error answered when the bucket of the client is empty.
+pub fn rate_limited(decision: &Decision) -> AppError {
+    AppError::TooManyRequests(format!(
+        "Rate limit of {} requests per minute exceeded",
+        decision.limit
+    ))
+}
+
+/// Registers the `X-App-RateLimit-*` headers, and adds `Retry-After` when the request
+/// was rejected.
+pub fn apply_rate_limit_headers<B>(decision: &Decision, res: &mut Response<B>) {
+    app_headers::set(res, AppHeader::RateLimitLimit, decision.limit.into());
+    app_headers::set(
+        res,
+        AppHeader::RateLimitRemaining,
+        decision.remaining.into(),
+    );
+    app_headers::set(
+        res,
+        AppHeader::RateLimitReset,
+        ceil_secs(decision.reset).into(),
+    );
+    if !decision.allowed {
+        res.headers_mut().insert(
+            RETRY_AFTER,
+            HeaderValue::from(ceil_secs(decision.retry_after)),
+        );
+    }
+}
+
+/// Whole seconds, rounded up so clients don't retry too early.
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs_f64().ceil() as u64
+}
+
+/// Identifies the client of a request, see the module documentation.
+fn client_key<B>(req: &Request<B>) -> Option<String> {
+    if let Ok(user) = authenticate(req) {
+        return Some(format!("user:{}", user.id));
+    }
+
+    let client = ClientInfo::of(req)?;
+    Some(format!("ip:{}", client.ip))
+}
@@ -0,0 +1,435 @@
+//! Route table of the API.
+//!
+//! Every endpoint is registered here with its method and path pattern.
+//! Handlers live in one submodule per resource.
+
+mod archives;
+mod audit;
+pub(crate) mod auth;
+mod carts;
+mod cdc;
+mod console;
+mod coupons;
+mod dashboard;
+mod diagnostics;
+pub(crate) mod docs;
+mod experiments;
+mod export;
+mod health;
+mod jobs;
+mod ledger;
+mod metrics;
+mod oidc;
+mod orders;
+mod passkeys;
+mod products;
+mod recommendations;
+mod retention;
+mod scim;
+mod search;
+mod signed_urls;
+mod sse;
+mod static_files;
+pub(crate) mod tools;
+pub(crate) mod users;
+mod views;
+mod webhooks;
+mod ws;
+
+use hyper::{Request, Response, body::Incoming};
+
+use crate::roles::Role;
+use crate::router::{HandlerResult, Params, Router};
+
+/// Builds the router with every route of the API.
+///
+/// The operational routes are unversioned, the API lives under `/api/v1`. Its former
+/// unprefixed paths (`/users`) still work but are deprecated (see
+/// `Router::legacy_prefix`). A future version is another router mounted next to it:
+/// `.nest("/api/v2", v2_routes())`.
+///
+/// # Routes
+///
+/// - `GET /`: Basic greeting message
+/// - `GET /healthz`: Liveness probe
+/// - `GET /readyz`: Readiness probe (database check and pool statistics)
+/// - `GET /metrics`: Prometheus metrics
+/// - `GET /admin/runtime`: Runtime diagnostics (tasks, blocking pool, pool waiters),
+///   requires the admin role
+/// - `GET /admin/heap-profile`: Heap profile of a `jemalloc` build, requires the
+///   admin role
+/// - `GET /debug/pprof/profile`: CPU profile of a `pprof` build, requires the admin
+///   role
+/// - `GET /admin/jobs`: Latest background jobs and their outcome, requires the admin
+///   role
+/// - `GET /admin/audit`: Latest writes to users, products and orders, requires the
+///   admin role
+/// - `PUT /admin/users/:id/role`: Change the role of a user (see `roles`), requires
+///   the admin role
+/// - `GET /admin/retention`: Rows each data retention rule would remove now, requires
+///   the admin role, accepts signed URLs
+/// - `GET /admin/archives`: Partitions exported to the file storage, requires the admin
+///   role
+/// - `POST /admin/archives/:id/restore`: Restores an exported partition in the
+///   background, requires the admin role
+/// - `GET /admin/export/parquet`: Snapshot of a table as a Parquet file, requires the
+///   admin role, accepts signed URLs
+/// - `GET /admin/queries`: Read-only queries of the admin console, requires the admin
+///   role
+/// - `POST /admin/queries/:name/run`: Rows of a console query, requires the admin role
+/// - `GET /admin/slo`: Burn rates and status of the service level objectives, requires
+///   the admin role, accepts signed URLs
+/// - `GET /admin/ledger/check`: Consistency of the ledger of the user credits, requires
+///   the admin role
+/// - `GET /openapi.json`: OpenAPI 3.0 description of the API
+/// - `GET /docs`: Browsable documentation (Swagger UI)
+/// - `GET /console`: Page sending requests to any route, with `DEV_CONSOLE` only
+/// - `GET /static/*path`: Files of `STATIC_DIR`, a frontend (see `static_files`)
+/// - `GET /.well-known/openid-configuration`: Discovery document of the OpenID Connect
+///   provider
+/// - `GET /oauth/jwks`: Keys of the ID tokens
+/// - `GET /oauth/authorize`: Login page of an app logging its users in
+/// - `POST /oauth/authorize`: Logs in and sends the browser back to the app with a code
+/// - `POST /oauth/token`: Exchanges a code for an ID token and an access token
+/// - `GET /oauth/userinfo`: Claims of the user of an access token, requires the client
+///   token of an app (checked by the handler) or an access token
+/// - `GET /scim/v2/Users`: Accounts for an identity provider, with a SCIM filter;
+///   requires the SCIM token, as every SCIM route
+/// - `POST /scim/v2/Users`: Provisions an account
+/// - `GET /scim/v2/Users/:id`: An account
+/// - `PUT /scim/v2/Users/:id`: Replaces an account
+/// - `PATCH /scim/v2/Users/:id`: Updates an account, deactivating it for instance
+/// - `DELETE /scim/v2/Users/:id`: Deprovisions an account
+/// - `/api/v1/...`: See [`v1_routes`]
+pub fn build_router() -> Router {
+    Router::new()
+        .get("/", handle_root)
+        // Health checks
+        .get("/healthz", health::handle_liveness)
+        .get("/readyz", health::handle_readiness)
+        .get("/metrics", metrics::handle_metrics)
+        .get("/admin/runtime", diagnostics::handle_runtime)
+        .require_role(Role::Admin)
+        .get("/admin/heap-profile", diagnostics::handle_heap_profile)
+        .require_role(Role::Admin)
+        .get("/debug/pprof/profile", diagnostics::handle_cpu_profile)
+        .require_role(Role::Admin)
+        .get("/admin/jobs", jobs::handle_list_jobs)
+        .require_role(Role::Admin)
+        .get("/admin/audit", audit::handle_list_audit)
+        .require_role(Role::Admin)
+        .put("/admin/users/:id/role", users::handle_set_user_role)
+        .require_role(Role::Admin)
+        .get("/admin/retention", retention::handle_retention_report)
+        .require_role(Role::Admin)
+        .allow_signed_urls()
+        .get("/admin/archives", archives::handle_list_archives)
+        .require_role(Role::Admin)
+        .post(
+            "/admin/archives/:id/restore",
+            archives::handle_restore_archive,
+        )
+        .require_role(Role::Admin)
+        .get("/admin/export/parquet", export::handle_export_parquet)
+        .require_role(Role::Admin)
+        .allow_signed_urls()
+        .get("/admin/queries", console::handle_list_queries)
+        .require_role(Role::Admin)
+        .post("/admin/queries/:name/run", console::handle_run_query)
+        .require_role(Role::Admin)
+        .get("/admin/slo", metrics::handle_slo)
+        .require_role(Role::Admin)
+        .allow_signed_urls()
+        .get("/admin/ledger/check", ledger::handle_check_ledger)
+        .require_role(Role::Admin)
+        // Documentation
+        .get("/openapi.json", docs::handle_openapi)
+        .get("/docs", docs::handle_docs)
+        .get("/console", docs::handle_console)
+        // Frontend
+        .get("/static/*path", static_files::handle_static_file)
+        .get("/.well-known/openid-configuration", oidc::handle_discovery)
+        .get("/oauth/jwks", oidc::handle_jwks)
+        .get("/oauth/authorize", oidc::handle_authorize_form)
+        .post("/oauth/authorize", oidc::handle_authorize)
+        .post("/oauth/token", oidc::handle_token)
+        .get("/oauth/userinfo", oidc::handle_userinfo)
+        // SCIM provisioning, with its own token
+        .get("/scim/v2/Users", scim::handle_list_users)
+        .post("/scim/v2/Users", scim::handle_create_user)
+        .get("/scim/v2/Users/:id", scim::handle_get_user)
+        .put("/scim/v2/Users/:id", scim::handle_replace_user)
+        .patch("/scim/v2/Users/:id", scim::handle_patch_user)
+        .delete("/scim/v2/Users/:id", scim::handle_delete_user)
+        .nest("/api/v1", v1_routes())
+        .legacy_prefix("/api/v1")
+}
+
+/// Routes of the version 1 of the API, relative to `/api/v1`.
+///
+/// Routes marked with 🔒 require an `Authorization: Bearer <token>` header
+/// obtained from `POST /api/v1/auth/login`. Some of them also require a role
+/// (see `roles`).
+///
+/// # Routes
+///
+/// - `POST /auth/register`: Create an account
+/// - `POST /auth/login`: Obtain an access token
+/// - `GET /auth/me` 🔒: Get the profile of the authenticated user
+/// - `POST /auth/passkeys/registration` 🔒: Start the registration of a passkey
+/// - `POST /auth/passkeys` 🔒: Register a passkey created by the browser
+/// - `GET /auth/passkeys` 🔒: Passkeys of the caller
+/// - `DELETE /auth/passkeys/:id` 🔒: Remove a passkey
+/// - `PUT /auth/second-factor` 🔒: Require a passkey after the password, or stop
+/// - `POST /auth/passkeys/authentication`: Start a login without password
+/// - `POST /auth/passkeys/login`: Obtain an access token with a passkey
+/// - `POST /signed-urls` 🔒: Sign a URL of a download or report, valid without access
+///   token until it expires
+/// - `GET /dashboard` 🔒: Users summary, product stats, latest orders and the parts of
+///   the upstream services
+/// - `GET /experiments`: Running experiments and the variants of the caller
+/// - `GET /users`: List all users
+/// - `POST /users` 🔒: Create a new user with JSON data
+/// - `POST /users/bulk` 🔒: Create many users (JSON array or NDJSON)
+/// - `POST /users/bulk-delete` 🔒: Delete the users matching a filter, in the background
+///   (admin role)
+/// - `POST /users/views` 🔒: Save a named filter and sort of the list (`?view=<name>`)
+/// - `GET /users/views` 🔒: Saved views of the list
+/// - `GET /users/:id`: Get information for a specific user
+/// - `PUT /users/:id` 🔒: Replace all the fields of a user
+/// - `PATCH /users/:id` 🔒: Update some fields of a user
+/// - `DELETE /users/:id` 🔒: Soft-delete a user (`?hard=true` to delete them for good,
+///   with the admin role)
+/// - `POST /users/:id/restore` 🔒: Undelete a soft-deleted user
+/// - `POST /users/:id/orders` 🔒: Place an order (takes the quantity from the product
+///   stock, redeems a coupon)
+/// - `POST /users/:id/orders/:order_id/refund` 🔒: Refund an order to the credit of the
+///   user (admin role)
+/// - `GET /users/:id/balance` 🔒: Credit of a user and their latest ledger entries
+/// - `POST /users/:id/credits` 🔒: Grant credit to a user, or take it back (admin role)
+/// - `POST /users/:id/avatar` 🔒: Upload the avatar of a user (`multipart/form-data`)
+/// - `GET /users/:id/avatar`: Download the avatar of a user (accepts signed URLs)
+/// - `GET /users/:id/history` 🔒: Changes of a user, field by field (audit log),
+///   requires the admin role
+/// - `GET /users/:id/recommendations` 🔒: Products recommended to a user, from the
+///   co-purchases and the popular products
+/// - `GET /products`: List all products
+/// - `POST /products` 🔒: Create a new product with JSON data (editor role)
+/// - `POST /products/bulk` 🔒: Create many products, JSON array or NDJSON (editor role)
+/// - `POST /products/views` 🔒: Save a named filter and sort of the list
+/// - `GET /products/views` 🔒: Saved views of the list
+/// - `GET /products/semantic-search`: Products matching the meaning of a text, blended
+///   with those matching its words
+/// - `GET /products/:id`: Get information for a specific product
+/// - `PUT /products/:id` 🔒: Replace all the fields of a product (editor role)
+/// - `DELETE /products/:id` 🔒: Delete a product (editor role)
+/// - `GET /products/:id/history` 🔒: Changes of a product, field by field (audit log),
+///   requires the admin role
+/// - `GET /cart`: Cart of the caller, of the user of the access token or of the `cart`
+///   cookie
+/// - `PUT /cart/items/:product_id`: Set the quantity of a product in the cart (starts
+///   an anonymous cart, with its cookie, without access token)
+/// - `DELETE /cart/items/:product_id`: Remove a product from the cart
+/// - `POST /cart/checkout` 🔒: Order every item of the cart, and empty it
+/// - `GET /wishlist` 🔒: Wishlist of the caller
+/// - `PUT /wishlist/:product_id` 🔒: Add a product to the wishlist
+/// - `DELETE /wishlist/:product_id` 🔒: Remove a product from the wishlist
+/// - `POST /coupons` 🔒: Create a coupon, a percentage or an amount off the orders
+///   (editor role)
+/// - `GET /coupons` 🔒: List the coupons (editor role)
+/// - `DELETE /coupons/:id` 🔒: Withdraw a coupon, keeping its redemptions (editor role)
+/// - `GET /coupons/:id/redemptions` 🔒: Latest redemptions of a coupon (editor role)
+/// - `GET /search`: Users and products matching a text, from the search engine or the
+///   database
+/// - `GET /search/suggest`: Completions and corrections of a search, from the product
+///   and user names
+/// - `GET /operations/:id` 🔒: Status and progress of an operation started by a request
+/// - `POST /webhooks` 🔒: Register a URL receiving the change events, signed
+/// - `GET /webhooks` 🔒: Webhooks of the caller
+/// - `GET /webhooks/:id` 🔒: Get a webhook of the caller
+/// - `PUT /webhooks/:id` 🔒: Replace the URL, secret and events of a webhook
+/// - `DELETE /webhooks/:id` 🔒: Delete a webhook
+/// - `GET /webhooks/:id/deliveries` 🔒: Latest delivery attempts of a webhook
+/// - `GET /cdc/:table` 🔒: Changes of the users, products or orders after a position,
+///   in order (admin role)
+/// - `GET /cdc/:table/checkpoints/:consumer` 🔒: Saved position of a consumer of a feed
+///   (admin role)
+/// - `PUT /cdc/:table/checkpoints/:consumer` 🔒: Save the position of a consumer (admin
+///   role)
+/// - `GET /ws`: WebSocket receiving every user and product change
+/// - `GET /events`: Server-Sent Events stream of the same changes
+/// - `GET /events/schemas`: Versioned schemas of the change events
+/// - `GET /tools`: Read-only tools for LLM agents, with the JSON Schema of their
+///   arguments
+/// - `POST /tools/:name/call` 🔒: Call a tool with JSON arguments
+fn v1_routes() -> Router {
+    Router::new()
+        // Auth
+        .post("/auth/register", auth::handle_register)
+        .post("/auth/login", auth::handle_login)
+        .get("/auth/me", auth::handle_me)
+        .require_auth()
+        .post(
+            "/auth/passkeys/registration",
+            passkeys::handle_start_registration,
+        )
+        .require_auth()
+        .post("/auth/passkeys", passkeys::handle_register_passkey)
+        .require_auth()
+        .get("/auth/passkeys", passkeys::handle_list_passkeys)
+        .require_auth()
+        .delete("/auth/passkeys/:id", passkeys::handle_delete_passkey)
+        .require_auth()
+        .put("/auth/second-factor", passkeys::handle_second_factor)
+        .require_auth()
+        .post(
+            "/auth/passkeys/authentication",
+            passkeys::handle_start_login,
+        )
+        .post("/auth/passkeys/login", passkeys::handle_passkey_login)
+        .post("/signed-urls", signed_urls::handle_sign_url)
+        .require_auth()
+        .get("/dashboard", dashboard::handle_dashboard)
+        .require_auth()
+        .get("/experiments", experiments::handle_experiments)
+        // Users
+        .get("/users", users::handle_get_all_users)
+        .post("/users", users::handle_create_user)
+        .require_auth()
+        .post("/users/bulk", users::handle_create_users)
+        .require_auth()
+        .post("/users/bulk-delete", users::handle_bulk_delete_users)
+        .require_role(Role::Admin)
+        // Before /users/:id, which would match them too
+        .post("/users/views", views::handle_save_user_view)
+        .require_auth()
+        .get("/users/views", views::handle_list_user_views)
+        .require_auth()
+        .get("/users/:id", users::handle_get_user)
+        .put("/users/:id", users::handle_update_user)
+        .require_auth()
+        .queue_when_offline()
+        .patch("/users/:id", users::handle_patch_user)
+        .require_auth()
+        .delete("/users/:id", users::handle_delete_user)
+        .require_auth()
+        .post("/users/:id/restore", users::handle_restore_user)
+        .require_auth()
+        .post("/users/:id/orders", orders::handle_create_order)
+        .require_auth()
+        .post(
+            "/users/:id/orders/:order_id/refund",
+            ledger::handle_refund_order,
+        )
+        .require_role(Role::Admin)
+        .get("/users/:id/balance", ledger::handle_get_balance)
+        .require_auth()
+        .post("/users/:id/credits", ledger::handle_grant_credit)
+        .require_role(Role::Admin)
+        .post("/users/:id/avatar", users::handle_upload_avatar)
+        .require_auth()
+        .get("/users/:id/avatar", users::handle_get_avatar)
+        .allow_signed_urls()
+        .get("/users/:id/history", audit::handle_user_history)
+        .require_role(Role::Admin)
+        .get(
+            "/users/:id/recommendations",
+            recommendations::handle_get_recommendations,
+        )
+        .require_auth()
+        // Products
+        .get("/products", products::handle_get_all_products)
+        .post("/products", products::handle_create_product)
+        .require_role(Role::Editor)
+        .post("/products/bulk", products::handle_create_products)
+        .require_role(Role::Editor)
+        .post("/products/views", views::handle_save_product_view)
+        .require_auth()
+        .get("/products/views", views::handle_list_product_views)
+        .require_auth()
+        .get(
+            "/products/semantic-search",
+            products::handle_semantic_search,
+        )
+        .get("/products/:id", products::handle_get_product)
+        .put("/products/:id", products::handle_update_product)
+        .require_role(Role::Editor)
+        .queue_when_offline()
+        .delete("/products/:id", products::handle_delete_product)
+        .require_role(Role::Editor)
+        .get("/products/:id/history", audit::handle_product_history)
+        .require_role(Role::Admin)
+        // Cart and wishlist
+        .get("/cart", carts::handle_get_cart)
+        .put("/cart/items/:product_id", carts::handle_set_item)
+        .delete("/cart/items/:product_id", carts::handle_remove_item)
+        .post("/cart/checkout", carts::handle_checkout)
+        .require_auth()
+        .get("/wishlist", carts::handle_get_wishlist)
+        .require_auth()
+        .put("/wishlist/:product_id", carts::handle_wish)
+        .require_auth()
+        .queue_when_offline()
+        .delete("/wishlist/:product_id", carts::handle_unwish)
+        .require_auth()
+        .queue_when_offline()
+        // Coupons
+        .post("/coupons", coupons::handle_create_coupon)
+        .require_role(Role::Editor)
+        .get("/coupons", coupons::handle_list_coupons)
+        .require_role(Role::Editor)
+        .delete("/coupons/:id", coupons::handle_delete_coupon)
+        .require_role(Role::Editor)
+        .get("/coupons/:id/redemptions", coupons::handle_list_redemptions)
+        .require_role(Role::Editor)
+        // Search
+        .get("/search", search::handle_search)
+        .get("/search/suggest", search::handle_suggest)
+        // Change notifications
+        .get("/operations/:id", jobs::handle_get_operation)
+        .require_auth()
+        .post("/webhooks", webhooks::handle_create_webhook)
+        .require_auth()
+        .get("/webhooks", webhooks::handle_list_webhooks)
+        .require_auth()
+        .get("/webhooks/:id", webhooks::handle_get_webhook)
+        .require_auth()
+        .put("/webhooks/:id", webhooks::handle_update_webhook)
+        .require_auth()
+        .delete("/webhooks/:id", webhooks::handle_delete_webhook)
+        .require_auth()
+        .get("/webhooks/:id/deliveries", webhooks::handle_list_deliveries)
+        .require_auth()
+        .get("/cdc/:table", cdc::handle_feed)
+        .require_role(Role::Admin)
+        .get(
+            "/cdc/:table/checkpoints/:consumer",
+            cdc::handle_get_checkpoint,
+        )
+        .require_role(Role::Admin)
+        .put(
+            "/cdc/:table/checkpoints/:consumer",
+            cdc::handle_save_checkpoint,
+        )
+        .require_role(Role::Admin)
+        .get("/ws", ws::handle_websocket)
+        .get("/events", sse::handle_events)
+        .get("/events/schemas", sse::handle_event_schemas)
+        // Tools for LLM agents
+        .get("/tools", tools::handle_list_tools)
+        .post("/tools/:name/call", tools::handle_call_tool)
+        .require_auth()
+}
+
+/// Handles GET requests to the root path.
+///
+/// # Route
+///
+/// `GET /`
+///
+/// # Response
+///
+/// Returns a plain text greeting message.
+async fn handle_root(_req: Request<Incoming>, _params: Params) -> HandlerResult {
+    Ok(Response::new("Hello World".into()))
+}
@@ -0,0 +1,85 @@
+//! Archives of the partitions exported to the file storage (see the `archives`
+//! module).
+
+use hyper::header::{HeaderValue, LOCATION};
+use hyper::{Request, StatusCode, body::Incoming};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::context::RequestContext;
+use crate::error::{AppError, ErrorCode};
+use crate::jobs::{self, Job};
+use crate::repository::archives::{ArchiveRepository, PgArchiveRepo};
+use crate::repository::jobs::{JobRepository, PgJobRepo};
+use crate::router::query::{self, DEFAULT_LIMIT, MAX_LIMIT};
+use crate::router::{HandlerResult, Params, json_response};
+
+/// `?limit=` of `GET /admin/archives`.
+#[derive(Deserialize, Default, Debug)]
+struct ArchivesQuery {
+    limit: Option<i64>,
+}
+
+/// Handles GET requests to list the manifests of the exported partitions.
+///
+/// # Route
+///
+/// `GET /admin/archives?limit=`
+///
+/// - `limit`: Number of archives (default 20, max 100)
+///
+/// # Response
+///
+/// - 200 OK with `[{id, table_name, partition_name, location, row_count, byte_size,
+///   sha256, created_at, restored_at}...]`, the latest first
+/// - 400 Bad Request if a query parameter is invalid
+/// - 401 Unauthorized without a valid access token
+pub async fn handle_list_archives(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let query = query::parse::<ArchivesQuery, _>(&req)?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let archives = PgArchiveRepo(db).list(limit).await?;
+    Ok(json_response(StatusCode::OK, archives))
+}
+
+/// Handles POST requests to restore an exported partition into the `archive` schema,
+/// in the background.
+///
+/// # Route
+///
+/// `POST /admin/archives/:id/restore`
+///
+/// # Response
+///
+/// - 202 Accepted with the operation (`Location: /api/v1/operations/:id`), whose
+///   `progress` names the `partition` once `restored` (`false` if it was there
+///   already)
+/// - 400 Bad Request if the ID is not a UUID
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if no archive has this ID
+pub async fn handle_restore_archive(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let archive_id = params
+        .parse::<Uuid>("id")
+        .ok_or_else(|| AppError::Validation("ID must be a UUID".to_string()))?;
+    if PgArchiveRepo(db).find(archive_id).await?.is_none() {
+        return Err(AppError::NotFound(
+            ErrorCode::ArchiveNotFound,
+            "Archive not found".to_string(),
+        ));
+    }
+
+    let id = jobs::submit(Job::RestoreArchive { archive_id }).await?;
+    let operation = PgJobRepo(db)
+        .find(id)
+        .await?
+        .ok_or_else(|| AppError::Internal(format!("Job {} vanished", id)))?;
+
+    let mut res = json_response(StatusCode::ACCEPTED, operation);
+    let location = format!("/api/v1/operations/{}", id);
+    if let Ok(location) = HeaderValue::from_str(&location) {
+        res.headers_mut().insert(LOCATION, location);
+    }
+    Ok(res)
+}
@@ -0,0 +1,112 @@
+//! Inspection of the audit log (see `repository::audit`).
+
+use hyper::{Request, StatusCode, body::Incoming};
+use serde::Deserialize;
+
+use crate::context::RequestContext;
+use crate::db::join_queries;
+use crate::error::AppError;
+use crate::repository::audit::{AuditRepository, Entity, PgAuditRepo};
+use crate::repository::filter::Filter;
+use crate::repository::ids::ResourceId;
+use crate::router::query::{self, DEFAULT_LIMIT, ListQuery, MAX_LIMIT};
+use crate::router::{HandlerResult, Params, json_response};
+
+use super::{products, users};
+
+/// Columns a history can be sorted by: `id`, the order of the changes
+const HISTORY_SORTABLE: &[&str] = &["id"];
+
+/// `?entity=&id=&limit=` of `GET /admin/audit`.
+#[derive(Deserialize, Default, Debug)]
+struct AuditQuery {
+    entity: Option<Entity>,
+    id: Option<String>,
+    limit: Option<i64>,
+}
+
+/// Handles GET requests to list the latest records of the audit log.
+///
+/// # Route
+///
+/// `GET /admin/audit?entity=&id=&limit=`
+///
+/// - `entity`: Only the changes of `user`, `product` or `order` rows
+/// - `id`: Only the changes of this row, its `entity_id`; requires `entity`
+/// - `limit`: Number of records (default 20, max 100)
+///
+/// # Response
+///
+/// - 200 OK with `[{id, actor_id, action, entity, entity_id, changes, request_id,
+///   created_at}...]`, the latest first
+/// - 400 Bad Request if a query parameter is invalid
+/// - 401 Unauthorized without a valid access token
+pub async fn handle_list_audit(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let query = query::parse::<AuditQuery, _>(&req)?;
+    if query.id.is_some() && query.entity.is_none() {
+        return Err(AppError::Validation("id requires an entity".to_string()));
+    }
+    let id = query
+        .id
+        .as_deref()
+        .map(str::parse::<ResourceId>)
+        .transpose()?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let records = PgAuditRepo(db).list(query.entity, id, limit).await?;
+    Ok(json_response(StatusCode::OK, records))
+}
+
+/// Lists a page of the history of an entity, the oldest change first unless
+/// `order=desc`.
+async fn history(req: Request<Incoming>, entity: Entity, id: i32) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let page = query::parse::<ListQuery, _>(&req)?.pagination(HISTORY_SORTABLE)?;
+
+    let (total, records) = join_queries!(
+        PgAuditRepo(db).count_history(entity, id),
+        PgAuditRepo(db).history(entity, id, &page)
+    )?;
+
+    Ok(json_response(
+        StatusCode::OK,
+        page.page(req.uri().path(), &Filter::default(), records, total),
+    ))
+}
+
+/// Handles GET requests for the change history of a user.
+///
+/// # Route
+///
+/// `GET /users/:id/history?limit=&offset=&order=`
+///
+/// - `limit`: Page size (default 20, max 100)
+/// - `offset`: Number of changes to skip (default 0)
+/// - `order`: `asc` (default, the oldest change first) or `desc`
+///
+/// # Response
+///
+/// - 200 OK with `{"data": [{id, actor_id, action, entity, entity_id, changes,
+///   request_id, created_at}...], "pagination": {...}}`; `changes` has the old and new
+///   value of every changed field
+/// - 400 Bad Request if the ID or a query parameter is invalid
+/// - 404 Not Found if the user does not exist (the history of a deleted user stays in
+///   `GET /admin/audit`)
+pub async fn handle_user_history(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = users::parse_user_id(db, &params).await?;
+    history(req, Entity::User, id).await
+}
+
+/// Handles GET requests for the change history of a product, as
+/// [`handle_user_history`].
+///
+/// # Route
+///
+/// `GET /products/:id/history?limit=&offset=&order=`
+pub async fn handle_product_history(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = products::parse_product_id(db, &params).await?;
+    history(req, Entity::Product, id).await
+}
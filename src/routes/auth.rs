@@ -0,0 +1,219 @@
+//! Authentication routes.
+//!
+//! The `email` and `password_hash` columns of the `users` table are created by the
+//! `V3__add_user_credentials` migration.
+
+use bb8_postgres::tokio_postgres::error::SqlState;
+use hyper::header::SET_COOKIE;
+use hyper::{Request, StatusCode, body::Incoming};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::auth::{hash_password, issue_token, verify_password};
+use crate::carts;
+use crate::context::RequestContext;
+use crate::db::Db;
+use crate::error::{AppError, ErrorCode};
+use crate::events::{self, Action, Collection};
+use crate::jobs::{self, Job};
+use crate::ldap;
+use crate::passkeys;
+use crate::repository::users::{NewAccount, PgUserRepo, UserRepository};
+use crate::router::{HandlerResult, Params, json_response, parse_json_body};
+use crate::validation::{
+    Validate, ValidationErrors, check_age, check_email, check_name, check_password,
+};
+
+// ==================== AUTH ROUTES ====================
+#[derive(Deserialize)]
+pub(crate) struct RegisterRequest {
+    pub name: String,
+    pub age: i32,
+    pub email: String,
+    pub password: String,
+}
+
+impl Validate for RegisterRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        check_name(&mut errors, "name", &self.name);
+        check_age(&mut errors, "age", self.age);
+        check_email(&mut errors, "email", &self.email);
+        check_password(&mut errors, "password", &self.password);
+        errors.into_result()
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    email: String,
+    password: String,
+}
+
+/// Handles POST requests to create an account.
+///
+/// # Route
+///
+/// `POST /auth/register`
+///
+/// # Request Body
+/// JSON object with `name`, `age`, `email` and `password`
+///
+/// # Response
+///
+/// - 201 Created with the ID of the new user, who is sent a welcome email in the
+///   background (see `jobs`)
+/// - 400 Bad Request if the JSON is invalid
+/// - 422 Unprocessable Entity if a field is invalid (e.g. the password is too short)
+/// - 409 Conflict if the email is already registered
+pub async fn handle_register(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let data = parse_json_body::<RegisterRequest>(req).await?;
+    let (id, public_id, account) = create_account(db, data).await?;
+
+    events::publish(Collection::Users, Action::Created, id, public_id);
+    jobs::enqueue(Job::WelcomeEmail {
+        user_id: id,
+        name: account.name,
+        email: account.email,
+    })
+    .await;
+    Ok(json_response(StatusCode::CREATED, json!({"id": public_id})))
+}
+
+/// Validates and inserts a new account, also used by the `create-admin` command.
+///
+/// # Returns
+///
+/// * `Result<(i32, Uuid, NewAccount), AppError>` - The key, the public ID and the
+///   account, an
+///   `AppError::Unprocessable` listing the invalid fields, or an `AppError::Conflict`
+///   if the email is already registered
+pub(crate) async fn create_account(
+    db: Db,
+    data: RegisterRequest,
+) -> Result<(i32, Uuid, NewAccount), AppError> {
+    data.validate()?;
+
+    let email = data.email.trim().to_lowercase();
+    let password_hash = Some(hash_password(data.password).await?);
+
+    let account = NewAccount {
+        name: data.name,
+        age: data.age,
+        email,
+        password_hash,
+    };
+
+    match PgUserRepo(db).create_account(&account).await {
+        Ok((id, public_id)) => Ok((id, public_id, account)),
+        Err(AppError::Db(e)) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+            Err(AppError::Conflict(
+                ErrorCode::EmailTaken,
+                "Email is already registered".to_string(),
+            ))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Handles POST requests to obtain an access token.
+///
+/// # Route
+///
+/// `POST /auth/login`
+///
+/// # Request Body
+/// JSON object with `email` and `password`
+///
+/// # Response
+///
+/// - 200 OK with `{"access_token", "token_type": "Bearer", "expires_in"}`, or with
+///   `{"second_factor": "passkey", challenge_id, publicKey}` if the user requires a
+///   passkey after the password, to send to `POST /auth/passkeys/login`; the
+///   anonymous cart of the `cart` cookie is merged into the cart of the user with the
+///   token, and the cookie cleared (see `carts`)
+/// - 400 Bad Request if the JSON is invalid
+/// - 401 Unauthorized if the credentials are wrong
+/// - 502 Bad Gateway if the directory checking the passwords can't be reached (see
+///   `ldap`)
+pub async fn handle_login(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let headers = req.headers().clone();
+    let data = parse_json_body::<LoginRequest>(req).await?;
+    let id = check_credentials(db, &data.email, data.password).await?;
+    if let Some(challenge) = passkeys::start_second_factor(db, id).await? {
+        return Ok(json_response(StatusCode::OK, challenge));
+    }
+    let mut res = json_response(StatusCode::OK, issue_token(id)?);
+    if let Some(cookie) = carts::merge_on_login(db, &headers, id).await {
+        res.headers_mut().insert(SET_COOKIE, cookie);
+    }
+    Ok(res)
+}
+
+/// Checks the email and password of an account and records the login, also used by
+/// the login form of the OpenID Connect provider.
+///
+/// # Returns
+///
+/// * `Result<i32, AppError>` - The key of the user, or `AppError::Unauthorized` if the
+///   credentials are wrong
+pub(crate) async fn check_credentials(
+    db: Db,
+    email: &str,
+    password: String,
+) -> Result<i32, AppError> {
+    let email = email.trim().to_lowercase();
+    // The directory decides for its users, the others have a local password
+    let id = match ldap::authenticate(db, &email, &password).await? {
+        Some(id) => id,
+        None => check_local_password(db, &email, password).await?,
+    };
+    // The login is valid without it, the account only looks inactive for longer
+    if let Err(e) = PgUserRepo(db).record_login(id).await {
+        warn!("Login of user {} not recorded: {}", id, e);
+    }
+    Ok(id)
+}
+
+/// Checks the password of an account against its hash.
+async fn check_local_password(db: Db, email: &str, password: String) -> Result<i32, AppError> {
+    let credentials = PgUserRepo(db).find_credentials(email).await?;
+
+    // Same error for unknown email and wrong password, so accounts can't be enumerated
+    let invalid = || AppError::Unauthorized("Invalid email or password".to_string());
+
+    let credentials = credentials.ok_or_else(invalid)?;
+    let hash = credentials.password_hash.ok_or_else(invalid)?;
+
+    if !verify_password(password, hash).await? {
+        return Err(invalid());
+    }
+    Ok(credentials.id)
+}
+
+/// Handles GET requests to retrieve the profile of the authenticated user.
+///
+/// # Route
+///
+/// `GET /auth/me` (requires authentication)
+///
+/// # Response
+///
+/// - 200 OK with the user profile
+/// - 401 Unauthorized if the token is missing or invalid
+/// - 404 Not Found if the user was deleted after the token was issued
+pub async fn handle_me(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+
+    let profile = PgUserRepo(db)
+        .find_profile(user.id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()))?;
+
+    Ok(json_response(StatusCode::OK, profile))
+}
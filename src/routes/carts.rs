@@ -0,0 +1,235 @@
+//! Cart of the caller, anonymous or not, its checkout, and the wishlist of the caller
+//! (see `carts`).
+
+use hyper::header::SET_COOKIE;
+use hyper::{Request, StatusCode, body::Incoming};
+use serde::Deserialize;
+
+use crate::carts;
+use crate::computed::{Resource, all_with_fields};
+use crate::context::RequestContext;
+use crate::db::Db;
+use crate::error::{AppError, ErrorCode};
+use crate::events::{self, Action, Collection};
+use crate::repository::carts::{Cart, CartRepository, MAX_QUANTITY, PgCartRepo};
+use crate::repository::ids::{ResourceId, resolve};
+use crate::router::{HandlerResult, Params, empty_response, json_response, parse_validated_body};
+use crate::validation::{Validate, ValidationErrors};
+
+#[derive(Deserialize, Debug)]
+struct CartQuantity {
+    quantity: i32,
+}
+
+impl Validate for CartQuantity {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check(
+            "quantity",
+            (1..=MAX_QUANTITY).contains(&self.quantity),
+            format!("must be between 1 and {}", MAX_QUANTITY),
+        );
+        errors.into_result()
+    }
+}
+
+/// The key of the `:product_id` of a route.
+async fn product_id(db: Db, params: &Params) -> Result<i32, AppError> {
+    let id = params
+        .parse::<ResourceId>("product_id")
+        .ok_or_else(ResourceId::invalid)?;
+    resolve(db, Collection::Products, id).await?.ok_or_else(|| {
+        AppError::NotFound(ErrorCode::ProductNotFound, "Product not found".to_string())
+    })
+}
+
+fn item_not_found() -> AppError {
+    AppError::NotFound(
+        ErrorCode::ItemNotFound,
+        "The product is not in the list".to_string(),
+    )
+}
+
+/// Handles GET requests for the cart of the caller.
+///
+/// # Route
+///
+/// `GET /cart` (with an access token, or the `cart` cookie)
+///
+/// # Response
+///
+/// - 200 OK with `{"items": [{product_id, name, unit_price, quantity, subtotal,
+///   in_stock}...], "total"}`, at the current prices, empty without a cart
+/// - 401 Unauthorized if the access token is invalid
+pub async fn handle_get_cart(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let cart = match carts::owner(&req)? {
+        Some(owner) => PgCartRepo(db).find(&owner).await?,
+        None => Cart::default(),
+    };
+    Ok(json_response(StatusCode::OK, cart))
+}
+
+/// Handles PUT requests setting the quantity of a product in the cart of the caller,
+/// adding it if needed.
+///
+/// Without an access token nor a `cart` cookie, the item starts an anonymous cart,
+/// whose cookie is set by the response.
+///
+/// # Route
+///
+/// `PUT /cart/items/:product_id` (with an access token, or the `cart` cookie)
+///
+/// # Request Body
+/// JSON object with `quantity` (1 to 100)
+///
+/// # Response
+///
+/// - 200 OK with the cart, and `Set-Cookie` for an anonymous cart
+/// - 400 Bad Request if the ID or the JSON is invalid
+/// - 401 Unauthorized if the access token is invalid
+/// - 404 Not Found if the product does not exist
+/// - 422 Unprocessable Entity if the quantity is invalid
+pub async fn handle_set_item(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let product_id = product_id(db, &params).await?;
+    let (owner, cookie) = match carts::owner(&req)? {
+        Some(owner) => (owner, carts::renew_cookie(&req)),
+        None => {
+            let (owner, cookie) = carts::new_anonymous(&req)?;
+            (owner, Some(cookie))
+        }
+    };
+    let data = parse_validated_body::<CartQuantity>(req).await?;
+
+    let cart = PgCartRepo(db)
+        .set_quantity(&owner, product_id, data.quantity)
+        .await?;
+    let mut res = json_response(StatusCode::OK, cart);
+    if let Some(cookie) = cookie {
+        res.headers_mut().insert(SET_COOKIE, cookie);
+    }
+    Ok(res)
+}
+
+/// Handles DELETE requests removing a product from the cart of the caller.
+///
+/// # Route
+///
+/// `DELETE /cart/items/:product_id` (with an access token, or the `cart` cookie)
+///
+/// # Response
+///
+/// - 204 No Content
+/// - 400 Bad Request if the ID is invalid
+/// - 401 Unauthorized if the access token is invalid
+/// - 404 Not Found if the product does not exist or is not in the cart
+pub async fn handle_remove_item(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let product_id = product_id(db, &params).await?;
+    let owner = carts::owner(&req)?.ok_or_else(item_not_found)?;
+    if !PgCartRepo(db).remove(&owner, product_id).await? {
+        return Err(item_not_found());
+    }
+    Ok(empty_response(StatusCode::NO_CONTENT))
+}
+
+/// Handles POST requests ordering the cart of the caller.
+///
+/// An order of each item is placed, taking the quantities from the stocks, and the
+/// cart is emptied, in a single transaction: either all happen or none does.
+///
+/// # Route
+///
+/// `POST /cart/checkout` (requires authentication)
+///
+/// # Response
+///
+/// - 201 Created with the orders, by product
+/// - 401 Unauthorized without a valid access token
+/// - 409 Conflict if the cart is empty, or a product doesn't have enough stock
+pub async fn handle_checkout(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let orders = PgCartRepo(db).checkout(user.id).await?;
+    // The stocks of the products went down
+    for order in &orders {
+        if let Some(key) = resolve(
+            db,
+            Collection::Products,
+            ResourceId::Public(order.product_id),
+        )
+        .await?
+        {
+            events::publish(Collection::Products, Action::Updated, key, order.product_id);
+        }
+    }
+    Ok(json_response(
+        StatusCode::CREATED,
+        all_with_fields(Resource::Orders, orders),
+    ))
+}
+
+/// Handles GET requests for the wishlist of the caller.
+///
+/// # Route
+///
+/// `GET /wishlist` (requires authentication)
+///
+/// # Response
+///
+/// - 200 OK with `[{product_id, name, price, stock, added_at}...]`, the latest product
+///   added first
+/// - 401 Unauthorized without a valid access token
+pub async fn handle_get_wishlist(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let wishlist = PgCartRepo(db).wishlist(user.id).await?;
+    Ok(json_response(StatusCode::OK, wishlist))
+}
+
+/// Handles PUT requests adding a product to the wishlist of the caller.
+///
+/// # Route
+///
+/// `PUT /wishlist/:product_id` (requires authentication)
+///
+/// # Response
+///
+/// - 201 Created if the product was added, 204 No Content if it was there already
+/// - 400 Bad Request if the ID is invalid
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if the product does not exist
+pub async fn handle_wish(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let product_id = product_id(db, &params).await?;
+    let status = if PgCartRepo(db).wish(user.id, product_id).await? {
+        StatusCode::CREATED
+    } else {
+        StatusCode::NO_CONTENT
+    };
+    Ok(empty_response(status))
+}
+
+/// Handles DELETE requests removing a product from the wishlist of the caller.
+///
+/// # Route
+///
+/// `DELETE /wishlist/:product_id` (requires authentication)
+///
+/// # Response
+///
+/// - 204 No Content
+/// - 400 Bad Request if the ID is invalid
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if the product does not exist or is not in the wishlist
+pub async fn handle_unwish(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let product_id = product_id(db, &params).await?;
+    if !PgCartRepo(db).unwish(user.id, product_id).await? {
+        return Err(item_not_found());
+    }
+    Ok(empty_response(StatusCode::NO_CONTENT))
+}
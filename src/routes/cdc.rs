@@ -0,0 +1,204 @@
+//! Change feeds of the tables for the ETL jobs (see `repository::cdc`).
+
+use hyper::{Request, StatusCode, body::Incoming};
+use serde::{Deserialize, Serialize};
+
+use crate::context::RequestContext;
+use crate::error::{AppError, ErrorCode};
+use crate::repository::audit::Entity;
+use crate::repository::cdc::{CdcRepository, Change, PgCdcRepo, Position};
+use crate::router::query;
+use crate::router::{HandlerResult, Params, json_response, parse_validated_body};
+use crate::validation::{Validate, ValidationErrors};
+
+/// Changes of a page of the feed unless `?limit=`
+const DEFAULT_FEED_LIMIT: i64 = 100;
+
+/// Most changes of a page of the feed
+const MAX_FEED_LIMIT: i64 = 1000;
+
+/// Longest name of a consumer
+const MAX_CONSUMER_LEN: usize = 100;
+
+/// `?from=&consumer=&limit=` of `GET /cdc/:table`.
+#[derive(Deserialize, Default, Debug)]
+struct FeedQuery {
+    from: Option<String>,
+    consumer: Option<String>,
+    limit: Option<i64>,
+}
+
+/// A page of a feed.
+#[derive(Serialize, Debug)]
+struct FeedPage {
+    changes: Vec<Change>,
+    /// Position of the last change, or the one the page started from without changes
+    next: Position,
+    /// Whether the page is full, and the next one may follow right away
+    has_more: bool,
+}
+
+/// Body of `PUT /cdc/:table/checkpoints/:consumer`.
+#[derive(Deserialize, Debug)]
+struct CheckpointRequest {
+    position: String,
+}
+
+impl Validate for CheckpointRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check(
+            "position",
+            self.position.parse::<Position>().is_ok(),
+            "must be a position of the feed, as in `next`",
+        );
+        errors.into_result()
+    }
+}
+
+/// The `:table` of a feed, and the kind of its entities in the audit log.
+fn table(params: &Params) -> Result<(&str, Entity), AppError> {
+    let table = params.get("table").unwrap_or_default();
+    let entity = match table {
+        "users" => Entity::User,
+        "products" => Entity::Product,
+        "orders" => Entity::Order,
+        _ => {
+            return Err(AppError::NotFound(
+                ErrorCode::FeedNotFound,
+                format!("No change feed for '{}'", table),
+            ));
+        }
+    };
+    Ok((table, entity))
+}
+
+/// The `:consumer` of a checkpoint.
+fn consumer(params: &Params) -> Result<&str, AppError> {
+    let consumer = params.get("consumer").unwrap_or_default();
+    let valid = !consumer.is_empty()
+        && consumer.len() <= MAX_CONSUMER_LEN
+        && consumer
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid {
+        return Err(AppError::Validation(format!(
+            "Consumer names are at most {} letters, digits, _, - and .",
+            MAX_CONSUMER_LEN
+        )));
+    }
+    Ok(consumer)
+}
+
+/// Handles GET requests for the changes of a table after a position.
+///
+/// # Route
+///
+/// `GET /cdc/:table?from=&consumer=&limit=`
+///
+/// - `table`: `users`, `products` or `orders`
+/// - `from`: Position after which the changes start (default `0-0`, the oldest change)
+/// - `consumer`: Start after the checkpoint of this consumer instead, or from the
+///   oldest change if it has none
+/// - `limit`: Number of changes (default 100, max 1000)
+///
+/// # Response
+///
+/// - 200 OK with `{"changes": [{position, id, actor_id, action, entity, entity_id,
+///   changes, request_id, created_at}...], "next": "<position>", "has_more": bool}`,
+///   in the order of the feed; `next` is the `from` of the next page
+/// - 400 Bad Request if a query parameter is invalid, or both `from` and `consumer` are
+///   given
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if the table has no feed
+pub async fn handle_feed(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let (table, entity) = table(&params)?;
+    let query = query::parse::<FeedQuery, _>(&req)?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_FEED_LIMIT)
+        .clamp(1, MAX_FEED_LIMIT);
+
+    let from = match (query.from, query.consumer) {
+        (Some(_), Some(_)) => {
+            return Err(AppError::Validation(
+                "from and consumer can't be used together".to_string(),
+            ));
+        }
+        (Some(from), None) => from.parse::<Position>().map_err(AppError::Validation)?,
+        (None, Some(consumer)) => PgCdcRepo(db)
+            .checkpoint(&consumer, table)
+            .await?
+            .map(|checkpoint| checkpoint.position)
+            .unwrap_or_default(),
+        (None, None) => Position::default(),
+    };
+
+    let changes = PgCdcRepo(db).changes(entity, from, limit).await?;
+    let page = FeedPage {
+        next: changes.last().map_or(from, |change| change.position),
+        has_more: changes.len() as i64 == limit,
+        changes,
+    };
+    Ok(json_response(StatusCode::OK, page))
+}
+
+/// Handles GET requests for the checkpoint of a consumer of a feed.
+///
+/// # Route
+///
+/// `GET /cdc/:table/checkpoints/:consumer`
+///
+/// # Response
+///
+/// - 200 OK with `{consumer, table, position, updated_at}`
+/// - 400 Bad Request if the consumer name is invalid
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if the table has no feed, or the consumer no checkpoint
+pub async fn handle_get_checkpoint(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let (table, _) = table(&params)?;
+    let consumer = consumer(&params)?;
+
+    let checkpoint = PgCdcRepo(db)
+        .checkpoint(consumer, table)
+        .await?
+        .ok_or_else(|| {
+            AppError::NotFound(
+                ErrorCode::CheckpointNotFound,
+                "Checkpoint not found".to_string(),
+            )
+        })?;
+    Ok(json_response(StatusCode::OK, checkpoint))
+}
+
+/// Handles PUT requests saving the position of a consumer in a feed, where
+/// `?consumer=` resumes.
+///
+/// # Route
+///
+/// `PUT /cdc/:table/checkpoints/:consumer`
+///
+/// # Request Body
+/// JSON object with `position`, usually the `next` of the last page processed
+///
+/// # Response
+///
+/// - 200 OK with `{consumer, table, position, updated_at}`
+/// - 400 Bad Request if the JSON or the consumer name is invalid
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if the table has no feed
+/// - 422 Unprocessable Entity if the position is invalid
+pub async fn handle_save_checkpoint(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let (table, _) = table(&params)?;
+    let consumer = consumer(&params)?;
+    let data = parse_validated_body::<CheckpointRequest>(req).await?;
+    let position = data.position.parse().map_err(AppError::Validation)?;
+
+    let checkpoint = PgCdcRepo(db)
+        .save_checkpoint(consumer, table, position)
+        .await?;
+    Ok(json_response(StatusCode::OK, checkpoint))
+}
@@ -0,0 +1,59 @@
+//! Admin console running the approved queries (see the `console` module).
+
+use hyper::{Request, StatusCode, body::Incoming};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::console;
+use crate::context::RequestContext;
+use crate::router::{HandlerResult, Params, json_response, parse_json_body};
+
+/// Body of `POST /admin/queries/:name/run`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RunRequest {
+    #[serde(default)]
+    params: Map<String, Value>,
+}
+
+/// Handles GET requests to list the queries of the console.
+///
+/// # Route
+///
+/// `GET /admin/queries`
+///
+/// # Response
+///
+/// - 200 OK with `[{name, description, sql, params: [{name, type}...], max_rows}...]`,
+///   in the order of `CONSOLE_QUERIES_PATH`
+/// - 401 Unauthorized without a valid access token
+pub async fn handle_list_queries(_req: Request<Incoming>, _params: Params) -> HandlerResult {
+    Ok(json_response(StatusCode::OK, console::queries()))
+}
+
+/// Handles POST requests running a query of the console.
+///
+/// # Route
+///
+/// `POST /admin/queries/:name/run`
+///
+/// # Request Body
+/// JSON object with `params`, the value of each parameter of the query by name
+///
+/// # Response
+///
+/// - 200 OK with `{"data": [{<column>: <value>...}...], "pagination": {rows, max_rows,
+///   truncated}}`, or the rows as CSV with `Accept: text/csv`
+/// - 400 Bad Request if the JSON is invalid
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if the console has no query with this name
+/// - 422 Unprocessable Entity if a parameter is missing, unknown or of the wrong type
+pub async fn handle_run_query(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let name = params.get("name").unwrap_or_default().to_string();
+    let caller = RequestContext::of(&req).caller()?;
+    let data = parse_json_body::<RunRequest>(req).await?;
+
+    let result = console::run(db, &name, &data.params, caller).await?;
+    Ok(json_response(StatusCode::OK, result))
+}
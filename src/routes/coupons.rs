@@ -0,0 +1,186 @@
+//! Coupons redeemed by the orders (see `repository::coupons`), and their redemptions.
+
+use hyper::{Request, StatusCode, body::Incoming};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::context::RequestContext;
+use crate::error::{AppError, ErrorCode};
+use crate::repository::coupons::{CouponRepository, NewCoupon, PgCouponRepo};
+use crate::router::query::{self, DEFAULT_LIMIT, MAX_LIMIT};
+use crate::router::{HandlerResult, Params, empty_response, json_response, parse_validated_body};
+use crate::validation::{Validate, ValidationErrors};
+
+/// Length of the codes, in characters
+const CODE_LEN: std::ops::RangeInclusive<usize> = 3..=32;
+
+/// Largest fixed discount, in cents
+const MAX_AMOUNT_OFF: i64 = 1_000_000;
+
+impl Validate for NewCoupon {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        let code = self.code.trim();
+        errors.check(
+            "code",
+            CODE_LEN.contains(&code.len())
+                && code
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            format!(
+                "must have {} to {} letters, digits, '-' or '_'",
+                CODE_LEN.start(),
+                CODE_LEN.end()
+            ),
+        );
+        errors.check(
+            "percent_off",
+            self.percent_off.is_some() != self.amount_off.is_some(),
+            "either percent_off or amount_off must be given",
+        );
+        if let Some(percent_off) = self.percent_off {
+            errors.check(
+                "percent_off",
+                (1..=100).contains(&percent_off),
+                "must be between 1 and 100",
+            );
+        }
+        if let Some(amount_off) = self.amount_off {
+            errors.check(
+                "amount_off",
+                (1..=MAX_AMOUNT_OFF).contains(&amount_off),
+                format!("must be between 1 and {} cents", MAX_AMOUNT_OFF),
+            );
+        }
+        errors.check(
+            "min_order_amount",
+            self.min_order_amount >= 0,
+            "must be >= 0",
+        );
+        errors.check(
+            "max_redemptions",
+            self.max_redemptions.is_none_or(|max| max > 0),
+            "must be > 0",
+        );
+        errors.check(
+            "max_per_user",
+            self.max_per_user.is_none_or(|max| max > 0),
+            "must be > 0",
+        );
+        errors.into_result()
+    }
+}
+
+/// `?limit=` of `GET /coupons/:id/redemptions`.
+#[derive(Deserialize, Default, Debug)]
+struct RedemptionsQuery {
+    limit: Option<i64>,
+}
+
+/// The `:id` of a coupon.
+fn coupon_id(params: &Params) -> Result<Uuid, AppError> {
+    params
+        .parse::<Uuid>("id")
+        .ok_or_else(|| AppError::Validation("ID must be a UUID".to_string()))
+}
+
+fn not_found() -> AppError {
+    AppError::NotFound(ErrorCode::CouponNotFound, "Coupon not found".to_string())
+}
+
+/// Handles POST requests creating a coupon.
+///
+/// # Route
+///
+/// `POST /coupons` (requires the editor role)
+///
+/// # Request Body
+/// JSON object with `code` (saved in upper case), either `percent_off` (1 to 100) or
+/// `amount_off` (in cents), and optionally `min_order_amount` (in cents), `expires_at`
+/// (RFC 3339), `max_redemptions` and `max_per_user`
+///
+/// # Response
+///
+/// - 201 Created with the coupon
+/// - 400 Bad Request if the JSON is malformed
+/// - 401 Unauthorized without a valid access token
+/// - 403 Forbidden without the editor role
+/// - 409 Conflict if another coupon has the code
+/// - 422 Unprocessable Entity if a field is invalid, or `expires_at` is past
+pub async fn handle_create_coupon(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let coupon = parse_validated_body::<NewCoupon>(req).await?;
+    let coupon = PgCouponRepo(db).create(&coupon).await?;
+    Ok(json_response(StatusCode::CREATED, coupon))
+}
+
+/// Handles GET requests for the coupons.
+///
+/// # Route
+///
+/// `GET /coupons` (requires the editor role)
+///
+/// # Response
+///
+/// - 200 OK with `[{id, code, percent_off, amount_off, min_order_amount, expires_at,
+///   max_redemptions, max_per_user, redemptions, active, created_at}...]`, the latest
+///   first
+/// - 401 Unauthorized without a valid access token
+/// - 403 Forbidden without the editor role
+pub async fn handle_list_coupons(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let coupons = PgCouponRepo(db).list().await?;
+    Ok(json_response(StatusCode::OK, coupons))
+}
+
+/// Handles DELETE requests for a coupon: it can't be redeemed anymore, its redemptions
+/// are kept.
+///
+/// # Route
+///
+/// `DELETE /coupons/:id` (requires the editor role)
+///
+/// # Response
+///
+/// - 204 No Content
+/// - 400 Bad Request if the ID is not a UUID
+/// - 401 Unauthorized without a valid access token
+/// - 403 Forbidden without the editor role
+/// - 404 Not Found if no coupon has this ID
+pub async fn handle_delete_coupon(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = coupon_id(&params)?;
+    if !PgCouponRepo(db).deactivate(id).await? {
+        return Err(not_found());
+    }
+    Ok(empty_response(StatusCode::NO_CONTENT))
+}
+
+/// Handles GET requests for the latest redemptions of a coupon.
+///
+/// # Route
+///
+/// `GET /coupons/:id/redemptions?limit=` (requires the editor role)
+///
+/// - `limit`: Number of redemptions (default 20, max 100)
+///
+/// # Response
+///
+/// - 200 OK with `[{user_id, order_id, discount, created_at}...]`, the latest first;
+///   `user_id` is null once the user is deleted
+/// - 400 Bad Request if the ID is not a UUID or a query parameter is invalid
+/// - 401 Unauthorized without a valid access token
+/// - 403 Forbidden without the editor role
+/// - 404 Not Found if no coupon has this ID
+pub async fn handle_list_redemptions(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = coupon_id(&params)?;
+    let query = query::parse::<RedemptionsQuery, _>(&req)?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let redemptions = PgCouponRepo(db)
+        .redemptions(id, limit)
+        .await?
+        .ok_or_else(not_found)?;
+    Ok(json_response(StatusCode::OK, redemptions))
+}
@@ -0,0 +1,27 @@
+//! Overview of the users, products and orders (see the `dashboard` module).
+
+use hyper::{Request, StatusCode, body::Incoming};
+
+use crate::context::RequestContext;
+use crate::dashboard;
+use crate::router::{HandlerResult, Params, json_response};
+
+/// Handles GET requests for the dashboard.
+///
+/// Its parts are fetched concurrently, each within `DASHBOARD_PART_TIMEOUT`; a part
+/// that fails is `null` and its reason is in `errors`.
+///
+/// # Route
+///
+/// `GET /dashboard` (requires authentication)
+///
+/// # Response
+///
+/// - 200 OK with `{users, products, recent_orders, upstreams, errors}`, even when
+///   some parts are missing
+/// - 401 Unauthorized without a valid access token
+pub async fn handle_dashboard(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let context = RequestContext::of(&req);
+    let dashboard = dashboard::compose(context.db, context.request_id.as_ref()).await;
+    Ok(json_response(StatusCode::OK, dashboard))
+}
@@ -0,0 +1,206 @@
+//! Runtime diagnostics, to find out why a deployment is stuck without attaching a
+//! debugger: tasks of the Tokio runtime, blocking pool, database pool waiters, clients.
+//!
+//! The blocking pool and task spawn counters are unstable Tokio metrics, only reported
+//! when built with `RUSTFLAGS="--cfg tokio_unstable"` (as the `console` feature requires).
+//!
+//! Heap profiles, for memory growth, require the `jemalloc` feature (see `memory`), and
+//! CPU profiles the `pprof` feature (see `cpu_profile`).
+
+use std::time::Duration;
+
+use futures_util::{Stream, stream};
+use hyper::{
+    Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, HeaderValue},
+};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+use tracing::warn;
+
+use crate::cpu_profile::{self, ProfileFormat};
+use crate::db::{PoolStatus, pool_status};
+use crate::error::AppError;
+use crate::events;
+use crate::memory;
+use crate::metrics;
+use crate::router::query;
+use crate::router::{Body, BoxError, HandlerResult, Params, json_response};
+
+/// Duration of a CPU profile when `seconds` isn't given
+const DEFAULT_PROFILE_SECONDS: u64 = 30;
+/// Longest CPU profile
+const MAX_PROFILE_SECONDS: u64 = 300;
+
+/// Snapshot of the Tokio runtime serving the request.
+#[derive(Debug, Serialize)]
+struct RuntimeStats {
+    /// Worker threads
+    workers: usize,
+    /// Tasks spawned and not finished yet (connections, streams, background jobs)
+    alive_tasks: usize,
+    /// Tasks waiting in the global queue for a worker; stays high when workers are
+    /// blocked by synchronous code
+    global_queue_depth: usize,
+    /// Threads of the blocking pool (`spawn_blocking`, file I/O)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blocking_threads: Option<usize>,
+    /// Blocking pool threads waiting for work; the others are busy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    idle_blocking_threads: Option<usize>,
+    /// Blocking tasks waiting for a thread
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blocking_queue_depth: Option<usize>,
+    /// Tasks spawned since startup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spawned_tasks: Option<u64>,
+}
+
+impl RuntimeStats {
+    fn current() -> Self {
+        let metrics = Handle::current().metrics();
+        #[allow(unused_mut)] // Only set with tokio_unstable
+        let mut stats = RuntimeStats {
+            workers: metrics.num_workers(),
+            alive_tasks: metrics.num_alive_tasks(),
+            global_queue_depth: metrics.global_queue_depth(),
+            blocking_threads: None,
+            idle_blocking_threads: None,
+            blocking_queue_depth: None,
+            spawned_tasks: None,
+        };
+        #[cfg(tokio_unstable)]
+        {
+            stats.blocking_threads = Some(metrics.num_blocking_threads());
+            stats.idle_blocking_threads = Some(metrics.num_idle_blocking_threads());
+            stats.blocking_queue_depth = Some(metrics.blocking_queue_depth());
+            stats.spawned_tasks = Some(metrics.spawned_tasks_count());
+        }
+        stats
+    }
+}
+
+/// Body of `GET /admin/runtime`.
+#[derive(Debug, Serialize)]
+struct Diagnostics {
+    runtime: RuntimeStats,
+    /// Open client connections (HTTP and HTTPS)
+    connections: i64,
+    /// Clients receiving the change notifications (WebSockets, Server-Sent Events)
+    event_subscribers: usize,
+    /// `None` if the pool is closed
+    pool: Option<PoolStatus>,
+}
+
+// ==================== DIAGNOSTICS ROUTES ====================
+
+/// Handles GET requests to dump the state of the runtime.
+///
+/// # Route
+///
+/// `GET /admin/runtime` (requires authentication)
+///
+/// # Response
+///
+/// 200 OK with `{"runtime": {...}, "connections", "event_subscribers", "pool": {...}}`,
+/// the pool including the requests waiting for a connection (`waiting`)
+pub async fn handle_runtime(_req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let diagnostics = Diagnostics {
+        runtime: RuntimeStats::current(),
+        connections: metrics::active_connections(),
+        event_subscribers: events::subscriber_count(),
+        pool: pool_status(),
+    };
+    Ok(json_response(StatusCode::OK, diagnostics))
+}
+
+/// Handles GET requests to download a heap profile.
+///
+/// # Route
+///
+/// `GET /admin/heap-profile` (requires authentication)
+///
+/// # Response
+///
+/// - 200 OK with the profile as an attachment, for `jeprof --svg <binary> <profile>`
+/// - 409 Conflict if heap profiling is disabled (`PROFILING_DISABLED`)
+pub async fn handle_heap_profile(_req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let profile = memory::heap_profile().await?;
+
+    let body = stream::once(async move { Ok(Bytes::from(profile)) });
+    Ok(attachment(
+        body,
+        "application/octet-stream",
+        "attachment; filename=\"heap.prof\"",
+    ))
+}
+
+/// Query of `GET /debug/pprof/profile`.
+#[derive(Deserialize, Debug)]
+struct ProfileQuery {
+    seconds: Option<u64>,
+    #[serde(default)]
+    format: ProfileFormat,
+}
+
+/// Handles GET requests to take a CPU profile.
+///
+/// # Route
+///
+/// `GET /debug/pprof/profile?seconds=30&format=pprof|flamegraph` (requires
+/// authentication)
+///
+/// # Response
+///
+/// - 200 OK with the profile as an attachment once `seconds` (default 30, at most
+///   300) have passed, in the pprof format or as a flame graph SVG
+/// - 400 Bad Request if `seconds` or `format` is invalid
+/// - 409 Conflict if CPU profiling is disabled (`PROFILING_DISABLED`) or another
+///   profile is being taken (`PROFILE_IN_PROGRESS`)
+pub async fn handle_cpu_profile(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let query: ProfileQuery = query::parse(&req)?;
+    let seconds = query.seconds.unwrap_or(DEFAULT_PROFILE_SECONDS);
+    if !(1..=MAX_PROFILE_SECONDS).contains(&seconds) {
+        return Err(AppError::Validation(format!(
+            "seconds must be between 1 and {}",
+            MAX_PROFILE_SECONDS
+        )));
+    }
+
+    let profile = cpu_profile::start(Duration::from_secs(seconds), query.format)?;
+
+    // The headers are sent right away and the body once the profile is taken, so the
+    // sampling isn't cut by REQUEST_TIMEOUT; a failure from then on aborts the response
+    let body = stream::once(async move {
+        profile.await.map(Bytes::from).map_err(|e| {
+            warn!("CPU profile aborted: {}", e);
+            BoxError::from(e)
+        })
+    });
+    Ok(match query.format {
+        ProfileFormat::Pprof => attachment(
+            body,
+            "application/octet-stream",
+            "attachment; filename=\"profile.pb\"",
+        ),
+        ProfileFormat::Flamegraph => attachment(
+            body,
+            "image/svg+xml",
+            "attachment; filename=\"flamegraph.svg\"",
+        ),
+    })
+}
+
+/// A downloaded file, streamed so the negotiation layer passes it through as is.
+fn attachment<S>(body: S, content_type: &'static str, disposition: &'static str) -> Response<Body>
+where
+    S: Stream<Item = Result<Bytes, BoxError>> + Send + 'static,
+{
+    let mut res = Response::new(Body::stream(body));
+    let headers = res.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(CONTENT_DISPOSITION, HeaderValue::from_static(disposition));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    res
+}
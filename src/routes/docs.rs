@@ -0,0 +1,133 @@
+//! Description of the API: the OpenAPI document and a page to browse it, and in
+//! development the console sending requests to any route (`DEV_CONSOLE`).
+
+mod openapi;
+
+use std::sync::OnceLock;
+
+use hyper::{
+    Request, Response, StatusCode,
+    body::Incoming,
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+};
+use serde_json::{Value, json};
+use tracing::warn;
+
+use crate::error::{AppError, ErrorCode};
+use crate::router::{HandlerResult, Params, json_response, router};
+
+/// Swagger UI, loaded from a CDN by the browser
+const DOCS_PAGE: &str = include_str!("docs/swagger.html");
+
+/// Console of the routes, whose list replaces `/*ROUTES*/null`
+const CONSOLE_PAGE: &str = include_str!("docs/console.html");
+
+// Built on the first request, the routes never change afterwards
+static DOCUMENT: OnceLock<Value> = OnceLock::new();
+static CONSOLE: OnceLock<String> = OnceLock::new();
+
+// Set once at startup
+static DEV_CONSOLE: OnceLock<bool> = OnceLock::new();
+
+/// Sets whether `GET /console` is served (`DEV_CONSOLE`).
+/// This function should be called once at application startup.
+pub(crate) fn init_dev_console(enabled: bool) {
+    if DEV_CONSOLE.set(enabled).is_err() {
+        warn!("Attempt to reset the dev console setting ignored");
+    }
+}
+
+/// Handles GET requests to retrieve the OpenAPI document.
+///
+/// # Route
+///
+/// `GET /openapi.json`
+///
+/// # Response
+///
+/// 200 OK with the OpenAPI 3.0 description of every route (see the `openapi` module)
+pub async fn handle_openapi(_req: Request<Incoming>, _params: Params) -> HandlerResult {
+    Ok(json_response(StatusCode::OK, document()))
+}
+
+/// The OpenAPI document of the routes, built on first use.
+pub(crate) fn document() -> &'static Value {
+    DOCUMENT.get_or_init(|| openapi::document(router()))
+}
+
+/// The Operation Object of `method` (`get`) on the route `pattern`, `None` if the route
+/// doesn't exist.
+pub(crate) fn operation(method: &str, pattern: &str) -> Option<&'static Value> {
+    document()["paths"]
+        .get(openapi::openapi_path(pattern))?
+        .get(method)
+}
+
+/// Handles GET requests to browse the documentation.
+///
+/// # Route
+///
+/// `GET /docs`
+///
+/// # Response
+///
+/// 200 OK with an HTML page rendering `/openapi.json` with Swagger UI, where requests
+/// can be tried out
+pub async fn handle_docs(_req: Request<Incoming>, _params: Params) -> HandlerResult {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(DOCS_PAGE.into())
+        .unwrap())
+}
+
+/// Handles GET requests for the console sending requests to the routes, in
+/// development.
+///
+/// # Route
+///
+/// `GET /console`
+///
+/// # Response
+///
+/// - 200 OK with an HTML page listing every route of the route table, with a form to
+///   send a request to the selected one: its path parameters, query, headers, access
+///   token and body, and the status, headers and body of the response
+/// - 404 Not Found unless `DEV_CONSOLE` is set, as if there were no such route
+pub async fn handle_console(_req: Request<Incoming>, _params: Params) -> HandlerResult {
+    if !DEV_CONSOLE.get().copied().unwrap_or(false) {
+        return Err(AppError::NotFound(
+            ErrorCode::RouteNotFound,
+            "Not found".to_string(),
+        ));
+    }
+    let page = CONSOLE.get_or_init(|| {
+        // `</script>` in a summary can't end the script the list is in
+        let routes = console_routes().to_string().replace("</", "<\\/");
+        CONSOLE_PAGE.replace("/*ROUTES*/null", &routes)
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(page.clone().into())
+        .unwrap())
+}
+
+/// The routes listed by the console, with the summary of their operation.
+fn console_routes() -> Value {
+    router()
+        .routes()
+        .map(|route| {
+            let operation = operation(&route.method.as_str().to_lowercase(), route.pattern);
+            json!({
+                "method": route.method.as_str(),
+                "path": route.pattern,
+                "summary": operation.and_then(|op| op["summary"].as_str()).unwrap_or(""),
+                "requires_auth": route.requires_auth,
+                "has_body": operation.is_some_and(|op| op.get("requestBody").is_some()),
+            })
+        })
+        .collect()
+}
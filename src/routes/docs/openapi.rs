@@ -0,0 +1,3705 @@
+//! OpenAPI 3.0 description of the API.
+//!
+//! The paths, their methods and whether they require a token come from the route table
+//! (`Router::routes`), so the document always lists the routes actually served. What a
+//! route takes and returns is described by its entry of `OPERATIONS`, and the bodies by
+//! the schemas of `schemas`, written after the types of the `repository` and `routes`
+//! modules. The tests of this module check every route has an entry and every entry a
+//! route.
+//!
+//! The deprecated unprefixed paths (`/users`) are not listed.
+
+use serde_json::{Map, Value, json};
+
+use super::super::{products, users};
+use crate::computed::{self, Resource};
+use crate::repository::filter::{FieldType, FilterField};
+use crate::router::query::{DEFAULT_LIMIT, MAX_LIMIT};
+use crate::router::{RouteInfo, Router};
+use crate::scim;
+use crate::validation::{MAX_NAME_LEN, MIN_PASSWORD_LEN};
+
+/// Name of the security scheme of the routes requiring a token
+const BEARER_AUTH: &str = "bearerAuth";
+/// Name of the security scheme of the SCIM routes, taking `SCIM_TOKEN`
+const SCIM_AUTH: &str = "scimToken";
+/// Name of the security scheme of the routes accepting signed URLs
+const SIGNED_URL: &str = "signedUrl";
+
+/// Documentation of a route.
+struct Operation {
+    method: &'static str,
+    /// Pattern of the route, as registered (`/api/v1/users/:id`)
+    path: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+    /// Markdown, empty when the summary says it all
+    description: &'static str,
+    query: &'static [Param],
+    /// Fields the list can be filtered by (see `repository::filter`)
+    filters: &'static [FilterField],
+    request: Option<Content>,
+    /// Whether the write requires `If-Match` (see `router::conditional`)
+    if_match: bool,
+    responses: &'static [Reply],
+}
+
+/// A query parameter.
+struct Param {
+    name: &'static str,
+    description: &'static str,
+    kind: ParamKind,
+}
+
+enum ParamKind {
+    Integer,
+    Boolean,
+    String,
+    /// One of the listed values
+    Enum(&'static [&'static str]),
+}
+
+/// Body of a request or of a response.
+#[derive(Clone, Copy)]
+enum Content {
+    /// JSON, described by a schema of `components`
+    Json(&'static str),
+    /// JSON array of items described by a schema of `components`
+    JsonArray(&'static str),
+    /// JSON matching either of two schemas of `components`
+    OneOf(&'static str, &'static str),
+    /// `application/scim+json`, described by a schema of `components`
+    Scim(&'static str),
+    /// Any other media type, not described further
+    Other(&'static str),
+    /// `multipart/form-data` with a file in the given field
+    Upload(&'static str),
+    Empty,
+}
+
+struct Reply {
+    status: u16,
+    description: &'static str,
+    content: Content,
+}
+
+impl Reply {
+    const fn json(status: u16, description: &'static str, schema: &'static str) -> Self {
+        Reply {
+            status,
+            description,
+            content: Content::Json(schema),
+        }
+    }
+
+    const fn error(status: u16, description: &'static str) -> Self {
+        Reply::json(status, description, "Error")
+    }
+
+    const fn scim(status: u16, description: &'static str, schema: &'static str) -> Self {
+        Reply {
+            status,
+            description,
+            content: Content::Scim(schema),
+        }
+    }
+
+    const fn other(status: u16, description: &'static str, media_type: &'static str) -> Self {
+        Reply {
+            status,
+            description,
+            content: Content::Other(media_type),
+        }
+    }
+
+    const fn empty(status: u16, description: &'static str) -> Self {
+        Reply {
+            status,
+            description,
+            content: Content::Empty,
+        }
+    }
+}
+
+impl Operation {
+    /// An operation taking nothing but its path parameters, to be completed with
+    /// struct update syntax.
+    const fn new(
+        method: &'static str,
+        path: &'static str,
+        tag: &'static str,
+        summary: &'static str,
+        responses: &'static [Reply],
+    ) -> Self {
+        Operation {
+            method,
+            path,
+            tag,
+            summary,
+            description: "",
+            query: &[],
+            filters: &[],
+            request: None,
+            if_match: false,
+            responses,
+        }
+    }
+}
+
+const INVALID_ID: Reply = Reply::error(400, "The ID is not a UUID");
+const INVALID_QUERY: Reply = Reply::error(400, "A query parameter is invalid");
+const INVALID_BODY: Reply = Reply::error(400, "The ID or the JSON is invalid");
+const INVALID_FIELDS: Reply = Reply::error(422, "Some fields are invalid, see `details`");
+const USER_NOT_FOUND: Reply = Reply::error(404, "The user does not exist");
+const PRODUCT_NOT_FOUND: Reply = Reply::error(404, "The product does not exist");
+const WEBHOOK_NOT_FOUND: Reply = Reply::error(404, "The caller has no webhook with this ID");
+const NOT_MODIFIED: Reply = Reply::empty(304, "`If-None-Match` matches the current `ETag`");
+const VERSION_MISMATCH: Reply =
+    Reply::error(412, "The entity changed since the `If-Match` version");
+const PRECONDITION_REQUIRED: Reply = Reply::error(428, "`If-Match` is missing");
+const PROFILING_DISABLED: Reply = Reply::error(409, "Profiling is disabled");
+const BULK_INVALID: Reply = Reply::error(400, "The body is not an array of items, or is empty");
+const BULK_TOO_LARGE: Reply = Reply::error(413, "The body is too large or has too many items");
+const SAVE_VIEW_DESCRIPTION: &str = "Replaces the view of the caller with the same name. The \
+     list applies it with `?view=<name>`, adding its filter and its sort.";
+const WEBHOOK_DESCRIPTION: &str = "Every change event of the types listed is POSTed to the \
+     URL, with `X-Webhook-Event`, `X-Webhook-Delivery` (the same for every attempt) and \
+     `X-Webhook-Signature: sha256=<hex HMAC-SHA256 of the body, keyed with the secret>`. \
+     A delivery not answered with a 2xx in 10 seconds is tried again after 30 seconds, \
+     then 2, 8 and 32 minutes.";
+const FEED_NOT_FOUND: Reply = Reply::error(404, "The table has no change feed");
+const CDC_DESCRIPTION: &str = "The inserts, updates and deletes of the table, from the audit \
+     log, in a stable order: a change only appears once the transactions that could write \
+     before it are finished, so none ever appears behind a position already read. Save the \
+     `next` of each page processed as the checkpoint of the consumer to resume from it with \
+     `?consumer=`.";
+const CART_DESCRIPTION: &str = "The cart of the user of the access token or, without \
+     one, of the `cart` cookie. The first item added without either starts an anonymous \
+     cart and sets its cookie; `POST /api/v1/auth/login` merges it into the cart of the \
+     user.";
+const COUPON_NOT_FOUND: Reply = Reply::error(404, "No coupon has this ID");
+const INSUFFICIENT_CREDIT: Reply = Reply::error(409, "The user has less credit than taken back");
+const PASSKEYS_DISABLED: Reply = Reply::error(404, "`WEBAUTHN_RP_ID` isn't set");
+const PASSKEYS_DESCRIPTION: &str = "Pass `publicKey` to `navigator.credentials.create()`, \
+     decoding its base64url values, then send the `toJSON()` of the credential with the \
+     `challenge_id` to `POST /api/v1/auth/passkeys` within 5 minutes.";
+const VIEW_NOT_FOUND: Reply = Reply::error(404, "The caller has no saved view with this name");
+const SCIM_INVALID: Reply =
+    Reply::scim(400, "An attribute is invalid (`invalidValue`)", "ScimError");
+const SCIM_UNAUTHORIZED: Reply =
+    Reply::scim(401, "The SCIM token is missing or wrong", "ScimError");
+const SCIM_DISABLED: Reply = Reply::scim(
+    404,
+    "The account does not exist, or `SCIM_TOKEN` isn't set",
+    "ScimError",
+);
+const SCIM_TAKEN: Reply = Reply::scim(
+    409,
+    "The email is already registered (`uniqueness`)",
+    "ScimError",
+);
+const SCIM_CHANGED: Reply = Reply::scim(412, "The account changed meanwhile", "ScimError");
+const VIEW_UNAUTHORIZED: Reply = Reply::error(401, "`view` is given without a valid access token");
+/// `?view=` of the lists
+const VIEW_PARAM: Param = Param {
+    name: "view",
+    description: "Name of a saved view of the caller, adding its filter and sort (requires \
+                  an access token)",
+    kind: ParamKind::String,
+};
+const BULK_DESCRIPTION: &str = "The body is a JSON array, or NDJSON (one item per line) with \
+     `Content-Type: application/x-ndjson`, of at most 10000 items. Every item is validated \
+     on its own: the invalid ones are reported in `results` and the others created.";
+
+const INCLUDE_DELETED: Param = Param {
+    name: "include_deleted",
+    description: "`true` to include the soft-deleted users, with their `deleted_at` \
+                  (requires an access token)",
+    kind: ParamKind::Boolean,
+};
+
+const PAGINATION: &[Param] = &[
+    Param {
+        name: "limit",
+        description: "Page size (default 20, max 100)",
+        kind: ParamKind::Integer,
+    },
+    Param {
+        name: "offset",
+        description: "Number of items to skip",
+        kind: ParamKind::Integer,
+    },
+    Param {
+        name: "order",
+        description: "Sort order",
+        kind: ParamKind::Enum(&["asc", "desc"]),
+    },
+    Param {
+        name: "stream",
+        description: "`true` to receive every item, sorted, in a streamed JSON array \
+                      (`limit` and `offset` are ignored)",
+        kind: ParamKind::Boolean,
+    },
+];
+
+/// Query of the histories, sorted by the order of the changes
+const HISTORY_QUERY: &[Param] = &[
+    Param {
+        name: "limit",
+        description: "Page size (default 20, max 100)",
+        kind: ParamKind::Integer,
+    },
+    Param {
+        name: "offset",
+        description: "Number of changes to skip",
+        kind: ParamKind::Integer,
+    },
+    Param {
+        name: "order",
+        description: "`asc` (default) for the oldest change first, `desc` for the latest",
+        kind: ParamKind::Enum(&["asc", "desc"]),
+    },
+];
+const HISTORY_DESCRIPTION: &str = "Every insert, update and delete recorded in the audit log, \
+     with the old and new value of each changed field, the caller and the request.";
+
+static OPERATIONS: &[Operation] = &[
+    // Operations
+    Operation::new(
+        "GET",
+        "/",
+        "operations",
+        "Greeting",
+        &[Reply::other(200, "Hello World", "text/plain")],
+    ),
+    Operation::new(
+        "GET",
+        "/healthz",
+        "operations",
+        "Liveness probe",
+        &[Reply::json(200, "The process answers requests", "Status")],
+    ),
+    Operation::new(
+        "GET",
+        "/readyz",
+        "operations",
+        "Readiness probe",
+        &[
+            Reply::json(
+                200,
+                "The database answers, with the pool statistics",
+                "Readiness",
+            ),
+            Reply::json(503, "The database doesn't answer", "Readiness"),
+        ],
+    ),
+    Operation {
+        description: "OpenMetrics with exemplars when `Accept` lists \
+                      `application/openmetrics-text`, the Prometheus text format otherwise.",
+        ..Operation::new(
+            "GET",
+            "/metrics",
+            "operations",
+            "Prometheus metrics",
+            &[Reply::other(200, "Every metric", "text/plain")],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/admin/runtime",
+        "operations",
+        "Runtime diagnostics",
+        &[Reply::json(
+            200,
+            "Tasks, connections, event subscribers and database pool",
+            "Object",
+        )],
+    ),
+    Operation {
+        description: "Requires a build with the `jemalloc` feature. The profile is read with \
+                      `jeprof --svg <binary> <profile>`.",
+        ..Operation::new(
+            "GET",
+            "/admin/heap-profile",
+            "operations",
+            "Heap profile",
+            &[
+                Reply::other(
+                    200,
+                    "Profile of the live memory",
+                    "application/octet-stream",
+                ),
+                PROFILING_DISABLED,
+            ],
+        )
+    },
+    Operation {
+        description: "Requires a build with the `pprof` feature. The response is sent once \
+                      the CPU has been sampled for `seconds`.",
+        query: &[
+            Param {
+                name: "seconds",
+                description: "Duration of the profile (default 30, max 300)",
+                kind: ParamKind::Integer,
+            },
+            Param {
+                name: "format",
+                description: "`pprof` (default) or a flame graph SVG",
+                kind: ParamKind::Enum(&["pprof", "flamegraph"]),
+            },
+        ],
+        ..Operation::new(
+            "GET",
+            "/debug/pprof/profile",
+            "operations",
+            "CPU profile",
+            &[
+                Reply::other(200, "The profile", "application/octet-stream"),
+                INVALID_QUERY,
+                Reply::error(
+                    409,
+                    "Profiling is disabled or another profile is being taken",
+                ),
+            ],
+        )
+    },
+    Operation {
+        query: &[
+            Param {
+                name: "status",
+                description: "Only the jobs with this status",
+                kind: ParamKind::Enum(&["queued", "running", "succeeded", "failed"]),
+            },
+            Param {
+                name: "limit",
+                description: "Number of jobs (default 20, max 100)",
+                kind: ParamKind::Integer,
+            },
+        ],
+        ..Operation::new(
+            "GET",
+            "/admin/jobs",
+            "operations",
+            "Latest background jobs",
+            &[
+                Reply {
+                    status: 200,
+                    description: "The jobs, the latest first",
+                    content: Content::JsonArray("Job"),
+                },
+                INVALID_QUERY,
+            ],
+        )
+    },
+    Operation {
+        query: &[
+            Param {
+                name: "entity",
+                description: "Only the changes of this kind of rows",
+                kind: ParamKind::Enum(&["user", "product", "order"]),
+            },
+            Param {
+                name: "id",
+                description: "Only the changes of this row, its entity_id (the UUID of a user \
+                              or product, the ID of an order); requires entity",
+                kind: ParamKind::String,
+            },
+            Param {
+                name: "limit",
+                description: "Number of records (default 20, max 100)",
+                kind: ParamKind::Integer,
+            },
+        ],
+        description: "Every insert, update and delete of a user, product or order, with \
+                      the caller and the changed fields.",
+        ..Operation::new(
+            "GET",
+            "/admin/audit",
+            "operations",
+            "Audit log",
+            &[
+                Reply {
+                    status: 200,
+                    description: "The records, the latest first",
+                    content: Content::JsonArray("AuditRecord"),
+                },
+                INVALID_QUERY,
+            ],
+        )
+    },
+    Operation {
+        request: Some(Content::Json("UserRole")),
+        description: "`viewer` reads, `editor` also writes the products, and `admin` is \
+                      allowed every route. The change applies to the tokens already \
+                      issued to the user.",
+        ..Operation::new(
+            "PUT",
+            "/admin/users/:id/role",
+            "operations",
+            "Change the role of a user",
+            &[
+                Reply::json(200, "The user and their new role", "UserRole"),
+                INVALID_BODY,
+                INVALID_FIELDS,
+                USER_NOT_FOUND,
+            ],
+        )
+    },
+    Operation {
+        description: "A dry run of the rules of `RETENTION_RULES_PATH`: the rows each would \
+                      delete or anonymize now. The rules run on their schedule as jobs, \
+                      whose `progress` reports the rows removed (or only counted, for a \
+                      rule with `dry_run`).",
+        ..Operation::new(
+            "GET",
+            "/admin/retention",
+            "operations",
+            "Data retention rules",
+            &[Reply {
+                status: 200,
+                description: "The rules, in the order of the configuration",
+                content: Content::JsonArray("RetentionRule"),
+            }],
+        )
+    },
+    Operation {
+        query: &[Param {
+            name: "limit",
+            description: "Number of archives (default 20, max 100)",
+            kind: ParamKind::Integer,
+        }],
+        description: "With `PARTITION_EXPORT`, the partitions of the `archive` schema are \
+                      exported to the file storage as gzipped CSV, then dropped; each \
+                      file has a manifest.",
+        ..Operation::new(
+            "GET",
+            "/admin/archives",
+            "operations",
+            "Exported partitions",
+            &[
+                Reply {
+                    status: 200,
+                    description: "The archives, the latest first",
+                    content: Content::JsonArray("Archive"),
+                },
+                INVALID_QUERY,
+            ],
+        )
+    },
+    Operation {
+        description: "The partition is created in the `archive` schema again from its \
+                      file, once checked against its SHA-256; follow the operation of the \
+                      `Location` header for the outcome.",
+        ..Operation::new(
+            "POST",
+            "/admin/archives/:id/restore",
+            "operations",
+            "Restore an exported partition",
+            &[
+                Reply::json(202, "The operation restoring the partition", "Job"),
+                Reply::error(400, "The ID is not a UUID"),
+                Reply::error(404, "No archive has this ID"),
+            ],
+        )
+    },
+    Operation {
+        query: &[Param {
+            name: "table",
+            description: "Table to export",
+            kind: ParamKind::Enum(&["users", "products", "orders"]),
+        }],
+        description: "A snapshot of the table, compressed with Snappy, for the ingestion in \
+                      a warehouse. The users and products are identified by their UUID; \
+                      the password hashes are left out.",
+        ..Operation::new(
+            "GET",
+            "/admin/export/parquet",
+            "operations",
+            "Parquet export of a table",
+            &[
+                Reply::other(200, "The Parquet file", "application/vnd.apache.parquet"),
+                Reply::error(400, "The table is missing or unknown"),
+            ],
+        )
+    },
+    Operation {
+        description: "The read-only queries of `CONSOLE_QUERIES_PATH`, with their SQL and \
+                      the parameters they take.",
+        ..Operation::new(
+            "GET",
+            "/admin/queries",
+            "operations",
+            "Queries of the admin console",
+            &[Reply {
+                status: 200,
+                description: "The queries, in the order of the configuration",
+                content: Content::JsonArray("ConsoleQuery"),
+            }],
+        )
+    },
+    Operation {
+        request: Some(Content::Json("ConsoleRun")),
+        description: "Runs the query in a read-only transaction, with the values of its \
+                      parameters. At most `max_rows` rows are returned, as CSV with \
+                      `Accept: text/csv`.",
+        ..Operation::new(
+            "POST",
+            "/admin/queries/:name/run",
+            "operations",
+            "Run a console query",
+            &[
+                Reply::json(200, "The rows of the query", "ConsoleResult"),
+                Reply::error(400, "The JSON is invalid"),
+                Reply::error(404, "The console has no query with this name"),
+                Reply::error(422, "A parameter is missing, unknown or of the wrong type"),
+            ],
+        )
+    },
+    Operation {
+        description: "The objectives of `SLO_PATH` and how fast they burn their error budget, \
+                      from the requests this instance served. An objective burning `fast` \
+                      or `slow` is sent to the webhooks as a `slo.burning` event.",
+        ..Operation::new(
+            "GET",
+            "/admin/slo",
+            "operations",
+            "Service level objectives",
+            &[Reply {
+                status: 200,
+                description: "The objectives, in the order of the configuration",
+                content: Content::JsonArray("Slo"),
+            }],
+        )
+    },
+    Operation {
+        description: "Recomputes the balance of every account from its postings, and looks \
+                      for entries whose postings don't sum to zero. The `check_ledger` job \
+                      runs the same check on `LEDGER_CHECK_SCHEDULE`.",
+        ..Operation::new(
+            "GET",
+            "/admin/ledger/check",
+            "operations",
+            "Consistency of the ledger",
+            &[Reply::json(200, "The outcome of the check", "LedgerReport")],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/openapi.json",
+        "operations",
+        "This document",
+        &[Reply::json(
+            200,
+            "OpenAPI 3.0 description of the API",
+            "Object",
+        )],
+    ),
+    Operation::new(
+        "GET",
+        "/docs",
+        "operations",
+        "Interactive documentation",
+        &[Reply::other(
+            200,
+            "Swagger UI page reading this document",
+            "text/html",
+        )],
+    ),
+    Operation {
+        description: "Development only: lists the routes and sends requests to them, \
+                      with an access token, path parameters, a query, headers and a \
+                      body.",
+        ..Operation::new(
+            "GET",
+            "/console",
+            "operations",
+            "Console of the routes",
+            &[
+                Reply::other(200, "Page sending requests to the routes", "text/html"),
+                Reply::error(404, "`DEV_CONSOLE` isn't set"),
+            ],
+        )
+    },
+    Operation {
+        description: "The file at `path` under `STATIC_DIR`, with an `ETag` and a \
+                      `Last-Modified` for the conditional requests, and a single range \
+                      of bytes served with `Range`. With `SPA_FALLBACK`, the browsers \
+                      asking for a path without routes outside of `/api/` get \
+                      `index.html`.",
+        ..Operation::new(
+            "GET",
+            "/static/*path",
+            "operations",
+            "Static file",
+            &[
+                Reply::other(200, "The file, of the media type of its extension", "*/*"),
+                Reply::other(206, "The part of the file asked for in `Range`", "*/*"),
+                Reply::empty(304, "`If-None-Match` or `If-Modified-Since` match the file"),
+                Reply::error(
+                    404,
+                    "The file doesn't exist or is hidden, or `STATIC_DIR` isn't set",
+                ),
+                Reply::empty(416, "The range starts past the end of the file"),
+            ],
+        )
+    },
+    // OpenID Connect
+    Operation::new(
+        "GET",
+        "/.well-known/openid-configuration",
+        "oidc",
+        "Discovery document",
+        &[
+            Reply::json(200, "The issuer and its endpoints", "Object"),
+            Reply::error(404, "`OIDC_ISSUER` isn't set"),
+        ],
+    ),
+    Operation::new(
+        "GET",
+        "/oauth/jwks",
+        "oidc",
+        "Keys of the ID tokens",
+        &[
+            Reply::json(200, "JWK Set of the ES256 signing key", "Object"),
+            Reply::error(404, "`OIDC_ISSUER` isn't set"),
+        ],
+    ),
+    Operation {
+        description: "Where the apps send the browser. Only the authorization code flow with \
+                      PKCE (S256) is supported. Errors other than the client and its \
+                      `redirect_uri` are sent back to the app.",
+        query: &[
+            Param {
+                name: "response_type",
+                description: "`code`",
+                kind: ParamKind::Enum(&["code"]),
+            },
+            Param {
+                name: "client_id",
+                description: "A client of `OIDC_CLIENTS_PATH`",
+                kind: ParamKind::String,
+            },
+            Param {
+                name: "redirect_uri",
+                description: "One of the `redirect_uris` of the client",
+                kind: ParamKind::String,
+            },
+            Param {
+                name: "scope",
+                description: "`openid`, with `profile` and `email` for the name and email",
+                kind: ParamKind::String,
+            },
+            Param {
+                name: "state",
+                description: "Sent back to the app as is",
+                kind: ParamKind::String,
+            },
+            Param {
+                name: "nonce",
+                description: "Copied in the ID token",
+                kind: ParamKind::String,
+            },
+            Param {
+                name: "code_challenge",
+                description: "Base64url SHA-256 of the code verifier",
+                kind: ParamKind::String,
+            },
+            Param {
+                name: "code_challenge_method",
+                description: "`S256`",
+                kind: ParamKind::Enum(&["S256"]),
+            },
+        ],
+        ..Operation::new(
+            "GET",
+            "/oauth/authorize",
+            "oidc",
+            "Login page of an app",
+            &[
+                Reply::other(200, "The login form", "text/html"),
+                Reply::empty(303, "Back to the app with `error`"),
+                Reply::error(
+                    400,
+                    "The client is unknown or the `redirect_uri` isn't one of its",
+                ),
+                Reply::error(404, "`OIDC_ISSUER` isn't set"),
+            ],
+        )
+    },
+    Operation {
+        description: "The form of the login page: the parameters of the authorization \
+                      request, `email` and `password`.",
+        request: Some(Content::Other("application/x-www-form-urlencoded")),
+        ..Operation::new(
+            "POST",
+            "/oauth/authorize",
+            "oidc",
+            "Log in for an app",
+            &[
+                Reply::empty(303, "Back to the app with `code` and `state`, or `error`"),
+                Reply::error(
+                    400,
+                    "The client is unknown or the `redirect_uri` isn't one of its",
+                ),
+                Reply::other(
+                    401,
+                    "The login form again, the credentials are wrong",
+                    "text/html",
+                ),
+                Reply::error(404, "`OIDC_ISSUER` isn't set"),
+            ],
+        )
+    },
+    Operation {
+        description: "`grant_type=authorization_code` with the `code`, the `redirect_uri` and \
+                      the `code_verifier`. Clients with a secret send it as `client_secret` \
+                      or with HTTP Basic. The access token is a token of this API.",
+        request: Some(Content::Other("application/x-www-form-urlencoded")),
+        ..Operation::new(
+            "POST",
+            "/oauth/token",
+            "oidc",
+            "Exchange a code for tokens",
+            &[
+                Reply::json(200, "The ID and access tokens", "OidcTokens"),
+                Reply::json(400, "The code is invalid, expired or used", "OAuthError"),
+                Reply::json(
+                    401,
+                    "The client is unknown or its secret wrong",
+                    "OAuthError",
+                ),
+                Reply::error(404, "`OIDC_ISSUER` isn't set"),
+            ],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/oauth/userinfo",
+        "oidc",
+        "Claims of the user of the token",
+        &[
+            Reply::json(
+                200,
+                "`sub`, `name` with the `profile` scope, `email` and `email_verified` with \
+                 the `email` scope",
+                "Object",
+            ),
+            Reply::error(
+                401,
+                "The client token or the access token is missing, invalid or expired",
+            ),
+            Reply::error(404, "`OIDC_ISSUER` isn't set"),
+        ],
+    ),
+    // SCIM
+    Operation {
+        description: "The accounts, the users with an email. The filter compares `id`, \
+                      `userName`, `emails.value`, `externalId` and `displayName` with `eq`, \
+                      `ne`, `co`, `sw`, `ew` and `pr`, case insensitive, combined with \
+                      `and`, `or`, `not` and parentheses.",
+        query: &[
+            Param {
+                name: "filter",
+                description: "SCIM filter, such as `userName eq \"ann@example.com\"`",
+                kind: ParamKind::String,
+            },
+            Param {
+                name: "startIndex",
+                description: "1-based index of the first account (default 1)",
+                kind: ParamKind::Integer,
+            },
+            Param {
+                name: "count",
+                description: "Page size (default and max 100)",
+                kind: ParamKind::Integer,
+            },
+        ],
+        ..Operation::new(
+            "GET",
+            "/scim/v2/Users",
+            "scim",
+            "List the accounts",
+            &[
+                Reply::scim(200, "A page of the accounts", "ScimListResponse"),
+                Reply::scim(400, "The filter is invalid (`invalidFilter`)", "ScimError"),
+                SCIM_UNAUTHORIZED,
+                SCIM_DISABLED,
+            ],
+        )
+    },
+    Operation {
+        description: "`userName` is the email. The name is the `displayName`, else \
+                      `name.formatted` or `name.givenName` and `name.familyName`.",
+        request: Some(Content::Scim("ScimUser")),
+        ..Operation::new(
+            "POST",
+            "/scim/v2/Users",
+            "scim",
+            "Provision an account",
+            &[
+                Reply::scim(201, "The account, with its `Location`", "ScimUser"),
+                SCIM_INVALID,
+                SCIM_UNAUTHORIZED,
+                SCIM_DISABLED,
+                SCIM_TAKEN,
+            ],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/scim/v2/Users/:id",
+        "scim",
+        "Get an account",
+        &[
+            Reply::scim(200, "The account and its `ETag`", "ScimUser"),
+            SCIM_UNAUTHORIZED,
+            SCIM_DISABLED,
+        ],
+    ),
+    Operation {
+        description: "`active`, `age` and the password keep their value when left out.",
+        request: Some(Content::Scim("ScimUser")),
+        ..Operation::new(
+            "PUT",
+            "/scim/v2/Users/:id",
+            "scim",
+            "Replace an account",
+            &[
+                Reply::scim(200, "The updated account", "ScimUser"),
+                SCIM_INVALID,
+                SCIM_UNAUTHORIZED,
+                SCIM_DISABLED,
+                SCIM_TAKEN,
+                SCIM_CHANGED,
+            ],
+        )
+    },
+    Operation {
+        description: "Deactivating an account (`active` set to `false`) keeps it from \
+                      logging in.",
+        request: Some(Content::Scim("ScimPatchOp")),
+        ..Operation::new(
+            "PATCH",
+            "/scim/v2/Users/:id",
+            "scim",
+            "Update an account",
+            &[
+                Reply::scim(200, "The updated account", "ScimUser"),
+                Reply::scim(
+                    400,
+                    "An operation is invalid (`invalidValue`, `invalidSyntax`, `noTarget`)",
+                    "ScimError",
+                ),
+                SCIM_UNAUTHORIZED,
+                SCIM_DISABLED,
+                SCIM_TAKEN,
+                SCIM_CHANGED,
+            ],
+        )
+    },
+    Operation::new(
+        "DELETE",
+        "/scim/v2/Users/:id",
+        "scim",
+        "Deprovision an account",
+        &[
+            Reply::empty(204, "The user and their avatar were deleted"),
+            SCIM_UNAUTHORIZED,
+            SCIM_DISABLED,
+        ],
+    ),
+    // Auth
+    Operation {
+        request: Some(Content::Json("Registration")),
+        description: "The new user is sent a welcome email in the background.",
+        ..Operation::new(
+            "POST",
+            "/api/v1/auth/register",
+            "auth",
+            "Create an account",
+            &[
+                Reply::json(201, "The account was created", "CreatedId"),
+                Reply::error(400, "The JSON is invalid"),
+                INVALID_FIELDS,
+                Reply::error(409, "The email is already registered"),
+            ],
+        )
+    },
+    Operation {
+        request: Some(Content::Json("Login")),
+        ..Operation::new(
+            "POST",
+            "/api/v1/auth/login",
+            "auth",
+            "Obtain an access token",
+            &[
+                Reply {
+                    status: 200,
+                    description: "A bearer token, or a challenge for a passkey of the user \
+                                  if they require one after the password",
+                    content: Content::OneOf("Token", "SecondFactorChallenge"),
+                },
+                Reply::error(400, "The JSON is invalid"),
+                Reply::error(401, "The credentials are wrong"),
+            ],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/api/v1/auth/me",
+        "auth",
+        "Profile of the authenticated user",
+        &[
+            Reply::json(200, "The profile", "Profile"),
+            Reply::error(404, "The user was deleted after the token was issued"),
+        ],
+    ),
+    Operation {
+        description: PASSKEYS_DESCRIPTION,
+        ..Operation::new(
+            "POST",
+            "/api/v1/auth/passkeys/registration",
+            "auth",
+            "Start the registration of a passkey",
+            &[
+                Reply::json(
+                    200,
+                    "The options of `navigator.credentials.create()`",
+                    "PasskeyChallenge",
+                ),
+                PASSKEYS_DISABLED,
+            ],
+        )
+    },
+    Operation {
+        request: Some(Content::Json("PasskeyRegistration")),
+        ..Operation::new(
+            "POST",
+            "/api/v1/auth/passkeys",
+            "auth",
+            "Register a passkey created by the browser",
+            &[
+                Reply::json(201, "The passkey was registered", "Passkey"),
+                Reply::error(
+                    400,
+                    "The JSON or the passkey is invalid, or the challenge unknown or expired",
+                ),
+                PASSKEYS_DISABLED,
+                Reply::error(409, "The passkey is already registered"),
+                INVALID_FIELDS,
+            ],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/api/v1/auth/passkeys",
+        "auth",
+        "Passkeys of the caller",
+        &[
+            Reply {
+                status: 200,
+                description: "The passkeys of the caller, the oldest first",
+                content: Content::JsonArray("Passkey"),
+            },
+            PASSKEYS_DISABLED,
+        ],
+    ),
+    Operation::new(
+        "DELETE",
+        "/api/v1/auth/passkeys/:id",
+        "auth",
+        "Remove a passkey",
+        &[
+            Reply::empty(204, "The passkey was removed"),
+            INVALID_ID,
+            Reply::error(
+                404,
+                "The caller has no passkey with this ID, or `WEBAUTHN_RP_ID` isn't set",
+            ),
+            Reply::error(
+                409,
+                "It's the last passkey of the caller, who requires one as second factor",
+            ),
+        ],
+    ),
+    Operation {
+        request: Some(Content::Json("SecondFactor")),
+        description: "Once required, `POST /api/v1/auth/login` answers the right password \
+                      with a challenge for the passkeys of the user, to be answered at \
+                      `POST /api/v1/auth/passkeys/login`. The OpenID Connect login page \
+                      refuses these users.",
+        ..Operation::new(
+            "PUT",
+            "/api/v1/auth/second-factor",
+            "auth",
+            "Require a passkey after the password, or stop",
+            &[
+                Reply::empty(204, "The setting was saved"),
+                Reply::error(400, "The JSON is invalid"),
+                PASSKEYS_DISABLED,
+                Reply::error(409, "The caller has no passkey"),
+            ],
+        )
+    },
+    Operation::new(
+        "POST",
+        "/api/v1/auth/passkeys/authentication",
+        "auth",
+        "Start a login without password",
+        &[
+            Reply::json(
+                200,
+                "The options of `navigator.credentials.get()`",
+                "PasskeyChallenge",
+            ),
+            PASSKEYS_DISABLED,
+        ],
+    ),
+    Operation {
+        request: Some(Content::Json("PasskeyLogin")),
+        description: "Answers the challenge of `POST /api/v1/auth/passkeys/authentication`, \
+                      the user verified by the authenticator, or the one of \
+                      `POST /api/v1/auth/login`, the user present.",
+        ..Operation::new(
+            "POST",
+            "/api/v1/auth/passkeys/login",
+            "auth",
+            "Obtain an access token with a passkey",
+            &[
+                Reply::json(200, "A bearer token", "Token"),
+                Reply::error(400, "The JSON is invalid"),
+                Reply::error(
+                    401,
+                    "The passkey or its signature is invalid, or the challenge unknown or \
+                     expired",
+                ),
+                PASSKEYS_DISABLED,
+            ],
+        )
+    },
+    // Signed URLs
+    Operation {
+        request: Some(Content::Json("SignUrl")),
+        description: "The URL is valid without access token until `expires`, with the rights \
+                      of the caller. Only some `GET` routes accept signed URLs: the avatars, \
+                      the Parquet exports, the retention and SLO reports.",
+        ..Operation::new(
+            "POST",
+            "/api/v1/signed-urls",
+            "auth",
+            "Sign a URL of a download or report",
+            &[
+                Reply::json(200, "The signed URL", "SignedUrl"),
+                Reply::error(400, "The JSON is invalid"),
+                INVALID_FIELDS,
+            ],
+        )
+    },
+    // Dashboard
+    Operation {
+        description: "The parts are fetched concurrently, each within \
+                      `DASHBOARD_PART_TIMEOUT`. A part that fails or times out is `null` and \
+                      its reason is in `errors`, the others are still returned.",
+        ..Operation::new(
+            "GET",
+            "/api/v1/dashboard",
+            "dashboard",
+            "Users summary, product stats, latest orders and upstream parts",
+            &[Reply::json(
+                200,
+                "The dashboard, some parts possibly missing",
+                "Dashboard",
+            )],
+        )
+    },
+    // Experiments
+    Operation {
+        description: "The caller is the user of the access token when one is sent, its client \
+                      IP otherwise. Every response carries the same variants in \
+                      `X-Experiments`.",
+        ..Operation::new(
+            "GET",
+            "/api/v1/experiments",
+            "experiments",
+            "Running A/B experiments and the variants of the caller",
+            &[Reply {
+                status: 200,
+                description: "The experiments, empty when none runs",
+                content: Content::JsonArray("Experiment"),
+            }],
+        )
+    },
+    // Users
+    Operation {
+        description: "With `modified_since`, returns only the users changed since then, \
+                      unpaginated (`UserChanges`).",
+        query: &[
+            Param {
+                name: "sort",
+                description: "Column to sort by",
+                kind: ParamKind::Enum(users::SORTABLE_COLUMNS),
+            },
+            Param {
+                name: "modified_since",
+                description: "The `version` of a previous delta, or an RFC 3339 timestamp",
+                kind: ParamKind::String,
+            },
+            VIEW_PARAM,
+            INCLUDE_DELETED,
+        ],
+        filters: users::FILTERABLE_FIELDS,
+        ..Operation::new(
+            "GET",
+            "/api/v1/users",
+            "users",
+            "List users",
+            &[
+                Reply {
+                    status: 200,
+                    description: "A page of users, or their changes",
+                    content: Content::OneOf("UserPage", "UserChanges"),
+                },
+                NOT_MODIFIED,
+                INVALID_QUERY,
+                Reply::error(
+                    401,
+                    "`view` or `include_deleted` is given without a valid access token",
+                ),
+                VIEW_NOT_FOUND,
+            ],
+        )
+    },
+    Operation {
+        request: Some(Content::Json("User")),
+        ..Operation::new(
+            "POST",
+            "/api/v1/users",
+            "users",
+            "Create a user",
+            &[
+                Reply::json(200, "The user was inserted", "CreatedId"),
+                Reply::error(400, "The JSON is invalid"),
+                INVALID_FIELDS,
+            ],
+        )
+    },
+    Operation {
+        request: Some(Content::JsonArray("User")),
+        description: BULK_DESCRIPTION,
+        ..Operation::new(
+            "POST",
+            "/api/v1/users/bulk",
+            "users",
+            "Create many users",
+            &[
+                Reply::json(200, "The outcome of every user", "BulkResult"),
+                BULK_INVALID,
+                BULK_TOO_LARGE,
+            ],
+        )
+    },
+    Operation {
+        request: Some(Content::Json("BulkDelete")),
+        description: "The users are soft-deleted in the background, 500 at a time, each to \
+                      be restored as any other; follow the operation of the `Location` \
+                      header for the progress.",
+        ..Operation::new(
+            "POST",
+            "/api/v1/users/bulk-delete",
+            "users",
+            "Soft-delete the users matching a filter",
+            &[
+                Reply::json(202, "The operation soft-deleting the users", "Job"),
+                Reply::error(400, "The JSON or the filter expression is invalid"),
+                Reply::error(
+                    422,
+                    "The filter is empty, or matches no user or more than 10000",
+                ),
+            ],
+        )
+    },
+    Operation {
+        request: Some(Content::Json("SavedView")),
+        description: SAVE_VIEW_DESCRIPTION,
+        ..Operation::new(
+            "POST",
+            "/api/v1/users/views",
+            "users",
+            "Save a view of the users list",
+            &[
+                Reply::json(200, "The view", "SavedView"),
+                Reply::error(400, "The JSON is invalid"),
+                INVALID_FIELDS,
+            ],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/api/v1/users/views",
+        "users",
+        "Saved views of the users list",
+        &[Reply {
+            status: 200,
+            description: "The views of the caller, by name",
+            content: Content::JsonArray("SavedView"),
+        }],
+    ),
+    Operation {
+        query: &[
+            Param {
+                name: "as_of",
+                description: "A timestamp (`2025-01-31T12:00:00Z`): the user as it was then, \
+                              rebuilt from the audit log even if deleted since (requires an \
+                              access token)",
+                kind: ParamKind::String,
+            },
+            INCLUDE_DELETED,
+        ],
+        ..Operation::new(
+            "GET",
+            "/api/v1/users/:id",
+            "users",
+            "Get a user",
+            &[
+                Reply::json(200, "The user, with its `ETag` (without `as_of`)", "User"),
+                NOT_MODIFIED,
+                Reply::error(400, "The ID is not a UUID, or `as_of` not a timestamp"),
+                Reply::error(
+                    401,
+                    "`as_of` or `include_deleted` is given without a valid access token",
+                ),
+                Reply::error(
+                    404,
+                    "The user does not exist (or didn't at `as_of`), or is soft-deleted",
+                ),
+            ],
+        )
+    },
+    Operation {
+        request: Some(Content::Json("User")),
+        if_match: true,
+        ..Operation::new(
+            "PUT",
+            "/api/v1/users/:id",
+            "users",
+            "Replace a user",
+            &[
+                Reply::json(200, "The updated user, with its new `ETag`", "User"),
+                INVALID_BODY,
+                INVALID_FIELDS,
+                USER_NOT_FOUND,
+                VERSION_MISMATCH,
+                PRECONDITION_REQUIRED,
+            ],
+        )
+    },
+    Operation {
+        request: Some(Content::Json("UserPatch")),
+        if_match: true,
+        ..Operation::new(
+            "PATCH",
+            "/api/v1/users/:id",
+            "users",
+            "Update some fields of a user",
+            &[
+                Reply::json(200, "The updated user, with its new `ETag`", "User"),
+                INVALID_BODY,
+                INVALID_FIELDS,
+                USER_NOT_FOUND,
+                VERSION_MISMATCH,
+                PRECONDITION_REQUIRED,
+            ],
+        )
+    },
+    Operation {
+        query: &[Param {
+            name: "hard",
+            description: "`true` to delete the user for good, soft-deleted or not",
+            kind: ParamKind::Boolean,
+        }],
+        description: "The user is soft-deleted: left out of the lists and missing for the \
+                      other routes, but kept with their avatar to be restored.",
+        if_match: true,
+        ..Operation::new(
+            "DELETE",
+            "/api/v1/users/:id",
+            "users",
+            "Delete a user",
+            &[
+                Reply::empty(
+                    204,
+                    "The user was deleted, along with their avatar when for good",
+                ),
+                INVALID_ID,
+                Reply::error(
+                    403,
+                    "`hard=true` without the admin role (`INSUFFICIENT_ROLE`)",
+                ),
+                USER_NOT_FOUND,
+                VERSION_MISMATCH,
+                PRECONDITION_REQUIRED,
+            ],
+        )
+    },
+    Operation::new(
+        "POST",
+        "/api/v1/users/:id/restore",
+        "users",
+        "Undelete a soft-deleted user",
+        &[
+            Reply::json(200, "The restored user, with its new `ETag`", "User"),
+            INVALID_ID,
+            USER_NOT_FOUND,
+            Reply::error(409, "The user isn't deleted"),
+        ],
+    ),
+    Operation {
+        request: Some(Content::Json("NewOrder")),
+        description: "The order is inserted, the product stock decremented and the coupon \
+                      redeemed in a single transaction. A coupon is redeemed at most \
+                      `max_redemptions` times, even by concurrent orders.",
+        ..Operation::new(
+            "POST",
+            "/api/v1/users/:id/orders",
+            "orders",
+            "Place an order",
+            &[
+                Reply::json(201, "The new order", "Order"),
+                INVALID_BODY,
+                INVALID_FIELDS,
+                Reply::error(404, "The user, the product or the coupon does not exist"),
+                Reply::error(
+                    409,
+                    "The product doesn't have enough stock, or the coupon can't be redeemed",
+                ),
+            ],
+        )
+    },
+    Operation {
+        description: "Credits the total of the order (quantity times unit price, less the \
+                      discount) to the user, who can be refunded once per order.",
+        ..Operation::new(
+            "POST",
+            "/api/v1/users/:id/orders/:order_id/refund",
+            "orders",
+            "Refund an order to the credit of the user",
+            &[
+                Reply::json(201, "The ledger entry of the refund", "LedgerEntry"),
+                Reply::error(400, "An ID is invalid, or the order was free"),
+                Reply::error(404, "The user does not exist or has no such order"),
+                Reply::error(409, "The order was already refunded"),
+            ],
+        )
+    },
+    Operation {
+        query: &[Param {
+            name: "limit",
+            description: "Number of entries (default 20, max 100)",
+            kind: ParamKind::Integer,
+        }],
+        description: "Amounts are in cents. Each entry has the credit of the user after it.",
+        ..Operation::new(
+            "GET",
+            "/api/v1/users/:id/balance",
+            "users",
+            "Credit of a user and their latest ledger entries",
+            &[
+                Reply::json(
+                    200,
+                    "The credit and the entries, the latest first",
+                    "Balance",
+                ),
+                Reply::error(400, "The ID or a query parameter is invalid"),
+                USER_NOT_FOUND,
+            ],
+        )
+    },
+    Operation {
+        request: Some(Content::Json("GrantCredit")),
+        description: "A positive amount grants credit, a negative one takes it back, in \
+                      cents. The credit of a user can't go below zero.",
+        ..Operation::new(
+            "POST",
+            "/api/v1/users/:id/credits",
+            "users",
+            "Grant credit to a user, or take it back",
+            &[
+                Reply::json(201, "The ledger entry", "LedgerEntry"),
+                INVALID_BODY,
+                INVALID_FIELDS,
+                USER_NOT_FOUND,
+                INSUFFICIENT_CREDIT,
+            ],
+        )
+    },
+    Operation {
+        request: Some(Content::Upload("avatar")),
+        description: "PNG, JPEG, GIF or WebP, told by the `Content-Type` of the part, up to \
+                      `MAX_UPLOAD_SIZE` bytes. Replaces the previous avatar.",
+        ..Operation::new(
+            "POST",
+            "/api/v1/users/:id/avatar",
+            "users",
+            "Upload the avatar of a user",
+            &[
+                Reply::json(200, "The URL of the avatar", "Avatar"),
+                Reply::error(400, "The ID is invalid or the body isn't valid multipart"),
+                USER_NOT_FOUND,
+                Reply::error(413, "The file is too large"),
+                Reply::error(
+                    422,
+                    "The `avatar` field is missing or not a supported image",
+                ),
+            ],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/api/v1/users/:id/avatar",
+        "users",
+        "Download the avatar of a user",
+        &[
+            Reply::other(200, "The image", "image/*"),
+            INVALID_ID,
+            Reply::error(404, "The user does not exist or has no avatar"),
+        ],
+    ),
+    Operation {
+        query: HISTORY_QUERY,
+        description: HISTORY_DESCRIPTION,
+        ..Operation::new(
+            "GET",
+            "/api/v1/users/:id/history",
+            "users",
+            "Change history of a user",
+            &[
+                Reply::json(200, "A page of changes", "AuditPage"),
+                Reply::error(400, "The ID or a query parameter is invalid"),
+                USER_NOT_FOUND,
+            ],
+        )
+    },
+    Operation {
+        query: &[Param {
+            name: "limit",
+            description: "Number of products (default 10, max 20)",
+            kind: ParamKind::Integer,
+        }],
+        description: "The products bought by the users who bought a product of the user, \
+                      then the products ordered the most in the last 90 days, without \
+                      those the user bought or out of stock. Computed on the first \
+                      request, then cached and refreshed on `RECOMMENDATIONS_SCHEDULE`.",
+        ..Operation::new(
+            "GET",
+            "/api/v1/users/:id/recommendations",
+            "users",
+            "Products recommended to a user",
+            &[
+                Reply::json(200, "The products, the best first", "Recommendations"),
+                Reply::error(400, "The ID or a query parameter is invalid"),
+                USER_NOT_FOUND,
+            ],
+        )
+    },
+    // Products
+    Operation {
+        query: &[
+            Param {
+                name: "sort",
+                description: "Column to sort by",
+                kind: ParamKind::Enum(products::SORTABLE_COLUMNS),
+            },
+            VIEW_PARAM,
+        ],
+        filters: products::FILTERABLE_FIELDS,
+        ..Operation::new(
+            "GET",
+            "/api/v1/products",
+            "products",
+            "List products",
+            &[
+                Reply::json(200, "A page of products", "ProductPage"),
+                NOT_MODIFIED,
+                INVALID_QUERY,
+                VIEW_UNAUTHORIZED,
+                VIEW_NOT_FOUND,
+            ],
+        )
+    },
+    Operation {
+        request: Some(Content::Json("NewProduct")),
+        ..Operation::new(
+            "POST",
+            "/api/v1/products",
+            "products",
+            "Create a product",
+            &[
+                Reply::json(201, "The new product", "Product"),
+                Reply::error(400, "The JSON is invalid"),
+                INVALID_FIELDS,
+            ],
+        )
+    },
+    Operation {
+        request: Some(Content::JsonArray("NewProduct")),
+        description: BULK_DESCRIPTION,
+        ..Operation::new(
+            "POST",
+            "/api/v1/products/bulk",
+            "products",
+            "Create many products",
+            &[
+                Reply::json(200, "The outcome of every product", "BulkResult"),
+                BULK_INVALID,
+                BULK_TOO_LARGE,
+            ],
+        )
+    },
+    Operation {
+        request: Some(Content::Json("SavedView")),
+        description: SAVE_VIEW_DESCRIPTION,
+        ..Operation::new(
+            "POST",
+            "/api/v1/products/views",
+            "products",
+            "Save a view of the products list",
+            &[
+                Reply::json(200, "The view", "SavedView"),
+                Reply::error(400, "The JSON is invalid"),
+                INVALID_FIELDS,
+            ],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/api/v1/products/views",
+        "products",
+        "Saved views of the products list",
+        &[Reply {
+            status: 200,
+            description: "The views of the caller, by name",
+            content: Content::JsonArray("SavedView"),
+        }],
+    ),
+    Operation {
+        query: &[
+            Param {
+                name: "q",
+                description: "The text searched, at most 200 characters (required)",
+                kind: ParamKind::String,
+            },
+            Param {
+                name: "limit",
+                description: "Number of products (default 20, max 100)",
+                kind: ParamKind::Integer,
+            },
+        ],
+        description: "The products whose embedding is the nearest to the one of `q`, \
+                      blended with those whose name contains its words, the products high \
+                      in both lists first. Without `EMBEDDINGS_PROVIDER`, or when the \
+                      provider fails, only the words are matched and `semantic` is `false`.",
+        ..Operation::new(
+            "GET",
+            "/api/v1/products/semantic-search",
+            "products",
+            "Search products by meaning",
+            &[
+                Reply::json(200, "The products found, the best first", "ProductSearch"),
+                INVALID_QUERY,
+            ],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/api/v1/products/:id",
+        "products",
+        "Get a product",
+        &[
+            Reply::json(200, "The product, with its `ETag`", "Product"),
+            NOT_MODIFIED,
+            INVALID_ID,
+            PRODUCT_NOT_FOUND,
+        ],
+    ),
+    Operation {
+        request: Some(Content::Json("NewProduct")),
+        if_match: true,
+        ..Operation::new(
+            "PUT",
+            "/api/v1/products/:id",
+            "products",
+            "Replace a product",
+            &[
+                Reply::json(200, "The updated product, with its new `ETag`", "Product"),
+                INVALID_BODY,
+                INVALID_FIELDS,
+                PRODUCT_NOT_FOUND,
+                VERSION_MISMATCH,
+                PRECONDITION_REQUIRED,
+            ],
+        )
+    },
+    Operation {
+        if_match: true,
+        ..Operation::new(
+            "DELETE",
+            "/api/v1/products/:id",
+            "products",
+            "Delete a product",
+            &[
+                Reply::empty(204, "The product was deleted"),
+                INVALID_ID,
+                PRODUCT_NOT_FOUND,
+                VERSION_MISMATCH,
+                PRECONDITION_REQUIRED,
+            ],
+        )
+    },
+    Operation {
+        query: HISTORY_QUERY,
+        description: HISTORY_DESCRIPTION,
+        ..Operation::new(
+            "GET",
+            "/api/v1/products/:id/history",
+            "products",
+            "Change history of a product",
+            &[
+                Reply::json(200, "A page of changes", "AuditPage"),
+                Reply::error(400, "The ID or a query parameter is invalid"),
+                PRODUCT_NOT_FOUND,
+            ],
+        )
+    },
+    // Cart and wishlist
+    Operation {
+        description: CART_DESCRIPTION,
+        ..Operation::new(
+            "GET",
+            "/api/v1/cart",
+            "cart",
+            "Cart of the caller",
+            &[
+                Reply::json(200, "The cart, at the current prices", "Cart"),
+                Reply::error(401, "The access token is invalid"),
+            ],
+        )
+    },
+    Operation {
+        request: Some(Content::Json("CartQuantity")),
+        description: CART_DESCRIPTION,
+        ..Operation::new(
+            "PUT",
+            "/api/v1/cart/items/:product_id",
+            "cart",
+            "Set the quantity of a product in the cart",
+            &[
+                Reply::json(200, "The cart", "Cart"),
+                INVALID_BODY,
+                Reply::error(401, "The access token is invalid"),
+                PRODUCT_NOT_FOUND,
+                INVALID_FIELDS,
+            ],
+        )
+    },
+    Operation::new(
+        "DELETE",
+        "/api/v1/cart/items/:product_id",
+        "cart",
+        "Remove a product from the cart",
+        &[
+            Reply::empty(204, "The product was removed"),
+            Reply::error(400, "The ID is invalid"),
+            Reply::error(401, "The access token is invalid"),
+            Reply::error(404, "The product does not exist or is not in the cart"),
+        ],
+    ),
+    Operation {
+        description: "Places an order of each item at the current price, taking the \
+                      quantities from the stocks, and empties the cart, in a single \
+                      transaction.",
+        ..Operation::new(
+            "POST",
+            "/api/v1/cart/checkout",
+            "cart",
+            "Order the cart",
+            &[
+                Reply {
+                    status: 201,
+                    description: "The orders, by product",
+                    content: Content::JsonArray("Order"),
+                },
+                Reply::error(
+                    409,
+                    "The cart is empty, or a product doesn't have enough stock",
+                ),
+            ],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/api/v1/wishlist",
+        "cart",
+        "Wishlist of the caller",
+        &[Reply {
+            status: 200,
+            description: "The products, the latest added first",
+            content: Content::JsonArray("WishlistItem"),
+        }],
+    ),
+    Operation::new(
+        "PUT",
+        "/api/v1/wishlist/:product_id",
+        "cart",
+        "Add a product to the wishlist",
+        &[
+            Reply::empty(201, "The product was added"),
+            Reply::empty(204, "The product was in the wishlist already"),
+            Reply::error(400, "The ID is invalid"),
+            PRODUCT_NOT_FOUND,
+        ],
+    ),
+    Operation::new(
+        "DELETE",
+        "/api/v1/wishlist/:product_id",
+        "cart",
+        "Remove a product from the wishlist",
+        &[
+            Reply::empty(204, "The product was removed"),
+            Reply::error(400, "The ID is invalid"),
+            Reply::error(404, "The product does not exist or is not in the wishlist"),
+        ],
+    ),
+    // Coupons
+    Operation {
+        request: Some(Content::Json("NewCoupon")),
+        description: "The code is saved in upper case, and typed in any case in the \
+                      `coupon` of `POST /api/v1/users/:id/orders`.",
+        ..Operation::new(
+            "POST",
+            "/api/v1/coupons",
+            "coupons",
+            "Create a coupon",
+            &[
+                Reply::json(201, "The coupon", "Coupon"),
+                Reply::error(400, "The JSON is invalid"),
+                Reply::error(409, "Another coupon has the code"),
+                Reply::error(422, "Some fields are invalid, or `expires_at` is past"),
+            ],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/api/v1/coupons",
+        "coupons",
+        "List the coupons",
+        &[Reply {
+            status: 200,
+            description: "The coupons, the latest first",
+            content: Content::JsonArray("Coupon"),
+        }],
+    ),
+    Operation::new(
+        "DELETE",
+        "/api/v1/coupons/:id",
+        "coupons",
+        "Withdraw a coupon",
+        &[
+            Reply::empty(
+                204,
+                "The coupon can't be redeemed anymore, its redemptions are kept",
+            ),
+            INVALID_ID,
+            COUPON_NOT_FOUND,
+        ],
+    ),
+    Operation {
+        query: &[Param {
+            name: "limit",
+            description: "Number of redemptions (default 20, max 100)",
+            kind: ParamKind::Integer,
+        }],
+        ..Operation::new(
+            "GET",
+            "/api/v1/coupons/:id/redemptions",
+            "coupons",
+            "Latest redemptions of a coupon",
+            &[
+                Reply {
+                    status: 200,
+                    description: "The redemptions, the latest first",
+                    content: Content::JsonArray("CouponRedemption"),
+                },
+                Reply::error(400, "The ID or a query parameter is invalid"),
+                COUPON_NOT_FOUND,
+            ],
+        )
+    },
+    // Search
+    Operation {
+        query: &[
+            Param {
+                name: "q",
+                description: "The text searched, at most 100 characters (required)",
+                kind: ParamKind::String,
+            },
+            Param {
+                name: "limit",
+                description: "Number of users, and of products (default 10, max 50)",
+                kind: ParamKind::Integer,
+            },
+        ],
+        description: "The users and products found by the search engine of \
+                      `SEARCH_ENGINE`, which indexes their changes in the background. \
+                      Without one, or while it's down, their names are searched in the \
+                      database and `engine` is `database`.",
+        ..Operation::new(
+            "GET",
+            "/api/v1/search",
+            "search",
+            "Search users and products",
+            &[
+                Reply::json(
+                    200,
+                    "The users and products found, the best first",
+                    "Search",
+                ),
+                INVALID_QUERY,
+            ],
+        )
+    },
+    Operation {
+        query: &[
+            Param {
+                name: "q",
+                description: "The text typed so far, at most 100 characters (required); \
+                              its last word is completed unless it ends with a space",
+                kind: ParamKind::String,
+            },
+            Param {
+                name: "limit",
+                description: "Number of completions (default 5, max 20)",
+                kind: ParamKind::Integer,
+            },
+        ],
+        description: "Completions of the last word of `q` by the words of the product and \
+                      user names, the most frequent first, and `q` with its misspelled \
+                      words replaced by the closest words of the names, by shared \
+                      trigrams.",
+        ..Operation::new(
+            "GET",
+            "/api/v1/search/suggest",
+            "search",
+            "Suggestions for a search box",
+            &[
+                Reply::json(200, "The suggestions", "Suggestions"),
+                INVALID_QUERY,
+            ],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/api/v1/operations/:id",
+        "operations",
+        "Status and progress of an operation",
+        &[
+            Reply::json(200, "The operation", "Job"),
+            Reply::error(400, "The ID is not an integer"),
+            Reply::error(404, "No operation has this ID"),
+        ],
+    ),
+    // Webhooks
+    Operation {
+        request: Some(Content::Json("WebhookSettings")),
+        description: WEBHOOK_DESCRIPTION,
+        ..Operation::new(
+            "POST",
+            "/api/v1/webhooks",
+            "webhooks",
+            "Register a webhook",
+            &[
+                Reply::json(201, "The webhook", "Webhook"),
+                Reply::error(400, "The JSON is invalid"),
+                INVALID_FIELDS,
+            ],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/api/v1/webhooks",
+        "webhooks",
+        "Webhooks of the caller",
+        &[Reply {
+            status: 200,
+            description: "The webhooks of the caller, the oldest first",
+            content: Content::JsonArray("Webhook"),
+        }],
+    ),
+    Operation::new(
+        "GET",
+        "/api/v1/webhooks/:id",
+        "webhooks",
+        "Get a webhook",
+        &[
+            Reply::json(200, "The webhook", "Webhook"),
+            INVALID_ID,
+            WEBHOOK_NOT_FOUND,
+        ],
+    ),
+    Operation {
+        request: Some(Content::Json("WebhookSettings")),
+        ..Operation::new(
+            "PUT",
+            "/api/v1/webhooks/:id",
+            "webhooks",
+            "Replace the URL, secret and events of a webhook",
+            &[
+                Reply::json(200, "The updated webhook", "Webhook"),
+                INVALID_BODY,
+                WEBHOOK_NOT_FOUND,
+                INVALID_FIELDS,
+            ],
+        )
+    },
+    Operation::new(
+        "DELETE",
+        "/api/v1/webhooks/:id",
+        "webhooks",
+        "Delete a webhook and its deliveries",
+        &[
+            Reply::empty(204, "The webhook was deleted"),
+            INVALID_ID,
+            WEBHOOK_NOT_FOUND,
+        ],
+    ),
+    Operation {
+        query: &[Param {
+            name: "limit",
+            description: "Number of attempts (default 20, max 100)",
+            kind: ParamKind::Integer,
+        }],
+        ..Operation::new(
+            "GET",
+            "/api/v1/webhooks/:id/deliveries",
+            "webhooks",
+            "Latest delivery attempts of a webhook",
+            &[
+                Reply {
+                    status: 200,
+                    description: "The attempts, the latest first",
+                    content: Content::JsonArray("Delivery"),
+                },
+                Reply::error(400, "The ID or a query parameter is invalid"),
+                WEBHOOK_NOT_FOUND,
+            ],
+        )
+    },
+    // Change feeds
+    Operation {
+        query: &[
+            Param {
+                name: "from",
+                description: "Position after which the changes start (default `0-0`, the \
+                              oldest change), the `next` of the previous page",
+                kind: ParamKind::String,
+            },
+            Param {
+                name: "consumer",
+                description: "Start after the checkpoint of this consumer instead",
+                kind: ParamKind::String,
+            },
+            Param {
+                name: "limit",
+                description: "Number of changes (default 100, max 1000)",
+                kind: ParamKind::Integer,
+            },
+        ],
+        description: CDC_DESCRIPTION,
+        ..Operation::new(
+            "GET",
+            "/api/v1/cdc/:table",
+            "cdc",
+            "Changes of a table after a position",
+            &[
+                Reply::json(200, "The changes, in the order of the feed", "ChangePage"),
+                Reply::error(400, "A query parameter is invalid"),
+                FEED_NOT_FOUND,
+            ],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/api/v1/cdc/:table/checkpoints/:consumer",
+        "cdc",
+        "Saved position of a consumer",
+        &[
+            Reply::json(200, "The checkpoint", "Checkpoint"),
+            Reply::error(400, "The consumer name is invalid"),
+            Reply::error(404, "The table has no feed, or the consumer no checkpoint"),
+        ],
+    ),
+    Operation {
+        request: Some(Content::Json("CheckpointPosition")),
+        ..Operation::new(
+            "PUT",
+            "/api/v1/cdc/:table/checkpoints/:consumer",
+            "cdc",
+            "Save the position of a consumer",
+            &[
+                Reply::json(200, "The checkpoint", "Checkpoint"),
+                Reply::error(400, "The JSON or the consumer name is invalid"),
+                FEED_NOT_FOUND,
+                Reply::error(422, "The position is invalid"),
+            ],
+        )
+    },
+    // Change notifications
+    Operation {
+        description: "One text message per change: `{\"type\": \"users.created\", \
+                      \"version\": 1, \"data\": {\"id\"}}`. A client that falls behind \
+                      receives `{\"lagged\": <number of lost events>}`.",
+        ..Operation::new(
+            "GET",
+            "/api/v1/ws",
+            "events",
+            "WebSocket of the user and product changes",
+            &[
+                Reply::empty(101, "Switched to the WebSocket protocol"),
+                Reply::error(400, "The request is not a WebSocket handshake"),
+            ],
+        )
+    },
+    Operation {
+        description: "Every change is sent as `event: change` with `data: {\"type\", \
+                      \"version\", \"data\"}`, a client that falls behind receives \
+                      `event: lagged` with the number of lost events.",
+        ..Operation::new(
+            "GET",
+            "/api/v1/events",
+            "events",
+            "Server-Sent Events of the user and product changes",
+            &[Reply::other(
+                200,
+                "A stream that stays open",
+                "text/event-stream",
+            )],
+        )
+    },
+    Operation::new(
+        "GET",
+        "/api/v1/events/schemas",
+        "events",
+        "Schemas of the event payloads",
+        &[Reply {
+            status: 200,
+            description: "Every version of every event type",
+            content: Content::JsonArray("EventSchema"),
+        }],
+    ),
+    Operation {
+        description: "Read-only routes an LLM agent can call through `POST \
+                      /api/v1/tools/{name}/call`, each with the JSON Schema of its arguments, \
+                      derived from this document.",
+        ..Operation::new(
+            "GET",
+            "/api/v1/tools",
+            "tools",
+            "Tools for LLM agents",
+            &[Reply::json(200, "The tools", "ToolManifest")],
+        )
+    },
+    Operation {
+        request: Some(Content::Json("ToolCall")),
+        description: "Calls the route of the tool with the arguments and the access token of \
+                      the caller. Errors of the route are answered as is. Each user can call \
+                      `TOOLS_RATE_LIMIT_PER_MINUTE` tools per minute.",
+        ..Operation::new(
+            "POST",
+            "/api/v1/tools/:name/call",
+            "tools",
+            "Call a tool",
+            &[
+                Reply::json(200, "The body of the route", "ToolResult"),
+                Reply::error(400, "The JSON is invalid"),
+                Reply::error(404, "No tool has this name, or the route found nothing"),
+                Reply::error(
+                    422,
+                    "An argument is missing, unknown or doesn't match its schema",
+                ),
+                Reply::error(429, "The caller exceeded `TOOLS_RATE_LIMIT_PER_MINUTE`"),
+            ],
+        )
+    },
+];
+
+/// Builds the OpenAPI document of the routes of `router`.
+pub fn document(router: &Router) -> Value {
+    let mut schemas = schemas();
+    add_computed_fields(&mut schemas);
+    let mut paths = Map::new();
+    for route in router.routes() {
+        let operation = OPERATIONS
+            .iter()
+            .find(|op| op.method == route.method.as_str() && op.path == route.pattern);
+        let item = paths
+            .entry(openapi_path(route.pattern))
+            .or_insert_with(|| json!({}));
+        item[route.method.as_str().to_lowercase()] = describe(&route, operation);
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "REST API of users, products and orders. Errors share the `Error` \
+                            body, its `code` is stable and meant for programs. Successful \
+                            writes may answer with an `X-Consistency-Token` header; sending \
+                            it back on the next reads makes them see the write.",
+        },
+        "tags": [
+            {"name": "auth", "description": "Accounts and access tokens"},
+            {"name": "oidc", "description": "OpenID Connect provider of the internal apps"},
+            {"name": "scim", "description": "SCIM 2.0 provisioning of the accounts by an \
+                                              identity provider"},
+            {"name": "dashboard", "description": "Overview composed of independent parts"},
+            {"name": "experiments", "description": "A/B experiments and their variants"},
+            {"name": "users"},
+            {"name": "products"},
+            {"name": "orders"},
+            {"name": "cart", "description": "Carts, anonymous or not, and wishlists"},
+            {"name": "coupons", "description": "Discounts redeemed by the orders"},
+            {"name": "search", "description": "Search of the users and products, and \
+                                                suggestions for search boxes"},
+            {"name": "events", "description": "Notifications of the changes"},
+            {"name": "webhooks", "description": "Changes POSTed to the URLs of the users"},
+            {"name": "cdc", "description": "Ordered feeds of the changes, for the ETL jobs"},
+            {"name": "tools", "description": "Read-only routes for LLM agents"},
+            {"name": "operations", "description": "Probes, metrics, diagnostics and background \
+                                                    operations"},
+        ],
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                BEARER_AUTH: {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "bearerFormat": "JWT",
+                    "description": "Token obtained from `POST /api/v1/auth/login`",
+                },
+                SCIM_AUTH: {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "`SCIM_TOKEN`, given to the identity provider",
+                },
+                SIGNED_URL: {
+                    "type": "apiKey",
+                    "in": "query",
+                    "name": "signature",
+                    "description": "URL signed by `POST /api/v1/signed-urls`, with its \
+                                    `expires` and `sub` parameters",
+                },
+            },
+        },
+    })
+}
+
+/// `/users/{id}` for the route pattern `/users/:id` (`/static/{path}` for `/static/*path`).
+pub(super) fn openapi_path(pattern: &str) -> String {
+    pattern
+        .split('/')
+        .map(|segment| match segment.strip_prefix([':', '*']) {
+            Some(name) => format!("{{{}}}", name),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The Operation Object of a route.
+fn describe(route: &RouteInfo, operation: Option<&Operation>) -> Value {
+    let mut parameters = route
+        .pattern
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix([':', '*']))
+        // Every path parameter is the UUID of a user or product, but the IDs of an
+        // operation (a job) and of an order, the names of the change feeds, console
+        // queries and tools, and the path of a static file
+        .map(|name| {
+            let schema = if route.pattern.starts_with("/api/v1/operations/") {
+                json!({"type": "integer", "format": "int64"})
+            } else if name == "order_id" {
+                json!({"type": "integer", "format": "int32"})
+            } else if name == "table" {
+                json!({"type": "string", "enum": ["users", "products", "orders"]})
+            } else if name == "consumer" {
+                json!({"type": "string", "example": "warehouse"})
+            } else if name == "name" && route.pattern.starts_with("/api/v1/tools/") {
+                json!({"type": "string", "example": "get_user"})
+            } else if name == "name" {
+                json!({"type": "string", "example": "orders-of-user"})
+            } else if name == "path" {
+                json!({"type": "string", "example": "assets/app.js"})
+            } else {
+                json!({"type": "string", "format": "uuid"})
+            };
+            json!({"name": name, "in": "path", "required": true, "schema": schema})
+        })
+        .collect::<Vec<_>>();
+
+    let mut responses = Map::new();
+    let mut object = Map::new();
+    match operation {
+        Some(operation) => {
+            object.insert("tags".into(), json!([operation.tag]));
+            object.insert("summary".into(), operation.summary.into());
+            if !operation.description.is_empty() {
+                object.insert("description".into(), operation.description.into());
+            }
+            // The lists are the filterable operations
+            if !operation.filters.is_empty() {
+                parameters.extend(PAGINATION.iter().map(param));
+            }
+            parameters.extend(operation.query.iter().map(param));
+            if !operation.filters.is_empty() {
+                parameters.extend(filter_params(operation.filters));
+            }
+            if operation.if_match {
+                parameters.push(json!({
+                    "name": "If-Match",
+                    "in": "header",
+                    "required": true,
+                    "description": "`ETag` of the entity as the client read it, or `*` to \
+                                    apply the write whatever its version",
+                    "schema": {"type": "string"},
+                }));
+            }
+            if let Some(request) = operation.request {
+                object.insert(
+                    "requestBody".into(),
+                    json!({"required": true, "content": content(request)}),
+                );
+            }
+            for reply in operation.responses {
+                let mut response = json!({"description": reply.description});
+                if !matches!(reply.content, Content::Empty) {
+                    response["content"] = content(reply.content);
+                }
+                responses.insert(reply.status.to_string(), response);
+            }
+        }
+        None => {
+            responses.insert("default".into(), json!({"description": "Not documented"}));
+        }
+    }
+
+    if route.accepts_signed_urls {
+        let security = if route.requires_auth {
+            json!([{ BEARER_AUTH: [] }, { SIGNED_URL: [] }])
+        } else {
+            json!([{}, { SIGNED_URL: [] }])
+        };
+        object.insert("security".into(), security);
+        responses.insert(
+            "401".into(),
+            json!({
+                "description": "The access token or the signed URL is missing, invalid or \
+                                expired",
+                "content": content(Content::Json("Error")),
+            }),
+        );
+    } else if route.requires_auth {
+        object.insert("security".into(), json!([{ BEARER_AUTH: [] }]));
+        responses.insert(
+            "401".into(),
+            json!({
+                "description": "The access token is missing, invalid or expired",
+                "content": content(Content::Json("Error")),
+            }),
+        );
+    } else if route.pattern == "/oauth/userinfo" {
+        // Authenticated by the handler, with the client tokens of the apps
+        object.insert("security".into(), json!([{ BEARER_AUTH: [] }]));
+    } else if route.pattern.starts_with("/scim/") {
+        object.insert("security".into(), json!([{ SCIM_AUTH: [] }]));
+    }
+    if let Some(role) = route.required_role {
+        responses.insert(
+            "403".into(),
+            json!({
+                "description": format!(
+                    "The caller doesn't have the {} role (`INSUFFICIENT_ROLE`)",
+                    role
+                ),
+                "content": content(Content::Json("Error")),
+            }),
+        );
+    }
+    if route.queues_when_offline {
+        parameters.push(json!({
+            "name": "Idempotency-Key",
+            "in": "header",
+            "description": "Key of the write, unique for the caller: while the database is \
+                            down, the write is queued and applied once it's back \
+                            (`OFFLINE_QUEUE_PATH`)",
+            "schema": {"type": "string", "maxLength": 255},
+        }));
+        responses.insert(
+            "202".into(),
+            json!({
+                "description": "The database is down, the write is queued (or was already, \
+                                with this key) and will be applied once it's back",
+                "content": content(Content::Json("QueuedWrite")),
+            }),
+        );
+    }
+    if !parameters.is_empty() {
+        object.insert("parameters".into(), parameters.into());
+    }
+    object.insert("responses".into(), responses.into());
+    object.into()
+}
+
+fn param(param: &Param) -> Value {
+    let schema = match param.kind {
+        ParamKind::Integer => json!({"type": "integer"}),
+        ParamKind::Boolean => json!({"type": "boolean"}),
+        ParamKind::String => json!({"type": "string"}),
+        ParamKind::Enum(values) => json!({"type": "string", "enum": values}),
+    };
+    json!({
+        "name": param.name,
+        "in": "query",
+        "description": param.description,
+        "schema": schema,
+    })
+}
+
+/// The filters as a free-form object, sent as one query parameter per property
+/// (`?name_like=ann&age_gte=18`), and the `filter` expression.
+fn filter_params(fields: &[FilterField]) -> [Value; 2] {
+    let fields = fields
+        .iter()
+        .map(|field| {
+            let kind = match field.field_type {
+                FieldType::Text => "text",
+                FieldType::Integer => "integer",
+                FieldType::Float => "number",
+                FieldType::Uuid => "UUID",
+            };
+            format!("`{}` ({})", field.name, kind)
+        })
+        .collect::<Vec<_>>();
+    let fields = fields.join(", ");
+    [
+        json!({
+            "name": "filters",
+            "in": "query",
+            "style": "form",
+            "explode": true,
+            "description": format!(
+                "`<field>_<operator>=<value>`, or `<field>=<value>` for `eq`, on {}. Operators: \
+                 `eq`, `ne` on every field, `gt`, `gte`, `lt`, `lte` on numbers, `like` on text \
+                 (contains, case insensitive).",
+                fields
+            ),
+            "schema": {"type": "object", "additionalProperties": {"type": "string"}},
+            "example": {"name_like": "ann"},
+        }),
+        json!({
+            "name": "filter",
+            "in": "query",
+            "description": format!(
+                "Expression comparing {} with `=`, `!=`, `>`, `>=`, `<`, `<=` (numbers) and \
+                 `~` (text contains, case insensitive), combined with `AND`, `OR`, `NOT` and \
+                 parentheses. Text values with spaces are quoted. At most 20 comparisons.",
+                fields
+            ),
+            "schema": {"type": "string"},
+            "example": "id>=10 AND name~\"an\"",
+        }),
+    ]
+}
+
+/// The Content map of a body.
+fn content(content: Content) -> Value {
+    let reference = |schema: &str| json!({"$ref": format!("#/components/schemas/{}", schema)});
+    match content {
+        Content::Json(schema) => json!({"application/json": {"schema": reference(schema)}}),
+        Content::JsonArray(schema) => json!({
+            "application/json": {"schema": {"type": "array", "items": reference(schema)}},
+        }),
+        Content::OneOf(first, second) => json!({
+            "application/json": {"schema": {"oneOf": [reference(first), reference(second)]}},
+        }),
+        Content::Scim(schema) => json!({"application/scim+json": {"schema": reference(schema)}}),
+        Content::Other(media_type) => {
+            json!({media_type: {"schema": {"type": "string", "format": "binary"}}})
+        }
+        Content::Upload(field) => json!({
+            "multipart/form-data": {
+                "schema": {
+                    "type": "object",
+                    "required": [field],
+                    "properties": {field: {"type": "string", "format": "binary"}},
+                },
+            },
+        }),
+        Content::Empty => json!({}),
+    }
+}
+
+/// Adds the computed fields of `COMPUTED_FIELDS_PATH` to the schemas of their resources.
+fn add_computed_fields(schemas: &mut Value) {
+    let resources = [
+        ("User", Resource::Users),
+        ("Product", Resource::Products),
+        ("Order", Resource::Orders),
+    ];
+    for (schema, resource) in resources {
+        for (name, source) in computed::fields(resource) {
+            schemas[schema]["properties"][name] = json!({
+                "readOnly": true,
+                "description": format!("Computed: `{}`", source),
+            });
+        }
+    }
+}
+
+/// Schemas of the request and response bodies.
+fn schemas() -> Value {
+    let id = json!({"type": "integer", "format": "int32", "readOnly": true});
+    let public_id = json!({"type": "string", "format": "uuid", "readOnly": true});
+    let name = json!({"type": "string", "minLength": 1, "maxLength": MAX_NAME_LEN});
+    let age = json!({"type": "integer", "format": "int32", "minimum": 0, "maximum": 150});
+    let price = json!({"type": "number", "format": "double", "minimum": 0});
+    let count = json!({"type": "integer", "format": "int32", "minimum": 0});
+    let page = |item: &str| {
+        json!({
+            "type": "object",
+            "required": ["data", "pagination"],
+            "properties": {
+                "data": {"type": "array", "items": {"$ref": format!("#/components/schemas/{}", item)}},
+                "pagination": {"$ref": "#/components/schemas/Pagination"},
+            },
+        })
+    };
+
+    json!({
+        "User": {
+            "type": "object",
+            "required": ["name", "age"],
+            "properties": {
+                "id": public_id,
+                "name": name,
+                "age": age,
+                "deleted_at": {
+                    "type": "string",
+                    "format": "date-time",
+                    "readOnly": true,
+                    "description": "When the user was soft-deleted, only sent for them",
+                },
+            },
+        },
+        "UserPatch": {
+            "type": "object",
+            "description": "Missing fields keep their current value",
+            "properties": {"name": name, "age": age},
+        },
+        "UserPage": page("User"),
+        "UserRole": {
+            "type": "object",
+            "required": ["role"],
+            "properties": {
+                "id": {"type": "string", "format": "uuid", "readOnly": true},
+                "role": {"type": "string", "enum": ["viewer", "editor", "admin"]},
+            },
+        },
+        "UserChanges": {
+            "type": "object",
+            "required": ["data", "deleted", "version"],
+            "properties": {
+                "data": {
+                    "type": "array",
+                    "description": "Created or updated users, oldest change first",
+                    "items": {
+                        "type": "object",
+                        "required": ["id", "name", "age"],
+                        "properties": {"id": public_id, "name": name, "age": age},
+                    },
+                },
+                "deleted": {
+                    "type": "array",
+                    "description": "IDs of the deleted users",
+                    "items": {"type": "string", "format": "uuid"},
+                },
+                "version": {
+                    "type": "integer",
+                    "format": "int64",
+                    "description": "Version to send as `modified_since` on the next poll",
+                },
+            },
+        },
+        "Profile": {
+            "type": "object",
+            "required": ["id", "name", "age", "email"],
+            "properties": {
+                "id": public_id,
+                "name": name,
+                "age": age,
+                "email": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "`null` for users created without an account",
+                },
+            },
+        },
+        "Avatar": {
+            "type": "object",
+            "required": ["avatar_url"],
+            "properties": {"avatar_url": {"type": "string"}},
+        },
+        "Product": {
+            "type": "object",
+            "required": ["id", "name", "price", "stock"],
+            "properties": {"id": public_id, "name": name, "price": price, "stock": count},
+        },
+        "NewProduct": {
+            "type": "object",
+            "required": ["name", "price", "stock"],
+            "properties": {"name": name, "price": price, "stock": count},
+        },
+        "ProductPage": page("Product"),
+        "Search": {
+            "type": "object",
+            "required": ["users", "products", "engine"],
+            "properties": {
+                "users": {"type": "array", "items": {"$ref": "#/components/schemas/User"}},
+                "products": {"type": "array", "items": {"$ref": "#/components/schemas/Product"}},
+                "engine": {
+                    "type": "string",
+                    "enum": ["meilisearch", "elasticsearch", "database"],
+                    "description": "What searched: the search engine, or the database without one or while it's down",
+                },
+            },
+        },
+        "Suggestions": {
+            "type": "object",
+            "required": ["completions", "did_you_mean"],
+            "properties": {
+                "completions": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["text", "frequency"],
+                        "properties": {
+                            "text": {
+                                "type": "string",
+                                "description": "The query, its last word completed",
+                                "example": "espresso machine",
+                            },
+                            "frequency": {
+                                "type": "integer",
+                                "description": "Names having the completed word",
+                                "example": 12,
+                            },
+                        },
+                    },
+                },
+                "did_you_mean": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "The query with its misspelled words corrected, null if none is",
+                    "example": "espresso mach",
+                },
+            },
+        },
+        "Recommendations": {
+            "type": "object",
+            "required": ["data", "computed_at"],
+            "properties": {
+                "data": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["product", "reason", "score"],
+                        "properties": {
+                            "product": {"$ref": "#/components/schemas/Product"},
+                            "reason": {
+                                "type": "string",
+                                "enum": ["co_purchase", "popular"],
+                                "description": "Why the product is recommended",
+                            },
+                            "score": {
+                                "type": "number",
+                                "description": "Users who bought it and a product of the user (`co_purchase`), or units ordered in the last 90 days (`popular`)",
+                                "example": 3.0,
+                            },
+                        },
+                    },
+                },
+                "computed_at": {
+                    "type": "string",
+                    "format": "date-time",
+                    "description": "When the recommendations were computed",
+                },
+            },
+        },
+        "ProductSearch": {
+            "type": "object",
+            "required": ["data", "semantic"],
+            "properties": {
+                "data": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["product", "score", "similarity", "keyword"],
+                        "properties": {
+                            "product": {"$ref": "#/components/schemas/Product"},
+                            "score": {
+                                "type": "number",
+                                "description": "Reciprocal rank fusion of the ranks of the product",
+                                "example": 0.0325,
+                            },
+                            "similarity": {
+                                "type": "number",
+                                "nullable": true,
+                                "description": "Cosine similarity to `q`, null when the product isn't among the nearest",
+                                "example": 0.82,
+                            },
+                            "keyword": {
+                                "type": "boolean",
+                                "description": "Whether the name contains words of `q`",
+                            },
+                        },
+                    },
+                },
+                "semantic": {
+                    "type": "boolean",
+                    "description": "Whether the embeddings were searched",
+                },
+            },
+        },
+        "Order": {
+            "type": "object",
+            "required": ["id", "user_id", "product_id", "quantity", "unit_price", "discount", "coupon"],
+            "properties": {
+                "id": id,
+                "user_id": {"type": "string", "format": "uuid"},
+                "product_id": {"type": "string", "format": "uuid"},
+                "quantity": {"type": "integer", "format": "int32"},
+                "unit_price": {
+                    "type": "number",
+                    "format": "double",
+                    "description": "Price of the product when the order was placed",
+                },
+                "discount": {
+                    "type": "number",
+                    "format": "double",
+                    "description": "Taken off `quantity * unit_price` by the coupon",
+                },
+                "coupon": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "Code of the coupon redeemed",
+                },
+            },
+        },
+        "LedgerEntry": {
+            "type": "object",
+            "required": ["id", "kind", "description", "order_id", "amount", "balance", "created_at"],
+            "properties": {
+                "id": {"type": "string", "format": "uuid"},
+                "kind": {"type": "string", "enum": ["credit", "refund"]},
+                "description": {"type": "string"},
+                "order_id": {
+                    "type": "integer",
+                    "format": "int32",
+                    "nullable": true,
+                    "description": "The order refunded",
+                },
+                "amount": {
+                    "type": "integer",
+                    "format": "int64",
+                    "description": "Change of the credit, in cents",
+                },
+                "balance": {
+                    "type": "integer",
+                    "format": "int64",
+                    "description": "Credit of the user after the entry, in cents",
+                },
+                "created_at": {"type": "string", "format": "date-time"},
+            },
+        },
+        "Balance": {
+            "type": "object",
+            "required": ["balance", "entries"],
+            "properties": {
+                "balance": {"type": "integer", "format": "int64", "description": "In cents"},
+                "entries": {"type": "array", "items": {"$ref": "#/components/schemas/LedgerEntry"}},
+            },
+        },
+        "GrantCredit": {
+            "type": "object",
+            "required": ["amount", "description"],
+            "properties": {
+                "amount": {
+                    "type": "integer",
+                    "format": "int64",
+                    "minimum": -1_000_000,
+                    "maximum": 1_000_000,
+                    "description": "In cents, negative to take credit back, not 0",
+                },
+                "description": {"type": "string", "minLength": 1, "maxLength": 200},
+            },
+        },
+        "LedgerReport": {
+            "type": "object",
+            "required": [
+                "accounts",
+                "entries",
+                "unbalanced_entries",
+                "drifted_accounts",
+                "trial_balance",
+                "consistent",
+            ],
+            "properties": {
+                "accounts": {"type": "integer", "format": "int64"},
+                "entries": {"type": "integer", "format": "int64"},
+                "unbalanced_entries": {
+                    "type": "array",
+                    "items": {"type": "string", "format": "uuid"},
+                    "description": "Entries whose postings don't sum to zero (100 at most)",
+                },
+                "drifted_accounts": {
+                    "type": "array",
+                    "description": "Accounts whose balance isn't the sum of their postings \
+                                    (100 at most)",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "account": {"type": "string"},
+                            "balance": {"type": "integer", "format": "int64"},
+                            "expected": {"type": "integer", "format": "int64"},
+                        },
+                    },
+                },
+                "trial_balance": {
+                    "type": "integer",
+                    "format": "int64",
+                    "description": "Sum of the balances, 0 when consistent",
+                },
+                "consistent": {"type": "boolean"},
+            },
+        },
+        "NewOrder": {
+            "type": "object",
+            "required": ["product_id", "quantity"],
+            "properties": {
+                "product_id": {"type": "string", "format": "uuid"},
+                "quantity": {"type": "integer", "format": "int32", "minimum": 1},
+                "coupon": {
+                    "type": "string",
+                    "description": "Code of a coupon to redeem, in any case",
+                    "example": "SPRING10",
+                },
+            },
+        },
+        "Cart": {
+            "type": "object",
+            "required": ["items", "total"],
+            "properties": {
+                "items": {
+                    "type": "array",
+                    "description": "In the order they were added",
+                    "items": {
+                        "type": "object",
+                        "required": ["product_id", "name", "unit_price", "quantity", "subtotal", "in_stock"],
+                        "properties": {
+                            "product_id": {"type": "string", "format": "uuid"},
+                            "name": name,
+                            "unit_price": {"type": "number", "format": "double"},
+                            "quantity": {"type": "integer", "format": "int32"},
+                            "subtotal": {"type": "number", "format": "double"},
+                            "in_stock": {
+                                "type": "boolean",
+                                "description": "Whether the stock has the quantity now, checked again by the checkout",
+                            },
+                        },
+                    },
+                },
+                "total": {"type": "number", "format": "double"},
+            },
+        },
+        "CartQuantity": {
+            "type": "object",
+            "required": ["quantity"],
+            "properties": {
+                "quantity": {"type": "integer", "format": "int32", "minimum": 1, "maximum": 100},
+            },
+        },
+        "WishlistItem": {
+            "type": "object",
+            "required": ["product_id", "name", "price", "stock", "added_at"],
+            "properties": {
+                "product_id": {"type": "string", "format": "uuid"},
+                "name": name,
+                "price": {"type": "number", "format": "double"},
+                "stock": {"type": "integer", "format": "int32"},
+                "added_at": {"type": "string", "format": "date-time"},
+            },
+        },
+        "QueuedWrite": {
+            "type": "object",
+            "required": ["id", "status"],
+            "properties": {
+                "id": {"type": "string", "format": "uuid"},
+                "status": {"type": "string", "enum": ["queued"]},
+            },
+        },
+        "Coupon": {
+            "type": "object",
+            "required": [
+                "id", "code", "percent_off", "amount_off", "min_order_amount", "expires_at",
+                "max_redemptions", "max_per_user", "redemptions", "active", "created_at",
+            ],
+            "properties": {
+                "id": {"type": "string", "format": "uuid"},
+                "code": {"type": "string", "example": "SPRING10"},
+                "percent_off": {"type": "integer", "format": "int32", "nullable": true},
+                "amount_off": {
+                    "type": "integer",
+                    "format": "int64",
+                    "nullable": true,
+                    "description": "In cents",
+                },
+                "min_order_amount": {"type": "integer", "format": "int64", "description": "In cents"},
+                "expires_at": {"type": "string", "format": "date-time", "nullable": true},
+                "max_redemptions": {"type": "integer", "format": "int32", "nullable": true},
+                "max_per_user": {"type": "integer", "format": "int32", "nullable": true},
+                "redemptions": {"type": "integer", "format": "int32"},
+                "active": {"type": "boolean", "description": "False once withdrawn"},
+                "created_at": {"type": "string", "format": "date-time"},
+            },
+        },
+        "NewCoupon": {
+            "type": "object",
+            "required": ["code"],
+            "description": "Either `percent_off` or `amount_off`",
+            "properties": {
+                "code": {
+                    "type": "string",
+                    "pattern": "^[A-Za-z0-9_-]{3,32}$",
+                    "example": "SPRING10",
+                },
+                "percent_off": {"type": "integer", "format": "int32", "minimum": 1, "maximum": 100},
+                "amount_off": {
+                    "type": "integer",
+                    "format": "int64",
+                    "minimum": 1,
+                    "maximum": 1_000_000,
+                    "description": "In cents, at most the subtotal of the order",
+                },
+                "min_order_amount": {
+                    "type": "integer",
+                    "format": "int64",
+                    "minimum": 0,
+                    "description": "Subtotal an order needs, in cents (default 0)",
+                },
+                "expires_at": {"type": "string", "format": "date-time"},
+                "max_redemptions": {
+                    "type": "integer",
+                    "format": "int32",
+                    "minimum": 1,
+                    "description": "Redemptions in all (no limit by default)",
+                },
+                "max_per_user": {
+                    "type": "integer",
+                    "format": "int32",
+                    "minimum": 1,
+                    "description": "Redemptions by each user (no limit by default)",
+                },
+            },
+        },
+        "CouponRedemption": {
+            "type": "object",
+            "required": ["user_id", "order_id", "discount", "created_at"],
+            "properties": {
+                "user_id": {
+                    "type": "string",
+                    "format": "uuid",
+                    "nullable": true,
+                    "description": "Null once the user is deleted",
+                },
+                "order_id": id,
+                "discount": {"type": "integer", "format": "int64", "description": "In cents"},
+                "created_at": {"type": "string", "format": "date-time"},
+            },
+        },
+        "Registration": {
+            "type": "object",
+            "required": ["name", "age", "email", "password"],
+            "properties": {
+                "name": name,
+                "age": age,
+                "email": {"type": "string", "format": "email"},
+                "password": {
+                    "type": "string",
+                    "format": "password",
+                    "minLength": MIN_PASSWORD_LEN,
+                },
+            },
+        },
+        "Login": {
+            "type": "object",
+            "required": ["email", "password"],
+            "properties": {
+                "email": {"type": "string", "format": "email"},
+                "password": {"type": "string", "format": "password"},
+            },
+        },
+        "Token": {
+            "type": "object",
+            "required": ["access_token", "token_type", "expires_in"],
+            "properties": {
+                "access_token": {"type": "string"},
+                "token_type": {"type": "string", "enum": ["Bearer"]},
+                "expires_in": {"type": "integer", "description": "Lifetime in seconds"},
+            },
+        },
+        "PasskeyChallenge": {
+            "type": "object",
+            "required": ["challenge_id", "publicKey"],
+            "properties": {
+                "challenge_id": {"type": "string", "format": "uuid"},
+                "publicKey": {
+                    "type": "object",
+                    "description": "Options of the WebAuthn ceremony, binary values in \
+                                    base64url",
+                },
+            },
+        },
+        "SecondFactorChallenge": {
+            "type": "object",
+            "required": ["second_factor", "challenge_id", "publicKey"],
+            "properties": {
+                "second_factor": {"type": "string", "enum": ["passkey"]},
+                "challenge_id": {"type": "string", "format": "uuid"},
+                "publicKey": {
+                    "type": "object",
+                    "description": "Options of `navigator.credentials.get()`, allowing the \
+                                    passkeys of the user",
+                },
+            },
+        },
+        "PasskeyRegistration": {
+            "type": "object",
+            "required": ["challenge_id", "credential"],
+            "properties": {
+                "challenge_id": {"type": "string", "format": "uuid"},
+                "name": {"type": "string", "maxLength": MAX_NAME_LEN, "default": "Passkey"},
+                "credential": {
+                    "type": "object",
+                    "description": "`toJSON()` of the `PublicKeyCredential` created",
+                    "required": ["id", "response"],
+                },
+            },
+        },
+        "PasskeyLogin": {
+            "type": "object",
+            "required": ["challenge_id", "credential"],
+            "properties": {
+                "challenge_id": {"type": "string", "format": "uuid"},
+                "credential": {
+                    "type": "object",
+                    "description": "`toJSON()` of the `PublicKeyCredential` returned by \
+                                    `navigator.credentials.get()`",
+                    "required": ["id", "response"],
+                },
+            },
+        },
+        "Passkey": {
+            "type": "object",
+            "required": ["id", "name", "created_at", "last_used_at"],
+            "properties": {
+                "id": {"type": "string", "format": "uuid"},
+                "name": {"type": "string"},
+                "created_at": {"type": "string", "format": "date-time"},
+                "last_used_at": {
+                    "type": "string",
+                    "format": "date-time",
+                    "nullable": true,
+                    "description": "`null` until the first login with it",
+                },
+            },
+        },
+        "SignUrl": {
+            "type": "object",
+            "required": ["path"],
+            "properties": {
+                "path": {
+                    "type": "string",
+                    "description": "Path and query of a route accepting signed URLs \
+                                    (`/admin/export/parquet?table=users`)",
+                },
+                "expires_in": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "default": 900,
+                    "description": "Lifetime in seconds, at most `SIGNED_URL_MAX_LIFETIME`",
+                },
+            },
+        },
+        "SignedUrl": {
+            "type": "object",
+            "required": ["url", "expires"],
+            "properties": {
+                "url": {"type": "string"},
+                "expires": {"type": "integer", "description": "Seconds since the Unix epoch"},
+            },
+        },
+        "SecondFactor": {
+            "type": "object",
+            "required": ["passkey"],
+            "properties": {
+                "passkey": {
+                    "type": "boolean",
+                    "description": "Whether the password logins need a passkey too",
+                },
+            },
+        },
+        "OidcTokens": {
+            "type": "object",
+            "required": ["access_token", "token_type", "expires_in", "id_token", "scope"],
+            "properties": {
+                "access_token": {"type": "string", "description": "A token of this API"},
+                "token_type": {"type": "string", "enum": ["Bearer"]},
+                "expires_in": {"type": "integer", "description": "Lifetime in seconds"},
+                "id_token": {
+                    "type": "string",
+                    "description": "JWT signed with ES256, its `sub` the ID of the user",
+                },
+                "scope": {"type": "string", "example": "openid email"},
+            },
+        },
+        "OAuthError": {
+            "type": "object",
+            "required": ["error", "error_description"],
+            "properties": {
+                "error": {"type": "string", "example": "invalid_grant"},
+                "error_description": {"type": "string"},
+            },
+        },
+        "ScimUser": {
+            "type": "object",
+            "required": ["userName"],
+            "properties": {
+                "schemas": {"type": "array", "items": {"type": "string"}},
+                "id": public_id,
+                "externalId": {"type": "string", "description": "ID in the identity provider"},
+                "userName": {"type": "string", "format": "email"},
+                "name": {
+                    "type": "object",
+                    "properties": {
+                        "formatted": {"type": "string"},
+                        "givenName": {"type": "string"},
+                        "familyName": {"type": "string"},
+                    },
+                },
+                "displayName": name,
+                "emails": {
+                    "type": "array",
+                    "readOnly": true,
+                    "description": "The `userName`",
+                    "items": {"type": "object", "properties": {"value": {"type": "string"}}},
+                },
+                "active": {"type": "boolean", "description": "Inactive accounts can't log in"},
+                "password": {
+                    "type": "string",
+                    "writeOnly": true,
+                    "minLength": MIN_PASSWORD_LEN,
+                },
+                (scim::EXTENSION_SCHEMA): {
+                    "type": "object",
+                    "properties": {"age": age},
+                },
+                "meta": {
+                    "type": "object",
+                    "readOnly": true,
+                    "properties": {
+                        "resourceType": {"type": "string", "enum": ["User"]},
+                        "lastModified": {"type": "string", "format": "date-time"},
+                        "location": {"type": "string"},
+                        "version": {"type": "string", "description": "The `ETag`"},
+                    },
+                },
+            },
+        },
+        "ScimListResponse": {
+            "type": "object",
+            "required": ["schemas", "totalResults", "startIndex", "itemsPerPage", "Resources"],
+            "properties": {
+                "schemas": {"type": "array", "items": {"type": "string"}},
+                "totalResults": {"type": "integer"},
+                "startIndex": {"type": "integer"},
+                "itemsPerPage": {"type": "integer"},
+                "Resources": {"type": "array", "items": {"$ref": "#/components/schemas/ScimUser"}},
+            },
+        },
+        "ScimPatchOp": {
+            "type": "object",
+            "required": ["Operations"],
+            "properties": {
+                "schemas": {"type": "array", "items": {"type": "string"}},
+                "Operations": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["op"],
+                        "properties": {
+                            "op": {"type": "string", "enum": ["add", "replace", "remove"]},
+                            "path": {"type": "string", "example": "active"},
+                            "value": {},
+                        },
+                    },
+                },
+            },
+        },
+        "ScimError": {
+            "type": "object",
+            "required": ["schemas", "status", "detail"],
+            "properties": {
+                "schemas": {"type": "array", "items": {"type": "string"}},
+                "status": {"type": "string", "example": "409"},
+                "scimType": {"type": "string", "example": "uniqueness"},
+                "detail": {"type": "string"},
+            },
+        },
+        "CreatedId": {
+            "type": "object",
+            "required": ["id"],
+            "properties": {"id": {"type": "string", "format": "uuid"}, "message": {"type": "string"}},
+        },
+        "Message": {
+            "type": "object",
+            "required": ["message"],
+            "properties": {"message": {"type": "string"}},
+        },
+        "Pagination": {
+            "type": "object",
+            "required": ["total", "limit", "offset", "next", "prev"],
+            "properties": {
+                "total": {"type": "integer", "description": "Matching items across all pages"},
+                "limit": {"type": "integer", "default": DEFAULT_LIMIT, "maximum": MAX_LIMIT},
+                "offset": {"type": "integer"},
+                "next": {"type": "string", "nullable": true, "description": "Link to the next page"},
+                "prev": {"type": "string", "nullable": true, "description": "Link to the previous page"},
+            },
+        },
+        "SavedView": {
+            "type": "object",
+            "required": ["name"],
+            "properties": {
+                "name": {"type": "string", "example": "active-adults"},
+                "filter": {
+                    "type": "string",
+                    "description": "Expression of `?filter=`, empty for none",
+                    "example": "age>=18",
+                },
+                "sort": {"type": "string", "nullable": true},
+                "order": {"type": "string", "enum": ["asc", "desc"], "nullable": true},
+            },
+        },
+        "Dashboard": {
+            "type": "object",
+            "required": ["users", "products", "recent_orders", "upstreams", "errors"],
+            "properties": {
+                "users": {
+                    "type": "object",
+                    "nullable": true,
+                    "properties": {
+                        "total": {"type": "integer", "format": "int64"},
+                        "average_age": {"type": "number", "format": "double", "nullable": true},
+                    },
+                },
+                "products": {
+                    "type": "object",
+                    "nullable": true,
+                    "properties": {
+                        "total": {"type": "integer", "format": "int64"},
+                        "total_stock": {"type": "integer", "format": "int64"},
+                        "out_of_stock": {"type": "integer", "format": "int64"},
+                        "average_price": {"type": "number", "format": "double", "nullable": true},
+                        "stock_value": {"type": "number", "format": "double"},
+                    },
+                },
+                "recent_orders": {
+                    "type": "array",
+                    "nullable": true,
+                    "description": "The latest orders, the latest first",
+                    "items": {"$ref": "#/components/schemas/Order"},
+                },
+                "upstreams": {
+                    "type": "object",
+                    "description": "JSON response of every `DASHBOARD_UPSTREAMS` service, by name",
+                    "additionalProperties": {"nullable": true},
+                },
+                "errors": {
+                    "type": "object",
+                    "description": "Reason of every missing part, by name",
+                    "additionalProperties": {"type": "string"},
+                },
+            },
+        },
+        "Experiment": {
+            "type": "object",
+            "required": ["name", "variant", "variants"],
+            "properties": {
+                "name": {"type": "string", "example": "checkout_button"},
+                "variant": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "Variant of the caller, `null` when it can't be told",
+                },
+                "variants": {"type": "array", "items": {"type": "string"}},
+            },
+        },
+        "Job": {
+            "type": "object",
+            "required": ["id", "kind", "payload", "status", "attempts", "created_at"],
+            "properties": {
+                "id": {"type": "integer", "format": "int64"},
+                "kind": {"type": "string"},
+                "payload": {"type": "object"},
+                "status": {"type": "string", "enum": ["queued", "running", "succeeded", "failed"]},
+                "attempts": {"type": "integer"},
+                "last_error": {"type": "string", "nullable": true},
+                "request_id": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "Request that enqueued the job, null for the scheduled ones",
+                },
+                "progress": {
+                    "type": "object",
+                    "nullable": true,
+                    "description": "How far the job went, `{total, deleted}` for a bulk delete",
+                },
+                "created_at": {"type": "string", "format": "date-time"},
+                "finished_at": {"type": "string", "format": "date-time", "nullable": true},
+            },
+        },
+        "Archive": {
+            "type": "object",
+            "required": [
+                "id", "table_name", "partition_name", "location", "row_count", "byte_size",
+                "sha256", "created_at",
+            ],
+            "properties": {
+                "id": {"type": "string", "format": "uuid"},
+                "table_name": {"type": "string", "example": "audit_log"},
+                "partition_name": {"type": "string", "example": "audit_log_p2024_01"},
+                "location": {"type": "string", "description": "Location of the gzipped CSV in the file storage"},
+                "row_count": {"type": "integer", "format": "int64"},
+                "byte_size": {"type": "integer", "format": "int64"},
+                "sha256": {"type": "string", "description": "Hex SHA-256 of the file"},
+                "created_at": {"type": "string", "format": "date-time"},
+                "restored_at": {"type": "string", "format": "date-time", "nullable": true},
+            },
+        },
+        "ConsoleQuery": {
+            "type": "object",
+            "required": ["name", "description", "sql", "params", "max_rows"],
+            "properties": {
+                "name": {"type": "string", "example": "orders-of-user"},
+                "description": {"type": "string"},
+                "sql": {"type": "string", "description": "The query, with `$1`, `$2`... for the parameters"},
+                "params": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["name", "type"],
+                        "properties": {
+                            "name": {"type": "string", "example": "email"},
+                            "type": {"type": "string", "enum": ["text", "integer", "float", "boolean", "uuid"]},
+                        },
+                    },
+                },
+                "max_rows": {"type": "integer"},
+            },
+        },
+        "ConsoleRun": {
+            "type": "object",
+            "properties": {
+                "params": {
+                    "type": "object",
+                    "description": "Value of each parameter of the query, by name",
+                    "example": {"email": "ada@example.com"},
+                },
+            },
+        },
+        "ConsoleResult": {
+            "type": "object",
+            "required": ["data", "pagination"],
+            "properties": {
+                "data": {
+                    "type": "array",
+                    "items": {"type": "object", "description": "Columns of the row, by name"},
+                },
+                "pagination": {
+                    "type": "object",
+                    "required": ["rows", "max_rows", "truncated"],
+                    "properties": {
+                        "rows": {"type": "integer"},
+                        "max_rows": {"type": "integer"},
+                        "truncated": {"type": "boolean", "description": "Whether the query had more rows"},
+                    },
+                },
+            },
+        },
+        "ToolManifest": {
+            "type": "object",
+            "required": ["tools"],
+            "properties": {
+                "tools": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["name", "description", "inputSchema", "route"],
+                        "properties": {
+                            "name": {"type": "string", "example": "list_users"},
+                            "description": {"type": "string"},
+                            "inputSchema": {"type": "object", "description": "JSON Schema of the arguments"},
+                            "route": {"type": "string", "example": "GET /api/v1/users"},
+                        },
+                    },
+                },
+            },
+        },
+        "ToolCall": {
+            "type": "object",
+            "properties": {
+                "arguments": {
+                    "type": "object",
+                    "description": "Arguments of the tool, matching its `inputSchema`",
+                    "example": {"filters": {"name_like": "ann"}, "limit": 5},
+                },
+            },
+        },
+        "ToolResult": {
+            "type": "object",
+            "required": ["tool", "result"],
+            "properties": {
+                "tool": {"type": "string", "example": "list_users"},
+                "result": {"description": "The body of the route"},
+            },
+        },
+        "Slo": {
+            "type": "object",
+            "required": ["name", "route", "objective", "status", "burn_rates", "requests", "bad_requests"],
+            "properties": {
+                "name": {"type": "string", "example": "users-list"},
+                "route": {"type": "string", "example": "GET /api/v1/users"},
+                "objective": {"type": "number", "description": "Percentage of good requests", "example": 99.0},
+                "latency_ms": {
+                    "type": "integer",
+                    "nullable": true,
+                    "description": "Longest time of a good request",
+                    "example": 200,
+                },
+                "status": {
+                    "type": "string",
+                    "enum": ["ok", "slow", "fast"],
+                    "description": "How fast the error budget burns",
+                },
+                "burn_rates": {
+                    "type": "object",
+                    "description": "Burn rate of the last `5m`, `30m`, `1h` and `6h`",
+                    "additionalProperties": {"type": "number"},
+                },
+                "requests": {"type": "integer", "description": "Requests of the last 6 hours"},
+                "bad_requests": {"type": "integer", "description": "Bad requests of the last 6 hours"},
+            },
+        },
+        "RetentionRule": {
+            "type": "object",
+            "required": ["name", "target", "action", "older_than_days", "schedule", "dry_run", "matching"],
+            "properties": {
+                "name": {"type": "string", "example": "audit-log-1y"},
+                "target": {
+                    "type": "string",
+                    "enum": ["audit_log", "webhook_deliveries", "experiment_exposures", "carts", "inactive_users"],
+                },
+                "action": {"type": "string", "enum": ["delete", "anonymize"]},
+                "older_than_days": {"type": "integer"},
+                "schedule": {"type": "string", "description": "Cron expression, in UTC", "example": "0 4 * * *"},
+                "dry_run": {"type": "boolean", "description": "Whether the runs only count the rows"},
+                "matching": {
+                    "type": "integer",
+                    "format": "int64",
+                    "description": "Rows the rule would delete or anonymize now",
+                },
+            },
+        },
+        "Change": {
+            "type": "object",
+            "required": ["position", "id", "action", "entity", "entity_id", "changes", "created_at"],
+            "properties": {
+                "position": {"type": "string", "description": "Position in the feed", "example": "742-1234"},
+                "id": {"type": "integer", "format": "int64", "description": "ID of the audit record"},
+                "actor_id": {"type": "string", "format": "uuid", "nullable": true},
+                "action": {"type": "string", "enum": ["insert", "update", "delete"]},
+                "entity": {"type": "string", "enum": ["user", "product", "order"]},
+                "entity_id": {
+                    "oneOf": [{"type": "string", "format": "uuid"}, {"type": "integer"}],
+                    "description": "UUID of a user or product, ID of an order",
+                },
+                "changes": {
+                    "type": "object",
+                    "description": "`{\"field\": {\"old\": ..., \"new\": ...}}`, without `old` for an insert \
+                                    and without `new` for a delete",
+                },
+                "request_id": {"type": "string", "nullable": true},
+                "created_at": {"type": "string", "format": "date-time"},
+            },
+        },
+        "ChangePage": {
+            "type": "object",
+            "required": ["changes", "next", "has_more"],
+            "properties": {
+                "changes": {"type": "array", "items": {"$ref": "#/components/schemas/Change"}},
+                "next": {
+                    "type": "string",
+                    "description": "Position of the last change, the `from` of the next page",
+                },
+                "has_more": {"type": "boolean", "description": "Whether the page is full"},
+            },
+        },
+        "Checkpoint": {
+            "type": "object",
+            "required": ["consumer", "table", "position", "updated_at"],
+            "properties": {
+                "consumer": {"type": "string", "example": "warehouse"},
+                "table": {"type": "string", "enum": ["users", "products", "orders"]},
+                "position": {"type": "string", "example": "742-1234"},
+                "updated_at": {"type": "string", "format": "date-time"},
+            },
+        },
+        "CheckpointPosition": {
+            "type": "object",
+            "required": ["position"],
+            "properties": {
+                "position": {"type": "string", "example": "742-1234"},
+            },
+        },
+        "Webhook": {
+            "type": "object",
+            "required": ["id", "url", "events", "created_at", "updated_at"],
+            "properties": {
+                "id": {"type": "string", "format": "uuid"},
+                "url": {"type": "string", "example": "https://example.com/hooks/users"},
+                "events": {
+                    "type": "array",
+                    "items": {"type": "string"},
+                    "description": "Event types delivered, all of them when empty",
+                    "example": ["users.created", "users.deleted"],
+                },
+                "created_at": {"type": "string", "format": "date-time"},
+                "updated_at": {"type": "string", "format": "date-time"},
+            },
+        },
+        "WebhookSettings": {
+            "type": "object",
+            "required": ["url", "secret"],
+            "properties": {
+                "url": {"type": "string", "description": "http or https URL, at most 2048 characters"},
+                "secret": {
+                    "type": "string",
+                    "minLength": 16,
+                    "writeOnly": true,
+                    "description": "Key of the `X-Webhook-Signature` of the deliveries",
+                },
+                "events": {
+                    "type": "array",
+                    "items": {"type": "string"},
+                    "description": "Registered event types to deliver, all of them when empty or missing",
+                },
+            },
+        },
+        "Delivery": {
+            "type": "object",
+            "required": ["id", "job_id", "attempt", "event_type", "payload", "duration_ms", "created_at"],
+            "properties": {
+                "id": {"type": "integer", "format": "int64"},
+                "job_id": {
+                    "type": "integer",
+                    "format": "int64",
+                    "description": "Job delivering the event, sent as `X-Webhook-Delivery`",
+                },
+                "attempt": {"type": "integer", "description": "1 for the first attempt of the job"},
+                "event_type": {"type": "string", "example": "users.created"},
+                "payload": {"type": "object", "description": "The body sent, an event envelope"},
+                "status_code": {
+                    "type": "integer",
+                    "nullable": true,
+                    "description": "Status of the response, null when the URL didn't answer",
+                },
+                "error": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "Why the attempt failed, null when it succeeded",
+                },
+                "duration_ms": {"type": "integer"},
+                "created_at": {"type": "string", "format": "date-time"},
+            },
+        },
+        "BulkDelete": {
+            "type": "object",
+            "required": ["filter"],
+            "properties": {
+                "filter": {
+                    "type": "string",
+                    "description": "Filter expression of the users, as `?filter=` of the list",
+                },
+            },
+        },
+        "BulkResult": {
+            "type": "object",
+            "required": ["created", "failed", "results"],
+            "properties": {
+                "created": {"type": "integer"},
+                "failed": {"type": "integer"},
+                "results": {
+                    "type": "array",
+                    "description": "The outcome of every item, in the request order",
+                    "items": {
+                        "type": "object",
+                        "required": ["index", "status"],
+                        "properties": {
+                            "index": {"type": "integer", "description": "Position in the request"},
+                            "status": {"type": "integer", "description": "201, 400 or 422"},
+                            "data": {"type": "object", "description": "The created item"},
+                            "error": {"$ref": "#/components/schemas/Error"},
+                        },
+                    },
+                },
+            },
+        },
+        "AuditRecord": {
+            "type": "object",
+            "required": ["id", "action", "entity", "entity_id", "changes", "created_at"],
+            "properties": {
+                "id": {"type": "integer", "format": "int64"},
+                "actor_id": {"type": "string", "format": "uuid", "nullable": true},
+                "action": {"type": "string", "enum": ["insert", "update", "delete"]},
+                "entity": {"type": "string", "enum": ["user", "product", "order"]},
+                "entity_id": {
+                    "oneOf": [{"type": "string", "format": "uuid"}, {"type": "integer"}],
+                    "description": "UUID of a user or product, ID of an order",
+                },
+                "changes": {
+                    "type": "object",
+                    "description": "Changed fields, each with its old and new value",
+                },
+                "request_id": {"type": "string", "nullable": true},
+                "created_at": {"type": "string", "format": "date-time"},
+            },
+        },
+        "AuditPage": page("AuditRecord"),
+        "EventSchema": {
+            "type": "object",
+            "required": ["event_type", "version", "fields", "breaking"],
+            "properties": {
+                "event_type": {"type": "string", "example": "users.created"},
+                "version": {"type": "integer"},
+                "fields": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "name": {"type": "string"},
+                            "kind": {"type": "string", "enum": ["integer", "number", "string", "boolean"]},
+                            "required": {"type": "boolean"},
+                        },
+                    },
+                },
+                "breaking": {
+                    "type": "boolean",
+                    "description": "Consumers of the previous version can't read this one",
+                },
+            },
+        },
+        "Status": {
+            "type": "object",
+            "properties": {"status": {"type": "string"}},
+        },
+        "Readiness": {
+            "type": "object",
+            "properties": {
+                "status": {"type": "string", "enum": ["ready", "not_ready"]},
+                "database": {"type": "object"},
+                "pool": {"type": "object"},
+            },
+        },
+        "Object": {"type": "object"},
+        "Error": {
+            "type": "object",
+            "required": ["code", "message"],
+            "properties": {
+                "code": {
+                    "type": "string",
+                    "description": "Stable, meant for programs",
+                    "example": "USER_NOT_FOUND",
+                },
+                "message": {"type": "string", "description": "Meant for humans"},
+                "details": {
+                    "type": "object",
+                    "description": "Messages by field, only with `VALIDATION_FAILED`",
+                    "additionalProperties": {"type": "array", "items": {"type": "string"}},
+                },
+                "request_id": {"type": "string", "description": "Matches `X-App-Request-Id`"},
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::build_router;
+
+    #[test]
+    fn every_route_is_documented() {
+        let router = build_router();
+        for route in router.routes() {
+            assert!(
+                OPERATIONS
+                    .iter()
+                    .any(|op| op.method == route.method.as_str() && op.path == route.pattern),
+                "{} {} has no entry in OPERATIONS",
+                route.method,
+                route.pattern
+            );
+        }
+        for op in OPERATIONS {
+            assert!(
+                router
+                    .routes()
+                    .any(|route| op.method == route.method.as_str() && op.path == route.pattern),
+                "{} {} is not a route",
+                op.method,
+                op.path
+            );
+        }
+    }
+
+    #[test]
+    fn references_point_to_schemas() {
+        let document = document(&build_router());
+        let schemas = document["components"]["schemas"].as_object().unwrap();
+        let text = document.to_string();
+        for reference in text.split("\"#/components/schemas/").skip(1) {
+            let name = &reference[..reference.find('"').unwrap()];
+            assert!(schemas.contains_key(name), "unknown schema {}", name);
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Variants of the running experiments (see the `experiments` module).
+
+use hyper::{Request, StatusCode, body::Incoming};
+use serde_json::json;
+
+use crate::experiments::{Assignments, experiments};
+use crate::router::{HandlerResult, Params, json_response};
+
+/// Handles GET requests for the experiments and the variants of the caller.
+///
+/// The caller is the authenticated user with an access token, its client IP otherwise,
+/// and gets the same variants in `X-Experiments`.
+///
+/// # Route
+///
+/// `GET /experiments`
+///
+/// # Response
+///
+/// - 200 OK with `[{name, variant, variants}]`, `[]` without `EXPERIMENTS_PATH`
+pub async fn handle_experiments(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let assignments = Assignments::of(&req);
+    let list: Vec<_> = experiments()
+        .iter()
+        .map(|experiment| {
+            json!({
+                "name": experiment.name,
+                "variant": assignments.and_then(|a| a.variant(&experiment.name)),
+                "variants": experiment.variants.iter().map(|v| &v.name).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    Ok(json_response(StatusCode::OK, list))
+}
@@ -0,0 +1,53 @@
+//! Export of the tables for the analytics pipelines (see the `export` module).
+
+use futures_util::TryStreamExt;
+use hyper::header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, HeaderValue};
+use hyper::{Request, Response, body::Incoming};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::export;
+use crate::repository::export::ExportTable;
+use crate::router::query;
+use crate::router::{Body, BoxError, HandlerResult, Params};
+
+/// `?table=` of `GET /admin/export/parquet`.
+#[derive(Deserialize, Debug)]
+struct ExportQuery {
+    table: ExportTable,
+}
+
+/// Handles GET requests to download a snapshot of a table as a Parquet file.
+///
+/// # Route
+///
+/// `GET /admin/export/parquet?table=`
+///
+/// - `table`: `users`, `products` or `orders`
+///
+/// # Response
+///
+/// - 200 OK with the file (`application/vnd.apache.parquet`), streamed as the table is
+///   read; a failure from then on aborts the response
+/// - 400 Bad Request if `table` is missing or unknown
+/// - 401 Unauthorized without a valid access token
+pub async fn handle_export_parquet(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let table = query::parse::<ExportQuery, _>(&req)?.table;
+    let body = export::parquet(table)?.map_err(move |e| {
+        warn!("Parquet export of {} aborted: {}", table.name(), e);
+        BoxError::from(e)
+    });
+
+    let disposition = format!("attachment; filename=\"{}.parquet\"", table.name());
+    let mut res = Response::new(Body::stream(body));
+    let headers = res.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/vnd.apache.parquet"),
+    );
+    if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+        headers.insert(CONTENT_DISPOSITION, disposition);
+    }
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    Ok(res)
+}
@@ -0,0 +1,77 @@
+//! Health check routes for orchestrators (Kubernetes probes, load balancers).
+
+use std::time::Duration;
+
+use hyper::{Request, StatusCode, body::Incoming};
+use serde_json::json;
+
+use crate::context::RequestContext;
+use crate::db::pool_status;
+use crate::error::AppError;
+use crate::router::{HandlerResult, Params, json_response};
+
+/// Maximum time the readiness check waits for the database.
+/// Kept short so the probe answers before the orchestrator gives up on it.
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+// ==================== HEALTH ROUTES ====================
+
+/// Handles liveness probes.
+///
+/// # Route
+///
+/// `GET /healthz`
+///
+/// # Response
+///
+/// Always 200 OK while the process is able to answer requests.
+pub async fn handle_liveness(_req: Request<Incoming>, _params: Params) -> HandlerResult {
+    Ok(json_response(StatusCode::OK, json!({"status": "ok"})))
+}
+
+/// Handles readiness probes.
+///
+/// # Route
+///
+/// `GET /readyz`
+///
+/// # Response
+///
+/// - 200 OK with the pool statistics if `SELECT 1` succeeds
+/// - 503 Service Unavailable with the pool statistics and the error otherwise
+pub async fn handle_readiness(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let context = RequestContext::of(&req);
+    let db = context.db;
+    // Never waits past the deadline of the probe itself
+    let timeout = context
+        .remaining_time()
+        .map_or(READINESS_TIMEOUT, |left| left.min(READINESS_TIMEOUT));
+    let check = tokio::time::timeout(timeout, async {
+        let conn = db.connection().await?;
+        conn.query_one("SELECT 1", &[]).await?;
+        Ok::<_, AppError>(())
+    })
+    .await;
+
+    let (status, database) = match check {
+        Ok(Ok(())) => (StatusCode::OK, json!({"status": "ok"})),
+        Ok(Err(e)) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({"status": "error", "error": e.to_string()}),
+        ),
+        Err(_) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({"status": "error", "error": "Timed out"}),
+        ),
+    };
+
+    let ready = status == StatusCode::OK;
+    Ok(json_response(
+        status,
+        json!({
+            "status": if ready { "ready" } else { "not_ready" },
+            "database": database,
+            "pool": pool_status(),
+        }),
+    ))
+}
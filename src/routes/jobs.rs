@@ -0,0 +1,71 @@
+//! Inspection of the background jobs (see the `jobs` module).
+
+use hyper::{Request, StatusCode, body::Incoming};
+use serde::Deserialize;
+
+use crate::context::RequestContext;
+use crate::error::{AppError, ErrorCode};
+use crate::repository::jobs::{JobRepository, JobStatus, PgJobRepo};
+use crate::router::query::{self, DEFAULT_LIMIT, MAX_LIMIT};
+use crate::router::{HandlerResult, Params, json_response};
+
+/// `?status=&limit=` of `GET /admin/jobs`.
+#[derive(Deserialize, Default, Debug)]
+struct JobsQuery {
+    status: Option<JobStatus>,
+    limit: Option<i64>,
+}
+
+/// Handles GET requests to list the latest background jobs.
+///
+/// # Route
+///
+/// `GET /admin/jobs?status=&limit=`
+///
+/// - `status`: Only the `queued`, `running`, `succeeded` or `failed` jobs
+/// - `limit`: Number of jobs (default 20, max 100)
+///
+/// # Response
+///
+/// - 200 OK with `[{id, kind, payload, status, attempts, last_error, created_at,
+///   finished_at}...]`, the latest first
+/// - 400 Bad Request if a query parameter is invalid
+/// - 401 Unauthorized without a valid access token
+pub async fn handle_list_jobs(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let query = query::parse::<JobsQuery, _>(&req)?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let jobs = PgJobRepo(db).list(query.status, limit).await?;
+    Ok(json_response(StatusCode::OK, jobs))
+}
+
+/// Handles GET requests to follow an operation, a job started by a request
+/// (`POST /users/bulk-delete`).
+///
+/// # Route
+///
+/// `GET /operations/:id` (requires authentication)
+///
+/// # Response
+///
+/// - 200 OK with `{id, kind, payload, status, attempts, last_error, progress,
+///   created_at, finished_at}`; `progress` is reported by the job as it runs
+/// - 400 Bad Request if the ID is not an integer
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if no operation has this ID (or it finished more than
+///   `JOB_RETENTION_DAYS` days ago)
+pub async fn handle_get_operation(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = params
+        .parse::<i64>("id")
+        .ok_or_else(|| AppError::Validation("ID must be an integer".to_string()))?;
+
+    let operation = PgJobRepo(db).find(id).await?.ok_or_else(|| {
+        AppError::NotFound(
+            ErrorCode::OperationNotFound,
+            "Operation not found".to_string(),
+        )
+    })?;
+    Ok(json_response(StatusCode::OK, operation))
+}
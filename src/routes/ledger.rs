@@ -0,0 +1,160 @@
+//! Credits of the users and consistency of the ledger (see `ledger`).
+
+use hyper::{Request, StatusCode, body::Incoming};
+use serde::Deserialize;
+
+use crate::context::RequestContext;
+use crate::error::AppError;
+use crate::ledger;
+use crate::repository::ledger::{LedgerRepository, PgLedgerRepo};
+use crate::router::query;
+use crate::router::{HandlerResult, Params, json_response, parse_validated_body};
+use crate::validation::{Validate, ValidationErrors};
+
+use super::users;
+
+/// Entries returned without `limit`
+const DEFAULT_LIMIT: i64 = 20;
+
+/// Most entries returned by a request
+const MAX_LIMIT: i64 = 100;
+
+/// Largest credit granted or taken back at once, in cents
+const MAX_AMOUNT: i64 = 1_000_000;
+
+/// Longest description of an entry, in characters
+const MAX_DESCRIPTION_LEN: usize = 200;
+
+/// `?limit=` of `GET /users/:id/balance`.
+#[derive(Deserialize, Default, Debug)]
+struct BalanceQuery {
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GrantCredit {
+    amount: i64,
+    description: String,
+}
+
+impl Validate for GrantCredit {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check(
+            "amount",
+            self.amount != 0 && self.amount.abs() <= MAX_AMOUNT,
+            format!(
+                "must be between -{0} and {0} cents, other than 0",
+                MAX_AMOUNT
+            ),
+        );
+        let len = self.description.trim().chars().count();
+        errors.check(
+            "description",
+            (1..=MAX_DESCRIPTION_LEN).contains(&len),
+            format!("must have 1 to {} characters", MAX_DESCRIPTION_LEN),
+        );
+        errors.into_result()
+    }
+}
+
+/// Handles GET requests for the credit of a user and their latest entries.
+///
+/// # Route
+///
+/// `GET /users/:id/balance?limit=` (requires authentication)
+///
+/// - `limit`: Number of entries (default 20, max 100)
+///
+/// # Response
+///
+/// - 200 OK with `{"balance", "entries": [{id, kind, description, order_id, amount,
+///   balance, created_at}...]}`, amounts in cents, the latest entry first
+/// - 400 Bad Request if the ID or `limit` is invalid
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if the user does not exist
+pub async fn handle_get_balance(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = users::parse_user_id(db, &params).await?;
+    let limit = query::parse::<BalanceQuery, _>(&req)?
+        .limit
+        .unwrap_or(DEFAULT_LIMIT)
+        .clamp(1, MAX_LIMIT);
+
+    let balance = PgLedgerRepo(db).balance(id, limit).await?;
+    Ok(json_response(StatusCode::OK, balance))
+}
+
+/// Handles POST requests granting credit to a user, or taking it back.
+///
+/// # Route
+///
+/// `POST /users/:id/credits` (requires the admin role)
+///
+/// # Request Body
+/// JSON object with `amount`, in cents, positive to grant credit and negative to take
+/// it back, and `description`, the reason told to the user
+///
+/// # Response
+///
+/// - 201 Created with the entry, and the credit of the user after it in `balance`
+/// - 400 Bad Request if the ID or the JSON is invalid
+/// - 401 Unauthorized without a valid access token
+/// - 403 Forbidden without the admin role
+/// - 404 Not Found if the user does not exist
+/// - 409 Conflict if the user has less credit than taken back
+/// - 422 Unprocessable Entity if the amount or the description is invalid
+pub async fn handle_grant_credit(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = users::parse_user_id(db, &params).await?;
+    let data = parse_validated_body::<GrantCredit>(req).await?;
+
+    let entry =
+        ledger::grant_credit(db, id, data.amount, data.description.trim().to_string()).await?;
+    Ok(json_response(StatusCode::CREATED, entry))
+}
+
+/// Handles POST requests refunding an order to the credit of its user.
+///
+/// # Route
+///
+/// `POST /users/:id/orders/:order_id/refund` (requires the admin role)
+///
+/// # Response
+///
+/// - 201 Created with the entry: the total of the order, and the credit of the user
+///   after it in `balance`
+/// - 400 Bad Request if an ID is invalid, or the order was free
+/// - 401 Unauthorized without a valid access token
+/// - 403 Forbidden without the admin role
+/// - 404 Not Found if the user does not exist or has no such order
+/// - 409 Conflict if the order was already refunded
+pub async fn handle_refund_order(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = users::parse_user_id(db, &params).await?;
+    let order_id = params
+        .parse::<i32>("order_id")
+        .ok_or_else(|| AppError::Validation("The order ID is not an integer".to_string()))?;
+
+    let entry = ledger::refund_order(db, id, order_id).await?;
+    Ok(json_response(StatusCode::CREATED, entry))
+}
+
+/// Handles GET requests checking the consistency of the ledger.
+///
+/// # Route
+///
+/// `GET /admin/ledger/check` (requires the admin role)
+///
+/// # Response
+///
+/// - 200 OK with `{accounts, entries, unbalanced_entries, drifted_accounts,
+///   trial_balance, consistent}`; a consistent ledger has no unbalanced entry nor
+///   drifted account, and a trial balance of 0 (status 200 either way)
+/// - 401 Unauthorized without a valid access token
+/// - 403 Forbidden without the admin role
+pub async fn handle_check_ledger(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let report = ledger::report(db).await?;
+    Ok(json_response(StatusCode::OK, report))
+}
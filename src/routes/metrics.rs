@@ -0,0 +1,56 @@
+use hyper::{
+    Request, Response, StatusCode,
+    body::Incoming,
+    header::{ACCEPT, CONTENT_TYPE},
+};
+
+use crate::metrics::{self, Format};
+use crate::router::{HandlerResult, Params, json_response};
+
+// ==================== METRICS ROUTES ====================
+
+/// Handles Prometheus scrapes.
+///
+/// # Route
+///
+/// `GET /metrics`
+///
+/// # Response
+///
+/// 200 OK with every metric (see the `metrics` module): in the OpenMetrics format, with
+/// exemplars, when the `Accept` header lists `application/openmetrics-text` (as
+/// Prometheus does), in the Prometheus text format otherwise
+pub async fn handle_metrics(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let openmetrics = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+    let (format, content_type) = if openmetrics {
+        (Format::OpenMetrics, metrics::OPENMETRICS_CONTENT_TYPE)
+    } else {
+        (Format::Prometheus, metrics::CONTENT_TYPE)
+    };
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type)
+        .body(metrics::render(format).into())
+        .unwrap())
+}
+
+/// Handles GET requests for the status of the service level objectives.
+///
+/// # Route
+///
+/// `GET /admin/slo`
+///
+/// # Response
+///
+/// - 200 OK with `[{name, route, objective, latency_ms, status, burn_rates: {5m, 30m, 1h,
+///   6h}, requests, bad_requests}...]`, in the order of `SLO_PATH`; `status` is `ok`,
+///   `slow` or `fast` (see `metrics::slo`), the requests are those of the last 6 hours
+/// - 401 Unauthorized without a valid access token
+pub async fn handle_slo(_req: Request<Incoming>, _params: Params) -> HandlerResult {
+    Ok(json_response(StatusCode::OK, metrics::slo_report()))
+}
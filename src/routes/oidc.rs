@@ -0,0 +1,308 @@
+//! OpenID Connect provider routes (see `oidc`): discovery, keys, the login form of the
+//! authorization endpoint, the token endpoint and the claims of the users.
+//!
+//! Every route answers 404 unless `OIDC_ISSUER` is set.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hyper::header::{
+    AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, HeaderName, HeaderValue, LOCATION, PRAGMA,
+};
+use hyper::{Request, Response, StatusCode, body::Incoming};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::auth::check_credentials;
+use crate::auth::authenticate_client;
+use crate::context::RequestContext;
+use crate::error::AppError;
+use crate::oidc::{self, AuthorizationRequest, Client, OAuthError, TokenRequest};
+use crate::passkeys;
+use crate::router::error_pages::escape_html;
+use crate::router::limits::max_body_size;
+use crate::router::{HandlerResult, Params, collect_body, empty_response, json_response, query};
+
+/// Form of the login page, posted to `/oauth/authorize`.
+#[derive(Deserialize, Debug)]
+struct LoginForm {
+    #[serde(flatten)]
+    request: AuthorizationRequest,
+    #[serde(default)]
+    email: String,
+    #[serde(default)]
+    password: String,
+}
+
+/// Collects a `application/x-www-form-urlencoded` body.
+async fn parse_form<T: serde::de::DeserializeOwned>(req: Request<Incoming>) -> Result<T, AppError> {
+    let body = collect_body(req, max_body_size()).await?;
+    serde_urlencoded::from_bytes(&body)
+        .map_err(|e| AppError::Validation(format!("Invalid form: {}", e)))
+}
+
+/// 303 See Other to `url`.
+fn redirect(url: &str) -> HandlerResult {
+    let mut res = empty_response(StatusCode::SEE_OTHER);
+    let location = HeaderValue::from_str(url)
+        .map_err(|_| AppError::Validation("Invalid redirect_uri".to_string()))?;
+    res.headers_mut().insert(LOCATION, location);
+    Ok(res)
+}
+
+/// Sends the browser back to the app with `error`, when the request of the app is
+/// invalid or can't be served.
+fn redirect_error(request: &AuthorizationRequest, error: OAuthError) -> HandlerResult {
+    let (error, description) = match error {
+        OAuthError::Protocol { error, description } => (error, description),
+        OAuthError::App(e) => return Err(e),
+    };
+    let mut params = vec![
+        ("error", error),
+        ("error_description", description.as_str()),
+    ];
+    if let Some(state) = &request.state {
+        params.push(("state", state));
+    }
+    redirect(&oidc::redirect_url(&request.redirect_uri, &params))
+}
+
+/// The login page for `client`, carrying the parameters of `request` to the POST.
+fn login_page(
+    client: &Client,
+    request: &AuthorizationRequest,
+    email: &str,
+    error: Option<&str>,
+    status: StatusCode,
+) -> HandlerResult {
+    let fields = [
+        ("response_type", Some(&request.response_type)),
+        ("client_id", Some(&request.client_id)),
+        ("redirect_uri", Some(&request.redirect_uri)),
+        ("scope", Some(&request.scope)),
+        ("state", request.state.as_ref()),
+        ("nonce", request.nonce.as_ref()),
+        ("code_challenge", Some(&request.code_challenge)),
+        (
+            "code_challenge_method",
+            request.code_challenge_method.as_ref(),
+        ),
+    ];
+    let hidden = fields
+        .iter()
+        .filter_map(|&(name, value)| {
+            Some(format!(
+                "<input type=\"hidden\" name=\"{}\" value=\"{}\">",
+                name,
+                escape_html(value?)
+            ))
+        })
+        .collect::<String>();
+    let error = error
+        .map(|error| format!("<p role=\"alert\">{}</p>", escape_html(error)))
+        .unwrap_or_default();
+    let page = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\"><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>Sign in to {name}</title></head><body><main>\
+         <h1>Sign in to {name}</h1>{error}\
+         <form method=\"post\" action=\"authorize\">{hidden}\
+         <label>Email <input type=\"email\" name=\"email\" value=\"{email}\" required \
+         autocomplete=\"username\"></label>\
+         <label>Password <input type=\"password\" name=\"password\" required \
+         autocomplete=\"current-password\"></label>\
+         <button type=\"submit\">Sign in</button></form></main></body></html>\n",
+        name = escape_html(client.name()),
+        error = error,
+        hidden = hidden,
+        email = escape_html(email),
+    );
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(CACHE_CONTROL, "no-store")
+        // Never framed, so the form can't be overlaid by another site
+        .header(HeaderName::from_static("x-frame-options"), "DENY")
+        .header(
+            HeaderName::from_static("content-security-policy"),
+            "default-src 'none'; form-action 'self'; frame-ancestors 'none'",
+        )
+        .body(page.into())
+        .unwrap())
+}
+
+/// Handles GET requests to the discovery document.
+///
+/// # Route
+///
+/// `GET /.well-known/openid-configuration`
+///
+/// # Response
+///
+/// - 200 OK with the issuer, the endpoints and what they support
+/// - 404 Not Found without `OIDC_ISSUER`
+pub async fn handle_discovery(_req: Request<Incoming>, _params: Params) -> HandlerResult {
+    Ok(json_response(StatusCode::OK, oidc::discovery()?))
+}
+
+/// Handles GET requests to the keys of the ID tokens.
+///
+/// # Route
+///
+/// `GET /oauth/jwks`
+///
+/// # Response
+///
+/// - 200 OK with the JWK Set of the signing key
+/// - 404 Not Found without `OIDC_ISSUER`
+pub async fn handle_jwks(_req: Request<Incoming>, _params: Params) -> HandlerResult {
+    Ok(json_response(StatusCode::OK, oidc::jwks()?))
+}
+
+/// Handles GET requests to the authorization endpoint, where an app sends the browser.
+///
+/// # Route
+///
+/// `GET /oauth/authorize?response_type=code&client_id=...&redirect_uri=...&scope=openid...`
+///
+/// # Response
+///
+/// - 200 OK with the login page
+/// - 303 See Other to the `redirect_uri` with `error` if a parameter is invalid
+/// - 400 Bad Request if the client is unknown or the `redirect_uri` isn't one of its
+/// - 404 Not Found without `OIDC_ISSUER`
+pub async fn handle_authorize_form(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let request = query::parse::<AuthorizationRequest, _>(&req)?;
+    let client = oidc::client(&request)?;
+    if let Err(e) = oidc::check_authorization(&request) {
+        return redirect_error(&request, e);
+    }
+    login_page(client, &request, "", None, StatusCode::OK)
+}
+
+/// Handles POST requests of the login page.
+///
+/// # Route
+///
+/// `POST /oauth/authorize`
+///
+/// # Request Body
+/// `application/x-www-form-urlencoded`: the parameters of the authorization request,
+/// `email` and `password`
+///
+/// # Response
+///
+/// - 303 See Other to the `redirect_uri` with `code` and `state`, or with `error`
+/// - 400 Bad Request if the client is unknown or the `redirect_uri` isn't one of its
+/// - 401 Unauthorized with the login page again if the credentials are wrong
+/// - 404 Not Found without `OIDC_ISSUER`
+pub async fn handle_authorize(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let form = parse_form::<LoginForm>(req).await?;
+    let request = form.request;
+    let client = oidc::client(&request)?;
+    if let Err(e) = oidc::check_authorization(&request) {
+        return redirect_error(&request, e);
+    }
+    let user_id = match check_credentials(db, &form.email, form.password).await {
+        Ok(user_id) => user_id,
+        Err(AppError::Unauthorized(message)) => {
+            return login_page(
+                client,
+                &request,
+                &form.email,
+                Some(&message),
+                StatusCode::UNAUTHORIZED,
+            );
+        }
+        Err(e) => return Err(e),
+    };
+    if passkeys::requires_second_factor(db, user_id).await? {
+        return login_page(
+            client,
+            &request,
+            &form.email,
+            Some(
+                "This account requires a passkey after the password, which this page can't ask for",
+            ),
+            StatusCode::UNAUTHORIZED,
+        );
+    }
+    redirect(&oidc::authorize(db, &request, user_id).await?)
+}
+
+/// Handles POST requests exchanging an authorization code for tokens.
+///
+/// # Route
+///
+/// `POST /oauth/token`
+///
+/// # Request Body
+/// `application/x-www-form-urlencoded` with `grant_type=authorization_code`, `code`,
+/// `redirect_uri`, `code_verifier`, and the `client_id` (and `client_secret`) unless
+/// sent with HTTP Basic
+///
+/// # Response
+///
+/// - 200 OK with `{"access_token", "token_type", "expires_in", "id_token", "scope"}`
+/// - 400 Bad Request with `{"error", "error_description"}` (`invalid_grant`, ...)
+/// - 401 Unauthorized with `{"error": "invalid_client"}` if the client is unknown or
+///   its secret wrong
+/// - 404 Not Found without `OIDC_ISSUER`
+pub async fn handle_token(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let basic = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|value| STANDARD.decode(value.trim()).ok())
+        .and_then(|value| String::from_utf8(value).ok())
+        .and_then(|value| {
+            let (id, secret) = value.split_once(':')?;
+            Some((id.to_string(), secret.to_string()))
+        });
+    let form = parse_form::<TokenRequest>(req).await?;
+
+    let (status, body) = match oidc::exchange(db, form, basic).await {
+        Ok(tokens) => (StatusCode::OK, tokens),
+        Err(OAuthError::Protocol { error, description }) => {
+            let status = if error == "invalid_client" {
+                StatusCode::UNAUTHORIZED
+            } else {
+                StatusCode::BAD_REQUEST
+            };
+            (
+                status,
+                json!({"error": error, "error_description": description}),
+            )
+        }
+        Err(OAuthError::App(e)) => return Err(e),
+    };
+    let mut res = json_response(status, body);
+    res.headers_mut()
+        .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    res.headers_mut()
+        .insert(PRAGMA, HeaderValue::from_static("no-cache"));
+    Ok(res)
+}
+
+/// Handles GET requests to the claims of the user of an access token.
+///
+/// # Route
+///
+/// `GET /oauth/userinfo` (requires a client token of an app, or a token of the API)
+///
+/// # Response
+///
+/// - 200 OK with `{"sub", "name", "email", "email_verified"}`, `name` with the
+///   `profile` scope and `email` with the `email` scope
+/// - 401 Unauthorized if the token is missing or invalid, or its user was deleted
+/// - 404 Not Found without `OIDC_ISSUER`
+pub async fn handle_userinfo(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let grant = authenticate_client(&req)?;
+    Ok(json_response(
+        StatusCode::OK,
+        oidc::userinfo(db, &grant).await?,
+    ))
+}
@@ -0,0 +1,82 @@
+use hyper::{Request, StatusCode, body::Incoming};
+
+use crate::computed::{Resource, with_fields};
+use crate::context::RequestContext;
+use crate::error::{AppError, ErrorCode};
+use crate::events::{self, Action, Collection};
+use crate::repository::ids::{ResourceId, resolve};
+use crate::repository::orders::{NewOrder, OrderRepository, PgOrderRepo};
+use crate::router::{HandlerResult, Params, json_response, parse_validated_body};
+use crate::validation::{Validate, ValidationErrors};
+
+// ==================== ORDER ROUTES ====================
+
+impl Validate for NewOrder {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check("quantity", self.quantity > 0, "must be > 0");
+        errors.check(
+            "coupon",
+            self.coupon
+                .as_ref()
+                .is_none_or(|code| !code.trim().is_empty()),
+            "must not be empty",
+        );
+        errors.into_result()
+    }
+}
+
+/// Handles POST requests to place an order for a user.
+///
+/// The order is inserted, the product stock decremented and the coupon redeemed in a
+/// single transaction, so either all happen or none does.
+///
+/// # Route
+///
+/// `POST /users/:id/orders` (requires authentication)
+///
+/// # Request Body
+/// JSON object with `product_id` (UUID of the product), `quantity` (greater than 0), and
+/// optionally `coupon`, the code of a coupon to redeem
+///
+/// # Response
+///
+/// - 201 Created with the new order
+/// - 400 Bad Request if the ID or the JSON is invalid
+/// - 422 Unprocessable Entity if the quantity is not greater than 0
+/// - 404 Not Found if the user, the product or the coupon does not exist
+/// - 409 Conflict if the product doesn't have enough stock, or the coupon expired, was
+///   withdrawn or used up, or needs a larger order
+pub async fn handle_create_order(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user_not_found =
+        || AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string());
+    let user_id = params
+        .parse::<ResourceId>("id")
+        .ok_or_else(ResourceId::invalid)?;
+    let user_id = resolve(db, Collection::Users, user_id)
+        .await?
+        .ok_or_else(user_not_found)?;
+    let data = parse_validated_body::<NewOrder>(req).await?;
+    let product_id = resolve(db, Collection::Products, data.product_id)
+        .await?
+        .ok_or_else(|| {
+            AppError::NotFound(ErrorCode::ProductNotFound, "Product not found".to_string())
+        })?;
+
+    let order = PgOrderRepo(db)
+        .place(user_id, product_id, data.quantity, data.coupon.as_deref())
+        .await?;
+    // The stock of the product went down
+    events::publish(
+        Collection::Products,
+        Action::Updated,
+        product_id,
+        order.product_id,
+    );
+
+    Ok(json_response(
+        StatusCode::CREATED,
+        with_fields(Resource::Orders, order),
+    ))
+}
@@ -0,0 +1,230 @@
+//! Passkeys of the caller, and the logins with them (see the `passkeys` module). Every
+//! route answers 404 without `WEBAUTHN_RP_ID`.
+
+use hyper::header::SET_COOKIE;
+use hyper::{Request, StatusCode, body::Incoming};
+use serde::Deserialize;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::auth::issue_token;
+use crate::carts;
+use crate::context::RequestContext;
+use crate::error::AppError;
+use crate::passkeys::{self, LoginCredential, RegistrationCredential};
+use crate::repository::users::{PgUserRepo, UserRepository};
+use crate::router::{
+    HandlerResult, Params, empty_response, json_response, parse_json_body, parse_validated_body,
+};
+use crate::validation::{MAX_NAME_LEN, Validate, ValidationErrors};
+
+/// Name of the passkeys registered without one
+const DEFAULT_NAME: &str = "Passkey";
+
+#[derive(Deserialize, Debug)]
+struct RegisterPasskeyRequest {
+    challenge_id: Uuid,
+    #[serde(default)]
+    name: Option<String>,
+    credential: RegistrationCredential,
+}
+
+impl Validate for RegisterPasskeyRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if let Some(name) = &self.name {
+            errors.check(
+                "name",
+                name.chars().count() <= MAX_NAME_LEN,
+                format!("must have at most {} characters", MAX_NAME_LEN),
+            );
+        }
+        errors.into_result()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct PasskeyLoginRequest {
+    challenge_id: Uuid,
+    credential: LoginCredential,
+}
+
+#[derive(Deserialize, Debug)]
+struct SecondFactorRequest {
+    passkey: bool,
+}
+
+/// Handles POST requests starting the registration of a passkey of the caller.
+///
+/// # Route
+///
+/// `POST /auth/passkeys/registration` (requires authentication)
+///
+/// # Response
+///
+/// - 200 OK with `{challenge_id, publicKey}`, `publicKey` being the options of
+///   `navigator.credentials.create()`, binary values in base64url
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found without `WEBAUTHN_RP_ID`
+pub async fn handle_start_registration(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let options = passkeys::start_registration(db, user.id).await?;
+    Ok(json_response(StatusCode::OK, options))
+}
+
+/// Handles POST requests registering a passkey of the caller.
+///
+/// # Route
+///
+/// `POST /auth/passkeys` (requires authentication)
+///
+/// # Request Body
+/// JSON object with `challenge_id`, `credential` (the `PublicKeyCredential` created by
+/// the browser, serialized by its `toJSON()`), and optionally `name`
+///
+/// # Response
+///
+/// - 201 Created with `{id, name, created_at, last_used_at}`
+/// - 400 Bad Request if the challenge is unknown or expired, or the passkey invalid
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found without `WEBAUTHN_RP_ID`
+/// - 409 Conflict if the passkey is already registered
+/// - 422 Unprocessable Entity if the name is too long
+pub async fn handle_register_passkey(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let data = parse_validated_body::<RegisterPasskeyRequest>(req).await?;
+    let name = data
+        .name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .unwrap_or(DEFAULT_NAME);
+    let passkey =
+        passkeys::finish_registration(db, user.id, data.challenge_id, &data.credential, name)
+            .await?;
+    Ok(json_response(StatusCode::CREATED, passkey))
+}
+
+/// Handles GET requests for the passkeys of the caller.
+///
+/// # Route
+///
+/// `GET /auth/passkeys` (requires authentication)
+///
+/// # Response
+///
+/// - 200 OK with `[{id, name, created_at, last_used_at}...]`, the oldest first
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found without `WEBAUTHN_RP_ID`
+pub async fn handle_list_passkeys(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    Ok(json_response(
+        StatusCode::OK,
+        passkeys::list(db, user.id).await?,
+    ))
+}
+
+/// Handles DELETE requests for a passkey of the caller.
+///
+/// # Route
+///
+/// `DELETE /auth/passkeys/:id` (requires authentication)
+///
+/// # Response
+///
+/// - 204 No Content
+/// - 400 Bad Request if the ID is not a UUID
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if the caller has no passkey with this ID, or without
+///   `WEBAUTHN_RP_ID`
+/// - 409 Conflict if it's the last passkey and the caller requires one as second factor
+pub async fn handle_delete_passkey(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let id = params
+        .parse::<Uuid>("id")
+        .ok_or_else(|| AppError::Validation("ID must be a UUID".to_string()))?;
+    passkeys::delete(db, user.id, id).await?;
+    Ok(empty_response(StatusCode::NO_CONTENT))
+}
+
+/// Handles PUT requests setting whether the password logins of the caller need a
+/// passkey too.
+///
+/// # Route
+///
+/// `PUT /auth/second-factor` (requires authentication)
+///
+/// # Request Body
+/// `{"passkey": true}` or `{"passkey": false}`
+///
+/// # Response
+///
+/// - 204 No Content
+/// - 400 Bad Request if the JSON is invalid
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found without `WEBAUTHN_RP_ID`
+/// - 409 Conflict if the caller has no passkey
+pub async fn handle_second_factor(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let data = parse_json_body::<SecondFactorRequest>(req).await?;
+    passkeys::set_second_factor(db, user.id, data.passkey).await?;
+    Ok(empty_response(StatusCode::NO_CONTENT))
+}
+
+/// Handles POST requests starting a login without password.
+///
+/// # Route
+///
+/// `POST /auth/passkeys/authentication`
+///
+/// # Response
+///
+/// - 200 OK with `{challenge_id, publicKey}`, `publicKey` being the options of
+///   `navigator.credentials.get()`
+/// - 404 Not Found without `WEBAUTHN_RP_ID`
+pub async fn handle_start_login(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    Ok(json_response(
+        StatusCode::OK,
+        passkeys::start_login(db).await?,
+    ))
+}
+
+/// Handles POST requests to obtain an access token with a passkey, without password
+/// or as the second factor of `POST /auth/login`.
+///
+/// # Route
+///
+/// `POST /auth/passkeys/login`
+///
+/// # Request Body
+/// JSON object with `challenge_id` and `credential` (the `PublicKeyCredential` returned
+/// by `navigator.credentials.get()`, serialized by its `toJSON()`)
+///
+/// # Response
+///
+/// - 200 OK with `{"access_token", "token_type": "Bearer", "expires_in"}`, merging the
+///   anonymous cart of the `cart` cookie as `POST /auth/login`
+/// - 400 Bad Request if the JSON is invalid
+/// - 401 Unauthorized if the challenge is unknown or expired, or the passkey invalid
+/// - 404 Not Found without `WEBAUTHN_RP_ID`
+pub async fn handle_passkey_login(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let headers = req.headers().clone();
+    let data = parse_json_body::<PasskeyLoginRequest>(req).await?;
+    let id = passkeys::finish_login(db, data.challenge_id, &data.credential).await?;
+    // The login is valid without it, the account only looks inactive for longer
+    if let Err(e) = PgUserRepo(db).record_login(id).await {
+        warn!("Login of user {} not recorded: {}", id, e);
+    }
+    let mut res = json_response(StatusCode::OK, issue_token(id)?);
+    if let Some(cookie) = carts::merge_on_login(db, &headers, id).await {
+        res.headers_mut().insert(SET_COOKIE, cookie);
+    }
+    Ok(res)
+}
@@ -0,0 +1,365 @@
+use futures_util::{TryFutureExt, TryStreamExt};
+use hyper::{Request, StatusCode, body::Incoming};
+use serde::Deserialize;
+
+use crate::cache;
+use crate::computed::{Resource, all_with_fields, with_fields};
+use crate::context::RequestContext;
+use crate::db::{Db, join_queries};
+use crate::embeddings;
+use crate::error::{AppError, ErrorCode};
+use crate::events::{self, Action, Collection};
+use crate::repository::filter::FilterField;
+use crate::repository::ids::{ResourceId, resolve};
+use crate::repository::products::{NewProduct, PgProductRepo, Product, ProductRepository};
+use crate::repository::versions::collection_version;
+use crate::router::bulk::BulkItems;
+use crate::router::conditional::{
+    Versioned, collection_etag, if_match, not_modified, version_etag, with_etag,
+};
+use crate::router::query::{self, ListQuery};
+use crate::router::{
+    HandlerResult, Params, empty_response, json_response, json_stream_response,
+    parse_validated_body,
+};
+use crate::validation::{Validate, ValidationErrors, check_name};
+
+use super::views;
+
+// ==================== PRODUCT ROUTES ====================
+
+impl Validate for NewProduct {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        check_name(&mut errors, "name", &self.name);
+        errors.check(
+            "price",
+            self.price.is_finite() && self.price >= 0.0,
+            "must be >= 0",
+        );
+        errors.check("stock", self.stock >= 0, "must be >= 0");
+        errors.into_result()
+    }
+}
+
+impl Versioned for Product {
+    fn version(&self) -> i32 {
+        self.version
+    }
+}
+
+/// Extracts the `:id` path parameter of the product routes.
+///
+/// # Returns
+///
+/// * `Result<i32, AppError>` - The key of the product (see `repository::ids`), a
+///   validation error if the ID is not a UUID (or an integer), or not found if no
+///   product has it
+pub(super) async fn parse_product_id(db: Db, params: &Params) -> Result<i32, AppError> {
+    let id = params
+        .parse::<ResourceId>("id")
+        .ok_or_else(ResourceId::invalid)?;
+    resolve(db, Collection::Products, id)
+        .await?
+        .ok_or_else(product_not_found)
+}
+
+/// Error returned when no product has the requested ID.
+fn product_not_found() -> AppError {
+    AppError::NotFound(ErrorCode::ProductNotFound, "Product not found".to_string())
+}
+
+/// Columns products can be sorted by (`?sort=`), the first one is the default
+pub(super) const SORTABLE_COLUMNS: &[&str] = &["id", "name", "price", "stock"];
+
+/// Columns products can be filtered by (`?name_like=book&price_lte=100`)
+pub(super) const FILTERABLE_FIELDS: &[FilterField] = &[
+    FilterField::uuid("id", "public_id"),
+    FilterField::text("name"),
+    FilterField::float("price"),
+    FilterField::integer("stock"),
+];
+
+/// Longest query of the semantic search, in characters
+const MAX_SEARCH_LENGTH: usize = 200;
+
+/// Products found by a search (`?limit=`)
+const DEFAULT_SEARCH_LIMIT: i64 = 20;
+const MAX_SEARCH_LIMIT: i64 = 100;
+
+/// Query of `GET /products/semantic-search`.
+#[derive(Deserialize, Debug)]
+struct SearchQuery {
+    q: Option<String>,
+    limit: Option<i64>,
+}
+
+/// Handles GET requests to retrieve a page of products.
+///
+/// # Route
+///
+/// `GET /products?limit=&offset=&sort=&order=&stream=&view=&<filters>`
+///
+/// - `limit`: Page size (default 20, max 100)
+/// - `offset`: Number of products to skip (default 0)
+/// - `sort`: `id` (default), `name`, `price` or `stock`
+/// - `order`: `asc` (default) or `desc`
+/// - Filters: `<field>_<operator>=<value>` on `id`, `name`, `price` and `stock`, such
+///   as `name_like=book` or `price_lte=100`, or a `filter` expression such as
+///   `price<=100 OR stock=0` (see `repository::filter`)
+/// - `view`: Name of a saved view of the caller (requires an access token), adding
+///   its filter and sort (see `routes::views`)
+/// - `stream`: `true` to receive every product, sorted, in a streamed JSON array
+///   (`limit` and `offset` are ignored, the body is neither converted nor compressed)
+///
+/// # Response
+///
+/// - 200 OK with `{"data": [...], "pagination": {total, limit, offset, next, prev}}`
+///   and an `ETag` header
+/// - 200 OK with `[...]` when `stream=true`
+/// - 304 Not Modified if `If-None-Match` matches the current `ETag`
+/// - 400 Bad Request if a query parameter is invalid
+/// - 401 Unauthorized if `view` is given without a valid access token
+/// - 404 Not Found if the caller has no view named `view`
+pub async fn handle_get_all_products<B>(req: Request<B>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let mut list = query::parse::<ListQuery, _>(&req)?;
+    let mut filter = query::filter(&req, FILTERABLE_FIELDS)?;
+    let view = views::apply_view(&req, &views::PRODUCTS, &mut list, &mut filter).await?;
+    let page = list.pagination(SORTABLE_COLUMNS)?;
+
+    // Polling clients that already have this version get a 304 without reading the rows
+    let etag = collection_etag(
+        "products",
+        collection_version(db, "products").await?,
+        &req,
+        &view,
+    );
+    if let Some(res) = not_modified(&req, &etag) {
+        return Ok(res);
+    }
+
+    if list.stream == Some(true) {
+        let items = PgProductRepo(db)
+            .stream_all(&page, &filter)
+            .map_ok(|products| all_with_fields(Resource::Products, products));
+        return Ok(with_etag(json_stream_response(items), &etag));
+    }
+
+    // Independent queries, run at the same time on two connections
+    let (total, products) = join_queries!(
+        PgProductRepo(db).count(&filter),
+        PgProductRepo(db).list(&page, &filter)
+    )?;
+
+    Ok(with_etag(
+        json_response(
+            StatusCode::OK,
+            page.page(
+                req.uri().path(),
+                &filter,
+                all_with_fields(Resource::Products, products),
+                total,
+            ),
+        ),
+        &etag,
+    ))
+}
+
+/// Handles GET requests to retrieve a specific product by ID.
+///
+/// # Route
+///
+/// `GET /products/:id` where `:id` is the UUID of the product
+///
+/// # Response
+///
+/// - 200 OK with product data and its `ETag` if the product exists (possibly cached)
+/// - 304 Not Modified if `If-None-Match` matches the current `ETag`
+/// - 400 Bad Request if the ID is not a UUID
+/// - 404 Not Found if the product does not exist
+pub async fn handle_get_product<B>(req: Request<B>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = parse_product_id(db, &params).await?;
+
+    let repo = PgProductRepo(db);
+    let load = repo
+        .find_by_id(id)
+        .map_ok(|product| product.map(|product| with_fields(Resource::Products, product)));
+    let product = cache::get_or_load(Collection::Products, id, load)
+        .await?
+        .ok_or_else(product_not_found)?;
+
+    if let Some(res) = not_modified(&req, &product.etag) {
+        return Ok(res);
+    }
+    Ok(product.into_response())
+}
+
+/// Handles GET requests to search the products by the meaning of a text.
+///
+/// # Route
+///
+/// `GET /products/semantic-search?q=&limit=`
+///
+/// - `q`: The text searched, at most 200 characters
+/// - `limit`: Number of products (default 20, max 100)
+///
+/// # Response
+///
+/// - 200 OK with `{"data": [{product, score, similarity, keyword}...], "semantic": bool}`,
+///   the best first: the products whose embedding is the nearest to the one of `q`,
+///   blended with those whose name contains its words (see `embeddings`); `semantic` is
+///   `false` when only the words were matched
+/// - 400 Bad Request if `q` is missing, empty or too long, or `limit` is invalid
+pub async fn handle_semantic_search<B>(req: Request<B>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let query = query::parse::<SearchQuery, _>(&req)?;
+    let text = query.q.as_deref().map(str::trim).unwrap_or_default();
+    if text.is_empty() || text.chars().count() > MAX_SEARCH_LENGTH {
+        return Err(AppError::Validation(format!(
+            "q must have 1 to {} characters",
+            MAX_SEARCH_LENGTH
+        )));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+
+    let results = embeddings::search(db, text, limit).await?;
+    Ok(json_response(StatusCode::OK, results))
+}
+
+/// Handles POST requests to create a new product.
+///
+/// # Route
+///
+/// `POST /products`
+///
+/// # Request Body
+/// JSON object with `name`, `price` and `stock`
+///
+/// # Response
+///
+/// - 201 Created with the new product (including its ID)
+/// - 400 Bad Request if the JSON is malformed or body collection fails
+/// - 422 Unprocessable Entity if a field has an invalid value
+/// - 500 Internal Server Error if the insert fails
+pub async fn handle_create_product(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let data = parse_validated_body::<NewProduct>(req).await?;
+
+    let product = PgProductRepo(db).create(&data).await?;
+    events::publish(
+        Collection::Products,
+        Action::Created,
+        product.id,
+        product.public_id,
+    );
+
+    Ok(json_response(
+        StatusCode::CREATED,
+        with_fields(Resource::Products, product),
+    ))
+}
+
+/// Handles POST requests to create many products at once.
+///
+/// # Route
+///
+/// `POST /products/bulk`
+///
+/// # Request Body
+/// JSON array of objects with `name`, `price` and `stock`, or the same objects one per
+/// line with `Content-Type: application/x-ndjson` (at most 10000, see `router::bulk`)
+///
+/// # Response
+///
+/// - 200 OK with `{created, failed, results: [{index, status, data: product} or
+///   {index, status, error}...]}`; the valid products are created even if others are not
+/// - 400 Bad Request if the body isn't a JSON array or holds no products
+/// - 413 Payload Too Large if the body exceeds `MAX_UPLOAD_SIZE` or holds too many
+///   products
+/// - 500 Internal Server Error if the insert fails (no product is created)
+pub async fn handle_create_products(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let products = BulkItems::<NewProduct>::from_request(req).await?;
+
+    let created = PgProductRepo(db).create_many(&products.valid).await?;
+    for product in &created {
+        events::publish(
+            Collection::Products,
+            Action::Created,
+            product.id,
+            product.public_id,
+        );
+    }
+
+    Ok(products.into_response(created))
+}
+
+/// Handles PUT requests to replace all the fields of a product.
+///
+/// # Route
+///
+/// `PUT /products/:id` with `If-Match` set to the `ETag` of the product (or `*`)
+///
+/// # Request Body
+/// JSON object with `name`, `price` and `stock` (all required)
+///
+/// # Response
+///
+/// - 200 OK with the updated product and its new `ETag`
+/// - 400 Bad Request if the ID or the JSON is invalid
+/// - 422 Unprocessable Entity if a field has an invalid value
+/// - 404 Not Found if the product does not exist
+/// - 412 Precondition Failed if the product changed since the version of `If-Match`
+/// - 428 Precondition Required without `If-Match`
+/// - 500 Internal Server Error if the update fails
+pub async fn handle_update_product(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = parse_product_id(db, &params).await?;
+    let versions = if_match(&req)?;
+    let data = parse_validated_body::<NewProduct>(req).await?;
+
+    let product = PgProductRepo(db)
+        .update(id, &data, versions.as_deref())
+        .await?
+        .ok_or_else(product_not_found)?;
+    events::publish(Collection::Products, Action::Updated, id, product.public_id);
+
+    let etag = version_etag(product.version);
+    Ok(with_etag(
+        json_response(StatusCode::OK, with_fields(Resource::Products, product)),
+        &etag,
+    ))
+}
+
+/// Handles DELETE requests to remove a product.
+///
+/// # Route
+///
+/// `DELETE /products/:id` with `If-Match` set to the `ETag` of the product (or `*`)
+///
+/// # Response
+///
+/// - 204 No Content if the product was deleted
+/// - 400 Bad Request if the ID is not a UUID
+/// - 404 Not Found if the product does not exist
+/// - 412 Precondition Failed if the product changed since the version of `If-Match`
+/// - 428 Precondition Required without `If-Match`
+/// - 500 Internal Server Error if the delete fails
+pub async fn handle_delete_product(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = parse_product_id(db, &params).await?;
+    let versions = if_match(&req)?;
+
+    let product = PgProductRepo(db)
+        .delete(id, versions.as_deref())
+        .await?
+        .ok_or_else(product_not_found)?;
+    events::publish(Collection::Products, Action::Deleted, id, product.public_id);
+
+    Ok(empty_response(StatusCode::NO_CONTENT))
+}
@@ -0,0 +1,69 @@
+//! Product recommendations of the users (see `recommendations`).
+
+use hyper::{Request, StatusCode, body::Incoming};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::computed::{Resource, with_fields};
+use crate::context::RequestContext;
+use crate::error::{AppError, ErrorCode};
+use crate::recommendations::{self, RECOMMENDATIONS};
+use crate::router::query;
+use crate::router::{HandlerResult, Params, json_response};
+
+use super::users;
+
+/// Recommendations returned without `limit`
+const DEFAULT_LIMIT: usize = 10;
+
+/// `?limit=` of `GET /users/:id/recommendations`.
+#[derive(Deserialize, Default, Debug)]
+struct RecommendationsQuery {
+    limit: Option<usize>,
+}
+
+/// Handles GET requests for the products recommended to a user.
+///
+/// # Route
+///
+/// `GET /users/:id/recommendations?limit=`
+///
+/// - `limit`: Number of products (default 10, max 20)
+///
+/// # Response
+///
+/// - 200 OK with `{"data": [{product, reason, score}...], "computed_at"}`, the best
+///   first; `reason` is `co_purchase` (bought by `score` users who bought a product of
+///   the user) or `popular` (`score` units ordered lately)
+/// - 400 Bad Request if the ID or `limit` is invalid
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if the user does not exist
+pub async fn handle_get_recommendations(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = users::parse_user_id(db, &params).await?;
+    let limit = query::parse::<RecommendationsQuery, _>(&req)?
+        .limit
+        .unwrap_or(DEFAULT_LIMIT)
+        .clamp(1, RECOMMENDATIONS as usize);
+
+    // Deleted meanwhile
+    let recommendations = recommendations::for_user(db, id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()))?;
+    let data = recommendations
+        .items
+        .into_iter()
+        .take(limit)
+        .map(|item| {
+            json!({
+                "product": with_fields(Resource::Products, item.product),
+                "reason": item.reason,
+                "score": item.score,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(json_response(
+        StatusCode::OK,
+        json!({"data": data, "computed_at": recommendations.computed_at}),
+    ))
+}
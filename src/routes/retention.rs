@@ -0,0 +1,25 @@
+//! Report of the data retention rules (see the `retention` module).
+
+use hyper::{Request, StatusCode, body::Incoming};
+
+use crate::context::RequestContext;
+use crate::retention;
+use crate::router::{HandlerResult, Params, json_response};
+
+/// Handles GET requests for a dry run of every retention rule.
+///
+/// # Route
+///
+/// `GET /admin/retention`
+///
+/// # Response
+///
+/// - 200 OK with `[{name, target, action, older_than_days, schedule, dry_run,
+///   matching}...]`, in the order of `RETENTION_RULES_PATH`; `matching` is the number
+///   of rows the rule would delete or anonymize now
+/// - 401 Unauthorized without a valid access token
+pub async fn handle_retention_report(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let report = retention::report(db).await?;
+    Ok(json_response(StatusCode::OK, report))
+}
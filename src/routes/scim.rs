@@ -0,0 +1,343 @@
+//! SCIM 2.0 provisioning routes (see `scim`): the accounts as `/scim/v2/Users`.
+//!
+//! Every route answers 404 unless `SCIM_TOKEN` is set, and takes it as bearer token.
+//! The responses are `application/scim+json`, and the errors SCIM errors:
+//!
+//! ```json
+//! {"schemas": ["urn:ietf:params:scim:api:messages:2.0:Error"], "status": "409",
+//!  "scimType": "uniqueness", "detail": "userName is already taken"}
+//! ```
+
+use bb8_postgres::tokio_postgres::error::SqlState;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderValue, LOCATION};
+use hyper::{Request, Response, StatusCode, body::Incoming};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+use super::users::remove_avatar;
+use crate::context::RequestContext;
+use crate::db::{Db, join_queries};
+use crate::error::{AppError, ErrorCode};
+use crate::events::{self, Action, Collection};
+use crate::repository::filter::Filter;
+use crate::repository::scim::{PgScimRepo, ScimAccount, ScimRepository, ScimUser};
+use crate::repository::users::{PgUserRepo, UserRepository};
+use crate::router::conditional::{version_etag, with_etag};
+use crate::router::{
+    self, Body, HandlerResult, Params, empty_response, json_response, parse_json_body, query,
+};
+use crate::scim::{
+    self, ERROR_SCHEMA, FILTERABLE_ATTRIBUTES, LIST_SCHEMA, MAX_COUNT, PatchRequest, ScimError,
+    UserRequest,
+};
+
+/// Media type of the SCIM bodies
+const SCIM_CONTENT_TYPE: &str = "application/scim+json";
+
+/// Query parameters of the list.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ListQuery {
+    filter: Option<String>,
+    /// 1-based index of the first account
+    start_index: Option<i64>,
+    count: Option<i64>,
+}
+
+fn scim_response(status: StatusCode, body: Value) -> Response<Body> {
+    let mut res = json_response(status, body);
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(SCIM_CONTENT_TYPE));
+    res
+}
+
+/// The User of an account, with its `ETag`.
+fn user_response(status: StatusCode, user: &ScimUser) -> Response<Body> {
+    with_etag(
+        scim_response(status, scim::resource(user)),
+        &version_etag(user.version),
+    )
+}
+
+/// The SCIM error of `e`, with the status and the headers of its usual response.
+fn error_response(e: ScimError) -> Response<Body> {
+    // SCIM has no 422, invalid values are 400 Bad Request
+    let status = match e.error {
+        AppError::Unprocessable(_) => Some(StatusCode::BAD_REQUEST),
+        _ => None,
+    };
+    let (mut parts, body) = router::error_response(e.error).into_parts();
+    if let Some(status) = status {
+        parts.status = status;
+    }
+    let detail = match &body {
+        Body::Buffered(text) => serde_json::from_str::<Value>(text)
+            .ok()
+            .and_then(|body| body["message"].as_str().map(str::to_string)),
+        Body::Stream(_) => None,
+    };
+    let mut body = json!({
+        "schemas": [ERROR_SCHEMA],
+        "status": parts.status.as_str(),
+        "detail": detail,
+    });
+    if let Some(scim_type) = e.scim_type {
+        body["scimType"] = scim_type.into();
+    }
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static(SCIM_CONTENT_TYPE));
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, Body::Buffered(body.to_string()))
+}
+
+/// Sends the error of a SCIM route as a SCIM error.
+fn reply(result: Result<Response<Body>, ScimError>) -> HandlerResult {
+    Ok(result.unwrap_or_else(error_response))
+}
+
+fn user_not_found() -> AppError {
+    AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string())
+}
+
+/// `AppError::Conflict` if the email of an account belongs to another one.
+fn email_taken(e: AppError) -> AppError {
+    match e {
+        AppError::Db(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => AppError::Conflict(
+            ErrorCode::EmailTaken,
+            "userName is already taken".to_string(),
+        ),
+        e => e,
+    }
+}
+
+/// The account of the `:id` path parameter.
+async fn find_user(db: Db, params: &Params) -> Result<ScimUser, AppError> {
+    let user = match params.parse::<Uuid>("id") {
+        Some(id) => PgScimRepo(db).find(id).await?,
+        None => None,
+    };
+    user.ok_or_else(user_not_found)
+}
+
+/// Replaces the fields of `user`, unless it changed since it was read.
+async fn replace(
+    db: Db,
+    user: &ScimUser,
+    account: ScimAccount,
+) -> Result<Response<Body>, ScimError> {
+    let user = PgScimRepo(db)
+        .replace(user.id, &account, Some(&[user.version]))
+        .await
+        .map_err(email_taken)?
+        .ok_or_else(user_not_found)?;
+    events::publish(Collection::Users, Action::Updated, user.id, user.public_id);
+    Ok(user_response(StatusCode::OK, &user))
+}
+
+/// Handles GET requests to list the accounts.
+///
+/// # Route
+///
+/// `GET /scim/v2/Users`
+///
+/// # Query Parameters
+///
+/// - `filter`: SCIM filter, such as `userName eq "ann@example.com"`
+/// - `startIndex`: 1-based index of the first account (default 1)
+/// - `count`: Page size (default and max 100)
+///
+/// # Response
+///
+/// - 200 OK with a `ListResponse` of the accounts, the oldest first
+/// - 400 Bad Request with `invalidFilter` if the filter is invalid
+/// - 401 Unauthorized if the token is missing or wrong
+/// - 404 Not Found without `SCIM_TOKEN`
+pub async fn handle_list_users(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    reply(list_users(req).await)
+}
+
+async fn list_users(req: Request<Incoming>) -> Result<Response<Body>, ScimError> {
+    let db = RequestContext::of(&req).db;
+    scim::authenticate(&req)?;
+    let query = query::parse::<ListQuery, _>(&req)?;
+    let filter = match &query.filter {
+        Some(filter) => Filter::parse_scim(filter, FILTERABLE_ATTRIBUTES)
+            .map_err(|e| ScimError::new("invalidFilter", e))?,
+        None => Filter::default(),
+    };
+    // Smaller values are taken as 1 and 0 (RFC 7644 §3.4.2.4)
+    let start_index = query.start_index.unwrap_or(1).max(1);
+    let count = query.count.unwrap_or(MAX_COUNT).clamp(0, MAX_COUNT);
+
+    let (total, users) = join_queries!(
+        PgScimRepo(db).count(&filter),
+        PgScimRepo(db).list(&filter, start_index - 1, count)
+    )?;
+    Ok(scim_response(
+        StatusCode::OK,
+        json!({
+            "schemas": [LIST_SCHEMA],
+            "totalResults": total,
+            "startIndex": start_index,
+            "itemsPerPage": users.len(),
+            "Resources": users.iter().map(scim::resource).collect::<Vec<_>>(),
+        }),
+    ))
+}
+
+/// Handles GET requests to retrieve an account.
+///
+/// # Route
+///
+/// `GET /scim/v2/Users/:id`
+///
+/// # Response
+///
+/// - 200 OK with the User and its `ETag`
+/// - 401 Unauthorized if the token is missing or wrong
+/// - 404 Not Found if no account has the ID, or without `SCIM_TOKEN`
+pub async fn handle_get_user(req: Request<Incoming>, params: Params) -> HandlerResult {
+    reply(get_user(req, params).await)
+}
+
+async fn get_user(req: Request<Incoming>, params: Params) -> Result<Response<Body>, ScimError> {
+    let db = RequestContext::of(&req).db;
+    scim::authenticate(&req)?;
+    let user = find_user(db, &params).await?;
+    Ok(user_response(StatusCode::OK, &user))
+}
+
+/// Handles POST requests to create an account.
+///
+/// # Route
+///
+/// `POST /scim/v2/Users`
+///
+/// # Request Body
+/// A SCIM User with `userName` (the email), and any of `externalId`, `displayName`,
+/// `name`, `active`, `password` and the `age` of the extension schema
+///
+/// # Response
+///
+/// - 201 Created with the User, its `ETag` and `Location`
+/// - 400 Bad Request with `invalidValue` if an attribute is invalid
+/// - 401 Unauthorized if the token is missing or wrong
+/// - 404 Not Found without `SCIM_TOKEN`
+/// - 409 Conflict with `uniqueness` if the email is already registered
+pub async fn handle_create_user(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    reply(create_user(req).await)
+}
+
+async fn create_user(req: Request<Incoming>) -> Result<Response<Body>, ScimError> {
+    let db = RequestContext::of(&req).db;
+    scim::authenticate(&req)?;
+    let request = parse_json_body::<UserRequest>(req).await?;
+    let account = scim::account(request, None).await?;
+
+    let user = PgScimRepo(db).create(&account).await.map_err(email_taken)?;
+    events::publish(Collection::Users, Action::Created, user.id, user.public_id);
+
+    let mut res = user_response(StatusCode::CREATED, &user);
+    if let Ok(location) = HeaderValue::from_str(&scim::location(user.public_id)) {
+        res.headers_mut().insert(LOCATION, location);
+    }
+    Ok(res)
+}
+
+/// Handles PUT requests to replace an account.
+///
+/// # Route
+///
+/// `PUT /scim/v2/Users/:id`
+///
+/// # Request Body
+/// A SCIM User, as for `POST`; `active`, `age` and the password keep their value when
+/// left out
+///
+/// # Response
+///
+/// - 200 OK with the updated User and its `ETag`
+/// - 400 Bad Request with `invalidValue` if an attribute is invalid
+/// - 401 Unauthorized if the token is missing or wrong
+/// - 404 Not Found if no account has the ID, or without `SCIM_TOKEN`
+/// - 409 Conflict with `uniqueness` if the email is already registered
+/// - 412 Precondition Failed if the account changed while it was replaced
+pub async fn handle_replace_user(req: Request<Incoming>, params: Params) -> HandlerResult {
+    reply(replace_user(req, params).await)
+}
+
+async fn replace_user(req: Request<Incoming>, params: Params) -> Result<Response<Body>, ScimError> {
+    let db = RequestContext::of(&req).db;
+    scim::authenticate(&req)?;
+    let user = find_user(db, &params).await?;
+    let request = parse_json_body::<UserRequest>(req).await?;
+    let account = scim::account(request, Some(&user)).await?;
+    replace(db, &user, account).await
+}
+
+/// Handles PATCH requests to update an account, deactivating it for instance.
+///
+/// # Route
+///
+/// `PATCH /scim/v2/Users/:id`
+///
+/// # Request Body
+/// A `PatchOp` whose `Operations` `add`, `replace` or `remove` attributes, such as
+/// `{"op": "replace", "path": "active", "value": false}`
+///
+/// # Response
+///
+/// - 200 OK with the updated User and its `ETag`
+/// - 400 Bad Request with `invalidValue`, `invalidSyntax` or `noTarget` if an
+///   operation is invalid
+/// - 401 Unauthorized if the token is missing or wrong
+/// - 404 Not Found if no account has the ID, or without `SCIM_TOKEN`
+/// - 409 Conflict with `uniqueness` if the email is already registered
+/// - 412 Precondition Failed if the account changed while it was updated
+pub async fn handle_patch_user(req: Request<Incoming>, params: Params) -> HandlerResult {
+    reply(patch_user(req, params).await)
+}
+
+async fn patch_user(req: Request<Incoming>, params: Params) -> Result<Response<Body>, ScimError> {
+    let db = RequestContext::of(&req).db;
+    scim::authenticate(&req)?;
+    let user = find_user(db, &params).await?;
+    let patch = parse_json_body::<PatchRequest>(req).await?;
+    let request = scim::patch(&user, patch)?;
+    let account = scim::account(request, Some(&user)).await?;
+    replace(db, &user, account).await
+}
+
+/// Handles DELETE requests to remove an account.
+///
+/// # Route
+///
+/// `DELETE /scim/v2/Users/:id`
+///
+/// # Response
+///
+/// - 204 No Content if the user was deleted, along with their avatar
+/// - 401 Unauthorized if the token is missing or wrong
+/// - 404 Not Found if no account has the ID, or without `SCIM_TOKEN`
+pub async fn handle_delete_user(req: Request<Incoming>, params: Params) -> HandlerResult {
+    reply(delete_user(req, params).await)
+}
+
+async fn delete_user(req: Request<Incoming>, params: Params) -> Result<Response<Body>, ScimError> {
+    let db = RequestContext::of(&req).db;
+    scim::authenticate(&req)?;
+    let user = find_user(db, &params).await?;
+
+    let deleted = PgUserRepo(db)
+        .delete(user.id, None)
+        .await?
+        .ok_or_else(user_not_found)?;
+    events::publish(Collection::Users, Action::Deleted, user.id, user.public_id);
+
+    if let Some(location) = deleted.avatar_path {
+        remove_avatar(&location).await;
+    }
+    Ok(empty_response(StatusCode::NO_CONTENT))
+}
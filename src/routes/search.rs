@@ -0,0 +1,99 @@
+//! Search of the users and products (see `search_engine`), and suggestions for search
+//! boxes (see `suggestions`).
+
+use hyper::{Request, StatusCode};
+use serde::Deserialize;
+
+use crate::context::RequestContext;
+use crate::error::AppError;
+use crate::router::query;
+use crate::router::{HandlerResult, Params, json_response};
+use crate::{search_engine, suggestions};
+
+/// Longest query, in characters
+const MAX_QUERY_LENGTH: usize = 100;
+
+/// Users and products found without `limit`, and at most
+const DEFAULT_SEARCH_LIMIT: i64 = 10;
+const MAX_SEARCH_LIMIT: i64 = 50;
+
+/// Completions returned without `limit`, and at most
+const DEFAULT_SUGGEST_LIMIT: i64 = 5;
+const MAX_SUGGEST_LIMIT: i64 = 20;
+
+/// Query of `GET /search` and `GET /search/suggest`.
+#[derive(Deserialize, Debug)]
+struct SearchQuery {
+    q: Option<String>,
+    limit: Option<i64>,
+}
+
+/// The text of `query`, if it's valid.
+fn text(query: &SearchQuery) -> Result<&str, AppError> {
+    let text = query.q.as_deref().unwrap_or_default();
+    if text.trim().is_empty() || text.chars().count() > MAX_QUERY_LENGTH {
+        return Err(AppError::Validation(format!(
+            "q must have 1 to {} characters",
+            MAX_QUERY_LENGTH
+        )));
+    }
+    Ok(text)
+}
+
+/// Handles GET requests to search the users and products by name.
+///
+/// # Route
+///
+/// `GET /search?q=&limit=`
+///
+/// - `q`: The text searched, at most 100 characters
+/// - `limit`: Number of users, and of products (default 10, max 50)
+///
+/// # Response
+///
+/// - 200 OK with `{"users": [...], "products": [...], "engine"}`, the best first:
+///   found by the search engine (`SEARCH_ENGINE`), or by their name in the database
+///   without one or while it's down (`engine` is `database`)
+/// - 400 Bad Request if `q` is missing, blank or too long, or `limit` is invalid
+pub async fn handle_search<B>(req: Request<B>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let query = query::parse::<SearchQuery, _>(&req)?;
+    let text = text(&query)?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+
+    let results = search_engine::search(db, text.trim(), limit).await?;
+    Ok(json_response(StatusCode::OK, results))
+}
+
+/// Handles GET requests for the suggestions of a search box as the user types.
+///
+/// # Route
+///
+/// `GET /search/suggest?q=&limit=`
+///
+/// - `q`: The text typed so far, at most 100 characters; its last word is completed
+///   unless it ends with a space
+/// - `limit`: Number of completions (default 5, max 20)
+///
+/// # Response
+///
+/// - 200 OK with `{"completions": [{text, frequency}...], "did_you_mean"}`: the query
+///   with its last word completed by the words of the product and user names, the most
+///   frequent first, and the query with its misspelled words corrected (`null` if none
+///   is)
+/// - 400 Bad Request if `q` is missing, blank or too long, or `limit` is invalid
+pub async fn handle_suggest<B>(req: Request<B>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let query = query::parse::<SearchQuery, _>(&req)?;
+    let text = text(&query)?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SUGGEST_LIMIT)
+        .clamp(1, MAX_SUGGEST_LIMIT);
+
+    let suggestions = suggestions::suggest(db, text, limit).await?;
+    Ok(json_response(StatusCode::OK, suggestions))
+}
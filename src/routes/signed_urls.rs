@@ -0,0 +1,99 @@
+//! Signed URLs minted for the caller (see the `signed_urls` module).
+
+use std::time::Duration;
+
+use hyper::http::uri::PathAndQuery;
+use hyper::{Request, StatusCode, body::Incoming};
+use serde::Deserialize;
+
+use crate::context::RequestContext;
+use crate::router::{HandlerResult, Params, json_response, parse_validated_body, router};
+use crate::signed_urls;
+use crate::validation::{Validate, ValidationErrors};
+
+/// Lifetime of the signed URLs asked without `expires_in`, in seconds
+const DEFAULT_LIFETIME_SECS: u64 = 900;
+
+#[derive(Deserialize, Debug)]
+struct SignRequest {
+    path: String,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+impl SignRequest {
+    fn lifetime(&self) -> Duration {
+        Duration::from_secs(self.expires_in.unwrap_or(DEFAULT_LIFETIME_SECS))
+    }
+}
+
+impl Validate for SignRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        match self.path.parse::<PathAndQuery>() {
+            Ok(path) if self.path.starts_with('/') => {
+                errors.check(
+                    "path",
+                    router().accepts_signed_url(path.path()),
+                    "must be the path of a route accepting signed URLs",
+                );
+                let reserved = path.query().is_some_and(|query| {
+                    serde_urlencoded::from_str::<Vec<(String, String)>>(query).map_or(
+                        true,
+                        |params| {
+                            params
+                                .iter()
+                                .any(|(name, _)| signed_urls::PARAMS.contains(&name.as_str()))
+                        },
+                    )
+                });
+                errors.check(
+                    "path",
+                    !reserved,
+                    format!(
+                        "must have a valid query without {}",
+                        signed_urls::PARAMS.join(", ")
+                    ),
+                );
+            }
+            _ => errors.check(
+                "path",
+                false,
+                "must be an absolute path, with its query if any",
+            ),
+        }
+        let max = signed_urls::max_lifetime();
+        errors.check(
+            "expires_in",
+            !self.lifetime().is_zero() && self.lifetime() <= max,
+            format!("must be between 1 and {} seconds", max.as_secs()),
+        );
+        errors.into_result()
+    }
+}
+
+/// Handles POST requests signing a URL for the caller.
+///
+/// # Route
+///
+/// `POST /signed-urls` (requires authentication)
+///
+/// # Request Body
+/// JSON object with `path`, the path and query of a route accepting signed URLs
+/// (`/admin/export/parquet?table=users`), and optionally `expires_in`, its lifetime in
+/// seconds (default 900, at most `SIGNED_URL_MAX_LIFETIME`)
+///
+/// # Response
+///
+/// - 200 OK with `{url, expires}`, the URL valid without access token until `expires`
+///   (seconds since the Unix epoch) with the rights of the caller
+/// - 400 Bad Request if the JSON is invalid
+/// - 401 Unauthorized without a valid access token
+/// - 422 Unprocessable Entity if the path doesn't accept signed URLs or the lifetime
+///   is out of range
+pub async fn handle_sign_url(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let user = RequestContext::of(&req).caller()?;
+    let data = parse_validated_body::<SignRequest>(req).await?;
+    let signed = signed_urls::sign(&data.path, user.id, data.lifetime())?;
+    Ok(json_response(StatusCode::OK, signed))
+}
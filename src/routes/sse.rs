@@ -0,0 +1,70 @@
+//! Server-Sent Events route streaming the change notifications of the `events` module,
+//! for clients that can't use the WebSocket route.
+
+use std::time::Duration;
+
+use futures_util::stream;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Request, Response, StatusCode, body::Bytes, body::Incoming};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{MissedTickBehavior, interval};
+
+use crate::events;
+use crate::router::{Body, BoxError, HandlerResult, Params, json_response};
+use crate::shutdown::stopping;
+
+/// Comments are sent this often when nothing happens, so proxies don't close the stream
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Handles GET requests to receive change notifications as Server-Sent Events.
+///
+/// # Route
+///
+/// `GET /events`
+///
+/// # Response
+///
+/// 200 OK with a `text/event-stream` body that stays open. Every change is sent as
+/// `event: change` with `data: {"type", "version", "data"}` (see the `events`
+/// module), a client that falls behind receives `event: lagged` with the number of
+/// lost events. A `: keep-alive` comment is sent every 15 seconds without changes.
+pub async fn handle_events(_req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let mut keep_alive = interval(KEEP_ALIVE_INTERVAL);
+    keep_alive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick is immediate and flushes the headers to the client
+    let state = (events::subscribe(), keep_alive);
+
+    let events = stream::unfold(state, |(mut events, mut keep_alive)| async move {
+        let chunk = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => format!("event: change\ndata: {}\n\n", event.to_json()),
+                Err(RecvError::Lagged(lost)) => format!("event: lagged\ndata: {}\n\n", lost),
+                Err(RecvError::Closed) => return None,
+            },
+            _ = keep_alive.tick() => ": keep-alive\n\n".to_string(),
+            // Ends the response so the connection can be drained
+            _ = stopping() => return None,
+        };
+        Some((Ok::<_, BoxError>(Bytes::from(chunk)), (events, keep_alive)))
+    });
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(Body::stream(events))
+        .unwrap())
+}
+
+/// Handles GET requests to retrieve the schemas of the event payloads.
+///
+/// # Route
+///
+/// `GET /events/schemas`
+///
+/// # Response
+///
+/// 200 OK with every version of every event type:
+/// `[{"event_type", "version", "fields": [{"name", "kind", "required"}]}]`
+pub async fn handle_event_schemas(_req: Request<Incoming>, _params: Params) -> HandlerResult {
+    Ok(json_response(StatusCode::OK, events::schemas()))
+}
@@ -0,0 +1,24 @@
+//! Files of `STATIC_DIR`, a frontend shipped with the service (see the `static_files`
+//! module).
+
+use hyper::{Request, body::Incoming};
+
+use crate::router::{HandlerResult, Params};
+use crate::static_files;
+
+/// Handles GET requests for a static file.
+///
+/// # Route
+///
+/// `GET /static/*path`
+///
+/// # Response
+///
+/// - 200 OK with the file, its `Content-Type`, `ETag` and `Last-Modified`
+/// - 206 Partial Content with the part of the file asked for in `Range`
+/// - 304 Not Modified if `If-None-Match` or `If-Modified-Since` match the file
+/// - 404 Not Found if the file doesn't exist, is hidden, or without `STATIC_DIR`
+/// - 416 Range Not Satisfiable if the range starts past the end of the file
+pub async fn handle_static_file(req: Request<Incoming>, params: Params) -> HandlerResult {
+    static_files::serve(&req, params.get("path").unwrap_or_default()).await
+}
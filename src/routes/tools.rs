@@ -0,0 +1,365 @@
+//! Tools of the API for LLM agents: a manifest of read-only routes with the JSON Schema
+//! of their arguments (`GET /api/v1/tools`), and an endpoint calling them with
+//! structured arguments (`POST /api/v1/tools/:name/call`).
+//!
+//! A tool is a `GET` route of `TOOLS`. Its description and the schema of its arguments
+//! are derived from the operation of the route in the OpenAPI document, so they follow
+//! the routes: the path and query parameters become the properties of the arguments,
+//! the path ones required. Streaming (`stream`) is left out, an agent reads pages.
+//!
+//! A call is checked against the schema of the tool, then runs the handler of the route
+//! on a request built from the arguments, with the access token of the caller: the
+//! tool sees what the caller can see, and answers like the route, errors included. On
+//! top of the limit of every request (`RATE_LIMIT_PER_MINUTE`), each user can call
+//! `TOOLS_RATE_LIMIT_PER_MINUTE` tools per minute.
+
+use std::sync::OnceLock;
+
+use hyper::header::{AUTHORIZATION, HeaderValue};
+use hyper::{Method, Request, StatusCode, Uri, body::Incoming};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use tracing::{info, warn};
+
+use crate::config::ToolsConfig;
+use crate::context::RequestContext;
+use crate::error::{AppError, ErrorCode};
+use crate::router::rate_limit::{
+    MemoryStore, Quota, RateLimitStore, apply_rate_limit_headers, rate_limited,
+};
+use crate::router::{
+    Body, HandlerFuture, HandlerResult, Params, error_response, json_response, parse_json_body,
+};
+use crate::validation::ValidationErrors;
+
+use super::{docs, products, search, users};
+
+/// Query parameters of the routes the tools don't take
+const EXCLUDED_PARAMS: &[&str] = &["stream"];
+
+// Set once at startup
+static LIMITER: OnceLock<(Quota, MemoryStore)> = OnceLock::new();
+
+// Built on first use, from the OpenAPI document
+static MANIFEST: OnceLock<Vec<ToolDescription>> = OnceLock::new();
+
+/// A tool: a read-only route and its handler.
+struct Tool {
+    name: &'static str,
+    /// Pattern of the route, a `GET` one (`/api/v1/users/:id`)
+    path: &'static str,
+    call: fn(Request<()>, Params) -> HandlerFuture,
+}
+
+const TOOLS: &[Tool] = &[
+    Tool {
+        name: "list_users",
+        path: "/api/v1/users",
+        call: |req, params| Box::pin(users::handle_get_all_users(req, params)),
+    },
+    Tool {
+        name: "get_user",
+        path: "/api/v1/users/:id",
+        call: |req, params| Box::pin(users::handle_get_user(req, params)),
+    },
+    Tool {
+        name: "list_products",
+        path: "/api/v1/products",
+        call: |req, params| Box::pin(products::handle_get_all_products(req, params)),
+    },
+    Tool {
+        name: "search_products",
+        path: "/api/v1/products/semantic-search",
+        call: |req, params| Box::pin(products::handle_semantic_search(req, params)),
+    },
+    Tool {
+        name: "search",
+        path: "/api/v1/search",
+        call: |req, params| Box::pin(search::handle_search(req, params)),
+    },
+    Tool {
+        name: "get_product",
+        path: "/api/v1/products/:id",
+        call: |req, params| Box::pin(products::handle_get_product(req, params)),
+    },
+];
+
+/// A tool of the manifest.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ToolDescription {
+    name: &'static str,
+    description: String,
+    /// JSON Schema of the arguments
+    input_schema: Value,
+    /// Route the tool calls (`GET /api/v1/users/:id`)
+    route: String,
+    /// Names of the path parameters among the arguments
+    #[serde(skip)]
+    path_params: Vec<String>,
+}
+
+/// Body of `POST /api/v1/tools/:name/call`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct CallRequest {
+    #[serde(default)]
+    arguments: Map<String, Value>,
+}
+
+/// Sets the rate limit of the tool calls.
+/// This function should be called once at application startup.
+pub(crate) fn init_tools(config: &ToolsConfig) {
+    let quota = Quota {
+        per_minute: config.rate_limit_per_minute,
+    };
+    if LIMITER.set((quota, MemoryStore::default())).is_err() {
+        warn!("Attempt to reset the tools rate limit ignored");
+    }
+}
+
+/// The description of `tool`, from the operation of its route.
+fn describe(tool: &Tool) -> ToolDescription {
+    let operation = docs::operation("get", tool.path).unwrap_or(&Value::Null);
+    let description = match (
+        operation["summary"].as_str(),
+        operation["description"].as_str(),
+    ) {
+        (Some(summary), Some(details)) => format!("{}\n\n{}", summary, details),
+        (summary, _) => summary.unwrap_or_default().to_string(),
+    };
+
+    let mut properties = Map::new();
+    let mut path_params = Vec::new();
+    let parameters = operation["parameters"]
+        .as_array()
+        .map_or(&[][..], Vec::as_slice);
+    for param in parameters {
+        let (Some(name), Some(location)) = (param["name"].as_str(), param["in"].as_str()) else {
+            continue;
+        };
+        if !matches!(location, "path" | "query") || EXCLUDED_PARAMS.contains(&name) {
+            continue;
+        }
+        let mut schema = param["schema"].clone();
+        if let Some(text) = param["description"].as_str() {
+            schema["description"] = text.into();
+        }
+        if location == "path" {
+            path_params.push(name.to_string());
+        }
+        properties.insert(name.to_string(), schema);
+    }
+
+    ToolDescription {
+        name: tool.name,
+        description,
+        input_schema: json!({
+            "type": "object",
+            "properties": properties,
+            "required": path_params,
+            "additionalProperties": false,
+        }),
+        route: format!("GET {}", tool.path),
+        path_params,
+    }
+}
+
+fn manifest() -> &'static [ToolDescription] {
+    MANIFEST.get_or_init(|| TOOLS.iter().map(describe).collect())
+}
+
+/// Whether `value` matches the type of `schema`, a parameter schema of the document.
+fn matches_schema(schema: &Value, value: &Value) -> bool {
+    if let Some(values) = schema["enum"].as_array() {
+        return values.contains(value);
+    }
+    match schema["type"].as_str() {
+        Some("string") => value.is_string(),
+        Some("integer") => value.is_i64() || value.is_u64(),
+        Some("number") => value.is_number(),
+        Some("boolean") => value.is_boolean(),
+        // The filters, sent as one query parameter per property
+        Some("object") => value
+            .as_object()
+            .is_some_and(|object| object.values().all(|v| v.is_string() || v.is_number())),
+        _ => true,
+    }
+}
+
+/// A value as a query or path parameter.
+fn parameter_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// `text` percent-encoded as a path segment.
+fn path_segment(text: &str) -> String {
+    text.bytes()
+        .map(|byte| {
+            if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                char::from(byte).to_string()
+            } else {
+                format!("%{:02X}", byte)
+            }
+        })
+        .collect()
+}
+
+/// The request calling `tool` with `arguments`, with the access token of the caller.
+///
+/// # Returns
+///
+/// * `Result<(Request<()>, Params), AppError>` - The request and its path parameters,
+///   or `AppError::Unprocessable` listing the missing, unknown and invalid arguments
+fn build_request(
+    tool: &Tool,
+    description: &'static ToolDescription,
+    arguments: &Map<String, Value>,
+    authorization: Option<&HeaderValue>,
+) -> Result<(Request<()>, Params), AppError> {
+    let properties = description.input_schema["properties"]
+        .as_object()
+        .expect("The input schema has properties");
+    let mut errors = ValidationErrors::default();
+    for name in &description.path_params {
+        errors.check(name.as_str(), arguments.contains_key(name), "is required");
+    }
+    let mut path = tool.path.to_string();
+    let mut path_params = Vec::new();
+    let mut query = Vec::new();
+    for (name, value) in arguments {
+        let Some((key, schema)) = properties.get_key_value(name) else {
+            errors.check(
+                "arguments",
+                false,
+                format!("'{}' is not an argument of the tool", name),
+            );
+            continue;
+        };
+        if !matches_schema(schema, value) {
+            errors.check(
+                key.as_str(),
+                false,
+                "doesn't match the schema of the argument",
+            );
+            continue;
+        }
+        if description.path_params.contains(name) {
+            let text = parameter_text(value);
+            path = path.replace(&format!(":{}", name), &path_segment(&text));
+            path_params.push((name.clone(), text));
+        } else if let Value::Object(filters) = value {
+            query.extend(filters.iter().map(|(k, v)| (k.clone(), parameter_text(v))));
+        } else {
+            query.push((name.clone(), parameter_text(value)));
+        }
+    }
+    errors.into_result()?;
+
+    let query =
+        serde_urlencoded::to_string(&query).map_err(|e| AppError::Internal(e.to_string()))?;
+    let uri: Uri = if query.is_empty() {
+        path.parse()
+    } else {
+        format!("{}?{}", path, query).parse()
+    }
+    .map_err(|e| AppError::Validation(format!("Invalid arguments: {}", e)))?;
+
+    let mut builder = Request::builder().method(Method::GET).uri(uri);
+    if let Some(authorization) = authorization {
+        builder = builder.header(AUTHORIZATION, authorization);
+    }
+    let req = builder
+        .body(())
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    Ok((req, path_params.into_iter().collect()))
+}
+
+/// Handles GET requests to list the tools.
+///
+/// # Route
+///
+/// `GET /api/v1/tools`
+///
+/// # Response
+///
+/// - 200 OK with `{"tools": [{name, description, inputSchema, route}...]}`, the
+///   `inputSchema` being the JSON Schema of the arguments of the tool
+pub async fn handle_list_tools(_req: Request<Incoming>, _params: Params) -> HandlerResult {
+    Ok(json_response(StatusCode::OK, json!({"tools": manifest()})))
+}
+
+/// Handles POST requests calling a tool.
+///
+/// # Route
+///
+/// `POST /api/v1/tools/:name/call`
+///
+/// # Request Body
+/// JSON object with `arguments`, matching the `inputSchema` of the tool
+///
+/// # Response
+///
+/// - 200 OK with `{"tool", "result"}`, `result` being the body of the route
+/// - 400 Bad Request if the JSON is invalid
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if no tool has this name, or the route answers 404
+/// - 422 Unprocessable Entity if an argument is missing, unknown or doesn't match its
+///   schema
+/// - 429 Too Many Requests past `TOOLS_RATE_LIMIT_PER_MINUTE` calls, with `Retry-After`
+/// - Any other error of the route, with its status and code
+pub async fn handle_call_tool(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let name = params.get("name").unwrap_or_default().to_string();
+    let position = TOOLS
+        .iter()
+        .position(|tool| tool.name == name)
+        .ok_or_else(|| {
+            AppError::NotFound(ErrorCode::ToolNotFound, format!("No tool named '{}'", name))
+        })?;
+    let context = RequestContext::of(&req).clone();
+    let user = context.caller()?;
+    if let Some((quota, store)) = LIMITER.get() {
+        let decision = store.acquire(&format!("user:{}", user.id), *quota).await?;
+        if !decision.allowed {
+            let mut res = error_response(rate_limited(&decision));
+            apply_rate_limit_headers(&decision, &mut res);
+            return Ok(res);
+        }
+    }
+    let authorization = req.headers().get(AUTHORIZATION).cloned();
+    let data = parse_json_body::<CallRequest>(req).await?;
+
+    let (tool, description) = (&TOOLS[position], &manifest()[position]);
+    let (mut call, path_params) =
+        build_request(tool, description, &data.arguments, authorization.as_ref())?;
+    // The route runs for the same caller, within the same deadline
+    call.extensions_mut().insert(context);
+    info!("Tool '{}' called by user {}", tool.name, user.id);
+    let res = (tool.call)(call, path_params).await?;
+
+    if !res.status().is_success() {
+        return Err(AppError::Internal(format!(
+            "The route of the tool '{}' answered {}",
+            tool.name,
+            res.status()
+        )));
+    }
+    let result = match res.into_body() {
+        Body::Buffered(text) if text.is_empty() => Value::Null,
+        Body::Buffered(text) => serde_json::from_str(&text)
+            .map_err(|e| AppError::Internal(format!("Invalid response of the route: {}", e)))?,
+        Body::Stream(_) => {
+            return Err(AppError::Internal(format!(
+                "The route of the tool '{}' streamed its response",
+                tool.name
+            )));
+        }
+    };
+    Ok(json_response(
+        StatusCode::OK,
+        json!({"tool": tool.name, "result": result}),
+    ))
+}
@@ -0,0 +1,814 @@
+use futures_util::{TryFutureExt, TryStreamExt};
+use hyper::{
+    Request, Response, StatusCode,
+    body::Incoming,
+    header::{
+        CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, HeaderValue, LOCATION, X_CONTENT_TYPE_OPTIONS,
+    },
+};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::warn;
+
+use crate::auth::authenticate;
+use crate::cache;
+use crate::computed::{Resource, all_with_fields, with_fields};
+use crate::context::RequestContext;
+use crate::db::{Db, join_queries};
+use crate::error::{AppError, ErrorCode};
+use crate::events::{self, Action, Collection};
+use crate::jobs::{self, Job};
+use crate::repository::audit::{AuditRepository, Entity, PgAuditRepo};
+use crate::repository::filter::{Filter, FilterField};
+use crate::repository::ids::{ResourceId, resolve};
+use crate::repository::jobs::{JobRepository, PgJobRepo};
+use crate::repository::users::{ModifiedSince, PgUserRepo, User, UserPatch, UserRepository};
+use crate::repository::versions::collection_version;
+use crate::roles::{self, Role};
+use crate::router::bulk::{BulkItems, MAX_BULK_ITEMS};
+use crate::router::conditional::{
+    Versioned, collection_etag, if_match, not_modified, version_etag, with_etag,
+};
+use crate::router::limits::max_upload_size;
+use crate::router::multipart::Multipart;
+use crate::router::query::{self, ListQuery};
+use crate::router::{
+    Body, BoxError, HandlerResult, Params, empty_response, json_response, json_stream_response,
+    parse_validated_body,
+};
+use crate::storage::{ObjectStore, store};
+use crate::validation::{Validate, ValidationErrors, check_age, check_name};
+
+use super::views;
+
+// ==================== USER ROUTES ====================
+
+impl Validate for User {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        check_name(&mut errors, "name", &self.name);
+        check_age(&mut errors, "age", self.age);
+        errors.into_result()
+    }
+}
+
+impl Versioned for User {
+    fn version(&self) -> i32 {
+        self.version
+    }
+}
+
+impl Validate for UserPatch {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if let Some(name) = &self.name {
+            check_name(&mut errors, "name", name);
+        }
+        if let Some(age) = self.age {
+            check_age(&mut errors, "age", age);
+        }
+        errors.into_result()
+    }
+}
+
+/// Extracts the `:id` path parameter of the user routes.
+///
+/// # Returns
+///
+/// * `Result<i32, AppError>` - The key of the user (see `repository::ids`), a
+///   validation error if the ID is not a UUID (or an integer), or not found if no user
+///   has it
+pub(super) async fn parse_user_id(db: Db, params: &Params) -> Result<i32, AppError> {
+    let id = params
+        .parse::<ResourceId>("id")
+        .ok_or_else(ResourceId::invalid)?;
+    resolve(db, Collection::Users, id)
+        .await?
+        .ok_or_else(user_not_found)
+}
+
+/// Error returned when no user has the requested ID.
+fn user_not_found() -> AppError {
+    AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string())
+}
+
+/// Image types accepted as avatars, with the extension of the stored file
+const AVATAR_TYPES: &[(&str, &str)] = &[
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+];
+
+/// Form field of the avatar file in `POST /users/:id/avatar`
+const AVATAR_FIELD: &str = "avatar";
+
+/// Columns users can be sorted by (`?sort=`), the first one is the default
+pub(super) const SORTABLE_COLUMNS: &[&str] = &["id", "name", "age"];
+
+/// Columns users can be filtered by (`?name_like=ann&age_gte=18`)
+pub(crate) const FILTERABLE_FIELDS: &[FilterField] = &[
+    FilterField::uuid("id", "public_id"),
+    FilterField::text("name"),
+    FilterField::integer("age"),
+];
+
+/// Body of `POST /users/bulk-delete`.
+#[derive(Deserialize, Debug)]
+struct BulkDeleteRequest {
+    /// Filter expression of the users to delete (see `repository::filter`)
+    filter: String,
+}
+
+impl Validate for BulkDeleteRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check(
+            "filter",
+            !self.filter.trim().is_empty(),
+            "must not be empty",
+        );
+        errors.into_result()
+    }
+}
+
+/// `?modified_since=` of the user list, asking for a delta instead of a page.
+#[derive(Deserialize, Default, Debug)]
+struct DeltaQuery {
+    modified_since: Option<String>,
+}
+
+impl DeltaQuery {
+    /// A number is a version from a previous delta, anything else a timestamp.
+    fn modified_since(&self) -> Option<ModifiedSince> {
+        let value = self.modified_since.as_deref()?.trim();
+        Some(match value.parse::<i64>() {
+            Ok(version) => ModifiedSince::Version(version),
+            Err(_) => ModifiedSince::Timestamp(value.to_string()),
+        })
+    }
+}
+
+/// `?include_deleted=` of the user list and of a user, asking for the soft-deleted
+/// users too.
+#[derive(Deserialize, Default, Debug)]
+struct IncludeDeletedQuery {
+    include_deleted: Option<bool>,
+}
+
+impl IncludeDeletedQuery {
+    /// Whether the request asks for the soft-deleted users, which are only shown to
+    /// the callers with an access token.
+    fn parse<B>(req: &Request<B>) -> Result<bool, AppError> {
+        let include = query::parse::<Self, _>(req)?.include_deleted == Some(true);
+        if include {
+            authenticate(req)?;
+        }
+        Ok(include)
+    }
+}
+
+/// `?hard=` of `DELETE /users/:id`, deleting the user for good.
+#[derive(Deserialize, Default, Debug)]
+struct HardDeleteQuery {
+    hard: Option<bool>,
+}
+
+/// Body of `PUT /admin/users/:id/role`.
+#[derive(Deserialize, Debug)]
+struct RoleRequest {
+    role: String,
+}
+
+impl Validate for RoleRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check(
+            "role",
+            self.role.parse::<Role>().is_ok(),
+            "must be viewer, editor or admin",
+        );
+        errors.into_result()
+    }
+}
+
+/// `?as_of=` of a user, asking for the state it had at a past moment.
+#[derive(Deserialize, Default, Debug)]
+struct AsOfQuery {
+    as_of: Option<String>,
+}
+
+/// Handles GET requests to retrieve a page of users.
+///
+/// # Route
+///
+/// `GET /users?limit=&offset=&sort=&order=&stream=&view=&include_deleted=&<filters>` or
+/// `GET /users?modified_since=`
+///
+/// - `limit`: Page size (default 20, max 100)
+/// - `offset`: Number of users to skip (default 0)
+/// - `sort`: `id` (default), `name` or `age`
+/// - `order`: `asc` (default) or `desc`
+/// - Filters: `<field>_<operator>=<value>` on `id`, `name` and `age`, such as
+///   `name_like=ann` or `age_gte=18`, or a `filter` expression such as
+///   `age>=18 AND name~"ann"` (see `repository::filter`)
+/// - `view`: Name of a saved view of the caller (requires an access token), adding
+///   its filter and sort (see `routes::views`)
+/// - `include_deleted`: `true` to list the soft-deleted users too, with their
+///   `deleted_at` (requires an access token)
+/// - `stream`: `true` to receive every user, sorted, in a streamed JSON array
+///   (`limit` and `offset` are ignored, the body is neither converted nor compressed)
+/// - `modified_since`: The `version` of a previous delta, or a timestamp
+///   (`2025-01-31T12:00:00Z`). Returns only what changed since then, unpaginated
+///
+/// # Response
+///
+/// - 200 OK with `{"data": [...], "pagination": {total, limit, offset, next, prev}}`
+///   and an `ETag` header
+/// - 200 OK with `[...]` when `stream=true`
+/// - 200 OK with `{"data": [{id, name, age}...], "deleted": [ids...], "version"}`
+///   when `modified_since` is given
+/// - 304 Not Modified if `If-None-Match` matches the current `ETag`
+/// - 400 Bad Request if a query parameter is invalid
+/// - 401 Unauthorized if `view` or `include_deleted` is given without a valid access
+///   token
+/// - 404 Not Found if the caller has no view named `view`
+pub async fn handle_get_all_users<B>(req: Request<B>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let mut list = query::parse::<ListQuery, _>(&req)?;
+    let mut filter = query::filter(&req, FILTERABLE_FIELDS)?;
+    if IncludeDeletedQuery::parse(&req)? {
+        filter.include_deleted();
+    }
+    let view = views::apply_view(&req, &views::USERS, &mut list, &mut filter).await?;
+    let page = list.pagination(SORTABLE_COLUMNS)?;
+    let since = query::parse::<DeltaQuery, _>(&req)?.modified_since();
+    if since.is_some() && !filter.is_empty() {
+        return Err(AppError::Validation(
+            "Filters can't be combined with modified_since".to_string(),
+        ));
+    }
+
+    // Polling clients that already have this version get a 304 without reading the rows
+    let etag = collection_etag("users", collection_version(db, "users").await?, &req, &view);
+    if let Some(res) = not_modified(&req, &etag) {
+        return Ok(res);
+    }
+
+    if list.stream == Some(true) {
+        let items = PgUserRepo(db)
+            .stream_all(&page, &filter)
+            .map_ok(|users| all_with_fields(Resource::Users, users));
+        return Ok(with_etag(json_stream_response(items), &etag));
+    }
+
+    if let Some(since) = since {
+        let changes = PgUserRepo(db).changes_since(&since).await?;
+        return Ok(with_etag(json_response(StatusCode::OK, changes), &etag));
+    }
+
+    // Independent queries, run at the same time on two connections
+    let (total, users) = join_queries!(
+        PgUserRepo(db).count(&filter),
+        PgUserRepo(db).list(&page, &filter)
+    )?;
+
+    Ok(with_etag(
+        json_response(
+            StatusCode::OK,
+            page.page(
+                req.uri().path(),
+                &filter,
+                all_with_fields(Resource::Users, users),
+                total,
+            ),
+        ),
+        &etag,
+    ))
+}
+
+/// Handles GET requests to retrieve a specific user by ID.
+///
+/// # Route
+///
+/// `GET /users/:id?as_of=&include_deleted=` where `:id` is the UUID of the user
+///
+/// - `as_of`: A timestamp (`2025-01-31T12:00:00Z`) to get the user as it was then,
+///   rebuilt from the audit log, even if it was deleted since (requires an access token)
+/// - `include_deleted`: `true` to get the user even if soft-deleted, with its
+///   `deleted_at` (requires an access token)
+///
+/// # Response
+///
+/// - 200 OK with user data and its `ETag` if the user exists (possibly cached)
+/// - 200 OK with the user as it was at `as_of`, without `ETag`
+/// - 304 Not Modified if `If-None-Match` matches the current `ETag`
+/// - 400 Bad Request if the ID is not a UUID or `as_of` not a timestamp
+/// - 401 Unauthorized if `as_of` or `include_deleted` is given without a valid access
+///   token
+/// - 404 Not Found if the user does not exist (or didn't at `as_of`), or is
+///   soft-deleted without `include_deleted`
+pub async fn handle_get_user<B>(req: Request<B>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    if let Some(as_of) = query::parse::<AsOfQuery, _>(&req)?.as_of {
+        return get_user_as_of(&req, &params, &as_of).await;
+    }
+
+    // Extract and validate the ID from the URL
+    let id = parse_user_id(db, &params).await?;
+
+    // The cache only holds the users that aren't deleted
+    if IncludeDeletedQuery::parse(&req)? {
+        let user = PgUserRepo(db)
+            .find_any(id)
+            .await?
+            .ok_or_else(user_not_found)?;
+        let etag = version_etag(user.version);
+        if let Some(res) = not_modified(&req, &etag) {
+            return Ok(res);
+        }
+        return Ok(with_etag(
+            json_response(StatusCode::OK, with_fields(Resource::Users, user)),
+            &etag,
+        ));
+    }
+
+    let repo = PgUserRepo(db);
+    let load = repo
+        .find_by_id(id)
+        .map_ok(|user| user.map(|user| with_fields(Resource::Users, user)));
+    let user = cache::get_or_load(Collection::Users, id, load)
+        .await?
+        .ok_or_else(user_not_found)?;
+
+    if let Some(res) = not_modified(&req, &user.etag) {
+        return Ok(res);
+    }
+    Ok(user.into_response())
+}
+
+/// The user of `GET /users/:id?as_of=`.
+async fn get_user_as_of<B>(req: &Request<B>, params: &Params, as_of: &str) -> HandlerResult {
+    let db = RequestContext::of(req).db;
+    // Past states are as private as the history they come from
+    authenticate(req)?;
+    let id = params
+        .parse::<ResourceId>("id")
+        .ok_or_else(ResourceId::invalid)?;
+    // A deleted user is found by the record of its delete
+    let key = match (resolve(db, Collection::Users, id).await?, id) {
+        (Some(key), _) => key,
+        (None, ResourceId::Public(uuid)) => PgAuditRepo(db)
+            .deleted_key(Entity::User, uuid)
+            .await?
+            .ok_or_else(user_not_found)?,
+        (None, ResourceId::Key(_)) => return Err(user_not_found()),
+    };
+
+    let user = PgUserRepo(db)
+        .find_as_of(key, as_of.trim())
+        .await?
+        .ok_or_else(user_not_found)?;
+    Ok(json_response(
+        StatusCode::OK,
+        with_fields(Resource::Users, user),
+    ))
+}
+
+/// Handles POST requests to create a new user.
+///
+/// # Route
+///
+/// `POST /users`
+///
+/// # Request Body
+/// JSON object with `name` and `age`
+///
+/// # Response
+///
+/// - 200 OK with the ID of the user if it was inserted
+/// - 400 Bad Request if the JSON is malformed or body collection fails
+/// - 422 Unprocessable Entity if a field has an invalid value
+/// - 500 Internal Server Error if the insert fails
+pub async fn handle_create_user(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let data = parse_validated_body::<User>(req).await?;
+
+    let (id, public_id) = PgUserRepo(db).create(&data).await?;
+    events::publish(Collection::Users, Action::Created, id, public_id);
+
+    Ok(json_response(
+        StatusCode::OK,
+        json!({"message": "User added", "id": public_id}),
+    ))
+}
+
+/// Handles POST requests to create many users at once.
+///
+/// # Route
+///
+/// `POST /users/bulk`
+///
+/// # Request Body
+/// JSON array of objects with `name` and `age`, or the same objects one per line with
+/// `Content-Type: application/x-ndjson` (at most 10000, see `router::bulk`)
+///
+/// # Response
+///
+/// - 200 OK with `{created, failed, results: [{index, status, data: {id}} or
+///   {index, status, error}...]}`; the valid users are created even if others are not
+/// - 400 Bad Request if the body isn't a JSON array or holds no users
+/// - 413 Payload Too Large if the body exceeds `MAX_UPLOAD_SIZE` or holds too many users
+/// - 500 Internal Server Error if the insert fails (no user is created)
+pub async fn handle_create_users(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let users = BulkItems::<User>::from_request(req).await?;
+
+    let ids = PgUserRepo(db).create_many(&users.valid).await?;
+    for &(id, public_id) in &ids {
+        events::publish(Collection::Users, Action::Created, id, public_id);
+    }
+
+    let created = ids
+        .into_iter()
+        .map(|(_, public_id)| json!({"id": public_id}))
+        .collect();
+    Ok(users.into_response(created))
+}
+
+/// Handles POST requests to soft-delete the users matching a filter, in the background.
+///
+/// The users are soft-deleted by a job, in batches of their own transactions, so
+/// neither the request nor the locks last as long as the whole deletion. Each of them
+/// can be restored as any other.
+///
+/// # Route
+///
+/// `POST /users/bulk-delete`, with the admin role
+///
+/// # Request Body
+/// JSON object with `filter`, a filter expression as `?filter=` of the list
+/// (`age<18 AND name~"test"`), matching between 1 and 10000 users
+///
+/// # Response
+///
+/// - 202 Accepted with the operation (`Location: /api/v1/operations/:id`), whose
+///   `progress` counts the `deleted` users out of the `total`
+/// - 400 Bad Request if the JSON or the filter expression is invalid
+/// - 422 Unprocessable Entity if the filter is empty, or matches no user or too many
+pub async fn handle_bulk_delete_users(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let caller = RequestContext::of(&req).caller()?;
+    let data = parse_validated_body::<BulkDeleteRequest>(req).await?;
+    let mut filter = Filter::default();
+    filter.add_expression(&data.filter, FILTERABLE_FIELDS)?;
+
+    let total = PgUserRepo(db).count(&filter).await?;
+    let mut errors = ValidationErrors::default();
+    errors.check("filter", total > 0, "matches no user");
+    errors.check(
+        "filter",
+        total <= MAX_BULK_ITEMS as i64,
+        format!(
+            "matches {} users, at most {} can be deleted at once",
+            total, MAX_BULK_ITEMS
+        ),
+    );
+    errors.into_result()?;
+
+    let id = jobs::submit(Job::BulkDeleteUsers {
+        filter: data.filter,
+        total,
+        actor_id: Some(caller.id),
+    })
+    .await?;
+    let operation = PgJobRepo(db)
+        .find(id)
+        .await?
+        .ok_or_else(|| AppError::Internal(format!("Job {} vanished", id)))?;
+
+    let mut res = json_response(StatusCode::ACCEPTED, operation);
+    let location = format!("/api/v1/operations/{}", id);
+    if let Ok(location) = HeaderValue::from_str(&location) {
+        res.headers_mut().insert(LOCATION, location);
+    }
+    Ok(res)
+}
+
+/// Handles PUT requests to replace all the fields of a user.
+///
+/// # Route
+///
+/// `PUT /users/:id` with `If-Match` set to the `ETag` of the user (or `*`)
+///
+/// # Request Body
+/// JSON object with `name` and `age` (both required)
+///
+/// # Response
+///
+/// - 200 OK with the updated user and its new `ETag`
+/// - 400 Bad Request if the ID or the JSON is invalid
+/// - 422 Unprocessable Entity if a field has an invalid value
+/// - 404 Not Found if the user does not exist
+/// - 412 Precondition Failed if the user changed since the version of `If-Match`
+/// - 428 Precondition Required without `If-Match`
+/// - 500 Internal Server Error if the update fails
+pub async fn handle_update_user(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = parse_user_id(db, &params).await?;
+    let versions = if_match(&req)?;
+    let data = parse_validated_body::<User>(req).await?;
+
+    let user = PgUserRepo(db)
+        .update(id, &data, versions.as_deref())
+        .await?
+        .ok_or_else(user_not_found)?;
+    events::publish(Collection::Users, Action::Updated, id, user.public_id);
+
+    let etag = version_etag(user.version);
+    Ok(with_etag(
+        json_response(StatusCode::OK, with_fields(Resource::Users, user)),
+        &etag,
+    ))
+}
+
+/// Handles PATCH requests to update some fields of a user.
+///
+/// # Route
+///
+/// `PATCH /users/:id` with `If-Match` set to the `ETag` of the user (or `*`)
+///
+/// # Request Body
+/// JSON object with any of `name` and `age`; missing fields keep their current value
+///
+/// # Response
+///
+/// - 200 OK with the updated user and its new `ETag`
+/// - 400 Bad Request if the ID or the JSON is invalid
+/// - 422 Unprocessable Entity if a field has an invalid value
+/// - 404 Not Found if the user does not exist
+/// - 412 Precondition Failed if the user changed since the version of `If-Match`
+/// - 428 Precondition Required without `If-Match`
+/// - 500 Internal Server Error if the update fails
+pub async fn handle_patch_user(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = parse_user_id(db, &params).await?;
+    let versions = if_match(&req)?;
+    let data = parse_validated_body::<UserPatch>(req).await?;
+
+    let user = PgUserRepo(db)
+        .patch(id, &data, versions.as_deref())
+        .await?
+        .ok_or_else(user_not_found)?;
+    events::publish(Collection::Users, Action::Updated, id, user.public_id);
+
+    let etag = version_etag(user.version);
+    Ok(with_etag(
+        json_response(StatusCode::OK, with_fields(Resource::Users, user)),
+        &etag,
+    ))
+}
+
+/// Handles DELETE requests to remove a user.
+///
+/// The user is soft-deleted: left out of the lists and missing for the other routes,
+/// but kept with their avatar to be restored (`POST /users/:id/restore`). `hard=true`
+/// deletes them for good, soft-deleted or not, and requires the admin role.
+///
+/// # Route
+///
+/// `DELETE /users/:id?hard=` with `If-Match` set to the `ETag` of the user (or `*`)
+///
+/// # Response
+///
+/// - 204 No Content if the user was deleted, along with their avatar when for good
+/// - 400 Bad Request if the ID is not a UUID
+/// - 403 Forbidden if `hard=true` is given by a caller without the admin role
+/// - 404 Not Found if the user does not exist, or is already soft-deleted without
+///   `hard=true`
+/// - 412 Precondition Failed if the user changed since the version of `If-Match`
+/// - 428 Precondition Required without `If-Match`
+/// - 500 Internal Server Error if the delete fails
+pub async fn handle_delete_user(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = parse_user_id(db, &params).await?;
+    let versions = if_match(&req)?;
+
+    if query::parse::<HardDeleteQuery, _>(&req)?.hard != Some(true) {
+        let user = PgUserRepo(db)
+            .soft_delete(id, versions.as_deref())
+            .await?
+            .ok_or_else(user_not_found)?;
+        events::publish(Collection::Users, Action::Deleted, id, user.public_id);
+        return Ok(empty_response(StatusCode::NO_CONTENT));
+    }
+    roles::require(db, RequestContext::of(&req).caller()?, Role::Admin).await?;
+
+    let user = PgUserRepo(db)
+        .delete(id, versions.as_deref())
+        .await?
+        .ok_or_else(user_not_found)?;
+    events::publish(Collection::Users, Action::Deleted, id, user.public_id);
+
+    if let Some(location) = user.avatar_path {
+        remove_avatar(&location).await;
+    }
+
+    Ok(empty_response(StatusCode::NO_CONTENT))
+}
+
+/// Handles POST requests to undelete a soft-deleted user.
+///
+/// # Route
+///
+/// `POST /users/:id/restore`
+///
+/// # Response
+///
+/// - 200 OK with the restored user and its new `ETag`
+/// - 400 Bad Request if the ID is not a UUID
+/// - 404 Not Found if the user does not exist (or was deleted for good)
+/// - 409 Conflict if the user isn't deleted
+pub async fn handle_restore_user(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = parse_user_id(db, &params).await?;
+
+    let user = PgUserRepo(db)
+        .restore(id)
+        .await?
+        .ok_or_else(user_not_found)?;
+    // Back for the clients that removed it
+    events::publish(Collection::Users, Action::Created, id, user.public_id);
+
+    let etag = version_etag(user.version);
+    Ok(with_etag(
+        json_response(StatusCode::OK, with_fields(Resource::Users, user)),
+        &etag,
+    ))
+}
+
+/// Handles PUT requests to set the role of a user (see `roles`).
+///
+/// # Route
+///
+/// `PUT /admin/users/:id/role`, with the admin role
+///
+/// # Request Body
+/// JSON object with `role`: `viewer`, `editor` or `admin`
+///
+/// # Response
+///
+/// - 200 OK with the ID and the new role of the user, effective at once
+/// - 400 Bad Request if the ID or the JSON is invalid
+/// - 404 Not Found if the user does not exist
+/// - 422 Unprocessable Entity if the role is unknown
+pub async fn handle_set_user_role(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = parse_user_id(db, &params).await?;
+    let data = parse_validated_body::<RoleRequest>(req).await?;
+    let role = data.role.parse::<Role>().map_err(AppError::Validation)?;
+
+    PgUserRepo(db)
+        .set_role(id, role)
+        .await?
+        .ok_or_else(user_not_found)?;
+    let user = PgUserRepo(db)
+        .find_by_id(id)
+        .await?
+        .ok_or_else(user_not_found)?;
+    Ok(json_response(
+        StatusCode::OK,
+        json!({"id": user.public_id, "role": role}),
+    ))
+}
+
+/// Handles POST requests to upload the avatar of a user.
+///
+/// # Route
+///
+/// `POST /users/:id/avatar`
+///
+/// # Request Body
+/// `multipart/form-data` with the image in the `avatar` field (PNG, JPEG, GIF or WebP,
+/// told by the `Content-Type` of the part). The file is streamed to the storage as it
+/// is received; other fields are ignored.
+///
+/// # Response
+///
+/// - 200 OK with the URL of the avatar, which replaces the previous one
+/// - 400 Bad Request if the ID is invalid or the body isn't valid multipart
+/// - 404 Not Found if the user does not exist
+/// - 413 Payload Too Large if the body exceeds `MAX_UPLOAD_SIZE`
+/// - 422 Unprocessable Entity if the `avatar` field is missing or not a supported image
+/// - 500 Internal Server Error if the file can't be stored
+pub async fn handle_upload_avatar(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = parse_user_id(db, &params).await?;
+
+    // Don't store anything for a user that doesn't exist
+    let user = PgUserRepo(db)
+        .find_by_id(id)
+        .await?
+        .ok_or_else(user_not_found)?;
+
+    let mut multipart = Multipart::from_request(req, max_upload_size())?;
+    let mut location = None;
+    while let Some(part) = multipart.next_part().await? {
+        if part.name.as_deref() != Some(AVATAR_FIELD) {
+            continue;
+        }
+        let content_type = part.content_type.as_deref().unwrap_or_default();
+        let Some((_, extension)) = AVATAR_TYPES
+            .iter()
+            .find(|(media_type, _)| content_type.eq_ignore_ascii_case(media_type))
+        else {
+            let mut errors = ValidationErrors::default();
+            errors.check(
+                AVATAR_FIELD,
+                false,
+                "must be a PNG, JPEG, GIF or WebP image",
+            );
+            return Err(AppError::Unprocessable(errors));
+        };
+
+        let key = format!("avatars/{}.{}", id, extension);
+        location = Some(store().put(&key, multipart.chunks()).await?);
+        break;
+    }
+    let Some(location) = location else {
+        let mut errors = ValidationErrors::default();
+        errors.check(AVATAR_FIELD, false, "is required");
+        return Err(AppError::Unprocessable(errors));
+    };
+
+    let Some(previous) = PgUserRepo(db).set_avatar(id, &location).await? else {
+        // Deleted while the file was being received
+        remove_avatar(&location).await;
+        return Err(user_not_found());
+    };
+    // An avatar of another type is stored under another key
+    if let Some(previous) = previous.filter(|previous| *previous != location) {
+        remove_avatar(&previous).await;
+    }
+    events::publish(Collection::Users, Action::Updated, id, user.public_id);
+
+    Ok(json_response(
+        StatusCode::OK,
+        json!({"avatar_url": format!("/api/v1/users/{}/avatar", user.public_id)}),
+    ))
+}
+
+/// Handles GET requests to download the avatar of a user.
+///
+/// # Route
+///
+/// `GET /users/:id/avatar`
+///
+/// # Response
+///
+/// - 200 OK with the image, streamed from the storage
+/// - 400 Bad Request if the ID is not a UUID
+/// - 404 Not Found if the user does not exist or has no avatar
+/// - 500 Internal Server Error if the file can't be read
+pub async fn handle_get_avatar(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let id = parse_user_id(db, &params).await?;
+    let avatar_not_found =
+        || AppError::NotFound(ErrorCode::AvatarNotFound, "Avatar not found".to_string());
+
+    let location = PgUserRepo(db)
+        .find_avatar(id)
+        .await?
+        .ok_or_else(avatar_not_found)?;
+    let Some(object) = store().get(&location).await? else {
+        warn!(
+            "Avatar of user {} missing from the storage: {}",
+            id, location
+        );
+        return Err(avatar_not_found());
+    };
+
+    let content_type = AVATAR_TYPES
+        .iter()
+        .find(|(_, extension)| location.ends_with(&format!(".{}", extension)))
+        .map_or("application/octet-stream", |(media_type, _)| media_type);
+    let body = object.chunks.map_err(BoxError::from);
+
+    let mut res = Response::new(Body::stream(body));
+    let headers = res.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(object.size));
+    // Served as an image even to browsers that would sniff the content
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    // Replaced in place by a new upload
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    Ok(res)
+}
+
+/// Deletes a file that is no longer referenced. A failure leaves an orphan file
+/// behind, which is logged rather than reported to the client.
+pub(super) async fn remove_avatar(location: &str) {
+    if let Err(e) = store().delete(location).await {
+        warn!("Unable to delete avatar {}: {}", location, e);
+    }
+}
@@ -0,0 +1,188 @@
+//! Saved views: named filter and sort combinations of the users and products lists,
+//! saved by each user and applied with `?view=<name>`.
+//!
+//! A view holds a filter expression (see `repository::filter`), a sort column and an
+//! order. Listing through a view adds its filter to the ones of the request, and its
+//! sort unless the request has its own. The pagination links carry the expression
+//! itself, so the following pages don't depend on the view any more.
+
+use hyper::{Request, StatusCode, body::Incoming};
+use serde::Deserialize;
+
+use crate::auth::authenticate;
+use crate::context::RequestContext;
+use crate::error::{AppError, ErrorCode};
+use crate::repository::filter::{Filter, FilterField};
+use crate::repository::views::{PgViewRepo, SavedView, ViewRepository};
+use crate::router::query::{self, ListQuery};
+use crate::router::{HandlerResult, Params, json_response, parse_json_body};
+use crate::validation::ValidationErrors;
+
+use super::{products, users};
+
+/// Longest name of a view
+const MAX_NAME_LEN: usize = 64;
+
+/// A list that can be read through saved views, with its whitelists.
+pub(super) struct ViewedList {
+    /// Name of the collection, which views are saved for
+    collection: &'static str,
+    sortable: &'static [&'static str],
+    fields: &'static [FilterField],
+}
+
+pub(super) const USERS: ViewedList = ViewedList {
+    collection: "users",
+    sortable: users::SORTABLE_COLUMNS,
+    fields: users::FILTERABLE_FIELDS,
+};
+
+pub(super) const PRODUCTS: ViewedList = ViewedList {
+    collection: "products",
+    sortable: products::SORTABLE_COLUMNS,
+    fields: products::FILTERABLE_FIELDS,
+};
+
+/// `?view=` of a list request.
+#[derive(Deserialize, Default, Debug)]
+struct ViewQuery {
+    view: Option<String>,
+}
+
+/// Applies the view named by `?view=` to a list request: its filter is added to
+/// `filter`, its sort to `query` unless the request sorts itself.
+///
+/// # Returns
+///
+/// * `Result<Option<SavedView>, AppError>` - The view, `None` without `?view=`;
+///   `AppError::Unauthorized` without an access token, `AppError::NotFound` if the
+///   caller has no view with this name
+pub(super) async fn apply_view<B>(
+    req: &Request<B>,
+    list: &ViewedList,
+    query: &mut ListQuery,
+    filter: &mut Filter,
+) -> Result<Option<SavedView>, AppError> {
+    let db = RequestContext::of(req).db;
+    let Some(name) = query::parse::<ViewQuery, _>(req)?.view else {
+        return Ok(None);
+    };
+    // Views are private, the list routes themselves are public
+    let user = authenticate(req)?;
+    let view = PgViewRepo(db)
+        .find(user.id, list.collection, &name)
+        .await?
+        .ok_or_else(|| {
+            AppError::NotFound(
+                ErrorCode::ViewNotFound,
+                format!("No saved view named '{}'", name),
+            )
+        })?;
+
+    if !view.filter.trim().is_empty() {
+        filter.add_expression(&view.filter, list.fields)?;
+    }
+    if query.sort.is_none() {
+        query.sort = view.sort.clone();
+        query.order = query.order.or(view.order);
+    }
+    Ok(Some(view))
+}
+
+/// Checks a view against the whitelists of its list.
+fn check_view(view: &SavedView, list: &ViewedList) -> Result<(), ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+    errors.check(
+        "name",
+        (1..=MAX_NAME_LEN).contains(&view.name.len())
+            && view
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        format!("must be 1 to {} letters, digits, '-' or '_'", MAX_NAME_LEN),
+    );
+    if !view.filter.trim().is_empty()
+        && let Err(AppError::Validation(message)) =
+            Filter::default().add_expression(&view.filter, list.fields)
+    {
+        errors.check("filter", false, message);
+    }
+    if let Some(sort) = &view.sort {
+        errors.check(
+            "sort",
+            list.sortable.contains(&sort.as_str()),
+            format!("must be one of: {}", list.sortable.join(", ")),
+        );
+    }
+    errors.into_result()
+}
+
+/// Saves a view of the caller for a list, replacing the one with the same name.
+async fn save_view(req: Request<Incoming>, list: &ViewedList) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let view = parse_json_body::<SavedView>(req).await?;
+    check_view(&view, list)?;
+    PgViewRepo(db).save(user.id, list.collection, &view).await?;
+    Ok(json_response(StatusCode::OK, view))
+}
+
+/// Lists the views of the caller for a list.
+async fn list_views(req: Request<Incoming>, list: &ViewedList) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let views = PgViewRepo(db).list(user.id, list.collection).await?;
+    Ok(json_response(StatusCode::OK, views))
+}
+
+/// Handles POST requests saving a view of the users list.
+///
+/// # Route
+///
+/// `POST /users/views`
+///
+/// # Request Body
+/// JSON object with `name`, and optionally `filter` (expression of `?filter=`), `sort`
+/// and `order`
+///
+/// # Response
+///
+/// - 200 OK with the view, which replaces the caller's view with the same name
+/// - 400 Bad Request if the JSON is malformed
+/// - 422 Unprocessable Entity if the name, filter or sort is invalid
+pub async fn handle_save_user_view(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    save_view(req, &USERS).await
+}
+
+/// Handles GET requests for the saved views of the users list.
+///
+/// # Route
+///
+/// `GET /users/views`
+///
+/// # Response
+///
+/// - 200 OK with `[{name, filter, sort, order}]`, the views of the caller by name
+pub async fn handle_list_user_views(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    list_views(req, &USERS).await
+}
+
+/// Handles POST requests saving a view of the products list, as
+/// [`handle_save_user_view`].
+///
+/// # Route
+///
+/// `POST /products/views`
+pub async fn handle_save_product_view(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    save_view(req, &PRODUCTS).await
+}
+
+/// Handles GET requests for the saved views of the products list, as
+/// [`handle_list_user_views`].
+///
+/// # Route
+///
+/// `GET /products/views`
+pub async fn handle_list_product_views(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    list_views(req, &PRODUCTS).await
+}
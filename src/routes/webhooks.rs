@@ -0,0 +1,216 @@
+//! Webhooks of the caller: the change events POSTed to their URLs (see the `webhooks`
+//! module), and the log of the deliveries.
+
+use hyper::{Request, StatusCode, Uri, body::Incoming};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::context::RequestContext;
+use crate::error::{AppError, ErrorCode};
+use crate::events;
+use crate::repository::webhooks::{PgWebhookRepo, WebhookRepository, WebhookSettings};
+use crate::router::query::{self, DEFAULT_LIMIT, MAX_LIMIT};
+use crate::router::{HandlerResult, Params, empty_response, json_response, parse_validated_body};
+use crate::validation::{Validate, ValidationErrors};
+
+/// Shortest secret, so that the signatures can't be guessed
+const MIN_SECRET_LEN: usize = 16;
+
+/// Longest URL of a webhook
+const MAX_URL_LEN: usize = 2048;
+
+impl Validate for WebhookSettings {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check(
+            "url",
+            self.url.len() <= MAX_URL_LEN
+                && self.url.parse::<Uri>().is_ok_and(|uri| {
+                    matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some()
+                }),
+            format!(
+                "must be an http or https URL of at most {} characters",
+                MAX_URL_LEN
+            ),
+        );
+        errors.check(
+            "secret",
+            self.secret.chars().count() >= MIN_SECRET_LEN,
+            format!("must be at least {} characters", MIN_SECRET_LEN),
+        );
+        for event_type in &self.events {
+            errors.check(
+                "events",
+                events::schemas()
+                    .iter()
+                    .any(|schema| schema.event_type == event_type.as_str()),
+                format!("'{}' is not a registered event type", event_type),
+            );
+        }
+        errors.into_result()
+    }
+}
+
+/// `?limit=` of `GET /webhooks/:id/deliveries`.
+#[derive(Deserialize, Default, Debug)]
+struct DeliveriesQuery {
+    limit: Option<i64>,
+}
+
+/// The `:id` of a webhook.
+fn webhook_id(params: &Params) -> Result<Uuid, AppError> {
+    params
+        .parse::<Uuid>("id")
+        .ok_or_else(|| AppError::Validation("ID must be a UUID".to_string()))
+}
+
+fn not_found() -> AppError {
+    AppError::NotFound(ErrorCode::WebhookNotFound, "Webhook not found".to_string())
+}
+
+/// Handles POST requests registering a webhook of the caller.
+///
+/// # Route
+///
+/// `POST /webhooks` (requires authentication)
+///
+/// # Request Body
+/// JSON object with `url`, `secret` (key of the `X-Webhook-Signature` of the
+/// deliveries), and optionally `events`, the event types to deliver (all of them when
+/// empty or missing)
+///
+/// # Response
+///
+/// - 201 Created with `{id, url, events, created_at, updated_at}`
+/// - 400 Bad Request if the JSON is malformed
+/// - 401 Unauthorized without a valid access token
+/// - 422 Unprocessable Entity if the URL, secret or an event type is invalid
+pub async fn handle_create_webhook(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let settings = parse_validated_body::<WebhookSettings>(req).await?;
+    let webhook = PgWebhookRepo(db).create(user.id, &settings).await?;
+    Ok(json_response(StatusCode::CREATED, webhook))
+}
+
+/// Handles GET requests for the webhooks of the caller.
+///
+/// # Route
+///
+/// `GET /webhooks` (requires authentication)
+///
+/// # Response
+///
+/// - 200 OK with `[{id, url, events, created_at, updated_at}...]`, the oldest first
+/// - 401 Unauthorized without a valid access token
+pub async fn handle_list_webhooks(req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let webhooks = PgWebhookRepo(db).list(user.id).await?;
+    Ok(json_response(StatusCode::OK, webhooks))
+}
+
+/// Handles GET requests for a webhook of the caller.
+///
+/// # Route
+///
+/// `GET /webhooks/:id` (requires authentication)
+///
+/// # Response
+///
+/// - 200 OK with `{id, url, events, created_at, updated_at}`
+/// - 400 Bad Request if the ID is not a UUID
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if the caller has no webhook with this ID
+pub async fn handle_get_webhook(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let id = webhook_id(&params)?;
+    let webhook = PgWebhookRepo(db)
+        .find(user.id, id)
+        .await?
+        .ok_or_else(not_found)?;
+    Ok(json_response(StatusCode::OK, webhook))
+}
+
+/// Handles PUT requests replacing the URL, secret and events of a webhook of the
+/// caller.
+///
+/// # Route
+///
+/// `PUT /webhooks/:id` (requires authentication)
+///
+/// # Request Body
+/// JSON object with `url`, `secret` and optionally `events`, as for `POST /webhooks`
+///
+/// # Response
+///
+/// - 200 OK with the updated webhook
+/// - 400 Bad Request if the ID is not a UUID or the JSON is malformed
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if the caller has no webhook with this ID
+/// - 422 Unprocessable Entity if the URL, secret or an event type is invalid
+pub async fn handle_update_webhook(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let id = webhook_id(&params)?;
+    let settings = parse_validated_body::<WebhookSettings>(req).await?;
+    let webhook = PgWebhookRepo(db)
+        .update(user.id, id, &settings)
+        .await?
+        .ok_or_else(not_found)?;
+    Ok(json_response(StatusCode::OK, webhook))
+}
+
+/// Handles DELETE requests for a webhook of the caller, with its deliveries. The
+/// deliveries still queued are dropped.
+///
+/// # Route
+///
+/// `DELETE /webhooks/:id` (requires authentication)
+///
+/// # Response
+///
+/// - 204 No Content
+/// - 400 Bad Request if the ID is not a UUID
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if the caller has no webhook with this ID
+pub async fn handle_delete_webhook(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let id = webhook_id(&params)?;
+    if !PgWebhookRepo(db).delete(user.id, id).await? {
+        return Err(not_found());
+    }
+    Ok(empty_response(StatusCode::NO_CONTENT))
+}
+
+/// Handles GET requests for the latest delivery attempts of a webhook of the caller.
+///
+/// # Route
+///
+/// `GET /webhooks/:id/deliveries?limit=` (requires authentication)
+///
+/// - `limit`: Number of attempts (default 20, max 100)
+///
+/// # Response
+///
+/// - 200 OK with `[{id, job_id, attempt, event_type, payload, status_code, error,
+///   duration_ms, created_at}...]`, the latest first; `status_code` is null when the
+///   URL didn't answer, `error` when the attempt succeeded
+/// - 400 Bad Request if the ID is not a UUID or a query parameter is invalid
+/// - 401 Unauthorized without a valid access token
+/// - 404 Not Found if the caller has no webhook with this ID
+pub async fn handle_list_deliveries(req: Request<Incoming>, params: Params) -> HandlerResult {
+    let db = RequestContext::of(&req).db;
+    let user = RequestContext::of(&req).caller()?;
+    let id = webhook_id(&params)?;
+    let query = query::parse::<DeliveriesQuery, _>(&req)?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let deliveries = PgWebhookRepo(db)
+        .deliveries(user.id, id, limit)
+        .await?
+        .ok_or_else(not_found)?;
+    Ok(json_response(StatusCode::OK, deliveries))
+}
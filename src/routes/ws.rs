@@ -0,0 +1,121 @@
+//! WebSocket route streaming the change notifications of the `events` module.
+
+use futures_util::{SinkExt, StreamExt};
+use hyper::header::{
+    CONNECTION, HeaderValue, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION,
+    UPGRADE,
+};
+use hyper::upgrade::Upgraded;
+use hyper::{Request, StatusCode, body::Incoming};
+use hyper_util::rt::TokioIo;
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tracing::{debug, warn};
+
+use crate::context;
+use crate::error::AppError;
+use crate::events;
+use crate::router::{HandlerResult, Params, empty_response};
+use crate::shutdown::stopping;
+
+/// Handles WebSocket upgrade requests to receive change notifications.
+///
+/// # Route
+///
+/// `GET /ws` with the WebSocket handshake headers
+///
+/// # Response
+///
+/// - 101 Switching Protocols, then one text message per change:
+///   `{"type": "users.created", "version": 2, "data": {"id"}}`, the UUID of the user (see
+///   the `events` module).
+///   A client that falls behind receives `{"lagged": <number of lost events>}`
+/// - 400 Bad Request if the request is not a WebSocket handshake
+pub async fn handle_websocket(mut req: Request<Incoming>, _params: Params) -> HandlerResult {
+    let headers = req.headers();
+    let has_token = |name, token: &str| {
+        headers.get_all(name).iter().any(|value| {
+            value.to_str().is_ok_and(|value| {
+                value
+                    .split(',')
+                    .any(|part| part.trim().eq_ignore_ascii_case(token))
+            })
+        })
+    };
+    if !has_token(CONNECTION, "upgrade") || !has_token(UPGRADE, "websocket") {
+        return Err(AppError::Validation(
+            "Expected a WebSocket upgrade request".to_string(),
+        ));
+    }
+    if headers.get(SEC_WEBSOCKET_VERSION) != Some(&HeaderValue::from_static("13")) {
+        return Err(AppError::Validation(
+            "Unsupported WebSocket version, expected 13".to_string(),
+        ));
+    }
+    let accept = headers
+        .get(SEC_WEBSOCKET_KEY)
+        .map(|key| derive_accept_key(key.as_bytes()))
+        .ok_or_else(|| AppError::Validation("Missing Sec-WebSocket-Key header".to_string()))?;
+
+    // Subscribe before answering so no event is missed between the handshake and the stream
+    let events = events::subscribe();
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    // The connection outlives the request, its logs still name it
+    context::spawn(async move {
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let socket =
+                    WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None)
+                        .await;
+                stream_events(socket, events).await;
+            }
+            Err(e) => warn!("WebSocket upgrade failed: {}", e),
+        }
+    });
+
+    let mut res = empty_response(StatusCode::SWITCHING_PROTOCOLS);
+    let headers = res.headers_mut();
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(
+        SEC_WEBSOCKET_ACCEPT,
+        HeaderValue::from_str(&accept).expect("base64 is a valid header value"),
+    );
+    Ok(res)
+}
+
+/// Forwards the events to the client until it disconnects or the server shuts down.
+/// Messages sent by the client are ignored, pings are answered by tungstenite.
+async fn stream_events(
+    mut socket: WebSocketStream<TokioIo<Upgraded>>,
+    mut events: tokio::sync::broadcast::Receiver<events::ChangeEvent>,
+) {
+    loop {
+        let message = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => event.to_json().to_string(),
+                Err(RecvError::Lagged(lost)) => json!({"lagged": lost}).to_string(),
+                Err(RecvError::Closed) => break,
+            },
+            _ = stopping() => break,
+            incoming = socket.next() => match incoming {
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    debug!("WebSocket closed: {}", e);
+                    break;
+                }
+            },
+        };
+
+        if let Err(e) = socket.send(Message::text(message)).await {
+            debug!("WebSocket closed: {}", e);
+            break;
+        }
+    }
+    let _ = socket.close(None).await;
+}
@@ -0,0 +1,492 @@
+//! SCIM 2.0 provisioning of the accounts (RFC 7643, RFC 7644), so the identity systems
+//! of enterprises (Okta, Microsoft Entra ID) create, update, deactivate and delete them
+//! as people join, move and leave.
+//!
+//! ## Routes
+//! `/scim/v2/Users` lists the accounts (`filter`, `startIndex`, `count`) and creates
+//! them, `/scim/v2/Users/:id` retrieves, replaces (`PUT`), updates (`PATCH` with a
+//! `PatchOp`) and deletes one (see `routes::scim`). The identity system authenticates
+//! with the bearer token of `SCIM_TOKEN`; without it the routes answer 404.
+//!
+//! ## Mapping
+//! A SCIM User is an account, a user with an email:
+//!
+//! | SCIM | `users` |
+//! |------|---------|
+//! | `id` | `public_id` |
+//! | `userName` | `email`, lowercased |
+//! | `displayName`, `name.formatted` | `name` |
+//! | `externalId` | `external_id` |
+//! | `active` | `active`: deactivated accounts can't log in |
+//! | `password` | `password_hash`, never returned |
+//! | `age` of the extension schema (`EXTENSION_SCHEMA`) | `age` |
+//!
+//! The name is the `displayName`, else `name.formatted`, else `name.givenName` and
+//! `name.familyName`, else the `userName`. `emails` mirrors `userName` and is ignored in
+//! requests, like the attributes of the schema this backend doesn't keep. `active` and
+//! `age` keep their value when a replacement leaves them out; a new account is active,
+//! and 0 years old until told otherwise.
+//!
+//! Filters compare `id`, `userName`, `emails.value`, `externalId`, `displayName` and
+//! `name.formatted` (see `repository::filter::scim`).
+
+use std::sync::OnceLock;
+
+use hyper::Request;
+use hyper::header::AUTHORIZATION;
+use ring::digest::{SHA256, digest};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::auth::hash_password;
+use crate::error::{AppError, ErrorCode};
+use crate::repository::filter::{FieldType, FilterField};
+use crate::repository::scim::{ScimAccount, ScimUser};
+use crate::router::conditional::version_etag;
+use crate::validation::{ValidationErrors, check_age, check_email, check_name, check_password};
+
+/// Schema of the users
+pub const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+/// Schema of the attributes of the users that SCIM doesn't define
+pub const EXTENSION_SCHEMA: &str = "urn:rust-backend:params:scim:schemas:extension:2.0:User";
+pub const LIST_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+pub const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+
+/// Accounts per page of a list, the default `count`
+pub const MAX_COUNT: i64 = 100;
+
+/// Attributes the accounts can be filtered by; strings compare lowercased
+pub const FILTERABLE_ATTRIBUTES: &[FilterField] = &[
+    FilterField::uuid("id", "public_id"),
+    lowered("userName", "lower(email)"),
+    lowered("emails", "lower(email)"),
+    lowered("emails.value", "lower(email)"),
+    lowered("externalId", "lower(external_id)"),
+    lowered("displayName", "lower(name)"),
+    lowered("name.formatted", "lower(name)"),
+];
+
+/// Names of the attributes kept, matched case insensitively in the paths of `PATCH`
+const ATTRIBUTES: &[&str] = &[
+    "userName",
+    "externalId",
+    "displayName",
+    "name",
+    "formatted",
+    "givenName",
+    "familyName",
+    "active",
+    "password",
+    "age",
+];
+
+// Set once at startup, `None` without SCIM_TOKEN
+static TOKEN: OnceLock<Option<Vec<u8>>> = OnceLock::new();
+
+const fn lowered(name: &'static str, column: &'static str) -> FilterField {
+    FilterField {
+        name,
+        column,
+        field_type: FieldType::Text,
+    }
+}
+
+/// An error of a SCIM route, sent as a SCIM error (RFC 7644 §3.12).
+#[derive(Debug)]
+pub struct ScimError {
+    pub error: AppError,
+    /// `scimType` of the error: `invalidFilter`, `invalidValue`, `uniqueness`, ...
+    pub scim_type: Option<&'static str>,
+}
+
+impl ScimError {
+    pub fn new(scim_type: &'static str, error: AppError) -> Self {
+        ScimError {
+            error,
+            scim_type: Some(scim_type),
+        }
+    }
+}
+
+impl From<AppError> for ScimError {
+    fn from(error: AppError) -> Self {
+        let scim_type = match &error {
+            AppError::Validation(_) | AppError::Unprocessable(_) => Some("invalidValue"),
+            AppError::Conflict(..) => Some("uniqueness"),
+            _ => None,
+        };
+        ScimError { error, scim_type }
+    }
+}
+
+/// Sets the token of the identity systems (`SCIM_TOKEN`).
+/// This function should be called at application startup, before serving requests.
+pub fn init_scim(token: Option<&str>) {
+    let digest = token.map(|token| digest(&SHA256, token.as_bytes()).as_ref().to_vec());
+    if digest.is_some() {
+        info!("SCIM provisioning at /scim/v2/Users");
+    }
+    if TOKEN.set(digest).is_err() {
+        warn!("Attempt to reset the SCIM token ignored");
+    }
+}
+
+/// Checks the bearer token of a SCIM request.
+///
+/// # Returns
+///
+/// * `Result<(), ScimError>` - `AppError::NotFound` without `SCIM_TOKEN`, or
+///   `AppError::Unauthorized` if the token is missing or wrong
+pub fn authenticate<B>(req: &Request<B>) -> Result<(), ScimError> {
+    let Some(Some(expected)) = TOKEN.get() else {
+        return Err(AppError::NotFound(
+            ErrorCode::RouteNotFound,
+            "SCIM provisioning is disabled (SCIM_TOKEN)".to_string(),
+        )
+        .into());
+    };
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| AppError::Unauthorized("Missing bearer token".to_string()))?;
+    // Compared by digest, so the time taken tells nothing of the token
+    if digest(&SHA256, token.trim().as_bytes()).as_ref() != expected.as_slice() {
+        return Err(AppError::Unauthorized("Invalid SCIM token".to_string()).into());
+    }
+    Ok(())
+}
+
+/// Path of an account, its `meta.location`.
+pub fn location(public_id: Uuid) -> String {
+    format!("/scim/v2/Users/{}", public_id)
+}
+
+/// The SCIM User of an account.
+pub fn resource(user: &ScimUser) -> Value {
+    let version = version_etag(user.version);
+    json!({
+        "schemas": [USER_SCHEMA, EXTENSION_SCHEMA],
+        "id": user.public_id,
+        "externalId": user.external_id,
+        "userName": user.email,
+        "name": {"formatted": user.name},
+        "displayName": user.name,
+        "emails": [{"value": user.email, "type": "work", "primary": true}],
+        "active": user.active,
+        EXTENSION_SCHEMA: {"age": user.age},
+        "meta": {
+            "resourceType": "User",
+            "lastModified": user.updated_at,
+            "location": location(user.public_id),
+            "version": version.to_str().unwrap_or_default(),
+        },
+    })
+}
+
+/// A User sent by the identity system, with the attributes kept.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct UserRequest {
+    user_name: Option<String>,
+    external_id: Option<String>,
+    display_name: Option<String>,
+    name: Option<Name>,
+    /// A boolean, or its text (`"False"`) as some systems send it
+    active: Option<Value>,
+    password: Option<String>,
+    #[serde(rename = "urn:rust-backend:params:scim:schemas:extension:2.0:User")]
+    extension: Option<Extension>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct Name {
+    formatted: Option<String>,
+    given_name: Option<String>,
+    family_name: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Extension {
+    age: Option<i32>,
+}
+
+/// The fields of an account from a User, validated.
+///
+/// # Arguments
+///
+/// * `request` - The User sent
+/// * `current` - The account it replaces, `None` for a new one
+///
+/// # Returns
+///
+/// * `Result<ScimAccount, AppError>` - The fields, with the password hashed, or an
+///   `AppError::Unprocessable` listing the invalid attributes
+pub async fn account(
+    request: UserRequest,
+    current: Option<&ScimUser>,
+) -> Result<ScimAccount, AppError> {
+    let mut errors = ValidationErrors::default();
+    let email = request.user_name.unwrap_or_default().trim().to_lowercase();
+    check_email(&mut errors, "userName", &email);
+
+    let name = request.name.unwrap_or_default();
+    let full_name = [name.given_name, name.family_name]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let name = [request.display_name, name.formatted, Some(full_name)]
+        .into_iter()
+        .flatten()
+        .map(|name| name.trim().to_string())
+        .find(|name| !name.is_empty())
+        .unwrap_or_else(|| email.clone());
+    check_name(&mut errors, "displayName", &name);
+
+    let age = request
+        .extension
+        .and_then(|extension| extension.age)
+        .or(current.map(|user| user.age))
+        .unwrap_or(0);
+    check_age(&mut errors, "age", age);
+
+    let active = match request.active {
+        None | Some(Value::Null) => Some(current.is_none_or(|user| user.active)),
+        Some(Value::Bool(active)) => Some(active),
+        Some(Value::String(text)) if text.eq_ignore_ascii_case("true") => Some(true),
+        Some(Value::String(text)) if text.eq_ignore_ascii_case("false") => Some(false),
+        Some(_) => None,
+    };
+    errors.check("active", active.is_some(), "must be a boolean");
+
+    if let Some(password) = &request.password {
+        check_password(&mut errors, "password", password);
+    }
+    errors.into_result()?;
+
+    let password_hash = match request.password {
+        Some(password) => Some(hash_password(password).await?),
+        None => None,
+    };
+    Ok(ScimAccount {
+        name,
+        age,
+        email,
+        external_id: request.external_id.filter(|id| !id.is_empty()),
+        active: active.unwrap_or(true),
+        password_hash,
+    })
+}
+
+/// Body of `PATCH /scim/v2/Users/:id`, a `PatchOp`.
+#[derive(Deserialize, Debug)]
+pub struct PatchRequest {
+    #[serde(rename = "Operations")]
+    operations: Vec<PatchOperation>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PatchOperation {
+    /// `add`, `replace` or `remove`, case insensitive
+    op: String,
+    path: Option<String>,
+    value: Option<Value>,
+}
+
+/// Applies a `PatchOp` to the User of an account.
+///
+/// # Returns
+///
+/// * `Result<UserRequest, ScimError>` - The User replacing the account, or the
+///   `invalidSyntax`, `noTarget` or `invalidValue` error of an operation
+pub fn patch(user: &ScimUser, patch: PatchRequest) -> Result<UserRequest, ScimError> {
+    let mut attributes = resource(user);
+    for operation in patch.operations {
+        match (operation.op.to_ascii_lowercase().as_str(), operation.path) {
+            ("add" | "replace", Some(path)) => {
+                set_path(&mut attributes, &path, operation.value.unwrap_or_default())
+            }
+            // Without path, the value holds the attributes
+            ("add" | "replace", None) => match operation.value {
+                Some(Value::Object(values)) => {
+                    for (path, value) in values {
+                        set_path(&mut attributes, &path, value);
+                    }
+                }
+                _ => {
+                    return Err(AppError::Validation(
+                        "An operation without path takes an object".to_string(),
+                    )
+                    .into());
+                }
+            },
+            ("remove", Some(path)) => set_path(&mut attributes, &path, Value::Null),
+            ("remove", None) => {
+                return Err(ScimError::new(
+                    "noTarget",
+                    AppError::Validation("remove takes a path".to_string()),
+                ));
+            }
+            (op, _) => {
+                return Err(ScimError::new(
+                    "invalidSyntax",
+                    AppError::Validation(format!(
+                        "Unknown operation '{}', expected add, replace or remove",
+                        op
+                    )),
+                ));
+            }
+        }
+    }
+    serde_json::from_value(attributes)
+        .map_err(|e| AppError::Validation(format!("Invalid attributes: {}", e)).into())
+}
+
+/// `text` without `prefix`, compared case insensitively, and the `:` after it.
+fn strip_urn<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    match text.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => {
+            Some(text[prefix.len()..].trim_start_matches(':'))
+        }
+        _ => None,
+    }
+}
+
+/// Sets the attribute at `path` (`active`, `name.givenName`, `<EXTENSION_SCHEMA>:age`)
+/// of a User to `value`.
+fn set_path(attributes: &mut Value, path: &str, value: Value) {
+    // Value filters (`emails[type eq "work"].value`) only select in the multi-valued
+    // attributes, which are ignored
+    if path.contains('[') {
+        return;
+    }
+    match strip_urn(path, EXTENSION_SCHEMA) {
+        Some(path) => set(&mut attributes[EXTENSION_SCHEMA], path, value),
+        None => set(
+            attributes,
+            strip_urn(path, USER_SCHEMA).unwrap_or(path),
+            value,
+        ),
+    }
+}
+
+/// Sets the attribute at `path` of `target`, merging objects attribute by attribute.
+fn set(target: &mut Value, path: &str, value: Value) {
+    if path.is_empty() {
+        match value {
+            Value::Object(values) => {
+                for (path, value) in values {
+                    set(target, &path, value);
+                }
+            }
+            value => *target = value,
+        }
+        return;
+    }
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(object) = target else {
+        return;
+    };
+    let (attribute, rest) = path.split_once('.').unwrap_or((path, ""));
+    // Attribute names are case insensitive
+    let key = object
+        .keys()
+        .map(String::as_str)
+        .chain(ATTRIBUTES.iter().copied())
+        .find(|key| key.eq_ignore_ascii_case(attribute))
+        .unwrap_or(attribute)
+        .to_string();
+    let entry = object.entry(key).or_insert(Value::Null);
+    if rest.is_empty() && !value.is_object() {
+        *entry = value;
+    } else {
+        set(entry, rest, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> ScimUser {
+        ScimUser {
+            id: 1,
+            public_id: Uuid::nil(),
+            name: "Ann".to_string(),
+            age: 30,
+            email: "ann@example.com".to_string(),
+            external_id: None,
+            active: true,
+            version: 1,
+            updated_at: "2026-01-01T00:00:00Z".to_string(),
+        }
+    }
+
+    fn operations(operations: Value) -> PatchRequest {
+        serde_json::from_value(json!({
+            "schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"],
+            "Operations": operations,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn patches_change_the_attributes_of_the_user() {
+        let request = patch(
+            &user(),
+            operations(json!([
+                {"op": "Replace", "path": "active", "value": "False"},
+                {"op": "add", "path": "urn:ietf:params:scim:schemas:core:2.0:User:externalid",
+                 "value": "00u1"},
+                {"op": "replace", "value": {
+                    "displayName": "Ann Smith",
+                    "urn:rust-backend:params:scim:schemas:extension:2.0:User": {"age": 31},
+                }},
+                {"op": "replace", "path": "emails[type eq \"work\"].value",
+                 "value": "other@example.com"},
+                {"op": "add", "path": "title", "value": "Engineer"},
+            ])),
+        )
+        .unwrap();
+        assert_eq!(request.active, Some(json!("False")));
+        assert_eq!(request.external_id.as_deref(), Some("00u1"));
+        assert_eq!(request.display_name.as_deref(), Some("Ann Smith"));
+        assert_eq!(request.extension.unwrap().age, Some(31));
+        assert_eq!(request.user_name.as_deref(), Some("ann@example.com"));
+    }
+
+    #[test]
+    fn invalid_patches_are_rejected() {
+        for operation in [
+            json!({"op": "remove"}),
+            json!({"op": "move", "path": "active"}),
+            json!({"op": "replace", "value": false}),
+            json!({"op": "replace", "path": "userName", "value": 5}),
+        ] {
+            let result = patch(&user(), operations(json!([operation])));
+            assert!(result.is_err(), "{} should be rejected", operation);
+        }
+    }
+
+    #[test]
+    fn removed_attributes_are_cleared() {
+        let request = patch(
+            &user(),
+            operations(json!([
+                {"op": "add", "path": "externalId", "value": "00u1"},
+                {"op": "remove", "path": "externalId"},
+                {"op": "replace", "path": "name.givenName", "value": "Bob"},
+            ])),
+        )
+        .unwrap();
+        assert_eq!(request.external_id, None);
+        assert_eq!(request.name.unwrap().given_name.as_deref(), Some("Bob"));
+    }
+}
@@ -0,0 +1,584 @@
+//! Search engine (`SEARCH_ENGINE`) indexing the users and products, for `GET /search`.
+//!
+//! ## Sync
+//! The indexes follow the change feed of the audit log (see `repository::cdc`), as its
+//! consumer `search_engine`: the `sync_search_engine` job reads the records after its
+//! checkpoint, indexes the current version of the users and products they changed,
+//! removes the deleted ones, then saves the position it reached. It's enqueued at
+//! startup and when users or products change, never twice while one waits, and one
+//! instance runs it at a time. While the engine is down the job fails and is retried;
+//! the records wait in the feed, so nothing is lost. The first run, without a
+//! checkpoint, indexes every user and product.
+//!
+//! ## Search
+//! A search asks the engine first. When it fails, the names are searched in the
+//! database instead, and the engine is left alone for `ENGINE_COOLDOWN`. Without
+//! `SEARCH_ENGINE`, the database is always searched.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use futures_util::TryStreamExt;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Bytes;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, HeaderValue};
+use hyper::{Method, Request, Uri};
+use serde::Serialize;
+use serde_json::{Value, json};
+use tokio::time::timeout;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::computed::{Resource, with_fields};
+use crate::config::{SearchEngineConfig, SearchEngineKind};
+use crate::context::within_deadline;
+use crate::db::{Db, DistributedLock, join_queries};
+use crate::embeddings;
+use crate::error::AppError;
+use crate::events::{ChangeEvent, Collection};
+use crate::http_client::http_client;
+use crate::jobs::{self, Job};
+use crate::repository::audit::Entity;
+use crate::repository::cdc::{CdcRepository, Change, PgCdcRepo, Position};
+use crate::repository::filter::Filter;
+use crate::repository::products::{PgProductRepo, ProductRepository};
+use crate::repository::users::{PgUserRepo, UserRepository};
+use crate::router::BoxError;
+use crate::router::query::{Pagination, SortOrder};
+
+/// Consumer of the change feed, for its checkpoints
+const CONSUMER: &str = "search_engine";
+
+/// Lock held while the indexes are synced
+const SYNC_LOCK: &str = "search_engine_sync";
+
+/// Records of the feed synced by each request to the engine
+const BATCH_SIZE: i64 = 500;
+
+/// Time the engine has to answer a search, then to index a batch
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(2);
+const INDEX_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time the database is searched instead of the engine after it failed
+const ENGINE_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Largest response read from the engine
+const MAX_ENGINE_BODY: usize = 16 * 1024 * 1024;
+
+// Set once at startup, unset without SEARCH_ENGINE
+static ENGINE: OnceLock<Engine> = OnceLock::new();
+
+// Whether a `sync_search_engine` job is waiting, which will sync the latest changes too
+static QUEUED: AtomicBool = AtomicBool::new(false);
+
+/// The indexes, one per resource.
+#[derive(Debug, Clone, Copy)]
+enum Index {
+    Users,
+    Products,
+}
+
+impl Index {
+    const ALL: [Index; 2] = [Index::Users, Index::Products];
+
+    fn entity(self) -> Entity {
+        match self {
+            Index::Users => Entity::User,
+            Index::Products => Entity::Product,
+        }
+    }
+
+    /// Name of the table, and of the index after the prefix
+    fn table(self) -> &'static str {
+        match self {
+            Index::Users => "users",
+            Index::Products => "products",
+        }
+    }
+
+    fn resource(self) -> Resource {
+        match self {
+            Index::Users => Resource::Users,
+            Index::Products => Resource::Products,
+        }
+    }
+
+    /// The document of the entity with the key `key`, `None` if it was deleted.
+    async fn document(self, key: i32) -> Result<Option<Value>, AppError> {
+        let document = match self {
+            Index::Users => PgUserRepo::default()
+                .find_by_id(key)
+                .await?
+                .map(|user| json!(user)),
+            Index::Products => PgProductRepo::default()
+                .find_by_id(key)
+                .await?
+                .map(|product| json!(product)),
+        };
+        Ok(document)
+    }
+
+    /// Public ID of the entity with the key `key` if it's soft-deleted, as only the
+    /// users can be.
+    async fn soft_deleted_id(self, key: i32) -> Result<Option<Uuid>, AppError> {
+        match self {
+            Index::Users => Ok(PgUserRepo::default()
+                .find_any(key)
+                .await?
+                .map(|user| user.public_id)),
+            Index::Products => Ok(None),
+        }
+    }
+}
+
+/// The engine, and when it may be asked again after failing.
+struct Engine {
+    config: SearchEngineConfig,
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Engine {
+    fn name(&self) -> &'static str {
+        match self.config.kind {
+            SearchEngineKind::Meilisearch => "meilisearch",
+            SearchEngineKind::Elasticsearch => "elasticsearch",
+        }
+    }
+
+    fn index_name(&self, index: Index) -> String {
+        format!("{}{}", self.config.index_prefix, index.table())
+    }
+
+    /// Whether the engine is asked, not cooling down after failing.
+    fn available(&self) -> bool {
+        let down_until = self.down_until.lock().unwrap_or_else(|e| e.into_inner());
+        down_until.is_none_or(|until| Instant::now() >= until)
+    }
+
+    fn failed(&self) {
+        let mut down_until = self.down_until.lock().unwrap_or_else(|e| e.into_inner());
+        *down_until = Some(Instant::now() + ENGINE_COOLDOWN);
+    }
+
+    /// Sends `body` to `path` of the engine, within `limit`, and reads the JSON
+    /// response.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        content_type: &'static str,
+        body: String,
+        limit: Duration,
+    ) -> Result<Value, AppError> {
+        let base = self.config.url.to_string();
+        let uri = format!("{}{}", base.trim_end_matches('/'), path)
+            .parse::<Uri>()
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        let mut req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(CONTENT_TYPE, HeaderValue::from_static(content_type))
+            .body(
+                Full::new(Bytes::from(body))
+                    .map_err(|never| match never {})
+                    .boxed_unsync(),
+            )
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        if let Some(key) = &self.config.api_key {
+            let scheme = match self.config.kind {
+                SearchEngineKind::Meilisearch => "Bearer",
+                SearchEngineKind::Elasticsearch => "ApiKey",
+            };
+            let value = HeaderValue::from_str(&format!("{} {}", scheme, key))
+                .map_err(|_| AppError::Internal("Invalid SEARCH_ENGINE_API_KEY".to_string()))?;
+            req.headers_mut().insert(AUTHORIZATION, value);
+        }
+
+        let request = async {
+            let res = http_client()
+                .request(req)
+                .await
+                .map_err(|e| AppError::BadGateway(format!("Search engine unreachable: {}", e)))?;
+            let status = res.status();
+            let body = Limited::new(res.into_body(), MAX_ENGINE_BODY)
+                .collect()
+                .await
+                .map_err(|e: BoxError| {
+                    AppError::BadGateway(format!("Invalid search engine response: {}", e))
+                })?
+                .to_bytes();
+            if !status.is_success() {
+                return Err(AppError::BadGateway(format!(
+                    "Search engine answered {}: {}",
+                    status.as_u16(),
+                    String::from_utf8_lossy(&body)
+                )));
+            }
+            serde_json::from_slice::<Value>(&body)
+                .map_err(|e| AppError::BadGateway(format!("Invalid search engine response: {}", e)))
+        };
+        match timeout(limit, request).await {
+            Ok(result) => result,
+            Err(_) => Err(AppError::BadGateway(format!(
+                "Search engine didn't answer within {} ms",
+                limit.as_millis()
+            ))),
+        }
+    }
+
+    /// Adds `documents` to `index`, replacing the ones with the same `id`, and removes
+    /// the documents of `deleted`.
+    async fn write(
+        &self,
+        index: Index,
+        documents: &[Value],
+        deleted: &[Uuid],
+    ) -> Result<(), AppError> {
+        let name = self.index_name(index);
+        match self.config.kind {
+            SearchEngineKind::Meilisearch => {
+                // Created with the first documents
+                if !documents.is_empty() {
+                    let path = format!("/indexes/{}/documents?primaryKey=id", name);
+                    let body = Value::from(documents).to_string();
+                    self.send(Method::POST, &path, "application/json", body, INDEX_TIMEOUT)
+                        .await?;
+                }
+                if !deleted.is_empty() {
+                    let path = format!("/indexes/{}/documents/delete-batch", name);
+                    let body = json!(deleted).to_string();
+                    self.send(Method::POST, &path, "application/json", body, INDEX_TIMEOUT)
+                        .await?;
+                }
+            }
+            SearchEngineKind::Elasticsearch => {
+                if documents.is_empty() && deleted.is_empty() {
+                    return Ok(());
+                }
+                let body = bulk_body(&name, documents, deleted);
+                let response = self
+                    .send(
+                        Method::POST,
+                        "/_bulk",
+                        "application/x-ndjson",
+                        body,
+                        INDEX_TIMEOUT,
+                    )
+                    .await?;
+                if response["errors"] == true {
+                    return Err(AppError::BadGateway(format!(
+                        "Search engine rejected documents of {}",
+                        name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The documents of `index` matching `query`, the best first.
+    async fn search(
+        &self,
+        db: Db,
+        index: Index,
+        query: &str,
+        limit: i64,
+    ) -> Result<Vec<Value>, AppError> {
+        let name = self.index_name(index);
+        let (path, body) = match self.config.kind {
+            SearchEngineKind::Meilisearch => (
+                format!("/indexes/{}/search", name),
+                json!({"q": query, "limit": limit}),
+            ),
+            SearchEngineKind::Elasticsearch => (
+                format!("/{}/_search", name),
+                json!({
+                    "size": limit,
+                    "query": {"match": {"name": {"query": query, "fuzziness": "AUTO"}}},
+                }),
+            ),
+        };
+        let response = self
+            .send(
+                Method::POST,
+                &path,
+                "application/json",
+                body.to_string(),
+                within_deadline(db, SEARCH_TIMEOUT),
+            )
+            .await?;
+        let hits = match self.config.kind {
+            SearchEngineKind::Meilisearch => response["hits"].as_array().cloned(),
+            SearchEngineKind::Elasticsearch => response["hits"]["hits"]
+                .as_array()
+                .map(|hits| hits.iter().map(|hit| hit["_source"].clone()).collect()),
+        };
+        hits.ok_or_else(|| {
+            AppError::BadGateway("Invalid search engine response: no hits".to_string())
+        })
+    }
+}
+
+/// Body of an Elasticsearch `_bulk` request indexing `documents` in `index` and
+/// deleting `deleted`.
+fn bulk_body(index: &str, documents: &[Value], deleted: &[Uuid]) -> String {
+    let mut body = String::new();
+    for document in documents {
+        let action = json!({"index": {"_index": index, "_id": document["id"]}});
+        body.push_str(&format!("{}\n{}\n", action, document));
+    }
+    for id in deleted {
+        body.push_str(&format!(
+            "{}\n",
+            json!({"delete": {"_index": index, "_id": id}})
+        ));
+    }
+    body
+}
+
+/// Sets the search engine, and syncs the indexes.
+/// This function should be called once at application startup, after `init_jobs`.
+///
+/// # Arguments
+///
+/// * `config` - The engine, `None` when the database is searched
+pub fn init_search_engine(config: Option<&SearchEngineConfig>) {
+    let Some(config) = config else {
+        return;
+    };
+    let engine = Engine {
+        config: config.clone(),
+        down_until: Mutex::new(None),
+    };
+    info!(
+        "Users and products indexed by {} at {}",
+        engine.name(),
+        config.url
+    );
+    if ENGINE.set(engine).is_err() {
+        warn!("Attempt to reset the search engine ignored");
+        return;
+    }
+    schedule();
+}
+
+/// Syncs the indexes after a change of a user or product published by this instance.
+pub fn dispatch(event: &ChangeEvent) {
+    if matches!(event.collection, Collection::Users | Collection::Products)
+        && ENGINE.get().is_some()
+    {
+        schedule();
+    }
+}
+
+/// Enqueues a `sync_search_engine` job, unless one is waiting.
+fn schedule() {
+    if QUEUED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    tokio::spawn(async {
+        if let Err(e) = jobs::submit(Job::SyncSearchEngine).await {
+            QUEUED.store(false, Ordering::Release);
+            warn!("Search engine not synced: {}", e);
+        }
+    });
+}
+
+/// Syncs the indexes with the change feed, from their checkpoints (job `id`).
+pub async fn sync(id: i64) -> Result<(), AppError> {
+    // The changes made from now on need another job
+    QUEUED.store(false, Ordering::Release);
+    let Some(engine) = ENGINE.get() else {
+        return Ok(());
+    };
+    let _lock = DistributedLock::acquire(SYNC_LOCK).await?;
+    let mut synced = 0;
+    for index in Index::ALL {
+        synced += sync_index(engine, index).await?;
+    }
+    if synced > 0 {
+        info!("Job {} synced {} changes to {}", id, synced, engine.name());
+    }
+    Ok(())
+}
+
+/// Syncs `index` with the records of the feed after its checkpoint, returning their
+/// number.
+async fn sync_index(engine: &Engine, index: Index) -> Result<usize, AppError> {
+    let mut position = match PgCdcRepo::default()
+        .checkpoint(CONSUMER, index.table())
+        .await?
+    {
+        Some(checkpoint) => checkpoint.position,
+        None => reindex(engine, index).await?,
+    };
+    let mut synced = 0;
+    loop {
+        let changes = PgCdcRepo::default()
+            .changes(index.entity(), position, BATCH_SIZE)
+            .await?;
+        let Some(last) = changes.last().map(|change| change.position) else {
+            break;
+        };
+
+        // The current version of each entity changed, whatever the changes were
+        let mut keys = changes
+            .iter()
+            .map(|change| change.record.entity_key)
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys.dedup();
+        let mut documents = Vec::new();
+        let mut deleted = Vec::new();
+        for key in keys {
+            match index.document(key).await? {
+                Some(document) => documents.push(document),
+                // Deleted in a later transaction otherwise, removed with its record
+                None => match deleted_id(&changes, key) {
+                    Some(id) => deleted.push(id),
+                    None => deleted.extend(index.soft_deleted_id(key).await?),
+                },
+            }
+        }
+        engine.write(index, &documents, &deleted).await?;
+        PgCdcRepo::default()
+            .save_checkpoint(CONSUMER, index.table(), last)
+            .await?;
+
+        synced += changes.len();
+        if (changes.len() as i64) < BATCH_SIZE {
+            break;
+        }
+        position = last;
+    }
+    Ok(synced)
+}
+
+/// Public ID of the entity `key` deleted by a record of `changes`.
+fn deleted_id(changes: &[Change], key: i32) -> Option<Uuid> {
+    changes
+        .iter()
+        .filter(|change| change.record.entity_key == key && change.record.action == "delete")
+        .find_map(|change| change.record.changes["id"]["old"].as_str()?.parse().ok())
+}
+
+/// Indexes every entity of `index`, returning the position of the feed they include.
+async fn reindex(engine: &Engine, index: Index) -> Result<Position, AppError> {
+    // Taken first: the records after it are synced next, some already indexed
+    let head = PgCdcRepo::default().head(index.entity()).await?;
+    let page = Pagination {
+        limit: 0,
+        offset: 0,
+        sort: "id",
+        order: SortOrder::Asc,
+    };
+    let filter = Filter::default();
+    let mut indexed = 0;
+    match index {
+        Index::Users => {
+            let mut batches = Box::pin(PgUserRepo::default().stream_all(&page, &filter));
+            while let Some(users) = batches.try_next().await? {
+                let documents = users.iter().map(|user| json!(user)).collect::<Vec<_>>();
+                engine.write(index, &documents, &[]).await?;
+                indexed += documents.len();
+            }
+        }
+        Index::Products => {
+            let mut batches = Box::pin(PgProductRepo::default().stream_all(&page, &filter));
+            while let Some(products) = batches.try_next().await? {
+                let documents = products
+                    .iter()
+                    .map(|product| json!(product))
+                    .collect::<Vec<_>>();
+                engine.write(index, &documents, &[]).await?;
+                indexed += documents.len();
+            }
+        }
+    }
+    PgCdcRepo::default()
+        .save_checkpoint(CONSUMER, index.table(), head)
+        .await?;
+    info!("{} {} indexed by {}", indexed, index.table(), engine.name());
+    Ok(head)
+}
+
+/// Result of a search.
+#[derive(Serialize, Debug)]
+pub struct SearchResults {
+    /// The users and products found, the best first, with their computed fields
+    users: Vec<Value>,
+    products: Vec<Value>,
+    /// `meilisearch` or `elasticsearch`, or `database` when the names were searched
+    /// in the database
+    engine: &'static str,
+}
+
+/// The users and products matching `query`, `limit` of each at most.
+///
+/// # Returns
+///
+/// * `Result<SearchResults, AppError>` - The results of the engine, or of the database
+///   when it's down; the error of the database
+pub async fn search(db: Db, query: &str, limit: i64) -> Result<SearchResults, AppError> {
+    if let Some(engine) = ENGINE.get()
+        && engine.available()
+    {
+        let found = tokio::try_join!(
+            engine.search(db, Index::Users, query, limit),
+            engine.search(db, Index::Products, query, limit),
+        );
+        match found {
+            Ok((users, products)) => {
+                return Ok(SearchResults {
+                    users: with_computed(Index::Users, users),
+                    products: with_computed(Index::Products, products),
+                    engine: engine.name(),
+                });
+            }
+            Err(e) => {
+                engine.failed();
+                warn!("Search engine skipped for {:?}: {}", ENGINE_COOLDOWN, e);
+            }
+        }
+    }
+
+    let terms = embeddings::terms(query);
+    let (users, products) = join_queries!(
+        PgUserRepo(db).search_by_name(&terms, limit),
+        PgProductRepo(db).search_by_name(&terms, limit),
+    )?;
+    Ok(SearchResults {
+        users: with_computed(Index::Users, users.iter().map(|user| json!(user)).collect()),
+        products: with_computed(
+            Index::Products,
+            products.iter().map(|(product, _)| json!(product)).collect(),
+        ),
+        engine: "database",
+    })
+}
+
+/// `documents` of `index` with their computed fields.
+fn with_computed(index: Index, documents: Vec<Value>) -> Vec<Value> {
+    documents
+        .into_iter()
+        .map(|document| json!(with_fields(index.resource(), document)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bulk_bodies_index_then_delete() {
+        let id = Uuid::nil();
+        let body = bulk_body("shop_products", &[json!({"id": "a", "name": "Pen"})], &[id]);
+        assert_eq!(
+            body,
+            "{\"index\":{\"_index\":\"shop_products\",\"_id\":\"a\"}}\n\
+             {\"id\":\"a\",\"name\":\"Pen\"}\n\
+             {\"delete\":{\"_index\":\"shop_products\",\"_id\":\"00000000-0000-0000-0000-000000000000\"}}\n"
+        );
+    }
+}
@@ -0,0 +1,489 @@
+//! Server bootstrap: initialization of the global services and the accept loops.
+//!
+//! `main` runs these steps in order; the integration tests (`tests/`) run the same ones
+//! against a test database, with a listener bound to an ephemeral port.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
+
+use crate::auth::init_auth;
+use crate::cache::init_cache;
+use crate::carts::init_carts;
+use crate::computed::init_computed_fields;
+use crate::config::{AppConfig, DatabaseConfig, ServerConfig, TlsConfig};
+use crate::console::init_console;
+use crate::dashboard::init_dashboard;
+use crate::db::{init_pool, run_migrations};
+use crate::embeddings::init_embeddings;
+use crate::error_reporting::init_error_reporting;
+use crate::events::init_bridge;
+use crate::experiments::init_experiments;
+use crate::forwarded::{Scheme, init_trusted_proxies};
+use crate::geo_policy::{load_policies, reload_on_sighup};
+use crate::geoip::init_geoip;
+use crate::jobs::init_jobs;
+use crate::ldap::init_ldap;
+use crate::legacy_proxy::init_legacy_proxy;
+use crate::metrics::{self, init_slos};
+use crate::offline_queue::init_offline_queue;
+use crate::oidc::init_oidc;
+use crate::passkeys::init_passkeys;
+use crate::proxy_protocol;
+use crate::region::init_region;
+use crate::repository::ids::init_ids;
+use crate::router::app_headers::init_app_headers;
+use crate::router::cors::init_cors;
+use crate::router::error_pages::init_error_pages;
+use crate::router::limits::init_limits;
+use crate::router::rate_limit::init_rate_limit;
+use crate::router::{Body, ClientAddr, ResponseBody, init_trailing_slash, router, serve_request};
+use crate::routes::docs::init_dev_console;
+use crate::routes::tools::init_tools;
+use crate::scim::init_scim;
+use crate::search_engine::init_search_engine;
+use crate::shutdown::stopping;
+use crate::signed_urls::init_signed_urls;
+use crate::static_files::init_static_files;
+use crate::storage::init_storage;
+use crate::tls::{ACME_TLS_ALPN, redirect_to_https, tls_settings};
+use crate::webhooks::init_webhooks;
+
+/// The HTTPS listener and the acceptor performing the TLS handshakes.
+pub struct TlsListener {
+    pub listener: TcpListener,
+    pub acceptor: TlsAcceptor,
+    pub port: u16,
+    /// Plain HTTP listener redirecting to HTTPS (`HTTP_REDIRECT_PORT`)
+    pub redirect: Option<TcpListener>,
+}
+
+/// Starts the database pool and brings the schema up to date, up to the first contract
+/// migration unless `contract` (see `db::migrations`).
+///
+/// # Returns
+///
+/// * `Result<(), String>` - Success or the reason the database can't be used
+pub async fn prepare_database(config: &DatabaseConfig, contract: bool) -> Result<(), String> {
+    init_pool(config)
+        .await
+        .map_err(|e| format!("Error starting database pool: {}", e))?;
+
+    // Bring the schema up to date before anything queries it
+    run_migrations(contract)
+        .await
+        .map_err(|e| format!("Error applying database migrations: {}", e))
+}
+
+/// Initializes everything the routes rely on besides the database.
+/// This function should be called once, after `prepare_database`.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - Success or the first setting that can't be applied
+pub async fn init(config: &AppConfig) -> Result<(), String> {
+    // Optional reporting of the panics and server errors, first so the panics of the
+    // startup are reported too
+    init_error_reporting(config.error_reporting.as_ref());
+
+    // Load the JWT signing keys
+    init_auth(&config.auth).map_err(|e| format!("Error configuring authentication: {}", e))?;
+    init_signed_urls(&config.auth).map_err(|e| format!("Error configuring signed URLs: {}", e))?;
+    init_carts(&config.auth).map_err(|e| format!("Error configuring carts: {}", e))?;
+
+    // Load the optional GeoIP database
+    init_geoip(config.geo.geoip_db_path.as_deref())
+        .map_err(|e| format!("Error loading GeoIP database: {}", e))?;
+
+    // Load the optional geo policies, reloaded later on SIGHUP
+    load_policies(config.geo.policy_path.as_deref())
+        .map_err(|e| format!("Error loading geo policies: {}", e))?;
+    tokio::spawn(reload_on_sighup(config.geo.policy_path.clone()));
+
+    // Optional region of the instance and home regions of the clients
+    init_region(&config.region, !config.database.replicas.is_empty())
+        .map_err(|e| format!("Error configuring the region: {}", e))?;
+
+    // Directory of the uploaded files
+    init_storage(&config.storage).map_err(|e| format!("Error configuring file storage: {}", e))?;
+
+    // IDs accepted for the users and products
+    init_ids(config.server.accept_integer_ids);
+    // Optional cache of the entity responses
+    init_cache(&config.cache);
+    // Changes made through the other instances, and sent to them
+    init_bridge();
+
+    // Background workers and scheduler, resuming the jobs queued before a restart
+    init_jobs(&config.jobs).await?;
+    // Deliveries of the events to the webhooks, made by the jobs
+    init_webhooks();
+    // Optional embeddings of the products, computed by the jobs
+    init_embeddings(config.embeddings.as_ref()).await?;
+    // Optional search engine, synced by the jobs
+    init_search_engine(config.search_engine.as_ref());
+    let count = init_oidc(config.oidc.as_ref())
+        .await
+        .map_err(|e| format!("Error loading the OpenID Connect provider: {}", e))?;
+    if count > 0 {
+        info!("{} OpenID Connect clients", count);
+    }
+    init_scim(config.auth.scim_token.as_deref());
+    init_ldap(config.ldap.as_ref());
+    init_passkeys(config.passkeys.as_ref());
+
+    // Writes queued while the database was down, replayed once it's back
+    let count = init_offline_queue(config.offline_queue.as_ref())
+        .await
+        .map_err(|e| format!("Error opening the offline queue: {}", e))?;
+    if count > 0 {
+        info!("{} queued writes to replay", count);
+    }
+
+    // Request limits, trailing slashes, client resolution, headers, CORS and rate limiting,
+    // applied by the router to every request
+    init_limits(&config.server);
+    init_trailing_slash(config.server.trailing_slash);
+    for (method, pattern, _) in &config.server.route_timeouts {
+        if !router()
+            .routes()
+            .any(|route| route.method == method && route.pattern == pattern)
+        {
+            return Err(format!("ROUTE_TIMEOUTS: no route {} {}", method, pattern));
+        }
+    }
+    init_trusted_proxies(&config.server.trusted_proxies);
+    init_app_headers(config.server.legacy_headers);
+    init_cors(&config.server.allowed_origins);
+    init_rate_limit(&config.server);
+    init_static_files(
+        config.server.static_dir.as_deref(),
+        config.server.spa_fallback,
+    )
+    .map_err(|e| format!("Error serving the static files: {}", e))?;
+    init_tools(&config.tools);
+    init_dev_console(config.server.dev_console);
+    let count = init_error_pages(config.server.error_pages_path.as_deref())
+        .map_err(|e| format!("Error loading error pages: {}", e))?;
+    if count > 0 {
+        info!("{} error pages", count);
+    }
+    // Optional reverse proxy to the legacy service
+    init_legacy_proxy(config.legacy_proxy.as_ref());
+    init_dashboard(&config.dashboard);
+
+    // Optional A/B experiments
+    let count = init_experiments(config.experiments.path.as_deref())
+        .map_err(|e| format!("Error loading experiments: {}", e))?;
+    if count > 0 {
+        info!("{} experiments running", count);
+    }
+
+    // Optional queries of the admin console
+    let count = init_console(config.console.queries_path.as_deref())
+        .map_err(|e| format!("Error loading console queries: {}", e))?;
+    if count > 0 {
+        info!("{} console queries available", count);
+    }
+
+    // Optional fields added to the responses
+    let count = init_computed_fields(config.computed.path.as_deref())
+        .map_err(|e| format!("Error loading computed fields: {}", e))?;
+    if count > 0 {
+        info!("{} computed fields", count);
+    }
+
+    // Optional objectives of the routes, after the router they refer to
+    let count =
+        init_slos(config.slo.path.as_deref()).map_err(|e| format!("Error loading SLOs: {}", e))?;
+    if count > 0 {
+        info!("{} SLOs tracked", count);
+    }
+    Ok(())
+}
+
+/// Binds the optional HTTPS listener, and the plain HTTP one redirecting to it.
+///
+/// # Returns
+///
+/// * `Result<Option<TlsListener>, String>` - The listener, `None` when TLS isn't
+///   configured, or an error if the certificate can't be loaded or a port bound
+pub async fn bind_tls(config: Option<&TlsConfig>) -> Result<Option<TlsListener>, String> {
+    let Some(settings) = tls_settings(config)
+        .await
+        .map_err(|e| format!("Error loading TLS configuration: {}", e))?
+    else {
+        return Ok(None);
+    };
+
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", settings.port))
+        .await
+        .map_err(|e| format!("Error binding to TCP port {}: {}", settings.port, e))?;
+    info!("HTTPS enabled on port {}", settings.port);
+
+    let redirect = match settings.redirect_port {
+        Some(port) => {
+            let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
+                .await
+                .map_err(|e| format!("Error binding to TCP port {}: {}", port, e))?;
+            info!("Port {} redirects to HTTPS", port);
+            Some(listener)
+        }
+        None => None,
+    };
+    Ok(Some(TlsListener {
+        listener,
+        acceptor: settings.acceptor,
+        port: settings.port,
+        redirect,
+    }))
+}
+
+/// Serves connections until a shutdown is signaled (see the `shutdown` module), then
+/// waits for the active connections to finish, at most `SHUTDOWN_TIMEOUT`.
+///
+/// # Arguments
+///
+/// * `listener` - The plain HTTP listener
+/// * `tls` - The optional HTTPS listener, served at the same time
+/// * `config` - HTTP settings of the connections
+pub async fn serve(listener: TcpListener, tls: Option<TlsListener>, config: &ServerConfig) {
+    // Tracks every active connection so they can be closed gracefully on shutdown
+    let graceful = GracefulShutdown::new();
+    let builder = connection_builder(config);
+    // Behind a TCP load balancer, the header has the same time to arrive as the request
+    let proxy = config.proxy_protocol.then_some(config.header_read_timeout);
+
+    // Run the HTTP and (optional) HTTPS accept loops until the shutdown signal arrives
+    let https = async {
+        if let Some(tls) = tls {
+            let redirect = async {
+                if let Some(redirect) = tls.redirect {
+                    redirect_loop(redirect, tls.port, proxy, &graceful, &builder).await;
+                }
+            };
+            tokio::join!(
+                accept_loop(tls.listener, Some(tls.acceptor), proxy, &graceful, &builder),
+                redirect
+            );
+        }
+    };
+    tokio::join!(
+        accept_loop(listener, None, proxy, &graceful, &builder),
+        https
+    );
+
+    // The accept loops have returned, so the listening sockets are already closed
+    // and new clients are refused immediately
+
+    // Ask active connections to finish their in-flight request and close,
+    // waiting at most the configured drain timeout
+    let timeout = config.shutdown_timeout;
+    tokio::select! {
+        _ = graceful.shutdown() => info!("All connections closed"),
+        _ = tokio::time::sleep(timeout) => {
+            warn!("Timed out after {:?} waiting for connections to close", timeout);
+        }
+    }
+}
+
+/// Accepts connections on `listener` until a shutdown is signaled.
+///
+/// # Arguments
+///
+/// * `listener` - The bound TCP listener, closed when the loop returns
+/// * `tls` - TLS acceptor for HTTPS listeners, `None` for plain HTTP
+/// * `proxy` - Time the PROXY protocol header has to arrive, `None` when disabled
+/// * `graceful` - Tracks the spawned connections for graceful shutdown
+/// * `builder` - HTTP settings of the connections, see [`connection_builder`]
+async fn accept_loop(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    proxy: Option<Duration>,
+    graceful: &GracefulShutdown,
+    builder: &auto::Builder<TokioExecutor>,
+) {
+    loop {
+        let (mut stream, peer) = tokio::select! {
+            // Wait for and accept a new connection asynchronously
+            conn = listener.accept() => conn.expect("Failed to accept connection"),
+            // Stop accepting new connections
+            _ = stopping() => break,
+        };
+
+        // Register the connection so it can be notified when the server shuts down
+        let watcher = graceful.watcher();
+        let tls = tls.clone();
+        let builder = builder.clone();
+
+        // Each new connection is handled in its own asynchronous task,
+        // allowing the server to continue accepting new connections
+        // while processing existing ones concurrently.
+        // The TLS handshake also runs in the task so a slow client can't block the loop.
+        tokio::spawn(async move {
+            let Some(addr) = client_addr(&mut stream, peer, proxy).await else {
+                return;
+            };
+            match tls {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        // The protocol was chosen by ALPN during the handshake
+                        let builder = match stream.get_ref().1.alpn_protocol() {
+                            // The handshake was the whole TLS-ALPN-01 validation
+                            Some(ACME_TLS_ALPN) => return,
+                            Some(b"h2") => builder.http2_only(),
+                            _ => builder.http1_only(),
+                        };
+                        let io = TokioIo::new(stream);
+                        serve_connection(io, addr, Scheme::Https, watcher, builder).await
+                    }
+                    Err(e) => warn!("TLS handshake with {} failed: {}", addr, e),
+                },
+                // Plain connections are detected from their first bytes
+                // (HTTP/2 with prior knowledge starts with the connection preface)
+                None => {
+                    let io = TokioIo::new(stream);
+                    serve_connection(io, addr, Scheme::Http, watcher, builder).await
+                }
+            }
+        });
+    }
+}
+
+/// Accepts connections on `listener` until a shutdown is signaled, answering every
+/// request with a redirect to HTTPS.
+///
+/// Unlike [`accept_loop`], the requests skip the router and its middlewares: no
+/// routing, logging or metrics.
+///
+/// # Arguments
+///
+/// * `https_port` - Port of the HTTPS listener the clients are sent to
+/// * `proxy` - Time the PROXY protocol header has to arrive, `None` when disabled
+async fn redirect_loop(
+    listener: TcpListener,
+    https_port: u16,
+    proxy: Option<Duration>,
+    graceful: &GracefulShutdown,
+    builder: &auto::Builder<TokioExecutor>,
+) {
+    loop {
+        let (mut stream, peer) = tokio::select! {
+            conn = listener.accept() => conn.expect("Failed to accept connection"),
+            _ = stopping() => break,
+        };
+
+        let watcher = graceful.watcher();
+        let builder = builder.clone();
+        tokio::spawn(async move {
+            let Some(addr) = client_addr(&mut stream, peer, proxy).await else {
+                return;
+            };
+            let service = service_fn(move |req| redirect(req, https_port));
+            let conn = builder.serve_connection(TokioIo::new(stream), service);
+            if let Err(e) = watcher.watch(conn).await {
+                warn!("Error in HTTP connection with {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Address of the client of a connection: the TCP peer, or with the PROXY protocol the
+/// client the load balancer announces (see the `proxy_protocol` module).
+///
+/// # Returns
+///
+/// * `Option<SocketAddr>` - The address, or `None` if the connection must be closed
+///   because it doesn't start with a valid PROXY protocol header
+async fn client_addr(
+    stream: &mut TcpStream,
+    peer: SocketAddr,
+    proxy: Option<Duration>,
+) -> Option<SocketAddr> {
+    let Some(timeout) = proxy else {
+        return Some(peer);
+    };
+    match proxy_protocol::read_header(stream, timeout).await {
+        // Connections of the load balancer itself, such as health checks
+        Ok(None) => Some(peer),
+        Ok(Some(client)) => Some(client),
+        Err(e) => {
+            warn!("Invalid PROXY protocol header from {}: {}", peer, e);
+            None
+        }
+    }
+}
+
+/// Service of the redirect listener.
+async fn redirect(
+    req: Request<Incoming>,
+    https_port: u16,
+) -> Result<Response<ResponseBody>, Infallible> {
+    Ok(redirect_to_https(&req, https_port).map(Body::into_response_body))
+}
+
+/// Serves HTTP requests on an accepted connection (plain TCP or TLS).
+///
+/// # Arguments
+///
+/// * `io` - The connection adapted to Tokio's I/O interface
+/// * `addr` - Address of the peer, attached to every request of the connection
+/// * `scheme` - `https` for TLS connections, attached to every request as well
+/// * `watcher` - Graceful shutdown watcher of the connection
+/// * `builder` - HTTP settings of the connection
+async fn serve_connection<I>(
+    io: TokioIo<I>,
+    addr: SocketAddr,
+    scheme: Scheme,
+    watcher: Watcher,
+    builder: auto::Builder<TokioExecutor>,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let _active = metrics::ConnectionGuard::new();
+
+    // Configure an HTTP service that routes requests to our handler function
+    let service = service_fn(move |mut req| {
+        req.extensions_mut().insert(ClientAddr(addr));
+        req.extensions_mut().insert(scheme);
+        serve_request(req)
+    });
+    // Upgrades are only used by `GET /ws` on HTTP/1 connections
+    let conn = builder.serve_connection_with_upgrades(io, service);
+
+    if let Err(e) = watcher.watch(conn).await {
+        warn!("Error in HTTP connection: {}", e);
+    }
+}
+
+/// HTTP/1 and HTTP/2 settings of the connections, from the server configuration.
+fn connection_builder(config: &ServerConfig) -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    // Clients that don't send the complete headers in time get their connection closed,
+    // the read buffer bounds the size of the headers (431 when they don't fit)
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(config.header_read_timeout)
+        .max_buf_size(config.max_header_size)
+        .keep_alive(config.keep_alive);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_header_list_size(u32::try_from(config.max_header_size).unwrap_or(u32::MAX))
+        .max_concurrent_streams(config.http2_max_concurrent_streams)
+        .keep_alive_interval(config.http2_keep_alive_interval)
+        .keep_alive_timeout(config.http2_keep_alive_timeout);
+    builder
+}
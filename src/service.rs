@@ -0,0 +1,253 @@
+//! Service mode, with the `service` feature.
+//!
+//! Lets native service managers other than systemd run the server:
+//!
+//! - Unix, `--daemon`: the process detaches from its terminal and goes on in the
+//!   background, as SysV init scripts, `start-stop-daemon`, rc.d or launchd expect. Its
+//!   process ID is written to `PID_FILE`, which stays locked while it runs so a second
+//!   daemon can't start, and its output (logs, panics) is appended to `DAEMON_LOG_PATH`.
+//!   SIGTERM stops it as usual (see the `shutdown` module), removing the PID file.
+//! - Windows, `--service`: the process is started by the Service Control Manager as the
+//!   service `SERVICE_NAME`, which stops it on a Stop request or when Windows shuts
+//!   down. Logs go to `DAEMON_LOG_PATH`.
+//!
+//! Both have to happen before the Tokio runtime is started: a forked process only keeps
+//! the thread that forked, and the service manager runs the service on its own thread.
+
+#[cfg(unix)]
+pub use unix::{daemonize, remove_pid_file};
+#[cfg(windows)]
+pub use windows::{enter_executable_dir, run_as_windows_service};
+
+use std::fs::{File, OpenOptions};
+
+use crate::config::ServiceConfig;
+
+/// Opens `DAEMON_LOG_PATH` to append to it.
+///
+/// # Returns
+///
+/// * `Result<Option<File>, String>` - The file, `None` without `DAEMON_LOG_PATH`, or
+///   an error if it can't be opened
+pub fn open_log_file(config: &ServiceConfig) -> Result<Option<File>, String> {
+    let Some(path) = &config.log_path else {
+        return Ok(None);
+    };
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map(Some)
+        .map_err(|e| format!("Unable to open '{}': {}", path.display(), e))
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::env;
+    use std::fs::{self, File, TryLockError};
+    use std::path::Path;
+
+    use daemonize::Daemonize;
+
+    use super::open_log_file;
+    use crate::config::ServiceConfig;
+
+    /// Detaches the process from its terminal, in the background.
+    /// This function must be called before the Tokio runtime is started.
+    ///
+    /// The calling process exits once the daemon is started; only the daemon returns.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), String>` - Success in the daemon, or the error of a running daemon
+    ///   or of an unwritable log file
+    pub fn daemonize(config: &ServiceConfig) -> Result<(), String> {
+        if let Some(path) = &config.pid_file {
+            ensure_not_running(path)?;
+        }
+        // Relative paths (`.env`, `config.toml`, `UPLOAD_DIR`) keep working
+        let dir = env::current_dir().map_err(|e| format!("Unable to daemonize: {}", e))?;
+        let mut daemon = Daemonize::new().working_directory(dir);
+        if let Some(path) = &config.pid_file {
+            daemon = daemon.pid_file(path);
+        }
+        if let Some(file) = open_log_file(config)? {
+            let copy = file
+                .try_clone()
+                .map_err(|e| format!("Unable to daemonize: {}", e))?;
+            daemon = daemon.stdout(file).stderr(copy);
+        }
+        daemon
+            .start()
+            .map_err(|e| format!("Unable to daemonize: {}", e))
+    }
+
+    /// Fails if a daemon holds the lock of the PID file.
+    ///
+    /// The daemon only takes the lock once the calling process has exited, so a running
+    /// daemon has to be detected before for the calling process to fail.
+    fn ensure_not_running(path: &Path) -> Result<(), String> {
+        let Ok(file) = File::open(path) else {
+            return Ok(());
+        };
+        // The lock is released when the file is closed
+        match file.try_lock() {
+            Ok(()) => Ok(()),
+            Err(TryLockError::WouldBlock) => {
+                let pid = fs::read_to_string(path).unwrap_or_default();
+                Err(format!(
+                    "Already running (PID {} in '{}')",
+                    pid.trim(),
+                    path.display()
+                ))
+            }
+            Err(TryLockError::Error(e)) => {
+                Err(format!("Unable to lock '{}': {}", path.display(), e))
+            }
+        }
+    }
+
+    /// Removes the PID file of a daemon about to exit.
+    pub fn remove_pid_file(config: &ServiceConfig) {
+        if let Some(path) = &config.pid_file
+            && let Err(e) = fs::remove_file(path)
+        {
+            eprintln!("Unable to remove '{}': {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::env;
+    use std::ffi::OsString;
+    use std::sync::{Mutex, mpsc};
+    use std::thread;
+    use std::time::Duration;
+
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    use crate::config::ServiceConfig;
+    use crate::shutdown::begin_shutdown;
+
+    /// What the service runs, handed over to the thread of the service manager
+    struct Service {
+        name: String,
+        /// Longest time the service manager is told to wait for the server to stop
+        stop_wait: Duration,
+        serve: Box<dyn FnOnce() + Send>,
+    }
+
+    static SERVICE: Mutex<Option<Service>> = Mutex::new(None);
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Makes the directory of the executable the working directory.
+    ///
+    /// Services are started in the system directory; `.env`, `config.toml` and the
+    /// relative paths of the settings are looked up next to the executable instead.
+    pub fn enter_executable_dir() -> Result<(), String> {
+        let exe =
+            env::current_exe().map_err(|e| format!("Unable to locate the executable: {}", e))?;
+        let Some(dir) = exe.parent() else {
+            return Ok(());
+        };
+        env::set_current_dir(dir).map_err(|e| format!("Unable to enter '{}': {}", dir.display(), e))
+    }
+
+    /// Runs the server as a Windows service, until the service manager stops it.
+    /// This function must be called before the Tokio runtime is started, and only from
+    /// a process started by the service manager.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Name of the service
+    /// * `stop_wait` - Longest time the server takes to stop
+    /// * `serve` - Runs the server until the shutdown, on the thread of the service
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), String>` - Success once the service has stopped, or an error if the
+    ///   process wasn't started by the service manager
+    pub fn run_as_windows_service(
+        config: &ServiceConfig,
+        stop_wait: Duration,
+        serve: impl FnOnce() + Send + 'static,
+    ) -> Result<(), String> {
+        *SERVICE.lock().unwrap_or_else(|e| e.into_inner()) = Some(Service {
+            name: config.name.clone(),
+            stop_wait,
+            serve: Box::new(serve),
+        });
+        service_dispatcher::start(&config.name, ffi_service_main).map_err(|e| {
+            format!(
+                "Unable to start the service {} (not started by the service manager?): {}",
+                config.name, e
+            )
+        })
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let Some(service) = SERVICE.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+            return;
+        };
+
+        let (stop_sender, stop) = mpsc::channel();
+        let handler = move |control: ServiceControl| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let _ = stop_sender.send(());
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+        let status = match service_control_handler::register(&service.name, handler) {
+            Ok(status) => status,
+            Err(e) => {
+                eprintln!("Unable to register the service {}: {}", service.name, e);
+                return;
+            }
+        };
+        let report = |state, controls_accepted, wait_hint| {
+            let status = status.set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state: state,
+                controls_accepted,
+                exit_code: ServiceExitCode::NO_ERROR,
+                checkpoint: 0,
+                wait_hint,
+                process_id: None,
+            });
+            if let Err(e) = status {
+                eprintln!("Unable to report the service status: {}", e);
+            }
+        };
+
+        report(
+            ServiceState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            Duration::ZERO,
+        );
+        let server = thread::spawn(service.serve);
+
+        // Stopped like on SIGTERM, the server then exits on its own
+        let _ = stop.recv();
+        report(
+            ServiceState::StopPending,
+            ServiceControlAccept::empty(),
+            service.stop_wait,
+        );
+        begin_shutdown();
+        let _ = server.join();
+        report(
+            ServiceState::Stopped,
+            ServiceControlAccept::empty(),
+            Duration::ZERO,
+        );
+    }
+}
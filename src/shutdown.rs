@@ -0,0 +1,123 @@
+//! Shutdown subsystem.
+//!
+//! Listens for termination signals so the server can stop accepting connections,
+//! let in-flight requests finish and release its resources before exiting.
+//! This is what makes `docker stop` (SIGTERM) and Ctrl+C (SIGINT) safe.
+//!
+//! Once the connections are drained, the background subsystems are stopped one after
+//! the other by a [`ShutdownController`], each one before those it depends on.
+
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// `true` once the shutdown has started
+static STOPPING: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::channel(false).0);
+
+/// Marks the shutdown as started, waking every [`stopping`] future.
+pub fn begin_shutdown() {
+    STOPPING.send_replace(true);
+}
+
+/// Completes once the shutdown has started (immediately if it already has).
+///
+/// Accept loops stop on it, and long-lived responses (Server-Sent Events, WebSockets)
+/// end on it so their connection can be drained.
+pub async fn stopping() {
+    let mut stopping = STOPPING.subscribe();
+    // The sender is static, so it is never dropped
+    let _ = stopping.wait_for(|stopping| *stopping).await;
+}
+
+/// Waits until the process receives SIGINT (Ctrl+C) or SIGTERM (`docker stop`, Kubernetes).
+///
+/// # Panics
+///
+/// Will panic if the signal handlers cannot be installed
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install SIGINT handler");
+    };
+
+    // SIGTERM only exists on Unix systems
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("SIGINT received, shutting down"),
+        _ = terminate => info!("SIGTERM received, shutting down"),
+    }
+}
+
+/// Stops the subsystems of the server in order, each within its own timeout.
+///
+/// A subsystem that doesn't stop in time is left behind (its work resumes at the next
+/// start) and the next one is stopped anyway, so the process always exits.
+pub struct ShutdownController {
+    started: Instant,
+    /// Subsystems that timed out
+    left_behind: Vec<&'static str>,
+}
+
+impl ShutdownController {
+    /// Starts timing the shutdown of the subsystems.
+    pub fn start() -> Self {
+        ShutdownController {
+            started: Instant::now(),
+            left_behind: Vec::new(),
+        }
+    }
+
+    /// Waits for a subsystem to stop.
+    ///
+    /// # Arguments
+    ///
+    /// * `subsystem` - Name of the subsystem, for the logs
+    /// * `timeout` - Longest wait
+    /// * `stop` - Completes once the subsystem has stopped
+    pub async fn stop(
+        &mut self,
+        subsystem: &'static str,
+        timeout: Duration,
+        stop: impl Future<Output = ()>,
+    ) {
+        let start = Instant::now();
+        match tokio::time::timeout(timeout, stop).await {
+            Ok(()) => info!(
+                "{} stopped in {} ms",
+                subsystem,
+                start.elapsed().as_millis()
+            ),
+            Err(_) => {
+                warn!("{} didn't stop within {:?}", subsystem, timeout);
+                self.left_behind.push(subsystem);
+            }
+        }
+    }
+
+    /// Logs the outcome of the shutdown.
+    pub fn finish(self) {
+        let elapsed = self.started.elapsed().as_millis();
+        if self.left_behind.is_empty() {
+            info!("Subsystems stopped in {} ms", elapsed);
+        } else {
+            warn!(
+                "Subsystems stopped in {} ms, left behind: {}",
+                elapsed,
+                self.left_behind.join(", ")
+            );
+        }
+    }
+}
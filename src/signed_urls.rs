@@ -0,0 +1,207 @@
+//! Signed URLs: links to some `GET` routes valid without an access token until they
+//! expire, for the clients that can't send one (a download started by the browser, an
+//! `<img>`, a report fetched by a script or sent by email).
+//!
+//! A signed URL is the path and query of the route followed by three parameters:
+//! `expires` (seconds since the Unix epoch), `sub` (the user who signed it, whose
+//! rights the request has) and `signature`, the base64url HMAC-SHA256 of everything
+//! before it. The key is derived from `JWT_SECRET`, so changing the secret revokes the
+//! signed URLs along with the tokens.
+//!
+//! Only the routes registered with `Router::allow_signed_urls` accept them, the router
+//! checks them before the handler as it checks the tokens.
+
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hyper::Request;
+use ring::hmac;
+use serde::Serialize;
+
+use crate::auth::AuthUser;
+use crate::config::AuthConfig;
+use crate::error::AppError;
+
+/// Parameters added to the signed path, reserved
+pub const PARAMS: [&str; 3] = ["expires", "sub", "signature"];
+
+// Set once at startup
+static SIGNER: OnceLock<Signer> = OnceLock::new();
+
+struct Signer {
+    key: hmac::Key,
+    max_lifetime: Duration,
+}
+
+/// A signed URL, as returned to the client that asked for it.
+#[derive(Serialize, Debug)]
+pub struct SignedUrl {
+    /// Path and query of the route, with the signature
+    pub url: String,
+    /// Seconds since the Unix epoch
+    pub expires: u64,
+}
+
+/// Derives the key of the signatures from `JWT_SECRET`.
+/// This function should be called once at application startup.
+pub fn init_signed_urls(config: &AuthConfig) -> Result<(), String> {
+    // A key of its own, so a signature is never a valid token signature and back
+    let secret = hmac::Key::new(hmac::HMAC_SHA256, config.jwt_secret.as_bytes());
+    let key = hmac::sign(&secret, b"signed-urls");
+    let signer = Signer {
+        key: hmac::Key::new(hmac::HMAC_SHA256, key.as_ref()),
+        max_lifetime: config.signed_url_max_lifetime,
+    };
+    SIGNER
+        .set(signer)
+        .map_err(|_| "Signed URLs are already initialized".to_string())
+}
+
+fn signer() -> Result<&'static Signer, AppError> {
+    SIGNER
+        .get()
+        .ok_or_else(|| AppError::Internal("Signed URLs are not initialized".to_string()))
+}
+
+/// Longest lifetime of a signed URL (`SIGNED_URL_MAX_LIFETIME`).
+pub fn max_lifetime() -> Duration {
+    SIGNER
+        .get()
+        .map_or(Duration::ZERO, |signer| signer.max_lifetime)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// `path_and_query` with the parameters of a signed URL.
+fn signed(key: &hmac::Key, path_and_query: &str, user_id: i32, expires: u64) -> String {
+    let separator = if path_and_query.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    let unsigned = format!(
+        "{}{}expires={}&sub={}",
+        path_and_query, separator, expires, user_id
+    );
+    let signature = URL_SAFE_NO_PAD.encode(hmac::sign(key, unsigned.as_bytes()));
+    format!("{}&signature={}", unsigned, signature)
+}
+
+/// Checks the signature and expiry of a signed URL.
+///
+/// # Returns
+///
+/// * `Result<i32, &'static str>` - The user who signed it, or why it's invalid
+fn check(key: &hmac::Key, path: &str, query: &str, now: u64) -> Result<i32, &'static str> {
+    // The signature comes last, covering everything before it
+    let (unsigned, signature) = query
+        .rsplit_once("&signature=")
+        .ok_or("The signature of the URL is invalid")?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| "The signature of the URL is invalid")?;
+    hmac::verify(key, format!("{}?{}", path, unsigned).as_bytes(), &signature)
+        .map_err(|_| "The signature of the URL is invalid")?;
+
+    let params = serde_urlencoded::from_str::<Vec<(String, String)>>(unsigned)
+        .map_err(|_| "The signature of the URL is invalid")?;
+    let param = |name: &str| {
+        params
+            .iter()
+            .rev()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let expires = param("expires")
+        .and_then(|expires| expires.parse::<u64>().ok())
+        .ok_or("The signature of the URL is invalid")?;
+    if expires <= now {
+        return Err("The signed URL expired");
+    }
+    param("sub")
+        .and_then(|sub| sub.parse().ok())
+        .ok_or("The signature of the URL is invalid")
+}
+
+/// Signs a path for a user.
+///
+/// # Arguments
+///
+/// * `path_and_query` - Path of the route, with its query if any
+/// * `user_id` - The user whose rights the requests of the URL have
+/// * `lifetime` - How long the URL is valid, at most `max_lifetime()`
+pub fn sign(path_and_query: &str, user_id: i32, lifetime: Duration) -> Result<SignedUrl, AppError> {
+    let signer = signer()?;
+    let expires = now_secs() + lifetime.min(signer.max_lifetime).as_secs();
+    Ok(SignedUrl {
+        url: signed(&signer.key, path_and_query, user_id, expires),
+        expires,
+    })
+}
+
+/// Whether the request was sent to a signed URL.
+pub fn is_signed<B>(req: &Request<B>) -> bool {
+    req.uri()
+        .query()
+        .is_some_and(|query| query.contains("&signature="))
+}
+
+/// Checks the signed URL a request was sent to.
+///
+/// # Returns
+///
+/// * `Result<AuthUser, AppError>` - The user who signed it, or `AppError::Unauthorized`
+///   if the signature is invalid or expired
+pub fn verify<B>(req: &Request<B>) -> Result<AuthUser, AppError> {
+    let signer = signer()?;
+    let query = req.uri().query().unwrap_or_default();
+    check(&signer.key, req.uri().path(), query, now_secs())
+        .map(|id| AuthUser { id })
+        .map_err(|reason| AppError::Unauthorized(reason.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA256, b"0123456789abcdef0123456789abcdef")
+    }
+
+    fn split(url: &str) -> (&str, &str) {
+        url.split_once('?').unwrap()
+    }
+
+    #[test]
+    fn signed_urls_are_checked() {
+        let url = signed(&key(), "/admin/export/parquet?table=users", 42, 1000);
+        let (path, query) = split(&url);
+        assert!(query.starts_with("table=users&expires=1000&sub=42&signature="));
+        assert_eq!(check(&key(), path, query, 999), Ok(42));
+        assert_eq!(
+            check(&key(), path, query, 1000),
+            Err("The signed URL expired")
+        );
+
+        // Another table, user, or path
+        let tampered = query.replace("table=users", "table=orders");
+        assert!(check(&key(), path, &tampered, 999).is_err());
+        let tampered = query.replace("sub=42", "sub=1");
+        assert!(check(&key(), path, &tampered, 999).is_err());
+        assert!(check(&key(), "/admin/retention", query, 999).is_err());
+        // Parameters after the signature
+        assert!(check(&key(), path, &format!("{}&sub=1", query), 999).is_err());
+
+        let url = signed(&key(), "/api/v1/users/1/avatar", 7, 1000);
+        let (path, query) = split(&url);
+        assert!(query.starts_with("expires=1000&sub=7&"));
+        assert_eq!(check(&key(), path, query, 0), Ok(7));
+    }
+}
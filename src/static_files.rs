@@ -0,0 +1,381 @@
+//! Static files (`STATIC_DIR`), to ship a frontend with the service.
+//!
+//! `GET /static/*path` serves the files under the directory, `/static/app/main.js`
+//! being `<STATIC_DIR>/app/main.js`. A path leaving the directory (`..`, a symbolic
+//! link pointing outside of it), naming a hidden file (`.env`) or a directory is a 404,
+//! as a missing file.
+//!
+//! The files are streamed, with:
+//! - the `Content-Type` of their extension, `application/octet-stream` for the others
+//! - an `ETag` and a `Last-Modified` made of their size and modification time:
+//!   `If-None-Match` and `If-Modified-Since` are answered 304 Not Modified
+//! - `Accept-Ranges: bytes`: a single range (`Range: bytes=0-1023`, `bytes=-500`) is
+//!   answered 206 Partial Content, or 416 when it starts past the end; several ranges,
+//!   or a range along with an `If-Range` that no longer matches, get the whole file
+//!
+//! With `SPA_FALLBACK`, a single-page application can route on the client: a `GET`
+//! matching no route, outside of `/api/` and from a client preferring HTML to JSON (a
+//! browser navigating), is answered with `index.html` instead of the JSON 404. The API
+//! clients keep getting their 404.
+
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures_util::{TryStreamExt, stream};
+use hyper::body::Bytes;
+use hyper::header::{
+    ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, HeaderMap,
+    HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE,
+    X_CONTENT_TYPE_OPTIONS,
+};
+use hyper::{Method, Request, Response, StatusCode};
+use percent_encoding::percent_decode_str;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::warn;
+
+use crate::error::{AppError, ErrorCode};
+use crate::router::{Body, BoxError, HandlerResult, empty_response};
+
+/// Bytes read from a file per chunk of a response
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Page of the single-page application
+const INDEX: &str = "index.html";
+
+/// Media types of the extensions, the others are `application/octet-stream`
+const MEDIA_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mp3", "audio/mpeg"),
+];
+
+// Set once at startup, unset without STATIC_DIR
+static STATIC_FILES: OnceLock<StaticFiles> = OnceLock::new();
+
+#[derive(Debug)]
+struct StaticFiles {
+    /// `STATIC_DIR`, canonicalized
+    root: PathBuf,
+    spa_fallback: bool,
+}
+
+/// Serves the files of `dir` (`STATIC_DIR`), and its `index.html` for the unknown paths
+/// with `spa_fallback` (`SPA_FALLBACK`).
+/// This function should be called once at application startup.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - An error if the directory, or the `index.html` the fallback
+///   needs, is missing
+pub fn init_static_files(dir: Option<&Path>, spa_fallback: bool) -> Result<(), String> {
+    let Some(dir) = dir else {
+        return Ok(());
+    };
+    let root = dir
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", dir.display(), e))?;
+    if !root.is_dir() {
+        return Err(format!("{} is not a directory", dir.display()));
+    }
+    if spa_fallback && !root.join(INDEX).is_file() {
+        return Err(format!(
+            "SPA_FALLBACK requires {}",
+            dir.join(INDEX).display()
+        ));
+    }
+    if STATIC_FILES
+        .set(StaticFiles { root, spa_fallback })
+        .is_err()
+    {
+        warn!("Attempt to reset the static files ignored");
+    }
+    Ok(())
+}
+
+fn not_found() -> AppError {
+    AppError::NotFound(ErrorCode::RouteNotFound, "Not found".to_string())
+}
+
+/// Answers a request for the file at `path` (percent-encoded, relative to
+/// `STATIC_DIR`).
+///
+/// # Returns
+///
+/// * `HandlerResult` - The file, or a 404 as for an unknown route when it doesn't exist
+///   or without `STATIC_DIR`
+pub async fn serve<B>(req: &Request<B>, path: &str) -> HandlerResult {
+    let files = STATIC_FILES.get().ok_or_else(not_found)?;
+    let file = resolve(&files.root, path).await.ok_or_else(not_found)?;
+    respond(req, &file).await
+}
+
+/// The `index.html` answering a request matching no route, with `SPA_FALLBACK`.
+///
+/// # Arguments
+///
+/// * `req` - The request, a `GET` or a `HEAD` outside of `/api/` to be answered
+/// * `prefers_html` - Whether the client prefers HTML to JSON
+pub async fn spa_fallback<B>(req: &Request<B>, prefers_html: bool) -> Option<HandlerResult> {
+    let files = STATIC_FILES.get().filter(|files| files.spa_fallback)?;
+    let path = req.uri().path();
+    if !matches!(*req.method(), Method::GET | Method::HEAD)
+        || !prefers_html
+        || path == "/api"
+        || path.starts_with("/api/")
+    {
+        return None;
+    }
+    Some(respond(req, &files.root.join(INDEX)).await)
+}
+
+/// The file of `root` at `path`, `None` if it isn't a visible file within `root`.
+async fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut file = root.to_path_buf();
+    for segment in path.split('/') {
+        let segment = percent_decode_str(segment).decode_utf8().ok()?;
+        // Hidden files, and the segments leaving the directory
+        if segment.is_empty() || segment.starts_with('.') || segment.contains(['/', '\\', '\0']) {
+            return None;
+        }
+        file.push(segment.as_ref());
+    }
+    // Symbolic links are followed, within the directory only
+    let file = fs::canonicalize(&file).await.ok()?;
+    file.starts_with(root).then_some(file)
+}
+
+/// The response to a request for a file: the file, part of it, or 304.
+async fn respond<B>(req: &Request<B>, path: &Path) -> HandlerResult {
+    let mut file = match File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(not_found()),
+        Err(e) => return Err(io_error(path, e)),
+    };
+    let metadata = file.metadata().await.map_err(|e| io_error(path, e))?;
+    if !metadata.is_file() {
+        return Err(not_found());
+    }
+    let len = metadata.len();
+    let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+    let etag = file_etag(len, modified);
+    let last_modified = httpdate::fmt_http_date(modified);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        ETAG,
+        HeaderValue::from_str(&etag).expect("ETag is hexadecimal"),
+    );
+    headers.insert(
+        LAST_MODIFIED,
+        HeaderValue::from_str(&last_modified).expect("HTTP date is ASCII"),
+    );
+    // Revalidated on every use, the ETag spares the download
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    if not_modified(req.headers(), &etag, modified) {
+        let mut res = empty_response(StatusCode::NOT_MODIFIED);
+        res.headers_mut().extend(headers);
+        return Ok(res);
+    }
+
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let media_type = extension
+        .and_then(|extension| {
+            MEDIA_TYPES
+                .iter()
+                .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+        })
+        .map_or("application/octet-stream", |(_, media_type)| media_type);
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(media_type));
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    let range = if if_range_matches(req.headers(), &etag, &last_modified) {
+        let header = req
+            .headers()
+            .get(RANGE)
+            .and_then(|value| value.to_str().ok());
+        byte_range(header, len)
+    } else {
+        ByteRange::Whole
+    };
+    let (status, start, end) = match range {
+        ByteRange::Whole => (StatusCode::OK, 0, len),
+        ByteRange::Part(start, end) => {
+            let content_range = format!("bytes {}-{}/{}", start, end - 1, len);
+            headers.insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&content_range).expect("Range is ASCII"),
+            );
+            (StatusCode::PARTIAL_CONTENT, start, end)
+        }
+        ByteRange::Unsatisfiable => {
+            let mut res = empty_response(StatusCode::RANGE_NOT_SATISFIABLE);
+            res.headers_mut().insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", len)).expect("Range is ASCII"),
+            );
+            return Ok(res);
+        }
+    };
+    if start > 0 {
+        file.seek(SeekFrom::Start(start))
+            .await
+            .map_err(|e| io_error(path, e))?;
+    }
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(end - start));
+
+    let state = (file.take(end - start), path.to_path_buf());
+    let chunks = stream::try_unfold(state, async |(mut file, path)| {
+        let mut buffer = vec![0; READ_CHUNK_SIZE];
+        let read = file
+            .read(&mut buffer)
+            .await
+            .map_err(|e| io_error(&path, e))?;
+        buffer.truncate(read);
+        Ok::<_, AppError>((read > 0).then(|| (Bytes::from(buffer), (file, path))))
+    });
+    let mut res = Response::new(Body::stream(chunks.map_err(BoxError::from)));
+    *res.status_mut() = status;
+    res.headers_mut().extend(headers);
+    Ok(res)
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> AppError {
+    AppError::Internal(format!("Unable to read {}: {}", path.display(), e))
+}
+
+/// Strong ETag of a file, from its size and modification time.
+fn file_etag(len: u64, modified: SystemTime) -> String {
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO);
+    format!("\"{:x}-{:x}\"", len, modified.as_nanos())
+}
+
+/// Whether the client has the current version of the file: `If-None-Match` lists its
+/// ETag (or `*`), or, without it, `If-Modified-Since` isn't older than the file.
+fn not_modified(headers: &HeaderMap, etag: &str, modified: SystemTime) -> bool {
+    if let Some(if_none_match) = headers.get(IF_NONE_MATCH) {
+        // Weak comparison, as for every GET
+        return if_none_match
+            .to_str()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|value| httpdate::parse_http_date(value.to_str().ok()?).ok())
+        // The dates are to the second
+        .is_some_and(|since| {
+            modified
+                .duration_since(since)
+                // A date later than the file is as good as its own
+                .map_or(true, |newer| newer < Duration::from_secs(1))
+        })
+}
+
+/// Whether a `Range` applies: without `If-Range`, or when it names the current version.
+fn if_range_matches(headers: &HeaderMap, etag: &str, last_modified: &str) -> bool {
+    headers
+        .get(IF_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .is_none_or(|if_range| if_range == etag || if_range == last_modified)
+}
+
+/// Part of a file a request asks for.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// No range, or one that isn't honored
+    Whole,
+    /// From the first offset, up to the second (excluded)
+    Part(u64, u64),
+    /// Starting past the end of the file
+    Unsatisfiable,
+}
+
+/// Reads the `Range` header of a request for a file of `len` bytes.
+///
+/// Only a single range of bytes is honored: the other units, several ranges and the
+/// invalid ones are ignored, and the whole file is sent.
+fn byte_range(header: Option<&str>, len: u64) -> ByteRange {
+    let Some(range) = header.and_then(|header| header.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Whole;
+    };
+    let Some((first, last)) = range.split_once('-').filter(|_| !range.contains(',')) else {
+        return ByteRange::Whole;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    // The last bytes
+    if first.is_empty() {
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Part(len.saturating_sub(suffix), len),
+            Err(_) => ByteRange::Whole,
+        };
+    }
+    let Ok(first) = first.parse::<u64>() else {
+        return ByteRange::Whole;
+    };
+    if first >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    if last.is_empty() {
+        return ByteRange::Part(first, len);
+    }
+    match last.parse::<u64>() {
+        Ok(last) if last >= first => ByteRange::Part(first, len.min(last + 1)),
+        _ => ByteRange::Whole,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_ranges_are_honored() {
+        let range = |header: &str| byte_range(Some(header), 1000);
+        assert_eq!(range("bytes=0-499"), ByteRange::Part(0, 500));
+        assert_eq!(range("bytes=900-"), ByteRange::Part(900, 1000));
+        assert_eq!(range("bytes=900-5000"), ByteRange::Part(900, 1000));
+        assert_eq!(range("bytes=-100"), ByteRange::Part(900, 1000));
+        assert_eq!(range("bytes=-5000"), ByteRange::Part(0, 1000));
+        assert_eq!(range("bytes=1000-"), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=-0"), ByteRange::Unsatisfiable);
+        // Several ranges, other units and invalid ranges get the whole file
+        assert_eq!(range("bytes=0-1,5-6"), ByteRange::Whole);
+        assert_eq!(range("items=0-1"), ByteRange::Whole);
+        assert_eq!(range("bytes=5-1"), ByteRange::Whole);
+        assert_eq!(byte_range(None, 1000), ByteRange::Whole);
+    }
+}
@@ -0,0 +1,179 @@
+//! File storage.
+//!
+//! Uploaded files (user avatars) are written and read through the `ObjectStore` trait.
+//! The files are streamed in both directions, so their size doesn't matter for memory.
+//!
+//! The only implementation today is `LocalStore`, keeping the files under `UPLOAD_DIR`
+//! (`uploads` by default). An object storage service (S3, ...) would be another
+//! implementation of the trait, selected in `init_storage`.
+
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::OnceLock;
+
+use futures_util::{Stream, StreamExt, stream};
+use hyper::body::Bytes;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::StorageConfig;
+use crate::error::AppError;
+
+/// Bytes read from a file per chunk of a download
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+// Set once at startup
+static STORE: OnceLock<LocalStore> = OnceLock::new();
+
+/// Content of a stored file, read chunk by chunk.
+pub struct StoredObject {
+    pub size: u64,
+    pub chunks: Pin<Box<dyn Stream<Item = Result<Bytes, AppError>> + Send>>,
+}
+
+/// Operations of a file storage backend.
+///
+/// Files are identified by a key chosen by the caller (`avatars/42.png`). Writing
+/// returns the location of the file, which is what gets stored in the database and
+/// given back to read or delete it.
+pub trait ObjectStore {
+    /// Writes a file from its chunks, replacing the file with the same key if any.
+    /// Readers see either the old or the new file, never a partial one.
+    fn put<S>(&self, key: &str, chunks: S) -> impl Future<Output = Result<String, AppError>> + Send
+    where
+        S: Stream<Item = Result<Bytes, AppError>> + Send;
+
+    /// Opens a file, `None` if there is none at `location`.
+    fn get(
+        &self,
+        location: &str,
+    ) -> impl Future<Output = Result<Option<StoredObject>, AppError>> + Send;
+
+    /// Deletes a file; a missing file is not an error.
+    fn delete(&self, location: &str) -> impl Future<Output = Result<(), AppError>> + Send;
+}
+
+/// `ObjectStore` writing the files in a local directory.
+/// The location of a file is its key, relative to the directory.
+#[derive(Debug)]
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    /// Path of a file, rejecting keys that could escape the directory.
+    fn path(&self, key: &str) -> Result<PathBuf, AppError> {
+        let relative = Path::new(key);
+        let safe = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !safe {
+            return Err(AppError::Internal(format!("Invalid storage key '{}'", key)));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+impl ObjectStore for LocalStore {
+    async fn put<S>(&self, key: &str, chunks: S) -> Result<String, AppError>
+    where
+        S: Stream<Item = Result<Bytes, AppError>> + Send,
+    {
+        let path = self.path(key)?;
+        let dir = path.parent().unwrap_or(&self.root);
+        fs::create_dir_all(dir).await.map_err(io_error)?;
+
+        // Written next to its final path, then renamed over it once complete
+        let temp = dir.join(format!(".{}.upload", Uuid::new_v4()));
+        let written = async {
+            let mut file = File::create(&temp).await.map_err(io_error)?;
+            let mut chunks = std::pin::pin!(chunks);
+            while let Some(chunk) = chunks.next().await {
+                file.write_all(&chunk?).await.map_err(io_error)?;
+            }
+            file.sync_all().await.map_err(io_error)?;
+            fs::rename(&temp, &path).await.map_err(io_error)
+        }
+        .await;
+
+        if let Err(e) = written {
+            if let Err(remove) = fs::remove_file(&temp).await
+                && remove.kind() != ErrorKind::NotFound
+            {
+                warn!("Unable to remove {}: {}", temp.display(), remove);
+            }
+            return Err(e);
+        }
+        Ok(key.to_string())
+    }
+
+    async fn get(&self, location: &str) -> Result<Option<StoredObject>, AppError> {
+        let file = match File::open(self.path(location)?).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(io_error(e)),
+        };
+        let size = file.metadata().await.map_err(io_error)?.len();
+
+        let chunks = stream::try_unfold(file, async |mut file| {
+            let mut buffer = vec![0; READ_CHUNK_SIZE];
+            let read = file.read(&mut buffer).await.map_err(io_error)?;
+            buffer.truncate(read);
+            Ok((read > 0).then(|| (Bytes::from(buffer), file)))
+        });
+        Ok(Some(StoredObject {
+            size,
+            chunks: Box::pin(chunks),
+        }))
+    }
+
+    async fn delete(&self, location: &str) -> Result<(), AppError> {
+        match fs::remove_file(self.path(location)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+}
+
+fn io_error(e: std::io::Error) -> AppError {
+    AppError::Internal(format!("File storage error: {}", e))
+}
+
+/// Creates the upload directory if needed.
+/// This function should be called at application startup.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - Success or an error message if the directory can't be created
+pub fn init_storage(config: &StorageConfig) -> Result<(), String> {
+    std::fs::create_dir_all(&config.upload_dir).map_err(|e| {
+        format!(
+            "Unable to create upload directory '{}': {}",
+            config.upload_dir.display(),
+            e
+        )
+    })?;
+
+    let store = LocalStore {
+        root: config.upload_dir.clone(),
+    };
+    if STORE.set(store).is_err() {
+        warn!("Attempt to reset the file storage ignored");
+    }
+    info!("Uploads stored in {}", config.upload_dir.display());
+    Ok(())
+}
+
+/// The configured file storage.
+///
+/// # Panics
+///
+/// Will panic if `init_storage` hasn't been called
+pub fn store() -> &'static LocalStore {
+    STORE.get().expect("File storage is not initialized")
+}
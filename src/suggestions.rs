@@ -0,0 +1,125 @@
+//! Suggestions for type-ahead search boxes (`GET /search/suggest`).
+//!
+//! The suggestions come from the words of the product and user names, counted in the
+//! `search_terms` table by triggers, so they follow every write:
+//!
+//! - completions: the terms starting with the word being typed, the last one of the
+//!   query unless it ends with a space, the most frequent first
+//! - did you mean: the query with each unknown word replaced by the closest term, by
+//!   shared trigrams like pg_trgm, if one is close enough (`MIN_SIMILARITY`)
+
+use serde::Serialize;
+
+use crate::db::Db;
+use crate::error::AppError;
+use crate::repository::search_terms::{PgSearchTermRepo, SearchTermRepository};
+
+/// Least similarity of a term replacing an unknown word, pg_trgm's default threshold
+const MIN_SIMILARITY: f64 = 0.3;
+
+/// Length of the shortest words of the terms
+const MIN_TERM_LENGTH: usize = 2;
+
+/// A completion of the query.
+#[derive(Serialize, Debug)]
+pub struct Completion {
+    /// The query, its last word completed
+    text: String,
+    /// Names having the completed word
+    frequency: i32,
+}
+
+/// Suggestions for a query.
+#[derive(Serialize, Debug)]
+pub struct Suggestions {
+    /// The completions, the most frequent first
+    completions: Vec<Completion>,
+    /// The query with its misspelled words corrected, `None` if none is
+    did_you_mean: Option<String>,
+}
+
+/// The lowercase words of `query`, split like the names are in the `search_terms`
+/// table, and whether the last one is still being typed.
+fn words(query: &str) -> (Vec<String>, bool) {
+    let words = query
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect::<Vec<_>>();
+    let typing = query.ends_with(|c: char| c.is_ascii_alphanumeric());
+    (words, typing)
+}
+
+/// `words`, with `last` as their last one.
+fn phrase(words: &[String], last: &str) -> String {
+    match words.split_last() {
+        Some((_, previous)) if !previous.is_empty() => format!("{} {}", previous.join(" "), last),
+        _ => last.to_string(),
+    }
+}
+
+/// The suggestions for `query`, at most `limit` completions.
+pub async fn suggest(db: Db, query: &str, limit: i64) -> Result<Suggestions, AppError> {
+    let (words, typing) = words(query);
+    let completions = match words.last() {
+        Some(prefix) if typing => PgSearchTermRepo(db)
+            .complete(prefix, limit)
+            .await?
+            .into_iter()
+            .map(|(term, frequency)| Completion {
+                text: phrase(&words, &term),
+                frequency,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    // A word being typed is only misspelled when nothing completes it
+    let checked = if typing && !completions.is_empty() {
+        &words[..words.len() - 1]
+    } else {
+        &words[..]
+    };
+    let known = PgSearchTermRepo(db).known(checked).await?;
+    let mut corrected = words.clone();
+    let mut changed = false;
+    for (word, correction) in checked.iter().zip(corrected.iter_mut()) {
+        if word.len() < MIN_TERM_LENGTH || known.contains(word) {
+            continue;
+        }
+        if let Some((term, _)) = PgSearchTermRepo(db)
+            .similar(word, MIN_SIMILARITY, 1)
+            .await?
+            .into_iter()
+            .next()
+        {
+            *correction = term;
+            changed = true;
+        }
+    }
+
+    Ok(Suggestions {
+        completions,
+        did_you_mean: changed.then(|| corrected.join(" ")),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queries_are_split_like_the_names() {
+        assert_eq!(
+            words("Espresso-Mach"),
+            (vec!["espresso".to_string(), "mach".to_string()], true)
+        );
+        assert_eq!(words("milk  "), (vec!["milk".to_string()], false));
+        assert_eq!(words(" ,"), (Vec::new(), false));
+        assert_eq!(
+            phrase(&words("espresso mach").0, "machine"),
+            "espresso machine"
+        );
+        assert_eq!(phrase(&words("mi").0, "milk"), "milk");
+    }
+}
@@ -0,0 +1,244 @@
+//! Administration tasks of the command line (`migrate`, `create-admin`, `routes`,
+//! `selftest`).
+//!
+//! They share the configuration and the database layer of the server, so the same
+//! binary and settings serve and operate a deployment. The database tasks start the
+//! pool with [`connect`] and close it with `close_pool`.
+
+mod selftest;
+
+use std::fmt::Write;
+
+use uuid::Uuid;
+
+use crate::config::{DatabaseConfig, Setting};
+use crate::db::{Db, init_pool, migration_status, plan_migrations, rollback_migration};
+use crate::repository::users::{PgUserRepo, UserRepository};
+use crate::roles::Role;
+use crate::router::router;
+use crate::routes::auth::{RegisterRequest, create_account};
+
+pub use crate::db::{MigrationStatus, Phase, PlannedMigration};
+pub use selftest::{Check, Outcome, Report, selftest};
+
+/// Starts the database pool, without applying the migrations.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - Success or the reason the database can't be used
+pub async fn connect(config: &DatabaseConfig) -> Result<(), String> {
+    init_pool(config)
+        .await
+        .map_err(|e| format!("Error starting database pool: {}", e))
+}
+
+/// Every migration, and when the database got it (`migrate status`).
+pub async fn migrations() -> Result<Vec<MigrationStatus>, String> {
+    migration_status()
+        .await
+        .map_err(|e| format!("Error reading the applied migrations: {}", e))
+}
+
+/// The pending migrations, and whether `migrate` would apply them (`migrate --plan`,
+/// with `--contract` for `contract`).
+pub async fn plan(contract: bool) -> Result<Vec<PlannedMigration>, String> {
+    plan_migrations(contract)
+        .await
+        .map_err(|e| format!("Error reading the applied migrations: {}", e))
+}
+
+/// Undoes the latest applied migration (`migrate rollback`).
+///
+/// # Returns
+///
+/// * `Result<Option<MigrationStatus>, String>` - The migration undone, `None` when the
+///   database has none
+pub async fn rollback() -> Result<Option<MigrationStatus>, String> {
+    rollback_migration()
+        .await
+        .map_err(|e| format!("Error undoing the latest migration: {}", e))
+}
+
+/// The migrations of `migrate status`, one line each with the time it was applied or
+/// `pending`.
+pub fn migration_table(migrations: &[MigrationStatus]) -> String {
+    let mut table = String::new();
+    for migration in migrations {
+        let _ = writeln!(
+            table,
+            "V{:<4} {:<32} {:<9} {}",
+            migration.version,
+            migration.name,
+            migration.phase.as_str(),
+            migration.applied_at.as_deref().unwrap_or("pending")
+        );
+    }
+    table
+}
+
+/// Creates an account with the admin role (`create-admin`), able to call every route,
+/// with the rules of `POST /api/v1/auth/register`.
+///
+/// # Returns
+///
+/// * `Result<Uuid, String>` - The ID of the account, or why it can't be created
+pub async fn create_admin(
+    name: String,
+    age: i32,
+    email: String,
+    password: String,
+) -> Result<Uuid, String> {
+    let request = RegisterRequest {
+        name,
+        age,
+        email,
+        password,
+    };
+    let (id, public_id, _) = create_account(Db::default(), request)
+        .await
+        .map_err(|e| format!("Error creating the account: {}", e))?;
+    PgUserRepo::default()
+        .set_role(id, Role::Admin)
+        .await
+        .map_err(|e| format!("Error making the account an administrator: {}", e))?;
+    Ok(public_id)
+}
+
+/// The effective settings (`config print-effective`), one `KEY=value` line each with
+/// its source, the secrets masked and the unset ones commented out.
+pub fn effective_config(settings: &[Setting]) -> String {
+    let mut text = String::new();
+    for setting in settings {
+        let line = match &setting.value {
+            None => format!("# {}=", setting.key),
+            Some(_) if setting.is_secret() => format!("{}=********", setting.key),
+            Some(value) => format!("{}={}", setting.key, value),
+        };
+        let _ = writeln!(text, "{:<48} # {}", line, setting.origin.as_str());
+    }
+    text
+}
+
+/// The route table (`routes`): method, pattern and authentication of every route.
+pub fn route_table() -> String {
+    let mut table = String::new();
+    for route in router().routes() {
+        let auth = match route.required_role {
+            Some(role) => format!("  (auth, {})", role),
+            None if route.requires_auth => "  (auth)".to_string(),
+            None => String::new(),
+        };
+        let _ = writeln!(
+            table,
+            "{:<7} {}{}{}{}",
+            route.method.as_str(),
+            route.pattern,
+            auth,
+            if route.accepts_signed_urls {
+                "  (signed URLs)"
+            } else {
+                ""
+            },
+            if route.queues_when_offline {
+                "  (queued offline)"
+            } else {
+                ""
+            }
+        );
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Origin;
+
+    #[test]
+    fn migration_table_tells_the_pending_ones() {
+        let migrations = [
+            MigrationStatus {
+                version: 1,
+                name: "create_users",
+                phase: Phase::Expand,
+                applied_at: Some("2026-01-02T03:04:05Z".to_string()),
+            },
+            MigrationStatus {
+                version: 19,
+                name: "partition_by_month",
+                phase: Phase::Contract,
+                applied_at: None,
+            },
+        ];
+        assert_eq!(
+            migration_table(&migrations),
+            "V1    create_users                     expand    2026-01-02T03:04:05Z\n\
+             V19   partition_by_month               contract  pending\n"
+        );
+        assert_eq!(migration_table(&[]), "");
+    }
+
+    #[test]
+    fn effective_config_masks_the_secrets() {
+        let setting = |key: &str, value: Option<&str>, origin| Setting {
+            key: key.to_string(),
+            value: value.map(str::to_string),
+            origin,
+        };
+        let settings = [
+            setting(
+                "DATABASE_URL",
+                Some("postgres://app:hunter2@db/shop"),
+                Origin::Env,
+            ),
+            setting("DB_PASSWORD", Some("hunter2"), Origin::File),
+            setting("DB_PORT", Some("5433"), Origin::Cli),
+            setting("JWT_SECRET", Some("hunter2"), Origin::Env),
+            setting("LDAP_URL", None, Origin::Unset),
+            setting("LOG_LEVEL", Some("info"), Origin::Default),
+            setting("OPENAI_API_KEY", Some("hunter2"), Origin::Env),
+            setting("SCIM_TOKEN", Some("hunter2"), Origin::Env),
+        ];
+        let text = effective_config(&settings);
+        assert!(!text.contains("hunter2"), "{}", text);
+        let lines = text.lines().map(str::trim_end).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                format!("{:<48} # env", "DATABASE_URL=********"),
+                format!("{:<48} # file", "DB_PASSWORD=********"),
+                format!("{:<48} # cli", "DB_PORT=5433"),
+                format!("{:<48} # env", "JWT_SECRET=********"),
+                format!("{:<48} # unset", "# LDAP_URL="),
+                format!("{:<48} # default", "LOG_LEVEL=info"),
+                format!("{:<48} # env", "OPENAI_API_KEY=********"),
+                format!("{:<48} # env", "SCIM_TOKEN=********"),
+            ]
+        );
+    }
+
+    #[test]
+    fn route_table_tells_the_authentication() {
+        let table = route_table();
+        let line = |start: &str| {
+            table
+                .lines()
+                .find(|line| line.starts_with(start))
+                .unwrap_or_else(|| panic!("{} missing from:\n{}", start, table))
+                .to_string()
+        };
+        assert_eq!(line("GET     /healthz"), "GET     /healthz");
+        assert_eq!(
+            line("GET     /api/v1/auth/me"),
+            "GET     /api/v1/auth/me  (auth)"
+        );
+        assert_eq!(
+            line("GET     /admin/retention"),
+            "GET     /admin/retention  (auth, admin)  (signed URLs)"
+        );
+        assert_eq!(
+            line("PUT     /api/v1/users/:id"),
+            "PUT     /api/v1/users/:id  (auth)  (queued offline)"
+        );
+    }
+}
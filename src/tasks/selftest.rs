@@ -0,0 +1,463 @@
+//! Self-test of a deployment (`selftest`), for the pipelines to run before switching
+//! traffic to a new release.
+//!
+//! Every check runs, even after a failure, so the report lists all the problems at
+//! once: the settings, the database servers and a read/write probe of the primary, the
+//! file storage, the files loaded at startup, the TLS certificate, the upstream
+//! services and the directory, and the rendering of the OpenAPI document. Nothing is
+//! left behind: the probe rolls back its writes, the test file is deleted, and no
+//! migration is applied.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use futures_util::{TryStreamExt, stream};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper::{Request, StatusCode, Uri};
+use serde::Serialize;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+use crate::auth::{init_auth, issue_token};
+use crate::computed::init_computed_fields;
+use crate::config::{AppConfig, CertificateSource};
+use crate::console::init_console;
+use crate::db::{init_pool, plan_migrations, probe};
+use crate::error::AppError;
+use crate::experiments::init_experiments;
+use crate::geo_policy::load_policies;
+use crate::geoip::init_geoip;
+use crate::http_client::http_client;
+use crate::ldap;
+use crate::metrics::init_slos;
+use crate::region::init_region;
+use crate::retention::init_retention;
+use crate::routes::docs;
+use crate::storage::{ObjectStore, init_storage, store};
+use crate::tls::load_tls_acceptor;
+
+/// Time each upstream service has to answer
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How a check ended.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Pass,
+    Fail,
+    /// Not configured, or depending on a check that failed
+    Skip,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Pass => "PASS",
+            Outcome::Fail => "FAIL",
+            Outcome::Skip => "SKIP",
+        }
+    }
+}
+
+/// A check of the report.
+#[derive(Serialize, Debug)]
+pub struct Check {
+    pub name: String,
+    pub outcome: Outcome,
+    /// What was found, or why the check failed or was skipped
+    pub detail: String,
+    pub duration_ms: u64,
+}
+
+/// Result of `selftest`, printed as a table or as JSON (`--json`).
+#[derive(Serialize, Debug)]
+pub struct Report {
+    /// Whether no check failed
+    pub passed: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    fn new(checks: Vec<Check>) -> Self {
+        Report {
+            passed: checks.iter().all(|check| check.outcome != Outcome::Fail),
+            checks,
+        }
+    }
+
+    /// The report of settings that can't be loaded, before anything else can be checked.
+    pub fn invalid_config(error: &str) -> Self {
+        Report::new(vec![Check {
+            name: "config".to_string(),
+            outcome: Outcome::Fail,
+            detail: error.to_string(),
+            duration_ms: 0,
+        }])
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            writeln!(
+                f,
+                "{}  {:<24} {:>6} ms  {}",
+                check.outcome.as_str(),
+                check.name,
+                check.duration_ms,
+                check.detail
+            )?;
+        }
+        let failed = self
+            .checks
+            .iter()
+            .filter(|check| check.outcome == Outcome::Fail)
+            .count();
+        if failed == 0 {
+            write!(f, "All {} checks passed", self.checks.len())
+        } else {
+            write!(f, "{} of {} checks failed", failed, self.checks.len())
+        }
+    }
+}
+
+/// Runs `probe` as the check `name`, its error failing it.
+async fn check(
+    name: impl Into<String>,
+    probe: impl Future<Output = Result<String, String>>,
+) -> Check {
+    let start = Instant::now();
+    let result = probe.await;
+    let (outcome, detail) = match result {
+        Ok(detail) => (Outcome::Pass, detail),
+        Err(e) => (Outcome::Fail, e),
+    };
+    Check {
+        name: name.into(),
+        outcome,
+        detail,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+fn skip(name: impl Into<String>, reason: &str) -> Check {
+    Check {
+        name: name.into(),
+        outcome: Outcome::Skip,
+        detail: reason.to_string(),
+        duration_ms: 0,
+    }
+}
+
+/// Runs every check with the settings `config`, which starts the database pool.
+pub async fn selftest(config: &AppConfig) -> Report {
+    let mut checks = Vec::new();
+
+    checks.push(
+        check("config", async {
+            init_auth(&config.auth)?;
+            issue_token(0).map_err(|e| format!("Unable to sign access tokens: {}", e))?;
+            Ok("Settings valid, access tokens signed".to_string())
+        })
+        .await,
+    );
+
+    let database = check("database", async {
+        init_pool(&config.database).await?;
+        let version = probe::primary().await.map_err(|e| e.to_string())?;
+        let pending = plan_migrations(false)
+            .await
+            .map_err(|e| format!("Unable to read the applied migrations: {}", e))?;
+        Ok(format!("{}, {} pending migrations", version, pending.len()))
+    })
+    .await;
+    let connected = database.outcome == Outcome::Pass;
+    checks.push(database);
+    if connected {
+        for (name, result) in probe::replicas().await {
+            checks.push(check(name, async { result.map_err(|e| e.to_string()) }).await);
+        }
+        checks.push(
+            check("database read/write", async {
+                probe::read_write().await.map_err(|e| e.to_string())?;
+                Ok("Row written, read, updated and deleted in a scratch table".to_string())
+            })
+            .await,
+        );
+    } else {
+        for i in 0..config.database.replicas.len() {
+            checks.push(skip(format!("replica-{}", i + 1), "No database"));
+        }
+        checks.push(skip("database read/write", "No database"));
+    }
+
+    checks.push(
+        check("storage", async {
+            init_storage(&config.storage)?;
+            storage_probe().await
+        })
+        .await,
+    );
+
+    checks.push(match &config.geo.geoip_db_path {
+        Some(path) => {
+            check("geoip", async {
+                init_geoip(Some(path))?;
+                Ok(format!("Loaded {}", path.display()))
+            })
+            .await
+        }
+        None => skip("geoip", "GEOIP_DB_PATH not set"),
+    });
+    checks.push(match &config.geo.policy_path {
+        Some(path) => {
+            check("geo policies", async {
+                let count = load_policies(Some(path))?;
+                Ok(format!("{} policies in {}", count, path.display()))
+            })
+            .await
+        }
+        None => skip("geo policies", "GEO_POLICY_PATH not set"),
+    });
+    checks.push(match &config.region.name {
+        Some(name) => {
+            check("region", async {
+                init_region(&config.region, !config.database.replicas.is_empty())?;
+                Ok(format!("Region {}", name))
+            })
+            .await
+        }
+        None => skip("region", "REGION not set"),
+    });
+    checks.push(match &config.experiments.path {
+        Some(path) => {
+            check("experiments", async {
+                let count = init_experiments(Some(path))?;
+                Ok(format!("{} experiments in {}", count, path.display()))
+            })
+            .await
+        }
+        None => skip("experiments", "EXPERIMENTS_PATH not set"),
+    });
+    checks.push(match &config.jobs.retention_path {
+        Some(path) => {
+            check("retention rules", async {
+                let count = init_retention(Some(path))?.len();
+                Ok(format!("{} rules in {}", count, path.display()))
+            })
+            .await
+        }
+        None => skip("retention rules", "RETENTION_RULES_PATH not set"),
+    });
+    checks.push(match &config.console.queries_path {
+        Some(path) => {
+            check("console queries", async {
+                let count = init_console(Some(path))?;
+                Ok(format!("{} queries in {}", count, path.display()))
+            })
+            .await
+        }
+        None => skip("console queries", "CONSOLE_QUERIES_PATH not set"),
+    });
+    checks.push(match &config.computed.path {
+        Some(path) => {
+            check("computed fields", async {
+                let count = init_computed_fields(Some(path))?;
+                Ok(format!("{} fields in {}", count, path.display()))
+            })
+            .await
+        }
+        None => skip("computed fields", "COMPUTED_FIELDS_PATH not set"),
+    });
+    checks.push(match &config.slo.path {
+        Some(path) => {
+            check("slos", async {
+                let count = init_slos(Some(path))?;
+                Ok(format!("{} objectives in {}", count, path.display()))
+            })
+            .await
+        }
+        None => skip("slos", "SLO_PATH not set"),
+    });
+
+    checks.push(match config.tls.as_ref().map(|tls| &tls.certificate) {
+        Some(CertificateSource::Files {
+            cert_path,
+            key_path,
+        }) => {
+            check("tls", async {
+                load_tls_acceptor(cert_path, key_path)?;
+                Ok(format!("Certificate {} and its key loaded", cert_path))
+            })
+            .await
+        }
+        // Obtained by the server at startup, when the challenges can be answered
+        Some(CertificateSource::Acme(_)) => skip("tls", "Certificate obtained with ACME"),
+        Some(CertificateSource::SelfSigned) => skip("tls", "Self-signed certificate (DEV_TLS)"),
+        None => skip("tls", "HTTPS disabled"),
+    });
+
+    if let Some(legacy) = &config.legacy_proxy {
+        // Any response will do, the service may answer nothing useful at its root
+        checks.push(
+            check("legacy upstream", async {
+                let status = get(&legacy.upstream).await?;
+                if status.is_server_error() {
+                    return Err(format!("{} answered {}", legacy.upstream, status));
+                }
+                Ok(format!("{} answered {}", legacy.upstream, status))
+            })
+            .await,
+        );
+    }
+    if let Some(ldap) = &config.ldap {
+        checks.push(check("directory", ldap::probe(ldap)).await);
+    }
+    for (name, url) in &config.dashboard.upstreams {
+        checks.push(
+            check(format!("dashboard upstream {}", name), async {
+                let status = get(url).await?;
+                if !status.is_success() {
+                    return Err(format!("{} answered {}", url, status));
+                }
+                Ok(format!("{} answered {}", url, status))
+            })
+            .await,
+        );
+    }
+
+    checks.push(
+        check("openapi document", async {
+            let document = docs::document();
+            let paths = document["paths"].as_object().map_or(0, |paths| paths.len());
+            let rendered = serde_json::to_string(document).map_err(|e| e.to_string())?;
+            Ok(format!("{} paths, {} bytes", paths, rendered.len()))
+        })
+        .await,
+    );
+
+    Report::new(checks)
+}
+
+/// Writes a file to the storage, reads it back and deletes it.
+async fn storage_probe() -> Result<String, String> {
+    // At the root, so no directory is left behind
+    let key = format!("selftest-{}.txt", Uuid::new_v4());
+    let content = Bytes::from_static(b"selftest");
+    let chunks = stream::once(async { Ok(content.clone()) });
+    let location = store().put(&key, chunks).await.map_err(|e| e.to_string())?;
+
+    let read = async {
+        let file = store()
+            .get(&location)
+            .await?
+            .ok_or_else(|| AppError::Internal("The file written is missing".to_string()))?;
+        file.chunks
+            .try_fold(Vec::new(), async |mut read, chunk| {
+                read.extend_from_slice(&chunk);
+                Ok(read)
+            })
+            .await
+    }
+    .await;
+    store().delete(&location).await.map_err(|e| e.to_string())?;
+    if read.map_err(|e| e.to_string())? != content {
+        return Err("The file read back differs from the one written".to_string());
+    }
+    Ok(format!("File {} written, read and deleted", location))
+}
+
+/// Status of a `GET` of `url`, within `UPSTREAM_TIMEOUT`.
+async fn get(url: &Uri) -> Result<StatusCode, String> {
+    let req = Request::get(url.clone())
+        .body(
+            Empty::<Bytes>::new()
+                .map_err(|never| match never {})
+                .boxed_unsync(),
+        )
+        .map_err(|e| e.to_string())?;
+    match timeout(UPSTREAM_TIMEOUT, http_client().request(req)).await {
+        Ok(Ok(res)) => Ok(res.status()),
+        Ok(Err(e)) => Err(format!("{} unreachable: {}", url, e)),
+        Err(_) => Err(format!(
+            "{} didn't answer within {} s",
+            url,
+            UPSTREAM_TIMEOUT.as_secs()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn stub(name: &str, outcome: Outcome, detail: &str) -> Check {
+        Check {
+            name: name.to_string(),
+            outcome,
+            detail: detail.to_string(),
+            duration_ms: 3,
+        }
+    }
+
+    #[tokio::test]
+    async fn probes_pass_unless_they_fail() {
+        let passed = check("database", async { Ok("PostgreSQL 17".to_string()) }).await;
+        assert_eq!(passed.name, "database");
+        assert_eq!(passed.outcome, Outcome::Pass);
+        assert_eq!(passed.detail, "PostgreSQL 17");
+
+        let failed = check("storage", async { Err("Permission denied".to_string()) }).await;
+        assert_eq!(failed.outcome, Outcome::Fail);
+        assert_eq!(failed.detail, "Permission denied");
+
+        let skipped = skip("tls", "HTTPS disabled");
+        assert_eq!(skipped.outcome, Outcome::Skip);
+        assert_eq!(skipped.duration_ms, 0);
+    }
+
+    #[test]
+    fn reports_fail_with_any_check() {
+        let report = Report::new(vec![
+            stub("config", Outcome::Pass, "Settings valid"),
+            stub("tls", Outcome::Skip, "HTTPS disabled"),
+        ]);
+        assert!(report.passed);
+        assert_eq!(
+            report.to_string(),
+            "PASS  config                        3 ms  Settings valid\n\
+             SKIP  tls                           3 ms  HTTPS disabled\n\
+             All 2 checks passed"
+        );
+
+        let report = Report::new(vec![
+            stub("config", Outcome::Pass, "Settings valid"),
+            stub("database", Outcome::Fail, "Connection refused"),
+            stub("database read/write", Outcome::Skip, "No database"),
+        ]);
+        assert!(!report.passed);
+        assert!(report.to_string().ends_with("\n1 of 3 checks failed"));
+        assert!(Report::new(Vec::new()).passed);
+    }
+
+    #[test]
+    fn invalid_settings_fail_the_report() {
+        let report = Report::invalid_config("invalid configuration");
+        assert!(!report.passed);
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            json!({
+                "passed": false,
+                "checks": [{
+                    "name": "config",
+                    "outcome": "fail",
+                    "detail": "invalid configuration",
+                    "duration_ms": 0,
+                }],
+            })
+        );
+    }
+}
@@ -0,0 +1,225 @@
+//! TLS (HTTPS) support.
+//!
+//! When `TLS_CERT_PATH` and `TLS_KEY_PATH` are set, or `ACME_DOMAINS` (see the `acme`
+//! module), the server also accepts HTTPS connections on `TLS_PORT` (3443 by default),
+//! alongside plain HTTP on `PORT`. In development, `DEV_TLS` (`--dev-tls`) serves a
+//! self-signed certificate for `localhost` instead, generated at startup.
+//! Accepted TCP streams are wrapped in a TLS session with `tokio-rustls` before
+//! being handed to hyper, with the HTTP version negotiated by ALPN (`h2` or `http/1.1`).
+//!
+//! With `HTTP_REDIRECT_PORT`, a plain HTTP listener on that port answers every request
+//! with a redirect to HTTPS (see [`redirect_to_https`]).
+
+use std::sync::Arc;
+
+use hyper::header::{HOST, HeaderValue, LOCATION};
+use hyper::http::uri::Authority;
+use hyper::{Request, Response, StatusCode};
+use rcgen::{CertificateParams, KeyPair};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tracing::warn;
+
+use crate::config::{CertificateSource, TlsConfig};
+use crate::error::AppError;
+use crate::router::{Body, empty_response, error_response};
+
+pub use acme::{ACME_TLS_ALPN, answer_http_challenge};
+
+mod acme;
+
+/// HTTPS listener settings built from the configuration.
+pub struct TlsSettings {
+    pub acceptor: TlsAcceptor,
+    pub port: u16,
+    pub redirect_port: Option<u16>,
+}
+
+/// Loads the certificate and key of the HTTPS listener.
+///
+/// # Arguments
+///
+/// * `config` - The TLS settings, `None` when TLS is not configured
+///
+/// # Returns
+///
+/// * `Result<Option<TlsSettings>, String>` - `None` when TLS is not configured,
+///   or an error message if the certificate/key can't be loaded
+pub async fn tls_settings(config: Option<&TlsConfig>) -> Result<Option<TlsSettings>, String> {
+    let Some(config) = config else {
+        return Ok(None);
+    };
+
+    let acceptor = match &config.certificate {
+        CertificateSource::Files {
+            cert_path,
+            key_path,
+        } => load_tls_acceptor(cert_path, key_path)?,
+        CertificateSource::Acme(acme) => acme::init_acme(acme).await?,
+        CertificateSource::SelfSigned => {
+            warn!("HTTPS uses a self-signed certificate for localhost, for development only");
+            self_signed_acceptor()?
+        }
+    };
+    Ok(Some(TlsSettings {
+        acceptor,
+        port: config.port,
+        redirect_port: config.redirect_port,
+    }))
+}
+
+/// Builds a TLS acceptor from a PEM certificate chain and a PEM private key.
+///
+/// # Arguments
+///
+/// * `cert_path` - Path to the certificate chain (leaf certificate first)
+/// * `key_path` - Path to the private key (PKCS#8, PKCS#1 or SEC1)
+pub fn load_tls_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Unable to read certificate '{}': {}", cert_path, e))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("Unable to read private key '{}': {}", key_path, e))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid certificate/key pair: {}", e))?;
+    config.alpn_protocols = http_alpn_protocols();
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Builds a TLS acceptor with a new self-signed certificate for `localhost`,
+/// `127.0.0.1` and `::1` (`DEV_TLS`). Clients have to be told to trust it (`curl -k`),
+/// a new one is generated at each start.
+pub fn self_signed_acceptor() -> Result<TlsAcceptor, String> {
+    let (certificate, key) = self_signed_certificate()?;
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![certificate], key)
+        .map_err(|e| format!("Invalid certificate/key pair: {}", e))?;
+    config.alpn_protocols = http_alpn_protocols();
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Generates a key and a certificate for `localhost`, `127.0.0.1` and `::1`, signed
+/// with it.
+fn self_signed_certificate() -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>), String> {
+    let key = KeyPair::generate().map_err(|e| format!("Unable to generate a key: {}", e))?;
+    let names = ["localhost", "127.0.0.1", "::1"].map(String::from).to_vec();
+    let certificate = CertificateParams::new(names)
+        .and_then(|params| params.self_signed(&key))
+        .map_err(|e| format!("Unable to generate a certificate: {}", e))?;
+    let key = PrivateKeyDer::Pkcs8(key.serialize_der().into());
+    Ok((certificate.der().clone(), key))
+}
+
+/// The protocols offered by ALPN: HTTP/2 is preferred, clients that don't support it
+/// fall back to HTTP/1.1.
+fn http_alpn_protocols() -> Vec<Vec<u8>> {
+    vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+}
+
+/// Redirects a plain HTTP request to the same URL in HTTPS, with 301 Moved Permanently.
+/// The ACME HTTP-01 challenges are answered instead.
+///
+/// # Arguments
+///
+/// * `https_port` - Port of the HTTPS listener, left out of the URL when it's 443
+pub fn redirect_to_https<B>(req: &Request<B>, https_port: u16) -> Response<Body> {
+    if let Some(answer) = answer_http_challenge(req) {
+        return answer;
+    }
+
+    // HTTP/2 requests carry the host in the URI, HTTP/1 ones in `Host`
+    let host = match req.uri().authority() {
+        Some(authority) => Some(authority.as_str()),
+        None => req.headers().get(HOST).and_then(|host| host.to_str().ok()),
+    };
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    let location = host
+        .and_then(|host| https_url(host, https_port, path_and_query))
+        .and_then(|url| HeaderValue::from_str(&url).ok());
+
+    match location {
+        Some(location) => {
+            let mut res = empty_response(StatusCode::MOVED_PERMANENTLY);
+            res.headers_mut().insert(LOCATION, location);
+            res
+        }
+        None => error_response(AppError::Validation(
+            "Missing or invalid Host header".to_string(),
+        )),
+    }
+}
+
+/// The HTTPS URL of a request made to `host` (`Host` header, with or without port).
+fn https_url(host: &str, https_port: u16, path_and_query: &str) -> Option<String> {
+    let authority: Authority = host.parse().ok()?;
+    Some(match https_port {
+        443 => format!("https://{}{}", authority.host(), path_and_query),
+        port => format!("https://{}:{}{}", authority.host(), port, path_and_query),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use x509_parser::extensions::GeneralName;
+
+    use super::*;
+
+    #[test]
+    fn generates_a_certificate_for_localhost() {
+        let acceptor = self_signed_acceptor().unwrap();
+        assert_eq!(
+            acceptor.config().alpn_protocols,
+            [b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+
+        let (certificate, _) = self_signed_certificate().unwrap();
+        let (_, x509) = x509_parser::parse_x509_certificate(&certificate).unwrap();
+        let names: Vec<String> = x509
+            .subject_alternative_name()
+            .unwrap()
+            .unwrap()
+            .value
+            .general_names
+            .iter()
+            .map(|name| match name {
+                GeneralName::DNSName(domain) => domain.to_string(),
+                GeneralName::IPAddress(ip) => match <[u8; 4]>::try_from(*ip) {
+                    Ok(v4) => IpAddr::from(v4).to_string(),
+                    Err(_) => IpAddr::from(<[u8; 16]>::try_from(*ip).unwrap()).to_string(),
+                },
+                other => panic!("Unexpected name {:?}", other),
+            })
+            .collect();
+        assert_eq!(names, ["localhost", "127.0.0.1", "::1"]);
+    }
+
+    #[test]
+    fn https_url_keeps_the_host_and_path() {
+        assert_eq!(
+            https_url("example.com:8080", 443, "/users?limit=5").as_deref(),
+            Some("https://example.com/users?limit=5")
+        );
+        assert_eq!(
+            https_url("example.com", 3443, "/").as_deref(),
+            Some("https://example.com:3443/")
+        );
+        assert_eq!(
+            https_url("[::1]:80", 443, "/").as_deref(),
+            Some("https://[::1]/")
+        );
+        assert_eq!(https_url("bad host", 443, "/"), None);
+    }
+}
@@ -0,0 +1,445 @@
+//! Automatic certificates from an ACME certificate authority (Let's Encrypt).
+//!
+//! With `ACME_DOMAINS`, the certificate of the HTTPS listener is ordered from the CA of
+//! `ACME_DIRECTORY_URL` at startup, then renewed in the background 30 days before it
+//! expires. The CA checks the server controls the domains with the challenge of
+//! `ACME_CHALLENGE`:
+//!
+//! - `http-01`: the plain HTTP listener answers `GET /.well-known/acme-challenge/<token>`
+//!   (see [`answer_http_challenge`]), the CA connecting to port 80.
+//! - `tls-alpn-01`: the HTTPS listener presents a challenge certificate to the clients
+//!   asking for the `acme-tls/1` protocol, the CA connecting to port 443.
+//!
+//! The account key, the certificate and its key are kept in the file storage under
+//! `acme/` (see the `storage` module), so a restart doesn't order a new certificate.
+//! HTTPS handshakes fail until the first certificate is obtained.
+//!
+//! The challenges are answered by the instance ordering the certificate: several
+//! instances behind a load balancer should use certificate files instead.
+
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures_util::{StreamExt, stream};
+use hyper::body::Bytes;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Method, Request, Response, StatusCode};
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+use ring::digest::{SHA256, digest};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::ring::sign::any_supported_type;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tracing::{error, info, warn};
+use x509_parser::extensions::GeneralName;
+
+use self::client::AcmeClient;
+use super::http_alpn_protocols;
+use crate::config::{AcmeChallenge, AcmeConfig};
+use crate::router::Body;
+use crate::shutdown::stopping;
+use crate::storage::{ObjectStore, store};
+
+mod client;
+
+/// Protocol of the TLS-ALPN-01 challenges (RFC 8737)
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// Path of the HTTP-01 challenges, followed by their token
+const HTTP_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Certificates are renewed when they expire within this
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+/// Time between two checks of the expiry
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+/// Time before ordering again after a failed order
+const RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+// Keys in the file storage
+const ACCOUNT_KEY: &str = "acme/account-key.pem";
+const CERTIFICATE: &str = "acme/certificate.pem";
+const CERTIFICATE_KEY: &str = "acme/certificate-key.pem";
+
+// Set once at startup, with `ACME_DOMAINS`
+static ACME: OnceLock<Acme> = OnceLock::new();
+
+struct Acme {
+    config: AcmeConfig,
+    resolver: Arc<CertificateResolver>,
+    /// Answers to the pending HTTP-01 challenges, by token
+    http_challenges: RwLock<HashMap<String, String>>,
+}
+
+/// Picks the certificate of each TLS handshake: a challenge certificate for the CA
+/// validating a domain, the ordered certificate for everyone else.
+#[derive(Debug, Default)]
+struct CertificateResolver {
+    /// `None` until the first certificate is obtained
+    current: RwLock<Option<Certificate>>,
+    /// Answers to the pending TLS-ALPN-01 challenges, by domain
+    alpn_challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+/// An ordered certificate.
+#[derive(Debug)]
+struct Certificate {
+    key: Arc<CertifiedKey>,
+    /// The domains it names
+    domains: Vec<String>,
+    expires: SystemTime,
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let validating = client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|protocol| protocol == ACME_TLS_ALPN));
+        if validating {
+            let domain = client_hello.server_name()?;
+            return self
+                .alpn_challenges
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .get(domain)
+                .cloned();
+        }
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map(|certificate| certificate.key.clone())
+    }
+}
+
+/// Loads the stored certificate and starts renewing it in the background.
+/// This function should be called once at application startup, after `init_storage`.
+///
+/// # Returns
+///
+/// * `Result<TlsAcceptor, String>` - The acceptor of the HTTPS listener, serving the
+///   certificate of the moment
+pub(super) async fn init_acme(config: &AcmeConfig) -> Result<TlsAcceptor, String> {
+    let resolver = Arc::new(CertificateResolver::default());
+    match load_certificate().await {
+        Ok(Some(certificate)) => {
+            info!(
+                "ACME certificate loaded for {} ({} days left)",
+                certificate.domains.join(", "),
+                days_left(certificate.expires)
+            );
+            *resolver.current.write().unwrap_or_else(|e| e.into_inner()) = Some(certificate);
+        }
+        Ok(None) => info!(
+            "No ACME certificate yet, ordering one for {}",
+            config.domains.join(", ")
+        ),
+        Err(e) => warn!("Stored ACME certificate ignored: {}", e),
+    }
+
+    let acme = Acme {
+        config: config.clone(),
+        resolver: resolver.clone(),
+        http_challenges: RwLock::default(),
+    };
+    if ACME.set(acme).is_err() {
+        warn!("Attempt to reset ACME ignored");
+    } else if let Some(acme) = ACME.get() {
+        tokio::spawn(acme.keep_renewed());
+    }
+
+    let mut tls = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    tls.alpn_protocols = http_alpn_protocols();
+    if config.challenge == AcmeChallenge::TlsAlpn01 {
+        tls.alpn_protocols.push(ACME_TLS_ALPN.to_vec());
+    }
+    Ok(TlsAcceptor::from(Arc::new(tls)))
+}
+
+/// Middleware answering the HTTP-01 challenges of the CA.
+///
+/// # Returns
+///
+/// * `Option<Response<Body>>` - The answer to a pending challenge, or `None` to handle
+///   the request as usual
+pub fn answer_http_challenge<B>(req: &Request<B>) -> Option<Response<Body>> {
+    let token = req.uri().path().strip_prefix(HTTP_CHALLENGE_PATH)?;
+    if req.method() != Method::GET {
+        return None;
+    }
+    let key_authorization = ACME
+        .get()?
+        .http_challenges
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(token)?
+        .clone();
+
+    Some(
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(Body::from(key_authorization))
+            .unwrap(),
+    )
+}
+
+impl Acme {
+    /// Orders a certificate whenever the current one is about to expire, until the
+    /// shutdown.
+    async fn keep_renewed(&'static self) {
+        loop {
+            let wait = if self.needs_certificate() {
+                match self.order_certificate().await {
+                    Ok(()) => CHECK_INTERVAL,
+                    Err(e) => {
+                        error!(
+                            "Unable to obtain a certificate for {}: {}",
+                            self.config.domains.join(", "),
+                            e
+                        );
+                        RETRY_DELAY
+                    }
+                }
+            } else {
+                CHECK_INTERVAL
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = stopping() => break,
+            }
+        }
+    }
+
+    /// Whether there is no certificate, one about to expire or one missing a domain.
+    fn needs_certificate(&self) -> bool {
+        let current = self
+            .resolver
+            .current
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        let Some(certificate) = current.as_ref() else {
+            return true;
+        };
+        certificate.expires < SystemTime::now() + RENEW_BEFORE
+            || !self
+                .config
+                .domains
+                .iter()
+                .all(|domain| certificate.domains.contains(domain))
+    }
+
+    /// Orders a certificate, stores it and serves it.
+    async fn order_certificate(&self) -> Result<(), String> {
+        let account_key = account_key().await?;
+        let mut client = AcmeClient::connect(
+            &self.config.directory_url,
+            &account_key.serialize_der(),
+            self.config.email.as_deref(),
+        )
+        .await?;
+
+        let key = KeyPair::generate().map_err(|e| format!("Unable to generate a key: {}", e))?;
+        let csr = CertificateParams::new(self.config.domains.clone())
+            .and_then(|params| params.serialize_request(&key))
+            .map_err(|e| format!("Invalid certificate request: {}", e))?;
+        let chain = client.order_certificate(self, csr.der()).await?;
+
+        let key = key.serialize_pem();
+        let certificate = parse_certificate(chain.as_bytes(), key.as_bytes())?;
+        write(CERTIFICATE_KEY, key.into_bytes()).await?;
+        write(CERTIFICATE, chain.into_bytes()).await?;
+        info!(
+            "ACME certificate obtained for {} ({} days left)",
+            certificate.domains.join(", "),
+            days_left(certificate.expires)
+        );
+        *self
+            .resolver
+            .current
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = Some(certificate);
+        Ok(())
+    }
+
+    /// Publishes the answer to a challenge of `domain` for the CA to check it.
+    fn publish(&self, domain: &str, token: &str, key_authorization: &str) -> Result<(), String> {
+        match self.config.challenge {
+            AcmeChallenge::Http01 => {
+                self.http_challenges
+                    .write()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(token.to_string(), key_authorization.to_string());
+            }
+            AcmeChallenge::TlsAlpn01 => {
+                let certificate = challenge_certificate(domain, key_authorization)?;
+                self.resolver
+                    .alpn_challenges
+                    .write()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(domain.to_string(), Arc::new(certificate));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the answer to a challenge once checked.
+    fn withdraw(&self, domain: &str, token: &str) {
+        match self.config.challenge {
+            AcmeChallenge::Http01 => {
+                self.http_challenges
+                    .write()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .remove(token);
+            }
+            AcmeChallenge::TlsAlpn01 => {
+                self.resolver
+                    .alpn_challenges
+                    .write()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .remove(domain);
+            }
+        }
+    }
+}
+
+/// The self-signed certificate answering a TLS-ALPN-01 challenge: it names the domain
+/// and carries the digest of the answer in its `acmeIdentifier` extension.
+fn challenge_certificate(domain: &str, key_authorization: &str) -> Result<CertifiedKey, String> {
+    let key = KeyPair::generate().map_err(|e| format!("Unable to generate a key: {}", e))?;
+    let mut params = CertificateParams::new(vec![domain.to_string()])
+        .map_err(|e| format!("Invalid challenge certificate: {}", e))?;
+    let digest = digest(&SHA256, key_authorization.as_bytes());
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest.as_ref())];
+    let certificate = params
+        .self_signed(&key)
+        .map_err(|e| format!("Invalid challenge certificate: {}", e))?;
+
+    let signing_key = any_supported_type(&PrivateKeyDer::Pkcs8(key.serialize_der().into()))
+        .map_err(|e| format!("Invalid challenge certificate: {}", e))?;
+    Ok(CertifiedKey::new(
+        vec![certificate.der().clone()],
+        signing_key,
+    ))
+}
+
+/// Reads the stored certificate, `None` before the first order.
+async fn load_certificate() -> Result<Option<Certificate>, String> {
+    let (Some(chain), Some(key)) = (read(CERTIFICATE).await?, read(CERTIFICATE_KEY).await?) else {
+        return Ok(None);
+    };
+    parse_certificate(&chain, &key).map(Some)
+}
+
+/// Builds a certificate from its PEM chain (leaf first) and key.
+fn parse_certificate(chain: &[u8], key: &[u8]) -> Result<Certificate, String> {
+    let chain = CertificateDer::pem_slice_iter(chain)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid certificate: {}", e))?;
+    let leaf = chain
+        .first()
+        .ok_or_else(|| "Empty certificate chain".to_string())?;
+    let (_, x509) = x509_parser::parse_x509_certificate(leaf)
+        .map_err(|e| format!("Invalid certificate: {}", e))?;
+    let expires = UNIX_EPOCH
+        + Duration::from_secs(u64::try_from(x509.validity().not_after.timestamp()).unwrap_or(0));
+    let domains = match x509.subject_alternative_name() {
+        Ok(Some(names)) => names
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(domain) => Some(domain.to_lowercase()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let key =
+        PrivateKeyDer::from_pem_slice(key).map_err(|e| format!("Invalid private key: {}", e))?;
+    let signing_key =
+        any_supported_type(&key).map_err(|e| format!("Invalid private key: {}", e))?;
+    let key = CertifiedKey::new(chain, signing_key);
+    key.keys_match()
+        .map_err(|e| format!("The key doesn't match the certificate: {}", e))?;
+    Ok(Certificate {
+        key: Arc::new(key),
+        domains,
+        expires,
+    })
+}
+
+/// The key of the account, created with the first order.
+async fn account_key() -> Result<KeyPair, String> {
+    if let Some(pem) = read(ACCOUNT_KEY).await? {
+        let pem = String::from_utf8(pem).map_err(|_| "Invalid account key".to_string())?;
+        return KeyPair::from_pem(&pem).map_err(|e| format!("Invalid account key: {}", e));
+    }
+    // P-256, as ES256 signatures need
+    let key = KeyPair::generate().map_err(|e| format!("Unable to generate a key: {}", e))?;
+    write(ACCOUNT_KEY, key.serialize_pem().into_bytes()).await?;
+    Ok(key)
+}
+
+/// Days until `expires`, for the logs.
+fn days_left(expires: SystemTime) -> u64 {
+    expires
+        .duration_since(SystemTime::now())
+        .map_or(0, |left| left.as_secs() / (24 * 3600))
+}
+
+/// Reads a file of the storage, `None` if it doesn't exist.
+async fn read(key: &str) -> Result<Option<Vec<u8>>, String> {
+    let Some(object) = store().get(key).await.map_err(|e| e.to_string())? else {
+        return Ok(None);
+    };
+    let mut content = Vec::new();
+    let mut chunks = object.chunks;
+    while let Some(chunk) = chunks.next().await {
+        content.extend_from_slice(&chunk.map_err(|e| e.to_string())?);
+    }
+    Ok(Some(content))
+}
+
+async fn write(key: &str, content: Vec<u8>) -> Result<(), String> {
+    store()
+        .put(key, stream::iter([Ok(Bytes::from(content))]))
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn certificate_gives_its_domains_and_expiry() {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![
+            "api.example.com".to_string(),
+            "Example.com".to_string(),
+        ])
+        .unwrap();
+        params.not_after = rcgen::date_time_ymd(2040, 1, 1);
+        let chain = params.self_signed(&key).unwrap().pem();
+
+        let certificate =
+            parse_certificate(chain.as_bytes(), key.serialize_pem().as_bytes()).unwrap();
+        assert_eq!(certificate.domains, ["api.example.com", "example.com"]);
+        assert_eq!(
+            certificate.expires,
+            UNIX_EPOCH + Duration::from_secs(2_208_988_800)
+        );
+
+        let other = KeyPair::generate().unwrap();
+        assert!(parse_certificate(chain.as_bytes(), other.serialize_pem().as_bytes()).is_err());
+    }
+}
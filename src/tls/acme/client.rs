@@ -0,0 +1,468 @@
+//! ACME (RFC 8555) client, just what ordering a certificate takes.
+//!
+//! Every request to the CA is a JWS signed with the ES256 key of the account, carrying
+//! the nonce given by the previous response. The CA is reached over HTTPS, its
+//! certificate checked against the Mozilla root CAs.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Bytes;
+use hyper::client::conn::http1;
+use hyper::header::{CONTENT_TYPE, HOST, LOCATION, USER_AGENT};
+use hyper::{Method, Request, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use ring::digest::{SHA256, digest};
+use ring::rand::SystemRandom;
+use ring::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+use super::Acme;
+use crate::config::AcmeChallenge;
+
+/// Longest time a request to the CA may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Largest response accepted from the CA, a certificate chain takes a few KiB
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+/// Checks of an authorization or an order before giving up on it
+const POLL_ATTEMPTS: u32 = 30;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Header of the nonce to sign the next request with
+const REPLAY_NONCE: &str = "replay-nonce";
+/// Error of a request signed with an expired nonce, sent again with a fresh one
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+
+/// The URLs of the CA.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    /// URL of the certificate, once the order is `valid`
+    certificate: Option<String>,
+    error: Option<Problem>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+    error: Option<Problem>,
+}
+
+/// An error reported by the CA (RFC 7807).
+#[derive(Deserialize, Debug, Default)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.detail, self.kind)
+    }
+}
+
+/// A response of the CA.
+struct Reply {
+    status: StatusCode,
+    location: Option<String>,
+    nonce: Option<String>,
+    body: Bytes,
+}
+
+impl Reply {
+    fn json<T: DeserializeOwned>(&self) -> Result<T, String> {
+        serde_json::from_slice(&self.body).map_err(|e| format!("Unexpected response: {}", e))
+    }
+
+    fn problem(&self) -> Problem {
+        serde_json::from_slice(&self.body).unwrap_or_default()
+    }
+}
+
+/// A session with the CA, as an account.
+pub(super) struct AcmeClient {
+    connector: TlsConnector,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    /// URL of the account, `None` until it's registered
+    account_url: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Registers the account of `account_key` with the CA, or finds it if it exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `directory_url` - URL of the directory of the CA
+    /// * `account_key` - PKCS#8 P-256 key of the account
+    /// * `email` - Contact of the account
+    pub(super) async fn connect(
+        directory_url: &str,
+        account_key: &[u8],
+        email: Option<&str>,
+    ) -> Result<Self, String> {
+        let rng = SystemRandom::new();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, account_key, &rng)
+            .map_err(|e| format!("Invalid account key: {}", e))?;
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+
+        let reply = send(&connector, Method::GET, directory_url, None).await?;
+        if !reply.status.is_success() {
+            return Err(format!(
+                "{} from {}: {}",
+                reply.status,
+                directory_url,
+                reply.problem()
+            ));
+        }
+        let mut client = AcmeClient {
+            connector,
+            directory: reply.json()?,
+            key,
+            rng,
+            account_url: None,
+            nonce: None,
+        };
+
+        let mut account = json!({"termsOfServiceAgreed": true});
+        if let Some(email) = email {
+            account["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let url = client.directory.new_account.clone();
+        let reply = client.post(&url, Some(&account)).await?;
+        client.account_url = Some(
+            reply
+                .location
+                .ok_or_else(|| "The CA didn't give the account URL".to_string())?,
+        );
+        Ok(client)
+    }
+
+    /// Orders a certificate for the domains of `acme`, answering the challenges of
+    /// the CA through it.
+    ///
+    /// # Arguments
+    ///
+    /// * `csr` - DER certificate request naming the domains
+    ///
+    /// # Returns
+    ///
+    /// * `Result<String, String>` - The certificate chain in PEM, leaf first
+    pub(super) async fn order_certificate(
+        &mut self,
+        acme: &Acme,
+        csr: &[u8],
+    ) -> Result<String, String> {
+        let identifiers: Vec<Value> = acme
+            .config
+            .domains
+            .iter()
+            .map(|domain| json!({"type": "dns", "value": domain}))
+            .collect();
+        let url = self.directory.new_order.clone();
+        let reply = self
+            .post(&url, Some(&json!({"identifiers": identifiers})))
+            .await?;
+        let order_url = reply
+            .location
+            .clone()
+            .ok_or_else(|| "The CA didn't give the order URL".to_string())?;
+        let order: Order = reply.json()?;
+
+        for url in &order.authorizations {
+            self.authorize(acme, url).await?;
+        }
+
+        let csr = URL_SAFE_NO_PAD.encode(csr);
+        self.post(&order.finalize, Some(&json!({"csr": csr})))
+            .await?;
+        let order: Order = self
+            .poll(&order_url, |order: &Order| {
+                matches!(order.status.as_str(), "pending" | "ready" | "processing")
+            })
+            .await?;
+        let url = match (order.status.as_str(), order.certificate) {
+            ("valid", Some(url)) => url,
+            (status, _) => {
+                return Err(format!(
+                    "Order {}: {}",
+                    status,
+                    order.error.unwrap_or_default()
+                ));
+            }
+        };
+
+        let reply = self.post(&url, None).await?;
+        String::from_utf8(reply.body.to_vec()).map_err(|_| "The certificate isn't PEM".to_string())
+    }
+
+    /// Proves the control of the domain of an authorization, if not done recently.
+    async fn authorize(&mut self, acme: &Acme, url: &str) -> Result<(), String> {
+        let authorization: Authorization = self.post(url, None).await?.json()?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let domain = authorization.identifier.value;
+        let kind = match acme.config.challenge {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+        };
+        let challenge = authorization
+            .challenges
+            .into_iter()
+            .find(|challenge| challenge.kind == kind)
+            .ok_or_else(|| format!("No {} challenge offered for {}", kind, domain))?;
+
+        let key_authorization = format!("{}.{}", challenge.token, self.thumbprint());
+        acme.publish(&domain, &challenge.token, &key_authorization)?;
+        let validated = self.validate(url, &challenge.url).await;
+        acme.withdraw(&domain, &challenge.token);
+        validated.map_err(|e| format!("{}: {}", domain, e))
+    }
+
+    /// Asks the CA to check a published challenge and waits for the outcome.
+    async fn validate(
+        &mut self,
+        authorization_url: &str,
+        challenge_url: &str,
+    ) -> Result<(), String> {
+        // An empty object tells the CA the answer is ready
+        self.post(challenge_url, Some(&json!({}))).await?;
+        let authorization: Authorization = self
+            .poll(authorization_url, |authorization: &Authorization| {
+                authorization.status == "pending"
+            })
+            .await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let problem = authorization
+            .challenges
+            .iter()
+            .find_map(|challenge| challenge.error.as_ref())
+            .map_or_else(|| "no reason given".to_string(), ToString::to_string);
+        Err(format!(
+            "Authorization {}: {}",
+            authorization.status, problem
+        ))
+    }
+
+    /// Fetches an authorization or an order until it's no longer `pending`.
+    async fn poll<T: DeserializeOwned>(
+        &mut self,
+        url: &str,
+        pending: fn(&T) -> bool,
+    ) -> Result<T, String> {
+        for _ in 0..POLL_ATTEMPTS {
+            let item: T = self.post(url, None).await?.json()?;
+            if !pending(&item) {
+                return Ok(item);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(format!(
+            "Still pending after {} checks: {}",
+            POLL_ATTEMPTS, url
+        ))
+    }
+
+    /// Sends a signed request, a POST-as-GET without `payload`.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Reply, String> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let body = self.sign(url, &nonce, payload)?;
+            let reply = send(&self.connector, Method::POST, url, Some(body)).await?;
+            self.nonce = reply.nonce.clone();
+            if reply.status.is_success() {
+                return Ok(reply);
+            }
+
+            let problem = reply.problem();
+            if problem.kind == BAD_NONCE && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(format!("{} from {}: {}", reply.status, url, problem));
+        }
+    }
+
+    async fn new_nonce(&self) -> Result<String, String> {
+        send(
+            &self.connector,
+            Method::HEAD,
+            &self.directory.new_nonce,
+            None,
+        )
+        .await?
+        .nonce
+        .ok_or_else(|| "The CA didn't give a nonce".to_string())
+    }
+
+    /// The flattened JWS of a request: signed with the key of the account, which is
+    /// named by its URL once registered.
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Result<String, String> {
+        let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+        match &self.account_url {
+            Some(account_url) => protected["kid"] = json!(account_url),
+            None => protected["jwk"] = self.jwk(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload
+            .map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string()))
+            .unwrap_or_default();
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|e| format!("Unable to sign: {}", e))?;
+
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        })
+        .to_string())
+    }
+
+    /// The public key of the account, as a JWK.
+    fn jwk(&self) -> Value {
+        // An uncompressed point: 0x04, then x and y
+        let point = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        })
+    }
+
+    /// The JWK thumbprint of the account key (RFC 7638), which ends the answers to the
+    /// challenges.
+    fn thumbprint(&self) -> String {
+        // The members are written in lexicographic order, without spaces
+        URL_SAFE_NO_PAD.encode(digest(&SHA256, self.jwk().to_string().as_bytes()))
+    }
+}
+
+/// Sends a request to the CA over a new connection.
+async fn send(
+    connector: &TlsConnector,
+    method: Method,
+    url: &str,
+    body: Option<String>,
+) -> Result<Reply, String> {
+    let request = async {
+        let uri: Uri = url.parse().map_err(|_| "invalid URL".to_string())?;
+        let (Some("https"), Some(host), Some(authority)) =
+            (uri.scheme_str(), uri.host(), uri.authority())
+        else {
+            return Err("not an HTTPS URL".to_string());
+        };
+
+        let tcp = TcpStream::connect((host, uri.port_u16().unwrap_or(443)))
+            .await
+            .map_err(|e| e.to_string())?;
+        let name = ServerName::try_from(host.to_string()).map_err(|e| e.to_string())?;
+        let tls = connector
+            .connect(name, tcp)
+            .await
+            .map_err(|e| e.to_string())?;
+        let (mut sender, connection) = http1::handshake(TokioIo::new(tls))
+            .await
+            .map_err(|e| e.to_string())?;
+        tokio::spawn(connection);
+
+        let mut builder = Request::builder()
+            .method(method.clone())
+            .uri(uri.path_and_query().map_or("/", |path| path.as_str()))
+            .header(HOST, authority.as_str())
+            .header(
+                USER_AGENT,
+                concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")),
+            );
+        if body.is_some() {
+            builder = builder.header(CONTENT_TYPE, "application/jose+json");
+        }
+        let req = builder
+            .body(Full::new(Bytes::from(body.unwrap_or_default())))
+            .map_err(|e| e.to_string())?;
+        let res = sender.send_request(req).await.map_err(|e| e.to_string())?;
+
+        let header = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let (location, nonce) = (header(LOCATION.as_str()), header(REPLAY_NONCE));
+        let status = res.status();
+        let body = Limited::new(res.into_body(), MAX_RESPONSE_SIZE)
+            .collect()
+            .await
+            .map_err(|e| e.to_string())?
+            .to_bytes();
+        Ok(Reply {
+            status,
+            location,
+            nonce,
+            body,
+        })
+    };
+
+    tokio::time::timeout(REQUEST_TIMEOUT, request)
+        .await
+        .map_err(|_| format!("{} {}: timed out", method, url))?
+        .map_err(|e: String| format!("{} {}: {}", method, url, e))
+}
@@ -0,0 +1,116 @@
+//! Validation of request payloads.
+//!
+//! Payload types implement `Validate`; handlers parse them with
+//! `router::parse_validated_body`, which runs the checks right after deserialization.
+//! Invalid payloads are answered with 422 Unprocessable Entity, code `VALIDATION_FAILED`,
+//! and every failed check grouped by field in `details`:
+//!
+//! ```json
+//! {
+//!   "code": "VALIDATION_FAILED",
+//!   "message": "Invalid fields: age must be >= 0; name must not be empty",
+//!   "details": {"age": ["must be >= 0"], "name": ["must not be empty"]},
+//!   "request_id": "..."
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::error::AppError;
+
+/// A payload whose fields can be checked after deserialization.
+pub trait Validate {
+    /// Checks every field, reporting all the failures at once.
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+/// Failed checks of a payload, by field name.
+#[derive(Debug, Default, Serialize)]
+#[serde(transparent)]
+pub struct ValidationErrors {
+    errors: BTreeMap<&'static str, Vec<String>>,
+}
+
+impl ValidationErrors {
+    /// Records `message` for `field` unless `valid` holds.
+    pub fn check(&mut self, field: &'static str, valid: bool, message: impl Into<String>) {
+        if !valid {
+            self.errors.entry(field).or_default().push(message.into());
+        }
+    }
+
+    /// `Ok` if every check passed.
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields: Vec<String> = self
+            .errors
+            .iter()
+            .map(|(field, messages)| format!("{} {}", field, messages.join(", ")))
+            .collect();
+        write!(f, "{}", fields.join("; "))
+    }
+}
+
+impl From<ValidationErrors> for AppError {
+    fn from(errors: ValidationErrors) -> Self {
+        AppError::Unprocessable(errors)
+    }
+}
+
+// ==================== COMMON CHECKS ====================
+
+/// Maximum length of names (users, products)
+pub const MAX_NAME_LEN: usize = 100;
+
+/// Checks a required name: not blank and at most `MAX_NAME_LEN` characters.
+pub fn check_name(errors: &mut ValidationErrors, field: &'static str, name: &str) {
+    errors.check(field, !name.trim().is_empty(), "must not be empty");
+    errors.check(
+        field,
+        name.chars().count() <= MAX_NAME_LEN,
+        format!("must have at most {} characters", MAX_NAME_LEN),
+    );
+}
+
+/// Checks an age: between 0 and 150.
+pub fn check_age(errors: &mut ValidationErrors, field: &'static str, age: i32) {
+    errors.check(field, age >= 0, "must be >= 0");
+    errors.check(field, age <= 150, "must be <= 150");
+}
+
+/// Minimum number of characters of a password
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// Checks an email address, trimmed. Deliverability is not checked, only the obvious
+/// mistakes.
+pub fn check_email(errors: &mut ValidationErrors, field: &'static str, email: &str) {
+    let email = email.trim();
+    errors.check(
+        field,
+        email.split_once('@').is_some_and(|(local, domain)| {
+            !local.is_empty() && domain.contains('.') && !email.contains(char::is_whitespace)
+        }),
+        "must be a valid email address",
+    );
+}
+
+/// Checks a password: at least `MIN_PASSWORD_LEN` characters.
+pub fn check_password(errors: &mut ValidationErrors, field: &'static str, password: &str) {
+    errors.check(
+        field,
+        password.chars().count() >= MIN_PASSWORD_LEN,
+        format!("must have at least {} characters", MIN_PASSWORD_LEN),
+    );
+}
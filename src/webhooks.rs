@@ -0,0 +1,237 @@
+//! Webhooks: the change events of users and products called back to other systems.
+//!
+//! A user registers a webhook with a URL, a secret and the event types it wants
+//! (`users.created`, all of them when empty), see `routes::webhooks`. Every event
+//! published by this instance is handed to [`dispatch`]; a task finds the webhooks
+//! wanting it and enqueues one `DeliverWebhook` job per webhook, so the deliveries
+//! survive a restart and a failed one is retried with the backoff of the jobs (30
+//! seconds, then 2, 8 and 32 minutes). The events received from the other instances
+//! aren't dispatched here: the instance publishing them does it.
+//!
+//! The alerts of the instance (`slo.burning`, see `metrics::slo`) are delivered the
+//! same way, by [`alert`].
+//!
+//! A delivery is a `POST` of the event envelope (see `events`) to the URL, with:
+//!
+//! - `X-Webhook-Event`: the event type
+//! - `X-Webhook-Delivery`: the ID of the job, the same for every attempt
+//! - `X-Webhook-Signature`: `sha256=` and the hex HMAC-SHA256 of the body, keyed with
+//!   the secret, for the receiver to check the body comes from here
+//!
+//! A 2xx response within `DELIVERY_TIMEOUT` delivers the event, anything else fails
+//! the attempt. Every attempt is recorded with the answer of the URL and listed by
+//! `GET /webhooks/:id/deliveries`.
+
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_TYPE, HeaderValue};
+use hyper::{Request, Uri};
+use ring::hmac;
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tracing::{Instrument, warn};
+use uuid::Uuid;
+
+use crate::context::RequestContext;
+use crate::error::AppError;
+use crate::events::{self, ChangeEvent};
+use crate::http_client::http_client;
+use crate::jobs::{self, Job};
+use crate::repository::webhooks::{NewDelivery, PgWebhookRepo, WebhookRepository};
+
+/// Time a URL has to answer a delivery
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Header naming the event type of a delivery
+const EVENT_HEADER: &str = "x-webhook-event";
+/// Header identifying a delivery, the same for its retries
+const DELIVERY_HEADER: &str = "x-webhook-delivery";
+/// Header carrying the signature of the body
+const SIGNATURE_HEADER: &str = "x-webhook-signature";
+
+// Events waiting for their webhooks to be looked up, with the context of the request
+// that published them, unset before `init_webhooks`
+static PENDING: OnceLock<mpsc::UnboundedSender<(ChangeEvent, RequestContext)>> = OnceLock::new();
+
+/// Starts the task turning the published events into deliveries.
+/// This function should be called once at application startup, after `init_jobs`.
+pub fn init_webhooks() {
+    let (sender, mut pending) = mpsc::unbounded_channel::<(ChangeEvent, RequestContext)>();
+    if PENDING.set(sender).is_err() {
+        warn!("Attempt to restart the webhooks ignored");
+        return;
+    }
+
+    tokio::spawn(async move {
+        while let Some((event, context)) = pending.recv().await {
+            // The deliveries are jobs of the request that published the event
+            let span = context.span();
+            context
+                .scope(enqueue_deliveries(event))
+                .instrument(span)
+                .await;
+        }
+    });
+}
+
+/// Enqueues a delivery of an event to every webhook subscribed to it.
+async fn enqueue_deliveries(event: ChangeEvent) {
+    let event_type = event.event_type();
+    let webhooks = match PgWebhookRepo::default().subscribed(&event_type).await {
+        Ok(webhooks) => webhooks,
+        Err(e) => {
+            warn!("{} not sent to the webhooks: {}", event_type, e);
+            return;
+        }
+    };
+    let envelope = event.to_json();
+    for webhook_id in webhooks {
+        jobs::enqueue(Job::DeliverWebhook {
+            webhook_id,
+            event: envelope.clone(),
+        })
+        .await;
+    }
+}
+
+/// Hands an event published by this instance to the webhooks wanting it.
+pub fn dispatch(event: &ChangeEvent) {
+    if let Some(pending) = PENDING.get() {
+        let _ = pending.send((event.clone(), RequestContext::current()));
+    }
+}
+
+/// Sends an alert of this instance to the webhooks wanting it.
+///
+/// # Arguments
+///
+/// * `event_type` - A registered event type (`slo.burning`)
+/// * `data` - The payload, matching the latest version of the event type
+pub async fn alert(event_type: &str, data: Value) -> Result<(), AppError> {
+    let webhooks = PgWebhookRepo::default().subscribed(event_type).await?;
+    let envelope = events::envelope(event_type, data);
+    for webhook_id in webhooks {
+        jobs::enqueue(Job::DeliverWebhook {
+            webhook_id,
+            event: envelope.clone(),
+        })
+        .await;
+    }
+    Ok(())
+}
+
+/// Delivers an event to a webhook, recording the attempt.
+///
+/// # Arguments
+///
+/// * `job_id` - The job delivering the event, sent as `X-Webhook-Delivery`
+/// * `event` - The event envelope, sent as the body
+///
+/// # Returns
+///
+/// * `Result<(), AppError>` - Success, also when the webhook was deleted meanwhile,
+///   or an `AppError::BadGateway` for the job to try again
+pub async fn deliver(job_id: i64, webhook_id: Uuid, event: &Value) -> Result<(), AppError> {
+    let Some(target) = PgWebhookRepo::default().target(webhook_id).await? else {
+        return Ok(());
+    };
+    let event_type = event["type"].as_str().unwrap_or_default();
+    let body = event.to_string();
+
+    let start = Instant::now();
+    let outcome = post(&target.url, &target.secret, job_id, event_type, &body).await;
+    let duration_ms = i32::try_from(start.elapsed().as_millis()).unwrap_or(i32::MAX);
+    let (status_code, error) = match &outcome {
+        Ok(status) if (200..300).contains(status) => (Some(i32::from(*status)), None),
+        Ok(status) => (
+            Some(i32::from(*status)),
+            Some(format!("Answered {}", status)),
+        ),
+        Err(reason) => (None, Some(reason.clone())),
+    };
+    PgWebhookRepo::default()
+        .record_delivery(&NewDelivery {
+            webhook_id,
+            job_id,
+            event_type,
+            payload: &body,
+            status_code,
+            error: error.as_deref(),
+            duration_ms,
+        })
+        .await?;
+
+    match error {
+        None => Ok(()),
+        Some(reason) => Err(AppError::BadGateway(format!(
+            "Webhook {} not delivered: {}",
+            webhook_id, reason
+        ))),
+    }
+}
+
+/// Sends a signed delivery.
+///
+/// # Returns
+///
+/// * `Result<u16, String>` - The status of the response, or why none was received
+async fn post(
+    url: &str,
+    secret: &str,
+    job_id: i64,
+    event_type: &str,
+    body: &str,
+) -> Result<u16, String> {
+    let uri = url
+        .parse::<Uri>()
+        .map_err(|e| format!("Invalid URL: {}", e))?;
+    let mut req = Request::post(uri)
+        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+        .header(DELIVERY_HEADER, job_id)
+        .header(SIGNATURE_HEADER, sign(secret, body.as_bytes()))
+        .body(
+            Full::new(Bytes::from(body.to_string()))
+                .map_err(|never| match never {})
+                .boxed_unsync(),
+        )
+        .map_err(|e| e.to_string())?;
+    if let Ok(event_type) = HeaderValue::from_str(event_type) {
+        req.headers_mut().insert(EVENT_HEADER, event_type);
+    }
+
+    match timeout(DELIVERY_TIMEOUT, http_client().request(req)).await {
+        Ok(Ok(res)) => Ok(res.status().as_u16()),
+        Ok(Err(e)) => Err(format!("Unreachable: {}", e)),
+        Err(_) => Err(format!("Timed out after {} s", DELIVERY_TIMEOUT.as_secs())),
+    }
+}
+
+/// Value of `X-Webhook-Signature` for `body`.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, body);
+    let hex = tag
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!("sha256={}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bodies_are_signed_with_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -0,0 +1,1272 @@
+//! `/api/v1/auth`: registration, login, the profile of the token owner and the experiment
+//! variants following it, the OpenID Connect provider (`/oauth`) and the provisioning
+//! of the accounts through SCIM (`/scim/v2`), and the logins with passkeys.
+
+mod common;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use arrow_array::{Array, StringArray};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, LOCATION};
+use hyper::{Method, Request, StatusCode};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use ring::digest::{SHA256, digest};
+use serde_json::{Value, json};
+
+#[tokio::test]
+async fn register_login_and_me() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    let res = app
+        .request(Method::GET, "/api/v1/auth/me", Some(&account.token), None)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()["id"], account.id);
+    assert_eq!(res.json()["email"], account.email);
+}
+
+#[tokio::test]
+async fn duplicated_email_is_a_conflict() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/auth/register",
+            None,
+            Some(json!({
+                "name": "Other",
+                "age": 20,
+                "email": account.email,
+                "password": common::PASSWORD,
+            })),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CONFLICT);
+    assert_eq!(res.error_code(), "EMAIL_TAKEN");
+}
+
+#[tokio::test]
+async fn invalid_registration_lists_every_field() {
+    let Some(app) = common::app() else { return };
+
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/auth/register",
+            None,
+            Some(json!({"name": "", "age": -1, "email": "nope", "password": "short"})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(res.error_code(), "VALIDATION_FAILED");
+    let details = res.json()["details"].clone();
+    for field in ["name", "age", "email", "password"] {
+        assert!(
+            details.get(field).is_some(),
+            "{} missing: {}",
+            field,
+            details
+        );
+    }
+}
+
+#[tokio::test]
+async fn wrong_password_and_missing_token_are_unauthorized() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/auth/login",
+            None,
+            Some(json!({"email": account.email, "password": "not the password"})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let res = app.get("/api/v1/auth/me").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(res.error_code(), "UNAUTHORIZED");
+
+    let res = app
+        .request(Method::GET, "/api/v1/auth/me", Some("invalid"), None)
+        .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn registration_sends_a_welcome_email_in_the_background() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account_with_role("admin").await;
+    let token = Some(account.token.as_str());
+    // The job runs after the response, give the workers some time
+    for _ in 0..50 {
+        let res = app
+            .request(Method::GET, "/admin/jobs?limit=100", token, None)
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let jobs = res.json();
+        let job = jobs
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|job| job["payload"]["email"] == account.email.as_str())
+            .cloned();
+        if let Some(job) = job
+            && job["status"] == "succeeded"
+        {
+            assert_eq!(job["kind"], "welcome_email");
+            assert_eq!(job["payload"]["user_id"], account.key);
+            assert_eq!(job["attempts"], 1);
+            assert!(job["finished_at"].is_string());
+            // Enqueued by the registration request
+            assert!(job["request_id"].is_string());
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("The welcome email job didn't succeed");
+}
+
+#[tokio::test]
+async fn jobs_require_a_token_and_a_valid_status() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/admin/jobs").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let account = app.create_account_with_role("admin").await;
+    let res = app
+        .request(
+            Method::GET,
+            "/admin/jobs?status=lost",
+            Some(&account.token),
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn writes_are_recorded_in_the_audit_log() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/admin/audit").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let account = app.create_account_with_role("admin").await;
+    let token = Some(account.token.as_str());
+    let res = app
+        .write(
+            Method::PATCH,
+            &format!("/api/v1/users/{}", account.id),
+            token,
+            Some(json!({"age": 77})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+
+    let res = app
+        .request(
+            Method::GET,
+            &format!("/admin/audit?entity=user&id={}", account.id),
+            token,
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let records = res.json();
+    let records = records.as_array().unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["action"], "update");
+    assert_eq!(records[0]["actor_id"], account.id.as_str());
+    assert_eq!(records[0]["entity_id"], account.id.as_str());
+    assert_eq!(records[0]["changes"]["age"]["new"], 77);
+    assert!(records[0]["changes"].get("name").is_none());
+    assert_eq!(records[1]["action"], "insert");
+    assert!(records[1]["actor_id"].is_null());
+    assert!(records[1]["changes"].get("password_hash").is_none());
+
+    let res = app
+        .request(Method::GET, "/admin/audit?id=1", token, None)
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn retention_rules_are_reported() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/admin/retention").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    let account = app.create_account().await;
+    let res = app
+        .request(Method::GET, "/admin/retention", Some(&account.token), None)
+        .await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+    let account = app.create_account_with_role("admin").await;
+    let res = app
+        .request(Method::GET, "/admin/retention", Some(&account.token), None)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let rules = res.json();
+    let rules = rules.as_array().unwrap();
+    assert_eq!(rules.len(), 2);
+    assert_eq!(rules[0]["name"], "audit-log");
+    assert_eq!(rules[0]["action"], "delete");
+    assert_eq!(rules[1]["target"], "inactive_users");
+    assert_eq!(rules[1]["action"], "anonymize");
+    // Everything was written by the tests, nothing is that old
+    assert_eq!(rules[1]["matching"], 0);
+}
+
+#[tokio::test]
+async fn archives_are_listed_and_restored_by_id() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/admin/archives").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    let account = app.create_account().await;
+    let res = app
+        .request(Method::GET, "/admin/archives", Some(&account.token), None)
+        .await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+    let account = app.create_account_with_role("admin").await;
+    let token = Some(account.token.as_str());
+    let res = app
+        .request(Method::GET, "/admin/archives?limit=5", token, None)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res.json().is_array());
+
+    let path = format!("/admin/archives/{}/restore", uuid::Uuid::new_v4());
+    let res = app.request(Method::POST, &path, token, None).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(res.json()["code"], "ARCHIVE_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn console_runs_only_its_read_only_queries() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/admin/queries").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    let account = app.create_account().await;
+    let res = app
+        .request(Method::GET, "/admin/queries", Some(&account.token), None)
+        .await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+    let account = app.create_account_with_role("admin").await;
+    let token = Some(account.token.as_str());
+    for _ in 0..2 {
+        app.create_account().await;
+    }
+    let res = app
+        .request(Method::GET, "/admin/queries", token, None)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()[0]["name"], "users-by-age");
+    assert_eq!(res.json()[0]["params"][1]["type"], "integer");
+
+    // Three accounts are 30, at most 2 rows are returned
+    let path = "/admin/queries/users-by-age/run";
+    let params = json!({"params": {"min": 30, "max": 30}});
+    let res = app
+        .request(Method::POST, path, token, Some(params.clone()))
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()["data"][0], json!({"name": "Test", "age": 30}));
+    assert_eq!(res.json()["pagination"]["rows"], 2);
+    assert_eq!(res.json()["pagination"]["truncated"], true);
+
+    let req = Request::post(format!("http://{}{}", app.addr(), path))
+        .header(ACCEPT, "text/csv")
+        .header(AUTHORIZATION, format!("Bearer {}", account.token))
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(params.to_string())))
+        .unwrap();
+    let res = app.send(req).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(&res.body[..], b"name,age\nTest,30\nTest,30\n");
+
+    let params = json!({"params": {"min": "30", "other": 1}});
+    let res = app.request(Method::POST, path, token, Some(params)).await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let details = &res.json()["details"];
+    assert_eq!(details["min"][0], "must be an integer");
+    assert_eq!(details["max"][0], "is required");
+    assert!(details["params"].is_array());
+
+    // nextval() writes, the read-only transaction refuses it
+    let res = app
+        .request(
+            Method::POST,
+            "/admin/queries/next-user-id/run",
+            token,
+            Some(json!({})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+
+    let res = app
+        .request(
+            Method::POST,
+            "/admin/queries/drop-users/run",
+            token,
+            Some(json!({})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(res.json()["code"], "QUERY_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn slos_count_the_bad_requests_of_their_route() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/admin/slo").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let account = app.create_account_with_role("admin").await;
+    let token = Some(account.token.as_str());
+    let slo = async || {
+        let res = app.request(Method::GET, "/admin/slo", token, None).await;
+        assert_eq!(res.status, StatusCode::OK);
+        res.json()[0].clone()
+    };
+    let before = slo().await;
+    assert_eq!(before["name"], "console-runs");
+    assert_eq!(before["route"], "POST /admin/queries/:name/run");
+
+    // A 500, the other tests may run the route meanwhile
+    let res = app
+        .request(
+            Method::POST,
+            "/admin/queries/next-user-id/run",
+            token,
+            Some(json!({})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+    let after = slo().await;
+    let count = |slo: &serde_json::Value, field: &str| slo[field].as_u64().unwrap();
+    assert!(count(&after, "requests") > count(&before, "requests"));
+    assert!(count(&after, "bad_requests") > count(&before, "bad_requests"));
+    assert!(after["burn_rates"]["5m"].as_f64().unwrap() > 0.0);
+
+    let res = app.get("/metrics").await;
+    let metrics = String::from_utf8_lossy(&res.body);
+    assert!(metrics.contains(r#"slo_burn_rate{slo="console-runs",window="1h"}"#));
+    assert!(metrics.contains(r#"slo_requests_total{outcome="bad",slo="console-runs"}"#));
+}
+
+#[tokio::test]
+async fn tables_are_exported_as_parquet() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/admin/export/parquet?table=products").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    let account = app.create_account().await;
+    let res = app
+        .request(
+            Method::GET,
+            "/admin/export/parquet?table=products",
+            Some(&account.token),
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+    let account = app.create_account_with_role("admin").await;
+    let token = Some(account.token.as_str());
+    let res = app
+        .request(
+            Method::GET,
+            "/admin/export/parquet?table=secrets",
+            token,
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+
+    let res = app
+        .request(
+            Method::GET,
+            "/admin/export/parquet?table=users",
+            token,
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers[CONTENT_TYPE], "application/vnd.apache.parquet");
+    let reader = ParquetRecordBatchReaderBuilder::try_new(res.body)
+        .unwrap()
+        .build()
+        .unwrap();
+    let batches = reader.collect::<Result<Vec<_>, _>>().unwrap();
+    let schema = batches[0].schema();
+    let names = schema.fields().iter().map(|field| field.name().as_str());
+    assert_eq!(
+        names.collect::<Vec<_>>(),
+        ["id", "name", "age", "email", "updated_at"]
+    );
+    // The account of this test is one of the rows
+    let ids = batches.iter().flat_map(|batch| {
+        let ids = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        ids.iter().flatten().map(str::to_string).collect::<Vec<_>>()
+    });
+    assert!(ids.into_iter().any(|id| id == account.id));
+}
+
+#[tokio::test]
+async fn signed_urls_replace_the_token_until_they_expire() {
+    let Some(app) = common::app() else { return };
+
+    let body = json!({"path": "/admin/retention"});
+    let res = app
+        .request(Method::POST, "/api/v1/signed-urls", None, Some(body))
+        .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let account = app.create_account_with_role("admin").await;
+    let token = Some(account.token.as_str());
+    // Routes that don't accept signed URLs, reserved parameters, lifetimes out of range
+    for body in [
+        json!({"path": "/admin/queries"}),
+        json!({"path": "admin/retention"}),
+        json!({"path": "/admin/retention?sub=1"}),
+        json!({"path": "/admin/retention", "expires_in": 0}),
+        json!({"path": "/admin/retention", "expires_in": 3601}),
+    ] {
+        let res = app
+            .request(Method::POST, "/api/v1/signed-urls", token, Some(body))
+            .await;
+        assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    let body = json!({"path": "/admin/export/parquet?table=users", "expires_in": 60});
+    let res = app
+        .request(Method::POST, "/api/v1/signed-urls", token, Some(body))
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let signed = res.json();
+    let url = signed["url"].as_str().unwrap();
+    assert!(url.starts_with("/admin/export/parquet?table=users&expires="));
+    let res = app.get(url).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers[CONTENT_TYPE], "application/vnd.apache.parquet");
+
+    // With the rights of the user who signed it, the admin role here
+    let user = app.create_account().await;
+    let body = json!({"path": "/admin/export/parquet?table=users"});
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/signed-urls",
+            Some(&user.token),
+            Some(body),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let res = app.get(res.json()["url"].as_str().unwrap()).await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+    assert_eq!(res.error_code(), "INSUFFICIENT_ROLE");
+
+    // Another table, or a forged signature
+    let res = app.get(&url.replace("table=users", "table=orders")).await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    let (unsigned, _) = url.rsplit_once("&signature=").unwrap();
+    let res = app
+        .get(&format!(
+            "{}&signature={}",
+            unsigned,
+            URL_SAFE_NO_PAD.encode([0; 32])
+        ))
+        .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(res.error_code(), "UNAUTHORIZED");
+}
+
+#[tokio::test]
+async fn history_lists_the_changes_of_a_user() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account_with_role("admin").await;
+    let token = Some(account.token.as_str());
+    let path = format!("/api/v1/users/{}", account.id);
+    for age in [31, 32] {
+        let res = app
+            .write(Method::PATCH, &path, token, Some(json!({"age": age})))
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+    }
+
+    let history = format!("{}/history", path);
+    let res = app.get(&history).await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let res = app.request(Method::GET, &history, token, None).await;
+    assert_eq!(res.status, StatusCode::OK);
+    let body = res.json();
+    assert_eq!(body["pagination"]["total"], 3);
+    let actions: Vec<_> = body["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|record| record["action"].as_str().unwrap())
+        .collect();
+    assert_eq!(actions, ["insert", "update", "update"]);
+    assert_eq!(body["data"][0]["changes"]["email"]["new"], account.email);
+    assert_eq!(
+        body["data"][2]["changes"],
+        json!({"age": {"old": 31, "new": 32}})
+    );
+
+    let res = app
+        .request(
+            Method::GET,
+            &format!("{}?order=desc&limit=1", history),
+            token,
+            None,
+        )
+        .await;
+    let body = res.json();
+    assert_eq!(body["data"][0]["changes"]["age"]["new"], 32);
+    assert!(body["pagination"]["next"].is_string());
+}
+
+#[tokio::test]
+async fn as_of_rebuilds_a_past_user() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account_with_role("admin").await;
+    let token = Some(account.token.as_str());
+    let path = format!("/api/v1/users/{}", account.id);
+    // The records are timestamped to the second in the history
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let res = app
+        .write(Method::PATCH, &path, token, Some(json!({"age": 31})))
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let res = app
+        .request(
+            Method::GET,
+            &format!("{}/history?order=desc", path),
+            token,
+            None,
+        )
+        .await;
+    let patched_at = res.json()["data"][0]["created_at"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let as_of = |timestamp: &str| format!("{}?as_of={}", path, timestamp);
+    let res = app.get(&as_of(&patched_at)).await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let res = app
+        .request(Method::GET, &as_of(&patched_at), token, None)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.json(),
+        json!({"id": account.id, "name": "Test", "age": 30})
+    );
+    assert!(!res.headers.contains_key("etag"));
+    let res = app
+        .request(Method::GET, &as_of("2999-01-01T00:00:00Z"), token, None)
+        .await;
+    assert_eq!(res.json()["age"], 31);
+    let res = app
+        .request(Method::GET, &as_of("2000-01-01T00:00:00Z"), token, None)
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    let res = app
+        .request(Method::GET, &as_of("yesterday-ish"), token, None)
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+
+    // Deleted users are rebuilt as well
+    let res = app.write(Method::DELETE, &path, token, None).await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app
+        .request(Method::GET, &as_of(&patched_at), token, None)
+        .await;
+    assert_eq!(res.json()["age"], 30);
+}
+
+#[tokio::test]
+async fn users_keep_their_experiment_variants() {
+    let Some(app) = common::app() else { return };
+    let account = app.create_account().await;
+
+    let res = app
+        .request(
+            Method::GET,
+            "/api/v1/experiments",
+            Some(&account.token),
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let variant = res.json()[0]["variant"].as_str().unwrap().to_string();
+    assert!(variant == "control" || variant == "green", "{}", variant);
+    assert_eq!(
+        res.headers["x-experiments"],
+        format!("checkout={}", variant)
+    );
+
+    // Any response of the user carries the same variant
+    let res = app
+        .request(Method::GET, "/api/v1/auth/me", Some(&account.token), None)
+        .await;
+    assert_eq!(
+        res.headers["x-experiments"],
+        format!("checkout={}", variant)
+    );
+}
+
+#[tokio::test]
+async fn change_feed_is_ordered_and_resumes_from_checkpoints() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/api/v1/cdc/users").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    let account = app.create_account().await;
+    let res = app
+        .request(Method::GET, "/api/v1/cdc/users", Some(&account.token), None)
+        .await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+    let account = app.create_account_with_role("admin").await;
+    let token = Some(account.token.as_str());
+    let res = app
+        .request(Method::GET, "/api/v1/cdc/secrets", token, None)
+        .await;
+    assert_eq!(res.error_code(), "FEED_NOT_FOUND");
+    let path = format!("/api/v1/users/{}", account.id);
+    let res = app
+        .write(Method::PATCH, &path, token, Some(json!({"age": 41})))
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+
+    // The changes of the other tests may hold the feed back for a moment
+    let position = |change: &serde_json::Value| {
+        let (xid, id) = change["position"]
+            .as_str()
+            .unwrap()
+            .split_once('-')
+            .unwrap();
+        (xid.parse::<u64>().unwrap(), id.parse::<i64>().unwrap())
+    };
+    let mut changes = Vec::new();
+    for _ in 0..50 {
+        changes.clear();
+        let mut from = "0-0".to_string();
+        loop {
+            let path = format!("/api/v1/cdc/users?from={}&limit=1000", from);
+            let page = app.request(Method::GET, &path, token, None).await.json();
+            changes.extend(page["changes"].as_array().unwrap().iter().cloned());
+            from = page["next"].as_str().unwrap().to_string();
+            if page["has_more"] == false {
+                break;
+            }
+        }
+        let updated = changes
+            .iter()
+            .any(|change| change["action"] == "update" && change["changes"]["age"]["new"] == 41);
+        if updated {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(
+        changes
+            .windows(2)
+            .all(|pair| position(&pair[0]) < position(&pair[1]))
+    );
+
+    let insert = changes
+        .iter()
+        .position(|change| change["changes"]["id"]["new"] == account.id.as_str())
+        .expect("insert of the account");
+    let entity_id = &changes[insert]["entity_id"];
+    assert_eq!(*entity_id, account.id.as_str());
+    assert!(
+        changes[insert + 1..]
+            .iter()
+            .any(|change| change["entity_id"] == *entity_id && change["action"] == "update")
+    );
+
+    // A consumer resumes after its checkpoint
+    let checkpoint = "/api/v1/cdc/users/checkpoints/test-etl";
+    let body = json!({"position": changes[insert]["position"]});
+    let res = app
+        .request(Method::PUT, checkpoint, token, Some(body))
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let res = app.request(Method::GET, checkpoint, token, None).await;
+    assert_eq!(res.json()["position"], changes[insert]["position"]);
+    let res = app
+        .request(
+            Method::GET,
+            "/api/v1/cdc/users?consumer=test-etl",
+            token,
+            None,
+        )
+        .await;
+    assert_eq!(res.json()["changes"][0], changes[insert + 1]);
+
+    let body = json!({"position": "latest"});
+    let res = app
+        .request(Method::PUT, checkpoint, token, Some(body))
+        .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+/// Posts an `application/x-www-form-urlencoded` form.
+async fn post_form(
+    app: &common::TestApp,
+    path: &str,
+    form: &[(&str, &str)],
+) -> common::TestResponse {
+    let req = Request::post(format!("http://{}{}", app.addr(), path))
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Full::new(Bytes::from(
+            serde_urlencoded::to_string(form).unwrap(),
+        )))
+        .unwrap();
+    app.send(req).await
+}
+
+#[tokio::test]
+async fn apps_log_their_users_in_with_openid_connect() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/.well-known/openid-configuration").await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()["issuer"], "http://id.test");
+    assert_eq!(res.json()["token_endpoint"], "http://id.test/oauth/token");
+    let res = app.get("/oauth/jwks").await;
+    let jwk = res.json()["keys"][0].clone();
+    assert_eq!(jwk["alg"], "ES256");
+
+    // The app sends the browser to the login page, with the challenge of its verifier
+    let account = app.create_account().await;
+    let verifier = "a-verifier-of-at-least-forty-three-characters-long";
+    let challenge = URL_SAFE_NO_PAD.encode(digest(&SHA256, verifier.as_bytes()));
+    let params = [
+        ("response_type", "code"),
+        ("client_id", "wiki"),
+        ("redirect_uri", "http://wiki.test/callback"),
+        ("scope", "openid email"),
+        ("state", "s1"),
+        ("nonce", "n1"),
+        ("code_challenge", challenge.as_str()),
+        ("code_challenge_method", "S256"),
+    ];
+    let query = serde_urlencoded::to_string(params).unwrap();
+    let res = app.get(&format!("/oauth/authorize?{}", query)).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(String::from_utf8_lossy(&res.body).contains("Sign in to Wiki"));
+
+    let res = app
+        .get("/oauth/authorize?client_id=wiki&redirect_uri=http://evil.test/")
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+
+    // Wrong password: the form again
+    let mut form = params.to_vec();
+    form.extend([("email", account.email.as_str()), ("password", "wrong")]);
+    let res = post_form(&app, "/oauth/authorize", &form).await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    form.pop();
+    form.push(("password", common::PASSWORD));
+    let res = post_form(&app, "/oauth/authorize", &form).await;
+    assert_eq!(res.status, StatusCode::SEE_OTHER);
+    let location = res.headers[LOCATION].to_str().unwrap().to_string();
+    let query = location
+        .strip_prefix("http://wiki.test/callback?")
+        .expect("back to the app");
+    let query: HashMap<String, String> = serde_urlencoded::from_str(query).unwrap();
+    assert_eq!(query["state"], "s1");
+    let code = query["code"].as_str();
+
+    // The code is exchanged once, with the verifier
+    let exchange = [
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", "http://wiki.test/callback"),
+        ("client_id", "wiki"),
+        ("code_verifier", verifier),
+    ];
+    let res = post_form(&app, "/oauth/token", &exchange).await;
+    assert_eq!(res.status, StatusCode::OK, "{:?}", res.body);
+    let tokens = res.json();
+    let key =
+        DecodingKey::from_ec_components(jwk["x"].as_str().unwrap(), jwk["y"].as_str().unwrap())
+            .unwrap();
+    let mut validation = Validation::new(Algorithm::ES256);
+    validation.set_audience(&["wiki"]);
+    validation.set_issuer(&["http://id.test"]);
+    let claims = decode::<Value>(tokens["id_token"].as_str().unwrap(), &key, &validation)
+        .unwrap()
+        .claims;
+    assert_eq!(claims["sub"], account.id);
+    assert_eq!(claims["nonce"], "n1");
+    assert_eq!(claims["email"], account.email);
+
+    let res = post_form(&app, "/oauth/token", &exchange).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    assert_eq!(res.json()["error"], "invalid_grant");
+
+    // The access token is a client token of the granted scopes, not one of the API
+    let token = tokens["access_token"].as_str();
+    let res = app
+        .request(Method::GET, "/oauth/userinfo", token, None)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()["sub"], account.id);
+    assert_eq!(res.json()["email"], account.email);
+    assert!(res.json().get("name").is_none());
+    let res = app
+        .request(Method::GET, "/api/v1/auth/me", token, None)
+        .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    // A client with a secret must send it
+    let res = post_form(
+        &app,
+        "/oauth/token",
+        &[
+            ("grant_type", "authorization_code"),
+            ("code", "unknown"),
+            ("redirect_uri", "http://crm.test/oidc"),
+            ("client_id", "crm"),
+            ("code_verifier", verifier),
+        ],
+    )
+    .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(res.json()["error"], "invalid_client");
+}
+
+#[tokio::test]
+async fn identity_providers_provision_accounts_with_scim() {
+    let Some(app) = common::app() else { return };
+    let token = Some(common::SCIM_TOKEN);
+
+    let res = app.get("/scim/v2/Users").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(res.headers[CONTENT_TYPE], "application/scim+json");
+
+    let email = format!("{}@Example.com", uuid::Uuid::new_v4());
+    let user = json!({
+        "schemas": ["urn:ietf:params:scim:schemas:core:2.0:User"],
+        "userName": email,
+        "externalId": "00u1",
+        "name": {"givenName": "Ann", "familyName": "Lee"},
+        "password": common::PASSWORD,
+    });
+    let res = app
+        .request(Method::POST, "/scim/v2/Users", token, Some(user.clone()))
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED, "{:?}", res.body);
+    let created = res.json();
+    let id = created["id"].as_str().unwrap().to_string();
+    let path = format!("/scim/v2/Users/{}", id);
+    assert_eq!(res.headers[LOCATION], path.as_str());
+    assert_eq!(created["displayName"], "Ann Lee");
+    assert_eq!(created["active"], true);
+
+    // The same email, in another case
+    let mut duplicate = user.clone();
+    duplicate["userName"] = email.to_lowercase().into();
+    let res = app
+        .request(Method::POST, "/scim/v2/Users", token, Some(duplicate))
+        .await;
+    assert_eq!(res.status, StatusCode::CONFLICT);
+    assert_eq!(res.json()["scimType"], "uniqueness");
+
+    let filter = serde_urlencoded::to_string([(
+        "filter",
+        format!("userName eq \"{}\" and externalId pr", email.to_uppercase()),
+    )])
+    .unwrap();
+    let res = app
+        .request(
+            Method::GET,
+            &format!("/scim/v2/Users?{}", filter),
+            token,
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK, "{:?}", res.body);
+    assert_eq!(res.json()["totalResults"], 1);
+    assert_eq!(res.json()["Resources"][0]["id"], id.as_str());
+    let res = app
+        .request(
+            Method::GET,
+            "/scim/v2/Users?filter=userName%20eq",
+            token,
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    assert_eq!(res.json()["scimType"], "invalidFilter");
+
+    // Deactivated, the account can't log in anymore
+    let login = json!({"email": email, "password": common::PASSWORD});
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/auth/login",
+            None,
+            Some(login.clone()),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let patch = json!({
+        "schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"],
+        "Operations": [{"op": "Replace", "path": "active", "value": "False"}],
+    });
+    let res = app.request(Method::PATCH, &path, token, Some(patch)).await;
+    assert_eq!(res.status, StatusCode::OK, "{:?}", res.body);
+    assert_eq!(res.json()["active"], false);
+    assert_eq!(res.json()["displayName"], "Ann Lee");
+    let res = app
+        .request(Method::POST, "/api/v1/auth/login", None, Some(login))
+        .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let res = app.request(Method::DELETE, &path, token, None).await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app.request(Method::GET, &path, token, None).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(res.json()["status"], "404");
+
+    // Nor the soft-deleted accounts
+    let account = app.create_account().await;
+    let path = format!("/scim/v2/Users/{}", account.id);
+    let res = app.request(Method::GET, &path, token, None).await;
+    assert_eq!(res.status, StatusCode::OK);
+    let res = app
+        .write(
+            Method::DELETE,
+            &format!("/api/v1/users/{}", account.id),
+            Some(&account.token),
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app.request(Method::GET, &path, token, None).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
+
+/// An authenticator holding one P-256 passkey of `https://app.test`.
+struct Authenticator {
+    key_pair: ring::signature::EcdsaKeyPair,
+    credential_id: Vec<u8>,
+    sign_count: u32,
+}
+
+impl Authenticator {
+    fn new() -> Self {
+        use ring::signature::{ECDSA_P256_SHA256_ASN1_SIGNING, EcdsaKeyPair};
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        Authenticator {
+            key_pair: EcdsaKeyPair::from_pkcs8(
+                &ECDSA_P256_SHA256_ASN1_SIGNING,
+                pkcs8.as_ref(),
+                &rng,
+            )
+            .unwrap(),
+            credential_id: uuid::Uuid::new_v4().as_bytes().to_vec(),
+            sign_count: 0,
+        }
+    }
+
+    fn client_data(kind: &str, options: &Value) -> Vec<u8> {
+        json!({
+            "type": kind,
+            "challenge": options["publicKey"]["challenge"],
+            "origin": "https://app.test",
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    /// `authenticatorData` with the flags (user present and verified) and the counter.
+    fn auth_data(&mut self, attested: bool) -> Vec<u8> {
+        use ring::signature::KeyPair;
+        self.sign_count += 1;
+        let mut data = digest(&SHA256, b"app.test").as_ref().to_vec();
+        data.push(if attested { 0x45 } else { 0x05 });
+        data.extend(self.sign_count.to_be_bytes());
+        if attested {
+            let point = self.key_pair.public_key().as_ref();
+            let int = |value: i64| ciborium::Value::Integer(value.into());
+            let key = ciborium::Value::Map(vec![
+                (int(1), int(2)),
+                (int(3), int(-7)),
+                (int(-1), int(1)),
+                (int(-2), ciborium::Value::Bytes(point[1..33].to_vec())),
+                (int(-3), ciborium::Value::Bytes(point[33..].to_vec())),
+            ]);
+            data.extend([0; 16]);
+            data.extend((self.credential_id.len() as u16).to_be_bytes());
+            data.extend(&self.credential_id);
+            ciborium::into_writer(&key, &mut data).unwrap();
+        }
+        data
+    }
+
+    /// The credential `navigator.credentials.create()` would return.
+    fn create(&mut self, options: &Value) -> Value {
+        let object = ciborium::Value::Map(vec![
+            ("fmt".into(), "none".into()),
+            ("attStmt".into(), ciborium::Value::Map(vec![])),
+            (
+                "authData".into(),
+                ciborium::Value::Bytes(self.auth_data(true)),
+            ),
+        ]);
+        let mut attestation = Vec::new();
+        ciborium::into_writer(&object, &mut attestation).unwrap();
+        json!({
+            "id": URL_SAFE_NO_PAD.encode(&self.credential_id),
+            "type": "public-key",
+            "response": {
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(Self::client_data("webauthn.create", options)),
+                "attestationObject": URL_SAFE_NO_PAD.encode(attestation),
+            },
+        })
+    }
+
+    /// The credential `navigator.credentials.get()` would return.
+    fn get(&mut self, options: &Value) -> Value {
+        let auth_data = self.auth_data(false);
+        let client_data = Self::client_data("webauthn.get", options);
+        let message = [&auth_data[..], digest(&SHA256, &client_data).as_ref()].concat();
+        let signature = self
+            .key_pair
+            .sign(&ring::rand::SystemRandom::new(), &message)
+            .unwrap();
+        json!({
+            "id": URL_SAFE_NO_PAD.encode(&self.credential_id),
+            "type": "public-key",
+            "response": {
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+                "authenticatorData": URL_SAFE_NO_PAD.encode(auth_data),
+                "signature": URL_SAFE_NO_PAD.encode(signature),
+            },
+        })
+    }
+}
+
+#[tokio::test]
+async fn users_log_in_with_passkeys() {
+    let Some(app) = common::app() else { return };
+    let account = app.create_account().await;
+    let token = Some(account.token.as_str());
+    let mut authenticator = Authenticator::new();
+
+    // Registration
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/auth/passkeys/registration",
+            token,
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK, "{:?}", res.body);
+    let options = res.json();
+    assert_eq!(options["publicKey"]["rp"]["id"], "app.test");
+    assert_eq!(options["publicKey"]["user"]["name"], account.email.as_str());
+    let credential = authenticator.create(&options);
+    let registration = json!({
+        "challenge_id": options["challenge_id"],
+        "name": "Laptop",
+        "credential": credential,
+    });
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/auth/passkeys",
+            token,
+            Some(registration.clone()),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED, "{:?}", res.body);
+    let passkey_id = res.json()["id"].as_str().unwrap().to_string();
+    assert_eq!(res.json()["name"], "Laptop");
+    // A challenge is answered once
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/auth/passkeys",
+            token,
+            Some(registration),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+
+    // Login without password
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/auth/passkeys/authentication",
+            None,
+            None,
+        )
+        .await;
+    let options = res.json();
+    let login = json!({
+        "challenge_id": options["challenge_id"],
+        "credential": authenticator.get(&options),
+    });
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/auth/passkeys/login",
+            None,
+            Some(login.clone()),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK, "{:?}", res.body);
+    let res = app
+        .request(
+            Method::GET,
+            "/api/v1/auth/me",
+            res.json()["access_token"].as_str(),
+            None,
+        )
+        .await;
+    assert_eq!(res.json()["id"], account.id.as_str());
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/auth/passkeys/login",
+            None,
+            Some(login),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    // Another authenticator, unknown
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/auth/passkeys/authentication",
+            None,
+            None,
+        )
+        .await;
+    let options = res.json();
+    let login = json!({
+        "challenge_id": options["challenge_id"],
+        "credential": Authenticator::new().get(&options),
+    });
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/auth/passkeys/login",
+            None,
+            Some(login),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let res = app
+        .request(Method::GET, "/api/v1/auth/passkeys", token, None)
+        .await;
+    let passkeys = res.json();
+    assert_eq!(passkeys.as_array().unwrap().len(), 1);
+    assert!(passkeys[0]["last_used_at"].is_string());
+
+    // Second factor
+    let res = app
+        .request(
+            Method::PUT,
+            "/api/v1/auth/second-factor",
+            token,
+            Some(json!({"passkey": true})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT, "{:?}", res.body);
+    let password_login = json!({"email": account.email, "password": common::PASSWORD});
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/auth/login",
+            None,
+            Some(password_login),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let options = res.json();
+    assert_eq!(options["second_factor"], "passkey");
+    assert!(options.get("access_token").is_none());
+    let allowed = &options["publicKey"]["allowCredentials"];
+    assert_eq!(
+        allowed[0]["id"],
+        URL_SAFE_NO_PAD
+            .encode(&authenticator.credential_id)
+            .as_str()
+    );
+    let login = json!({
+        "challenge_id": options["challenge_id"],
+        "credential": authenticator.get(&options),
+    });
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/auth/passkeys/login",
+            None,
+            Some(login),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK, "{:?}", res.body);
+    assert!(res.json()["access_token"].is_string());
+
+    // The last passkey is kept while it's required
+    let path = format!("/api/v1/auth/passkeys/{}", passkey_id);
+    let res = app.request(Method::DELETE, &path, token, None).await;
+    assert_eq!(res.status, StatusCode::CONFLICT);
+    assert_eq!(res.error_code(), "PASSKEY_REQUIRED");
+    let res = app
+        .request(
+            Method::PUT,
+            "/api/v1/auth/second-factor",
+            token,
+            Some(json!({"passkey": false})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app.request(Method::DELETE, &path, token, None).await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app.request(Method::DELETE, &path, token, None).await;
+    assert_eq!(res.error_code(), "PASSKEY_NOT_FOUND");
+}
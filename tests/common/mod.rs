@@ -0,0 +1,567 @@
+//! Harness of the integration tests.
+//!
+//! Every test binary (`tests/*.rs`) starts the real server once, on an ephemeral port of
+//! `127.0.0.1`, in a runtime of its own, so it outlives the runtime of each test. The
+//! server uses a database created for the binary (`rust_backend_test_<binary>`), dropped
+//! and migrated again on every run. Tests of a binary share it and run concurrently, so
+//! each one creates the rows it needs and never relies on counts.
+//!
+//! PostgreSQL is reached with `TEST_DB_HOST`, `TEST_DB_PORT`, `TEST_DB_USER` and
+//! `TEST_DB_PASSWORD` (`localhost:5432`, `postgres`/`123456` by default). When it isn't
+//! reachable the tests are skipped with a message instead of failing.
+
+#![allow(dead_code)] // Every test binary uses a different part of the harness
+
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::{OnceLock, mpsc};
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use bb8_postgres::tokio_postgres::{self, NoTls};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, ETAG, HeaderMap, IF_MATCH};
+use hyper::{Method, Request, StatusCode};
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+use uuid::Uuid;
+
+use rust_backend::config::{
+    AppConfig, AuthConfig, CacheConfig, ComputedConfig, ConsoleConfig, DashboardConfig,
+    DatabaseConfig, EmbeddingsConfig, ExperimentsConfig, GeoConfig, JobsConfig, OidcConfig,
+    PasskeysConfig, RegionConfig, ReplicaConfig, ServerConfig, ServiceConfig, SloConfig, SslMode,
+    StorageConfig, ToolsConfig, TrailingSlash,
+};
+use rust_backend::server;
+
+/// Address of the server of this test binary, `None` without PostgreSQL
+static SERVER: OnceLock<Option<SocketAddr>> = OnceLock::new();
+
+/// Database of the server of this test binary, set once it's started
+static DATABASE: OnceLock<DatabaseConfig> = OnceLock::new();
+
+/// Password of the accounts created by [`TestApp::create_account`]
+pub const PASSWORD: &str = "correct horse battery";
+
+/// Bearer token of the SCIM routes
+pub const SCIM_TOKEN: &str = "integration-tests-scim-token-0123456789";
+
+/// Client of the server started for the tests.
+pub struct TestApp {
+    addr: SocketAddr,
+    client: Client<HttpConnector, Full<Bytes>>,
+}
+
+/// A response, with its body collected.
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    /// The body parsed as JSON.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the body is not JSON
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or_else(|e| {
+            panic!("Invalid JSON body ({}): {:?}", e, self.body);
+        })
+    }
+
+    /// The `code` of an error body.
+    pub fn error_code(&self) -> String {
+        self.json()["code"].as_str().unwrap_or_default().to_string()
+    }
+}
+
+/// An account created for a test, with its access token.
+pub struct Account {
+    /// Public ID (UUID)
+    pub id: String,
+    /// Key of the user, used by the events, the jobs and the audit log
+    pub key: i32,
+    pub email: String,
+    pub token: String,
+}
+
+/// Returns a client of the server of this test binary, starting it on the first call.
+/// `None` (the test should return) if PostgreSQL isn't available.
+pub fn app() -> Option<TestApp> {
+    app_with(|_| {})
+}
+
+/// Returns a client of the server of this test binary like [`app`], the server started
+/// with the settings changed by `configure`. A binary always passes the same function.
+pub fn app_with(configure: fn(&mut AppConfig)) -> Option<TestApp> {
+    let addr = (*SERVER.get_or_init(|| start_server(configure)))?;
+    // A connection per request: the server closes connections whose request body it
+    // didn't read (rejected uploads), they must not be reused
+    let client = Client::builder(TokioExecutor::new())
+        .pool_max_idle_per_host(0)
+        .build_http();
+    Some(TestApp { addr, client })
+}
+
+impl TestApp {
+    /// Address the server listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Sends a request, with a JSON body and an access token when given.
+    pub async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        token: Option<&str>,
+        body: Option<Value>,
+    ) -> TestResponse {
+        self.request_if_match(method, path, token, body, None).await
+    }
+
+    /// Sends a write conditioned on the current version of the entity at `path`: its
+    /// `ETag` is read first and sent back in `If-Match`.
+    pub async fn write(
+        &self,
+        method: Method,
+        path: &str,
+        token: Option<&str>,
+        body: Option<Value>,
+    ) -> TestResponse {
+        let etag = self.etag(path).await;
+        self.request_if_match(method, path, token, body, Some(&etag))
+            .await
+    }
+
+    /// The `ETag` of the entity at `path`.
+    pub async fn etag(&self, path: &str) -> String {
+        let res = self.get(path).await;
+        assert_eq!(res.status, StatusCode::OK, "etag: {:?}", res.body);
+        res.headers[ETAG].to_str().unwrap().to_string()
+    }
+
+    /// Sends a request like [`TestApp::request`], with `If-Match` when given.
+    pub async fn request_if_match(
+        &self,
+        method: Method,
+        path: &str,
+        token: Option<&str>,
+        body: Option<Value>,
+        if_match: Option<&str>,
+    ) -> TestResponse {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", self.addr, path));
+        if let Some(token) = token {
+            builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        if let Some(etag) = if_match {
+            builder = builder.header(IF_MATCH, etag);
+        }
+        let body = match body {
+            Some(body) => {
+                builder = builder.header(CONTENT_TYPE, "application/json");
+                Full::new(Bytes::from(body.to_string()))
+            }
+            None => Full::default(),
+        };
+        self.send(builder.body(body).unwrap()).await
+    }
+
+    /// Sends a prepared request.
+    pub async fn send(&self, req: Request<Full<Bytes>>) -> TestResponse {
+        let res = self.client.request(req).await.expect("Request failed");
+        let (parts, body) = res.into_parts();
+        let body = body.collect().await.expect("Body failed").to_bytes();
+        TestResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body,
+        }
+    }
+
+    pub async fn get(&self, path: &str) -> TestResponse {
+        self.request(Method::GET, path, None, None).await
+    }
+
+    /// Registers an account with a unique email and logs in with it.
+    pub async fn create_account(&self) -> Account {
+        let email = format!("{}@example.com", Uuid::new_v4());
+        let res = self
+            .request(
+                Method::POST,
+                "/api/v1/auth/register",
+                None,
+                Some(json!({"name": "Test", "age": 30, "email": email, "password": PASSWORD})),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::CREATED, "register: {:?}", res.body);
+        let id = res.json()["id"].as_str().unwrap().to_string();
+
+        let res = self
+            .request(
+                Method::POST,
+                "/api/v1/auth/login",
+                None,
+                Some(json!({"email": email, "password": PASSWORD})),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK, "login: {:?}", res.body);
+        let token = res.json()["access_token"].as_str().unwrap().to_string();
+        // The subject of the token is the key
+        let claims = token.split('.').nth(1).unwrap();
+        let claims: Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).unwrap()).unwrap();
+        let key = claims["sub"].as_str().unwrap().parse().unwrap();
+
+        Account {
+            id,
+            key,
+            email,
+            token,
+        }
+    }
+
+    /// Registers an account as [`create_account`](Self::create_account) does, with the
+    /// given role (`editor`, `admin`), set in the database as no route can set the first.
+    pub async fn create_account_with_role(&self, role: &str) -> Account {
+        let account = self.create_account().await;
+        let database = DATABASE.get().expect("Test database");
+        let client = connect(database, &database.name)
+            .await
+            .expect("Test database");
+        client
+            .execute(
+                "UPDATE users SET role = $1 WHERE id = $2",
+                &[&role, &account.key],
+            )
+            .await
+            .expect("Role");
+        account
+    }
+
+    /// Creates a product, returning its ID.
+    pub async fn create_product(&self, token: &str, price: f64, stock: i32) -> String {
+        let res = self
+            .request(
+                Method::POST,
+                "/api/v1/products",
+                Some(token),
+                Some(json!({"name": "Book", "price": price, "stock": stock})),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::CREATED, "product: {:?}", res.body);
+        res.json()["id"].as_str().unwrap().to_string()
+    }
+}
+
+/// Starts the server in a thread of its own, returning its address.
+fn start_server(configure: fn(&mut AppConfig)) -> Option<SocketAddr> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("Test runtime");
+        runtime.block_on(async move {
+            let mut config = test_config();
+            configure(&mut config);
+            if !create_database(&config.database).await {
+                let _ = sender.send(None);
+                return;
+            }
+
+            server::prepare_database(&config.database, false)
+                .await
+                .expect("Test database");
+            server::init(&config).await.expect("Server initialization");
+            let _ = DATABASE.set(config.database.clone());
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.expect("Test port");
+            let _ = sender.send(Some(listener.local_addr().unwrap()));
+            server::serve(listener, None, &config.server).await;
+        });
+    });
+    receiver.recv().expect("Test server thread")
+}
+
+/// Creates the database of this test binary, empty, without starting the server.
+/// `None` (the test should return) if PostgreSQL isn't available.
+pub async fn empty_database() -> Option<DatabaseConfig> {
+    let config = test_config().database;
+    create_database(&config).await.then_some(config)
+}
+
+/// Drops and creates the database of `config`, `false` if PostgreSQL isn't available.
+async fn create_database(config: &DatabaseConfig) -> bool {
+    let admin = match connect(config, "postgres").await {
+        Ok(admin) => admin,
+        Err(e) => {
+            eprintln!(
+                "PostgreSQL unavailable, integration tests skipped (set TEST_DB_*): {}",
+                e
+            );
+            return false;
+        }
+    };
+    // One statement per call: DROP DATABASE can't run in a transaction block
+    let name = &config.name;
+    for sql in [
+        format!("DROP DATABASE IF EXISTS \"{}\" WITH (FORCE)", name),
+        format!("CREATE DATABASE \"{}\"", name),
+    ] {
+        admin.execute(&sql, &[]).await.expect("Test database");
+    }
+    true
+}
+
+/// Connects to a database of the server of `config`: `postgres`, to create the database
+/// of this test binary, or that one.
+pub async fn connect(
+    config: &DatabaseConfig,
+    dbname: &str,
+) -> Result<tokio_postgres::Client, tokio_postgres::Error> {
+    let (client, connection) = tokio_postgres::Config::new()
+        .host(&config.host)
+        .port(config.port)
+        .user(&config.user)
+        .password(&config.password)
+        .dbname(dbname)
+        .connect_timeout(Duration::from_secs(5))
+        .connect(NoTls)
+        .await?;
+    tokio::spawn(connection);
+    Ok(client)
+}
+
+fn test_config() -> AppConfig {
+    let binary = env!("CARGO_CRATE_NAME");
+    let setting = |name: &str, default: &str| env::var(name).unwrap_or(default.to_string());
+    let host = setting("TEST_DB_HOST", "localhost");
+    let port = setting("TEST_DB_PORT", "5432")
+        .parse()
+        .expect("TEST_DB_PORT");
+    // Clients can only pin a region, GeoIP is disabled
+    let regions = env::temp_dir().join(format!("rust_backend_test_{}_regions.json", binary));
+    fs::write(
+        &regions,
+        r#"{"regions": {"test-1": "http://localhost", "test-2": "https://test-2.example.com"}}"#,
+    )
+    .expect("region routing");
+    // A frontend, and a file it must not serve
+    let static_dir = env::temp_dir().join(format!("rust_backend_test_{}_static", binary));
+    fs::create_dir_all(static_dir.join("assets")).expect("static files");
+    for (path, content) in [
+        ("index.html", "<!doctype html><title>Admin</title>"),
+        ("assets/app.js", "console.log('0123456789');"),
+        (".env", "SECRET=1"),
+    ] {
+        fs::write(static_dir.join(path), content).expect("static files");
+    }
+    let experiments =
+        env::temp_dir().join(format!("rust_backend_test_{}_experiments.json", binary));
+    fs::write(
+        &experiments,
+        r#"[{"name": "checkout", "variants": [
+            {"name": "control", "weight": 1}, {"name": "green", "weight": 1}
+        ]}]"#,
+    )
+    .expect("experiments");
+    // Reported, but never run during the tests
+    let retention = env::temp_dir().join(format!("rust_backend_test_{}_retention.json", binary));
+    fs::write(
+        &retention,
+        r#"[
+            {"name": "audit-log", "target": "audit_log", "older_than_days": 365,
+             "schedule": "0 0 1 1 *", "dry_run": true},
+            {"name": "inactive-users", "target": "inactive_users", "older_than_days": 1095,
+             "schedule": "0 0 1 1 *", "dry_run": true}
+        ]"#,
+    )
+    .expect("retention rules");
+    let console = env::temp_dir().join(format!("rust_backend_test_{}_console.json", binary));
+    fs::write(
+        &console,
+        r#"[
+            {"name": "users-by-age", "description": "Users of an age range",
+             "sql": "SELECT name, age FROM users WHERE age BETWEEN $1 AND $2 ORDER BY id",
+             "params": [{"name": "min", "type": "integer"}, {"name": "max", "type": "integer"}],
+             "max_rows": 2},
+            {"name": "next-user-id", "sql": "SELECT nextval('users_id_seq') AS id"}
+        ]"#,
+    )
+    .expect("console queries");
+    let slo = env::temp_dir().join(format!("rust_backend_test_{}_slo.json", binary));
+    fs::write(
+        &slo,
+        r#"[{"name": "console-runs", "route": "POST /admin/queries/:name/run",
+             "objective": 99.0, "latency_ms": 10000}]"#,
+    )
+    .expect("SLOs");
+    let computed = env::temp_dir().join(format!("rust_backend_test_{}_computed.json", binary));
+    fs::write(
+        &computed,
+        r#"{"products": {"in_stock": "stock > 0", "label": "name + \" (\" + stock + \" left)\""},
+            "orders": {"total": "round(quantity * unit_price, 2)"}}"#,
+    )
+    .expect("computed fields");
+    let oidc_clients = env::temp_dir().join(format!("rust_backend_test_{}_oidc.json", binary));
+    fs::write(
+        &oidc_clients,
+        r#"[{"client_id": "wiki", "name": "Wiki", "redirect_uris": ["http://wiki.test/callback"]},
+            {"client_id": "crm", "redirect_uris": ["http://crm.test/oidc"],
+             "client_secret": "crm-secret"}]"#,
+    )
+    .expect("OIDC clients");
+
+    AppConfig {
+        server: ServerConfig {
+            port: 0,
+            shutdown_timeout: Duration::from_secs(1),
+            max_body_size: 1024 * 1024,
+            max_upload_size: 64 * 1024,
+            header_read_timeout: Duration::from_secs(30),
+            max_header_size: 16 * 1024,
+            keep_alive: true,
+            http2_keep_alive_interval: None,
+            http2_keep_alive_timeout: Duration::from_secs(20),
+            http2_max_concurrent_streams: 200,
+            request_timeout: Duration::from_secs(30),
+            route_timeouts: Vec::new(),
+            allowed_origins: Vec::new(),
+            rate_limit_per_minute: None,
+            trusted_proxies: Vec::new(),
+            proxy_protocol: false,
+            static_dir: Some(static_dir),
+            spa_fallback: true,
+            accept_integer_ids: false,
+            trailing_slash: TrailingSlash::Redirect,
+            error_pages_path: None,
+            dev_console: true,
+            legacy_headers: false,
+        },
+        tls: None,
+        database: DatabaseConfig {
+            host: host.clone(),
+            port,
+            name: format!("rust_backend_test_{}", binary),
+            user: setting("TEST_DB_USER", "postgres"),
+            password: setting("TEST_DB_PASSWORD", "123456"),
+            pool_max_size: 10,
+            pool_min_idle: 1,
+            connection_timeout: Duration::from_secs(5),
+            // The primary stands for the replica, so reads take the replica path
+            replicas: vec![ReplicaConfig {
+                host,
+                port,
+                name: format!("rust_backend_test_{}", binary),
+                user: setting("TEST_DB_USER", "postgres"),
+                password: setting("TEST_DB_PASSWORD", "123456"),
+            }],
+            replica_max_wait: Duration::from_millis(100),
+            breaker_threshold: 5,
+            breaker_cooldown: Duration::from_secs(10),
+            ssl_mode: SslMode::Disable,
+            ssl_root_cert: None,
+        },
+        auth: AuthConfig {
+            jwt_secret: "integration-tests-secret-0123456789abcdef".to_string(),
+            jwt_expiration: 3600,
+            scim_token: Some(SCIM_TOKEN.to_string()),
+            signed_url_max_lifetime: Duration::from_secs(3600),
+        },
+        geo: GeoConfig {
+            geoip_db_path: None,
+            policy_path: None,
+        },
+        region: RegionConfig {
+            name: Some("test-1".to_string()),
+            primary: Some("test-1".to_string()),
+            routing_path: Some(regions),
+        },
+        storage: StorageConfig {
+            upload_dir: env::temp_dir().join(format!("rust_backend_test_{}", binary)),
+        },
+        cache: CacheConfig {
+            ttl: Some(Duration::from_secs(60)),
+            capacity: 1000,
+        },
+        jobs: JobsConfig {
+            workers: 2,
+            queue_capacity: 100,
+            cleanup_schedule: "0 3 * * *".to_string(),
+            retention_days: 30,
+            recommendations_schedule: "0 * * * *".to_string(),
+            ledger_check_schedule: "30 4 * * *".to_string(),
+            drain_timeout: Duration::from_secs(5),
+            retention_path: Some(retention),
+            partition_archive_months: None,
+            partition_export: false,
+        },
+        service: ServiceConfig {
+            name: "rust-backend".to_string(),
+            pid_file: None,
+            log_path: None,
+        },
+        legacy_proxy: None,
+        // Nothing listens on port 9, the part fails
+        dashboard: DashboardConfig {
+            upstreams: vec![(
+                "unreachable".to_string(),
+                "http://127.0.0.1:9/".parse().unwrap(),
+            )],
+            part_timeout: Duration::from_secs(2),
+        },
+        experiments: ExperimentsConfig {
+            path: Some(experiments),
+        },
+        console: ConsoleConfig {
+            queries_path: Some(console),
+        },
+        slo: SloConfig { path: Some(slo) },
+        computed: ComputedConfig {
+            path: Some(computed),
+        },
+        tools: ToolsConfig {
+            rate_limit_per_minute: 5,
+        },
+        // No service to call, the words are hashed
+        embeddings: Some(EmbeddingsConfig::Hashing),
+        search_engine: None,
+        oidc: Some(OidcConfig {
+            issuer: "http://id.test".to_string(),
+            clients_path: oidc_clients,
+        }),
+        ldap: None,
+        passkeys: Some(PasskeysConfig {
+            rp_id: "app.test".to_string(),
+            rp_name: "App".to_string(),
+            origins: vec!["https://app.test".to_string()],
+        }),
+        offline_queue: None,
+        error_reporting: None,
+    }
+}
+
+/// A `multipart/form-data` body with a single file field.
+pub fn multipart_body(field: &str, content_type: &str, data: &[u8]) -> (String, Vec<u8>) {
+    let boundary = format!("boundary{}", Uuid::new_v4().simple());
+    let mut body = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"file\"\r\n\
+         Content-Type: {}\r\n\r\n",
+        boundary, field, content_type
+    )
+    .into_bytes();
+    body.extend_from_slice(data);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
@@ -0,0 +1,132 @@
+//! Change notifications: event schemas, Server-Sent Events and WebSocket.
+
+mod common;
+
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use http_body_util::BodyExt;
+use hyper::{Method, Request, StatusCode};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde_json::{Value, json};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_tungstenite::client_async;
+
+/// Time to wait for a notification
+const WAIT: Duration = Duration::from_secs(5);
+
+#[tokio::test]
+async fn schemas_are_listed() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/api/v1/events/schemas").await;
+    assert_eq!(res.status, StatusCode::OK);
+    let schemas = res.json();
+    assert!(
+        schemas
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|schema| schema["event_type"] == "products.created")
+    );
+}
+
+#[tokio::test]
+async fn server_sent_events_receive_changes() {
+    let Some(app) = common::app() else { return };
+
+    let client = Client::builder(TokioExecutor::new()).build_http::<String>();
+    let req = Request::get(format!("http://{}/api/v1/events", app.addr()))
+        .body(String::new())
+        .unwrap();
+    let res = client.request(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["content-type"], "text/event-stream");
+    let mut body = res.into_body();
+
+    let account = app.create_account_with_role("editor").await;
+    let product = app.create_product(&account.token, 1.0, 1).await;
+
+    let received = timeout(WAIT, async {
+        let mut text = String::new();
+        while let Some(frame) = body.frame().await {
+            if let Ok(data) = frame.unwrap().into_data() {
+                text.push_str(&String::from_utf8_lossy(&data));
+            }
+            let found = text
+                .lines()
+                .filter_map(|line| line.strip_prefix("data: "))
+                .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+                .any(|event| {
+                    event["type"] == "products.created"
+                        && event["version"] == 2
+                        && event["data"]["id"] == product.as_str()
+                });
+            if found {
+                return;
+            }
+        }
+        panic!("Stream ended: {}", text);
+    })
+    .await;
+    assert!(
+        received.is_ok(),
+        "No products.created event for {}",
+        product
+    );
+}
+
+#[tokio::test]
+async fn websocket_receives_changes() {
+    let Some(app) = common::app() else { return };
+
+    let stream = TcpStream::connect(app.addr()).await.unwrap();
+    let url = format!("ws://{}/api/v1/ws", app.addr());
+    let (mut socket, response) = client_async(url, stream).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+    let account = app.create_account().await;
+    let res = app
+        .write(
+            Method::DELETE,
+            &format!("/api/v1/users/{}", account.id),
+            Some(&account.token),
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+
+    let expected = json!({
+        "type": "users.deleted",
+        "version": 2,
+        "data": {"id": account.id},
+    });
+    let received = timeout(WAIT, async {
+        while let Some(message) = socket.next().await {
+            let message = message.unwrap();
+            let Ok(text) = message.to_text() else {
+                continue;
+            };
+            if serde_json::from_str::<Value>(text).is_ok_and(|event| event == expected) {
+                return;
+            }
+        }
+        panic!("WebSocket closed");
+    })
+    .await;
+    assert!(
+        received.is_ok(),
+        "No users.deleted event for {}",
+        account.id
+    );
+}
+
+#[tokio::test]
+async fn websocket_requires_an_upgrade() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/api/v1/ws").await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,351 @@
+//! Operational routes: greeting, probes, metrics, documentation, static files and the
+//! fallback of unknown routes and methods, and the region serving them.
+
+mod common;
+
+use std::time::Duration;
+
+use http_body_util::Full;
+use hyper::header::{
+    ACCEPT, ACCEPT_RANGES, ALLOW, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, HeaderName,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, LOCATION, RANGE,
+};
+use hyper::{Method, Request, StatusCode};
+
+#[tokio::test]
+async fn root_greets() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/").await;
+    assert_eq!(res.status, StatusCode::OK);
+    // The X-App-* headers, without their former names (LEGACY_HEADERS=false)
+    assert!(res.headers.contains_key("x-app-request-id"));
+    assert_eq!(res.headers["x-app-version"], env!("CARGO_PKG_VERSION"));
+    assert!(!res.headers.contains_key("x-request-id"));
+}
+
+#[tokio::test]
+async fn probes_report_ready() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/healthz").await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()["status"], "ok");
+
+    let res = app.get("/readyz").await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()["status"], "ready");
+    assert_eq!(res.json()["database"]["status"], "ok");
+}
+
+#[tokio::test]
+async fn metrics_are_exposed() {
+    let Some(app) = common::app() else { return };
+
+    app.get("/healthz").await;
+    let res = app.get("/metrics").await;
+    assert_eq!(res.status, StatusCode::OK);
+    let body = String::from_utf8_lossy(&res.body);
+    assert!(body.contains("http_requests_total"), "{}", body);
+}
+
+#[tokio::test]
+async fn connections_reuse_prepared_statements() {
+    let Some(app) = common::app() else { return };
+
+    let hits = async || app.get("/readyz").await.json()["pool"]["statement_cache_hits"].clone();
+    let before = hits().await.as_u64().unwrap();
+    // More queries than the pool has connections: some connection runs two of them
+    // (lists aren't served by the response cache)
+    for _ in 0..20 {
+        let res = app.get("/api/v1/users").await;
+        assert_eq!(res.status, StatusCode::OK);
+    }
+    assert!(hits().await.as_u64().unwrap() > before);
+}
+
+#[tokio::test]
+async fn requests_keep_their_connection() {
+    let Some(app) = common::app() else { return };
+
+    let reused = async || {
+        app.get("/readyz").await.json()["pool"]["reused"]
+            .as_u64()
+            .unwrap()
+    };
+    let before = reused().await;
+    // Version, then count and page at the same time: one of them reuses the
+    // connection of the version, the other one takes another
+    let res = app.get("/api/v1/users").await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(reused().await > before);
+}
+
+#[tokio::test]
+async fn unknown_route_is_not_found() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/api/v1/nothing-here").await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(res.error_code(), "ROUTE_NOT_FOUND");
+    assert!(
+        res.headers[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("application/json")
+    );
+}
+
+#[tokio::test]
+async fn routes_answer_other_methods_and_trailing_slashes() {
+    let Some(app) = common::app() else { return };
+
+    let res = app
+        .request(Method::DELETE, "/api/v1/users", None, None)
+        .await;
+    assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(res.error_code(), "METHOD_NOT_ALLOWED");
+    assert_eq!(res.headers[ALLOW], "GET, HEAD, POST");
+
+    // The headers of the GET response, without its body
+    let get = app.get("/healthz").await;
+    let res = app.request(Method::HEAD, "/healthz", None, None).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res.body.is_empty());
+    assert_eq!(res.headers[CONTENT_LENGTH], get.body.len().to_string());
+
+    let res = app.get("/api/v1/users/?limit=1").await;
+    assert_eq!(res.status, StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(res.headers[LOCATION], "/api/v1/users?limit=1");
+    // Not when the path has no route without the slash either
+    let res = app.get("/api/v1/nothing-here/").await;
+    assert_eq!(res.error_code(), "ROUTE_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn clients_are_sent_to_their_home_region() {
+    let Some(app) = common::app() else { return };
+
+    let pinned = async |region: &str| {
+        let req = Request::get(format!("http://{}/api/v1/users?limit=1", app.addr()))
+            .header("x-home-region", region)
+            .body(Full::default())
+            .unwrap();
+        app.send(req).await
+    };
+
+    let res = app.get("/healthz").await;
+    assert_eq!(res.headers["x-app-region"], "test-1");
+
+    let res = pinned("test-1").await;
+    assert_eq!(res.status, StatusCode::OK);
+
+    let res = pinned("test-2").await;
+    assert_eq!(res.status, StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(
+        res.headers[LOCATION],
+        "https://test-2.example.com/api/v1/users?limit=1"
+    );
+    assert_eq!(res.headers["x-app-region"], "test-1");
+
+    let res = pinned("mars").await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    assert_eq!(res.error_code(), "INVALID_REQUEST");
+}
+
+#[tokio::test]
+async fn runtime_diagnostics_require_a_token() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/admin/runtime").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let account = app.create_account_with_role("admin").await;
+    let res = app
+        .request(Method::GET, "/admin/runtime", Some(&account.token), None)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let body = res.json();
+    assert!(body["runtime"]["workers"].as_u64().unwrap() > 0);
+    assert!(body["connections"].as_i64().unwrap() >= 1);
+    assert!(body["pool"]["waiting"].is_u64());
+}
+
+#[tokio::test]
+async fn heap_profile_requires_the_jemalloc_feature() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/admin/heap-profile").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let account = app.create_account_with_role("admin").await;
+    let res = app
+        .request(
+            Method::GET,
+            "/admin/heap-profile",
+            Some(&account.token),
+            None,
+        )
+        .await;
+    if cfg!(feature = "jemalloc") {
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(res.body.starts_with(b"heap_v2/"));
+    } else {
+        assert_eq!(res.status, StatusCode::CONFLICT);
+        assert_eq!(res.error_code(), "PROFILING_DISABLED");
+    }
+}
+
+#[tokio::test]
+async fn cpu_profile_requires_the_pprof_feature() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/debug/pprof/profile?seconds=1").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let account = app.create_account_with_role("admin").await;
+    let token = Some(account.token.as_str());
+    let res = app
+        .request(Method::GET, "/debug/pprof/profile?seconds=0", token, None)
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+
+    let res = app
+        .request(Method::GET, "/debug/pprof/profile?seconds=1", token, None)
+        .await;
+    if cfg!(feature = "pprof") {
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(!res.body.is_empty());
+    } else {
+        assert_eq!(res.status, StatusCode::CONFLICT);
+        assert_eq!(res.error_code(), "PROFILING_DISABLED");
+    }
+}
+
+#[tokio::test]
+async fn api_is_described() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/openapi.json").await;
+    assert_eq!(res.status, StatusCode::OK);
+    let document = res.json();
+    assert_eq!(document["openapi"], "3.0.3");
+    let user = &document["paths"]["/api/v1/users/{id}"];
+    assert_eq!(user["get"]["parameters"][0]["name"], "id");
+    assert!(user["get"].get("security").is_none());
+    assert_eq!(
+        user["put"]["security"][0]["bearerAuth"],
+        serde_json::json!([])
+    );
+    // Queued while the database is down
+    assert!(user["put"]["responses"]["202"].is_object());
+    assert!(user["delete"]["responses"].get("202").is_none());
+    assert!(document["components"]["schemas"]["User"].is_object());
+
+    let res = app.get("/docs").await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(
+        res.headers[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html")
+    );
+    assert!(String::from_utf8_lossy(&res.body).contains("openapi.json"));
+
+    // DEV_CONSOLE is set for the tests
+    let res = app.get("/console").await;
+    assert_eq!(res.status, StatusCode::OK);
+    let page = String::from_utf8_lossy(&res.body);
+    assert!(page.contains(r#""method":"PUT","path":"/api/v1/users/:id""#));
+    assert!(!page.contains("/*ROUTES*/"));
+}
+
+#[tokio::test]
+async fn static_files_are_served_with_their_validators() {
+    let Some(app) = common::app() else { return };
+    let get = async |path: &str, headers: &[(HeaderName, &str)]| {
+        let mut req = Request::get(format!("http://{}{}", app.addr(), path));
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        app.send(req.body(Full::default()).unwrap()).await
+    };
+
+    let res = get("/static/assets/app.js", &[]).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers[CONTENT_TYPE], "text/javascript; charset=utf-8");
+    assert_eq!(res.headers[ACCEPT_RANGES], "bytes");
+    assert_eq!(&res.body[..], b"console.log('0123456789');");
+    let etag = res.headers[ETAG].to_str().unwrap().to_string();
+    let last_modified = res.headers[LAST_MODIFIED].to_str().unwrap().to_string();
+
+    let res = get("/static/assets/app.js", &[(IF_NONE_MATCH, &etag)]).await;
+    assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+    assert!(res.body.is_empty());
+    let res = get(
+        "/static/assets/app.js",
+        &[(IF_MODIFIED_SINCE, &last_modified)],
+    )
+    .await;
+    assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+    let modified = httpdate::parse_http_date(&last_modified).unwrap();
+    let later = httpdate::fmt_http_date(modified + Duration::from_secs(3600));
+    let res = get("/static/assets/app.js", &[(IF_MODIFIED_SINCE, &later)]).await;
+    assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+    let earlier = httpdate::fmt_http_date(modified - Duration::from_secs(3600));
+    let res = get("/static/assets/app.js", &[(IF_MODIFIED_SINCE, &earlier)]).await;
+    assert_eq!(res.status, StatusCode::OK);
+
+    let res = get("/static/assets/app.js", &[(RANGE, "bytes=13-22")]).await;
+    assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.headers[CONTENT_RANGE], "bytes 13-22/26");
+    assert_eq!(&res.body[..], b"0123456789");
+    // Another version of the file than the one the client has parts of
+    let range = [(RANGE, "bytes=13-22"), (IF_RANGE, "\"stale\"")];
+    assert_eq!(
+        get("/static/assets/app.js", &range).await.status,
+        StatusCode::OK
+    );
+    let res = get("/static/assets/app.js", &[(RANGE, "bytes=26-")]).await;
+    assert_eq!(res.status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(res.headers[CONTENT_RANGE], "bytes */26");
+
+    // Hidden, outside of the directory, or missing
+    for path in [
+        "/static/.env",
+        "/static/assets/%2E%2E/.env",
+        "/static/%2E%2E/Cargo.toml",
+        "/static/assets",
+        "/static/missing.js",
+    ] {
+        let res = get(path, &[]).await;
+        assert_eq!(res.status, StatusCode::NOT_FOUND, "{}", path);
+        assert_eq!(res.error_code(), "ROUTE_NOT_FOUND");
+    }
+}
+
+#[tokio::test]
+async fn browsers_get_the_single_page_application_on_unknown_paths() {
+    let Some(app) = common::app() else { return };
+    let get = async |path: &str, accept: &str| {
+        let req = Request::get(format!("http://{}{}", app.addr(), path))
+            .header(ACCEPT, accept)
+            .body(Full::default())
+            .unwrap();
+        app.send(req).await
+    };
+    let browser = "text/html,application/xhtml+xml,*/*;q=0.8";
+
+    let res = get("/orders/42/edit", browser).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers[CONTENT_TYPE], "text/html; charset=utf-8");
+    assert_eq!(&res.body[..], b"<!doctype html><title>Admin</title>");
+
+    // The API clients, and the API paths, keep their 404
+    let res = get("/orders/42/edit", "application/json").await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(res.error_code(), "ROUTE_NOT_FOUND");
+    let res = get("/api/v1/nothing-here", browser).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(res.error_code(), "ROUTE_NOT_FOUND");
+}
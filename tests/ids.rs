@@ -0,0 +1,31 @@
+//! Integer keys taken in place of the UUIDs while the clients migrate
+//! (`ACCEPT_INTEGER_IDS`, disabled in the other binaries).
+
+mod common;
+
+use hyper::StatusCode;
+
+use common::TestApp;
+use rust_backend::config::AppConfig;
+
+fn configure(config: &mut AppConfig) {
+    config.server.accept_integer_ids = true;
+}
+
+fn app() -> Option<TestApp> {
+    common::app_with(configure)
+}
+
+#[tokio::test]
+async fn integer_keys_resolve_to_their_row() {
+    let Some(app) = app() else { return };
+
+    let account = app.create_account().await;
+    let res = app.get(&format!("/api/v1/users/{}", account.key)).await;
+    assert_eq!(res.status, StatusCode::OK);
+    // Still identified by its UUID
+    assert_eq!(res.json()["id"], account.id.as_str());
+
+    let res = app.get(&format!("/api/v1/users/{}", i32::MAX)).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,82 @@
+//! Migrations applied to an empty database and undone one by one (`migrate rollback`).
+//!
+//! The binary starts no server: the database of the binary is only migrated.
+
+mod common;
+
+use rust_backend::server;
+use rust_backend::tasks;
+
+/// The tables, sequences, functions, types and schemas of a database, but the ones of
+/// PostgreSQL and `schema_migrations`
+const LEFTOVERS: &str = "
+    SELECT n.nspname || '.' || c.relname FROM pg_class c
+    JOIN pg_namespace n ON n.oid = c.relnamespace
+    WHERE n.nspname NOT IN ('pg_catalog', 'information_schema')
+      AND n.nspname NOT LIKE 'pg\\_toast%' AND n.nspname NOT LIKE 'pg\\_temp%'
+      AND c.relname NOT LIKE 'schema\\_migrations%'
+    UNION ALL
+    SELECT n.nspname || '.' || p.proname || '()' FROM pg_proc p
+    JOIN pg_namespace n ON n.oid = p.pronamespace
+    WHERE n.nspname NOT IN ('pg_catalog', 'information_schema')
+    UNION ALL
+    SELECT n.nspname || '.' || t.typname FROM pg_type t
+    JOIN pg_namespace n ON n.oid = t.typnamespace
+    WHERE n.nspname NOT IN ('pg_catalog', 'information_schema') AND t.typtype IN ('d', 'e', 'r')
+    UNION ALL
+    SELECT nspname FROM pg_namespace
+    WHERE nspname NOT IN ('public', 'pg_catalog', 'information_schema')
+      AND nspname NOT LIKE 'pg\\_toast%' AND nspname NOT LIKE 'pg\\_temp%'
+    UNION ALL
+    SELECT 'extension ' || extname FROM pg_extension WHERE extname <> 'plpgsql'";
+
+#[tokio::test]
+async fn every_migration_is_undone() {
+    let Some(database) = common::empty_database().await else {
+        return;
+    };
+    server::prepare_database(&database, true)
+        .await
+        .expect("Migrations");
+    let migrations = tasks::migrations().await.expect("Migrations");
+    assert!(
+        migrations
+            .iter()
+            .all(|migration| migration.applied_at.is_some())
+    );
+
+    // The latest first, until none is left
+    let mut undone = Vec::new();
+    while let Some(migration) = tasks::rollback().await.expect("Undo script") {
+        undone.push(migration.version);
+    }
+    let latest_first = migrations
+        .iter()
+        .rev()
+        .map(|migration| migration.version)
+        .collect::<Vec<_>>();
+    assert_eq!(undone, latest_first);
+    assert!(
+        tasks::migrations()
+            .await
+            .expect("Migrations")
+            .iter()
+            .all(|migration| migration.applied_at.is_none())
+    );
+
+    let client = common::connect(&database, &database.name)
+        .await
+        .expect("Test database");
+    let leftovers = client
+        .query(LEFTOVERS, &[])
+        .await
+        .expect("Leftovers")
+        .iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+    assert!(
+        leftovers.is_empty(),
+        "Left by the undo scripts: {:?}",
+        leftovers
+    );
+}
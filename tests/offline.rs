@@ -0,0 +1,326 @@
+//! Writes queued while the database is down (`OFFLINE_QUEUE_PATH`), and replayed once
+//! it's back.
+//!
+//! The server of this binary reaches PostgreSQL through a proxy the tests cut off to
+//! take the database down. Its breaker opens at the first connection that fails, for
+//! a few seconds, and the queued writes expire after a few more. The tests take the
+//! database down in turn, and wait for the writes they queued to be replayed.
+
+mod common;
+
+use std::env;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock, mpsc};
+use std::time::Duration;
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, IF_MATCH, RETRY_AFTER};
+use hyper::{Method, Request, StatusCode};
+use serde_json::{Value, json};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, watch};
+
+use common::{TestApp, TestResponse};
+use rust_backend::config::{AppConfig, OfflineQueueConfig};
+
+/// Requests waiting at most
+const MAX_ENTRIES: usize = 3;
+
+/// Age of the queued requests dropped instead of replayed
+const MAX_AGE: Duration = Duration::from_secs(6);
+
+/// Proxy between the server and PostgreSQL, set once the server is started
+static PROXY: OnceLock<Proxy> = OnceLock::new();
+
+/// Held by the test taking the database down
+static OUTAGE: Mutex<()> = Mutex::const_new(());
+
+/// Forwards the connections of the server to PostgreSQL while it's up.
+struct Proxy {
+    addr: SocketAddr,
+    up: Arc<watch::Sender<bool>>,
+}
+
+impl Proxy {
+    /// Starts a proxy to `upstream` in a thread of its own.
+    fn start(upstream: (String, u16)) -> Proxy {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("Proxy runtime");
+            runtime.block_on(async move {
+                let listener = TcpListener::bind("127.0.0.1:0").await.expect("Proxy port");
+                let up = Arc::new(watch::Sender::new(true));
+                let _ = sender.send(Proxy {
+                    addr: listener.local_addr().unwrap(),
+                    up: up.clone(),
+                });
+                loop {
+                    let Ok((mut client, _)) = listener.accept().await else {
+                        continue;
+                    };
+                    let mut state = up.subscribe();
+                    // Closed at once while down
+                    if !*state.borrow() {
+                        continue;
+                    }
+                    let upstream = upstream.clone();
+                    tokio::spawn(async move {
+                        let Ok(mut server) = TcpStream::connect(upstream).await else {
+                            return;
+                        };
+                        tokio::select! {
+                            _ = tokio::io::copy_bidirectional(&mut client, &mut server) => {}
+                            _ = state.wait_for(|up| !up) => {}
+                        }
+                    });
+                }
+            });
+        });
+        receiver.recv().expect("Proxy thread")
+    }
+}
+
+/// Settings of the server of this binary: PostgreSQL through the proxy, without
+/// replicas, and the offline queue.
+fn configure(config: &mut AppConfig) {
+    let database = &mut config.database;
+    let proxy = PROXY.get_or_init(|| Proxy::start((database.host.clone(), database.port)));
+    database.host = "127.0.0.1".to_string();
+    database.port = proxy.addr.port();
+    database.replicas = Vec::new();
+    database.connection_timeout = Duration::from_secs(1);
+    database.breaker_threshold = 1;
+    database.breaker_cooldown = Duration::from_secs(3);
+
+    let path = env::temp_dir().join("rust_backend_test_offline_queue.jsonl");
+    let _ = std::fs::remove_file(&path);
+    config.offline_queue = Some(OfflineQueueConfig {
+        path,
+        max_entries: MAX_ENTRIES,
+        max_age: MAX_AGE,
+    });
+}
+
+fn app() -> Option<TestApp> {
+    common::app_with(configure)
+}
+
+/// Value of a series of `/metrics`, 0 before it's observed.
+async fn metric(app: &TestApp, series: &str) -> f64 {
+    let res = app.get("/metrics").await;
+    String::from_utf8_lossy(&res.body)
+        .lines()
+        .find_map(|line| line.strip_prefix(series)?.trim().parse().ok())
+        .unwrap_or(0.0)
+}
+
+/// Polls `/metrics` until `series` reaches `value`.
+async fn wait_for_metric(app: &TestApp, series: &str, value: f64) {
+    for _ in 0..150 {
+        if metric(app, series).await >= value {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("{} never reached {}", series, value);
+}
+
+/// Takes the database down, until the breaker of the primary is open.
+async fn take_down(app: &TestApp) {
+    PROXY.get().unwrap().up.send_replace(false);
+    // The first connection that fails opens the breaker
+    let _ = app.get("/health/ready").await;
+    wait_for_metric(app, "db_circuit_state{pool=\"primary\"}", 1.0).await;
+}
+
+/// Brings the database back, and waits until the requests queued meanwhile are replayed
+/// or dropped.
+async fn bring_back(app: &TestApp) {
+    PROXY.get().unwrap().up.send_replace(true);
+    let queued = metric(app, "offline_writes_total{outcome=\"queued\"}").await;
+    for _ in 0..150 {
+        let mut done = 0.0;
+        for outcome in ["applied", "failed", "expired"] {
+            let series = format!("offline_writes_total{{outcome=\"{}\"}}", outcome);
+            done += metric(app, &series).await;
+        }
+        if done >= queued {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("The queued writes were never replayed");
+}
+
+/// Sends a write with an `Idempotency-Key`.
+async fn write(
+    app: &TestApp,
+    method: Method,
+    path: &str,
+    token: &str,
+    key: &str,
+    body: Option<Value>,
+    if_match: Option<&str>,
+) -> TestResponse {
+    let mut builder = Request::builder()
+        .method(method)
+        .uri(format!("http://{}{}", app.addr(), path))
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .header("idempotency-key", key);
+    if let Some(etag) = if_match {
+        builder = builder.header(IF_MATCH, etag);
+    }
+    let body = match body {
+        Some(body) => {
+            builder = builder.header(CONTENT_TYPE, "application/json");
+            Full::new(Bytes::from(body.to_string()))
+        }
+        None => Full::default(),
+    };
+    app.send(builder.body(body).unwrap()).await
+}
+
+#[tokio::test]
+async fn writes_are_queued_while_down_and_replayed_once_back() {
+    let Some(app) = app() else { return };
+    let _outage = OUTAGE.lock().await;
+
+    let editor = app.create_account_with_role("editor").await;
+    let product = app.create_product(&editor.token, 9.5, 3).await;
+    let account = app.create_account().await;
+    let wish = format!("/api/v1/wishlist/{}", product);
+
+    take_down(&app).await;
+    // Without a key, the write fails as usual
+    let res = app
+        .request(Method::PUT, &wish, Some(&account.token), None)
+        .await;
+    assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+
+    let res = write(
+        &app,
+        Method::PUT,
+        &wish,
+        &account.token,
+        "wish-1",
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(res.status, StatusCode::ACCEPTED, "{:?}", res.body);
+    assert_eq!(res.json()["status"], "queued");
+    let id = res.json()["id"].clone();
+    // Sent again, it's the same queued request
+    let res = write(
+        &app,
+        Method::PUT,
+        &wish,
+        &account.token,
+        "wish-1",
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(res.status, StatusCode::ACCEPTED);
+    assert_eq!(res.json()["id"], id);
+    bring_back(&app).await;
+
+    let res = app
+        .request(Method::GET, "/api/v1/wishlist", Some(&account.token), None)
+        .await;
+    let wishlist = res.json();
+    assert_eq!(wishlist.as_array().unwrap().len(), 1, "{}", wishlist);
+    assert_eq!(wishlist[0]["product_id"], product.as_str());
+}
+
+#[tokio::test]
+async fn a_key_reused_for_another_request_conflicts() {
+    let Some(app) = app() else { return };
+    let _outage = OUTAGE.lock().await;
+
+    let account = app.create_account().await;
+    let path = format!("/api/v1/users/{}", account.id);
+    let etag = app.etag(&path).await;
+
+    take_down(&app).await;
+    let update = |name: &str| Some(json!({"name": name, "age": 31}));
+    let res = write(
+        &app,
+        Method::PUT,
+        &path,
+        &account.token,
+        "rename",
+        update("Ada"),
+        Some(&etag),
+    )
+    .await;
+    assert_eq!(res.status, StatusCode::ACCEPTED, "{:?}", res.body);
+    // Another body
+    let res = write(
+        &app,
+        Method::PUT,
+        &path,
+        &account.token,
+        "rename",
+        update("Grace"),
+        Some(&etag),
+    )
+    .await;
+    assert_eq!(res.status, StatusCode::CONFLICT);
+    assert_eq!(res.error_code(), "IDEMPOTENCY_KEY_REUSED");
+    // Another path
+    let other = format!("/api/v1/wishlist/{}", uuid::Uuid::new_v4());
+    let res = write(
+        &app,
+        Method::PUT,
+        &other,
+        &account.token,
+        "rename",
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(res.error_code(), "IDEMPOTENCY_KEY_REUSED");
+    bring_back(&app).await;
+
+    // Only the first request was applied
+    assert_eq!(app.get(&path).await.json()["name"], "Ada");
+}
+
+#[tokio::test]
+async fn the_queue_is_bounded_in_entries_and_age() {
+    let Some(app) = app() else { return };
+    let _outage = OUTAGE.lock().await;
+
+    let editor = app.create_account_with_role("editor").await;
+    let product = app.create_product(&editor.token, 2.0, 1).await;
+    let account = app.create_account().await;
+    let wish = format!("/api/v1/wishlist/{}", product);
+
+    take_down(&app).await;
+    for i in 0..MAX_ENTRIES {
+        let key = format!("full-{}", i);
+        let res = write(&app, Method::PUT, &wish, &account.token, &key, None, None).await;
+        assert_eq!(res.status, StatusCode::ACCEPTED, "{:?}", res.body);
+    }
+    let res = write(&app, Method::PUT, &wish, &account.token, "full", None, None).await;
+    assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(res.headers.contains_key(RETRY_AFTER));
+
+    // Back too late for the queued requests
+    let expired = metric(&app, "offline_writes_total{outcome=\"expired\"}").await;
+    tokio::time::sleep(MAX_AGE + Duration::from_secs(2)).await;
+    bring_back(&app).await;
+    assert_eq!(
+        metric(&app, "offline_writes_total{outcome=\"expired\"}").await,
+        expired + MAX_ENTRIES as f64
+    );
+    let res = app
+        .request(Method::GET, "/api/v1/wishlist", Some(&account.token), None)
+        .await;
+    assert_eq!(res.json(), json!([]));
+}
@@ -0,0 +1,591 @@
+//! `POST /api/v1/users/:id/orders`: orders taken from the product stock, redeeming the
+//! coupons, the carts ordered at once, the refunds and credits of the ledger, and the
+//! dashboard listing them.
+
+mod common;
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_TYPE, COOKIE, SET_COOKIE};
+use hyper::{Method, Request, StatusCode};
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+#[tokio::test]
+async fn order_takes_the_stock() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account_with_role("editor").await;
+    let product = app.create_product(&account.token, 4.0, 5).await;
+    let path = format!("/api/v1/users/{}/orders", account.id);
+    // Cached until the order changes the stock
+    let res = app.get(&format!("/api/v1/products/{}", product)).await;
+    assert_eq!(res.json()["stock"], 5);
+
+    let res = app
+        .request(
+            Method::POST,
+            &path,
+            Some(&account.token),
+            Some(json!({"product_id": product, "quantity": 3})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    assert_eq!(res.json()["user_id"], account.id);
+    assert_eq!(res.json()["product_id"], product);
+    assert_eq!(res.json()["quantity"], 3);
+    assert_eq!(res.json()["unit_price"], 4.0);
+
+    let res = app.get(&format!("/api/v1/products/{}", product)).await;
+    assert_eq!(res.json()["stock"], 2);
+
+    let res = app
+        .request(
+            Method::POST,
+            &path,
+            Some(&account.token),
+            Some(json!({"product_id": product, "quantity": 3})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CONFLICT);
+    assert_eq!(res.error_code(), "INSUFFICIENT_STOCK");
+}
+
+#[tokio::test]
+async fn unknown_user_or_product_is_not_found() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account_with_role("editor").await;
+    let product = app.create_product(&account.token, 1.0, 1).await;
+
+    let res = app
+        .request(
+            Method::POST,
+            &format!("/api/v1/users/{}/orders", account.id),
+            Some(&account.token),
+            Some(json!({"product_id": Uuid::now_v7(), "quantity": 1})),
+        )
+        .await;
+    assert_eq!(res.error_code(), "PRODUCT_NOT_FOUND");
+
+    let res = app
+        .request(
+            Method::POST,
+            &format!("/api/v1/users/{}/orders", Uuid::now_v7()),
+            Some(&account.token),
+            Some(json!({"product_id": product, "quantity": 1})),
+        )
+        .await;
+    assert_eq!(res.error_code(), "USER_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn dashboard_tolerates_a_failed_part() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account_with_role("editor").await;
+    let product = app.create_product(&account.token, 2.5, 4).await;
+    let res = app
+        .request(
+            Method::POST,
+            &format!("/api/v1/users/{}/orders", account.id),
+            Some(&account.token),
+            Some(json!({"product_id": product, "quantity": 1})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    let order = res.json()["id"].clone();
+
+    let res = app
+        .request(Method::GET, "/api/v1/dashboard", Some(&account.token), None)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let dashboard = res.json();
+    assert!(dashboard["users"]["total"].as_i64().unwrap() >= 1);
+    assert!(dashboard["products"]["total_stock"].as_i64().unwrap() >= 3);
+    let orders = dashboard["recent_orders"].as_array().unwrap();
+    assert!(orders.iter().any(|o| o["id"] == order));
+    // The upstream can't be reached, the rest is still there
+    assert_eq!(
+        dashboard["upstreams"]["unreachable"],
+        serde_json::Value::Null
+    );
+    assert_eq!(dashboard["errors"]["unreachable"], "Unreachable");
+    assert!(dashboard["errors"].get("users").is_none());
+}
+
+#[tokio::test]
+async fn recommendations_follow_the_co_purchases() {
+    let Some(app) = common::app() else { return };
+
+    let first = app.create_account_with_role("editor").await;
+    let second = app.create_account().await;
+    let shared = app.create_product(&first.token, 5.0, 10).await;
+    let other = app.create_product(&first.token, 8.0, 10).await;
+    for (account, product) in [(&first, &shared), (&first, &other), (&second, &shared)] {
+        let res = app
+            .request(
+                Method::POST,
+                &format!("/api/v1/users/{}/orders", account.id),
+                Some(&account.token),
+                Some(json!({"product_id": product, "quantity": 1})),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::CREATED);
+    }
+
+    let path = format!("/api/v1/users/{}/recommendations?limit=20", second.id);
+    let res = app.request(Method::GET, &path, None, None).await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let res = app
+        .request(Method::GET, &path, Some(&second.token), None)
+        .await;
+    assert_eq!(res.status, StatusCode::OK, "{}", res.json());
+    let body = res.json();
+    let data = body["data"].as_array().unwrap();
+    // Bought by the other buyer of the shared product, first
+    assert_eq!(data[0]["product"]["id"], other, "{}", body);
+    assert_eq!(data[0]["reason"], "co_purchase");
+    assert_eq!(data[0]["score"], 1.0);
+    assert!(data.iter().all(|item| item["product"]["id"] != shared));
+    assert!(body["computed_at"].is_string());
+
+    let res = app
+        .request(
+            Method::GET,
+            &format!("/api/v1/users/{}/recommendations", Uuid::new_v4()),
+            Some(&second.token),
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn refunds_and_credits_go_through_the_ledger() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    let token = Some(account.token.as_str());
+    let admin = app.create_account_with_role("admin").await;
+    let admin_token = Some(admin.token.as_str());
+    let product = app.create_product(&admin.token, 2.35, 10).await;
+    let res = app
+        .request(
+            Method::POST,
+            &format!("/api/v1/users/{}/orders", account.id),
+            token,
+            Some(json!({"product_id": product, "quantity": 3})),
+        )
+        .await;
+    let order = res.json()["id"].as_i64().unwrap();
+
+    let refund = format!("/api/v1/users/{}/orders/{}/refund", account.id, order);
+    // By an administrator, not the user
+    let res = app.request(Method::POST, &refund, token, None).await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+    let res = app.request(Method::POST, &refund, admin_token, None).await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    assert_eq!(res.json()["kind"], "refund");
+    assert_eq!(res.json()["order_id"], order);
+    assert_eq!(res.json()["amount"], 705);
+    assert_eq!(res.json()["balance"], 705);
+    // Once
+    let res = app.request(Method::POST, &refund, admin_token, None).await;
+    assert_eq!(res.status, StatusCode::CONFLICT);
+    assert_eq!(res.error_code(), "ORDER_REFUNDED");
+    // Only the orders of the user
+    let other = app.create_account().await;
+    let res = app
+        .request(
+            Method::POST,
+            &format!("/api/v1/users/{}/orders/{}/refund", other.id, order),
+            admin_token,
+            None,
+        )
+        .await;
+    assert_eq!(res.error_code(), "ORDER_NOT_FOUND");
+
+    let credits = format!("/api/v1/users/{}/credits", account.id);
+    let res = app
+        .request(
+            Method::POST,
+            &credits,
+            admin_token,
+            Some(json!({"amount": 0, "description": " "})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let res = app
+        .request(
+            Method::POST,
+            &credits,
+            admin_token,
+            Some(json!({"amount": 295, "description": "Late delivery"})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    assert_eq!(res.json()["balance"], 1000);
+    // Never below zero
+    let res = app
+        .request(
+            Method::POST,
+            &credits,
+            admin_token,
+            Some(json!({"amount": -1001, "description": "Taken back"})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CONFLICT);
+    assert_eq!(res.error_code(), "INSUFFICIENT_CREDIT");
+
+    // Concurrent withdrawals can't take more than the credit
+    let withdrawals = (0..4).map(|_| {
+        app.request(
+            Method::POST,
+            &credits,
+            admin_token,
+            Some(json!({"amount": -300, "description": "Taken back"})),
+        )
+    });
+    let results = futures_util::future::join_all(withdrawals).await;
+    let taken = results
+        .iter()
+        .filter(|res| res.status == StatusCode::CREATED)
+        .count();
+    assert_eq!(taken, 3);
+
+    let res = app
+        .request(
+            Method::GET,
+            &format!("/api/v1/users/{}/balance?limit=2", account.id),
+            token,
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let body = res.json();
+    assert_eq!(body["balance"], 100);
+    let entries = body["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["amount"], -300);
+    assert_eq!(entries[0]["balance"], 100);
+    assert_eq!(entries[1]["balance"], 400);
+
+    let res = app
+        .request(Method::GET, "/admin/ledger/check", admin_token, None)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let report = res.json();
+    assert_eq!(report["consistent"], true, "{}", report);
+    assert_eq!(report["trial_balance"], 0);
+    assert!(report["entries"].as_i64().unwrap() >= 5);
+}
+
+#[tokio::test]
+async fn coupons_are_redeemed_within_their_limits() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    let token = Some(account.token.as_str());
+    let admin = app.create_account_with_role("admin").await;
+    let admin_token = Some(admin.token.as_str());
+    let product = app.create_product(&admin.token, 19.99, 100).await;
+    let orders = format!("/api/v1/users/{}/orders", account.id);
+    let new_code = || format!("t-{}", &Uuid::new_v4().simple().to_string()[..12]);
+    let create = async |coupon: Value| {
+        app.request(Method::POST, "/api/v1/coupons", admin_token, Some(coupon))
+            .await
+    };
+
+    // Created by an editor or an administrator, not any user
+    let coupon = json!({"code": new_code(), "percent_off": 10});
+    let res = app
+        .request(Method::POST, "/api/v1/coupons", token, Some(coupon))
+        .await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+    let res = create(json!({"code": new_code(), "percent_off": 10, "amount_off": 100})).await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let res = create(
+        json!({"code": new_code(), "percent_off": 10, "expires_at": "2001-01-01T00:00:00Z"}),
+    )
+    .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    // 10% off, twice in all, by anyone
+    let code = new_code();
+    let res = create(json!({"code": code, "percent_off": 10, "max_redemptions": 2})).await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    let coupon = res.json();
+    assert_eq!(coupon["code"], code.to_uppercase());
+    assert_eq!(coupon["redemptions"], 0);
+    let res = create(json!({"code": code.to_uppercase(), "amount_off": 100})).await;
+    assert_eq!(res.status, StatusCode::CONFLICT);
+    assert_eq!(res.error_code(), "COUPON_TAKEN");
+
+    // Concurrent orders can't redeem it past its limit
+    let placed = (0..5).map(|_| {
+        app.request(
+            Method::POST,
+            &orders,
+            token,
+            Some(json!({"product_id": product, "quantity": 1, "coupon": code})),
+        )
+    });
+    let results = futures_util::future::join_all(placed).await;
+    let redeemed: Vec<_> = results
+        .iter()
+        .filter(|res| res.status == StatusCode::CREATED)
+        .map(|res| res.json())
+        .collect();
+    assert_eq!(redeemed.len(), 2);
+    assert_eq!(redeemed[0]["discount"], 2.0);
+    assert_eq!(redeemed[0]["coupon"], code.to_uppercase());
+    for res in results
+        .iter()
+        .filter(|res| res.status != StatusCode::CREATED)
+    {
+        assert_eq!(res.error_code(), "COUPON_UNAVAILABLE");
+    }
+    // The orders that failed took no stock
+    let res = app.get(&format!("/api/v1/products/{}", product)).await;
+    assert_eq!(res.json()["stock"], 98);
+
+    let path = format!(
+        "/api/v1/coupons/{}/redemptions",
+        coupon["id"].as_str().unwrap()
+    );
+    let res = app.request(Method::GET, &path, admin_token, None).await;
+    assert_eq!(res.status, StatusCode::OK);
+    let redemptions = res.json();
+    assert_eq!(redemptions.as_array().unwrap().len(), 2);
+    assert_eq!(redemptions[0]["user_id"], account.id.as_str());
+    assert_eq!(redemptions[0]["discount"], 200);
+
+    // 5.00 off orders of 30.00 or more, once per user
+    let code = new_code();
+    let res = create(json!({
+        "code": code, "amount_off": 500, "min_order_amount": 3000, "max_per_user": 1
+    }))
+    .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    let id = res.json()["id"].as_str().unwrap().to_string();
+    let order = |quantity: i32| {
+        app.request(
+            Method::POST,
+            &orders,
+            token,
+            Some(json!({"product_id": product, "quantity": quantity, "coupon": code})),
+        )
+    };
+    let res = order(1).await;
+    assert_eq!(res.error_code(), "COUPON_UNAVAILABLE");
+    let res = order(2).await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    assert_eq!(res.json()["discount"], 5.0);
+    let placed = res.json()["id"].as_i64().unwrap();
+    let res = order(2).await;
+    assert_eq!(res.error_code(), "COUPON_UNAVAILABLE");
+    // The refund is what was paid
+    let refund = format!("/api/v1/users/{}/orders/{}/refund", account.id, placed);
+    let res = app.request(Method::POST, &refund, admin_token, None).await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    assert_eq!(res.json()["amount"], 2 * 1999 - 500);
+
+    let res = app
+        .request(
+            Method::DELETE,
+            &format!("/api/v1/coupons/{}", id),
+            admin_token,
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let other = app.create_account().await;
+    let res = app
+        .request(
+            Method::POST,
+            &format!("/api/v1/users/{}/orders", other.id),
+            Some(other.token.as_str()),
+            Some(json!({"product_id": product, "quantity": 2, "coupon": code})),
+        )
+        .await;
+    assert_eq!(res.error_code(), "COUPON_UNAVAILABLE");
+
+    let res = app
+        .request(
+            Method::POST,
+            &orders,
+            token,
+            Some(json!({"product_id": product, "quantity": 1, "coupon": new_code()})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(res.error_code(), "COUPON_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn anonymous_carts_are_merged_on_login_and_checked_out() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    let token = Some(account.token.as_str());
+    let editor = app.create_account_with_role("editor").await;
+    let book = app.create_product(&editor.token, 12.5, 10).await;
+    let pen = app.create_product(&editor.token, 1.99, 2).await;
+    let anonymous =
+        async |method: Method, path: &str, cookie: Option<&str>, body: Option<Value>| {
+            let mut builder =
+                Request::builder()
+                    .method(method)
+                    .uri(format!("http://{}{}", app.addr(), path));
+            if let Some(cookie) = cookie {
+                builder = builder.header(COOKIE, cookie);
+            }
+            if body.is_some() {
+                builder = builder.header(CONTENT_TYPE, "application/json");
+            }
+            let body = body.map_or_else(Full::default, |body| {
+                Full::new(Bytes::from(body.to_string()))
+            });
+            app.send(builder.body(body).unwrap()).await
+        };
+
+    // The first item starts an anonymous cart
+    let res = anonymous(
+        Method::PUT,
+        &format!("/api/v1/cart/items/{}", book),
+        None,
+        Some(json!({"quantity": 2})),
+    )
+    .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let set_cookie = res.headers[SET_COOKIE].to_str().unwrap();
+    assert!(set_cookie.contains("HttpOnly"), "{}", set_cookie);
+    let cookie = set_cookie.split(';').next().unwrap().to_string();
+    let res = anonymous(
+        Method::PUT,
+        &format!("/api/v1/cart/items/{}", pen),
+        Some(&cookie),
+        Some(json!({"quantity": 2})),
+    )
+    .await;
+    assert_eq!(res.json()["items"].as_array().unwrap().len(), 2);
+    assert_eq!(res.json()["total"], 28.98);
+    let res = anonymous(Method::GET, "/api/v1/cart", None, None).await;
+    assert_eq!(res.json()["items"], json!([]));
+
+    // A token the server didn't sign isn't a cart: a new one is started
+    let (planted, _) = cookie.split_once('.').unwrap();
+    let res = anonymous(Method::GET, "/api/v1/cart", Some(planted), None).await;
+    assert_eq!(res.json()["items"], json!([]));
+    let res = anonymous(
+        Method::PUT,
+        &format!("/api/v1/cart/items/{}", pen),
+        Some(planted),
+        Some(json!({"quantity": 1})),
+    )
+    .await;
+    assert_eq!(res.json()["items"].as_array().unwrap().len(), 1);
+    let set_cookie = res.headers[SET_COOKIE].to_str().unwrap();
+    assert!(!set_cookie.starts_with(&format!("{}.", planted)));
+
+    // The user already has a book in their cart
+    let path = format!("/api/v1/cart/items/{}", book);
+    let res = app
+        .request(Method::PUT, &path, token, Some(json!({"quantity": 0})))
+        .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let res = app
+        .request(Method::PUT, &path, token, Some(json!({"quantity": 1})))
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res.headers.get(SET_COOKIE).is_none());
+
+    let login = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{}/api/v1/auth/login", app.addr()))
+        .header(CONTENT_TYPE, "application/json")
+        .header(COOKIE, &cookie)
+        .body(Full::new(Bytes::from(
+            json!({"email": account.email, "password": common::PASSWORD}).to_string(),
+        )))
+        .unwrap();
+    let res = app.send(login).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(
+        res.headers[SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .contains("Max-Age=0")
+    );
+    let res = anonymous(Method::GET, "/api/v1/cart", Some(&cookie), None).await;
+    assert_eq!(res.json()["items"], json!([]));
+    let res = app.request(Method::GET, "/api/v1/cart", token, None).await;
+    let cart = res.json();
+    // In the order they were added, to either cart
+    assert_eq!(cart["items"][0]["product_id"], pen.as_str());
+    assert_eq!(cart["items"][0]["quantity"], 2);
+    assert_eq!(cart["items"][0]["in_stock"], true);
+    assert_eq!(cart["items"][1]["product_id"], book.as_str());
+    assert_eq!(cart["items"][1]["quantity"], 3);
+
+    // Every item is ordered, or none
+    let res = app
+        .request(
+            Method::PUT,
+            &format!("/api/v1/cart/items/{}", pen),
+            token,
+            Some(json!({"quantity": 3})),
+        )
+        .await;
+    assert_eq!(res.json()["items"][0]["in_stock"], false);
+    let res = app
+        .request(Method::POST, "/api/v1/cart/checkout", token, None)
+        .await;
+    assert_eq!(res.status, StatusCode::CONFLICT);
+    assert_eq!(res.error_code(), "INSUFFICIENT_STOCK");
+    let res = app.get(&format!("/api/v1/products/{}", book)).await;
+    assert_eq!(res.json()["stock"], 10);
+
+    let res = app
+        .request(
+            Method::DELETE,
+            &format!("/api/v1/cart/items/{}", pen),
+            token,
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app
+        .request(Method::POST, "/api/v1/cart/checkout", token, None)
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    let orders = res.json();
+    assert_eq!(orders.as_array().unwrap().len(), 1);
+    assert_eq!(orders[0]["quantity"], 3);
+    assert_eq!(orders[0]["unit_price"], 12.5);
+    let res = app.get(&format!("/api/v1/products/{}", book)).await;
+    assert_eq!(res.json()["stock"], 7);
+    let res = app
+        .request(Method::POST, "/api/v1/cart/checkout", token, None)
+        .await;
+    assert_eq!(res.error_code(), "CART_EMPTY");
+
+    // The wishlist keeps products for later
+    let wish = format!("/api/v1/wishlist/{}", pen);
+    let res = app.request(Method::PUT, &wish, token, None).await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    let res = app.request(Method::PUT, &wish, token, None).await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app
+        .request(Method::GET, "/api/v1/wishlist", token, None)
+        .await;
+    assert_eq!(res.json()[0]["product_id"], pen.as_str());
+    assert_eq!(res.json()[0]["stock"], 2);
+    let res = app.request(Method::DELETE, &wish, token, None).await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app.request(Method::DELETE, &wish, token, None).await;
+    assert_eq!(res.error_code(), "ITEM_NOT_FOUND");
+}
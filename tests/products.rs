@@ -0,0 +1,343 @@
+//! `/api/v1/products`: CRUD, bulk creation and listing.
+
+mod common;
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Method, Request, StatusCode};
+use serde_json::json;
+
+#[tokio::test]
+async fn create_get_update_and_delete() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account_with_role("editor").await;
+    let token = Some(account.token.as_str());
+    let id = app.create_product(&account.token, 9.5, 3).await;
+    let path = format!("/api/v1/products/{}", id);
+
+    let res = app.get(&path).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.json(),
+        json!({"id": id, "name": "Book", "price": 9.5, "stock": 3,
+               "in_stock": true, "label": "Book (3 left)"})
+    );
+
+    let res = app
+        .write(
+            Method::PUT,
+            &path,
+            token,
+            Some(json!({"name": "Pen", "price": 1.25, "stock": 10})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()["name"], "Pen");
+
+    let res = app.write(Method::DELETE, &path, token, None).await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app.get(&path).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(res.error_code(), "PRODUCT_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn computed_fields_are_added_to_writes_and_lists() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account_with_role("editor").await;
+    let id = app.create_product(&account.token, 2.0, 1).await;
+    let path = format!("/api/v1/products/{}", id);
+    let res = app
+        .write(
+            Method::PUT,
+            &path,
+            Some(&account.token),
+            Some(json!({"name": "Sold", "price": 2.0, "stock": 0})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()["in_stock"], false);
+    assert_eq!(res.json()["label"], "Sold (0 left)");
+
+    for query in ["", "&stream=true"] {
+        let res = app
+            .get(&format!("/api/v1/products?id={}{}", id, query))
+            .await;
+        let body = res.json();
+        let product = if query.is_empty() {
+            &body["data"][0]
+        } else {
+            &body[0]
+        };
+        assert_eq!(product["label"], "Sold (0 left)", "{}", query);
+    }
+
+    let buyer = app.create_account().await;
+    app.write(
+        Method::PUT,
+        &path,
+        Some(&account.token),
+        Some(json!({"name": "Sold", "price": 2.5, "stock": 4})),
+    )
+    .await;
+    let res = app
+        .request(
+            Method::POST,
+            &format!("/api/v1/users/{}/orders", buyer.id),
+            Some(&buyer.token),
+            Some(json!({"product_id": id, "quantity": 3})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    assert_eq!(res.json()["total"], 7.5);
+}
+
+#[tokio::test]
+async fn writes_require_a_token() {
+    let Some(app) = common::app() else { return };
+
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/products",
+            None,
+            Some(json!({"name": "Book", "price": 1.0, "stock": 1})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn invalid_product_is_rejected() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account_with_role("editor").await;
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/products",
+            Some(&account.token),
+            Some(json!({"name": "", "price": -1.0, "stock": -1})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(res.error_code(), "VALIDATION_FAILED");
+}
+
+#[tokio::test]
+async fn list_is_paginated() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account_with_role("editor").await;
+    app.create_product(&account.token, 2.0, 1).await;
+    let res = app.get("/api/v1/products?limit=1&sort=id&order=desc").await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()["data"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn list_is_filtered() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account_with_role("editor").await;
+    let cheap = app.create_product(&account.token, 5.5, 3).await;
+    let expensive = app.create_product(&account.token, 250.0, 3).await;
+    let ids = format!("filter=id%3D{}%20OR%20id%3D{}", cheap, expensive);
+
+    let res = app
+        .get(&format!("/api/v1/products?{}&price_lte=100&name=Book", ids))
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let body = res.json();
+    assert_eq!(body["pagination"]["total"], 1);
+    assert_eq!(body["data"][0]["id"], cheap);
+
+    let res = app
+        .get(&format!(
+            "/api/v1/products?{}&price_gt=100.5&stock_eq=3",
+            ids
+        ))
+        .await;
+    assert_eq!(res.json()["data"][0]["id"], expensive);
+    let res = app
+        .get(&format!("/api/v1/products?id={}&name_ne=Book", cheap))
+        .await;
+    assert_eq!(res.json()["pagination"]["total"], 0);
+
+    for query in [
+        "color_eq=red",
+        "price_lte=cheap",
+        "price_lte=NaN",
+        "stock_in=1",
+        "id=1",
+        "id_gt=0192f4c8-5b1e-7a3c-9d2e-4f6a8b0c1d2e",
+    ] {
+        let res = app.get(&format!("/api/v1/products?{}", query)).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", query);
+    }
+}
+
+#[tokio::test]
+async fn legacy_paths_are_deprecated_aliases() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/products").await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers["deprecation"], "true");
+}
+
+#[tokio::test]
+async fn bulk_create_from_ndjson() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account_with_role("editor").await;
+    let body = "{\"name\": \"Book\", \"price\": 9.5, \"stock\": 3}\n\
+                not json\n\
+                \n\
+                {\"name\": \"Pen\", \"price\": 1.25, \"stock\": 10}\n";
+    let req = Request::post(format!("http://{}/api/v1/products/bulk", app.addr()))
+        .header(AUTHORIZATION, format!("Bearer {}", account.token))
+        .header(CONTENT_TYPE, "application/x-ndjson")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap();
+    let res = app.send(req).await;
+    assert_eq!(res.status, StatusCode::OK, "{:?}", res.body);
+    let body = res.json();
+    assert_eq!(body["created"], 2);
+    assert_eq!(body["failed"], 1);
+    assert_eq!(body["results"][1]["status"], 400);
+    assert_eq!(body["results"][2]["data"]["name"], "Pen");
+    assert!(body["results"][2]["data"]["id"].is_string());
+}
+
+#[tokio::test]
+async fn semantic_search_blends_embeddings_and_keywords() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account_with_role("editor").await;
+    let token = Some(account.token.as_str());
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/products",
+            token,
+            Some(json!({"name": "Espresso grinder", "price": 90.0, "stock": 3})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    let id = res.json()["id"].clone();
+
+    // Embedded by a job; the hashing provider of the tests matches close spellings
+    let mut found = serde_json::Value::Null;
+    for _ in 0..50 {
+        let res = app.get("/api/v1/products/semantic-search?q=expresso").await;
+        assert_eq!(res.status, StatusCode::OK);
+        found = res.json();
+        if found["data"][0]["product"]["id"] == id {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(found["semantic"], true);
+    assert_eq!(found["data"][0]["product"]["id"], id, "{}", found);
+    assert_eq!(
+        found["data"][0]["product"]["label"],
+        "Espresso grinder (3 left)"
+    );
+    assert_eq!(found["data"][0]["keyword"], false);
+    assert!(found["data"][0]["similarity"].as_f64().unwrap() > 0.0);
+
+    let res = app
+        .get("/api/v1/products/semantic-search?q=Grinder&limit=1")
+        .await;
+    let found = res.json();
+    assert_eq!(found["data"].as_array().unwrap().len(), 1);
+    assert_eq!(found["data"][0]["product"]["id"], id);
+    assert_eq!(found["data"][0]["keyword"], true);
+    assert!(found["data"][0]["similarity"].is_number());
+
+    let res = app.get("/api/v1/products/semantic-search?q=%20").await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn suggestions_complete_and_correct_the_names() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account_with_role("editor").await;
+    let token = Some(account.token.as_str());
+    // A word of its own, the products of the other tests share the database
+    let word = format!("grinder{}", &uuid::Uuid::new_v4().simple().to_string()[..8]);
+    for name in [format!("Espresso {}", word), format!("Coffee {}", word)] {
+        let res = app
+            .request(
+                Method::POST,
+                "/api/v1/products",
+                token,
+                Some(json!({"name": name, "price": 40.0, "stock": 2})),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::CREATED);
+    }
+
+    let res = app
+        .get(&format!("/api/v1/search/suggest?q=coffee%20{}", &word[..9]))
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let body = res.json();
+    assert_eq!(
+        body["completions"],
+        json!([{"text": format!("coffee {}", word), "frequency": 2}])
+    );
+    assert_eq!(body["did_you_mean"], serde_json::Value::Null);
+
+    // One letter missing, and a space: nothing to complete
+    let typo = word.replacen("nd", "n", 1);
+    let res = app
+        .get(&format!("/api/v1/search/suggest?q={}%20", typo))
+        .await;
+    let body = res.json();
+    assert_eq!(body["completions"], json!([]));
+    assert_eq!(body["did_you_mean"], word);
+
+    let res = app.get("/api/v1/search/suggest?q=%20").await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn suggestions_leave_out_the_soft_deleted_users() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    let token = Some(account.token.as_str());
+    let path = format!("/api/v1/users/{}", account.id);
+    let word = format!("barista{}", &uuid::Uuid::new_v4().simple().to_string()[..8]);
+    let res = app
+        .write(Method::PATCH, &path, token, Some(json!({"name": word})))
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let suggest = format!("/api/v1/search/suggest?q={}", &word[..10]);
+    let res = app.get(&suggest).await;
+    assert_eq!(
+        res.json()["completions"],
+        json!([{"text": word, "frequency": 1}])
+    );
+
+    let res = app.write(Method::DELETE, &path, token, None).await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app.get(&suggest).await;
+    assert_eq!(res.json()["completions"], json!([]));
+
+    let res = app
+        .request(Method::POST, &format!("{}/restore", path), token, None)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let res = app.get(&suggest).await;
+    assert_eq!(
+        res.json()["completions"],
+        json!([{"text": word, "frequency": 1}])
+    );
+}
@@ -0,0 +1,926 @@
+//! `/api/v1/users`: CRUD, soft delete, roles, bulk creation, listing, deltas and avatars.
+
+mod common;
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, ETAG, HeaderValue, IF_NONE_MATCH};
+use hyper::{Method, Request, StatusCode};
+use serde_json::json;
+
+#[tokio::test]
+async fn create_requires_a_token() {
+    let Some(app) = common::app() else { return };
+
+    let user = json!({"name": "Ada", "age": 36});
+    let res = app
+        .request(Method::POST, "/api/v1/users", None, Some(user.clone()))
+        .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let account = app.create_account().await;
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/users",
+            Some(&account.token),
+            Some(user),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn get_update_patch_and_delete() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    let token = Some(account.token.as_str());
+    let path = format!("/api/v1/users/{}", account.id);
+
+    let res = app.get(&path).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.json(),
+        json!({"id": account.id, "name": "Test", "age": 30})
+    );
+
+    let res = app
+        .write(
+            Method::PUT,
+            &path,
+            token,
+            Some(json!({"name": "Grace", "age": 45})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.json(),
+        json!({"id": account.id, "name": "Grace", "age": 45})
+    );
+
+    let res = app
+        .write(Method::PATCH, &path, token, Some(json!({"age": 46})))
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.json(),
+        json!({"id": account.id, "name": "Grace", "age": 46})
+    );
+
+    let res = app.write(Method::DELETE, &path, token, None).await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+
+    let res = app.get(&path).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(res.error_code(), "USER_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn soft_deleted_users_can_be_restored() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    let token = Some(account.token.as_str());
+    let path = format!("/api/v1/users/{}", account.id);
+    let restore = format!("{}/restore", path);
+
+    let res = app.request(Method::POST, &restore, token, None).await;
+    assert_eq!(res.status, StatusCode::CONFLICT);
+    assert_eq!(res.error_code(), "USER_NOT_DELETED");
+
+    let res = app.write(Method::DELETE, &path, token, None).await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app.get(&path).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+
+    let deleted = format!("{}?include_deleted=true", path);
+    let res = app.get(&deleted).await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    let res = app.request(Method::GET, &deleted, token, None).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res.json()["deleted_at"].is_string());
+
+    let res = app.request(Method::POST, &restore, token, None).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.json(),
+        json!({"id": account.id, "name": "Test", "age": 30})
+    );
+    let res = app.get(&path).await;
+    assert_eq!(res.status, StatusCode::OK);
+
+    // For good, by an administrator only
+    let hard = format!("{}?hard=true", path);
+    let res = app.write(Method::DELETE, &hard, token, None).await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+    assert_eq!(res.error_code(), "INSUFFICIENT_ROLE");
+    let admin = app.create_account_with_role("admin").await;
+    let res = app
+        .write(Method::DELETE, &hard, Some(&admin.token), None)
+        .await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app
+        .request(Method::POST, &restore, Some(&admin.token), None)
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn roles_are_required_and_given_by_administrators() {
+    let Some(app) = common::app() else { return };
+
+    let admin = app.create_account_with_role("admin").await;
+    let account = app.create_account().await;
+    let product = json!({"name": "Lamp", "price": 20.0, "stock": 1});
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/products",
+            Some(&account.token),
+            Some(product.clone()),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+    assert_eq!(res.error_code(), "INSUFFICIENT_ROLE");
+    assert_eq!(
+        res.json()["details"],
+        json!({"required_role": "editor", "role": "viewer"})
+    );
+
+    let role = format!("/admin/users/{}/role", account.id);
+    let res = app
+        .request(
+            Method::PUT,
+            &role,
+            Some(&account.token),
+            Some(json!({"role": "admin"})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+    let res = app
+        .request(
+            Method::PUT,
+            &role,
+            Some(&admin.token),
+            Some(json!({"role": "root"})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let res = app
+        .request(
+            Method::PUT,
+            &format!("/admin/users/{}/role", uuid::Uuid::new_v4()),
+            Some(&admin.token),
+            Some(json!({"role": "editor"})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    let res = app
+        .request(
+            Method::PUT,
+            &role,
+            Some(&admin.token),
+            Some(json!({"role": "editor"})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json(), json!({"id": account.id, "role": "editor"}));
+
+    // The token issued before the change carries it too
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/products",
+            Some(&account.token),
+            Some(product),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn get_is_tagged_and_refreshed_after_a_change() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    let path = format!("/api/v1/users/{}", account.id);
+    let conditional_get = async |etag: &HeaderValue| {
+        let req = Request::get(format!("http://{}{}", app.addr(), path))
+            .header(IF_NONE_MATCH, etag)
+            .body(Full::default())
+            .unwrap();
+        app.send(req).await
+    };
+
+    let res = app.get(&path).await;
+    let etag = res.headers[ETAG].clone();
+    let res = conditional_get(&etag).await;
+    assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+    assert_eq!(res.headers[ETAG], etag);
+
+    let res = app
+        .write(
+            Method::PATCH,
+            &path,
+            Some(&account.token),
+            Some(json!({"age": 31})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+
+    let res = conditional_get(&etag).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.json(),
+        json!({"id": account.id, "name": "Test", "age": 31})
+    );
+    assert_ne!(res.headers[ETAG], etag);
+}
+
+#[tokio::test]
+async fn writes_require_the_current_version() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    let token = Some(account.token.as_str());
+    let path = format!("/api/v1/users/{}", account.id);
+    let read = app.etag(&path).await;
+
+    let res = app
+        .request(Method::PATCH, &path, token, Some(json!({"age": 40})))
+        .await;
+    assert_eq!(res.status, StatusCode::PRECONDITION_REQUIRED);
+    assert_eq!(res.error_code(), "PRECONDITION_REQUIRED");
+
+    let res = app
+        .request_if_match(
+            Method::PATCH,
+            &path,
+            token,
+            Some(json!({"age": 40})),
+            Some(&read),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let current = res.headers[ETAG].to_str().unwrap().to_string();
+    assert_ne!(current, read);
+    assert_eq!(app.etag(&path).await, current);
+
+    // Another client still holding the first version
+    let res = app
+        .request_if_match(
+            Method::PUT,
+            &path,
+            token,
+            Some(json!({"name": "Stale", "age": 41})),
+            Some(&read),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(res.error_code(), "VERSION_MISMATCH");
+    assert_eq!(app.get(&path).await.json()["age"], 40);
+
+    // Weak tags never match
+    let weak = format!("W/{}", current);
+    let res = app
+        .request_if_match(Method::DELETE, &path, token, None, Some(&weak))
+        .await;
+    assert_eq!(res.status, StatusCode::PRECONDITION_FAILED);
+
+    let res = app
+        .request_if_match(Method::DELETE, &path, token, None, Some("*"))
+        .await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app
+        .request_if_match(Method::DELETE, &path, token, None, Some("*"))
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn writes_return_a_consistency_token() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    let path = format!("/api/v1/users/{}", account.id);
+    let res = app
+        .write(
+            Method::PATCH,
+            &path,
+            Some(&account.token),
+            Some(json!({"age": 52})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let token = res.headers["x-consistency-token"].clone();
+
+    let get_after = async |token: &str| {
+        let req = Request::get(format!("http://{}{}", app.addr(), path))
+            .header("x-consistency-token", token)
+            .body(Full::default())
+            .unwrap();
+        app.send(req).await
+    };
+    let res = get_after(token.to_str().unwrap()).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.json(),
+        json!({"id": account.id, "name": "Test", "age": 52})
+    );
+    assert!(!res.headers.contains_key("x-consistency-token"));
+
+    let res = get_after("yesterday").await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    assert_eq!(res.error_code(), "INVALID_REQUEST");
+}
+
+#[tokio::test]
+async fn invalid_ids_and_payloads_are_rejected() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/api/v1/users/abc").await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    assert_eq!(res.error_code(), "INVALID_REQUEST");
+
+    // Integer IDs are disabled by the test configuration: a key names no user
+    let account = app.create_account().await;
+    let res = app.get(&format!("/api/v1/users/{}", account.key)).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(res.error_code(), "USER_NOT_FOUND");
+
+    let res = app
+        .write(
+            Method::PATCH,
+            &format!("/api/v1/users/{}", account.id),
+            Some(&account.token),
+            Some(json!({"age": -5})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(res.json()["details"]["age"].is_array());
+}
+
+#[tokio::test]
+async fn list_is_paginated() {
+    let Some(app) = common::app() else { return };
+
+    app.create_account().await;
+    app.create_account().await;
+    let res = app.get("/api/v1/users?limit=1").await;
+    assert_eq!(res.status, StatusCode::OK);
+    let body = res.json();
+    assert_eq!(body["data"].as_array().unwrap().len(), 1);
+    assert_eq!(body["pagination"]["limit"], 1);
+    assert!(body["pagination"]["next"].is_string());
+}
+
+#[tokio::test]
+async fn list_is_filtered() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    // A unique name, with a wildcard that must match literally
+    let tag = format!("{}_%", uuid::Uuid::new_v4().simple());
+    for age in [17, 40, 65] {
+        let res = app
+            .request(
+                Method::POST,
+                "/api/v1/users",
+                Some(&account.token),
+                Some(json!({"name": format!("Ann {}", tag), "age": age})),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+    }
+
+    let like = tag.to_uppercase().replace('%', "%25");
+    let res = app
+        .get(&format!(
+            "/api/v1/users?name_like={}&age_gte=18&sort=age",
+            like
+        ))
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let body = res.json();
+    assert_eq!(body["pagination"]["total"], 2);
+    assert_eq!(body["data"][0]["age"], 40);
+    assert_eq!(body["data"][1]["age"], 65);
+
+    // Filters are kept by the pagination links
+    let res = app
+        .get(&format!(
+            "/api/v1/users?name_like={}&age_ne=40&limit=1",
+            like
+        ))
+        .await;
+    let next = res.json()["pagination"]["next"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert!(next.contains("age_ne=40"), "{}", next);
+    let res = app.get(&next).await;
+    assert_eq!(res.json()["data"][0]["age"], 65);
+
+    let res = app
+        .get(&format!(
+            "/api/v1/users?name_like={}&age_lt=18&stream=true",
+            like
+        ))
+        .await;
+    let users = res.json();
+    assert_eq!(users.as_array().unwrap().len(), 1);
+    assert_eq!(users[0]["name"], format!("Ann {}", tag));
+    assert_eq!(users[0]["age"], 17);
+
+    // The same conditions as an expression
+    let expression = format!(r#"name~"{}" AND (age<18 OR NOT age<=60)"#, tag);
+    let query = serde_urlencoded::to_string([("filter", &expression)]).unwrap();
+    let res = app
+        .get(&format!("/api/v1/users?{}&sort=age&limit=1", query))
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let body = res.json();
+    assert_eq!(body["pagination"]["total"], 2);
+    assert_eq!(body["data"][0]["age"], 17);
+    let next = body["pagination"]["next"].as_str().unwrap().to_string();
+    assert_eq!(app.get(&next).await.json()["data"][0]["age"], 65);
+
+    for query in [
+        "filter=age%3E",
+        "filter=email~ann",
+        "filter=age%3E%3D18%20OR",
+        "email_like=ann",
+        "age_between=18",
+        "age_like=18",
+        "name_gte=ann",
+        "age_gte=adult",
+        "age_gte=18&modified_since=0",
+    ] {
+        let res = app.get(&format!("/api/v1/users?{}", query)).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", query);
+        assert_eq!(res.error_code(), "INVALID_REQUEST", "{}", query);
+    }
+}
+
+#[tokio::test]
+async fn list_through_saved_views() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    let token = Some(account.token.as_str());
+    let tag = uuid::Uuid::new_v4().simple().to_string();
+    for age in [17, 40, 65] {
+        let user = json!({"name": format!("Ann {}", tag), "age": age});
+        let res = app
+            .request(Method::POST, "/api/v1/users", token, Some(user))
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+    }
+
+    let view = json!({
+        "name": "adults",
+        "filter": format!(r#"name~"{}" AND age>=18"#, tag),
+        "sort": "age",
+        "order": "desc",
+    });
+    let res = app
+        .request(Method::POST, "/api/v1/users/views", token, Some(view))
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let res = app
+        .request(Method::GET, "/api/v1/users/views", token, None)
+        .await;
+    assert_eq!(res.json()[0]["name"], "adults");
+
+    let res = app
+        .request(Method::GET, "/api/v1/users?view=adults", token, None)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let body = res.json();
+    assert_eq!(body["pagination"]["total"], 2);
+    assert_eq!(body["data"][0]["age"], 65);
+    // The sort of the request wins, its filters are added
+    let res = app
+        .request(
+            Method::GET,
+            "/api/v1/users?view=adults&sort=id&age_lt=50",
+            token,
+            None,
+        )
+        .await;
+    assert_eq!(res.json()["data"][0]["age"], 40);
+
+    // Views are private to their user
+    assert_eq!(
+        app.get("/api/v1/users?view=adults").await.status,
+        StatusCode::UNAUTHORIZED
+    );
+    let other = app.create_account().await;
+    let res = app
+        .request(
+            Method::GET,
+            "/api/v1/users?view=adults",
+            Some(&other.token),
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(res.error_code(), "VIEW_NOT_FOUND");
+
+    for view in [
+        json!({"name": "has space"}),
+        json!({"name": "bad", "filter": "email~ann"}),
+        json!({"name": "bad", "sort": "price"}),
+    ] {
+        let res = app
+            .request(Method::POST, "/api/v1/users/views", token, Some(view))
+            .await;
+        assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
+
+#[tokio::test]
+async fn list_as_csv() {
+    let Some(app) = common::app() else { return };
+
+    app.create_account().await;
+    let req = Request::get(format!("http://{}/api/v1/users", app.addr()))
+        .header(ACCEPT, "text/csv")
+        .body(Full::default())
+        .unwrap();
+    let res = app.send(req).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res.body.starts_with(b"id,name,age\n"));
+    assert!(res.headers.contains_key("x-total-count"));
+}
+
+#[tokio::test]
+async fn delta_reports_changes_after_a_version() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/api/v1/users?modified_since=0").await;
+    assert_eq!(res.status, StatusCode::OK);
+
+    let account = app.create_account().await;
+    let path = format!("/api/v1/users/{}", account.id);
+    app.write(Method::DELETE, &path, Some(&account.token), None)
+        .await;
+    let res = app.get("/api/v1/users?modified_since=0").await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(
+        res.body
+            .windows(account.id.len())
+            .any(|window| window == account.id.as_bytes())
+    );
+}
+
+#[tokio::test]
+async fn avatar_upload_and_download() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    let path = format!("/api/v1/users/{}/avatar", account.id);
+    let res = app.get(&path).await;
+    assert_eq!(res.error_code(), "AVATAR_NOT_FOUND");
+
+    let image: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+    let upload = |content_type: &str, data: &[u8]| {
+        let (multipart_type, body) = common::multipart_body("avatar", content_type, data);
+        Request::post(format!("http://{}{}", app.addr(), path))
+            .header(AUTHORIZATION, format!("Bearer {}", account.token))
+            .header(CONTENT_TYPE, multipart_type)
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()
+    };
+
+    let res = app.send(upload("image/png", &image)).await;
+    assert_eq!(res.status, StatusCode::OK, "{:?}", res.body);
+    assert_eq!(res.json()["avatar_url"], path);
+
+    let res = app.get(&path).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers[CONTENT_TYPE], "image/png");
+    assert_eq!(res.body.as_ref(), image.as_slice());
+
+    let res = app.send(upload("text/plain", b"hello")).await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Larger than the MAX_UPLOAD_SIZE of the tests
+    let res = app.send(upload("image/png", &vec![0; 100_000])).await;
+    assert_eq!(res.status, StatusCode::PAYLOAD_TOO_LARGE);
+
+    let res = app
+        .write(
+            Method::DELETE,
+            &format!("/api/v1/users/{}", account.id),
+            Some(&account.token),
+            None,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app.get(&path).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn bulk_create_reports_every_item() {
+    let Some(app) = common::app() else { return };
+
+    let users = json!([
+        {"name": "Ada", "age": 36},
+        {"name": "Grace", "age": -1},
+        {"name": "Alan"},
+        {"name": "Linus", "age": 54},
+    ]);
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/users/bulk",
+            None,
+            Some(users.clone()),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let account = app.create_account().await;
+    let token = Some(account.token.as_str());
+    let res = app
+        .request(Method::POST, "/api/v1/users/bulk", token, Some(users))
+        .await;
+    assert_eq!(res.status, StatusCode::OK, "{:?}", res.body);
+    let body = res.json();
+    assert_eq!(body["created"], 2);
+    assert_eq!(body["failed"], 2);
+    let statuses: Vec<_> = body["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| result["status"].as_u64().unwrap())
+        .collect();
+    assert_eq!(statuses, [201, 422, 400, 201]);
+    assert_eq!(body["results"][1]["error"]["code"], "VALIDATION_FAILED");
+
+    let id = body["results"][3]["data"]["id"].as_str().unwrap();
+    let res = app.get(&format!("/api/v1/users/{}", id)).await;
+    assert_eq!(res.json()["name"], "Linus");
+
+    let res = app
+        .request(Method::POST, "/api/v1/users/bulk", token, Some(json!([])))
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn bulk_delete_runs_as_an_operation() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account_with_role("admin").await;
+    let token = Some(account.token.as_str());
+    // Names no other test (or run) uses
+    let marker = account.id[..8].to_string();
+    let users = json!([
+        {"name": format!("Purge {} A", marker), "age": 20},
+        {"name": format!("Purge {} B", marker), "age": 30},
+        {"name": format!("Purge {} C", marker), "age": 40},
+    ]);
+    let res = app
+        .request(Method::POST, "/api/v1/users/bulk", token, Some(users))
+        .await;
+    let ids: Vec<String> = res.json()["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| result["data"]["id"].as_str().unwrap().to_string())
+        .collect();
+
+    let filter = format!("name~\"{}\" AND age<35", marker);
+    let viewer = app.create_account().await;
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/users/bulk-delete",
+            Some(&viewer.token),
+            Some(json!({"filter": filter})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+    assert_eq!(res.error_code(), "INSUFFICIENT_ROLE");
+
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/users/bulk-delete",
+            token,
+            Some(json!({"filter": "name~\"nobody at all\""})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/users/bulk-delete",
+            token,
+            Some(json!({"filter": "email=x"})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/users/bulk-delete",
+            token,
+            Some(json!({"filter": filter})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::ACCEPTED, "{:?}", res.body);
+    let location = res.headers["location"].to_str().unwrap().to_string();
+    assert_eq!(res.json()["kind"], "bulk_delete_users");
+
+    // The job runs after the response, give the workers some time
+    let mut operation = json!(null);
+    for _ in 0..50 {
+        operation = app
+            .request(Method::GET, &location, token, None)
+            .await
+            .json();
+        if operation["status"] == "succeeded" {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(operation["status"], "succeeded", "{}", operation);
+    assert_eq!(operation["progress"], json!({"total": 2, "deleted": 2}));
+
+    let query = serde_urlencoded::to_string([("filter", &filter)]).unwrap();
+    let res = app.get(&format!("/api/v1/users?{}", query)).await;
+    assert_eq!(res.json()["data"], json!([]));
+    let res = app.get(&format!("/api/v1/users/{}", ids[2])).await;
+    assert_eq!(res.status, StatusCode::OK);
+
+    // Soft-deleted, so they can be restored
+    for id in &ids[..2] {
+        let path = format!("/api/v1/users/{}", id);
+        let res = app
+            .request(
+                Method::GET,
+                &format!("{}?include_deleted=true", path),
+                token,
+                None,
+            )
+            .await;
+        assert!(res.json()["deleted_at"].is_string());
+        let res = app
+            .request(Method::POST, &format!("{}/restore", path), token, None)
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let res = app.get(&path).await;
+        assert_eq!(res.status, StatusCode::OK);
+    }
+
+    let res = app
+        .request(Method::GET, "/api/v1/operations/999999999", token, None)
+        .await;
+    assert_eq!(res.error_code(), "OPERATION_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn tools_call_the_read_routes_as_the_caller() {
+    let Some(app) = common::app() else { return };
+
+    let res = app.get("/api/v1/tools").await;
+    assert_eq!(res.status, StatusCode::OK);
+    let tools = res.json()["tools"].clone();
+    let get_user = tools
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|tool| tool["name"] == "get_user")
+        .unwrap();
+    assert_eq!(get_user["route"], "GET /api/v1/users/:id");
+    assert_eq!(get_user["inputSchema"]["required"], json!(["id"]));
+    let list_users = &tools[0];
+    assert_eq!(list_users["name"], "list_users");
+    assert!(list_users["inputSchema"]["properties"]["filters"].is_object());
+    assert!(list_users["inputSchema"]["properties"]["stream"].is_null());
+
+    let call = |name: &str| format!("/api/v1/tools/{}/call", name);
+    let arguments = |arguments| Some(json!({ "arguments": arguments }));
+    let res = app
+        .request(Method::POST, &call("get_user"), None, arguments(json!({})))
+        .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let account = app.create_account().await;
+    let token = Some(account.token.as_str());
+    let res = app
+        .request(
+            Method::POST,
+            &call("get_user"),
+            token,
+            arguments(json!({"id": account.id})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK, "{:?}", res.body);
+    assert_eq!(res.json()["tool"], "get_user");
+    assert_eq!(res.json()["result"]["name"], "Test");
+
+    let res = app
+        .request(
+            Method::POST,
+            &call("list_users"),
+            token,
+            arguments(json!({"filters": {"id": account.id}, "limit": 5})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK, "{:?}", res.body);
+    assert_eq!(res.json()["result"]["data"][0]["id"], account.id.as_str());
+
+    let res = app
+        .request(
+            Method::POST,
+            &call("get_user"),
+            token,
+            arguments(json!({"id": account.id, "email": "x"})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let res = app
+        .request(Method::POST, &call("get_user"), token, arguments(json!({})))
+        .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let res = app
+        .request(
+            Method::POST,
+            &call("delete_user"),
+            token,
+            arguments(json!({})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(res.error_code(), "TOOL_NOT_FOUND");
+
+    // Past TOOLS_RATE_LIMIT_PER_MINUTE (5 in the tests) calls of the user
+    let res = app
+        .request(
+            Method::POST,
+            &call("get_user"),
+            token,
+            arguments(json!({"id": account.id})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let res = app
+        .request(
+            Method::POST,
+            &call("get_user"),
+            token,
+            arguments(json!({"id": account.id})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(res.headers.contains_key("retry-after"));
+}
+
+#[tokio::test]
+async fn search_falls_back_to_the_database() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    // A name of its own, the users of the other tests share the database
+    let name = format!("Searchable {}", uuid::Uuid::new_v4().simple());
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/users",
+            Some(&account.token),
+            Some(json!({"name": name, "age": 40})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let id = res.json()["id"].clone();
+
+    // No search engine in the tests
+    let word = name.split(' ').nth(1).unwrap();
+    let res = app.get(&format!("/api/v1/search?q={}", word)).await;
+    assert_eq!(res.status, StatusCode::OK);
+    let body = res.json();
+    assert_eq!(body["engine"], "database");
+    assert_eq!(body["users"][0]["id"], id, "{}", body);
+    assert_eq!(body["users"][0]["name"], name);
+    assert_eq!(body["products"], json!([]));
+
+    // Not the soft-deleted users
+    let admin = app.create_account_with_role("admin").await;
+    let path = format!("/api/v1/users/{}", id.as_str().unwrap());
+    let res = app
+        .write(Method::DELETE, &path, Some(&admin.token), None)
+        .await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app.get(&format!("/api/v1/search?q={}", word)).await;
+    assert_eq!(res.json()["users"], json!([]));
+
+    let res = app.get("/api/v1/search?q=").await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,224 @@
+//! Webhooks: registration and signed delivery of the change events.
+
+mod common;
+
+use std::time::Duration;
+
+use hyper::{Method, StatusCode};
+use ring::hmac;
+use serde_json::{Value, json};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// Secret of the webhooks of the tests
+const SECRET: &str = "0123456789abcdef";
+
+/// A request received by [`receiver`]: its headers, lowercased, and its body.
+struct Received {
+    head: String,
+    body: Vec<u8>,
+}
+
+/// Starts a server answering every request with 204, and returns its URL with the
+/// requests it receives.
+async fn receiver() -> (String, mpsc::UnboundedReceiver<Received>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hooks", listener.local_addr().unwrap());
+    let (sender, received) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let mut data = Vec::new();
+                let mut buf = [0; 4096];
+                // Until the head and the body announced by Content-Length are read
+                let (head, body) = loop {
+                    let n = stream.read(&mut buf).await.unwrap_or(0);
+                    if n == 0 {
+                        return;
+                    }
+                    data.extend_from_slice(&buf[..n]);
+                    let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") else {
+                        continue;
+                    };
+                    let head = String::from_utf8_lossy(&data[..end]).to_lowercase();
+                    let length = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .and_then(|value| value.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if data.len() >= end + 4 + length {
+                        break (head, data[end + 4..end + 4 + length].to_vec());
+                    }
+                };
+                let _ = stream
+                    .write_all(b"HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n")
+                    .await;
+                let _ = sender.send(Received { head, body });
+            });
+        }
+    });
+    (url, received)
+}
+
+/// Value of a header of a received request.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        .map(str::trim)
+}
+
+#[tokio::test]
+async fn webhooks_are_managed_by_their_owner() {
+    let Some(app) = common::app() else { return };
+
+    let owner = app.create_account().await;
+    let token = Some(owner.token.as_str());
+    let other = app.create_account().await;
+
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/webhooks",
+            token,
+            Some(
+                json!({"url": "ftp://example.com", "secret": "short", "events": ["users.renamed"]}),
+            ),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let details = &res.json()["details"];
+    for field in ["url", "secret", "events"] {
+        assert!(details.get(field).is_some(), "{}", details);
+    }
+
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/webhooks",
+            token,
+            Some(json!({"url": "https://example.com/hooks", "secret": SECRET})),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED, "{:?}", res.body);
+    let webhook = res.json();
+    assert_eq!(webhook["events"], json!([]));
+    assert!(webhook.get("secret").is_none());
+    let path = format!("/api/v1/webhooks/{}", webhook["id"].as_str().unwrap());
+
+    let res = app
+        .request(
+            Method::PUT,
+            &path,
+            token,
+            Some(json!({
+                "url": "https://example.com/other",
+                "secret": SECRET,
+                "events": ["products.deleted"],
+            })),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()["events"], json!(["products.deleted"]));
+
+    let res = app
+        .request(Method::GET, "/api/v1/webhooks", token, None)
+        .await;
+    assert_eq!(res.json().as_array().unwrap().len(), 1);
+
+    // Nobody else sees it
+    let res = app
+        .request(Method::GET, &path, Some(other.token.as_str()), None)
+        .await;
+    assert_eq!(res.error_code(), "WEBHOOK_NOT_FOUND");
+
+    let res = app.request(Method::DELETE, &path, token, None).await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app.request(Method::GET, &path, token, None).await;
+    assert_eq!(res.error_code(), "WEBHOOK_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn events_are_delivered_signed() {
+    let Some(app) = common::app() else { return };
+
+    let account = app.create_account().await;
+    let token = Some(account.token.as_str());
+    let (url, mut received) = receiver().await;
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/webhooks",
+            token,
+            Some(json!({"url": url, "secret": SECRET, "events": ["users.created"]})),
+        )
+        .await;
+    let path = format!("/api/v1/webhooks/{}", res.json()["id"].as_str().unwrap());
+
+    let res = app
+        .request(
+            Method::POST,
+            "/api/v1/users",
+            token,
+            Some(json!({"name": "Hooked", "age": 30})),
+        )
+        .await;
+    let user_id = res.json()["id"].clone();
+
+    // Other tests create users too: wait for the delivery of this one
+    let event = loop {
+        let request = tokio::time::timeout(Duration::from_secs(10), received.recv())
+            .await
+            .expect("No delivery")
+            .unwrap();
+        let event = serde_json::from_slice::<Value>(&request.body).unwrap();
+        assert_eq!(
+            header(&request.head, "x-webhook-event"),
+            Some("users.created")
+        );
+        let signature = header(&request.head, "x-webhook-signature")
+            .and_then(|value| value.strip_prefix("sha256="))
+            .unwrap();
+        let signature = (0..signature.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&signature[i..i + 2], 16).unwrap())
+            .collect::<Vec<_>>();
+        let key = hmac::Key::new(hmac::HMAC_SHA256, SECRET.as_bytes());
+        assert!(hmac::verify(&key, &request.body, &signature).is_ok());
+        if event["data"]["id"] == user_id {
+            break event;
+        }
+    };
+    assert_eq!(event["type"], "users.created");
+
+    // The attempt is recorded once the response is received
+    let mut deliveries = json!([]);
+    for _ in 0..50 {
+        deliveries = app
+            .request(Method::GET, &format!("{}/deliveries", path), token, None)
+            .await
+            .json();
+        if deliveries
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|delivery| delivery["payload"] == event)
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let delivery = deliveries
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|delivery| delivery["payload"] == event)
+        .expect("Delivery not recorded");
+    assert_eq!(delivery["status_code"], 204);
+    assert_eq!(delivery["attempt"], 1);
+    assert_eq!(delivery["error"], Value::Null);
+
+    app.request(Method::DELETE, &path, token, None).await;
+}